@@ -0,0 +1,281 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * File events of the Owlyshield agent on Linux, read by owlyshield_predict (src/ebpf.rs):
+ * - the writes, with a sample of the written buffer for its entropy;
+ * - the renames and the deletions, which fanotify cannot report.
+ *
+ * The syscalls tracepoints are used, so that no BTF is needed. The paths are sent as given to
+ * the syscalls, with their directory descriptor, and resolved by the agent through /proc, which
+ * also ignores the files out of WATCHED_MOUNTS. An event is kept by thread at the entry of its
+ * syscall, and sent at the exit only if the syscall succeeded.
+ *
+ * Build with "make", then copy owlyshield.bpf.o in the utils directory of the agent.
+ */
+#include <linux/bpf.h>
+#include <bpf/bpf_helpers.h>
+
+#define SAMPLE_LEN 512
+#define NAME_LEN 256
+#define AT_FDCWD -100
+#define AT_REMOVEDIR 0x200
+
+enum op {
+	OP_WRITE = 1,
+	OP_RENAME = 2,
+	OP_UNLINK = 3,
+};
+
+/* Keep in sync with RawEvent in src/ebpf.rs */
+struct event {
+	__u32 pid;
+	__u32 op;
+	/* written file, or directory of path */
+	__s32 fd;
+	/* directory of new_path */
+	__s32 new_fd;
+	/* bytes written */
+	__u64 count;
+	__u32 sample_len;
+	__u32 padding;
+	char path[NAME_LEN];
+	char new_path[NAME_LEN];
+	__u8 sample[SAMPLE_LEN];
+};
+
+/* Layout of /sys/kernel/tracing/events/syscalls/sys_enter_* /format */
+struct sys_enter_ctx {
+	__u64 common;
+	__s32 syscall_nr;
+	__u32 padding;
+	__u64 args[6];
+};
+
+/* Layout of /sys/kernel/tracing/events/syscalls/sys_exit_* /format */
+struct sys_exit_ctx {
+	__u64 common;
+	__s32 syscall_nr;
+	__u32 padding;
+	__s64 ret;
+};
+
+/* Legacy maps definitions, as expected by the loader */
+struct map_def {
+	unsigned int type;
+	unsigned int key_size;
+	unsigned int value_size;
+	unsigned int max_entries;
+	unsigned int map_flags;
+};
+
+struct map_def SEC("maps") EVENTS = {
+	.type = BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+	.key_size = sizeof(__u32),
+	.value_size = sizeof(__u32),
+	.max_entries = 1024,
+};
+
+/* An event is too big for the stack */
+struct map_def SEC("maps") SCRATCH = {
+	.type = BPF_MAP_TYPE_PERCPU_ARRAY,
+	.key_size = sizeof(__u32),
+	.value_size = sizeof(struct event),
+	.max_entries = 1,
+};
+
+/* Events of the syscalls in progress, by thread */
+struct map_def SEC("maps") PENDING = {
+	.type = BPF_MAP_TYPE_HASH,
+	.key_size = sizeof(__u64),
+	.value_size = sizeof(struct event),
+	.max_entries = 10240,
+};
+
+/* Index 0: pid of the agent, whose events are ignored */
+struct map_def SEC("maps") CONFIG = {
+	.type = BPF_MAP_TYPE_ARRAY,
+	.key_size = sizeof(__u32),
+	.value_size = sizeof(__u32),
+	.max_entries = 1,
+};
+
+static __always_inline struct event *new_event(__u32 op)
+{
+	__u32 zero = 0;
+	__u32 pid = bpf_get_current_pid_tgid() >> 32;
+	__u32 *agent_pid = bpf_map_lookup_elem(&CONFIG, &zero);
+	if (agent_pid && *agent_pid == pid)
+		return 0;
+	struct event *e = bpf_map_lookup_elem(&SCRATCH, &zero);
+	if (!e)
+		return 0;
+	e->pid = pid;
+	e->op = op;
+	e->fd = AT_FDCWD;
+	e->new_fd = AT_FDCWD;
+	e->count = 0;
+	e->sample_len = 0;
+	e->path[0] = 0;
+	e->new_path[0] = 0;
+	return e;
+}
+
+/* Kept until the exit of the syscall of the thread */
+static __always_inline int stash(struct event *e)
+{
+	__u64 tid = bpf_get_current_pid_tgid();
+	bpf_map_update_elem(&PENDING, &tid, e, BPF_ANY);
+	return 0;
+}
+
+/* Sends the event of the syscall if it succeeded: failed writes, renames and unlinks are not
+ * counted. A write sends the bytes actually written. */
+static __always_inline int on_sys_exit(struct sys_exit_ctx *ctx)
+{
+	__u64 tid = bpf_get_current_pid_tgid();
+	struct event *e = bpf_map_lookup_elem(&PENDING, &tid);
+	if (!e)
+		return 0;
+	__s64 ret = ctx->ret;
+	if (ret > 0 || (ret == 0 && e->op != OP_WRITE)) {
+		if (e->op == OP_WRITE) {
+			e->count = (__u64)ret;
+			if (e->sample_len > ret)
+				e->sample_len = (__u32)ret;
+		}
+		bpf_perf_event_output(ctx, &EVENTS, BPF_F_CURRENT_CPU, e, sizeof(*e));
+	}
+	bpf_map_delete_elem(&PENDING, &tid);
+	return 0;
+}
+
+static __always_inline int on_write(struct sys_enter_ctx *ctx)
+{
+	__u64 count = ctx->args[2];
+	if (count == 0)
+		return 0;
+	struct event *e = new_event(OP_WRITE);
+	if (!e)
+		return 0;
+	e->fd = (__s32)ctx->args[0];
+	e->count = count;
+	__u32 len = count > SAMPLE_LEN ? SAMPLE_LEN : (__u32)count;
+	if (len > SAMPLE_LEN)
+		return 0;
+	if (bpf_probe_read_user(e->sample, len, (void *)ctx->args[1]) == 0)
+		e->sample_len = len;
+	return stash(e);
+}
+
+SEC("tracepoint/syscalls/sys_enter_write")
+int sys_enter_write(struct sys_enter_ctx *ctx)
+{
+	return on_write(ctx);
+}
+
+SEC("tracepoint/syscalls/sys_enter_pwrite64")
+int sys_enter_pwrite64(struct sys_enter_ctx *ctx)
+{
+	return on_write(ctx);
+}
+
+static __always_inline int on_rename(struct sys_enter_ctx *ctx, __s32 fd, const char *path,
+				     __s32 new_fd, const char *new_path)
+{
+	struct event *e = new_event(OP_RENAME);
+	if (!e)
+		return 0;
+	e->fd = fd;
+	e->new_fd = new_fd;
+	bpf_probe_read_user_str(e->path, NAME_LEN, path);
+	bpf_probe_read_user_str(e->new_path, NAME_LEN, new_path);
+	return stash(e);
+}
+
+SEC("tracepoint/syscalls/sys_enter_rename")
+int sys_enter_rename(struct sys_enter_ctx *ctx)
+{
+	return on_rename(ctx, AT_FDCWD, (const char *)ctx->args[0], AT_FDCWD, (const char *)ctx->args[1]);
+}
+
+SEC("tracepoint/syscalls/sys_enter_renameat")
+int sys_enter_renameat(struct sys_enter_ctx *ctx)
+{
+	return on_rename(ctx, (__s32)ctx->args[0], (const char *)ctx->args[1], (__s32)ctx->args[2],
+			 (const char *)ctx->args[3]);
+}
+
+SEC("tracepoint/syscalls/sys_enter_renameat2")
+int sys_enter_renameat2(struct sys_enter_ctx *ctx)
+{
+	return on_rename(ctx, (__s32)ctx->args[0], (const char *)ctx->args[1], (__s32)ctx->args[2],
+			 (const char *)ctx->args[3]);
+}
+
+static __always_inline int on_unlink(struct sys_enter_ctx *ctx, __s32 fd, const char *path)
+{
+	struct event *e = new_event(OP_UNLINK);
+	if (!e)
+		return 0;
+	e->fd = fd;
+	bpf_probe_read_user_str(e->path, NAME_LEN, path);
+	return stash(e);
+}
+
+SEC("tracepoint/syscalls/sys_enter_unlink")
+int sys_enter_unlink(struct sys_enter_ctx *ctx)
+{
+	return on_unlink(ctx, AT_FDCWD, (const char *)ctx->args[0]);
+}
+
+SEC("tracepoint/syscalls/sys_enter_unlinkat")
+int sys_enter_unlinkat(struct sys_enter_ctx *ctx)
+{
+	/* rmdir */
+	if (ctx->args[2] & AT_REMOVEDIR)
+		return 0;
+	return on_unlink(ctx, (__s32)ctx->args[0], (const char *)ctx->args[1]);
+}
+
+SEC("tracepoint/syscalls/sys_exit_write")
+int sys_exit_write(struct sys_exit_ctx *ctx)
+{
+	return on_sys_exit(ctx);
+}
+
+SEC("tracepoint/syscalls/sys_exit_pwrite64")
+int sys_exit_pwrite64(struct sys_exit_ctx *ctx)
+{
+	return on_sys_exit(ctx);
+}
+
+SEC("tracepoint/syscalls/sys_exit_rename")
+int sys_exit_rename(struct sys_exit_ctx *ctx)
+{
+	return on_sys_exit(ctx);
+}
+
+SEC("tracepoint/syscalls/sys_exit_renameat")
+int sys_exit_renameat(struct sys_exit_ctx *ctx)
+{
+	return on_sys_exit(ctx);
+}
+
+SEC("tracepoint/syscalls/sys_exit_renameat2")
+int sys_exit_renameat2(struct sys_exit_ctx *ctx)
+{
+	return on_sys_exit(ctx);
+}
+
+SEC("tracepoint/syscalls/sys_exit_unlink")
+int sys_exit_unlink(struct sys_exit_ctx *ctx)
+{
+	return on_sys_exit(ctx);
+}
+
+SEC("tracepoint/syscalls/sys_exit_unlinkat")
+int sys_exit_unlinkat(struct sys_exit_ctx *ctx)
+{
+	return on_sys_exit(ctx);
+}
+
+char LICENSE[] SEC("license") = "GPL";
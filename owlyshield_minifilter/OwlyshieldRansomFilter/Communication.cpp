@@ -120,6 +120,7 @@ RWFDissconnect(
 	//
 	DbgPrint("Disconnent\n");
 	commHandle->CommClosed = TRUE;
+	driverData->ClearGidCaptures(); // nobody to capture the files anymore
 }
 
 NTSTATUS 
@@ -199,6 +200,52 @@ RWFNewMessage(
 		return STATUS_INVALID_PARAMETER;
 		
 	}
+	else if (message->type == MESSAGE_ADD_PROTECTED_PID) {
+		if (message->pid != 0 && driverData->AddProtectedPid(message->pid)) {
+			DbgPrint("Added protected pid %d\n", message->pid);
+			return STATUS_SUCCESS;
+		}
+		return STATUS_INVALID_PARAMETER;
+	}
+	else if (message->type == MESSAGE_MUTE_GID) {
+		if (message->gid != 0 && driverData->MuteGid(message->gid)) {
+			DbgPrint("Muted gid %llu\n", message->gid);
+			return STATUS_SUCCESS;
+		}
+		return STATUS_INVALID_PARAMETER;
+	}
+	else if (message->type == MESSAGE_CAPTURE_GID) { // pid is the flag
+		if (message->gid != 0 && driverData->SetGidCapture(message->gid, message->pid != 0)) {
+			DbgPrint("Capture %d for gid %llu\n", message->pid != 0, message->gid);
+			return STATUS_SUCCESS;
+		}
+		return STATUS_INVALID_PARAMETER;
+	}
+	else if (message->type == MESSAGE_SET_BACKPRESSURE) { // pid is the flag, gid the depth of the queue of the application
+		driverData->SetBackpressure(message->pid != 0, message->gid);
+		return STATUS_SUCCESS;
+	}
+	else if (message->type == MESSAGE_GET_AGGREGATES) {
+		if (OutputBuffer == NULL || OutputBufferLength < sizeof(AGGREGATES_REPLY)) {
+			return STATUS_INVALID_PARAMETER;
+		}
+		driverData->GetAggregates(OutputBuffer, OutputBufferLength, ReturnOutputBufferLength);
+		return STATUS_SUCCESS;
+	}
+	else if (message->type == MESSAGE_GET_TAMPER_ATTEMPTS) {
+		if (OutputBuffer == NULL || OutputBufferLength < sizeof(TAMPER_ATTEMPT)) {
+			return STATUS_INVALID_PARAMETER;
+		}
+		driverData->GetTamperAttempts(OutputBuffer, OutputBufferLength, ReturnOutputBufferLength);
+		return STATUS_SUCCESS;
+	}
+	else if (message->type == MESSAGE_GET_SPOOFED_PARENTS) {
+		if (OutputBuffer == NULL || OutputBufferLength < sizeof(SPOOFED_PARENT)) {
+			return STATUS_INVALID_PARAMETER;
+		}
+		driverData->GetSpoofedParents(OutputBuffer, OutputBufferLength, ReturnOutputBufferLength);
+		return STATUS_SUCCESS;
+	}
 	// FIXME: the kill code to gid
 	else if (message->type == MESSAGE_KILL_GID) {
 		if (OutputBuffer == NULL || OutputBufferLength != sizeof(LONG)) {
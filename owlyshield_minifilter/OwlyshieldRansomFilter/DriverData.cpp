@@ -6,6 +6,7 @@ DriverData::DriverData(PDRIVER_OBJECT DriverObject) :
 	DriverObject(DriverObject), 
 	pid(0), 
 	irpOpsSize(0), 
+	irpPathBytes(0),
 	directoryRootsSize(0),
 	GidToPids(),
 	PidToGids()
@@ -15,11 +16,24 @@ DriverData::DriverData(PDRIVER_OBJECT DriverObject) :
 	InitializeListHead(&rootDirectories);
 	KeInitializeSpinLock(&irpOpsLock); //init spin lock
 	KeInitializeSpinLock(&directoriesSpinLock); //init spin lock
+
+	backpressure = FALSE;
+	appQueueDepth = 0;
+	aggregatesSize = 0;
+	aggregatedIrps = 0;
+	droppedIrps = 0;
 	
 	GidCounter = 0;
 	KeInitializeSpinLock(&GIDSystemLock); //init spin lock
 	gidsSize = 0;
 	InitializeListHead(&GidsList);
+
+	protectedPidsSize = 0;
+	tamperAttemptsSize = 0;
+	KeInitializeSpinLock(&protectionLock); //init spin lock
+
+	spoofedParentsSize = 0;
+	KeInitializeSpinLock(&spoofedParentsLock); //init spin lock
 }
 
 DriverData::~DriverData()
@@ -122,6 +136,7 @@ BOOLEAN DriverData::RecordNewProcess(PUNICODE_STRING ProcessName, ULONG ProcessI
 		PGID_ENTRY gidRecord = (PGID_ENTRY)GidToPids.get(gid);
 		InsertHeadList(&(gidRecord->HeadListPids), &(pStrct->entry));
 		gidRecord->pidsSize++;
+		gidRecord->muted = FALSE; // the new child may run anything
 		PidToGids.insertNode(ProcessId, (HANDLE)gid);
 	}
 	else {
@@ -155,6 +170,81 @@ BOOLEAN DriverData::RemoveGid(ULONGLONG gid) {
 	return ret;
 }
 
+BOOLEAN DriverData::MuteGid(ULONGLONG gid) {
+	BOOLEAN ret = FALSE;
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&GIDSystemLock, &irql);
+	PGID_ENTRY GidRecord = (PGID_ENTRY)GidToPids.get(gid);
+	if (GidRecord != nullptr) {
+		GidRecord->muted = TRUE;
+		ret = TRUE;
+	}
+	KeReleaseSpinLock(&GIDSystemLock, irql);
+	return ret;
+}
+
+BOOLEAN DriverData::UnmuteProcessGid(ULONG ProcessId) {
+	BOOLEAN ret = FALSE;
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&GIDSystemLock, &irql);
+	ULONGLONG gid = (ULONGLONG)PidToGids.get(ProcessId);
+	PGID_ENTRY GidRecord = gid ? (PGID_ENTRY)GidToPids.get(gid) : nullptr;
+	if (GidRecord != nullptr && GidRecord->muted) {
+		GidRecord->muted = FALSE;
+		ret = TRUE;
+	}
+	KeReleaseSpinLock(&GIDSystemLock, irql);
+	return ret;
+}
+
+BOOLEAN DriverData::IsGidMuted(ULONGLONG gid) {
+	BOOLEAN ret = FALSE;
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&GIDSystemLock, &irql);
+	PGID_ENTRY GidRecord = (PGID_ENTRY)GidToPids.get(gid);
+	if (GidRecord != nullptr) {
+		ret = GidRecord->muted;
+	}
+	KeReleaseSpinLock(&GIDSystemLock, irql);
+	return ret;
+}
+
+BOOLEAN DriverData::SetGidCapture(ULONGLONG gid, BOOLEAN captured) {
+	BOOLEAN ret = FALSE;
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&GIDSystemLock, &irql);
+	PGID_ENTRY GidRecord = (PGID_ENTRY)GidToPids.get(gid);
+	if (GidRecord != nullptr) {
+		GidRecord->captured = captured;
+		ret = TRUE;
+	}
+	KeReleaseSpinLock(&GIDSystemLock, irql);
+	return ret;
+}
+
+BOOLEAN DriverData::IsGidCaptured(ULONGLONG gid) {
+	BOOLEAN ret = FALSE;
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&GIDSystemLock, &irql);
+	PGID_ENTRY GidRecord = (PGID_ENTRY)GidToPids.get(gid);
+	if (GidRecord != nullptr) {
+		ret = GidRecord->captured;
+	}
+	KeReleaseSpinLock(&GIDSystemLock, irql);
+	return ret;
+}
+
+VOID DriverData::ClearGidCaptures() {
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&GIDSystemLock, &irql);
+	PLIST_ENTRY headGids = &GidsList;
+	for (PLIST_ENTRY iterator = headGids->Flink; iterator != headGids; iterator = iterator->Flink) {
+		PGID_ENTRY pStrct = (PGID_ENTRY)CONTAINING_RECORD(iterator, GID_ENTRY, GidListEntry);
+		pStrct->captured = FALSE;
+	}
+	KeReleaseSpinLock(&GIDSystemLock, irql);
+}
+
 ULONGLONG DriverData::GetGidSize(ULONGLONG gid, PBOOLEAN found) {
 	ASSERT(found != nullptr);
 	*found = FALSE;
@@ -263,7 +353,13 @@ VOID DriverData::ClearIrps() {
 		pEntryIrps = temp.Flink;
 	}
 	irpOpsSize = 0;
+	irpPathBytes = 0;
 	InitializeListHead(&irpOps);
+	// a new application starts without backpressure
+	backpressure = FALSE;
+	aggregatesSize = 0;
+	aggregatedIrps = 0;
+	droppedIrps = 0;
 	KeReleaseSpinLock(&irpOpsLock, irql);
 }
 
@@ -282,11 +378,15 @@ BOOLEAN DriverData::AddIrpMessage(PIRP_ENTRY newEntry)
 
 	KIRQL irql = KeGetCurrentIrql();
 	KeAcquireSpinLock(&irpOpsLock, &irql);
-	if (irpOpsSize < MAX_OPS_SAVE) {
+	// the paths are allocated to their size (up to 64KB), so their total is bounded too
+	if (!backpressure && irpOpsSize < MAX_OPS_SAVE
+		&& irpPathBytes + newEntry->filePath.MaximumLength <= MAX_PATH_BYTES_SAVE) {
 		irpOpsSize++;
+		irpPathBytes += newEntry->filePath.MaximumLength;
 		InsertTailList(&irpOps, &newEntry->entry); 
 	}
 	else {
+		AggregateIrpAux(&newEntry->data);
 		KeReleaseSpinLock(&irpOpsLock, irql);
 		return FALSE;
 	}
@@ -301,6 +401,7 @@ BOOLEAN DriverData::RemIrpMessage(PIRP_ENTRY newEntry)
 	KeAcquireSpinLock(&irpOpsLock, &irql);
 	RemoveEntryList(&newEntry->entry);
 	irpOpsSize--;
+	irpPathBytes -= newEntry->filePath.MaximumLength;
 
 	KeReleaseSpinLock(&irpOpsLock, irql);
 	return TRUE;
@@ -313,6 +414,9 @@ PIRP_ENTRY DriverData::GetFirstIrpMessage()
 	KeAcquireSpinLock(&irpOpsLock, &irql);
 	ret = RemoveHeadList(&irpOps);
 	irpOpsSize--;
+	if (ret != &irpOps) {
+		irpPathBytes -= ((PIRP_ENTRY)CONTAINING_RECORD(ret, IRP_ENTRY, entry))->filePath.MaximumLength;
+	}
 	KeReleaseSpinLock(&irpOpsLock, irql);
 	if (ret == &irpOps) {
 		return NULL;
@@ -334,7 +438,7 @@ VOID DriverData::DriverGetIrps(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutp
 
 	PIRP_ENTRY PrevEntry = nullptr;
 	PDRIVER_MESSAGE Prev = nullptr;
-	USHORT prevBufferSize = 0;
+	ULONG prevBufferSize = 0;
 
 	KIRQL irql = KeGetCurrentIrql();
 	KeAcquireSpinLock(&irpOpsLock, &irql);
@@ -343,6 +447,7 @@ VOID DriverData::DriverGetIrps(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutp
 		irpEntryList = RemoveHeadList(&irpOps);
 		irpOpsSize--;
 		PIRP_ENTRY irp = (PIRP_ENTRY)CONTAINING_RECORD(irpEntryList, IRP_ENTRY, entry);
+		irpPathBytes -= irp->filePath.MaximumLength;
 		UNICODE_STRING FilePath = irp->filePath;
 		PDRIVER_MESSAGE irpMsg = &(irp->data);
 		USHORT nameBufferSize = FilePath.Length;
@@ -360,6 +465,7 @@ VOID DriverData::DriverGetIrps(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutp
 		if (sizeof(DRIVER_MESSAGE) + nameBufferSize >= BufferSizeRemain) { // return to irps list, not enough space
 			InsertHeadList(&irpOps, irpEntryList);
 			irpOpsSize++;
+			irpPathBytes += irp->filePath.MaximumLength;
 			break;
 		}
 		else {
@@ -373,7 +479,7 @@ VOID DriverData::DriverGetIrps(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutp
 				outHeader.addSize(sizeof(DRIVER_MESSAGE));
 				*ReturnOutputBufferLength += sizeof(DRIVER_MESSAGE);
 				if (prevBufferSize) {
-					RtlCopyMemory(OutputBuffer, PrevEntry->Buffer, prevBufferSize); // copy previous filePath
+					RtlCopyMemory(OutputBuffer, PrevEntry->filePath.Buffer, prevBufferSize); // copy previous filePath
 					OutputBuffer += prevBufferSize;
 					outHeader.addSize(prevBufferSize);
 					*ReturnOutputBufferLength += prevBufferSize;
@@ -385,13 +491,11 @@ VOID DriverData::DriverGetIrps(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutp
 		PrevEntry = irp;
 		Prev = irpMsg;
 		prevBufferSize = nameBufferSize;
-		if (prevBufferSize > MAX_FILE_NAME_SIZE) prevBufferSize = MAX_FILE_NAME_SIZE;
 		BufferSizeRemain -= (sizeof(DRIVER_MESSAGE) + prevBufferSize);
 		outHeader.addOp();
 
 	}
 	KeReleaseSpinLock(&irpOpsLock, irql);
-	if (prevBufferSize > MAX_FILE_NAME_SIZE) prevBufferSize = MAX_FILE_NAME_SIZE;
 	if (Prev != nullptr && PrevEntry != nullptr) {
 		Prev->next = nullptr;
 		if (prevBufferSize) {
@@ -402,7 +506,7 @@ VOID DriverData::DriverGetIrps(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutp
 		outHeader.addSize(sizeof(DRIVER_MESSAGE));
 		*ReturnOutputBufferLength += sizeof(DRIVER_MESSAGE);
 		if (prevBufferSize) {
-			RtlCopyMemory(OutputBuffer, PrevEntry->Buffer, prevBufferSize); // copy previous filePath
+			RtlCopyMemory(OutputBuffer, PrevEntry->filePath.Buffer, prevBufferSize); // copy previous filePath
 			OutputBuffer += prevBufferSize;
 			outHeader.addSize(prevBufferSize);
 			*ReturnOutputBufferLength += prevBufferSize;
@@ -423,6 +527,7 @@ LIST_ENTRY DriverData::GetAllEntries()
 	KIRQL irql = KeGetCurrentIrql();
 	KeAcquireSpinLock(&irpOpsLock, &irql);
 	irpOpsSize = 0;
+	irpPathBytes = 0;
 	newList = irpOps;
 	InitializeListHead(&irpOps);
 
@@ -430,6 +535,70 @@ LIST_ENTRY DriverData::GetAllEntries()
 	return newList;
 }
 
+//#######################################################################################
+//# Backpressure handling
+//#######################################################################################
+
+VOID DriverData::AggregateIrpAux(PDRIVER_MESSAGE irpMsg) {
+	PIRP_AGGREGATE aggregate = nullptr;
+	for (ULONG i = 0; i < aggregatesSize; i++) {
+		if (aggregates[i].PID == irpMsg->PID && aggregates[i].Gid == irpMsg->Gid) {
+			aggregate = &aggregates[i];
+			break;
+		}
+	}
+	if (aggregate == nullptr) {
+		if (aggregatesSize == MAX_AGGREGATES) {
+			droppedIrps++;
+			return;
+		}
+		aggregate = &aggregates[aggregatesSize++];
+		RtlZeroMemory(aggregate, sizeof(IRP_AGGREGATE));
+		aggregate->Gid = irpMsg->Gid;
+		aggregate->PID = irpMsg->PID;
+	}
+	if (irpMsg->IRP_OP <= IRP_CLEANUP) aggregate->Ops[irpMsg->IRP_OP]++;
+	if (irpMsg->FileChange <= FILE_CHANGE_RAW_DISK_WRITE) aggregate->FileChanges[irpMsg->FileChange]++;
+	if (irpMsg->IRP_OP == IRP_READ) {
+		aggregate->BytesRead += irpMsg->MemSizeUsed;
+	}
+	else if (irpMsg->IRP_OP == IRP_WRITE) {
+		aggregate->BytesWritten += irpMsg->MemSizeUsed;
+		if (irpMsg->isEntropyCalc && irpMsg->Entropy > AGGREGATE_HIGH_ENTROPY) aggregate->HighEntropyWrites++;
+	}
+	aggregatedIrps++;
+}
+
+VOID DriverData::SetBackpressure(BOOLEAN Backpressure, ULONGLONG QueueDepth) {
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&irpOpsLock, &irql);
+	if (backpressure != Backpressure) {
+		DbgPrint("Backpressure %d, application queue depth %llu\n", Backpressure, QueueDepth);
+	}
+	backpressure = Backpressure;
+	appQueueDepth = QueueDepth;
+	KeReleaseSpinLock(&irpOpsLock, irql);
+}
+
+VOID DriverData::GetAggregates(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutputBufferLength) {
+	PAGGREGATES_REPLY header = (PAGGREGATES_REPLY)Buffer;
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&irpOpsLock, &irql);
+	ULONG count = min(aggregatesSize, (BufferSize - sizeof(AGGREGATES_REPLY)) / sizeof(IRP_AGGREGATE));
+	RtlCopyMemory((PCHAR)Buffer + sizeof(AGGREGATES_REPLY), aggregates, count * sizeof(IRP_AGGREGATE));
+	header->aggregatedIrps = aggregatedIrps;
+	header->droppedIrps = droppedIrps;
+	header->numAggregates = count;
+	header->backpressure = backpressure;
+	*ReturnOutputBufferLength = sizeof(AGGREGATES_REPLY) + count * sizeof(IRP_AGGREGATE);
+	// the aggregates which do not fit are kept for the next call
+	RtlMoveMemory(aggregates, aggregates + count, (aggregatesSize - count) * sizeof(IRP_AGGREGATE));
+	aggregatesSize -= count;
+	aggregatedIrps = 0;
+	droppedIrps = 0;
+	KeReleaseSpinLock(&irpOpsLock, irql);
+}
+
 //#######################################################################################
 //# Directory handling
 //#######################################################################################
@@ -555,4 +724,113 @@ VOID DriverData::ClearDirectories() {
 	directoryRootsSize = 0;
 	InitializeListHead(&rootDirectories);
 	KeReleaseSpinLock(&directoriesSpinLock, irql);
+}
+
+//#######################################################################################
+//# Self protection handling
+//#######################################################################################
+
+BOOLEAN DriverData::AddProtectedPid(ULONG Pid) {
+	BOOLEAN ret = FALSE;
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&protectionLock, &irql);
+	for (ULONG i = 0; i < protectedPidsSize; i++) {
+		if (protectedPids[i] == Pid) {
+			ret = TRUE;
+			break;
+		}
+	}
+	if (!ret && protectedPidsSize < MAX_PROTECTED_PIDS) {
+		protectedPids[protectedPidsSize++] = Pid;
+		ret = TRUE;
+	}
+	KeReleaseSpinLock(&protectionLock, irql);
+	return ret;
+}
+
+VOID DriverData::RemoveProtectedPid(ULONG Pid) {
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&protectionLock, &irql);
+	for (ULONG i = 0; i < protectedPidsSize; i++) {
+		if (protectedPids[i] == Pid) {
+			protectedPids[i] = protectedPids[--protectedPidsSize];
+			break;
+		}
+	}
+	KeReleaseSpinLock(&protectionLock, irql);
+}
+
+BOOLEAN DriverData::IsProtectedPid(ULONG Pid) {
+	BOOLEAN ret = FALSE;
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&protectionLock, &irql);
+	for (ULONG i = 0; i < protectedPidsSize; i++) {
+		if (protectedPids[i] == Pid) {
+			ret = TRUE;
+			break;
+		}
+	}
+	KeReleaseSpinLock(&protectionLock, irql);
+	return ret;
+}
+
+VOID DriverData::ClearProtectedPids() {
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&protectionLock, &irql);
+	protectedPidsSize = 0;
+	tamperAttemptsSize = 0;
+	KeReleaseSpinLock(&protectionLock, irql);
+}
+
+VOID DriverData::AddTamperAttempt(ULONG SourcePid, ULONG TargetPid, ULONG DesiredAccess) {
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&protectionLock, &irql);
+	if (tamperAttemptsSize == MAX_TAMPER_ATTEMPTS) { // drop the oldest
+		RtlMoveMemory(tamperAttempts, tamperAttempts + 1, sizeof(TAMPER_ATTEMPT) * (MAX_TAMPER_ATTEMPTS - 1));
+		tamperAttemptsSize--;
+	}
+	tamperAttempts[tamperAttemptsSize].sourcePid = SourcePid;
+	tamperAttempts[tamperAttemptsSize].targetPid = TargetPid;
+	tamperAttempts[tamperAttemptsSize].desiredAccess = DesiredAccess;
+	tamperAttemptsSize++;
+	KeReleaseSpinLock(&protectionLock, irql);
+}
+
+VOID DriverData::GetTamperAttempts(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutputBufferLength) {
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&protectionLock, &irql);
+	ULONG count = min(tamperAttemptsSize, BufferSize / sizeof(TAMPER_ATTEMPT));
+	RtlCopyMemory(Buffer, tamperAttempts, count * sizeof(TAMPER_ATTEMPT));
+	*ReturnOutputBufferLength = count * sizeof(TAMPER_ATTEMPT);
+	tamperAttemptsSize = 0;
+	KeReleaseSpinLock(&protectionLock, irql);
+}
+
+
+//#######################################################################################
+//# Process creations handling
+//#######################################################################################
+
+VOID DriverData::AddSpoofedParent(ULONG Pid, ULONG ParentPid, ULONG CreatorPid) {
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&spoofedParentsLock, &irql);
+	if (spoofedParentsSize == MAX_SPOOFED_PARENTS) { // drop the oldest
+		RtlMoveMemory(spoofedParents, spoofedParents + 1, sizeof(SPOOFED_PARENT) * (MAX_SPOOFED_PARENTS - 1));
+		spoofedParentsSize--;
+	}
+	spoofedParents[spoofedParentsSize].pid = Pid;
+	spoofedParents[spoofedParentsSize].parentPid = ParentPid;
+	spoofedParents[spoofedParentsSize].creatorPid = CreatorPid;
+	spoofedParentsSize++;
+	KeReleaseSpinLock(&spoofedParentsLock, irql);
+}
+
+VOID DriverData::GetSpoofedParents(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutputBufferLength) {
+	KIRQL irql = KeGetCurrentIrql();
+	KeAcquireSpinLock(&spoofedParentsLock, &irql);
+	ULONG count = min(spoofedParentsSize, BufferSize / sizeof(SPOOFED_PARENT));
+	RtlCopyMemory(Buffer, spoofedParents, count * sizeof(SPOOFED_PARENT));
+	*ReturnOutputBufferLength = count * sizeof(SPOOFED_PARENT);
+	spoofedParentsSize = 0;
+	KeReleaseSpinLock(&spoofedParentsLock, irql);
 }
\ No newline at end of file
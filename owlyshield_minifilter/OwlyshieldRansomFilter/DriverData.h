@@ -16,8 +16,17 @@ class DriverData
 	ULONG pid; // pid of the current connected user mode application, set by communication
 	
 	ULONG irpOpsSize; // number of irp ops waiting in entry_list
+	ULONG irpPathBytes; // bytes allocated for the paths of the irp ops waiting in entry_list
 	LIST_ENTRY irpOps; // list entry bdirectional list of irp ops
 	KSPIN_LOCK irpOpsLock; // lock for irp list ops
+
+	/* Backpressure data members, protected by irpOpsLock */
+	BOOLEAN backpressure; // the application falls behind: irps are aggregated instead of queued
+	ULONGLONG appQueueDepth; // messages waiting in the application, as of its last report
+	IRP_AGGREGATE aggregates[MAX_AGGREGATES]; // aggregates waiting to be reported to the application
+	ULONG aggregatesSize;
+	ULONGLONG aggregatedIrps; // irps summarized since the last report
+	ULONGLONG droppedIrps; // irps lost since the last report
 	
 	ULONG directoryRootsSize;  // number of protected dirs in list
 	LIST_ENTRY rootDirectories;  // list entry bdirectional of protected dirs
@@ -31,6 +40,18 @@ class DriverData
 	LIST_ENTRY GidsList;  // list entry of gids, used to clear memory 
 	KSPIN_LOCK GIDSystemLock;
 
+	/* Self protection data members */
+	ULONG protectedPids[MAX_PROTECTED_PIDS]; // pids which cannot be terminated, suspended or written by other processes
+	ULONG protectedPidsSize;
+	TAMPER_ATTEMPT tamperAttempts[MAX_TAMPER_ATTEMPTS]; // attempts waiting to be reported to the application
+	ULONG tamperAttemptsSize;
+	KSPIN_LOCK protectionLock;
+
+	/* Process creations data members */
+	SPOOFED_PARENT spoofedParents[MAX_SPOOFED_PARENTS]; // creations waiting to be reported to the application
+	ULONG spoofedParentsSize;
+	KSPIN_LOCK spoofedParentsLock;
+
 
 private:
 	// call assumes protected code - high IRQL
@@ -39,6 +60,9 @@ private:
 	// call assumes protected code - high IRQL
 	BOOLEAN RemoveGidRecordAux(PGID_ENTRY gidRecord);
 
+	// adds an irp to the aggregate of its pid, call assumes protected code - high IRQL
+	VOID AggregateIrpAux(PDRIVER_MESSAGE irpMsg);
+
 public:
 
 	// c'tor init D.S.
@@ -76,6 +100,24 @@ public:
 	// if found return true on found else return false
 	ULONGLONG GetProcessGid(ULONG ProcessId, PBOOLEAN found);
 
+	// stops reporting the irps of a gid until it ends, returns false if there is no such gid, function raise IRQL
+	BOOLEAN MuteGid(ULONGLONG gid);
+
+	// function raise IRQL
+	BOOLEAN IsGidMuted(ULONGLONG gid);
+
+	// reports the irps of the gid of a process again, returns true if it was muted, function raise IRQL
+	BOOLEAN UnmuteProcessGid(ULONG ProcessId);
+
+	// starts or stops the capture of the files of a gid before they are written or deleted, returns false if there is no such gid, function raise IRQL
+	BOOLEAN SetGidCapture(ULONGLONG gid, BOOLEAN captured);
+
+	// function raise IRQL
+	BOOLEAN IsGidCaptured(ULONGLONG gid);
+
+	// stops the capture of all the gids, function raise IRQL
+	VOID ClearGidCaptures();
+
 	//clear all data related to Gid system
 	VOID ClearGidsPids();
 	
@@ -96,13 +138,37 @@ public:
 	PFLT_FILTER* getFilterAdd() { return &Filter; }
 	PFLT_FILTER getFilter() { return Filter; }
 	ULONG getPID() { return pid; }
-	ULONG setPID(ULONG Pid) { pid = Pid; return Pid; }
+	// a new application connects: it replaces the previous protected pids
+	ULONG setPID(ULONG Pid) { pid = Pid; ClearProtectedPids(); AddProtectedPid(Pid); return Pid; }
+
+	// adds a pid to the protected list, returns false if the list is full, function raise IRQL
+	BOOLEAN AddProtectedPid(ULONG Pid);
+
+	// removes a pid which ended from the protected list, function raise IRQL
+	VOID RemoveProtectedPid(ULONG Pid);
+
+	BOOLEAN IsProtectedPid(ULONG Pid);
+
+	VOID ClearProtectedPids();
+
+	// records a tamper attempt, the oldest ones are dropped when the list is full, function raise IRQL
+	VOID AddTamperAttempt(ULONG SourcePid, ULONG TargetPid, ULONG DesiredAccess);
+
+	// copies the tamper attempts to a buffer and clears them, function raise IRQL
+	VOID GetTamperAttempts(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutputBufferLength);
+
+	// records a process created with a parent other than its creator, the oldest ones are dropped when the list is full, function raise IRQL
+	VOID AddSpoofedParent(ULONG Pid, ULONG ParentPid, ULONG CreatorPid);
+
+	// copies the spoofed parents to a buffer and clears them, function raise IRQL
+	VOID GetSpoofedParents(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutputBufferLength);
 
 	// clears all irps waiting to report, function raise IRQL
 	VOID ClearIrps();
 
 	ULONG IrpSize();
 
+	// queues an irp, or aggregates it under backpressure or if the list is full: returns false if the entry was not queued
 	BOOLEAN AddIrpMessage(PIRP_ENTRY newEntry);
 
 	BOOLEAN RemIrpMessage(PIRP_ENTRY newEntry);
@@ -114,6 +180,12 @@ public:
 
 	LIST_ENTRY GetAllEntries();
 
+	// the application reports the depth of its queue, and whether irps should be aggregated, function raise IRQL
+	VOID SetBackpressure(BOOLEAN Backpressure, ULONGLONG QueueDepth);
+
+	// copies the aggregates to a buffer, after an AGGREGATES_REPLY header, and clears them, function raise IRQL
+	VOID GetAggregates(PVOID Buffer, ULONG BufferSize, PULONG ReturnOutputBufferLength);
+
 	BOOLEAN AddDirectoryEntry(PDIRECTORY_ENTRY newEntry);
 
 	PDIRECTORY_ENTRY RemDirectoryEntry(LPCWSTR directory);
@@ -135,6 +207,9 @@ public:
 		// clear gid system
 		ClearGidsPids();
 
+		// clear self protection
+		ClearProtectedPids();
+
 	}
 };
 
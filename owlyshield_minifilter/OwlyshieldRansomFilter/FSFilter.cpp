@@ -18,6 +18,9 @@ Environment:
 
 #pragma prefast(disable:__WARNING_ENCODE_MEMBER_FUNCTION_POINTER, "Not valid for kernel mode drivers")
 
+//  Registration of FSProcessHandlePreOperation, NULL if the self protection is not active
+PVOID ObCallbackHandle = NULL;
+
 //  Structure that contains all the global data structures used throughout the driver.
 
 EXTERN_C_START
@@ -166,6 +169,15 @@ Return Value:
 	// new code
 	// FIXME: check status and release in unload
 	PsSetCreateProcessNotifyRoutine(AddRemProcessRoutine, FALSE);
+	status = PsSetLoadImageNotifyRoutine(LoadImageRoutine);
+	if (!NT_SUCCESS(status)) {
+		DbgPrint("!!! FSFilter: Failed to register the image load routine: %#010x\n", status);
+	}
+	// self protection is not mandatory, the filter works without it
+	status = FSRegisterSelfProtection();
+	if (!NT_SUCCESS(status)) {
+		DbgPrint("!!! FSFilter: Failed to register self protection: %#010x\n", status);
+	}
 	return STATUS_SUCCESS;
 }
 
@@ -193,6 +205,13 @@ Return Value:
 {
 	UNREFERENCED_PARAMETER(Flags);
 
+	//
+	//  The callbacks read driverData: unregister them before it is freed
+	//
+	FSUnregisterSelfProtection();
+	PsSetCreateProcessNotifyRoutine(AddRemProcessRoutine, TRUE);
+	PsRemoveLoadImageNotifyRoutine(LoadImageRoutine);
+
 	//
 	//  Close the server port.
 	//
@@ -206,7 +225,6 @@ Return Value:
 	FltUnregisterFilter(driverData->getFilter());
 	delete driverData;
 	delete commHandle;
-	PsSetCreateProcessNotifyRoutine(AddRemProcessRoutine, TRUE);
 	return STATUS_SUCCESS;
 }
 
@@ -411,6 +429,11 @@ Return Value:
 	if (FltObjects->FileObject == NULL) { //no file object
 		return FLT_PREOP_SUCCESS_NO_CALLBACK;
 	}
+	// writes on a volume handle bypass the files (boot sector, MFT...)
+	if (Data->Iopb->MajorFunction == IRP_MJ_WRITE && FlagOn(FltObjects->FileObject->Flags, FO_VOLUME_OPEN)) {
+		FSReportVolumeWrite(Data, FltObjects);
+		return FLT_PREOP_SUCCESS_NO_CALLBACK;
+	}
 	// create tested only on post op, cant check here
 	if (Data->Iopb->MajorFunction == IRP_MJ_CREATE) {
 		return FLT_PREOP_SUCCESS_WITH_CALLBACK;
@@ -470,7 +493,7 @@ FSProcessPreOperartion(
 
 	BOOLEAN isGidFound;
 	ULONGLONG gid = driverData->GetProcessGid(newItem->PID, &isGidFound);
-	if (gid == 0 || !isGidFound) {
+	if (gid == 0 || !isGidFound || driverData->IsGidMuted(gid)) {
 		if (IS_DEBUG_IRP) DbgPrint("!!! FSFilter: Item does not have a gid, skipping\n");
 		FltReferenceFileNameInformation(nameInfo);
 		delete newEntry;
@@ -593,11 +616,10 @@ FSProcessPreOperartion(
 			newItem->FileChange = FILE_CHANGE_RENAME_FILE;
 			PFILE_RENAME_INFORMATION renameInfo = (PFILE_RENAME_INFORMATION)Data->Iopb->Parameters.SetFileInformation.InfoBuffer;
 			PFLT_FILE_NAME_INFORMATION newNameInfo;
-			WCHAR Buffer[MAX_FILE_NAME_LENGTH];
-			UNICODE_STRING NewFilePath;
-			NewFilePath.Buffer = Buffer;
+			UNICODE_STRING NewFilePath; // allocated to its size by GetFileNameInfo
+			NewFilePath.Buffer = nullptr;
 			NewFilePath.Length = 0;
-			NewFilePath.MaximumLength = MAX_FILE_NAME_SIZE;
+			NewFilePath.MaximumLength = 0;
 			
 			hr = FltGetDestinationFileNameInformation(
 				FltObjects->Instance,
@@ -615,13 +637,15 @@ FSProcessPreOperartion(
 
 			NTSTATUS status = GetFileNameInfo(FltObjects, &NewFilePath, newNameInfo);
 			if (!NT_SUCCESS(status)) {
+				FreePathBuffer(&NewFilePath);
 				delete newEntry;
 				FltReleaseFileNameInformation(nameInfo);
 				FltReleaseFileNameInformation(newNameInfo);
 				return FLT_PREOP_SUCCESS_NO_CALLBACK;
 			}
 
-			RtlCopyBytes(newEntry->Buffer, Buffer, MAX_FILE_NAME_SIZE); // replace buffer data with new file
+			FreePathBuffer(&newEntry->filePath); // replace buffer data with new file
+			newEntry->filePath = NewFilePath;
 			newItem->FileLocationInfo = FILE_MOVED_OUT;
 			/*
 			if (FSIsFileNameInScanDirs(&NewFilePath)) {
@@ -667,6 +691,9 @@ FSProcessPreOperartion(
 		delete newEntry;
 		return FLT_PREOP_SUCCESS_NO_CALLBACK;
 	}
+	if ((newItem->FileChange == FILE_CHANGE_WRITE || newItem->FileChange == FILE_CHANGE_DELETE_FILE) && driverData->IsGidCaptured(gid)) {
+		FSCaptureBeforeChange(Data, newItem, &newEntry->filePath);
+	}
 	if (IS_DEBUG_IRP) DbgPrint("!!! FSFilter: Adding entry to irps %s\n", FltGetIrpName(Data->Iopb->MajorFunction));
 	if (!driverData->AddIrpMessage(newEntry)) {
 		delete newEntry;
@@ -675,6 +702,100 @@ FSProcessPreOperartion(
 
 }
 
+VOID
+FSCaptureBeforeChange(
+	_In_ PFLT_CALLBACK_DATA Data,
+	_In_ PDRIVER_MESSAGE Item,
+	_In_ PUNICODE_STRING FilePath
+)
+/*++
+
+Routine Description:
+
+	Sends a CAPTURE_REQUEST to the application, which copies the file, and holds the write or the
+	deletion until it replies, CAPTURE_TIMEOUT_MS at most. The paging writes, which may come at
+	APC level, and the paths longer than MAX_FILE_NAME_LENGTH are let through.
+
+--*/
+{
+	if (FlagOn(Data->Iopb->IrpFlags, IRP_PAGING_IO) || KeGetCurrentIrql() != PASSIVE_LEVEL) {
+		return;
+	}
+	if (FilePath->Length > MAX_FILE_NAME_SIZE || IsCommClosed()) {
+		return;
+	}
+	PCAPTURE_REQUEST request = new CAPTURE_REQUEST();
+	if (request == NULL) {
+		return;
+	}
+	request->Gid = Item->Gid;
+	request->FileID = Item->FileID;
+	request->PID = Item->PID;
+	request->FileChange = Item->FileChange;
+	request->PathLength = FilePath->Length / sizeof(WCHAR);
+	RtlCopyMemory(request->Path, FilePath->Buffer, FilePath->Length);
+
+	CAPTURE_REPLY reply = { 0 };
+	ULONG replyLength = sizeof(CAPTURE_REPLY);
+	LARGE_INTEGER timeout;
+	timeout.QuadPart = -10000LL * CAPTURE_TIMEOUT_MS; // relative, in 100 ns
+	NTSTATUS status = FltSendMessage(driverData->getFilter(), &commHandle->ClientPort, request, sizeof(CAPTURE_REQUEST), &reply, &replyLength, &timeout);
+	if (status == STATUS_TIMEOUT) {
+		DbgPrint("!!! FSFilter: Capture timed out for gid %llu\n", Item->Gid);
+	}
+	else if (IS_DEBUG_IRP && NT_SUCCESS(status)) {
+		DbgPrint("!!! FSFilter: Capture for gid %llu: %d\n", Item->Gid, reply.captured);
+	}
+	delete request;
+}
+
+VOID
+FSReportVolumeWrite(
+	_Inout_ PFLT_CALLBACK_DATA Data,
+	_In_ PCFLT_RELATED_OBJECTS FltObjects
+)
+/*++
+
+Routine Description:
+
+	Reports a write on a volume handle (\\.\C:) as FILE_CHANGE_RAW_DISK_WRITE, with the volume
+	name as path. There is no file name nor file id for those.
+
+--*/
+{
+	if (driverData->isFilterClosed() || IsCommClosed()) {
+		return;
+	}
+	ULONG pid = FltGetRequestorProcessId(Data);
+	BOOLEAN isGidFound;
+	ULONGLONG gid = driverData->GetProcessGid(pid, &isGidFound);
+	if (gid == 0 || !isGidFound || driverData->IsGidMuted(gid)) {
+		return;
+	}
+	PIRP_ENTRY newEntry = new IRP_ENTRY();
+	if (newEntry == NULL) {
+		return;
+	}
+	PDRIVER_MESSAGE newItem = &newEntry->data;
+	ULONG bufferSizeRequired = 0;
+	// first call with an empty buffer for the size of the name
+	if (FltGetVolumeName(FltObjects->Volume, NULL, &bufferSizeRequired) != STATUS_BUFFER_TOO_SMALL
+		|| !NT_SUCCESS(AllocatePathBuffer(&newEntry->filePath, bufferSizeRequired))
+		|| !NT_SUCCESS(FltGetVolumeName(FltObjects->Volume, &newEntry->filePath, &bufferSizeRequired))) {
+		FreePathBuffer(&newEntry->filePath);
+	}
+	newItem->PID = pid;
+	newItem->Gid = gid;
+	newItem->IRP_OP = IRP_WRITE;
+	newItem->FileChange = FILE_CHANGE_RAW_DISK_WRITE;
+	newItem->MemSizeUsed = Data->Iopb->Parameters.Write.Length;
+
+	if (IS_DEBUG_IRP) DbgPrint("!!! FSFilter: Volume write for Gid: %d with pid: %d\n", gid, pid);
+	if (!driverData->AddIrpMessage(newEntry)) {
+		delete newEntry;
+	}
+}
+
 FLT_POSTOP_CALLBACK_STATUS
 FSPostOperation(
 	_Inout_ PFLT_CALLBACK_DATA Data,
@@ -773,7 +894,7 @@ FSProcessCreateIrp(
 
 	BOOLEAN isGidFound;
 	ULONGLONG gid = driverData->GetProcessGid(newItem->PID, &isGidFound);
-	if (gid == 0 || !isGidFound) {
+	if (gid == 0 || !isGidFound || driverData->IsGidMuted(gid)) {
 		//DbgPrint("!!! FSFilter: Item does not have a gid, skipping\n"); // TODO: incase it doesnt exist we can add it with our method that checks for system process
 		FltReferenceFileNameInformation(nameInfo);
 		delete newEntry;
@@ -960,10 +1081,10 @@ FSEntrySetFileName(
 {
 	NTSTATUS hr = STATUS_SUCCESS;
 	PDEVICE_OBJECT devObject;
-	USHORT volumeDosNameSize;
-	USHORT finalNameSize;
-	USHORT volumeNameSize = nameInfo->Volume.Length; // in bytes
-	USHORT origNameSize = nameInfo->Name.Length; // in bytes
+	ULONG volumeDosNameSize;
+	ULONG finalNameSize;
+	ULONG volumeNameSize = nameInfo->Volume.Length; // in bytes
+	ULONG origNameSize = nameInfo->Name.Length; // in bytes
 	
 	WCHAR newTemp[40]; 
 
@@ -985,6 +1106,7 @@ FSEntrySetFileName(
 	}
 	volumeDosNameSize = GvolumeData.Length;
 	finalNameSize = origNameSize - volumeNameSize + volumeDosNameSize; // not null terminated, in bytes
+	if (finalNameSize > MAX_PATH_SIZE) finalNameSize = MAX_PATH_SIZE;
 
 	//DbgPrint("Volume name: %wZ, Size: %d, finalNameSize: %d, volumeNameSize: %d\n", volumeData, volumeDosNameSize, finalNameSize, volumeNameSize);
 	//DbgPrint("Name buffer: %wZ\n", nameInfo->Name);
@@ -995,17 +1117,25 @@ FSEntrySetFileName(
 	}
 	if (volumeNameSize == origNameSize) { // file is the volume, don't need to do anything
 		ObDereferenceObject(devObject);
+		if (!NT_SUCCESS(hr = AllocatePathBuffer(uString, origNameSize))) {
+			return hr;
+		}
 		return RtlUnicodeStringCopy(uString, &nameInfo->Name);
 	}
+	// the buffer is allocated to the size of the name, up to MAX_PATH_SIZE
+	if (!NT_SUCCESS(hr = AllocatePathBuffer(uString, finalNameSize))) {
+		ObDereferenceObject(devObject);
+		return hr;
+	}
 	
-	if (NT_SUCCESS(hr = RtlUnicodeStringCopy(uString, &GvolumeData))) {// prefix of volume e.g. C:
+	if (volumeDosNameSize <= finalNameSize && NT_SUCCESS(hr = RtlUnicodeStringCopy(uString, &GvolumeData))) {// prefix of volume e.g. C:
 
 		DbgPrint("File name: %wZ\n", uString);
 		RtlCopyMemory(uString->Buffer + (volumeDosNameSize / 2),
 			nameInfo->Name.Buffer + (volumeNameSize / 2),
-			((finalNameSize - volumeDosNameSize > MAX_FILE_NAME_SIZE - volumeDosNameSize) ? (MAX_FILE_NAME_SIZE - volumeDosNameSize) : (finalNameSize - volumeDosNameSize))
+			finalNameSize - volumeDosNameSize
 		);
-		uString->Length = (finalNameSize > MAX_FILE_NAME_SIZE) ? MAX_FILE_NAME_SIZE : finalNameSize;
+		uString->Length = (USHORT)finalNameSize;
 		DbgPrint("File name: %wZ\n", uString);	
 	}
 	ObDereferenceObject(devObject);
@@ -1087,6 +1217,12 @@ VOID AddRemProcessRoutine(
 ) {
 	if (commHandle->CommClosed) return;
 	if (Create) {
+		// the routine runs in the context of the creator, which may have given another parent (parent pid spoofing)
+		ULONG creatorPid = (ULONG)(ULONG_PTR)PsGetCurrentProcessId();
+		if (creatorPid != (ULONG)(ULONG_PTR)ParentId) {
+			DbgPrint("!!! FSFilter: Pid %d created by pid %d with parent %d\n", (ULONG)(ULONG_PTR)ProcessId, creatorPid, (ULONG)(ULONG_PTR)ParentId);
+			driverData->AddSpoofedParent((ULONG)(ULONG_PTR)ProcessId, (ULONG)(ULONG_PTR)ParentId, creatorPid);
+		}
 		NTSTATUS hr;
 		if (ZwQueryInformationProcess == NULL)
 		{
@@ -1182,5 +1318,73 @@ VOID AddRemProcessRoutine(
 	else {
 		DbgPrint("!!! FSFilter: Terminate Process, Process: %d pid\n", (ULONG)(ULONG_PTR)ProcessId);
 		driverData->RemoveProcess((ULONG)(ULONG_PTR)ProcessId); 
+		driverData->RemoveProtectedPid((ULONG)(ULONG_PTR)ProcessId);
+	}
+}
+
+VOID LoadImageRoutine(
+	PUNICODE_STRING FullImageName,
+	HANDLE ProcessId,
+	PIMAGE_INFO ImageInfo
+) {
+	UNREFERENCED_PARAMETER(ImageInfo);
+	if (commHandle->CommClosed || ProcessId == 0 || FullImageName == NULL) return;
+	if (startsWith(FullImageName, driverData->GetSystemRootPath())) return;
+	if (driverData->UnmuteProcessGid((ULONG)(ULONG_PTR)ProcessId)) {
+		DbgPrint("!!! FSFilter: Unmuted pid %d, image loaded: %wZ\n", (ULONG)(ULONG_PTR)ProcessId, FullImageName);
+	}
+}
+
+// self protection of the user mode application
+OB_PREOP_CALLBACK_STATUS
+FSProcessHandlePreOperation(
+	_In_ PVOID RegistrationContext,
+	_Inout_ POB_PRE_OPERATION_INFORMATION OperationInformation
+) {
+	UNREFERENCED_PARAMETER(RegistrationContext);
+	if (OperationInformation->KernelHandle || commHandle->CommClosed) return OB_PREOP_SUCCESS;
+
+	ULONG targetPid = (ULONG)(ULONG_PTR)PsGetProcessId((PEPROCESS)OperationInformation->Object);
+	ULONG sourcePid = (ULONG)(ULONG_PTR)PsGetCurrentProcessId();
+	if (sourcePid == targetPid || !driverData->IsProtectedPid(targetPid) || driverData->IsProtectedPid(sourcePid)) {
+		return OB_PREOP_SUCCESS;
+	}
+
+	PACCESS_MASK desiredAccess = (OperationInformation->Operation == OB_OPERATION_HANDLE_CREATE) ?
+		&OperationInformation->Parameters->CreateHandleInformation.DesiredAccess :
+		&OperationInformation->Parameters->DuplicateHandleInformation.DesiredAccess;
+	if (*desiredAccess & SELF_PROTECTION_DENIED_ACCESS) {
+		DbgPrint("!!! FSFilter: Tamper attempt on pid %d from pid %d, access %#010x\n", targetPid, sourcePid, *desiredAccess);
+		driverData->AddTamperAttempt(sourcePid, targetPid, *desiredAccess);
+		*desiredAccess &= ~SELF_PROTECTION_DENIED_ACCESS;
+	}
+	return OB_PREOP_SUCCESS;
+}
+
+NTSTATUS FSRegisterSelfProtection() {
+	OB_OPERATION_REGISTRATION operation = { 0 };
+	operation.ObjectType = PsProcessType;
+	operation.Operations = OB_OPERATION_HANDLE_CREATE | OB_OPERATION_HANDLE_DUPLICATE;
+	operation.PreOperation = FSProcessHandlePreOperation;
+	operation.PostOperation = NULL;
+
+	OB_CALLBACK_REGISTRATION registration = { 0 };
+	registration.Version = OB_FLT_REGISTRATION_VERSION;
+	registration.OperationRegistrationCount = 1;
+	RtlInitUnicodeString(&registration.Altitude, L"378781");
+	registration.RegistrationContext = NULL;
+	registration.OperationRegistration = &operation;
+
+	NTSTATUS status = ObRegisterCallbacks(&registration, &ObCallbackHandle);
+	if (!NT_SUCCESS(status)) {
+		ObCallbackHandle = NULL;
+	}
+	return status;
+}
+
+VOID FSUnregisterSelfProtection() {
+	if (ObCallbackHandle != NULL) {
+		ObUnRegisterCallbacks(ObCallbackHandle);
+		ObCallbackHandle = NULL;
 	}
 }
\ No newline at end of file
@@ -83,6 +83,21 @@ FSProcessPreOperartion(
 	_Flt_CompletionContext_Outptr_ PVOID *CompletionContext
 );
 
+// reports writes on volume handles, which have no file
+VOID
+FSReportVolumeWrite(
+	_Inout_ PFLT_CALLBACK_DATA Data,
+	_In_ PCFLT_RELATED_OBJECTS FltObjects
+);
+
+// asks the application to capture a file before a write or a deletion by a captured gid
+VOID
+FSCaptureBeforeChange(
+	_In_ PFLT_CALLBACK_DATA Data,
+	_In_ PDRIVER_MESSAGE Item,
+	_In_ PUNICODE_STRING FilePath
+);
+
 NTSTATUS
 FSEntrySetFileName(
 	const PFLT_VOLUME volume,
@@ -162,5 +177,32 @@ VOID AddRemProcessRoutine(
 	BOOLEAN Create
 );
 
+// LoadImageRoutine is the function hooked to the images loads.
+// A muted gid loading an image outside of SystemRoot is reported again.
+
+VOID LoadImageRoutine(
+	PUNICODE_STRING FullImageName,
+	HANDLE ProcessId,
+	PIMAGE_INFO ImageInfo
+);
+
 UNICODE_STRING GvolumeData;
 
+// accesses to a protected process which are stripped from handles opened by other processes:
+// PROCESS_TERMINATE, PROCESS_CREATE_THREAD, PROCESS_VM_OPERATION, PROCESS_VM_WRITE, PROCESS_SUSPEND_RESUME
+#define SELF_PROTECTION_DENIED_ACCESS (0x0001 | 0x0002 | 0x0008 | 0x0020 | 0x0800)
+
+// FSProcessHandlePreOperation is called when a handle to a process is created or duplicated.
+// If the target is a protected pid and the caller is not, dangerous accesses are removed and
+// the attempt is recorded for the user mode application
+OB_PREOP_CALLBACK_STATUS
+FSProcessHandlePreOperation(
+	_In_ PVOID RegistrationContext,
+	_Inout_ POB_PRE_OPERATION_INFORMATION OperationInformation
+);
+
+// registers FSProcessHandlePreOperation, requires the /INTEGRITYCHECK linker flag
+NTSTATUS FSRegisterSelfProtection();
+
+VOID FSUnregisterSelfProtection();
+
@@ -49,23 +49,49 @@ typedef struct _DIRECTORY_ENTRY {
 
 } DIRECTORY_ENTRY, *PDIRECTORY_ENTRY;
 
+// frees the buffer of a path allocated by AllocatePathBuffer
+inline VOID FreePathBuffer(PUNICODE_STRING path) {
+	if (path->Buffer != nullptr) {
+		ExFreePoolWithTag(path->Buffer, 'RWp');
+	}
+	path->Buffer = nullptr;
+	path->Length = 0;
+	path->MaximumLength = 0;
+}
+
+// allocates the buffer of a path for size bytes (at most MAX_PATH_SIZE), freeing the previous one
+inline NTSTATUS AllocatePathBuffer(PUNICODE_STRING path, ULONG size) {
+	FreePathBuffer(path);
+	if (size > MAX_PATH_SIZE) size = MAX_PATH_SIZE;
+	if (size == 0) return STATUS_SUCCESS;
+	path->Buffer = (PWCH)ExAllocatePoolWithTag(NonPagedPool, size, 'RWp');
+	if (path->Buffer == nullptr) return STATUS_INSUFFICIENT_RESOURCES;
+	path->MaximumLength = (USHORT)size;
+	return STATUS_SUCCESS;
+}
+
 typedef struct _IRP_ENTRY {
 	LIST_ENTRY entry;
 	DRIVER_MESSAGE data;
-	UNICODE_STRING filePath; // keep path to unicode string related to the object, we copy it later to user
-	WCHAR Buffer[MAX_FILE_NAME_LENGTH]; // unicode string buffer for file name
+	UNICODE_STRING filePath; // keep path to unicode string related to the object, we copy it later to user. Its buffer is allocated to its size, see AllocatePathBuffer
 
 	_IRP_ENTRY() {
 		filePath.Length = 0;
-		filePath.MaximumLength = MAX_FILE_NAME_SIZE;
-		filePath.Buffer = Buffer;
-		RtlZeroBytes(Buffer, MAX_FILE_NAME_SIZE);
+		filePath.MaximumLength = 0;
+		filePath.Buffer = nullptr;
 		data.next = nullptr;
 		data.IRP_OP = IRP_NONE;
 		data.MemSizeUsed = 0;
 		data.isEntropyCalc = FALSE;
 		data.FileChange = FILE_CHANGE_NOT_SET;
 		data.FileLocationInfo = FILE_NOT_PROTECTED;
+		LARGE_INTEGER now;
+		KeQuerySystemTimePrecise(&now);
+		data.Timestamp = now.QuadPart;
+	}
+
+	~_IRP_ENTRY() {
+		FreePathBuffer(&filePath);
 	}
 
 	void* _IRP_ENTRY::operator new(size_t size)
@@ -102,6 +128,8 @@ struct GID_ENTRY {
 	ULONGLONG gid;
 	ULONGLONG pidsSize;
 	LIST_ENTRY HeadListPids;
+	BOOLEAN muted; // benign for the application: its irps are not reported anymore
+	BOOLEAN captured; // watched by the application: the files are captured before they are written or deleted
 
 	// gid as input
 	GID_ENTRY(ULONGLONG Gid) {
@@ -109,6 +137,8 @@ struct GID_ENTRY {
 		InitializeListHead(&HeadListPids);
 		InitializeListHead(&GidListEntry);
 		pidsSize = 0;
+		muted = FALSE;
+		captured = FALSE;
 	}
 
 	//copy
@@ -119,6 +149,8 @@ struct GID_ENTRY {
 		GidListEntry.Blink = a.GidListEntry.Blink;
 		gid = a.gid;
 		pidsSize = a.pidsSize;
+		muted = a.muted;
+		captured = a.captured;
 	}
 
 	const GID_ENTRY& operator=(const GID_ENTRY& a) {
@@ -128,6 +160,8 @@ struct GID_ENTRY {
 		GidListEntry.Blink = a.GidListEntry.Blink;
 		gid = a.gid;
 		pidsSize = a.pidsSize;
+		muted = a.muted;
+		captured = a.captured;
 		this;
 	}
 };
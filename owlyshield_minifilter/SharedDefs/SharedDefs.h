@@ -22,13 +22,15 @@ const PWSTR ComPortName = L"\\RWFilter";
 
 #define MAX_FILE_NAME_LENGTH 520
 #define MAX_FILE_NAME_SIZE (MAX_FILE_NAME_LENGTH * sizeof(WCHAR)) // max length in bytes of files sizes and dir paths
+#define MAX_PATH_SIZE 0xFFFE // max length in bytes of the path of a DRIVER_MESSAGE (32767 wchars, the max of a UNICODE_STRING)
 #define FILE_OBJECT_ID_SIZE 16
 #define FILE_OBJEC_MAX_EXTENSION_SIZE 11 
 //#define MAX_COMM_BUFFER_SIZE 0x100000 // size of the buffer we allocate to recieve irp ops from the driver 
 //#define MAX_OPS_SAVE 0x10000 // max ops to save, we limit this to prevent driver from filling the non paged memory and crashing the os
 
-#define MAX_COMM_BUFFER_SIZE 0x10000 // size of the buffer we allocate to recieve irp ops from the driver 
+#define MAX_COMM_BUFFER_SIZE 0x20000 // size of the buffer we allocate to recieve irp ops from the driver, fits a DRIVER_MESSAGE with a path of MAX_PATH_SIZE
 #define MAX_OPS_SAVE 0x1000 // max ops to save, we limit this to prevent driver from filling the non paged memory and crashing the os
+#define MAX_PATH_BYTES_SAVE 0x800000 // max bytes of the paths of the ops saved, as they are allocated to their size
 
 // msgs types that the application may send to the driver
 enum COM_MESSAGE_TYPE {
@@ -36,9 +38,56 @@ enum COM_MESSAGE_TYPE {
 	MESSAGE_REM_SCAN_DIRECTORY,
 	MESSAGE_GET_OPS,
 	MESSAGE_SET_PID,
-	MESSAGE_KILL_GID
+	MESSAGE_KILL_GID,
+	MESSAGE_ADD_PROTECTED_PID,
+	MESSAGE_GET_TAMPER_ATTEMPTS,
+	MESSAGE_MUTE_GID,
+	MESSAGE_SET_BACKPRESSURE,
+	MESSAGE_GET_AGGREGATES,
+	MESSAGE_GET_SPOOFED_PARENTS,
+	MESSAGE_CAPTURE_GID
 };
 
+#define MAX_PROTECTED_PIDS 16 // pids of the user mode application and its helpers, protected against tampering
+#define MAX_TAMPER_ATTEMPTS 64 // max tamper attempts kept until the application asks for them
+#define MAX_SPOOFED_PARENTS 64 // max spoofed parents kept until the application asks for them
+#define MAX_AGGREGATES 256 // max pids whose irps are aggregated until the application asks for them
+#define AGGREGATE_HIGH_ENTROPY 7.0 // entropy above which an aggregated write is counted in HighEntropyWrites
+#define CAPTURE_TIMEOUT_MS 2000 // max wait of a write or a deletion for the application to capture the file
+
+// reported when a process tried to open a protected process with a dangerous access
+typedef struct _TAMPER_ATTEMPT {
+	ULONG sourcePid; // pid of the process which opened the handle
+	ULONG targetPid; // protected pid
+	ULONG desiredAccess; // access mask asked, before it was stripped
+} TAMPER_ATTEMPT, *PTAMPER_ATTEMPT;
+
+// reported when a process was created with a parent other than its creator (PROC_THREAD_ATTRIBUTE_PARENT_PROCESS)
+typedef struct _SPOOFED_PARENT {
+	ULONG pid; // pid of the new process
+	ULONG parentPid; // parent given at the creation
+	ULONG creatorPid; // process which created it
+} SPOOFED_PARENT, *PSPOOFED_PARENT;
+
+// sent by the driver (FltSendMessage) before a write or a deletion by a captured gid (MESSAGE_CAPTURE_GID), so that the application copies the file first
+typedef struct _CAPTURE_REQUEST {
+	ULONGLONG Gid; // 8 bytes
+#ifdef _KERNEL_MODE
+	FILE_ID_INFORMATION FileID; // 24 bytes
+#else
+	FILE_ID_INFO FileID; // 24 bytes
+#endif
+	ULONG PID; // 4 bytes
+	UCHAR FileChange; // 1 byte + 1 byte align - FILE_CHANGE_WRITE or FILE_CHANGE_DELETE_FILE
+	USHORT PathLength; // 2 bytes - in WCHARs, not null terminated
+	WCHAR Path[MAX_FILE_NAME_LENGTH]; // 1040 bytes
+} CAPTURE_REQUEST, *PCAPTURE_REQUEST;
+
+// reply of the application to a CAPTURE_REQUEST
+typedef struct _CAPTURE_REPLY {
+	BOOLEAN captured;
+} CAPTURE_REPLY, *PCAPTURE_REPLY;
+
 // msgs struct that the application send when sending msg to the driver, type member should be one of the COM_MESSAGE_TYPE
 typedef struct _COM_MESSAGE {
 	ULONG type;
@@ -57,7 +106,8 @@ enum FILE_CHANGE_INFO {
 	FILE_CHANGE_EXTENSION_CHANGED,
 	FILE_CHANGE_DELETE_FILE,
 	FILE_CHANGE_DELETE_NEW_FILE,
-	FILE_CHANGE_OVERWRITE_FILE
+	FILE_CHANGE_OVERWRITE_FILE,
+	FILE_CHANGE_RAW_DISK_WRITE // write on a volume handle (\\.\C:), filePath is the volume name
 };
 
 enum FILE_LOCATION_INFO {
@@ -76,7 +126,7 @@ enum IRP_MAJOR_OP {
 	IRP_CLEANUP,
 };
 
-// -64- bytes structure, fixed to -96- bytes, fixed to 104 bytes
+// -64- bytes structure, fixed to -96- bytes, fixed to 104 bytes, fixed to 112 bytes
 typedef struct _DRIVER_MESSAGE {
 	WCHAR Extension[FILE_OBJEC_MAX_EXTENSION_SIZE + 1]; // null terminated 24 bytes
 
@@ -96,9 +146,29 @@ typedef struct _DRIVER_MESSAGE {
 	UNICODE_STRING filePath; // 16 bytes unicode string - filename, also contains size and max size, buffer is outside the struct
 	ULONGLONG Gid; // 8 bytes process ransomwatch gid
 	PVOID next; // 8 bytes - next PDRIVER_MESSAGE, we use it to allow adding the fileName to the same buffer, this pointer should point to the next PDRIVER_MESSAGE in buffer (kernel handled)
+	LONGLONG Timestamp; // 8 bytes - KeQuerySystemTimePrecise when the irp is seen, in 100 ns since 1601 (UTC)
 	
 } DRIVER_MESSAGE, *PDRIVER_MESSAGE;
 
+// irps of a pid summarized instead of queued as DRIVER_MESSAGEs, while the application falls behind (MESSAGE_SET_BACKPRESSURE) or the irps list is full
+typedef struct _IRP_AGGREGATE {
+	ULONGLONG Gid; // 8 bytes
+	ULONGLONG BytesRead; // 8 bytes
+	ULONGLONG BytesWritten; // 8 bytes
+	ULONG PID; // 4 bytes
+	ULONG Ops[IRP_CLEANUP + 1]; // 24 bytes - count by IRP_MAJOR_OP
+	ULONG FileChanges[FILE_CHANGE_RAW_DISK_WRITE + 1]; // 40 bytes - count by FILE_CHANGE_INFO
+	ULONG HighEntropyWrites; // 4 bytes - writes with an entropy above AGGREGATE_HIGH_ENTROPY
+} IRP_AGGREGATE, *PIRP_AGGREGATE;
+
+// header of the reply to MESSAGE_GET_AGGREGATES, followed by numAggregates IRP_AGGREGATEs, counts since the last call
+typedef struct _AGGREGATES_REPLY {
+	ULONGLONG aggregatedIrps; // 8 bytes - irps summarized in the aggregates
+	ULONGLONG droppedIrps; // 8 bytes - irps lost, the aggregates were full
+	ULONG numAggregates; // 4 bytes
+	BOOLEAN backpressure; // 1 byte + 3 bytes align - irps are aggregated as asked by the application
+} AGGREGATES_REPLY, *PAGGREGATES_REPLY;
+
 // header for return buffer from driver on irp ops, has pointer to the first driver message, num ops in the buffer and readable data size in the buffer
 typedef struct _RWD_REPLY_IRPS {
 	size_t dataSize; // 8 bytes
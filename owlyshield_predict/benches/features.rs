@@ -0,0 +1,55 @@
+//! Benchmarks of the per-gid aggregates updated for each driver message, and read for each
+//! prediction.
+//!
+//! Run with ```cargo bench --bench features```. The modules are included by path, as the crate
+//! has no library target.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[allow(dead_code)]
+#[path = "../src/extensions.rs"]
+mod extensions;
+#[allow(dead_code)]
+#[path = "../src/sketch.rs"]
+mod sketch;
+
+use extensions::{ExtensionList, ExtensionsCount};
+use sketch::BoundedSet;
+
+fn bounded_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bounded_set");
+    for size in [1_000usize, 100_000] {
+        let mut set = BoundedSet::new(20_000);
+        for i in 0..size {
+            set.insert(format!("C:\\Users\\user\\Documents\\file_{}.docx", i));
+        }
+        group.bench_with_input(BenchmarkId::new("insert", size), &size, |b, _| {
+            let mut i = 0;
+            b.iter(|| {
+                i += 1;
+                set.insert(format!("C:\\Users\\user\\Documents\\new_{}.docx", i));
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("len", size), &size, |b, _| {
+            b.iter(|| black_box(set.len()))
+        });
+    }
+    group.finish();
+}
+
+fn extensions_count(c: &mut Criterion) {
+    let list = ExtensionList::new();
+    let mut count = ExtensionsCount::new(&list);
+    let extensions = ["docx", "PDF", "locked", "sqlite", "jpg", "exe", "encrypted"];
+    c.bench_function("extensions/add", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i += 1;
+            count.add_cat_extension(extensions[i % extensions.len()]);
+        })
+    });
+    c.bench_function("extensions/count_all", |b| b.iter(|| black_box(count.count_all())));
+}
+
+criterion_group!(benches, bounded_set, extensions_count);
+criterion_main!(benches);
@@ -0,0 +1,397 @@
+//! Benchmarks of the hot path, for each driver message: parsing of the replies of the minifilter
+//! by [driver_reply::parse] and conversion to [IOMessage]s, feature aggregation by
+//! [ProcessRecord::add_irp_record], inference by [ProcessRecord::eval] and
+//! [TfLite::make_prediction], and scan of the dropped payloads by [payloads::scan_queued] and
+//! [TfLiteStatic::make_prediction].
+//!
+//! The synthetic workloads are one second of events at 10k, 50k and 200k events per second,
+//! spread over [GIDS] process families: a rate is sustained if an iteration takes less than a
+//! second. Run with ```cargo bench --bench hot_path```, and compare with a saved baseline
+//! (```-- --save-baseline before``` then ```-- --baseline before```) to catch the regressions.
+//! The aggregation and inference run on one thread: a workload taking *t* seconds needs about
+//! *t* workers, which gives the *PIPELINE_THREADS* of a hardware tier.
+//!
+//! As in the *features* benchmarks, the modules are included by path, with the modules they
+//! depend on.
+
+#![allow(dead_code, unused_imports, unused_variables)]
+
+#[macro_use]
+extern crate num_derive;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+#[path = "../src/anomaly.rs"]
+mod anomaly;
+#[path = "../src/autostart.rs"]
+mod autostart;
+#[path = "../src/backpressure.rs"]
+mod backpressure;
+#[path = "../src/capture.rs"]
+mod capture;
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/cloudsync.rs"]
+mod cloudsync;
+#[path = "../src/config.rs"]
+mod config;
+#[path = "../src/connectors/mod.rs"]
+mod connectors;
+#[path = "../src/container.rs"]
+mod container;
+#[path = "../src/correlation.rs"]
+mod correlation;
+#[path = "../src/csvwriter.rs"]
+mod csvwriter;
+#[path = "../src/decay.rs"]
+mod decay;
+#[path = "../src/dirtree.rs"]
+mod dirtree;
+#[path = "../src/driver_com.rs"]
+mod driver_com;
+#[path = "../src/driver_reply.rs"]
+mod driver_reply;
+#[path = "../src/enrichment.rs"]
+mod enrichment;
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/escalation.rs"]
+mod escalation;
+#[path = "../src/eventstore.rs"]
+mod eventstore;
+#[path = "../src/exclusions.rs"]
+mod exclusions;
+#[path = "../src/exfil.rs"]
+mod exfil;
+#[path = "../src/extensions.rs"]
+mod extensions;
+#[path = "../src/extprofiles.rs"]
+mod extprofiles;
+#[path = "../src/fastpath.rs"]
+mod fastpath;
+#[path = "../src/features.rs"]
+mod features;
+#[path = "../src/filetable.rs"]
+mod filetable;
+#[path = "../src/fingerprint.rs"]
+mod fingerprint;
+#[path = "../src/gidmerge.rs"]
+mod gidmerge;
+#[path = "../src/heatmap.rs"]
+mod heatmap;
+#[path = "../src/history.rs"]
+mod history;
+#[path = "../src/identity.rs"]
+mod identity;
+#[path = "../src/inputcapture.rs"]
+mod inputcapture;
+#[path = "../src/intern.rs"]
+mod intern;
+#[path = "../src/integrity.rs"]
+mod integrity;
+#[path = "../src/iosource.rs"]
+mod iosource;
+#[path = "../src/killcheck.rs"]
+mod killcheck;
+#[path = "../src/logging.rs"]
+mod logging;
+#[path = "../src/lolbin.rs"]
+mod lolbin;
+#[path = "../src/magic.rs"]
+mod magic;
+#[path = "../src/memscan.rs"]
+mod memscan;
+#[path = "../src/notelang.rs"]
+mod notelang;
+#[path = "../src/notifications.rs"]
+mod notifications;
+#[path = "../src/os/mod.rs"]
+mod os;
+#[path = "../src/payloads.rs"]
+mod payloads;
+#[path = "../src/prediction.rs"]
+mod prediction;
+#[path = "../src/prediction_static.rs"]
+mod prediction_static;
+#[path = "../src/privileges.rs"]
+mod privileges;
+#[path = "../src/process.rs"]
+mod process;
+#[path = "../src/profiles.rs"]
+mod profiles;
+#[path = "../src/quarantine.rs"]
+mod quarantine;
+#[path = "../src/ransomnote.rs"]
+mod ransomnote;
+#[path = "../src/rawdisk.rs"]
+mod rawdisk;
+#[path = "../src/reputation.rs"]
+mod reputation;
+#[path = "../src/routing.rs"]
+mod routing;
+#[path = "../src/schema.rs"]
+mod schema;
+#[path = "../src/scripthost.rs"]
+mod scripthost;
+#[cfg(windows)]
+#[path = "../src/secrets.rs"]
+mod secrets;
+#[cfg(windows)]
+#[path = "../src/selfprotect.rs"]
+mod selfprotect;
+#[path = "../src/service_ctl.rs"]
+mod service_ctl;
+#[cfg(windows)]
+#[path = "../src/signer.rs"]
+mod signer;
+#[path = "../src/sketch.rs"]
+mod sketch;
+#[path = "../src/sysmon.rs"]
+mod sysmon;
+#[path = "../src/token.rs"]
+mod token;
+#[path = "../src/utils.rs"]
+mod utils;
+#[path = "../src/volumes.rs"]
+mod volumes;
+#[path = "../src/watchdog.rs"]
+mod watchdog;
+#[path = "../src/wiper.rs"]
+mod wiper;
+#[path = "../src/wsl.rs"]
+mod wsl;
+#[path = "../src/yara.rs"]
+mod yara;
+
+use config::Config;
+use driver_com::shared_def::{IOMessage, RuntimeFeatures};
+use driver_com::IrpMajorOp;
+use driver_reply::DriverMsg;
+use prediction::input_tensors::RollingFeatures;
+use features::REGISTRY;
+use prediction::{TfLite, PREDMTRXROWS};
+use prediction_static::TfLiteStatic;
+use process::ProcessRecord;
+
+/// Events per second of the workloads.
+const RATES: [usize; 3] = [10_000, 50_000, 200_000];
+/// Process families sharing the events.
+const GIDS: u64 = 16;
+/// Payloads dropped by each family, within the *DROPPED_SCAN_RATE* of the default configuration.
+const PAYLOADS: usize = 8;
+
+const EXTENSIONS: [&str; 8] = ["docx", "xlsx", "pdf", "jpg", "txt", "sqlite", "locked", "tmp"];
+
+/// The *i*th event of a synthetic workload: a family reads, writes, renames and closes files in
+/// 64 directories.
+fn synthetic_iomsg(i: usize) -> IOMessage {
+    let gid = (i as u64 % GIDS) + 1;
+    let file = i / 4;
+    let extension = EXTENSIONS[file % EXTENSIONS.len()];
+    let (irp_op, file_change) = match i % 8 {
+        0 => (IrpMajorOp::IrpCreate as u8, 3),
+        1 | 2 => (IrpMajorOp::IrpRead as u8, 0),
+        3 | 4 | 5 => (IrpMajorOp::IrpWrite as u8, 2),
+        6 => (IrpMajorOp::IrpSetInfo as u8, 4),
+        _ => (IrpMajorOp::IrpCleanUp as u8, 0),
+    };
+    let mut file_id_id = [0u8; 16];
+    file_id_id[..8].copy_from_slice(&(file as u64).to_le_bytes());
+    let mut runtime_features = RuntimeFeatures::new();
+    runtime_features.exepath = PathBuf::from(format!(r"C:\Users\user\AppData\Local\Temp\app_{}.exe", gid));
+    IOMessage {
+        extension: [0; 12],
+        file_id_vsn: 0x1234_5678,
+        file_id_id,
+        mem_sized_used: 4096,
+        entropy: if irp_op == IrpMajorOp::IrpWrite as u8 { 7.9 } else { 4.2 },
+        pid: 1000 + gid as u32,
+        irp_op,
+        is_entropy_calc: 1,
+        file_change,
+        file_location_info: 0,
+        filepathstr: format!(r"C:\Users\user\Documents\dir_{}\file_{}.{}", file % 64, file, extension),
+        gid,
+        runtime_features,
+        file_size: 65536,
+    }
+}
+
+/// One second of events at *rate*.
+fn workload(rate: usize) -> Vec<IOMessage> {
+    (0..rate).map(synthetic_iomsg).collect()
+}
+
+fn records<'a>(config: &'a Config, iomsgs: &[IOMessage]) -> Vec<ProcessRecord<'a>> {
+    (0..GIDS as usize)
+        .map(|i| {
+            let iomsg = &iomsgs[i];
+            ProcessRecord::from(config, iomsg, format!("app_{}.exe", iomsg.gid), iomsg.runtime_features.exepath.clone(), None)
+        })
+        .collect()
+}
+
+fn config() -> Config {
+    Config::from_args(&[]).expect("Invalid default configuration")
+}
+
+fn replies(c: &mut Criterion) {
+    // the address of the buffer for the minifilter
+    let base = 0x7ff6_0000_0000;
+    let mut group = c.benchmark_group("driver_reply");
+    group.sample_size(20);
+    for rate in RATES {
+        let drivermsgs: Vec<DriverMsg> = workload(rate)
+            .into_iter()
+            .map(|iomsg| DriverMsg {
+                extension: iomsg.extension,
+                file_id_vsn: iomsg.file_id_vsn,
+                file_id_id: iomsg.file_id_id,
+                mem_sized_used: iomsg.mem_sized_used,
+                entropy: iomsg.entropy,
+                pid: iomsg.pid,
+                irp_op: iomsg.irp_op,
+                is_entropy_calc: iomsg.is_entropy_calc,
+                file_change: iomsg.file_change,
+                file_location_info: iomsg.file_location_info,
+                filepath: iomsg.filepathstr,
+                gid: iomsg.gid,
+                timestamp: 0,
+            })
+            .collect();
+        // the replies hold BUFFER_SIZE bytes at most: the workload is parsed as one large reply
+        let buffer = driver_reply::encode(&drivermsgs, base);
+        group.throughput(Throughput::Elements(rate as u64));
+        group.bench_with_input(BenchmarkId::new("parse", rate), &rate, |b, _| {
+            b.iter(|| black_box(driver_reply::parse(&buffer, base).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("convert", rate), &rate, |b, _| {
+            b.iter(|| {
+                let events: Vec<IOMessage> = drivermsgs.iter().map(IOMessage::from).collect();
+                black_box(events)
+            })
+        });
+    }
+    group.finish();
+}
+
+fn aggregation(c: &mut Criterion) {
+    let config = config();
+    let mut group = c.benchmark_group("aggregation");
+    group.sample_size(20);
+    for rate in RATES {
+        let iomsgs = workload(rate);
+        group.throughput(Throughput::Elements(rate as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(rate), &rate, |b, _| {
+            b.iter_batched(
+                || records(&config, &iomsgs),
+                |mut procs| {
+                    for iomsg in &iomsgs {
+                        procs[(iomsg.gid - 1) as usize].add_irp_record(iomsg);
+                    }
+                    procs
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn inference(c: &mut Criterion) {
+    let config = config();
+    let tflite = TfLite::new().expect("Cannot load the model");
+
+    let mut group = c.benchmark_group("inference");
+    for rows in [10, 100, PREDMTRXROWS] {
+        let mut predmtrx = RollingFeatures::new(PREDMTRXROWS);
+        for i in 0..rows {
+            predmtrx.push_row((0..REGISTRY.len()).map(|j| (i * j) as f32).collect()).unwrap();
+        }
+        group.bench_with_input(BenchmarkId::new("make_prediction", rows), &rows, |b, _| {
+            b.iter(|| black_box(tflite.make_prediction(&predmtrx)))
+        });
+    }
+
+    // aggregation and predictions, as done by the workers
+    group.sample_size(10);
+    for rate in RATES {
+        let iomsgs = workload(rate);
+        group.throughput(Throughput::Elements(rate as u64));
+        group.bench_with_input(BenchmarkId::new("eval", rate), &rate, |b, _| {
+            b.iter_batched(
+                || records(&config, &iomsgs),
+                |mut procs| {
+                    for iomsg in &iomsgs {
+                        let proc = &mut procs[(iomsg.gid - 1) as usize];
+                        proc.add_irp_record(iomsg);
+                        black_box(proc.eval(&tflite, None));
+                    }
+                    procs
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Copies of the benchmark executable (a PE on Windows), as the payloads dropped by the families.
+fn payload_files() -> Vec<PathBuf> {
+    let dir = std::env::temp_dir().join("owlyshield_hot_path");
+    std::fs::create_dir_all(&dir).expect("Cannot create the payloads directory");
+    let exe = std::env::current_exe().expect("No path for the benchmark executable");
+    (0..PAYLOADS)
+        .map(|i| {
+            let path = dir.join(format!("payload_{}.exe", i));
+            std::fs::copy(&exe, &path).expect("Cannot copy the payload");
+            path
+        })
+        .collect()
+}
+
+fn payload_scans(c: &mut Criterion) {
+    let config = config();
+    let tflite_static = TfLiteStatic::new().expect("Cannot load the static model");
+    let files = payload_files();
+
+    let mut group = c.benchmark_group("payloads");
+    group.sample_size(20);
+    group.bench_function("make_prediction", |b| b.iter(|| black_box(tflite_static.make_prediction(&files[0]))));
+
+    // the scans of the workers, PAYLOADS queued by each family
+    let iomsgs = workload(GIDS as usize);
+    group.throughput(Throughput::Elements(GIDS * PAYLOADS as u64));
+    group.bench_function("scan_queued", |b| {
+        b.iter_batched(
+            || {
+                let mut procs = records(&config, &iomsgs);
+                for proc in &mut procs {
+                    for file in &files {
+                        let fpath: Arc<str> = Arc::from(file.to_string_lossy().as_ref());
+                        proc.payloads.on_created(&fpath);
+                        proc.payloads.on_closed(&fpath, SystemTime::now());
+                    }
+                }
+                procs
+            },
+            |mut procs| {
+                for proc in &mut procs {
+                    for _ in 0..PAYLOADS {
+                        payloads::scan_queued(&config, proc, &tflite_static);
+                    }
+                }
+                procs
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, replies, aggregation, inference, payload_scans);
+criterion_main!(benches);
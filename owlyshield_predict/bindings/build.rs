@@ -13,6 +13,32 @@ fn main() {
         Windows::Win32::System::LibraryLoader::GetModuleFileNameA,
         Windows::Win32::System::ProcessStatus::K32GetModuleFileNameExA,
         Windows::Win32::System::Diagnostics::Debug::{DebugActiveProcess, DebugActiveProcessStop, DebugSetProcessKillOnExit},
+        Windows::Win32::System::Threading::{OpenProcessToken, PROCESS_QUERY_LIMITED_INFORMATION},
+        Windows::Win32::Security::{GetTokenInformation, TOKEN_USER, TOKEN_QUERY, TokenUser, TokenSessionId, LookupAccountSidW, SID_NAME_USE},
+        Windows::Win32::Security::Authorization::ConvertSidToStringSidW,
+        Windows::Win32::System::Memory::LocalFree,
+        Windows::Win32::Security::Cryptography::Core::{CryptQueryObject, CryptMsgGetParam, CryptMsgClose, CertFindCertificateInStore, CertGetNameStringW, CertFreeCertificateContext, CertCloseStore, CMSG_SIGNER_INFO, CERT_INFO, CERT_CONTEXT},
+        Windows::Win32::Security::Cryptography::Core::{CryptProtectData, CryptUnprotectData, CRYPTOAPI_BLOB},
+        Windows::Win32::Security::Authorization::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SetNamedSecurityInfoW, SetSecurityInfo, SE_OBJECT_TYPE},
+        Windows::Win32::Security::{GetSecurityDescriptorDacl, ACL},
+        Windows::Win32::System::Threading::{GetCurrentProcess, GetCurrentProcessId, PROCESS_DUP_HANDLE},
+        Windows::Win32::System::Threading::{TerminateProcess, PROCESS_TERMINATE},
+        Windows::Win32::Foundation::{DuplicateHandle, DUPLICATE_SAME_ACCESS},
+        Windows::Win32::Storage::FileSystem::{GetFileType, FILE_TYPE_DISK},
+        Windows::Win32::System::Diagnostics::Debug::{MiniDumpWriteDump, MINIDUMP_TYPE},
+        Windows::Win32::Security::{TokenGroups, TOKEN_GROUPS, SID_AND_ATTRIBUTES},
+        Windows::Win32::System::Antimalware::{AmsiInitialize, AmsiScanBuffer, AmsiUninitialize, AMSI_RESULT, HAMSISESSION},
+        Windows::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW, IsProcessInJob, TerminateJobObject},
+        Windows::Win32::System::Threading::PROCESS_SET_QUOTA,
+        Windows::Win32::Security::TokenIsAppContainer,
+        Windows::Win32::Security::TokenPrivileges,
+        Windows::Win32::System::EventLog::{EvtClose, EvtRender, EvtSubscribe, EVT_SUBSCRIBE_NOTIFY_ACTION, EVT_RENDER_FLAGS, EVT_SUBSCRIBE_FLAGS},
+        Windows::Win32::Security::WinTrust::{WinVerifyTrust, WINTRUST_DATA, WINTRUST_FILE_INFO, WINTRUST_DATA_UICHOICE, WINTRUST_DATA_REVOCATION_CHECKS, WINTRUST_DATA_UNION_CHOICE, WINTRUST_DATA_STATE_ACTION},
+        Windows::Win32::System::Memory::{VirtualQueryEx, MEMORY_BASIC_INFORMATION, MEM_COMMIT, MEM_PRIVATE, PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY},
+        Windows::Win32::System::Diagnostics::Debug::ReadProcessMemory,
+        Windows::Win32::Storage::FileSystem::{FindFirstVolumeW, FindNextVolumeW, FindVolumeClose, GetVolumePathNamesForVolumeNameW, QueryDosDeviceW, GetLogicalDrives, GetLongPathNameW},
+        Windows::Win32::System::Diagnostics::Etw::{StartTraceW, ControlTraceW, EnableTraceEx2, OpenTraceW, ProcessTrace, CloseTrace, TdhGetEventInformation, EVENT_RECORD, EVENT_TRACE_PROPERTIES, EVENT_TRACE_LOGFILEW, TRACE_EVENT_INFO},
+        Windows::Win32::System::Diagnostics::Etw::{EVENT_CONTROL_CODE_ENABLE_PROVIDER, EVENT_TRACE_CONTROL_STOP, EVENT_TRACE_REAL_TIME_MODE, PROCESS_TRACE_MODE_EVENT_RECORD, PROCESS_TRACE_MODE_REAL_TIME, WNODE_FLAG_TRACED_GUID},
 	);
 
 }
@@ -0,0 +1,26 @@
+//! Fuzzes [driver_reply::parse_msg] with an arbitrary *DRIVER_MESSAGE* and *UNICODE_STRING*
+//! path: ```cargo +nightly fuzz run driver_msg``` in *owlyshield_predict*.
+//!
+//! The first 8 bytes of the input are the address of the buffer, where the message starts.
+
+#![no_main]
+
+use std::convert::TryInto;
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/driver_reply.rs"]
+mod driver_reply;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 {
+        return;
+    }
+    let (base, buffer) = data.split_at(8);
+    let base = u64::from_le_bytes(base.try_into().unwrap());
+    if let Ok((drivermsg, _next)) = driver_reply::parse_msg(buffer, base, base) {
+        assert!(buffer.len() >= driver_reply::MSG_SIZE);
+        assert!(drivermsg.filepath.encode_utf16().count() <= driver_reply::MAX_PATH_LENGTH);
+    }
+});
@@ -0,0 +1,31 @@
+//! Fuzzes [driver_reply::parse] with arbitrary replies of the minifilter:
+//! ```cargo +nightly fuzz run reply``` in *owlyshield_predict*.
+//!
+//! The first 8 bytes of the input are the address of the buffer, so that the fuzzer can make the
+//! pointers of the reply point into it. A parsed reply must be encoded and parsed again to the
+//! same messages.
+
+#![no_main]
+
+use std::convert::TryInto;
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/driver_reply.rs"]
+mod driver_reply;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 {
+        return;
+    }
+    let (base, buffer) = data.split_at(8);
+    let base = u64::from_le_bytes(base.try_into().unwrap());
+    if let Ok(drivermsgs) = driver_reply::parse(buffer, base) {
+        assert!(drivermsgs.len() <= buffer.len() / driver_reply::MSG_SIZE);
+        // compared as bytes: the entropy may be a NaN
+        let encoded = driver_reply::encode(&drivermsgs, base);
+        let reparsed = driver_reply::parse(&encoded, base).expect("Cannot parse an encoded reply");
+        assert_eq!(driver_reply::encode(&reparsed, base), encoded);
+    }
+});
@@ -2,9 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::convert::TryFrom;
-use std::ffi::{CStr, c_void};
+use std::ffi::{CStr, CString, c_void};
 use std::marker::PhantomData;
 use std::os::raw::c_char;
+use std::path::Path;
 use std::ptr;
 
 #[cfg(feature = "edgetpu")]
@@ -50,6 +51,15 @@ pub enum Type {
     Float16 = 10,
 }
 
+// Affine quantization of a tensor, aka TfLiteQuantizationParams:
+// real_value = scale * (quantized_value - zero_point)
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(C)]
+pub struct QuantizationParams {
+    pub scale: f32,
+    pub zero_point: i32,
+}
+
 #[derive(Copy, Clone)]
 #[repr(transparent)]
 struct TfLiteStatus(libc::c_int);
@@ -57,6 +67,7 @@ struct TfLiteStatus(libc::c_int);
 //#[link(name = r"C:\Users\lesco\IdeaProjects\owlyshield_ransom\tensorflowlite_c")]
 extern "C" {
     fn TfLiteModelCreate(model_data: *const u8, model_size: usize) -> *mut TfLiteModel;
+    fn TfLiteModelCreateFromFile(model_path: *const c_char) -> *mut TfLiteModel;
     fn TfLiteModelDelete(model: *mut TfLiteModel);
 
     fn TfLiteInterpreterOptionsCreate() -> *mut TfLiteInterpreterOptions;
@@ -90,6 +101,7 @@ extern "C" {
     fn TfLiteTensorByteSize(tensor: *const Tensor) -> usize;
     fn TfLiteTensorData(tensor: *const Tensor) -> *mut u8;
     fn TfLiteTensorName(tensor: *const Tensor) -> *const c_char;
+    fn TfLiteTensorQuantizationParams(tensor: *const Tensor) -> QuantizationParams;
     fn TfLiteInterpreterResizeInputTensor(interpreter: *const TfLiteInterpreter, input_index: usize, input_data: *const c_void, input_data_size: usize) -> TfLiteStatus;
 
    // fn TfLiteTypeGetName(type_: Type) -> *const c_char;
@@ -278,6 +290,9 @@ impl Tensor {
             .to_str()
             .unwrap()
     }
+    pub fn quantization_params(&self) -> QuantizationParams {
+        unsafe { TfLiteTensorQuantizationParams(self) }
+    }
 }
 
 impl std::fmt::Debug for Tensor {
@@ -325,6 +340,12 @@ impl Model {
         let m = unsafe { TfLiteModelCreate(model.as_ptr(), model.len()) };
         Ok(Model(ptr::NonNull::new(m).ok_or(())?))
     }
+
+    pub fn from_file(path: &Path) -> Result<Self, ()> {
+        let path = CString::new(path.to_str().ok_or(())?).map_err(|_| ())?;
+        let m = unsafe { TfLiteModelCreateFromFile(path.as_ptr()) };
+        Ok(Model(ptr::NonNull::new(m).ok_or(())?))
+    }
 }
 
 impl Drop for Model {
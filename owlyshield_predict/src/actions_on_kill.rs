@@ -6,15 +6,23 @@ use std::path::Path;
 use std::time::SystemTime;
 
 use chrono::{DateTime, Local};
-use log::error;
+use serde_json::json;
+use tracing::{error, info};
 
 use crate::config::{Config, Param};
+use crate::correlation::IncidentRef;
+use crate::identity::AgentIdentity;
 use crate::notifications::toast;
-use crate::prediction::input_tensors::VecvecCappedF32;
+use crate::prediction::input_tensors::RollingFeatures;
 use crate::process::{ProcessRecord, ProcessState};
+use crate::schema::{self, Detection, Envelope, Event};
+use crate::stix;
+use crate::timeline::TimelineEntry;
 use crate::utils::{FILE_TIME_FORMAT, LONG_TIME_FORMAT};
+use crate::volumes::PathForms;
 
 use crate::connectors::connector::{Connector, Connectors};
+#[cfg(windows)]
 use crate::connectors::sitincloud::SitinCloud;
 
 pub struct ActionsOnKill {
@@ -25,8 +33,20 @@ pub struct WriteReportFile();
 
 pub struct WriteReportHtmlFile();
 
+/// The report as a [Detection] event, in the versioned JSON contract of [crate::schema].
+pub struct WriteReportJsonFile();
+
 pub struct PostReport();
 
+pub struct WriteStixBundle();
+
+/// The features the model saw, as JSON ([RollingFeatures]), for the exports and the retraining.
+pub struct WriteFeaturesFile();
+
+/// The files the gid created, wrote, renamed or deleted, by file id with their original and last
+/// paths ([crate::filetable::FileTable::manifest]): what a restore has to put back, and where.
+pub struct WriteAffectedFiles();
+
 pub struct ToastIncident();
 
 pub trait ActionOnKill {
@@ -34,9 +54,10 @@ pub trait ActionOnKill {
         &self,
         config: &Config,
         proc: &ProcessRecord,
-        pred_mtrx: &VecvecCappedF32,
+        pred_mtrx: &RollingFeatures,
         prediction: f32,
         now: &String,
+        incident: &IncidentRef,
     ) -> Result<(), Box<dyn Error>>;
 }
 
@@ -46,7 +67,11 @@ impl ActionsOnKill {
             actions: vec![
                 Box::new(WriteReportFile()),
                 Box::new(WriteReportHtmlFile()),
+                Box::new(WriteReportJsonFile()),
                 Box::new(PostReport()),
+                Box::new(WriteStixBundle()),
+                Box::new(WriteFeaturesFile()),
+                Box::new(WriteAffectedFiles()),
                 Box::new(ToastIncident()),
             ],
         }
@@ -56,15 +81,16 @@ impl ActionsOnKill {
         &self,
         config: &Config,
         proc: &ProcessRecord,
-        pred_mtrx: &VecvecCappedF32,
+        pred_mtrx: &RollingFeatures,
         prediction: f32,
+        incident: &IncidentRef,
     ) {
         let now = (DateTime::from(SystemTime::now()) as DateTime<Local>)
             .format(FILE_TIME_FORMAT)
             .to_string();
         for action in &self.actions {
             action
-                .run(config, proc, pred_mtrx, prediction, &now)
+                .run(config, proc, pred_mtrx, prediction, &now, incident)
                 .unwrap_or_else(|e| error!("Error with post_kill action: {}", e));
         }
     }
@@ -75,13 +101,14 @@ impl ActionOnKill for WriteReportFile {
         &self,
         config: &Config,
         proc: &ProcessRecord,
-        _pred_mtrx: &VecvecCappedF32,
+        _pred_mtrx: &RollingFeatures,
         _prediction: f32,
         now: &String,
+        incident: &IncidentRef,
     ) -> Result<(), Box<dyn Error>> {
         // let now: DateTime<Local> = SystemTime::now().into();
         // let snow = now.format(FILE_TIME_FORMAT).to_string();
-        let report_dir = Path::new(&config[Param::ConfigPath]).join("threats");
+        let report_dir = config.get_path(Param::ConfigPath).join("threats");
         if !report_dir.exists() {
             error!(
                 "Cannot Write report file: dir does not exist: {}",
@@ -95,19 +122,29 @@ impl ActionOnKill for WriteReportFile {
                 &proc.gid,
             )));
             let report_path = temp.to_str().unwrap_or("");
-            println!("{}", report_path);
+            info!("Report written to {}", report_path);
             let mut file = File::create(Path::new(&report_path))?;
             let stime_started: DateTime<Local> = proc.time_started.into();
             file.write_all(b"Owlyshield report file\n\n")?;
             file.write_all(
                 format!("Ransomware detected running from: {}\n\n", proc.appname).as_bytes(),
             )?;
+            file.write_all(format!("Running as {}\n", proc.user()).as_bytes())?;
+            let identity = AgentIdentity::load(config);
+            file.write_all(format!("On machine {}\n", identity.machine()).as_bytes())?;
+            file.write_all(format!("OS: {}\n", identity.os_version).as_bytes())?;
+            if let Some(domain) = &identity.domain {
+                file.write_all(format!("Domain: {}\n", domain).as_bytes())?;
+            }
+            file.write_all(format!("Agent version: {}\n", identity.agent_version).as_bytes())?;
+            file.write_all(format!("Incident: {}\n", incident).as_bytes())?;
             file.write_all(
                 format!("Started at {}\n", stime_started.format(LONG_TIME_FORMAT)).as_bytes(),
             )?;
             file.write_all(
                 format!(
-                    "Killed at {}\n\n",
+                    "{} at {}\n\n",
+                    killed_label(config, proc),
                     DateTime::<Local>::from(proc.time_killed.unwrap_or(SystemTime::now()))
                         .format(LONG_TIME_FORMAT)
                 )
@@ -117,6 +154,171 @@ impl ActionOnKill for WriteReportFile {
             for f in &proc.fpaths_updated {
                 file.write_all(format!("\t{:?}\n", f).as_bytes())?;
             }
+            if proc.fpaths_updated.is_saturated() {
+                file.write_all(
+                    format!("\t... about {} files in total\n", proc.fpaths_updated.len()).as_bytes(),
+                )?;
+            }
+            if let Some(verdict) = proc.fast_path.verdict() {
+                file.write_all(format!("\nDetected without the model: {}\n", verdict).as_bytes())?;
+            }
+            if let Some(suspected) = proc.fingerprint.suspected_family() {
+                file.write_all(format!("\nSuspected family: {}\n", suspected).as_bytes())?;
+            }
+            if let Some(deleted) = proc.wiper.detected() {
+                file.write_all(
+                    format!(
+                        "\nWiper: {} files deleted without encryption within {} seconds\n",
+                        deleted,
+                        proc.wiper.window().as_secs()
+                    )
+                    .as_bytes(),
+                )?;
+            }
+            if !proc.sync_clients.is_empty() {
+                let clients: Vec<String> = proc.sync_clients.iter().map(|c| c.to_string()).collect();
+                file.write_all(
+                    format!(
+                        "\n{} files written in the folders synchronized by {}\n",
+                        proc.files_written_sync.len(),
+                        clients.join(", ")
+                    )
+                    .as_bytes(),
+                )?;
+            }
+            if !proc.files_written_remote.is_empty() {
+                file.write_all(
+                    format!(
+                        "\n{} files written on network shares ({} of the {} writes)\n",
+                        proc.files_written_remote.len(),
+                        proc.ops_written_remote,
+                        proc.ops_written
+                    )
+                    .as_bytes(),
+                )?;
+            }
+            for archive in proc.exfil.reported() {
+                file.write_all(format!("\nArchive staged for exfiltration: {}\n", archive.display()).as_bytes())?;
+            }
+            if let Some(note) = proc.ransom_note.note() {
+                file.write_all(
+                    format!("\nRansom note dropped in {} directories ({}):\n", note.dirs, note.path).as_bytes(),
+                )?;
+                for line in note.text.lines() {
+                    file.write_all(format!("\t{}\n", line).as_bytes())?;
+                }
+                let analysis = &note.analysis;
+                if let Some(language) = &analysis.language {
+                    file.write_all(format!("Language: {} ({:.2})\n", language.code, language.confidence).as_bytes())?;
+                }
+                if !analysis.keywords.is_empty() {
+                    file.write_all(format!("Keywords: {}\n", analysis.keywords.join(", ")).as_bytes())?;
+                }
+                for hint in &analysis.family_hints {
+                    file.write_all(format!("Family hint: {} (\"{}\")\n", hint.family, hint.phrase).as_bytes())?;
+                }
+            }
+            if !proc.payloads.scanned.is_empty() {
+                file.write_all(b"\nExecutables dropped:\n")?;
+                for payload in &proc.payloads.scanned {
+                    let prediction = payload.prediction.map_or(String::from("-"), |p| p.to_string());
+                    file.write_all(format!("\t{} (static: {}", payload.path.display(), prediction).as_bytes())?;
+                    if !payload.rules.is_empty() {
+                        file.write_all(format!(", YARA: {}", payload.rules.join(", ")).as_bytes())?;
+                    }
+                    file.write_all(b")\n")?;
+                }
+                if proc.payloads.skipped > 0 {
+                    file.write_all(format!("\t{} more not scanned\n", proc.payloads.skipped).as_bytes())?;
+                }
+            }
+            if let Some(mismatch) = proc.integrity.image_mismatch {
+                file.write_all(format!("\nImage of the process differs from its executable: {}\n", mismatch).as_bytes())?;
+            }
+            if let Some(creator) = proc.integrity.spoofed_by {
+                file.write_all(format!("\nProcess created by pid {} with a spoofed parent\n", creator).as_bytes())?;
+            }
+            if !proc.privileges.escalated.is_empty() {
+                file.write_all(format!("\nPrivileges enabled during the run: {}\n", proc.privileges.escalated).as_bytes())?;
+            }
+            if !proc.autostart.entries.is_empty() {
+                file.write_all(b"\nPersistence:\n")?;
+                for entry in &proc.autostart.entries {
+                    file.write_all(format!("\t{}\n", entry).as_bytes())?;
+                }
+            }
+            if let Some(script) = &proc.script {
+                file.write_all(format!("\nScript host: {}\n", script.host).as_bytes())?;
+                if let Some(path) = &script.script_path {
+                    file.write_all(format!("Script: {}\n", path.display()).as_bytes())?;
+                }
+                if let Some(command) = &script.command {
+                    let label = if script.encoded { "Decoded command" } else { "Command" };
+                    file.write_all(format!("{}:\n", label).as_bytes())?;
+                    for line in command.lines() {
+                        file.write_all(format!("\t{}\n", line).as_bytes())?;
+                    }
+                }
+                if let Some(amsi) = script.amsi {
+                    file.write_all(format!("AMSI verdict: {}\n", amsi).as_bytes())?;
+                }
+            }
+            if let Some(lolbin) = &proc.lolbin {
+                file.write_all(format!("\nLOLBin: {} (prior {:.2})\n", lolbin.host, lolbin.prior()).as_bytes())?;
+                if let Some(payload) = &lolbin.payload {
+                    let signed = match lolbin.payload_signed {
+                        Some(true) => " (signed)",
+                        Some(false) => " (unsigned)",
+                        None => "",
+                    };
+                    file.write_all(format!("Runs: {}{}\n", payload.display(), signed).as_bytes())?;
+                }
+                for url in &lolbin.urls {
+                    file.write_all(format!("URL: {}\n", url).as_bytes())?;
+                }
+                if !lolbin.abused_args.is_empty() {
+                    file.write_all(format!("Abused arguments: {}\n", lolbin.abused_args.join(" ")).as_bytes())?;
+                }
+                for module in &lolbin.foreign_modules {
+                    file.write_all(format!("Unsigned module: {}\n", module.display()).as_bytes())?;
+                }
+            }
+            if !proc.sysmon.is_empty() {
+                let sysmon = &proc.sysmon;
+                file.write_all(
+                    format!(
+                        "\nSysmon: {} processes created, {} files created, {} connections to {} hosts\n",
+                        sysmon.processes_created,
+                        sysmon.files_created,
+                        sysmon.connections,
+                        sysmon.remote_hosts.len()
+                    )
+                    .as_bytes(),
+                )?;
+                for command_line in &sysmon.command_lines {
+                    file.write_all(format!("\t{}\n", command_line).as_bytes())?;
+                }
+                if !sysmon.remote_hosts.is_empty() {
+                    let hosts: Vec<&str> = sysmon.remote_hosts.iter().map(String::as_str).collect();
+                    file.write_all(format!("Remote hosts: {}\n", hosts.join(", ")).as_bytes())?;
+                }
+            }
+            if !proc.input_capture.is_empty() {
+                file.write_all(format!("\nKeyboard and clipboard APIs: {}\n", proc.input_capture).as_bytes())?;
+            }
+            file.write_all(b"\nLast driver messages:\n")?;
+            for iomsg in proc.history.recent() {
+                let entry = TimelineEntry::from(iomsg, "", SystemTime::now());
+                let path = PathForms::of(&iomsg.filepathstr);
+                if path.normalized == path.raw {
+                    file.write_all(format!("\t{} {}\n", entry.datetime, entry.message).as_bytes())?;
+                } else {
+                    file.write_all(format!("\t{} {} ({})\n", entry.datetime, entry.message, path.normalized).as_bytes())?;
+                }
+            }
+            if let Some(spill_path) = proc.history.spill_path() {
+                file.write_all(format!("\tOlder messages: {}\n", spill_path.display()).as_bytes())?;
+            }
         }
         Ok(())
     }
@@ -127,11 +329,12 @@ impl ActionOnKill for WriteReportHtmlFile {
         &self,
         config: &Config,
         proc: &ProcessRecord,
-        _pred_mtrx: &VecvecCappedF32,
+        _pred_mtrx: &RollingFeatures,
         _prediction: f32,
         now: &String,
+        _incident: &IncidentRef,
     ) -> Result<(), Box<dyn Error>> {
-        let report_dir = Path::new(&config[Param::ConfigPath]).join("threats");
+        let report_dir = config.get_path(Param::ConfigPath).join("threats");
         if !report_dir.exists() {
             error!(
                 "Cannot Write report file: dir does not exist: {}",
@@ -162,19 +365,21 @@ impl ActionOnKill for WriteReportHtmlFile {
             };
 
             let report_path = temp.to_str().unwrap_or("");
-            println!("{}", report_path);
+            info!("Report written to {}", report_path);
             let mut file = File::create(Path::new(&report_path))?;
             let stime_started: DateTime<Local> = proc.time_started.into();
+            let identity = AgentIdentity::load(config);
             file.write_all(b"<!DOCTYPE html><html><head>")?;
             file.write_all(format!("<title>Owlyshield Report {}</title><link rel='icon' href='https://static.thenounproject.com/png/3420953-200.png'/><meta name='viewport' content='width=device-width, initial-scale=1'/>\n", proc.gid).as_bytes())?;
             file.write_all(b"<style>body{font-family: Arial;}.tab{overflow: hidden;border: 1px solid #ccc;background-color: #f1f1f1;}.tab button{background-color: inherit;    float: inherit;    border: none;    outline: none;    cursor: pointer;    padding: 14px 16px;    transition: 0.3s;    font-size: 17px;    width: 33%;}.tab button:hover{    background-color: #ddd;}.tab button.active{	background-color: #ccc;}.tabcontent{	display: none;	padding: 6px 12px;/*border: 1px solid #ccc;border-top: none;*/}table{	width: 80%;	align: center;	margin-left: auto;	margin-right: auto;}th{	background-color: red;}select{	width: 100%;    align: center;	margin-left: auto;	margin-right: auto;}</style>")?;
             file.write_all(b"</head><body>\n")?;
             file.write_all(b"<table><tr><th><h1><b>Owlyshield detected a </b><span style='color: white;'>ransomware</span><b>!</b></h1></th></tr></table>\n")?;
-            file.write_all(format!("<br/><table><tr><td style='text-align: center;'><h3>Ransomware detected running from: <span style='color: red;' id='fullPath'>{}</span></h3></td></tr><tr valign='top'><td style='text-align: left;'><ul><li>Process State:<b id='processState'> {}</b></li> <li>Started on<b id='startDate'> {}</b></li><li>Killed on<b id='killedDate'> {}</b></li><li>GID: <b id='gid'> {}</b></li></ul></td></tr></table>\n", proc.exepath.to_string_lossy().to_string(), proc.process_state ,stime_started.format(LONG_TIME_FORMAT), DateTime::<Local>::from(proc.time_killed.unwrap_or(SystemTime::now())).format(LONG_TIME_FORMAT), proc.gid).as_bytes())?;
+            file.write_all(format!("<br/><table><tr><td style='text-align: center;'><h3>Ransomware detected running from: <span style='color: red;' id='fullPath'>{}</span></h3></td></tr><tr valign='top'><td style='text-align: left;'><ul><li>Process State:<b id='processState'> {}</b></li> <li>Started on<b id='startDate'> {}</b></li><li>{} on<b id='killedDate'> {}</b></li><li>GID: <b id='gid'> {}</b></li><li>User:<b id='user'> {}</b></li><li>Machine:<b id='machine'> {}</b></li><li>OS:<b id='osVersion'> {}</b></li><li>Agent version:<b id='agentVersion'> {}</b></li></ul></td></tr></table>\n", proc.exepath.to_string_lossy().to_string(), proc.process_state ,stime_started.format(LONG_TIME_FORMAT), killed_label(config, proc), DateTime::<Local>::from(proc.time_killed.unwrap_or(SystemTime::now())).format(LONG_TIME_FORMAT), proc.gid, proc.user(), identity.machine(), identity.os_version, identity.agent_version).as_bytes())?;
             file.write_all(b"<table><tr><td><div class='tab'>\n")?;
             // file.write_all(b"<button class="tablinks" onclick="openTab(event,'instructions')" id="defaultOpen">Instructions</button>")?;
             file.write_all(format!("<button class='tablinks' onclick=\"openTab(event,'files_u')\">Files updated ({})</button>\n", &proc.fpaths_updated.len()).as_bytes())?;
             file.write_all(format!("<button class='tablinks' onclick=\"openTab(event,'files_c')\">Files created ({})</button>\n", &proc.fpaths_created.len()).as_bytes())?;
+            file.write_all(format!("<button class='tablinks' onclick=\"openTab(event,'writes')\">Write activity ({} directories)</button>\n", proc.heatmap.dirs_len()).as_bytes())?;
             file.write_all(b"</div></td></tr></table>\n")?;
             file.write_all(b"<div id='files_u' class='tabcontent'><table><tr><td><select name='files_u' size='30' multiple='multiple'>\n")?;
             for f in &proc.fpaths_updated {
@@ -186,6 +391,7 @@ impl ActionOnKill for WriteReportHtmlFile {
                 file.write_all(format!("<option value='{}'>{}</option>\n", f, f).as_bytes())?;
             }
             file.write_all(b"</select></td></tr></table></div>\n")?;
+            file.write_all(format!("<div id='writes' class='tabcontent'>{}</div>\n", proc.heatmap.to_html()).as_bytes())?;
             file.write_all(b"<script>function openTab(evt, tab) {	var i, tabcontent, tablinks;	tabcontent = document.getElementsByClassName('tabcontent');	for (i = 0; i != tabcontent.length; i++) {		tabcontent[i].style.display = 'none';	}	tablinks = document.getElementsByClassName('tablinks');	for (i = 0; i != tablinks.length; i++) {		tablinks[i].className = tablinks[i].className.replace(' active', '');	}	document.getElementById(tab).style.display = 'block';	evt.currentTarget.className += ' active';}document.getElementById('defaultOpen').click();</script>\n")?;
             file.write_all(b"</body></html>")?;
         }
@@ -193,14 +399,42 @@ impl ActionOnKill for WriteReportHtmlFile {
     }
 }
 
+impl ActionOnKill for WriteReportJsonFile {
+    fn run(
+        &self,
+        config: &Config,
+        proc: &ProcessRecord,
+        _pred_mtrx: &RollingFeatures,
+        prediction: f32,
+        now: &String,
+        incident: &IncidentRef,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = config.get_path(Param::ConfigPath).join("threats").join(format!(
+            "{}_{}_report_{}.json",
+            &proc.appname.replace('.', "_"),
+            now,
+            &proc.gid,
+        ));
+        let detection = Detection {
+            incident: Some(schema::IncidentRef::from(incident)),
+            ..Detection::from(proc, prediction)
+        };
+        let envelope = Envelope::new(&AgentIdentity::load(config), Event::Detection(detection));
+        std::fs::write(&path, serde_json::to_string_pretty(&envelope)?)?;
+        info!("Report written to {}", path.display());
+        Ok(())
+    }
+}
+
 impl ActionOnKill for PostReport {
     fn run(
         &self,
         config: &Config,
         proc: &ProcessRecord,
-        pred_mtrx: &VecvecCappedF32,
+        pred_mtrx: &RollingFeatures,
         prediction: f32,
         now: &String,
+        _incident: &IncidentRef,
     ) -> Result<(), Box<dyn Error>> {
         // let mut cs = Connectors::new();
         // cs.add(SitinCloud);
@@ -209,20 +443,101 @@ impl ActionOnKill for PostReport {
     }
 }
 
+impl ActionOnKill for WriteStixBundle {
+    fn run(
+        &self,
+        config: &Config,
+        proc: &ProcessRecord,
+        _pred_mtrx: &RollingFeatures,
+        prediction: f32,
+        now: &String,
+        _incident: &IncidentRef,
+    ) -> Result<(), Box<dyn Error>> {
+        if config.get_bool(Param::StixExport) {
+            stix::export(config, proc, prediction, now)?;
+        }
+        Ok(())
+    }
+}
+
+impl ActionOnKill for WriteFeaturesFile {
+    fn run(
+        &self,
+        config: &Config,
+        proc: &ProcessRecord,
+        pred_mtrx: &RollingFeatures,
+        _prediction: f32,
+        now: &String,
+        _incident: &IncidentRef,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = config.get_path(Param::ConfigPath).join("threats").join(format!(
+            "{}_{}_features_{}.json",
+            &proc.appname.replace('.', "_"),
+            now,
+            &proc.gid,
+        ));
+        std::fs::write(&path, serde_json::to_string(pred_mtrx)?)?;
+        info!("Features written to {}", path.display());
+        Ok(())
+    }
+}
+
+impl ActionOnKill for WriteAffectedFiles {
+    fn run(
+        &self,
+        config: &Config,
+        proc: &ProcessRecord,
+        _pred_mtrx: &RollingFeatures,
+        _prediction: f32,
+        now: &String,
+        _incident: &IncidentRef,
+    ) -> Result<(), Box<dyn Error>> {
+        let manifest = proc.file_table.manifest();
+        if manifest.is_empty() {
+            return Ok(());
+        }
+        let path = config.get_path(Param::ConfigPath).join("threats").join(format!(
+            "{}_{}_affected_{}.json",
+            &proc.appname.replace('.', "_"),
+            now,
+            &proc.gid,
+        ));
+        let content = json!({
+            "gid": proc.gid,
+            "appname": proc.appname,
+            "untracked": proc.file_table.untracked,
+            "files": manifest,
+        });
+        std::fs::write(&path, serde_json::to_string_pretty(&content)?)?;
+        info!("Affected files written to {}", path.display());
+        Ok(())
+    }
+}
+
 impl ActionOnKill for ToastIncident {
     fn run(
         &self,
         config: &Config,
         proc: &ProcessRecord,
-        _pred_mtrx: &VecvecCappedF32,
+        _pred_mtrx: &RollingFeatures,
         _prediction: f32,
         now: &String,
+        incident: &IncidentRef,
     ) -> Result<(), Box<dyn Error>> {
-        let report_dir = Path::new(&config[Param::ConfigPath]).join("threats");
+        if proc.would_kill {
+            // not in front of the users of a server being audited
+            return Ok(());
+        }
+        if !incident.notify {
+            info!(incident = %incident, "Already notified, no toast");
+            return Ok(());
+        }
+        let message = format!("Ransomware detected! {} (incident {})", proc.appname, incident.id);
+        let report_dir = config.get_path(Param::ConfigPath).join("threats");
         if !report_dir.exists() {
             toast(
                 config,
-                &format!("Ransomware detected! {}", proc.appname),
+                &message,
                 "",
             );
             error!(
@@ -239,7 +554,7 @@ impl ActionOnKill for ToastIncident {
             let report_path = temp_report.to_str().unwrap_or("");
             toast(
                 config,
-                &format!("Ransomware detected! {}", proc.appname),
+                &message,
                 report_path,
             );
         }
@@ -247,6 +562,15 @@ impl ActionOnKill for ToastIncident {
     }
 }
 
+/// *Killed*, or *Would have been killed (AUDIT mode)*.
+fn killed_label(config: &Config, proc: &ProcessRecord) -> String {
+    if proc.would_kill {
+        format!("Would have been killed ({} mode)", config.get_scheduled(Param::Mode))
+    } else {
+        String::from("Killed")
+    }
+}
+
 impl Debug for ActionsOnKill {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ActionsOnKill").finish()
@@ -0,0 +1,117 @@
+//! Generation of the Group Policy templates (ADMX and ADML) matching [Param].
+//!
+//! Copy ```owlyshield.admx``` to ```PolicyDefinitions``` and ```owlyshield.adml``` to
+//! ```PolicyDefinitions\en-US``` of the domain central store. Settings are written by the GPO to
+//! [crate::config::POLICY_REGISTRY_KEY] and read by [crate::config::Config].
+
+use std::fs;
+use std::path::Path;
+
+use strum::IntoEnumIterator;
+
+use crate::config::{Param, ParamKind, POLICY_REGISTRY_KEY};
+
+static NAMESPACE: &str = "SitinCloud.Policies.Owlyshield";
+static PREFIX: &str = "owlyshield";
+
+/// Writes *owlyshield.admx* and *en-US/owlyshield.adml* into *dir*.
+pub fn write_templates(dir: &Path) -> Result<(), std::io::Error> {
+    fs::create_dir_all(dir.join("en-US"))?;
+    fs::write(dir.join("owlyshield.admx"), admx())?;
+    fs::write(dir.join("en-US").join("owlyshield.adml"), adml())?;
+    Ok(())
+}
+
+fn policy_name(param: &Param) -> String {
+    Param::convert_to_str(param).to_lowercase()
+}
+
+pub fn admx() -> String {
+    let mut res = String::new();
+    res += "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+    res += "<policyDefinitions xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" revision=\"1.0\" schemaVersion=\"1.0\" xmlns=\"http://schemas.microsoft.com/GroupPolicy/2006/07/PolicyDefinitions\">\n";
+    res += &format!("  <policyNamespaces>\n    <target prefix=\"{}\" namespace=\"{}\" />\n    <using prefix=\"windows\" namespace=\"Microsoft.Policies.Windows\" />\n  </policyNamespaces>\n", PREFIX, NAMESPACE);
+    res += "  <resources minRequiredRevision=\"1.0\" />\n";
+    res += "  <supportedOn>\n    <definitions>\n      <definition name=\"SUPPORTED_OWLYSHIELD\" displayName=\"$(string.SUPPORTED_OWLYSHIELD)\" />\n    </definitions>\n  </supportedOn>\n";
+    res += "  <categories>\n    <category name=\"Owlyshield\" displayName=\"$(string.Owlyshield)\" />\n  </categories>\n";
+    res += "  <policies>\n";
+    for param in Param::iter() {
+        let name = policy_name(&param);
+        let key = Param::convert_to_str(&param);
+        res += &format!(
+            "    <policy name=\"{}\" class=\"Machine\" displayName=\"$(string.{})\" explainText=\"$(string.{}_help)\" presentation=\"$(presentation.{})\" key=\"{}\">\n",
+            name, name, name, name, POLICY_REGISTRY_KEY
+        );
+        res += "      <parentCategory ref=\"Owlyshield\" />\n      <supportedOn ref=\"SUPPORTED_OWLYSHIELD\" />\n      <elements>\n";
+        res += &match param.kind() {
+            ParamKind::Path | ParamKind::Str | ParamKind::Float => format!(
+                "        <text id=\"{}\" valueName=\"{}\" required=\"true\" />\n",
+                name, key
+            ),
+            ParamKind::Int => format!(
+                "        <decimal id=\"{}\" valueName=\"{}\" required=\"true\" />\n",
+                name, key
+            ),
+            ParamKind::Bool => format!(
+                "        <enum id=\"{}\" valueName=\"{}\" required=\"true\">\n          <item displayName=\"$(string.true)\"><value><string>true</string></value></item>\n          <item displayName=\"$(string.false)\"><value><string>false</string></value></item>\n        </enum>\n",
+                name, key
+            ),
+            ParamKind::Choice(choices) => {
+                let mut e = format!("        <enum id=\"{}\" valueName=\"{}\" required=\"true\">\n", name, key);
+                for choice in choices {
+                    e += &format!(
+                        "          <item displayName=\"$(string.{}_{})\"><value><string>{}</string></value></item>\n",
+                        name,
+                        choice.to_lowercase(),
+                        choice
+                    );
+                }
+                e += "        </enum>\n";
+                e
+            }
+        };
+        res += "      </elements>\n    </policy>\n";
+    }
+    res += "  </policies>\n</policyDefinitions>\n";
+    res
+}
+
+pub fn adml() -> String {
+    let mut strings = String::new();
+    let mut presentations = String::new();
+    strings += "      <string id=\"Owlyshield\">Owlyshield</string>\n";
+    strings += "      <string id=\"SUPPORTED_OWLYSHIELD\">Owlyshield agent</string>\n";
+    strings += "      <string id=\"true\">Enabled</string>\n      <string id=\"false\">Disabled</string>\n";
+    for param in Param::iter() {
+        let name = policy_name(&param);
+        strings += &format!("      <string id=\"{}\">{}</string>\n", name, Param::convert_to_str(&param));
+        strings += &format!(
+            "      <string id=\"{}_help\">{}. When set, overrides the local configuration of the agent.</string>\n",
+            name,
+            param.description()
+        );
+        if let ParamKind::Choice(choices) = param.kind() {
+            for choice in choices {
+                strings += &format!("      <string id=\"{}_{}\">{}</string>\n", name, choice.to_lowercase(), choice);
+            }
+        }
+        presentations += &format!("      <presentation id=\"{}\">\n", name);
+        presentations += &match param.kind() {
+            ParamKind::Int => format!("        <decimalTextBox refId=\"{}\">{}</decimalTextBox>\n", name, Param::convert_to_str(&param)),
+            ParamKind::Choice(_) | ParamKind::Bool => format!("        <dropdownList refId=\"{}\">{}</dropdownList>\n", name, Param::convert_to_str(&param)),
+            _ => format!("        <textBox refId=\"{}\"><label>{}</label></textBox>\n", name, Param::convert_to_str(&param)),
+        };
+        presentations += "      </presentation>\n";
+    }
+
+    let mut res = String::new();
+    res += "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+    res += "<policyDefinitionResources xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" revision=\"1.0\" schemaVersion=\"1.0\" xmlns=\"http://schemas.microsoft.com/GroupPolicy/2006/07/PolicyDefinitions\">\n";
+    res += "  <displayName>Owlyshield</displayName>\n  <description>Owlyshield agent settings</description>\n";
+    res += "  <resources>\n    <stringTable>\n";
+    res += &strings;
+    res += "    </stringTable>\n    <presentationTable>\n";
+    res += &presentations;
+    res += "    </presentationTable>\n  </resources>\n</policyDefinitionResources>\n";
+    res
+}
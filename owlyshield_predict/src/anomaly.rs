@@ -0,0 +1,227 @@
+//! Unsupervised anomaly scoring of the behaviour of a gid, for the environments where the
+//! supervised model of [crate::prediction] was not trained on labels representative of their
+//! activity.
+//!
+//! The anomaly model is a second tflite model, trained on the benign activity only, and loaded from
+//! *ConfigPath\anomaly.tflite* with its metadata *ConfigPath\anomaly.json* ([Metadata]). It scores
+//! the last row of the prediction matrix, on the same features (the names of its inputs, if given,
+//! are checked against the [REGISTRY]), standardized with its own means and standard deviations:
+//! * an autoencoder returns the reconstruction of the row (*output = "RECONSTRUCTION"*): its mean
+//!   squared error is mapped to 0..1, 0.5 at the *error_scale* (the error of the benign rows at the
+//!   chosen percentile);
+//! * an isolation forest (or any scorer) returns the anomaly score in 0..1 (*output = "SCORE"*).
+//!
+//! With *ANOMALY_MODEL = STANDALONE* the anomaly score replaces the supervised prediction, with
+//! *ENSEMBLE* it is mixed into it with the weight *ANOMALY_WEIGHT*. Both are then weighted by the
+//! static prediction and the reputation as usual.
+
+use std::fs;
+use std::path::PathBuf;
+
+use byteorder::{ByteOrder, LittleEndian};
+use moonfire_tflite::{Interpreter, Model};
+use serde::Deserialize;
+use tracing::{error, warn};
+
+use crate::config::{Config, Param};
+use crate::error::ModelError;
+use crate::prediction::input_tensors::RollingFeatures;
+use crate::features::REGISTRY;
+use crate::prediction::{short_digest, TfLite};
+
+pub static ANOMALY_MODEL_FILE_NAME: &str = "anomaly.tflite";
+pub static ANOMALY_METADATA_FILE_NAME: &str = "anomaly.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyMode {
+    Off,
+    /// The anomaly score only
+    Standalone,
+    /// Mixed into the supervised prediction
+    Ensemble,
+}
+
+impl AnomalyMode {
+    pub fn from(config: &Config) -> AnomalyMode {
+        match config.get_str(Param::AnomalyModel).to_uppercase().as_str() {
+            "STANDALONE" => AnomalyMode::Standalone,
+            "ENSEMBLE" => AnomalyMode::Ensemble,
+            _ => AnomalyMode::Off,
+        }
+    }
+}
+
+/// What the anomaly model returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Output {
+    /// The standardized row, reconstructed by an autoencoder
+    Reconstruction,
+    /// An anomaly score in 0..1
+    Score,
+}
+
+/// *ConfigPath\anomaly.json*, written with the model.
+#[derive(Debug, Deserialize)]
+pub struct Metadata {
+    /// Needed by Standard Scaling, their length is the number of features used
+    means: Vec<f32>,
+    stdvs: Vec<f32>,
+    /// Names of the inputs, the first columns of the [REGISTRY]. Without them, the alignment of
+    /// the inputs is not checked
+    #[serde(default)]
+    features: Option<Vec<String>>,
+    output: Output,
+    /// Reconstruction error scored 0.5
+    #[serde(default = "default_error_scale")]
+    error_scale: f32,
+}
+
+fn default_error_scale() -> f32 {
+    1.0
+}
+
+pub struct AnomalyModel {
+    model: Model,
+    metadata: Metadata,
+    mode: AnomalyMode,
+    weight: f32,
+    /// See [Self::version]
+    version: String,
+}
+
+impl AnomalyModel {
+    /// The anomaly model of the *config*, None if *ANOMALY_MODEL* is OFF.
+    pub fn from(config: &Config) -> Result<Option<AnomalyModel>, ModelError> {
+        let mode = AnomalyMode::from(config);
+        if mode == AnomalyMode::Off {
+            return Ok(None);
+        }
+        let data = |path: &PathBuf, e: String| ModelError::Data("anomaly", format!("{}: {}", path.display(), e));
+        let model_path = config.get_path(Param::ConfigPath).join(ANOMALY_MODEL_FILE_NAME);
+        let metadata_path = config.get_path(Param::ConfigPath).join(ANOMALY_METADATA_FILE_NAME);
+        let bytes = fs::read(&model_path).map_err(|e| data(&model_path, e.to_string()))?;
+        let json = fs::read(&metadata_path).map_err(|e| data(&metadata_path, e.to_string()))?;
+        let metadata: Metadata = serde_json::from_slice(&json).map_err(|e| data(&metadata_path, e.to_string()))?;
+        if metadata.means.is_empty() || metadata.stdvs.len() < metadata.means.len() || metadata.error_scale <= 0.0 {
+            return Err(data(&metadata_path, String::from("inconsistent means, stdvs or error_scale")));
+        }
+        match &metadata.features {
+            Some(features) if features.len() != metadata.means.len() => {
+                return Err(data(&metadata_path, format!("{} features, {} means", features.len(), metadata.means.len())));
+            }
+            Some(features) => REGISTRY.validate_schema("anomaly", features)?,
+            None => {
+                REGISTRY.validate("anomaly", metadata.means.len())?;
+                warn!(
+                    "{} does not name its features, the model is assumed to use the first {}",
+                    metadata_path.display(),
+                    metadata.means.len()
+                );
+            }
+        }
+        Ok(Some(AnomalyModel {
+            model: Model::from_file(&model_path).map_err(|_| ModelError::Model("anomaly"))?,
+            metadata,
+            mode,
+            weight: config.get_f32(Param::AnomalyWeight).clamp(0.0, 1.0),
+            version: short_digest(&bytes),
+        }))
+    }
+
+    /// Identifies the model: first 12 hex chars of the sha256 of *anomaly.tflite*.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Identifies the models making the predictions, in the audit files.
+    pub fn versions(&self, tflite: &TfLite) -> String {
+        match self.mode {
+            AnomalyMode::Standalone => format!("anomaly-{}", self.version),
+            _ => format!("{}+anomaly-{}", tflite.version(), self.version),
+        }
+    }
+
+    /// The prediction on the sequence *predmtrx*, according to the [AnomalyMode]. None if a
+    /// model fails, see [TfLite::make_prediction].
+    pub fn predict(&self, tflite: &TfLite, predmtrx: &RollingFeatures) -> Option<f32> {
+        let score = self.score(&predmtrx[predmtrx.rows_len() - 1])?;
+        match self.mode {
+            AnomalyMode::Ensemble => Some(mix(self.weight, tflite.make_prediction(predmtrx)?, score)),
+            _ => Some(score),
+        }
+    }
+
+    /// The anomaly score of a row of the prediction matrix, in 0..1. None, with the error logged,
+    /// if the interpreter fails.
+    pub fn score(&self, row: &[f32]) -> Option<f32> {
+        match self.run(row) {
+            Ok(score) => Some(score),
+            Err(e) => {
+                error!("{}, prediction skipped", e);
+                None
+            }
+        }
+    }
+
+    fn run(&self, row: &[f32]) -> Result<f32, ModelError> {
+        let inference = |_| ModelError::Inference(format!("anomaly-{}", self.version));
+        let input = standardize(&self.metadata, row);
+        let mut interpreter = Interpreter::builder().build(&self.model, 1, input.len()).map_err(inference)?;
+        let mut inputs = interpreter.inputs();
+        LittleEndian::write_f32_into(&input, inputs[0].bytes_mut());
+        interpreter.invoke().map_err(inference)?;
+        let outputs = interpreter.outputs();
+        let output = outputs[0].f32s();
+        Ok(match self.metadata.output {
+            Output::Reconstruction => reconstruction_score(&input, output, self.metadata.error_scale),
+            Output::Score => output[0].clamp(0.0, 1.0),
+        })
+    }
+}
+
+/// Standard Scaling of the features of *row* used by the model.
+fn standardize(metadata: &Metadata, row: &[f32]) -> Vec<f32> {
+    let epsilon = 0.0001f32;
+    let cols = metadata.means.len().min(row.len());
+    (0..cols)
+        .map(|j| (row[j] - metadata.means[j]) / metadata.stdvs[j].max(epsilon))
+        .collect()
+}
+
+/// The mean squared error of the reconstruction *output* of *input*, mapped to 0..1.
+fn reconstruction_score(input: &[f32], output: &[f32], error_scale: f32) -> f32 {
+    if input.is_empty() || output.len() < input.len() {
+        return 0.0;
+    }
+    let mse = input.iter().zip(output).map(|(x, y)| (x - y) * (x - y)).sum::<f32>() / input.len() as f32;
+    if mse.is_finite() {
+        mse / (mse + error_scale)
+    } else {
+        1.0
+    }
+}
+
+/// The supervised *prediction* with the anomaly *score* mixed in, with the *weight* of the score.
+fn mix(weight: f32, prediction: f32, score: f32) -> f32 {
+    (1.0 - weight) * prediction + weight * score
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anomaly::{mix, reconstruction_score};
+
+    #[test]
+    fn reconstruction_error_should_be_scored() {
+        let input = [1.0, -1.0, 0.5, 2.0];
+        assert_eq!(reconstruction_score(&input, &input, 0.2), 0.0);
+        // mse of 0.2, at the error scale
+        let output = [1.0 + 0.4f32.sqrt(), -1.0 - 0.4f32.sqrt(), 0.5, 2.0];
+        assert!((reconstruction_score(&input, &output, 0.2) - 0.5).abs() < 1e-5);
+        assert_eq!(reconstruction_score(&input, &[f32::INFINITY; 4], 0.2), 1.0);
+        assert_eq!(reconstruction_score(&input, &[0.0], 0.2), 0.0);
+
+        assert_eq!(mix(0.25, 0.2, 1.0), 0.4);
+        assert_eq!(mix(0.0, 0.2, 1.0), 0.2);
+    }
+}
@@ -0,0 +1,543 @@
+//! Management API, on *127.0.0.1:API_PORT* (0 disables it), for the local tools and the tray app.
+//!
+//! Each request must carry ```Authorization: Bearer <token>```, the token being the content of
+//! *ConfigPath/api_token* (generated on the first start, readable by the administrators only).
+//! Bodies and responses are JSON:
+//!
+//! | Request           | Body                                   | Response                          |
+//! |-------------------|----------------------------------------|-----------------------------------|
+//! | GET /status       |                                        | version, uptime, paused...        |
+//! | GET /gids         |                                        | monitored gids with their scores  |
+//! | GET /alerts       |                                        | last alerts, most recent first    |
+//! | POST /pause       |                                        | no process is killed nor suspended|
+//! | POST /resume      |                                        |                                   |
+//! | POST /exclusions  | ```{"scope", "kind", "value"}```       | see [Exclusions::add]             |
+//! | POST /gids/{gid}/kill  |                                   | kills a suspended gid             |
+//! | POST /gids/{gid}/awake |                                   | resumes a suspended gid           |
+//! | POST /alerts/{gid}/acknowledge |                           | the last alert of the gid         |
+//! | POST /alerts/{gid}/false_positive |                        | same, the executable is excluded  |
+//! | POST /scan        | ```{"path"}```                         | static predictions                |
+//! | GET /isolation    |                                        | network isolation in place, or null|
+//! | POST /isolation/lift |                                     | see [isolation::lift]             |
+//! | POST /follow      | ```{"pid"}``` or ```{"gid"}```         | starts the trace of a gid         |
+//! | GET /follow?since={seq} |                                  | see [crate::follow::TracePage]    |
+//! | DELETE /follow    |                                        | stops the trace                   |
+//! | POST /slack/interactions | form posted by Slack            | see [crate::connectors::slack]    |
+//! | GET /grafana      |                                        | test of the Grafana datasource    |
+//! | POST /grafana/metrics |                                    | names of the [crate::stats]       |
+//! | POST /grafana/query | query of the JSON datasource         | series and tables of [crate::stats]|
+//!
+//! *scope* is *never_monitor* or *never_kill*. The requests are served one at a time: a scan of a
+//! large directory delays the others.
+//!
+//! Pause, resume, exclusions, kill, awake, reviews, lift, follow and its stop also require an
+//! administrator caller, otherwise they are rejected with a 403 (see [crate::authz]). The Slack
+//! interactions carry the signature of Slack instead of the token, and are audited with the Slack
+//! user id.
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+use glob::Pattern;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::authz::{AdminAuthz, Caller};
+use crate::config::{Config, Param};
+use crate::eventstore::EventStore;
+use crate::connectors::slack;
+use crate::connectors::slack::Review;
+use crate::exclusions::{ExclusionScope, Exclusions};
+use crate::follow::Target;
+use crate::identity::AgentIdentity;
+use crate::isolation;
+use crate::prediction_static::TfLiteStatic;
+use crate::service_ctl::Lifecycle;
+use crate::stats;
+use crate::stats::{QueryRequest, StatsError};
+use crate::status::{rfc3339, AgentStatus, Alert};
+use crate::utils::constant_time_eq;
+
+/// Name of the file of the token, in *ConfigPath*.
+pub(crate) const TOKEN_FILE: &str = "api_token";
+/// Larger bodies are rejected.
+const MAX_BODY_LEN: u64 = 64 * 1024;
+
+#[derive(Deserialize)]
+struct ExclusionRequest {
+    scope: String,
+    kind: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct ScanRequest {
+    path: PathBuf,
+}
+
+/// Sets the flag when dropped, even on a panic, so that [serve], [crate::heartbeat::run] and
+/// [crate::broker::Broker::serve] return.
+pub struct StopOnDrop<'a>(pub &'a AtomicBool);
+
+impl Drop for StopOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Serves the requests until *done* is set. Returns immediately if the API is disabled or cannot
+/// be started.
+pub fn serve(config: &Config, lifecycle: &Lifecycle, exclusions: &Exclusions, status: &AgentStatus, done: &AtomicBool) {
+    let port = config.get_usize(Param::ApiPort);
+    if port == 0 {
+        return;
+    }
+    let token = match load_or_create_token(&config.get_path(Param::ConfigPath).join(TOKEN_FILE)) {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Cannot read or create the API token, the API is disabled: {}", e);
+            return;
+        }
+    };
+    let server = match Server::http(("127.0.0.1", port as u16)) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Cannot start the API on port {}: {}", port, e);
+            return;
+        }
+    };
+    info!("API listening on 127.0.0.1:{}", port);
+    let api = Api {
+        config,
+        lifecycle,
+        exclusions,
+        status,
+        token,
+        authz: AdminAuthz::from(config),
+        port: port as u16,
+    };
+    while !done.load(Ordering::SeqCst) {
+        match server.recv_timeout(Duration::from_secs(1)) {
+            Ok(Some(request)) => api.respond(request),
+            Ok(None) => {}
+            Err(e) => {
+                error!("API stopped: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+struct Api<'a> {
+    config: &'a Config,
+    lifecycle: &'a Lifecycle,
+    exclusions: &'a Exclusions,
+    status: &'a AgentStatus,
+    token: String,
+    authz: AdminAuthz,
+    port: u16,
+}
+
+impl Api<'_> {
+    fn respond(&self, mut request: Request) {
+        let slack_interaction = *request.method() == Method::Post && request.url() == slack::INTERACTIONS_PATH;
+        let (code, body) = if slack_interaction {
+            self.slack_interaction(&mut request)
+        } else if self.is_authorized(&request) {
+            self.handle(&mut request)
+        } else {
+            warn!(url = %request.url(), "Unauthorized API request");
+            error_body(401, "Missing or invalid token")
+        };
+        let response = Response::from_string(body.to_string())
+            .with_status_code(code)
+            .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+        if let Err(e) = request.respond(response) {
+            warn!("Cannot send the API response: {}", e);
+        }
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), self.token.as_bytes()))
+    }
+
+    fn handle(&self, request: &mut Request) -> (u16, Value) {
+        let path = request.url().split('?').next().unwrap_or("").trim_end_matches('/').to_string();
+        match (request.method(), path.as_str()) {
+            (Method::Get, "/status") => (200, self.get_status()),
+            (Method::Get, "/gids") => (200, json!(self.status.gids())),
+            (Method::Get, "/alerts") => (200, json!(self.status.alerts())),
+            (Method::Post, "/pause") => self.as_admin(request, "pause", || {
+                info!("Protection paused from the API");
+                self.lifecycle.set_paused(true);
+                (200, json!({ "paused": true }))
+            }),
+            (Method::Post, "/resume") => self.as_admin(request, "resume", || {
+                info!("Protection resumed from the API");
+                self.lifecycle.set_paused(false);
+                (200, json!({ "paused": false }))
+            }),
+            (Method::Post, "/exclusions") => match read_json::<ExclusionRequest>(request) {
+                Ok(rule) => {
+                    let command = format!("exclusion {} {} {}", rule.scope, rule.kind, rule.value);
+                    self.as_admin(request, &command, || self.add_exclusion(&rule))
+                }
+                Err(e) => error_body(400, &e),
+            },
+            (Method::Post, "/scan") => match read_json::<ScanRequest>(request) {
+                Ok(scan) => self.scan(&scan.path),
+                Err(e) => error_body(400, &e),
+            },
+            (Method::Get, "/isolation") => (200, json!(isolation::state(self.config))),
+            (Method::Post, "/isolation/lift") => self.as_admin(request, "lift isolation", || match isolation::lift(self.config) {
+                Ok(count) => {
+                    info!("Network isolation lifted from the API");
+                    (200, json!({ "lifted": count }))
+                }
+                Err(e) => error_body(500, &e.to_string()),
+            }),
+            (Method::Post, "/follow") => match read_json::<Target>(request) {
+                Ok(target) => self.as_admin(request, "follow", || {
+                    info!(?target, "Follow mode started from the API");
+                    self.status.follow.start(target);
+                    (200, json!({ "following": target }))
+                }),
+                Err(e) => error_body(400, &e),
+            },
+            (Method::Get, "/follow") => {
+                let since = query_param(request.url(), "since").and_then(|s| s.parse().ok()).unwrap_or(0);
+                match self.status.follow.poll(since) {
+                    Some(page) => (200, json!(page)),
+                    None => error_body(404, "No gid followed"),
+                }
+            }
+            (Method::Delete, "/follow") => self.as_admin(request, "unfollow", || {
+                info!("Follow mode stopped from the API");
+                (200, json!({ "stopped": self.status.follow.stop() }))
+            }),
+            (Method::Get, "/grafana") => (200, json!({ "status": "ok" })),
+            (Method::Post, "/grafana/metrics") => (200, stats::metrics()),
+            (Method::Post, "/grafana/search") => (200, json!(stats::METRICS)),
+            (Method::Post, "/grafana/query") => match read_json::<QueryRequest>(request) {
+                Ok(query) => self.grafana_query(&query),
+                Err(e) => error_body(400, &e),
+            },
+            (Method::Post, review) if parse_alert_review(review).is_some() => {
+                let (gid, false_positive) = parse_alert_review(review).unwrap();
+                let command = format!("{} {}", if false_positive { "false positive" } else { "acknowledge" }, gid);
+                self.as_admin(request, &command, || match self.review(gid, "api", false_positive) {
+                    Ok(alert) => (200, json!(alert)),
+                    Err(error) => error,
+                })
+            }
+            (Method::Post, gid_command) if parse_gid_command(gid_command).is_some() => {
+                let (gid, command) = parse_gid_command(gid_command).unwrap();
+                self.as_admin(request, &format!("{} {}", command, gid), || self.gid_command(gid, command))
+            }
+            (_, "/status") | (_, "/gids") | (_, "/alerts") | (_, "/pause") | (_, "/resume")
+            | (_, "/exclusions") | (_, "/scan") | (_, "/isolation") | (_, "/isolation/lift") | (_, "/follow") | (_, slack::INTERACTIONS_PATH)
+            | (_, "/grafana") | (_, "/grafana/metrics") | (_, "/grafana/search") | (_, "/grafana/query") => {
+                error_body(405, "Method not allowed")
+            }
+            _ => error_body(404, "Not found"),
+        }
+    }
+
+    /// Runs *f* if the caller is an administrator.
+    fn as_admin<F>(&self, request: &Request, command: &str, f: F) -> (u16, Value)
+    where
+        F: FnOnce() -> (u16, Value),
+    {
+        let caller = request
+            .remote_addr()
+            .filter(|peer| peer.ip().is_loopback())
+            .and_then(|peer: &SocketAddr| Caller::from_connection(peer, self.port));
+        if self.authz.authorize(caller.as_ref(), command) {
+            f()
+        } else {
+            error_body(403, "An administrator is required")
+        }
+    }
+
+    fn grafana_query(&self, query: &QueryRequest) -> (u16, Value) {
+        if !self.config.get_bool(Param::EventStore) {
+            return error_body(404, "The event store is disabled (EVENT_STORE)");
+        }
+        let path = EventStore::path(self.config);
+        match EventStore::open(&path).map_err(StatsError::from).and_then(|store| stats::query(&store, query)) {
+            Ok(results) => (200, results),
+            Err(StatsError::Request(e)) => error_body(400, &e),
+            Err(e) => error_body(500, &e.to_string()),
+        }
+    }
+
+    fn get_status(&self) -> Value {
+        let identity = AgentIdentity::load(self.config);
+        json!({
+            "version": identity.agent_version,
+            "machine_id": identity.machine_id,
+            "hostname": identity.hostname,
+            "started": rfc3339(self.status.time_started),
+            "uptime_secs": SystemTime::now().duration_since(self.status.time_started).unwrap_or_default().as_secs(),
+            "paused": self.lifecycle.is_paused(),
+            "mode": self.config.get_scheduled(Param::Mode),
+            "profile": self.config.active_profile(),
+            "kill_policy": self.config.get_str(Param::KillPolicy),
+            "gids": self.status.gids().len(),
+            "alerts": self.status.alerts().len(),
+            "queued_msgs": self.status.queued_msgs(),
+            "backpressure": self.status.backpressure(),
+            "av": self.status.av.status(),
+        })
+    }
+
+    fn add_exclusion(&self, rule: &ExclusionRequest) -> (u16, Value) {
+        let scope = match rule.scope.as_str() {
+            "never_monitor" => ExclusionScope::NeverMonitor,
+            "never_kill" => ExclusionScope::NeverKill,
+            _ => return error_body(400, &format!("Unknown scope {}", rule.scope)),
+        };
+        match self.exclusions.add(scope, &rule.kind, &rule.value) {
+            Ok(()) => {
+                info!(scope = %rule.scope, kind = %rule.kind, value = %rule.value, "Exclusion added from the API");
+                (200, json!({ "added": true }))
+            }
+            Err(e) => error_body(400, &e),
+        }
+    }
+
+    /// A click on a button of a [slack] message, authenticated by its signature instead of the
+    /// token, which Slack does not have.
+    fn slack_interaction(&self, request: &mut Request) -> (u16, Value) {
+        let secret = match fs::read_to_string(self.config.get_path(Param::ConfigPath).join(slack::SIGNING_SECRET_FILE)) {
+            Ok(secret) => secret,
+            Err(_) => return error_body(404, "Slack is not configured"),
+        };
+        let header = |name: &str| {
+            request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv(name))
+                .map(|h| h.value.as_str().to_string())
+                .unwrap_or_default()
+        };
+        let (timestamp, signature) = (header("X-Slack-Request-Timestamp"), header("X-Slack-Signature"));
+        let mut body = String::new();
+        if let Err(e) = request.as_reader().take(MAX_BODY_LEN).read_to_string(&mut body) {
+            return error_body(400, &e.to_string());
+        }
+        if !slack::verify(secret.trim(), &timestamp, &signature, &body, SystemTime::now()) {
+            warn!("Invalid signature of a Slack interaction");
+            return error_body(401, "Invalid signature");
+        }
+        let interaction = match slack::parse_interaction(&body) {
+            Ok(interaction) => interaction,
+            Err(e) => return error_body(400, &e),
+        };
+        if interaction.machine_id != AgentIdentity::load(self.config).machine_id {
+            // The message of another agent, when the clicks are forwarded to all of them
+            return (200, json!({ "ignored": true }));
+        }
+        let false_positive = interaction.review == Review::FalsePositive;
+        // authenticated by the signature: recorded as the remote commands of the heartbeat
+        self.authz.record_remote(
+            &format!("slack:{}", interaction.user_id),
+            &format!("{} {}", if false_positive { "false positive" } else { "acknowledge" }, interaction.gid),
+            true,
+        );
+        let alert = match self.review(interaction.gid, &interaction.user, false_positive) {
+            Ok(alert) => alert,
+            Err(error) => return error,
+        };
+        let note = if false_positive {
+            format!("Marked as a false positive by {}: {} is not killed anymore", interaction.user, alert.exepath)
+        } else {
+            format!("Acknowledged by {}", interaction.user)
+        };
+        slack::respond(&interaction, &note);
+        (200, json!({ "gid": alert.gid, "false_positive": false_positive }))
+    }
+
+    /// Marks the alerts of *gid* as acknowledged, or as false positives: the executable is then
+    /// never killed, and the gid is resumed if suspended. The connectors are told by the pipeline.
+    fn review(&self, gid: u64, user: &str, false_positive: bool) -> Result<Alert, (u16, Value)> {
+        let alert = self
+            .status
+            .alerts()
+            .into_iter()
+            .find(|a| a.gid == gid)
+            .ok_or_else(|| error_body(404, &format!("No alert of gid {}", gid)))?;
+        if false_positive {
+            self.exclusions
+                .add(ExclusionScope::NeverKill, "paths", &Pattern::escape(&alert.exepath))
+                .map_err(|e| error_body(500, &e))?;
+            if self.status.gids().iter().any(|g| g.gid == gid && g.state == "SUSPENDED") {
+                self.gid_command(gid, "awake");
+            }
+        }
+        info!(gid, user, false_positive, "Alert reviewed");
+        Ok(self.status.review(gid, user, false_positive).unwrap_or(alert))
+    }
+
+    /// Same as the command files of the tray app, processed by
+    /// [crate::worker::process_suspended_procs].
+    fn gid_command(&self, gid: u64, command: &str) -> (u16, Value) {
+        if !self.status.gids().iter().any(|g| g.gid == gid) {
+            return error_body(404, &format!("Unknown gid {}", gid));
+        }
+        let prefix = if command == "kill" { "K" } else { "A" };
+        let dir = self.config.get_path(Param::ConfigPath).join("tmp");
+        match fs::create_dir_all(&dir).and_then(|_| fs::write(dir.join(format!("{}_{}", prefix, gid)), "")) {
+            Ok(()) => (200, json!({ "gid": gid, "command": command })),
+            Err(e) => error_body(500, &e.to_string()),
+        }
+    }
+
+    fn scan(&self, path: &Path) -> (u16, Value) {
+        if !path.exists() {
+            return error_body(400, &format!("{} does not exist", path.display()));
+        }
+        let threshold = self.config.threshold_prediction;
+        let tflite_static = match TfLiteStatic::new() {
+            Ok(tflite_static) => tflite_static,
+            Err(e) => return error_body(500, &e.to_string()),
+        };
+        let results: Vec<Value> = tflite_static
+            .scan(path)
+            .into_iter()
+            .map(|(file, prediction)| {
+                json!({
+                    "path": file.to_string_lossy(),
+                    "prediction": prediction,
+                    "malware": prediction.map(|p| p > threshold),
+                })
+            })
+            .collect();
+        (200, json!(results))
+    }
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(request: &mut Request) -> Result<T, String> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_LEN)
+        .read_to_string(&mut body)
+        .map_err(|e| e.to_string())?;
+    serde_json::from_str(&body).map_err(|e| format!("Invalid body: {}", e))
+}
+
+/// */gids/{gid}/kill* or */gids/{gid}/awake*.
+fn parse_gid_command(path: &str) -> Option<(u64, &str)> {
+    let (gid, command) = path.strip_prefix("/gids/")?.split_once('/')?;
+    match command {
+        "kill" | "awake" => Some((gid.parse().ok()?, command)),
+        _ => None,
+    }
+}
+
+/// */alerts/{gid}/acknowledge* or */alerts/{gid}/false_positive*, with true for the latter.
+fn parse_alert_review(path: &str) -> Option<(u64, bool)> {
+    let (gid, review) = path.strip_prefix("/alerts/")?.split_once('/')?;
+    match review {
+        "acknowledge" | "false_positive" => Some((gid.parse().ok()?, review == "false_positive")),
+        _ => None,
+    }
+}
+
+/// The value of *name* in the query string of *url*.
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    url.split_once('?')?
+        .1
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn error_body(code: u16, message: &str) -> (u16, Value) {
+    (code, json!({ "error": message }))
+}
+
+/// The token stored in *path*, or a new one written there. The file is restricted to SYSTEM and
+/// the administrators (root on Linux), before the token is written.
+pub(crate) fn load_or_create_token(path: &Path) -> Result<String, io::Error> {
+    if let Ok(token) = fs::read_to_string(path) {
+        if !token.trim().is_empty() {
+            restrict(path)?;
+            return Ok(token.trim().to_string());
+        }
+    }
+    let token = Uuid::new_v4().simple().to_string();
+    let mut file = create_restricted(path)?;
+    file.write_all(token.as_bytes())?;
+    info!("New API token written to {}", path.display());
+    Ok(token)
+}
+
+#[cfg(windows)]
+fn create_restricted(path: &Path) -> io::Result<fs::File> {
+    let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+    restrict(path)?;
+    Ok(file)
+}
+
+#[cfg(windows)]
+fn restrict(path: &Path) -> io::Result<()> {
+    crate::selfprotect::restrict_to_admins(path).map_err(|code| io::Error::from_raw_os_error(code as i32))
+}
+
+#[cfg(unix)]
+fn create_restricted(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // the mode only applies to a new file
+    restrict(path)?;
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn restrict(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{parse_alert_review, parse_gid_command};
+    use crate::utils::constant_time_eq;
+
+    #[test]
+    fn tokens_should_be_compared_entirely() {
+        assert!(constant_time_eq(b"0123abcd", b"0123abcd"));
+        assert!(!constant_time_eq(b"0123abcd", b"0123abce"));
+        assert!(!constant_time_eq(b"0123abcd", b"0123abc"));
+    }
+
+    #[test]
+    fn gid_commands_should_be_parsed() {
+        assert_eq!(parse_gid_command("/gids/42/kill"), Some((42, "kill")));
+        assert_eq!(parse_gid_command("/gids/42/awake"), Some((42, "awake")));
+        assert_eq!(parse_gid_command("/gids/42/delete"), None);
+        assert_eq!(parse_gid_command("/gids/abc/kill"), None);
+        assert_eq!(parse_alert_review("/alerts/42/false_positive"), Some((42, true)));
+        assert_eq!(parse_alert_review("/alerts/42/acknowledge"), Some((42, false)));
+        assert_eq!(parse_alert_review("/alerts/42/kill"), None);
+    }
+}
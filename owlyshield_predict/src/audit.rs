@@ -0,0 +1,215 @@
+//! Audit of the predictions, used to retrain the model on real-world data.
+//!
+//! Each prediction made in live mode is appended to *DebugPath\audit\audit_v4_<yyyy-mm-dd>.csv*
+//! (*.csv.zst* with *AUDIT_COMPRESSION = ZSTD*): a new file is started every day. The first line
+//! is the header. Columns are separated by ```;``` and text columns are quoted:
+//!
+//! | Column            | Description                                                      |
+//! |-------------------|------------------------------------------------------------------|
+//! | time              | RFC 3339 local time of the prediction                            |
+//! | gid               | Group identifier of the process family                           |
+//! | appname           | Name of the main process                                         |
+//! | exepath           | Path of the main process                                         |
+//! | model_version     | See [crate::prediction::TfLite::version]                         |
+//! | prediction        | Score of the model, pondered by the static prediction            |
+//! | prediction_static | Score of the static model, empty for a non-PE executable         |
+//! | driver_msg_count  | Number of driver messages received for this gid                  |
+//! | *features*        | The last row of the prediction matrix, see [REGISTRY]            |
+//!
+//! The schema version ([SCHEMA_VERSION]) is part of the file name and is increased with any
+//! change of the columns: v2 added the directory tree features, v3 the magic bytes mismatch
+//! ratio, v4 the ransom note score.
+//!
+//! *AUDIT_SAMPLING* is the fraction of gids audited (0 disables the audit). Sampling is done by
+//! gid, so that the whole sequence of predictions of an audited gid is kept.
+//!
+//! In the *LEARNING* [Mode], all the gids are audited, and the gids which would have been killed
+//! are appended to *DebugPath\audit\would_kill.csv* ([WOULD_KILL_HEADER]).
+//!
+//! The threshold is calibrated on the predictions of the audit files by [crate::calibrate].
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{Local, NaiveDate, SecondsFormat};
+use tracing::error;
+
+use crate::config::{Config, Mode, Param};
+use crate::features::REGISTRY;
+use crate::process::ProcessRecord;
+
+pub static SCHEMA_VERSION: u32 = 4;
+static SEPARATOR: &str = ";";
+static WOULD_KILL_FILE_NAME: &str = "would_kill.csv";
+static WOULD_KILL_HEADER: &str = "time;gid;appname;exepath;user;prediction;threshold;fast_path";
+
+/// Writer of the audit files. Can be shared between threads.
+pub struct AuditLog {
+    dir: PathBuf,
+    sampling_rate: f64,
+    compression: bool,
+    current: Mutex<Option<AuditFile>>,
+}
+
+/// The file of the day.
+struct AuditFile {
+    day: NaiveDate,
+    writer: Box<dyn Write + Send>,
+}
+
+impl AuditLog {
+    pub fn from(config: &Config) -> AuditLog {
+        AuditLog {
+            dir: config.get_path(Param::DebugPath).join("audit"),
+            sampling_rate: if config.get_mode() == Mode::Learning {
+                1.0
+            } else {
+                config.get_f32(Param::AuditSampling) as f64
+            },
+            compression: config.get_str(Param::AuditCompression).eq_ignore_ascii_case("ZSTD"),
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Appends a row for the last prediction of *proc*, if its gid is sampled. *features* is the
+    /// last row of the prediction matrix. Errors are logged.
+    pub fn write(&self, proc: &ProcessRecord, model_version: &str, prediction: f32, features: &[f32]) {
+        if !is_sampled(proc.gid, self.sampling_rate) {
+            return;
+        }
+        let mut columns = vec![
+            Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            proc.gid.to_string(),
+            quote(&proc.appname),
+            quote(&proc.exepath.to_string_lossy()),
+            quote(model_version),
+            prediction.to_string(),
+            proc.prediction_static.map(|p| p.to_string()).unwrap_or_default(),
+            proc.driver_msg_count.to_string(),
+        ];
+        columns.extend(features.iter().map(|f| f.to_string()));
+        if let Err(e) = self.write_line(&columns.join(SEPARATOR)) {
+            error!("Cannot write audit file in {}: {}", self.dir.display(), e);
+        }
+    }
+
+    /// Appends *proc* to the gids which would have been killed. Errors are logged.
+    pub fn write_would_kill(&self, proc: &ProcessRecord, prediction: f32) {
+        let columns = [
+            Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            proc.gid.to_string(),
+            quote(&proc.appname),
+            quote(&proc.exepath.to_string_lossy()),
+            quote(&proc.user()),
+            prediction.to_string(),
+            proc.threshold_prediction.to_string(),
+            quote(&proc.fast_path.verdict().map(|v| v.to_string()).unwrap_or_default()),
+        ];
+        let path = self.dir.join(WOULD_KILL_FILE_NAME);
+        let res = fs::create_dir_all(&self.dir).and_then(|_| {
+            let is_new = !path.exists();
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            if is_new {
+                writeln!(file, "{}", WOULD_KILL_HEADER)?;
+            }
+            writeln!(file, "{}", columns.join(SEPARATOR))
+        });
+        if let Err(e) = res {
+            error!("Cannot write {}: {}", path.display(), e);
+        }
+    }
+
+    fn write_line(&self, line: &str) -> Result<(), io::Error> {
+        let mut current = self.current.lock().unwrap();
+        let today = Local::now().naive_local().date();
+        if current.as_ref().map_or(true, |f| f.day != today) {
+            // the previous file is closed (and its zstd frame finished) on drop
+            *current = None;
+            *current = Some(self.open(today)?);
+        }
+        let file = current.as_mut().unwrap();
+        writeln!(file.writer, "{}", line)?;
+        file.writer.flush()
+    }
+
+    fn open(&self, day: NaiveDate) -> Result<AuditFile, io::Error> {
+        fs::create_dir_all(&self.dir)?;
+        let path = audit_file_path(&self.dir, day, self.compression);
+        let is_new = !path.exists();
+        let file: File = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Concatenated zstd frames are a valid zstd file: appending after a restart is fine
+        let mut writer: Box<dyn Write + Send> = if self.compression {
+            Box::new(zstd::Encoder::new(file, 0)?.auto_finish())
+        } else {
+            Box::new(BufWriter::new(file))
+        };
+        if is_new {
+            writeln!(writer, "{}", header())?;
+        }
+        Ok(AuditFile { day, writer })
+    }
+}
+
+pub fn audit_file_path(dir: &Path, day: NaiveDate, compression: bool) -> PathBuf {
+    let ext = if compression { "csv.zst" } else { "csv" };
+    dir.join(format!("audit_v{}_{}.{}", SCHEMA_VERSION, day.format("%Y-%m-%d"), ext))
+}
+
+pub fn header() -> String {
+    let mut columns = vec![
+        "time",
+        "gid",
+        "appname",
+        "exepath",
+        "model_version",
+        "prediction",
+        "prediction_static",
+        "driver_msg_count",
+    ];
+    columns.extend(REGISTRY.columns());
+    columns.join(SEPARATOR)
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+/// Deterministic sampling of the gids: a gid is either always or never audited.
+fn is_sampled(gid: u64, sampling_rate: f64) -> bool {
+    if sampling_rate <= 0.0 {
+        return false;
+    }
+    if sampling_rate >= 1.0 {
+        return true;
+    }
+    // splitmix64 finalizer, to spread the consecutive gids
+    let mut z = gid.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z as f64 / u64::MAX as f64) < sampling_rate
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::{header, is_sampled};
+    use crate::features::REGISTRY;
+
+    #[test]
+    fn sampling_should_be_stable_and_proportional() {
+        let sampled = (0..10_000u64).filter(|gid| is_sampled(*gid, 0.1)).count();
+        assert!(sampled > 800 && sampled < 1200);
+        assert_eq!(is_sampled(42, 0.5), is_sampled(42, 0.5));
+        assert!(!is_sampled(42, 0.0));
+        assert!(is_sampled(42, 1.0));
+    }
+
+    #[test]
+    fn header_should_contain_all_features() {
+        assert_eq!(header().split(';').count(), 8 + REGISTRY.len());
+    }
+}
@@ -0,0 +1,251 @@
+//! Authorization of the management commands of the local API ([crate::api]) which weaken the
+//! protection: pause, resume, exclusions, kill or awake of a suspended gid.
+//!
+//! The token of the API proves that the caller can read *ConfigPath*. These commands further
+//! require the caller to be an administrator: the process owning the other end of the loopback
+//! connection is looked up, and its user must be in the local Administrators group (with an
+//! elevated token on Windows, root on Linux) or in *ADMIN_GROUP*.
+//!
+//! Each command, allowed or denied, is appended to *DebugPath/audit/admin_commands.log*:
+//! ```text
+//! 2022-07-01T10:12:31+02:00;CORP\alice;S-1-5-21-...-1104;4242;pause;allowed
+//! ```
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{Local, SecondsFormat};
+use tracing::{error, info, warn};
+
+use crate::config::{Config, Param};
+use crate::token::ProcessOwner;
+
+/// Local Administrators (*BUILTIN\Administrators*).
+const ADMINISTRATORS_SID: &str = "S-1-5-32-544";
+const ROOT_UID: &str = "0";
+
+/// The user at the other end of a loopback connection.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub owner: ProcessOwner,
+    /// Unknown on Linux, where the connection only gives the uid
+    pub pid: Option<u32>,
+    /// SIDs and names of the groups of the user (enabled groups of the token on Windows)
+    pub groups: Vec<String>,
+}
+
+impl Caller {
+    /// The caller connected from *peer* to the API listening on *server_port*.
+    #[cfg(windows)]
+    pub fn from_connection(peer: &SocketAddr, server_port: u16) -> Option<Caller> {
+        let pid = tcp_table::owning_pid(peer.port(), server_port)?;
+        Some(Caller {
+            owner: crate::token::owner_from_pid(pid)?,
+            pid: Some(pid),
+            groups: crate::token::groups_from_pid(pid)?,
+        })
+    }
+
+    /// The caller connected from *peer* to the API listening on *server_port*.
+    #[cfg(target_os = "linux")]
+    pub fn from_connection(peer: &SocketAddr, server_port: u16) -> Option<Caller> {
+        let tcp = fs::read_to_string("/proc/net/tcp").ok()?;
+        let uid = socket_uid(&tcp, peer.port(), server_port)?;
+        Some(Caller {
+            owner: crate::token::owner_from_uid(uid)?,
+            pid: None,
+            groups: crate::token::groups_from_uid(uid),
+        })
+    }
+
+    /// In the Administrators group, root, or in *admin_group* (SID or name, empty for none).
+    pub fn is_admin(&self, admin_group: &str) -> bool {
+        if self.owner.sid == ROOT_UID {
+            return true;
+        }
+        let admin_group = admin_group.trim();
+        self.groups.iter().any(|group| {
+            group.eq_ignore_ascii_case(ADMINISTRATORS_SID)
+                || (!admin_group.is_empty() && group.eq_ignore_ascii_case(admin_group))
+        })
+    }
+}
+
+/// Checks the callers of the commands and keeps the audit trail.
+pub struct AdminAuthz {
+    admin_group: String,
+    log_path: PathBuf,
+    log: Mutex<()>,
+}
+
+impl AdminAuthz {
+    pub fn from(config: &Config) -> AdminAuthz {
+        AdminAuthz {
+            admin_group: config.get_str(Param::AdminGroup).to_string(),
+            log_path: config.get_path(Param::DebugPath).join("audit").join("admin_commands.log"),
+            log: Mutex::new(()),
+        }
+    }
+
+    /// Whether *caller* may run *command*. The decision is logged and written to the audit
+    /// trail.
+    pub fn authorize(&self, caller: Option<&Caller>, command: &str) -> bool {
+        let allowed = caller.is_some_and(|c| c.is_admin(&self.admin_group));
+        let user = caller.map_or(String::from("unknown"), |c| c.owner.to_string());
+        if allowed {
+            info!(%user, command, "Admin command allowed");
+        } else {
+            warn!(%user, command, "Admin command denied");
+        }
+        self.record(caller, command, allowed);
+        allowed
+    }
+
+    /// Writes a command received from *origin* (the management server of the heartbeat, see
+    /// [crate::heartbeat], or a Slack user) to the audit trail.
+    pub fn record_remote(&self, origin: &str, command: &str, allowed: bool) {
+        self.write(origin, "", "", command, allowed);
+    }
+
+    fn record(&self, caller: Option<&Caller>, command: &str, allowed: bool) {
+        self.write(
+            &caller.and_then(|c| c.owner.username.clone()).unwrap_or_default(),
+            &caller.map(|c| c.owner.sid.clone()).unwrap_or_default(),
+            &caller.and_then(|c| c.pid).map(|p| p.to_string()).unwrap_or_default(),
+            command,
+            allowed,
+        );
+    }
+
+    fn write(&self, user: &str, sid: &str, pid: &str, command: &str, allowed: bool) {
+        let line = [
+            Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            user.replace(';', ","),
+            sid.to_string(),
+            pid.to_string(),
+            command.replace(';', ","),
+            String::from(if allowed { "allowed" } else { "denied" }),
+        ]
+        .join(";");
+        let _lock = self.log.lock().unwrap();
+        let res = self
+            .log_path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(&self.log_path))
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(e) = res {
+            error!("Cannot write {}: {}", self.log_path.display(), e);
+        }
+    }
+}
+
+/// Uid owning the socket connected from *client_port* to *server_port*, from the content of
+/// */proc/net/tcp*.
+#[cfg(target_os = "linux")]
+fn socket_uid(tcp: &str, client_port: u16, server_port: u16) -> Option<u32> {
+    let port = |address: &str| {
+        address
+            .split(':')
+            .nth(1)
+            .and_then(|p| u16::from_str_radix(p, 16).ok())
+    };
+    tcp.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() > 7 && port(fields[1]) == Some(client_port) && port(fields[2]) == Some(server_port) {
+            fields[7].parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// The IPv4 connections table of *iphlpapi*, with the owning processes.
+#[cfg(windows)]
+mod tcp_table {
+    use std::ffi::c_void;
+
+    const AF_INET: u32 = 2;
+    const TCP_TABLE_OWNER_PID_CONNECTIONS: i32 = 4;
+    const ERROR_INSUFFICIENT_BUFFER: u32 = 122;
+
+    #[repr(C)]
+    struct TcpRow {
+        state: u32,
+        local_addr: u32,
+        /// In network byte order, in the lower 16 bits
+        local_port: u32,
+        remote_addr: u32,
+        remote_port: u32,
+        owning_pid: u32,
+    }
+
+    #[link(name = "iphlpapi")]
+    extern "system" {
+        fn GetExtendedTcpTable(table: *mut c_void, size: *mut u32, order: i32, af: u32, class: i32, reserved: u32) -> u32;
+    }
+
+    fn port(raw: u32) -> u16 {
+        u16::from_be(raw as u16)
+    }
+
+    /// Pid of the process whose connection goes from *client_port* to *server_port*.
+    pub fn owning_pid(client_port: u16, server_port: u16) -> Option<u32> {
+        let mut len: u32 = 16 * 1024;
+        loop {
+            // u32 for the alignment of the rows
+            let mut buffer: Vec<u32> = vec![0; len as usize / 4 + 1];
+            let res = unsafe {
+                GetExtendedTcpTable(
+                    buffer.as_mut_ptr() as *mut c_void,
+                    &mut len,
+                    0,
+                    AF_INET,
+                    TCP_TABLE_OWNER_PID_CONNECTIONS,
+                    0,
+                )
+            };
+            match res {
+                0 => {
+                    let count = buffer[0] as usize;
+                    let rows = unsafe { std::slice::from_raw_parts(buffer.as_ptr().add(1) as *const TcpRow, count) };
+                    return rows
+                        .iter()
+                        .find(|r| port(r.local_port) == client_port && port(r.remote_port) == server_port)
+                        .map(|r| r.owning_pid);
+                }
+                ERROR_INSUFFICIENT_BUFFER if len < 1 << 26 => continue,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authz::Caller;
+    use crate::token::ProcessOwner;
+
+    #[test]
+    fn administrators_and_admin_group_should_be_admins() {
+        let caller = |sid: &str, groups: &[&str]| Caller {
+            owner: ProcessOwner {
+                sid: String::from(sid),
+                username: None,
+                session_id: None,
+            },
+            pid: None,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        };
+        let alice = caller("S-1-5-21-1-2-3-1104", &["S-1-5-32-545", r"CORP\Owly Admins"]);
+        assert!(!alice.is_admin(""));
+        assert!(alice.is_admin(r"corp\owly admins"));
+        assert!(caller("S-1-5-21-1-2-3-500", &["S-1-5-32-544"]).is_admin(""));
+        assert!(caller("0", &[]).is_admin(""));
+        assert!(!caller("1000", &["users"]).is_admin("wheel"));
+    }
+}
@@ -0,0 +1,272 @@
+//! Persistence of the gids: the scheduled tasks and the services they create, to run again after a
+//! kill or a reboot, or to spread the encryption to the other sessions.
+//!
+//! An [AutostartEntry] is recorded by the [AutostartMonitor] of a gid when:
+//! * it writes the definition of a task (*\Windows\System32\Tasks\\*, or a legacy *.job* of
+//!   *\Windows\Tasks\\*), seen by the minifilter or by Sysmon (event 11);
+//! * one of its processes runs *schtasks /create*, *sc create*, *New-Service*,
+//!   *Register-ScheduledTask*..., read from the command line of each new process of the gid, or
+//!   from the process creations of Sysmon (event 1);
+//! * it writes the registry keys of the services or of the task cache, seen by Sysmon (event 13).
+//!
+//! The tasks and services created are features of the model (*persistence_tasks* and
+//! *persistence_services*), and each one is sent once to the connectors as a [Persistence] event.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+
+use crate::correlation::IncidentRef;
+use crate::process::ProcessRecord;
+use crate::sysmon::{SysmonEvent, SysmonEventKind};
+
+/// Entries kept per gid, the first ones.
+const MAX_ENTRIES: usize = 32;
+/// Lowercase directories of the task definitions.
+const TASK_DIRS: [&str; 3] = [r"\windows\system32\tasks\", r"\windows\syswow64\tasks\", r"\windows\tasks\"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutostartKind {
+    ScheduledTask,
+    Service,
+}
+
+impl fmt::Display for AutostartKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            AutostartKind::ScheduledTask => "scheduled task",
+            AutostartKind::Service => "service",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// How the entry was seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutostartSource {
+    /// A task definition written
+    TaskFile,
+    /// A command line creating it
+    CommandLine,
+    /// A registry value written, seen by Sysmon
+    Registry,
+}
+
+impl fmt::Display for AutostartSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            AutostartSource::TaskFile => "task file",
+            AutostartSource::CommandLine => "command line",
+            AutostartSource::Registry => "registry",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutostartEntry {
+    pub kind: AutostartKind,
+    pub source: AutostartSource,
+    /// The path, command line or registry key
+    pub target: String,
+    pub pid: u32,
+    pub time: SystemTime,
+}
+
+impl fmt::Display for AutostartEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}, pid {}): {}", self.kind, self.source, self.pid, self.target)
+    }
+}
+
+/// The scheduled tasks and services created by a gid.
+#[derive(Debug, Default)]
+pub struct AutostartMonitor {
+    pub entries: Vec<AutostartEntry>,
+    /// Not sent to the connectors yet
+    new: usize,
+}
+
+impl AutostartMonitor {
+    pub fn new() -> AutostartMonitor {
+        AutostartMonitor::default()
+    }
+
+    /// A file written or created by the gid.
+    pub fn on_file(&mut self, path: &str, pid: u32, time: SystemTime) {
+        if let Some(kind) = kind_of_path(path) {
+            self.add(kind, AutostartSource::TaskFile, path, pid, time);
+        }
+    }
+
+    /// A process of the gid, started with *command_line*.
+    pub fn on_command_line(&mut self, command_line: &str, pid: u32, time: SystemTime) {
+        if let Some(kind) = kind_of_command_line(command_line) {
+            self.add(kind, AutostartSource::CommandLine, command_line, pid, time);
+        }
+    }
+
+    /// A new process of the gid, whose command line is read.
+    pub fn on_process(&mut self, pid: u32, time: SystemTime) {
+        if let Some(command_line) = command_line(pid) {
+            self.on_command_line(&command_line, pid, time);
+        }
+    }
+
+    /// An event of Sysmon attributed to the gid.
+    pub fn on_sysmon(&mut self, event: &SysmonEvent) {
+        let now = SystemTime::now();
+        match &event.kind {
+            SysmonEventKind::ProcessCreate { command_line, .. } => self.on_command_line(command_line, event.pid, now),
+            SysmonEventKind::FileCreate { target } => self.on_file(target, event.pid, now),
+            SysmonEventKind::RegistrySet { target } => {
+                if let Some(kind) = kind_of_registry_key(target) {
+                    self.add(kind, AutostartSource::Registry, target, event.pid, now);
+                }
+            }
+            SysmonEventKind::NetworkConnect { .. } => {}
+        }
+    }
+
+    pub fn count(&self, kind: AutostartKind) -> usize {
+        self.entries.iter().filter(|entry| entry.kind == kind).count()
+    }
+
+    /// The entries recorded since the last call.
+    pub fn take_new(&mut self) -> Vec<AutostartEntry> {
+        let new = self.entries[self.entries.len() - self.new..].to_vec();
+        self.new = 0;
+        new
+    }
+
+    /// Once per target, the same command line being seen by Sysmon and in the process.
+    fn add(&mut self, kind: AutostartKind, source: AutostartSource, target: &str, pid: u32, time: SystemTime) {
+        let key = normalize(target);
+        if self.entries.len() >= MAX_ENTRIES || self.entries.iter().any(|entry| normalize(&entry.target) == key) {
+            return;
+        }
+        self.entries.push(AutostartEntry {
+            kind,
+            source,
+            target: target.to_string(),
+            pid,
+            time,
+        });
+        self.new += 1;
+    }
+}
+
+/// Lowercase, without the quotes and the repeated spaces.
+fn normalize(target: &str) -> String {
+    target.replace('"', "").to_lowercase().split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+pub fn kind_of_path(path: &str) -> Option<AutostartKind> {
+    let path = path.to_lowercase();
+    TASK_DIRS.iter().any(|dir| path.contains(dir)).then_some(AutostartKind::ScheduledTask)
+}
+
+pub fn kind_of_command_line(command_line: &str) -> Option<AutostartKind> {
+    let command_line = normalize(command_line);
+    let args: Vec<&str> = command_line.split(' ').collect();
+    let program = args.first()?.rsplit(['\\', '/']).next()?;
+    let program = program.strip_suffix(".exe").unwrap_or(program);
+    let has = |options: &[&str]| args.iter().skip(1).any(|arg| options.contains(&arg.trim_start_matches(['/', '-'])));
+    match program {
+        "schtasks" if has(&["create", "change"]) => Some(AutostartKind::ScheduledTask),
+        "sc" if has(&["create", "config"]) => Some(AutostartKind::Service),
+        "reg" if has(&["add", "import"]) => kind_of_registry_key(&command_line),
+        "powershell" | "pwsh" if command_line.contains("register-scheduledtask") => Some(AutostartKind::ScheduledTask),
+        "powershell" | "pwsh" if command_line.contains("new-service") => Some(AutostartKind::Service),
+        _ => None,
+    }
+}
+
+/// Of a registry key of the services or of the task cache, as given by Sysmon (*HKLM\System\...*)
+/// or by the kernel (*\REGISTRY\MACHINE\SYSTEM\...*).
+pub fn kind_of_registry_key(key: &str) -> Option<AutostartKind> {
+    let key = key.to_lowercase();
+    if key.contains(r"\schedule\taskcache\") {
+        Some(AutostartKind::ScheduledTask)
+    } else if key.contains(r"system\currentcontrolset\services\") || (key.contains(r"system\controlset0") && key.contains(r"\services\")) {
+        Some(AutostartKind::Service)
+    } else {
+        None
+    }
+}
+
+fn command_line(pid: u32) -> Option<String> {
+    let mut system = System::new();
+    system.refresh_process(pid as Pid);
+    let cmd = system.process(pid as Pid)?.cmd().join(" ");
+    (!cmd.is_empty()).then_some(cmd)
+}
+
+/// A scheduled task or a service created by a gid, sent to the connectors.
+#[derive(Debug, Clone)]
+pub struct Persistence {
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub entry: AutostartEntry,
+    /// Of the [crate::enrichment] providers, added when sent to the connectors
+    pub tags: Vec<String>,
+    /// Of the [crate::correlation], added when sent to the connectors
+    pub incident: Option<IncidentRef>,
+}
+
+impl Persistence {
+    pub fn from(proc: &ProcessRecord, entry: AutostartEntry) -> Persistence {
+        Persistence {
+            gid: proc.gid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            entry,
+            tags: Vec::new(),
+            incident: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use crate::autostart::{AutostartKind, AutostartMonitor, AutostartSource};
+    use crate::sysmon::{SysmonEvent, SysmonEventKind};
+
+    #[test]
+    fn tasks_and_services_should_be_recorded_once() {
+        let now = SystemTime::now();
+        let mut monitor = AutostartMonitor::new();
+        monitor.on_file(r"\Device\HarddiskVolume3\Windows\System32\Tasks\Updater", 4242, now);
+        monitor.on_file(r"C:\Users\bob\Documents\tasks\notes.txt", 4242, now);
+        monitor.on_command_line(r#"C:\Windows\system32\schtasks.exe /Create /SC ONLOGON /TN "Updater" /TR "C:\Users\bob\AppData\x.exe""#, 4243, now);
+        monitor.on_command_line("schtasks /query /fo list", 4244, now);
+        monitor.on_command_line("sc.exe create evil binPath= C:\\x.exe start= auto", 4245, now);
+        assert_eq!(monitor.take_new().len(), 3);
+
+        // the same command line, from Sysmon
+        monitor.on_sysmon(&SysmonEvent {
+            pid: 4242,
+            kind: SysmonEventKind::ProcessCreate {
+                image: String::from(r"C:\Windows\System32\schtasks.exe"),
+                command_line: String::from(r#"C:\Windows\system32\schtasks.exe  /Create /SC ONLOGON /TN Updater /TR C:\Users\bob\AppData\x.exe"#),
+            },
+        });
+        monitor.on_sysmon(&SysmonEvent {
+            pid: 4242,
+            kind: SysmonEventKind::RegistrySet {
+                target: String::from(r"HKLM\System\CurrentControlSet\Services\evil2\ImagePath"),
+            },
+        });
+        monitor.on_command_line(r"powershell -c Register-ScheduledTask -TaskName x -Action $a", 4246, now);
+        let new = monitor.take_new();
+        assert_eq!(new.len(), 2);
+        assert_eq!((new[0].kind, new[0].source), (AutostartKind::Service, AutostartSource::Registry));
+        assert!(monitor.take_new().is_empty());
+        assert_eq!((monitor.count(AutostartKind::ScheduledTask), monitor.count(AutostartKind::Service)), (3, 2));
+    }
+}
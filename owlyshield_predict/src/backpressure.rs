@@ -0,0 +1,182 @@
+//! Backpressure from the [crate::pipeline] to the minifilter. Once *BACKPRESSURE_QUEUE_DEPTH*
+//! messages wait to be processed, the minifilter is asked to stop sending them, and to count the
+//! irps of each pid instead ([IrpAggregate]), until the queue is back under half of it. The
+//! minifilter also aggregates the irps when its own list is full, instead of dropping them.
+//!
+//! The aggregates are fetched by the fetch stage, and added to the records of their gids
+//! ([crate::process::ProcessRecord::add_aggregate]): the counters and the rates keep up, but not
+//! the features of the files. The aggregates of a gid being processed by a worker wait for the
+//! next fetch. The numbers of aggregated and dropped irps ([BackpressureStats]) are published to
+//! the [crate::status::AgentStatus], for the API and the heartbeat.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::config::{Config, Param};
+use crate::driver_com::shared_def::IrpAggregate;
+use crate::gidmerge::GidMerger;
+use crate::iosource::IoEventSource;
+use crate::process::procs::Procs;
+
+/// Aggregates of a gid without record are dropped after this delay.
+const PENDING_EXPIRY: Duration = Duration::from_secs(60);
+
+/// The irps aggregated by the source since the last call.
+#[derive(Debug, Default)]
+pub struct Aggregates {
+    pub aggregated: u64,
+    /// Irps lost: the aggregates of the source were full
+    pub dropped: u64,
+    pub aggregates: Vec<IrpAggregate>,
+}
+
+/// Since the start of the agent.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct BackpressureStats {
+    /// The source is aggregating the irps
+    pub active: bool,
+    pub activations: u64,
+    pub aggregated_irps: u64,
+    pub dropped_irps: u64,
+}
+
+pub struct Backpressure {
+    /// Queued messages engaging the backpressure, 0 if disabled
+    high: usize,
+    stats: BackpressureStats,
+    /// Aggregates not added to a record yet, by gid and pid
+    pending: HashMap<(u64, u32), (Instant, IrpAggregate)>,
+}
+
+impl Backpressure {
+    pub fn from(config: &Config) -> Backpressure {
+        Backpressure {
+            high: config.get_usize(Param::BackpressureQueueDepth),
+            stats: BackpressureStats::default(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.stats.active
+    }
+
+    pub fn stats(&self) -> BackpressureStats {
+        self.stats
+    }
+
+    /// Engages or releases the backpressure of the *source*, given the *queued* messages.
+    pub fn update(&mut self, source: &dyn IoEventSource, queued: usize) {
+        let active = match self.transition(queued) {
+            Some(active) => active,
+            None => return,
+        };
+        if let Err(e) = source.set_backpressure(active, queued) {
+            warn!("Cannot set the backpressure of the driver: {}", e);
+            return;
+        }
+        self.stats.active = active;
+        if active {
+            self.stats.activations += 1;
+            warn!(queued, "Pipeline falling behind, the driver aggregates the i/o");
+        } else {
+            info!(queued, "Pipeline caught up, the driver sends the i/o again");
+        }
+    }
+
+    /// The new state of the backpressure, if it changes.
+    fn transition(&self, queued: usize) -> Option<bool> {
+        if self.high == 0 {
+            None
+        } else if !self.stats.active && queued >= self.high {
+            Some(true)
+        } else if self.stats.active && queued < self.high / 2 {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Takes the aggregates of the *source*, and adds them to the records of *procs*, under the
+    /// gids of their families for the *merger*.
+    pub fn collect(&mut self, source: &dyn IoEventSource, merger: &GidMerger, procs: &mut Procs) {
+        match source.aggregates() {
+            Ok(reply) => {
+                self.stats.aggregated_irps += reply.aggregated;
+                self.stats.dropped_irps += reply.dropped;
+                if reply.dropped > 0 {
+                    warn!(dropped = reply.dropped, "Driver messages lost, the aggregates of the driver are full");
+                }
+                let now = Instant::now();
+                for mut aggregate in reply.aggregates {
+                    aggregate.gid = merger.family_of(aggregate.gid);
+                    match self.pending.get_mut(&(aggregate.gid, aggregate.pid)) {
+                        Some((_, pending)) => add(pending, &aggregate),
+                        None => {
+                            self.pending.insert((aggregate.gid, aggregate.pid), (now, aggregate));
+                        }
+                    }
+                }
+            }
+            Err(e) => debug!("Cannot get the aggregates of the driver: {}", e),
+        }
+        let now = Instant::now();
+        self.pending.retain(|(gid, _), (since, aggregate)| match procs.get_by_gid_index(*gid) {
+            Some(i) => {
+                procs.procs[i].add_aggregate(aggregate);
+                false
+            }
+            None => now.duration_since(*since) < PENDING_EXPIRY,
+        });
+    }
+}
+
+fn add(into: &mut IrpAggregate, aggregate: &IrpAggregate) {
+    into.bytes_read += aggregate.bytes_read;
+    into.bytes_written += aggregate.bytes_written;
+    for (count, other) in into.ops.iter_mut().zip(aggregate.ops) {
+        *count += other;
+    }
+    for (count, other) in into.file_changes.iter_mut().zip(aggregate.file_changes) {
+        *count += other;
+    }
+    into.high_entropy_writes += aggregate.high_entropy_writes;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::backpressure::{add, Backpressure, BackpressureStats};
+    use crate::driver_com::shared_def::IrpAggregate;
+
+    #[test]
+    fn backpressure_should_be_released_under_half_the_depth() {
+        let mut backpressure = Backpressure {
+            high: 1000,
+            stats: BackpressureStats::default(),
+            pending: HashMap::new(),
+        };
+        assert_eq!(backpressure.transition(999), None);
+        assert_eq!(backpressure.transition(1000), Some(true));
+        backpressure.stats.active = true;
+        assert_eq!(backpressure.transition(600), None);
+        assert_eq!(backpressure.transition(499), Some(false));
+        backpressure.high = 0;
+        assert_eq!(backpressure.transition(1_000_000), None);
+
+        let mut aggregate = IrpAggregate {
+            gid: 7,
+            pid: 42,
+            bytes_written: 4096,
+            ..IrpAggregate::default()
+        };
+        aggregate.ops[2] = 1;
+        let mut pending = aggregate;
+        add(&mut pending, &aggregate);
+        assert_eq!((pending.gid, pending.bytes_written, pending.ops[2]), (7, 8192, 2));
+    }
+}
@@ -0,0 +1,182 @@
+//! Awareness of the backup agents, the most common source of false positives: a backup job reads
+//! and writes a lot of files, in many directories.
+//!
+//! Each [BackupDetector] recognizes the processes of an agent, by the name of the executable and
+//! its signer, and tells whether a job is running from the running processes. While a job runs,
+//! the write volume features of the gids of the agent are multiplied by *BACKUP_WRITE_RELAX* (see
+//! [crate::features]). The features showing an
+//! encryption (entropy, extensions, headers, ransom notes) are left untouched.
+//!
+//! Detectors are provided for Veeam, Acronis and Windows Backup. Others may be added with
+//! [BackupAgents::add].
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use sysinfo::{ProcessExt, System, SystemExt};
+use tracing::info;
+
+use crate::exclusions::ExclusionSubject;
+
+/// Recognizes the processes and the jobs of a backup agent.
+pub trait BackupDetector: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Whether the executable (lowercase file name) may belong to the agent. Only then is its
+    /// signer computed.
+    fn is_candidate(&self, exename: &str) -> bool;
+    /// Whether a candidate executable signed by *signer* (lowercase) belongs to the agent.
+    fn is_agent(&self, signer: Option<&str>) -> bool;
+    /// Whether a job is running, from the lowercase names of the running processes.
+    fn is_job_running(&self, processes: &HashSet<String>) -> bool;
+}
+
+/// An agent whose executables are signed by its editor, and whose jobs run in dedicated
+/// processes.
+pub struct SignedAgent {
+    pub name: &'static str,
+    /// Lowercase subjects of the signer certificates
+    pub signers: &'static [&'static str],
+    /// Lowercase file names of the executables
+    pub executables: &'static [&'static str],
+    /// Lowercase file names of the processes only running during a job
+    pub job_processes: &'static [&'static str],
+}
+
+impl BackupDetector for SignedAgent {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn is_candidate(&self, exename: &str) -> bool {
+        self.executables.contains(&exename)
+    }
+
+    fn is_agent(&self, signer: Option<&str>) -> bool {
+        signer.is_some_and(|s| self.signers.contains(&s))
+    }
+
+    fn is_job_running(&self, processes: &HashSet<String>) -> bool {
+        self.job_processes.iter().any(|p| processes.contains(*p))
+    }
+}
+
+pub const VEEAM: SignedAgent = SignedAgent {
+    name: "Veeam",
+    signers: &["veeam software group gmbh", "veeam software ag"],
+    executables: &[
+        "veeamagent.exe",
+        "veeam.endpoint.service.exe",
+        "veeam.agent.configurator.exe",
+        "veeam.backup.service.exe",
+        "veeam.backup.manager.exe",
+    ],
+    job_processes: &["veeamagent.exe", "veeam.backup.manager.exe"],
+};
+
+pub const ACRONIS: SignedAgent = SignedAgent {
+    name: "Acronis",
+    signers: &["acronis international gmbh"],
+    executables: &[
+        "trueimage.exe",
+        "trueimagehomeservice.exe",
+        "service_process.exe",
+        "backup_worker.exe",
+        "mms.exe",
+        "schedul2.exe",
+    ],
+    job_processes: &["backup_worker.exe", "service_process.exe"],
+};
+
+/// Its executables are in System32, whose processes are not monitored: only their copies elsewhere
+/// are concerned.
+pub const WINDOWS_BACKUP: SignedAgent = SignedAgent {
+    name: "Windows Backup",
+    signers: &["microsoft windows"],
+    executables: &["wbengine.exe", "wbadmin.exe", "sdclt.exe"],
+    job_processes: &["wbengine.exe"],
+};
+
+/// The detectors, and the agents running a job.
+pub struct BackupAgents {
+    detectors: Vec<Box<dyn BackupDetector>>,
+    running_jobs: Mutex<HashSet<&'static str>>,
+}
+
+impl BackupAgents {
+    /// With the detectors of Veeam, Acronis and Windows Backup.
+    pub fn new() -> BackupAgents {
+        let mut agents = BackupAgents {
+            detectors: Vec::new(),
+            running_jobs: Mutex::new(HashSet::new()),
+        };
+        agents.add(VEEAM);
+        agents.add(ACRONIS);
+        agents.add(WINDOWS_BACKUP);
+        agents
+    }
+
+    pub fn add<T: 'static + BackupDetector>(&mut self, detector: T) {
+        self.detectors.push(Box::new(detector));
+    }
+
+    /// The name of the agent the executable of *subject* belongs to.
+    pub fn detect(&self, subject: &mut ExclusionSubject) -> Option<&'static str> {
+        let exename = subject.exepath.file_name()?.to_string_lossy().to_lowercase();
+        let mut candidates = self.detectors.iter().filter(|d| d.is_candidate(&exename)).peekable();
+        candidates.peek()?;
+        let signer = subject.signer().cloned();
+        let agent = candidates.find(|d| d.is_agent(signer.as_deref())).map(|d| d.name());
+        if let Some(agent) = agent {
+            info!(agent, exepath = %subject.exepath.display(), "Backup agent");
+        }
+        agent
+    }
+
+    /// Updates the agents running a job, from the processes of *system* (already refreshed).
+    pub fn refresh_jobs(&self, system: &System) {
+        let processes: HashSet<String> = system.processes().values().map(|p| p.name().to_lowercase()).collect();
+        let running: HashSet<&'static str> = self
+            .detectors
+            .iter()
+            .filter(|d| d.is_job_running(&processes))
+            .map(|d| d.name())
+            .collect();
+        let mut running_jobs = self.running_jobs.lock().unwrap();
+        for started in running.difference(&running_jobs) {
+            info!(agent = started, "Backup job started");
+        }
+        for ended in running_jobs.difference(&running) {
+            info!(agent = ended, "Backup job ended");
+        }
+        *running_jobs = running;
+    }
+
+    pub fn is_job_running(&self, agent: &str) -> bool {
+        self.running_jobs.lock().unwrap().contains(agent)
+    }
+}
+
+impl Default for BackupAgents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::backup::{BackupDetector, VEEAM};
+
+    #[test]
+    fn veeam_should_be_signed_and_run_jobs() {
+        assert!(VEEAM.is_candidate("veeamagent.exe"));
+        assert!(!VEEAM.is_candidate("veeam.exe"));
+        assert!(VEEAM.is_agent(Some("veeam software group gmbh")));
+        assert!(!VEEAM.is_agent(None));
+        let mut processes: HashSet<String> = ["explorer.exe", "veeam.endpoint.service.exe"].iter().map(|p| p.to_string()).collect();
+        assert!(!VEEAM.is_job_running(&processes));
+        processes.insert(String::from("veeamagent.exe"));
+        assert!(VEEAM.is_job_running(&processes));
+    }
+}
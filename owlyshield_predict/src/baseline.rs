@@ -0,0 +1,341 @@
+//! Baseline of the benign activity of a machine, learnt in the *LEARNING* [crate::config::Mode]
+//! before switching to *PROTECT*.
+//!
+//! The executables of the monitored gids are observed during *BASELINE_DAYS* days, with their
+//! hashes and signers, and persisted in *DebugPath\baseline.json*. The executables are hashed in a
+//! background thread, not to slow down the fetch stage. At the end of the period, the executables
+//! seen on at least half of the days are proposed as *never_kill* rules, written to
+//! *ConfigPath\exclusions.proposed.toml* for the admin to review: one *sha256* rule per version of
+//! each executable, never a *signers* nor a *paths* rule, which would also exclude the executables
+//! not observed. The executables found malicious at least once, or scored above
+//! *THRESHOLD_PREDICTION*, are not proposed.
+//!
+//! ```owlyshield_ransom baseline approve``` merges the proposal into *exclusions.toml*.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::config::{Config, Param};
+use crate::exclusions::{signer_subject, ExclusionScope, Exclusions};
+use crate::process::ProcessRecord;
+use crate::utils::sha256_file;
+
+pub static BASELINE_FILE_NAME: &str = "baseline.json";
+pub static PROPOSAL_FILE_NAME: &str = "exclusions.proposed.toml";
+/// Period of the observation of the running gids, and of the saves.
+pub const OBSERVATION_INTERVAL: Duration = Duration::from_secs(600);
+static DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// What is known of an executable, over the period.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Executable {
+    sha256: BTreeSet<String>,
+    signer: Option<String>,
+    /// Days it ran, as *yyyy-mm-dd*
+    days: BTreeSet<String>,
+    gids: usize,
+    max_prediction: f32,
+    /// Times it was found malicious, see [ProcessRecord::would_kill]
+    would_kill: usize,
+    /// The file is hashed again once a day, in case it is updated
+    last_hashed: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct BaselineFile {
+    /// First day of the observation
+    started: Option<String>,
+    /// By path of the executable
+    executables: BTreeMap<String, Executable>,
+}
+
+/// What is observed of a gid, taken out of the [ProcessRecord] so that the executable is hashed
+/// without holding the lock of the procs.
+pub struct Observed {
+    gid: u64,
+    exepath: PathBuf,
+    max_prediction: f32,
+    would_kill: bool,
+}
+
+impl From<&ProcessRecord<'_>> for Observed {
+    fn from(proc: &ProcessRecord) -> Observed {
+        Observed {
+            gid: proc.gid,
+            exepath: proc.exepath.clone(),
+            max_prediction: proc.predictions.get_max_prediction().unwrap_or(0.0),
+            would_kill: proc.would_kill,
+        }
+    }
+}
+
+/// The hashes of an executable, computed by the background thread.
+struct Hashed {
+    path: String,
+    sha256: String,
+    signer: Option<String>,
+}
+
+/// A proposed *never_kill* *sha256* rule.
+#[derive(Debug, PartialEq)]
+struct ProposedRule {
+    value: String,
+    comment: String,
+}
+
+/// Observations of the pipeline, in the *LEARNING* mode.
+pub struct Baseline {
+    path: PathBuf,
+    proposal_path: PathBuf,
+    days: usize,
+    threshold: f32,
+    file: BaselineFile,
+    /// Gids already counted, and those already counted as malicious
+    seen_gids: HashSet<u64>,
+    would_kill_gids: HashSet<u64>,
+    /// Executables to hash, and the thread hashing the previous ones
+    pending: Vec<PathBuf>,
+    hashing: Option<JoinHandle<Vec<Hashed>>>,
+}
+
+impl Baseline {
+    pub fn from(config: &Config) -> Baseline {
+        let path = config.get_path(Param::DebugPath).join(BASELINE_FILE_NAME);
+        Baseline {
+            file: load(&path),
+            path,
+            proposal_path: config.get_path(Param::ConfigPath).join(PROPOSAL_FILE_NAME),
+            days: config.get_usize(Param::BaselineDays).max(1),
+            threshold: config.get_threshold_prediction(),
+            seen_gids: HashSet::new(),
+            would_kill_gids: HashSet::new(),
+            pending: Vec::new(),
+            hashing: None,
+        }
+    }
+
+    pub fn observe(&mut self, proc: &Observed) {
+        if proc.exepath.as_os_str().is_empty() {
+            return;
+        }
+        let today = Local::now().format(DAY_FORMAT).to_string();
+        self.file.started.get_or_insert_with(|| today.clone());
+        let exe = self.file.executables.entry(proc.exepath.to_string_lossy().to_string()).or_default();
+        if exe.last_hashed != today {
+            self.pending.push(proc.exepath.clone());
+            exe.last_hashed = today.clone();
+        }
+        exe.days.insert(today);
+        if self.seen_gids.insert(proc.gid) {
+            exe.gids += 1;
+        }
+        exe.max_prediction = exe.max_prediction.max(proc.max_prediction);
+        if proc.would_kill && self.would_kill_gids.insert(proc.gid) {
+            exe.would_kill += 1;
+        }
+    }
+
+    /// Adds the hashes computed by the background thread, and starts it again on the executables
+    /// observed since.
+    pub fn hash_pending(&mut self) {
+        if self.hashing.as_ref().is_some_and(|hashing| hashing.is_finished()) {
+            if let Some(Ok(hashed)) = self.hashing.take().map(|hashing| hashing.join()) {
+                for hashed in hashed {
+                    if let Some(exe) = self.file.executables.get_mut(&hashed.path) {
+                        exe.sha256.insert(hashed.sha256);
+                        exe.signer = hashed.signer;
+                    }
+                }
+            }
+        }
+        if self.hashing.is_none() && !self.pending.is_empty() {
+            let paths = std::mem::take(&mut self.pending);
+            self.hashing = Some(thread::spawn(move || hash_all(paths)));
+        }
+    }
+
+    /// Saves the observations, and writes the proposal once the period is over.
+    pub fn save(&self) {
+        match serde_json::to_string(&self.file) {
+            Ok(json) => {
+                if let Err(e) = fs::write(&self.path, json) {
+                    error!("Cannot write {}: {}", self.path.display(), e);
+                }
+            }
+            Err(e) => error!("Cannot serialize the baseline: {}", e),
+        }
+        if self.is_complete() && !self.proposal_path.exists() {
+            match write_proposal(&self.file, self.days, self.threshold, &self.proposal_path) {
+                Ok(count) => warn!(
+                    "Baseline of {} days complete: {} rules proposed in {}, approve them before switching to the PROTECT mode",
+                    self.days,
+                    count,
+                    self.proposal_path.display()
+                ),
+                Err(e) => error!("Cannot write {}: {}", self.proposal_path.display(), e),
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        let started = self.file.started.as_ref().and_then(|d| NaiveDate::parse_from_str(d, DAY_FORMAT).ok());
+        started.is_some_and(|started| (Local::now().naive_local().date() - started).num_days() >= self.days as i64)
+    }
+}
+
+fn hash_all(paths: Vec<PathBuf>) -> Vec<Hashed> {
+    paths
+        .into_iter()
+        // the executable may have exited since: keep what is known
+        .filter_map(|path| {
+            let sha256 = sha256_file(&path).ok()?;
+            Some(Hashed {
+                path: path.to_string_lossy().to_string(),
+                sha256,
+                signer: signer_subject(&path),
+            })
+        })
+        .collect()
+}
+
+fn load(path: &Path) -> BaselineFile {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            error!("Invalid baseline {}, starting a new one: {}", path.display(), e);
+            BaselineFile::default()
+        }),
+        Err(_) => BaselineFile::default(),
+    }
+}
+
+/// Writes the proposal of the baseline of *DebugPath* now, whatever the elapsed days. Returns the
+/// number of rules.
+pub fn propose(config: &Config) -> Result<usize, String> {
+    let file = load(&config.get_path(Param::DebugPath).join(BASELINE_FILE_NAME));
+    if file.executables.is_empty() {
+        return Err(String::from("Nothing observed yet: is the LEARNING mode enabled?"));
+    }
+    let path = config.get_path(Param::ConfigPath).join(PROPOSAL_FILE_NAME);
+    let days = config.get_usize(Param::BaselineDays).max(1);
+    write_proposal(&file, days, config.get_threshold_prediction(), &path).map_err(|e| e.to_string())
+}
+
+/// Adds the rules of the proposal to *exclusions.toml*, then deletes the proposal. Returns the
+/// number of rules.
+pub fn approve(config: &Config) -> Result<usize, String> {
+    let path = config.get_path(Param::ConfigPath).join(PROPOSAL_FILE_NAME);
+    let content = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let proposal: toml::Value = content.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    let exclusions = Exclusions::from(&config.get_path(Param::ConfigPath).join("exclusions.toml"));
+    let mut count = 0;
+    if let Some(rules) = proposal.get("never_kill").and_then(|r| r.as_table()) {
+        for (kind, values) in rules {
+            for value in values.as_array().into_iter().flatten().filter_map(|v| v.as_str()) {
+                exclusions.add(ExclusionScope::NeverKill, kind, value)?;
+                count += 1;
+            }
+        }
+    }
+    fs::remove_file(&path).map_err(|e| e.to_string())?;
+    info!(count, "Baseline approved");
+    Ok(count)
+}
+
+fn write_proposal(file: &BaselineFile, days: usize, threshold: f32, path: &Path) -> Result<usize, std::io::Error> {
+    let observed_days = file.executables.values().flat_map(|e| e.days.iter()).collect::<BTreeSet<_>>().len();
+    let rules = propose_rules(file, (observed_days / 2).max(2), threshold);
+    let mut content = format!(
+        "# Proposed by the baseline started on {} ({} days observed of {}).\n\
+         # Review, remove the unwanted rules, then run: owlyshield_ransom baseline approve\n\n[never_kill]\n",
+        file.started.as_deref().unwrap_or("-"),
+        observed_days,
+        days
+    );
+    content.push_str("sha256 = [\n");
+    for rule in &rules {
+        let _ = writeln!(content, "    # {}", rule.comment);
+        let _ = writeln!(content, "    {},", toml::Value::String(rule.value.clone()));
+    }
+    content.push_str("]\n");
+    fs::write(path, content)?;
+    Ok(rules.len())
+}
+
+/// One *sha256* rule per version of the benign executables seen on at least *min_days* days.
+fn propose_rules(file: &BaselineFile, min_days: usize, threshold: f32) -> Vec<ProposedRule> {
+    let benign = file
+        .executables
+        .iter()
+        .filter(|(_, e)| e.days.len() >= min_days && e.would_kill == 0 && e.max_prediction <= threshold);
+    let mut rules = Vec::new();
+    for (path, exe) in benign {
+        for sha256 in &exe.sha256 {
+            rules.push(ProposedRule {
+                value: sha256.clone(),
+                comment: describe(path, exe),
+            });
+        }
+    }
+    rules
+}
+
+fn describe(path: &str, exe: &Executable) -> String {
+    let mut res = format!(
+        "{}: {} days, {} gids, max prediction {:.2}",
+        path,
+        exe.days.len(),
+        exe.gids,
+        exe.max_prediction
+    );
+    if let Some(signer) = &exe.signer {
+        let _ = write!(res, ", signed by {}", signer);
+    }
+    if exe.sha256.len() > 1 {
+        let _ = write!(res, ", {} versions", exe.sha256.len());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::baseline::{propose_rules, BaselineFile, Executable};
+
+    #[test]
+    fn benign_recurring_executables_should_be_proposed_by_hash() {
+        let exe = |hashes: &[&str], signer: Option<&str>, days: &[&str]| Executable {
+            sha256: hashes.iter().map(|h| h.to_string()).collect(),
+            signer: signer.map(String::from),
+            days: days.iter().map(|d| d.to_string()).collect(),
+            max_prediction: 0.1,
+            ..Executable::default()
+        };
+        let mut file = BaselineFile::default();
+        let recurring = ["2022-07-01", "2022-07-02", "2022-07-03"];
+        file.executables.insert(String::from(r"C:\Acme\a.exe"), exe(&["a"], Some("Acme"), &recurring));
+        file.executables.insert(String::from(r"C:\Acme\b.exe"), exe(&["b"], Some("Acme"), &recurring));
+        file.executables.insert(String::from(r"C:\Tools\t.exe"), exe(&["t"], None, &recurring));
+        file.executables.insert(String::from(r"C:\Tools\[u].exe"), exe(&["u1", "u2"], None, &recurring));
+        file.executables.insert(String::from(r"C:\Once\o.exe"), exe(&["o"], None, &["2022-07-01"]));
+        let mut killed = exe(&["k"], Some("Acme"), &recurring);
+        killed.would_kill = 1;
+        file.executables.insert(String::from(r"C:\Acme\k.exe"), killed);
+        let mut suspicious = exe(&["s"], None, &recurring);
+        suspicious.max_prediction = 0.9;
+        file.executables.insert(String::from(r"C:\Tools\s.exe"), suspicious);
+
+        let rules = propose_rules(&file, 2, 0.7);
+        let rules: Vec<&str> = rules.iter().map(|r| r.value.as_str()).collect();
+        assert_eq!(rules, vec!["a", "b", "u1", "u2", "t"]);
+    }
+}
@@ -0,0 +1,214 @@
+//! Broker of the driver messages, for the auxiliary tools (recorders, debuggers, notebooks): the
+//! filter port accepts only one connection, taken by the agent.
+//!
+//! The [IOMessage]s fetched by the pipeline are republished on *127.0.0.1:BROKER_PORT* (0 disables
+//! it). A subscriber connects, sends the token of *ConfigPath/api_token* on the first line, then
+//! receives the messages as JSON lines:
+//! ```text
+//! $ (cat api_token; echo) | nc 127.0.0.1 7479
+//! {"extra":{...},"pid":4242,"gid":12,...}
+//! ```
+//! The subscribers never slow the protection down: each one has a queue of [QUEUE_LEN] batches,
+//! and the batches are dropped while it is full. Nothing is serialized without a subscriber.
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error, info, warn};
+
+use crate::api::{load_or_create_token, TOKEN_FILE};
+use crate::config::{Config, Param};
+use crate::driver_com::shared_def::IOMessage;
+use crate::utils::constant_time_eq;
+
+/// Batches (one per fetch of the driver) queued per subscriber.
+pub const QUEUE_LEN: usize = 1024;
+const MAX_SUBSCRIBERS: usize = 8;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// A subscriber not reading for longer is disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Subscriber {
+    peer: SocketAddr,
+    sender: SyncSender<Arc<str>>,
+    dropped: usize,
+}
+
+pub struct Broker {
+    listener: TcpListener,
+    token: String,
+    subscribers: Mutex<Vec<Subscriber>>,
+    /// Read by [Broker::publish] without taking the lock
+    subscribed: AtomicUsize,
+}
+
+impl Broker {
+    /// The broker listening on *BROKER_PORT*, if enabled and started.
+    pub fn from(config: &Config) -> Option<Broker> {
+        let port = config.get_usize(Param::BrokerPort);
+        if port == 0 {
+            return None;
+        }
+        let token = match load_or_create_token(&config.get_path(Param::ConfigPath).join(TOKEN_FILE)) {
+            Ok(token) => token,
+            Err(e) => {
+                error!("Cannot read or create the API token, the broker is disabled: {}", e);
+                return None;
+            }
+        };
+        match Self::bind(port as u16, token) {
+            Ok(broker) => {
+                info!("Broker listening on 127.0.0.1:{}", port);
+                Some(broker)
+            }
+            Err(e) => {
+                error!("Cannot start the broker on port {}: {}", port, e);
+                None
+            }
+        }
+    }
+
+    fn bind(port: u16, token: String) -> Result<Broker, std::io::Error> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(Broker {
+            listener,
+            token,
+            subscribers: Mutex::new(Vec::new()),
+            subscribed: AtomicUsize::new(0),
+        })
+    }
+
+    /// Accepts the subscribers until *done* is set.
+    pub fn serve(&self, done: &AtomicBool) {
+        thread::scope(|s| {
+            while !done.load(Ordering::SeqCst) {
+                match self.listener.accept() {
+                    Ok((stream, peer)) => {
+                        let spawned = thread::Builder::new()
+                            .name(String::from("broker-subscriber"))
+                            .spawn_scoped(s, move || self.run_subscriber(stream, peer));
+                        if let Err(e) = spawned {
+                            error!(%peer, "Cannot start the broker subscriber thread: {}", e);
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(200)),
+                    Err(e) => warn!("Broker: cannot accept a subscriber: {}", e),
+                }
+            }
+            // the writers return once their sender is dropped
+            self.subscribers.lock().unwrap().clear();
+            self.subscribed.store(0, Ordering::SeqCst);
+        });
+    }
+
+    /// Queues the messages of a fetch for every subscriber.
+    pub fn publish(&self, events: &[IOMessage]) {
+        if events.is_empty() || self.subscribed.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut batch = String::new();
+        for iomsg in events {
+            match serde_json::to_string(iomsg) {
+                Ok(json) => {
+                    batch.push_str(&json);
+                    batch.push('\n');
+                }
+                Err(e) => debug!(gid = iomsg.gid, "Broker: cannot serialize a message: {}", e),
+            }
+        }
+        self.publish_batch(Arc::from(batch));
+    }
+
+    fn publish_batch(&self, batch: Arc<str>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain_mut(|subscriber| match subscriber.sender.try_send(batch.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                if subscriber.dropped == 0 {
+                    warn!(peer = %subscriber.peer, "Broker: slow subscriber, messages dropped");
+                }
+                subscriber.dropped += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                info!(peer = %subscriber.peer, dropped = subscriber.dropped, "Broker: subscriber disconnected");
+                false
+            }
+        });
+        self.subscribed.store(subscribers.len(), Ordering::Relaxed);
+    }
+
+    fn subscribe(&self, peer: SocketAddr) -> Option<Receiver<Arc<str>>> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.len() >= MAX_SUBSCRIBERS {
+            return None;
+        }
+        let (sender, receiver) = sync_channel(QUEUE_LEN);
+        subscribers.push(Subscriber { peer, sender, dropped: 0 });
+        self.subscribed.store(subscribers.len(), Ordering::Relaxed);
+        Some(receiver)
+    }
+
+    fn run_subscriber(&self, mut stream: TcpStream, peer: SocketAddr) {
+        if let Err(e) = self.handshake(&stream) {
+            warn!(%peer, "Broker: subscriber rejected: {}", e);
+            return;
+        }
+        let receiver = match self.subscribe(peer) {
+            Some(receiver) => receiver,
+            None => {
+                warn!(%peer, "Broker: subscriber rejected: more than {} subscribers", MAX_SUBSCRIBERS);
+                return;
+            }
+        };
+        info!(%peer, "Broker: new subscriber");
+        for batch in receiver {
+            if let Err(e) = stream.write_all(batch.as_bytes()) {
+                // the next publish removes the subscriber
+                debug!(%peer, "Broker: cannot write to the subscriber: {}", e);
+                return;
+            }
+        }
+    }
+
+    /// Checks the token of the first line.
+    fn handshake(&self, stream: &TcpStream) -> Result<(), String> {
+        stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(|e| e.to_string())?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT)).map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        BufReader::new(stream.take(256)).read_line(&mut line).map_err(|e| e.to_string())?;
+        if constant_time_eq(line.trim().as_bytes(), self.token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(String::from("invalid token"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::broker::{Broker, QUEUE_LEN};
+
+    #[test]
+    fn slow_subscribers_should_drop_batches() {
+        let broker = Broker::bind(0, String::from("token")).unwrap();
+        let receiver = broker.subscribe("127.0.0.1:1".parse().unwrap()).unwrap();
+        for _ in 0..QUEUE_LEN + 2 {
+            broker.publish_batch(Arc::from("{}\n"));
+        }
+        assert_eq!(broker.subscribers.lock().unwrap()[0].dropped, 2);
+        assert_eq!(receiver.try_iter().count(), QUEUE_LEN);
+        drop(receiver);
+        broker.publish_batch(Arc::from("{}\n"));
+        assert!(broker.subscribers.lock().unwrap().is_empty());
+    }
+}
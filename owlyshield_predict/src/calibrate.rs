@@ -0,0 +1,231 @@
+//! Calibration of *THRESHOLD_PREDICTION* on the audit files of a machine in the *AUDIT* or
+//! *LEARNING* [crate::config::Mode], whose activity is assumed benign.
+//!
+//! A process family is killed as soon as one of its predictions is above the threshold: the
+//! distribution of the highest prediction of each family ([Distribution]) gives the false positives
+//! of any threshold. For a budget of false positives per week, [suggest] returns the lowest
+//! threshold which would have killed at most the budget over the days audited.
+//!
+//! ```owlyshield_ransom calibrate --fp-per-week 1 --write``` writes the threshold into
+//! *ConfigPath\owlyshield.toml*. Only the predictions of one model version are used, the most
+//! recent by default: the scores of different models are not comparable.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Budgets of false positives per week shown by the calibration.
+pub const BUDGETS: [f32; 5] = [0.1, 0.5, 1.0, 2.0, 5.0];
+/// Suggestions are never lower.
+pub const MIN_THRESHOLD: f32 = 0.5;
+const THRESHOLD_KEY: &str = "threshold_prediction";
+
+/// The highest prediction of each process family of the audit files.
+#[derive(Debug, Default)]
+pub struct Distribution {
+    pub model_version: String,
+    /// Days with predictions
+    pub days: usize,
+    pub predictions: usize,
+    /// Highest first
+    maxima: Vec<f32>,
+}
+
+impl Distribution {
+    /// Reads the audit files of *dir* (*.csv* and *.csv.zst*), keeping the predictions of
+    /// *model_version*, or of the version of the most recent prediction.
+    pub fn load(dir: &Path, model_version: Option<&str>) -> Result<Distribution, String> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                name.starts_with("audit_v") && (name.ends_with(".csv") || name.ends_with(".csv.zst"))
+            })
+            .collect();
+        // the dates are part of the names
+        files.sort();
+        let mut rows = Vec::new();
+        for path in files.iter().rev() {
+            let content = read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            rows.extend(parse(&content));
+        }
+        let model_version = match model_version {
+            Some(version) => version.to_string(),
+            None => rows.iter().max_by(|a, b| a.time.cmp(&b.time)).map(|r| r.model_version.clone()).unwrap_or_default(),
+        };
+        Ok(Distribution::from_rows(model_version, rows.iter()))
+    }
+
+    fn from_rows<'a>(model_version: String, rows: impl Iterator<Item = &'a AuditRow>) -> Distribution {
+        let mut maxima: HashMap<(&str, &str), f32> = HashMap::new();
+        let mut days = BTreeSet::new();
+        let mut predictions = 0;
+        for row in rows.filter(|r| r.model_version == model_version) {
+            predictions += 1;
+            days.insert(row.time.get(..10).unwrap_or_default());
+            // the gids are reset by a reboot
+            let max = maxima.entry((&row.gid, &row.exepath)).or_insert(0.0);
+            *max = max.max(row.prediction);
+        }
+        let mut maxima: Vec<f32> = maxima.into_values().collect();
+        maxima.sort_by(|a, b| b.total_cmp(a));
+        Distribution {
+            model_version,
+            days: days.len(),
+            predictions,
+            maxima,
+        }
+    }
+
+    pub fn families(&self) -> usize {
+        self.maxima.len()
+    }
+
+    /// Process families which would have been killed with *threshold*.
+    pub fn false_positives(&self, threshold: f32) -> usize {
+        self.maxima.iter().take_while(|max| **max > threshold).count()
+    }
+
+    /// The false positives of *threshold*, per week.
+    pub fn false_positives_per_week(&self, threshold: f32) -> f32 {
+        self.false_positives(threshold) as f32 * 7.0 / self.days.max(1) as f32
+    }
+}
+
+/// The lowest threshold with at most *fp_per_week* false positives in *distribution*, and at
+/// least [MIN_THRESHOLD]: the highest prediction of the first family spared.
+pub fn suggest(distribution: &Distribution, fp_per_week: f32) -> f32 {
+    let allowed = (fp_per_week.max(0.0) * distribution.days as f32 / 7.0).floor() as usize;
+    distribution.maxima.get(allowed).copied().unwrap_or(0.0).clamp(MIN_THRESHOLD, 1.0)
+}
+
+/// Sets *THRESHOLD_PREDICTION* in the configuration file *path*, keeping its other lines.
+pub fn write_threshold(path: &Path, threshold: f32, comment: &str) -> Result<(), String> {
+    let content = if path.exists() {
+        fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?
+    } else {
+        String::new()
+    };
+    let content = set_threshold(&content, threshold, comment);
+    content.parse::<toml::Value>().map_err(|e| format!("{}: {}", path.display(), e))?;
+    fs::write(path, content).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// *content* with the top-level key *threshold_prediction* replaced, or added before the first
+/// table.
+fn set_threshold(content: &str, threshold: f32, comment: &str) -> String {
+    let line = format!("{} = {} # {}", THRESHOLD_KEY, threshold, comment);
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    let first_table = lines.iter().position(|l| l.trim_start().starts_with('[')).unwrap_or(lines.len());
+    let existing = lines[..first_table].iter().position(|l| {
+        l.split('=').next().is_some_and(|key| key.trim().eq_ignore_ascii_case(THRESHOLD_KEY))
+    });
+    match existing {
+        Some(i) => lines[i] = line,
+        None => lines.insert(first_table, line),
+    }
+    let mut content = lines.join("\n");
+    content.push('\n');
+    content
+}
+
+/// The columns of an audit row used by the calibration.
+#[derive(Debug)]
+struct AuditRow {
+    time: String,
+    gid: String,
+    exepath: String,
+    model_version: String,
+    prediction: f32,
+}
+
+fn read(path: &Path) -> std::io::Result<String> {
+    let mut content = String::new();
+    if path.extension().is_some_and(|ext| ext == "zst") {
+        zstd::Decoder::new(fs::File::open(path)?)?.read_to_string(&mut content)?;
+    } else {
+        content = fs::read_to_string(path)?;
+    }
+    Ok(content)
+}
+
+/// The rows of an audit file, see [crate::audit::header]. The headers and the invalid lines are skipped.
+fn parse(content: &str) -> Vec<AuditRow> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let columns = split(line);
+            Some(AuditRow {
+                time: columns.first()?.clone(),
+                gid: columns.get(1)?.clone(),
+                exepath: columns.get(3)?.clone(),
+                model_version: columns.get(4)?.clone(),
+                prediction: columns.get(5)?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// The columns of a line, unquoted.
+fn split(line: &str) -> Vec<String> {
+    let mut columns = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                columns.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ';' if !quoted => columns.push(String::new()),
+            c => columns.last_mut().unwrap().push(c),
+        }
+    }
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calibrate::{parse, set_threshold, suggest, Distribution};
+
+    #[test]
+    fn threshold_should_fit_the_false_positive_budget() {
+        let mut content = String::from("time;gid;appname;exepath;model_version;prediction\n");
+        // 14 days, 20 families with maxima 0.60, 0.61... 0.79
+        for i in 0..20 {
+            let day = 1 + i % 14;
+            for p in &[String::from("0.1"), format!("0.{}", 60 + i), String::from("0.3")] {
+                content.push_str(&format!(
+                    "2022-03-{:02}T10:00:00.000+01:00;{};\"a;b.exe\";\"C:\\a;b.exe\";\"abc\";{}\n",
+                    day, i, p
+                ));
+            }
+        }
+        content.push_str("2022-03-20T10:00:00.000+01:00;1;\"x.exe\";\"C:\\x.exe\";\"old\";0.99\n");
+        let rows = parse(&content);
+        assert_eq!(rows.len(), 61);
+        assert_eq!(rows[0].exepath, "C:\\a;b.exe");
+
+        let distribution = Distribution::from_rows(String::from("abc"), rows.iter());
+        assert_eq!((distribution.days, distribution.families(), distribution.predictions), (14, 20, 60));
+        // 2 over 2 weeks: the 3rd highest
+        assert_eq!(suggest(&distribution, 1.0), 0.77);
+        assert_eq!(distribution.false_positives(0.77), 2);
+        assert_eq!(distribution.false_positives_per_week(0.77), 1.0);
+        assert_eq!(suggest(&distribution, 0.1), 0.79);
+        assert_eq!(suggest(&distribution, 100.0), 0.5);
+
+        let toml = "log_level = \"INFO\"\nTHRESHOLD_PREDICTION = 0.65\n\n[profiles.night]\nTHRESHOLD_PREDICTION = 0.55\n";
+        assert_eq!(
+            set_threshold(toml, 0.77, "calibrated"),
+            "log_level = \"INFO\"\nthreshold_prediction = 0.77 # calibrated\n\n[profiles.night]\nTHRESHOLD_PREDICTION = 0.55\n"
+        );
+        assert_eq!(
+            set_threshold("[profiles.night]\n", 0.77, "calibrated"),
+            "threshold_prediction = 0.77 # calibrated\n[profiles.night]\n"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Capture of the files of the watched gids before they are overwritten or deleted (*CAPTURE*), so
+//! that the files hit before the kill can be recovered.
+//!
+//! Once a gid enters Watch ([crate::escalation]), the minifilter is told to capture it
+//! ([IoEventSource::capture_gid]) until it cools down to Normal: before each of its writes and
+//! deletions, the minifilter sends a [CaptureRequest] and holds the operation until the agent
+//! replies, two seconds at most (*CAPTURE_TIMEOUT_MS*). The paging writes are not held.
+//!
+//! The [CaptureStore] copies each file once, if not larger than *CAPTURE_MAX_FILE_KB*, into
+//! *ConfigPath\capture\<gid>\<volume serial>_<file id>*, and appends a [CapturedFile] line to the
+//! *index.jsonl* of the gid: by its file id, a copy is joined to the affected-files manifest of the
+//! gid ([crate::filetable]). Once the store reaches *CAPTURE_QUOTA_MB*, nothing more is captured.
+//! The store is only accessible to SYSTEM and the administrators, and the captures of a gid are
+//! deleted *CAPTURE_RETENTION_DAYS* days after its last one, freeing the quota.
+//!
+//! The files opened by the gid without sharing the reads cannot be copied, and the files truncated
+//! when opened (overwrite and supersede dispositions) or deleted on close are seen too late.
+
+use std::collections::HashSet;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{Config, Param};
+use crate::iosource::IoEventSource;
+use crate::process::FileId;
+use crate::schema::rfc3339;
+use crate::utils::extended_path;
+use crate::volumes;
+
+/// Directory of the store, in *ConfigPath*.
+pub const CAPTURE_DIR: &str = "capture";
+/// Wait for a request before the stop is checked again.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Beyond, the files of the run are not captured anymore.
+const MAX_SEEN: usize = 100_000;
+/// Period of the deletion of the expired captures.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+/// Name of the index of the captures of a gid, in its directory.
+const INDEX_FILE: &str = "index.jsonl";
+
+/// A file about to be written or deleted by a captured gid.
+#[derive(Debug, Clone)]
+pub struct CaptureRequest {
+    /// Given by the source, to reply
+    pub message_id: u64,
+    pub gid: u64,
+    pub pid: u32,
+    pub file_id: FileId,
+    /// As reported by the minifilter
+    pub path: String,
+    pub deleted: bool,
+}
+
+/// A line of the *index.jsonl* of a gid.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CapturedFile {
+    pub volume_serial: u64,
+    /// Hexadecimal
+    pub file_id: String,
+    pub path: String,
+    /// Name of the copy, in the directory of the gid
+    pub item: String,
+    pub pid: u32,
+    /// *write* or *delete*
+    pub operation: &'static str,
+    pub size: u64,
+    /// RFC 3339, in UTC
+    pub time: String,
+}
+
+pub struct CaptureStore {
+    dir: PathBuf,
+    max_file_size: u64,
+    quota: u64,
+    retention: Duration,
+    /// Bytes in the store
+    used: u64,
+    /// Files already captured or skipped
+    seen: HashSet<FileId>,
+}
+
+impl CaptureStore {
+    pub fn from(config: &Config) -> CaptureStore {
+        let dir = config.get_path(Param::ConfigPath).join(CAPTURE_DIR);
+        let mut store = CaptureStore::new(
+            dir,
+            config.get_usize(Param::CaptureMaxFileKb) as u64 * 1024,
+            config.get_usize(Param::CaptureQuotaMb) as u64 * 1024 * 1024,
+            Duration::from_secs(config.get_usize(Param::CaptureRetentionDays) as u64 * 24 * 3600),
+        );
+        store.used = dir_size(&store.dir);
+        store
+    }
+
+    pub fn new(dir: PathBuf, max_file_size: u64, quota: u64, retention: Duration) -> CaptureStore {
+        CaptureStore {
+            dir,
+            max_file_size,
+            quota,
+            retention,
+            used: 0,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Deletes the captures of the gids whose last one is older than the retention.
+    pub fn purge(&mut self, now: SystemTime) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for dir in entries.flatten().map(|entry| entry.path()).filter(|path| path.is_dir()) {
+            let last = fs::metadata(dir.join(INDEX_FILE)).or_else(|_| fs::metadata(&dir)).and_then(|m| m.modified());
+            if last.is_ok_and(|last| now.duration_since(last).unwrap_or_default() > self.retention) {
+                let size = dir_size(&dir);
+                match fs::remove_dir_all(&dir) {
+                    Ok(()) => {
+                        info!(dir = %dir.display(), size, "Expired captures deleted");
+                        self.used = self.used.saturating_sub(size);
+                    }
+                    Err(e) => error!(dir = %dir.display(), "Cannot delete the expired captures: {}", e),
+                }
+            }
+        }
+    }
+
+    /// Copies the file of *request*, the first time it is asked for. Returns the copy made, None
+    /// if the file was already seen, is empty, too large or over the quota.
+    pub fn capture(&mut self, request: &CaptureRequest, path: &Path) -> io::Result<Option<CapturedFile>> {
+        if request.file_id.is_null() || self.seen.len() >= MAX_SEEN || !self.seen.insert(request.file_id.clone()) {
+            return Ok(None);
+        }
+        let mut source = open_shared(&extended_path(path))?;
+        let size = source.metadata()?.len();
+        if size == 0 || size > self.max_file_size {
+            return Ok(None);
+        }
+        if self.used + size > self.quota {
+            debug!(gid = request.gid, path = %path.display(), "Capture quota reached");
+            return Ok(None);
+        }
+        if !self.dir.exists() {
+            create_dir(&self.dir)?;
+        }
+        let dir = self.dir.join(request.gid.to_string());
+        fs::create_dir_all(&dir)?;
+        let file_id: String = request.file_id.file_id.iter().map(|b| format!("{:02x}", b)).collect();
+        let item = format!("{:016x}_{}", request.file_id.volume_serial, file_id);
+        let copied = io::copy(&mut source, &mut File::create(dir.join(&item))?)?;
+        self.used += copied;
+        let captured = CapturedFile {
+            volume_serial: request.file_id.volume_serial,
+            file_id,
+            path: path.to_string_lossy().to_string(),
+            item,
+            pid: request.pid,
+            operation: if request.deleted { "delete" } else { "write" },
+            size: copied,
+            time: rfc3339(SystemTime::now()),
+        };
+        let mut index = OpenOptions::new().create(true).append(true).open(dir.join(INDEX_FILE))?;
+        writeln!(index, "{}", serde_json::to_string(&captured)?)?;
+        Ok(Some(captured))
+    }
+}
+
+/// Answers the capture requests of *source* until *done*.
+pub fn serve(source: &dyn IoEventSource, config: &Config, done: &AtomicBool) {
+    let mut store = CaptureStore::from(config);
+    store.purge(SystemTime::now());
+    info!(used = store.used, quota = store.quota, "Capture of the files of the watched gids started");
+    let mut last_purge = Instant::now();
+    while !done.load(Ordering::Relaxed) {
+        if last_purge.elapsed() >= PURGE_INTERVAL {
+            store.purge(SystemTime::now());
+            last_purge = Instant::now();
+        }
+        if let Some(request) = source.next_capture_request(POLL_INTERVAL) {
+            let path = PathBuf::from(volumes::normalize(&request.path));
+            let captured = match store.capture(&request, &path) {
+                Ok(Some(captured)) => {
+                    info!(gid = request.gid, path = %captured.path, item = %captured.item, "File captured");
+                    true
+                }
+                Ok(None) => false,
+                Err(e) => {
+                    warn!(gid = request.gid, path = %path.display(), "Cannot capture the file: {}", e);
+                    false
+                }
+            };
+            source.reply_capture(&request, captured);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn create_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::create_dir_all(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
+
+#[cfg(windows)]
+fn create_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    crate::selfprotect::restrict_to_admins(dir).map_err(|code| io::Error::from_raw_os_error(code as i32))
+}
+
+/// Opened for reading, while the gid writes or deletes it.
+fn open_shared(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE
+        options.share_mode(0x7);
+    }
+    options.open(path)
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| match entry.file_type() {
+                    Ok(t) if t.is_dir() => dir_size(&entry.path()),
+                    _ => entry.metadata().map(|m| m.len()).unwrap_or(0),
+                })
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    use crate::capture::{CaptureRequest, CaptureStore};
+    use crate::process::FileId;
+
+    #[test]
+    fn files_should_be_captured_once_within_the_quota() {
+        let root = std::env::temp_dir().join(format!("owlyshield_capture_{}", std::process::id()));
+        let files = root.join("files");
+        fs::create_dir_all(&files).unwrap();
+        for (name, len) in [("a.docx", 10), ("b.docx", 100), ("c.docx", 10)] {
+            fs::write(files.join(name), vec![b'x'; len]).unwrap();
+        }
+        let request = |n: u8| CaptureRequest {
+            message_id: n as u64,
+            gid: 42,
+            pid: 1234,
+            file_id: FileId::new(7, &[n, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            path: String::new(),
+            deleted: false,
+        };
+
+        let mut store = CaptureStore::new(root.join("capture"), 64, 15, Duration::from_secs(3600));
+        let captured = store.capture(&request(1), &files.join("a.docx")).unwrap().unwrap();
+        assert_eq!((captured.size, captured.item.as_str()), (10, "0000000000000007_01000000000000000000000000000000"));
+        // once
+        assert!(store.capture(&request(1), &files.join("a.docx")).unwrap().is_none());
+        // too large
+        assert!(store.capture(&request(2), &files.join("b.docx")).unwrap().is_none());
+        // over the quota
+        assert!(store.capture(&request(3), &files.join("c.docx")).unwrap().is_none());
+
+        let gid_dir = root.join("capture").join("42");
+        assert_eq!(fs::read(gid_dir.join(&captured.item)).unwrap().len(), 10);
+        assert_eq!(fs::read_to_string(gid_dir.join("index.jsonl")).unwrap().lines().count(), 1);
+
+        // the quota is freed once the captures expire
+        store.purge(SystemTime::now());
+        assert!(gid_dir.exists());
+        store.purge(SystemTime::now() + Duration::from_secs(7200));
+        assert!(!gid_dir.exists());
+        let captured = store.capture(&request(3), &files.join("c.docx")).unwrap();
+        assert!(captured.is_none(), "already seen in this run");
+        let captured = store.capture(&request(4), &files.join("c.docx")).unwrap().unwrap();
+        assert_eq!(captured.size, 10);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
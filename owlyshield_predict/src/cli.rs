@@ -0,0 +1,944 @@
+//! Command line interface of the agent.
+//!
+//! Without subcommand, the agent runs the protection loop (or, with ```--features service```, is
+//! started by the Service Control Manager). The [Param] flags (ex: ```--kill-policy SUSPEND```)
+//! are accepted everywhere and read by [Config], as ```--portable <dir>```.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+use clap::{Arg, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use strum::IntoEnumIterator;
+
+use crate::anomaly::AnomalyModel;
+use crate::calibrate::Distribution;
+use crate::config::{Config, ConfigSource, Param, CONFIG_FILE_NAME};
+use crate::configcheck::{ConfigReport, Severity};
+use crate::connectors::connector::Connectors;
+use crate::csvwriter::IrpRecordsReader;
+use crate::eventstore::{EventStore, Filter};
+use crate::follow::{Target, TracePage};
+use crate::prediction_static::TfLiteStatic;
+use crate::quarantine::Quarantine;
+use crate::routing::ModelRouter;
+use crate::process::procs::Procs;
+use crate::timeline::TimelineFormat;
+use crate::whitelist::WhiteList;
+use crate::worker::process_drivermessage_replay;
+use crate::identity::AgentIdentity;
+use crate::utils::{FILE_TIME_FORMAT, LONG_TIME_FORMAT};
+use crate::{admx, baseline, calibrate, diag, eventstore, follow, isolation, journal, schema, selftest, timeline};
+#[cfg(windows)]
+use crate::setup::Answers;
+#[cfg(windows)]
+use crate::{driver_setup, secrets, service_ctl, setup};
+
+#[derive(Parser, Debug)]
+#[clap(name = "owlyshield_ransom", version, about = "Owlyshield behaviour based antiransomware agent")]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage the agent Windows service
+    #[cfg(windows)]
+    Service {
+        #[clap(subcommand)]
+        action: ServiceAction,
+    },
+    /// Show the state of the agent service and of the minifilter, with its conflicts with the
+    /// other minifilters
+    #[cfg(windows)]
+    Status,
+    /// Install, upgrade or remove the minifilter
+    #[cfg(windows)]
+    Driver {
+        #[clap(subcommand)]
+        action: DriverAction,
+    },
+    /// Static prediction of an executable, or of all the executables of a directory
+    Scan { path: PathBuf },
+    /// Replay a file of recorded driver messages (see --features record)
+    Replay { file: PathBuf },
+    /// Configuration commands
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+    /// Connectors commands
+    Connectors {
+        #[clap(subcommand)]
+        action: ConnectorsAction,
+    },
+    /// Edit the whitelist of application names (exclusions.txt in ConfigPath)
+    Whitelist {
+        #[clap(subcommand)]
+        action: WhitelistAction,
+    },
+    /// Export the recorded history of a gid to DebugPath
+    Timeline {
+        gid: u64,
+        #[clap(default_value = "l2tcsv")]
+        format: TimelineFormat,
+    },
+    /// Show the journal of the kills and suspensions, with the features which led to them
+    Journal {
+        #[clap(long)]
+        gid: Option<u64>,
+    },
+    /// Show the events of the local store (EVENT_STORE), the most recent first
+    Events {
+        /// Age of the oldest event, ex: 30m, 24h, 7d
+        #[clap(long, value_parser = eventstore::parse_age)]
+        since: Option<Duration>,
+        #[clap(long)]
+        min_score: Option<f32>,
+        #[clap(long)]
+        gid: Option<u64>,
+        /// Type of the events, ex: kill, escalation, mass_deletion
+        #[clap(long = "type")]
+        kind: Option<String>,
+        /// A summary per gid instead of the events
+        #[clap(long, conflicts_with = "kind")]
+        gids: bool,
+        /// The events as sent to the connectors, one JSON per line
+        #[clap(long, conflicts_with = "gids")]
+        json: bool,
+        #[clap(long, default_value = "100")]
+        limit: usize,
+    },
+    /// Write the Group Policy templates into a directory
+    Admx { dir: PathBuf },
+    /// JSON schema of the events and reports sent by the agent
+    Schema {
+        #[clap(subcommand)]
+        action: SchemaAction,
+    },
+    /// Diagnostics for the support
+    Diag {
+        #[clap(subcommand)]
+        action: DiagAction,
+    },
+    /// Allowlist learnt in the LEARNING mode
+    Baseline {
+        #[clap(subcommand)]
+        action: BaselineAction,
+    },
+    /// Suggest THRESHOLD_PREDICTION from the predictions of the audit files (AUDIT or LEARNING
+    /// mode), for a budget of false positives per week
+    Calibrate {
+        /// Directory of the audit files, DebugPath\audit by default
+        #[clap(long)]
+        dir: Option<PathBuf>,
+        /// Model version of the predictions, the most recent by default
+        #[clap(long)]
+        model_version: Option<String>,
+        #[clap(long, default_value = "1")]
+        fp_per_week: f32,
+        /// Write the threshold of --fp-per-week into owlyshield.toml in ConfigPath
+        #[clap(long)]
+        write: bool,
+    },
+    /// Network isolation of the Critical alerts (NETWORK_ISOLATION)
+    Isolation {
+        #[clap(subcommand)]
+        action: IsolationAction,
+    },
+    /// Executables of the killed process families (QUARANTINE)
+    Quarantine {
+        #[clap(subcommand)]
+        action: QuarantineAction,
+    },
+    /// Live trace of a process family in the running agent: its driver messages, features and
+    /// predictions (requires the management API)
+    Follow {
+        #[clap(long, required_unless_present = "gid", conflicts_with = "gid")]
+        pid: Option<u32>,
+        #[clap(long)]
+        gid: Option<u64>,
+    },
+    /// Helper process of the self-test, spawned by the agent
+    #[clap(name = "self-test-helper", hide = true)]
+    SelfTestHelper { dir: PathBuf, token: String },
+    /// Manage encrypted connectors credentials
+    #[cfg(windows)]
+    Secret {
+        #[clap(subcommand)]
+        action: SecretAction,
+    },
+    /// First-run setup: configuration, minifilter, agent service, handshake with the minifilter
+    /// and self-test
+    #[cfg(windows)]
+    Setup {
+        /// Answers file (TOML), instead of the questions on the console
+        #[clap(long)]
+        answers: Option<PathBuf>,
+    },
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+pub enum ServiceAction {
+    Install,
+    Uninstall,
+    Start,
+    Stop,
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+pub enum DriverAction {
+    /// Install the minifilter of an .inf and load it, if the altitude is free
+    Install { inf: PathBuf },
+    /// Replace the installed minifilter by the one of an .inf if it is newer, the agent service
+    /// being stopped meanwhile
+    Upgrade {
+        inf: PathBuf,
+        /// Also if it is not newer
+        #[clap(long)]
+        force: bool,
+    },
+    /// Unload the minifilter and uninstall it by its .inf, or else delete its service and driver
+    Remove {
+        #[clap(long)]
+        inf: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Check the configuration and show each value with its source, then the errors and warnings
+    /// of the paths, policy files and connectors
+    Validate {
+        /// The report as JSON, for the deployment tooling
+        #[clap(long)]
+        json: bool,
+        /// Also check that the endpoints of the connectors answer
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConnectorsAction {
+    /// Start each enabled connector and send it a test event, showing its delivery time or its
+    /// error
+    Test,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SchemaAction {
+    /// Print the schema of the current version, or write it to --output
+    Dump {
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DiagAction {
+    /// Zip the logs, the config (secrets redacted), the recent predictions and incident reports,
+    /// and the driver status (DebugPath\diag_<host>_<time>.zip by default)
+    Collect {
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BaselineAction {
+    /// Write the proposal (exclusions.proposed.toml in ConfigPath) now, before the end of the
+    /// BASELINE_DAYS
+    Propose,
+    /// Add the reviewed proposal to the never_kill exclusions
+    Approve,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IsolationAction {
+    /// Show the isolation in place, if any
+    Status,
+    /// Remove the filters now, before the expiry
+    Lift,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum QuarantineAction {
+    List,
+    /// Restore an item to its original path, or to --to, and remove it from the quarantine
+    Restore {
+        id: String,
+        #[clap(long)]
+        to: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum WhitelistAction {
+    Add { appname: String },
+    Remove { appname: String },
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+pub enum SecretAction {
+    /// Read the value on stdin, encrypt it and store it (ex: SitinCloud.API_KEY)
+    Set { name: String },
+}
+
+impl Cli {
+    /// Parses the command line, accepting the [Param] flags as global options.
+    pub fn parse_with_config_flags() -> Cli {
+        let flags: HashMap<Param, &'static str> = Param::iter()
+            .map(|p| (p, &*Box::leak(p.cli_flag().trim_start_matches("--").to_string().into_boxed_str())))
+            .collect();
+        let mut command = Cli::command().arg(
+            Arg::new("portable")
+                .long("portable")
+                .takes_value(true)
+                .global(true)
+                .help("Portable mode: the configuration, reports and state live under this directory, without the registry"),
+        );
+        for param in Param::iter() {
+            command = command.arg(
+                Arg::new(flags[&param])
+                    .long(flags[&param])
+                    .takes_value(true)
+                    .global(true)
+                    .help(param.description()),
+            );
+        }
+        let matches: ArgMatches = command.get_matches();
+        Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+}
+
+/// Runs a subcommand and returns the process exit code.
+pub fn run_command(command: Command) -> i32 {
+    match command {
+        #[cfg(windows)]
+        Command::Service { action } => {
+            let res = match action {
+                ServiceAction::Install => service_ctl::install(),
+                ServiceAction::Uninstall => service_ctl::uninstall(),
+                ServiceAction::Start => service_ctl::start(),
+                ServiceAction::Stop => service_ctl::stop(),
+            };
+            match res {
+                Ok(()) => {
+                    println!("Service {:?}: done", action);
+                    0
+                }
+                Err(e) => {
+                    println!("Service {:?}: {}", action, e);
+                    1
+                }
+            }
+        }
+        #[cfg(windows)]
+        Command::Status => status(),
+        #[cfg(windows)]
+        Command::Driver { action } => driver(action),
+        Command::Scan { path } => scan(&path),
+        Command::Replay { file } => {
+            let config = config_or_exit();
+            replay(&config, &file);
+            0
+        }
+        Command::Config { action: ConfigAction::Validate { json, dry_run } } => validate_config(json, dry_run),
+        Command::Connectors { action: ConnectorsAction::Test } => test_connectors(),
+        Command::Whitelist { action } => edit_whitelist(action),
+        Command::Timeline { gid, format } => export_timeline(gid, format),
+        Command::Journal { gid } => show_journal(gid),
+        Command::Events { since, min_score, gid, kind, gids, json, limit } => {
+            show_events(&Filter { since, min_score, gid, kind, limit }, gids, json)
+        }
+        Command::Admx { dir } => match admx::write_templates(&dir) {
+            Ok(()) => {
+                println!("ADMX templates written to {}", dir.display());
+                0
+            }
+            Err(e) => {
+                println!("Cannot write ADMX templates: {}", e);
+                1
+            }
+        },
+        Command::Schema { action: SchemaAction::Dump { output } } => dump_schema(output),
+        Command::Diag { action: DiagAction::Collect { output } } => collect_diag(output),
+        Command::Baseline { action } => edit_baseline(action),
+        Command::Calibrate { dir, model_version, fp_per_week, write } => {
+            calibrate(dir, model_version.as_deref(), fp_per_week, write)
+        }
+        Command::Isolation { action } => edit_isolation(action),
+        Command::Quarantine { action } => edit_quarantine(action),
+        Command::Follow { pid, gid } => follow(pid.map_or_else(|| Target::Gid(gid.unwrap_or(0)), Target::Pid)),
+        Command::SelfTestHelper { dir, token } => match selftest::run_helper(&dir, &token) {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("Self-test helper failed: {}", e);
+                1
+            }
+        },
+        #[cfg(windows)]
+        Command::Secret { action: SecretAction::Set { name } } => set_secret(&name),
+        #[cfg(windows)]
+        Command::Setup { answers } => setup(answers.as_deref()),
+    }
+}
+
+fn config_or_exit() -> Config {
+    Config::new().unwrap_or_else(|e| {
+        println!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    })
+}
+
+#[cfg(windows)]
+fn status() -> i32 {
+    let mut code = 0;
+    for name in &[service_ctl::FILTER_SERVICE_NAME, service_ctl::SERVICE_NAME] {
+        match service_ctl::query_state(name) {
+            Ok(state) => println!("{}: {:?}", name, state),
+            Err(e) => {
+                println!("{}: {}", name, e);
+                code = 1;
+            }
+        }
+    }
+    match diag::fltmc::filters() {
+        Ok(filters) => {
+            let problems = diag::coexistence_problems(&filters);
+            if problems.is_empty() {
+                println!("Minifilters: {} loaded, no conflict", filters.len());
+            }
+            for problem in problems {
+                println!("Minifilters: {}", problem);
+            }
+        }
+        Err(e) => println!("Cannot list the minifilters: {}", e),
+    }
+    if let Err(e) = Config::new() {
+        println!("Invalid configuration: {}", e);
+        code = 1;
+    }
+    code
+}
+
+#[cfg(windows)]
+fn driver(action: DriverAction) -> i32 {
+    let res = match &action {
+        DriverAction::Install { inf } => driver_setup::install(inf),
+        DriverAction::Upgrade { inf, force } => driver_setup::upgrade(inf, *force),
+        DriverAction::Remove { inf } => driver_setup::remove(inf.as_deref()),
+    };
+    match res {
+        Ok(outcome) => {
+            println!("Minifilter {}", outcome);
+            0
+        }
+        Err(e) => {
+            println!("Minifilter: {}", e);
+            1
+        }
+    }
+}
+
+fn scan(path: &Path) -> i32 {
+    let config = config_or_exit();
+    let tflite_static = match TfLiteStatic::new() {
+        Ok(tflite_static) => tflite_static,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    for (file, prediction) in tflite_static.scan(path) {
+        match prediction {
+            Some(prediction) => {
+                let verdict = if prediction > config.threshold_prediction { "MALWARE" } else { "ok" };
+                println!("{:.4}\t{}\t{}", prediction, verdict, file.display());
+            }
+            None => println!("-\tnot a PE file\t{}", file.display()),
+        }
+    }
+    0
+}
+
+/// Feeds the records of *path* to the prediction pipeline, as if they were received from the driver.
+pub fn replay(config: &Config, path: &Path) {
+    let models = match ModelRouter::from(config) {
+        Ok(models) => models,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let anomaly = match AnomalyModel::from(config) {
+        Ok(anomaly) => anomaly,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let mut procs: Procs = Procs::new();
+    let records = match IrpRecordsReader::from_path(path) {
+        Ok(records) => records,
+        Err(e) => {
+            println!("Cannot open {}: {}", path.display(), e);
+            return;
+        }
+    };
+    for res_iomsg in records {
+        match res_iomsg {
+            Ok(iomsg) => {
+                process_drivermessage_replay(config, &mut procs, &models, anomaly.as_ref(), &iomsg);
+            }
+            Err(offset) => {
+                println!("Error deserializeing buffer {}", offset);
+            }
+        }
+    }
+}
+
+fn validate_config(json: bool, dry_run: bool) -> i32 {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let report = ConfigReport::of(&args, dry_run);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        for value in &report.values {
+            println!("{} = {} ({})", value.key, value.value, value.source);
+        }
+        for finding in &report.findings {
+            let severity = match finding.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            match &finding.key {
+                Some(key) => println!("{}: {}: {}", severity, key, finding.message),
+                None => println!("{}: {}", severity, finding.message),
+            }
+        }
+        if !report.valid {
+            println!("Invalid configuration");
+        }
+    }
+    if report.valid {
+        0
+    } else {
+        1
+    }
+}
+
+fn test_connectors() -> i32 {
+    let config = config_or_exit();
+    let tests = Connectors::from(&config).test(&config);
+    if tests.is_empty() {
+        println!("No connector enabled");
+    }
+    for test in &tests {
+        let latency = test.latency.map_or_else(|| String::from("not started"), |latency| format!("{} ms", latency.as_millis()));
+        match &test.error {
+            None => println!("{}: OK ({})", test.connector, latency),
+            Some(e) => println!("{}: FAILED ({}): {}", test.connector, latency, e),
+        }
+    }
+    if tests.iter().any(|test| test.error.is_some()) {
+        1
+    } else {
+        0
+    }
+}
+
+fn edit_whitelist(action: WhitelistAction) -> i32 {
+    let config = config_or_exit();
+    let path = config.get_path(Param::ConfigPath).join("exclusions.txt");
+    let res = match &action {
+        WhitelistAction::Add { appname } => WhiteList::add_to_file(&path, appname),
+        WhitelistAction::Remove { appname } => WhiteList::remove_from_file(&path, appname),
+    };
+    match res {
+        Ok(true) => {
+            println!("{} updated", path.display());
+            0
+        }
+        Ok(false) => {
+            println!("Nothing to do");
+            0
+        }
+        Err(e) => {
+            println!("Cannot update {}: {}", path.display(), e);
+            1
+        }
+    }
+}
+
+fn export_timeline(gid: u64, format: TimelineFormat) -> i32 {
+    let config = config_or_exit();
+    let debug_path = config.get_path(Param::DebugPath);
+    let ext = match format {
+        TimelineFormat::L2tCsv => "csv",
+        TimelineFormat::JsonL => "jsonl",
+    };
+    let timeline_path = debug_path.join(format!("timeline_{}.{}", gid, ext));
+    match timeline::export_timeline(&debug_path.join("drivermessages.txt"), gid, &timeline_path, format) {
+        Ok(count) => {
+            println!("{} events exported to {}", count, timeline_path.display());
+            0
+        }
+        Err(e) => {
+            println!("Cannot export timeline: {}", e);
+            1
+        }
+    }
+}
+
+fn show_journal(gid: Option<u64>) -> i32 {
+    let config = config_or_exit();
+    let path = config.get_path(Param::DebugPath).join(journal::JOURNAL_DIR).join(journal::JOURNAL_FILE_NAME);
+    let decisions = match journal::load(&path) {
+        Ok(decisions) => decisions,
+        Err(e) => {
+            println!("Cannot read {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+    for decision in decisions.iter().filter(|d| gid.map_or(true, |gid| d.gid == gid)) {
+        println!(
+            "{}\t{:?} of gid {} ({}), {:?}: score {:.3} for a threshold of {:.3}, {} mode",
+            decision.time,
+            decision.action,
+            decision.gid,
+            decision.appname,
+            decision.trigger,
+            decision.score,
+            decision.threshold,
+            decision.mode
+        );
+        for (name, value) in decision.features.iter().filter(|(_, value)| **value != 0.0) {
+            println!("\t\t{}: {}", name, value);
+        }
+    }
+    0
+}
+
+fn show_events(filter: &Filter, gids: bool, json: bool) -> i32 {
+    let config = config_or_exit();
+    let path = EventStore::path(&config);
+    if !path.exists() {
+        println!("No event store at {}", path.display());
+        return 1;
+    }
+    let local = |time: SystemTime| DateTime::<Local>::from(time).format(LONG_TIME_FORMAT);
+    let score = |score: Option<f32>| score.map_or_else(|| String::from("-"), |score| format!("{:.3}", score));
+    let now = SystemTime::now();
+    let res = EventStore::open(&path).and_then(|store| {
+        if gids {
+            for summary in store.gids(filter, now)? {
+                println!(
+                    "{} - {}\tgid {} ({})\t{} events\tmax score {}\t{}\t{}",
+                    local(summary.first),
+                    local(summary.last),
+                    summary.gid,
+                    summary.appname,
+                    summary.events,
+                    score(summary.max_score),
+                    summary.outcome.as_deref().unwrap_or("-"),
+                    summary.exepath
+                );
+            }
+        } else {
+            for event in store.events(filter, now)? {
+                if json {
+                    println!("{}", event.envelope);
+                    continue;
+                }
+                let gid = event.gid.map_or_else(String::new, |gid| format!("gid {}", gid));
+                println!(
+                    "{}\t{}\t{} ({})\tscore {}",
+                    local(event.time),
+                    event.kind,
+                    gid,
+                    event.appname.as_deref().unwrap_or("-"),
+                    score(event.score)
+                );
+            }
+        }
+        Ok(())
+    });
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Cannot read {}: {}", path.display(), e);
+            1
+        }
+    }
+}
+
+fn collect_diag(output: Option<PathBuf>) -> i32 {
+    let config = config_or_exit();
+    let output = output.unwrap_or_else(|| {
+        let host = AgentIdentity::load(&config).hostname;
+        config
+            .get_path(Param::DebugPath)
+            .join(format!("diag_{}_{}.zip", host, Local::now().format(FILE_TIME_FORMAT)))
+    });
+    match diag::collect(&config, &output) {
+        Ok(count) => {
+            println!("{} files collected into {}", count, output.display());
+            0
+        }
+        Err(e) => {
+            println!("Cannot write {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+fn dump_schema(output: Option<PathBuf>) -> i32 {
+    let json = match serde_json::to_string_pretty(&schema::dump()) {
+        Ok(json) => json + "\n",
+        Err(e) => {
+            println!("Cannot serialize the schema: {}", e);
+            return 1;
+        }
+    };
+    match output {
+        None => {
+            print!("{}", json);
+            0
+        }
+        Some(output) => match std::fs::write(&output, json) {
+            Ok(()) => {
+                println!("Schema {} written to {}", schema::SCHEMA_VERSION, output.display());
+                0
+            }
+            Err(e) => {
+                println!("Cannot write {}: {}", output.display(), e);
+                1
+            }
+        },
+    }
+}
+
+fn edit_baseline(action: BaselineAction) -> i32 {
+    let config = config_or_exit();
+    let res = match action {
+        BaselineAction::Propose => baseline::propose(&config).map(|count| {
+            println!(
+                "{} rules proposed in {}",
+                count,
+                config.get_path(Param::ConfigPath).join(baseline::PROPOSAL_FILE_NAME).display()
+            )
+        }),
+        BaselineAction::Approve => {
+            baseline::approve(&config).map(|count| println!("{} rules added to the never_kill exclusions", count))
+        }
+    };
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Baseline: {}", e);
+            1
+        }
+    }
+}
+
+fn calibrate(dir: Option<PathBuf>, model_version: Option<&str>, fp_per_week: f32, write: bool) -> i32 {
+    let config = config_or_exit();
+    let dir = dir.unwrap_or_else(|| config.get_path(Param::DebugPath).join("audit"));
+    let distribution = match Distribution::load(&dir, model_version) {
+        Ok(distribution) if distribution.families() > 0 => distribution,
+        Ok(_) => {
+            println!("No prediction in {}", dir.display());
+            return 1;
+        }
+        Err(e) => {
+            println!("Calibrate: {}", e);
+            return 1;
+        }
+    };
+    println!(
+        "Model {}: {} predictions of {} process families over {} days",
+        distribution.model_version,
+        distribution.predictions,
+        distribution.families(),
+        distribution.days
+    );
+    let current = config.get_threshold_prediction();
+    println!(
+        "Current threshold {}: {} false positives ({:.2} per week)",
+        current,
+        distribution.false_positives(current),
+        distribution.false_positives_per_week(current)
+    );
+    println!("FP/week budget\tthreshold\tFP/week");
+    for budget in calibrate::BUDGETS.iter().chain(std::iter::once(&fp_per_week)) {
+        let threshold = calibrate::suggest(&distribution, *budget);
+        println!("{}\t{}\t{:.2}", budget, threshold, distribution.false_positives_per_week(threshold));
+    }
+    if !write {
+        return 0;
+    }
+    let threshold = calibrate::suggest(&distribution, fp_per_week);
+    let path = config.get_path(Param::ConfigPath).join(CONFIG_FILE_NAME);
+    let comment = format!(
+        "calibrated on {} for {} FP/week ({} days, model {})",
+        Local::now().format("%Y-%m-%d"),
+        fp_per_week,
+        distribution.days,
+        distribution.model_version
+    );
+    match calibrate::write_threshold(&path, threshold, &comment) {
+        Ok(()) => {
+            println!("THRESHOLD_PREDICTION = {} written to {}", threshold, path.display());
+            if config.get_source(Param::ThresholdPrediction) > ConfigSource::File {
+                println!("Warning: overridden by the {:?} source", config.get_source(Param::ThresholdPrediction));
+            }
+            0
+        }
+        Err(e) => {
+            println!("Calibrate: {}", e);
+            1
+        }
+    }
+}
+
+fn edit_isolation(action: IsolationAction) -> i32 {
+    let config = config_or_exit();
+    match action {
+        IsolationAction::Status => {
+            match isolation::state(&config) {
+                Some(state) => {
+                    let until = chrono::DateTime::<Local>::from(std::time::UNIX_EPOCH + std::time::Duration::from_secs(state.until));
+                    println!("Isolated until {}", until.format(LONG_TIME_FORMAT));
+                    for entry in state.entries {
+                        let target = entry.exepath.map_or(String::from("machine"), |p| p.display().to_string());
+                        println!("gid {}\t{:?}\t{}", entry.gid, entry.scope, target);
+                    }
+                }
+                None => println!("Not isolated"),
+            }
+            0
+        }
+        IsolationAction::Lift => match isolation::lift(&config) {
+            Ok(count) => {
+                println!("Network isolation of {} alert(s) lifted", count);
+                0
+            }
+            Err(e) => {
+                println!("Cannot lift the network isolation: {}", e);
+                1
+            }
+        },
+    }
+}
+
+fn edit_quarantine(action: QuarantineAction) -> i32 {
+    let quarantine = Quarantine::from(&config_or_exit());
+    let res = match action {
+        QuarantineAction::List => quarantine.list().map(|items| {
+            for item in items {
+                println!(
+                    "{}\t{}\tgid {}\t{:.4}\t{}\t{}",
+                    item.id,
+                    item.time,
+                    item.gid,
+                    item.prediction,
+                    item.sha256,
+                    item.original_path.display()
+                );
+            }
+        }),
+        QuarantineAction::Restore { id, to } => quarantine
+            .restore(&id, to.as_deref())
+            .map(|item| println!("{} restored to {}", id, to.unwrap_or(item.original_path).display())),
+    };
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Quarantine: {}", e);
+            1
+        }
+    }
+}
+
+/// Starts the trace of *target*, then prints it until interrupted or stopped by the agent.
+fn follow(target: Target) -> i32 {
+    let config = config_or_exit();
+    let body = serde_json::to_string(&target).unwrap();
+    if let Err(e) = follow::request(&config, "POST", "/follow", Some(&body)) {
+        println!("Cannot start the trace: {}", e);
+        return 1;
+    }
+    println!("Following {:?}, Ctrl+C to stop", target);
+    let mut since = 0;
+    let mut gid = None;
+    loop {
+        std::thread::sleep(follow::POLL_INTERVAL);
+        let page = match follow::request(&config, "GET", &format!("/follow?since={}", since), None)
+            .and_then(|response| serde_json::from_str::<TracePage>(&response).map_err(|e| e.to_string()))
+        {
+            Ok(page) => page,
+            Err(e) => {
+                println!("Trace stopped: {}", e);
+                return 1;
+            }
+        };
+        if page.gid != gid {
+            gid = page.gid;
+            if let Some(gid) = gid {
+                println!("gid {}", gid);
+            }
+        }
+        if page.dropped > 0 {
+            println!("... {} entries dropped", page.dropped);
+        }
+        for entry in page.entries {
+            since = entry.seq;
+            println!("{}", entry);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn set_secret(name: &str) -> i32 {
+    let mut value = String::new();
+    println!("Value of secret {}:", name);
+    if let Err(e) = std::io::stdin().read_line(&mut value) {
+        println!("Cannot read secret value: {}", e);
+        return 1;
+    }
+    match secrets::set_secret(name, value.trim_end_matches(&['\r', '\n'][..])) {
+        Ok(()) => {
+            println!("Secret {} saved", name);
+            0
+        }
+        Err(e) => {
+            println!("Cannot save secret {}: {}", name, e);
+            1
+        }
+    }
+}
+
+#[cfg(windows)]
+fn setup(answers: Option<&Path>) -> i32 {
+    let answers = match answers {
+        Some(path) => Answers::from_file(path),
+        None => Answers::ask(&mut std::io::stdin().lock(), &mut std::io::stdout()),
+    };
+    match answers.and_then(|answers| setup::run(&answers)) {
+        Ok(()) => {
+            println!("Setup done");
+            0
+        }
+        Err(e) => {
+            println!("Setup failed: {}", e);
+            1
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Time of the driver messages. The minifilter stamps each message with *KeQuerySystemTimePrecise*
+//! when it sees the irp: unlike the reception time, it does not depend on how long the message
+//! waited in the queues of the minifilter and of the pipeline, so that the rates of the features
+//! and the timelines stay accurate under load.
+//!
+//! The kernel time is a FILETIME (100 ns since 1601, UTC) read from the same system clock as
+//! [SystemTime]. [align] converts it, and falls back to the reception time when it cannot be
+//! trusted: a minifilter without timestamps (0), or a system clock set between the irp and its
+//! reception.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// FILETIME of the Unix epoch.
+const FILETIME_UNIX_EPOCH: i64 = 116_444_736_000_000_000;
+/// Messages seen by the minifilter longer before their reception are deemed stamped by another
+/// clock.
+pub const MAX_LAG: Duration = Duration::from_secs(300);
+
+/// The [SystemTime] of a FILETIME, None for 0 or a time before the Unix epoch.
+pub fn from_filetime(filetime: i64) -> Option<SystemTime> {
+    let ticks = filetime.checked_sub(FILETIME_UNIX_EPOCH).filter(|t| *t > 0)?;
+    Some(UNIX_EPOCH + Duration::from_nanos(ticks as u64 * 100))
+}
+
+/// The time of a message stamped *filetime* by the minifilter and *received* now.
+pub fn align(filetime: i64, received: SystemTime) -> SystemTime {
+    match from_filetime(filetime) {
+        // a message cannot be seen after its reception, the clock was set back
+        Some(kernel) => match received.duration_since(kernel) {
+            Ok(lag) if lag <= MAX_LAG => kernel,
+            _ => received,
+        },
+        None => received,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::clock::{align, from_filetime, FILETIME_UNIX_EPOCH};
+
+    #[test]
+    fn kernel_time_should_be_aligned_on_reception() {
+        // 2022-01-01T00:00:00Z
+        let filetime = FILETIME_UNIX_EPOCH + 1_640_995_200 * 10_000_000;
+        let kernel = UNIX_EPOCH + Duration::from_secs(1_640_995_200);
+        assert_eq!(from_filetime(filetime), Some(kernel));
+        assert_eq!(from_filetime(0), None);
+
+        // queued for 2 s under load
+        assert_eq!(align(filetime, kernel + Duration::from_secs(2)), kernel);
+        // older minifilter, clock set back or forward
+        let received = kernel + Duration::from_secs(1);
+        assert_eq!(align(0, received), received);
+        assert_eq!(align(filetime + 20_000_000, received), received);
+        assert_eq!(align(filetime, kernel + Duration::from_secs(3600)), kernel + Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,256 @@
+//! Folders synchronized by OneDrive, Dropbox and Google Drive: a ransomware encrypting them
+//! propagates the encrypted files to the cloud, and to the other devices of the user.
+//!
+//! The sync roots of all the users are read from the registry (OneDrive accounts, the default
+//! mount point of Google Drive) and from the *info.json* of Dropbox, then refreshed every
+//! [REFRESH_INTERVAL]. The driver messages under a root are tagged with its [SyncClient] by the
+//! fetch stage; the files written there by a gid are the *files_written_cloud_sync* feature.
+//!
+//! A gid writing in a sync root is judged with *CLOUD_SYNC_THRESHOLD* if it is lower than its
+//! threshold. With *CLOUD_SYNC_PAUSE*, the sync clients are suspended when it is detected, so
+//! that nothing more is uploaded; they are resumed when the agent stops, or when the protection
+//! loop panics. Their pids are kept in *DebugPath\cloudsync_paused.json*, so that the clients
+//! left suspended by a crash are resumed when the protection starts again.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sysinfo::{ProcessExt, System, SystemExt};
+use tracing::{error, info, warn};
+
+use crate::config::{Config, Param};
+use crate::driver_com::shared_def::IOMessage;
+use crate::os;
+
+pub static PAUSED_FILE_NAME: &str = "cloudsync_paused.json";
+
+/// Period of the detection of the sync roots (new users, new accounts).
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Pids of the sync clients suspended by [pause_clients].
+static PAUSED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SyncClient {
+    OneDrive,
+    Dropbox,
+    GoogleDrive,
+}
+
+impl SyncClient {
+    /// Lowercase names of the processes of the client.
+    pub fn process_names(&self) -> &'static [&'static str] {
+        match self {
+            SyncClient::OneDrive => &["onedrive.exe"],
+            SyncClient::Dropbox => &["dropbox.exe", "dropbox"],
+            SyncClient::GoogleDrive => &["googledrivefs.exe"],
+        }
+    }
+}
+
+impl fmt::Display for SyncClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncClient::OneDrive => write!(f, "OneDrive"),
+            SyncClient::Dropbox => write!(f, "Dropbox"),
+            SyncClient::GoogleDrive => write!(f, "Google Drive"),
+        }
+    }
+}
+
+/// The sync roots of the machine.
+#[derive(Debug, Default)]
+pub struct SyncRoots {
+    /// Lowercase, with a trailing separator
+    roots: Vec<(String, SyncClient)>,
+}
+
+impl SyncRoots {
+    /// The roots of all the users.
+    pub fn detect() -> SyncRoots {
+        let mut roots = SyncRoots::default();
+        for (path, client) in platform_roots() {
+            roots.add(&path, client);
+        }
+        info!(roots = ?roots.roots, "Cloud sync roots");
+        roots
+    }
+
+    pub fn add(&mut self, root: &Path, client: SyncClient) {
+        let mut root = root.to_string_lossy().to_lowercase().replace('/', "\\");
+        if !root.ends_with('\\') {
+            root.push('\\');
+        }
+        if !self.roots.iter().any(|(r, _)| *r == root) {
+            self.roots.push((root, client));
+        }
+    }
+
+    /// The client synchronizing *path*, if any.
+    pub fn client_of(&self, path: &str) -> Option<SyncClient> {
+        if self.roots.is_empty() {
+            return None;
+        }
+        let path = path.to_lowercase().replace('/', "\\");
+        self.roots.iter().find(|(root, _)| path.starts_with(root.as_str())).map(|(_, client)| *client)
+    }
+
+    /// Tags *iomsg* with the client synchronizing its file.
+    pub fn tag(&self, iomsg: &mut IOMessage) {
+        iomsg.runtime_features.sync_client = self.client_of(&iomsg.filepathstr);
+    }
+}
+
+/// The Dropbox folders of an *info.json* (*%LOCALAPPDATA%\Dropbox\info.json*).
+pub fn parse_dropbox_info(json: &str) -> Vec<PathBuf> {
+    #[derive(Deserialize)]
+    struct Account {
+        path: PathBuf,
+    }
+    serde_json::from_str::<std::collections::HashMap<String, Account>>(json)
+        .map(|accounts| accounts.into_values().map(|a| a.path).collect())
+        .unwrap_or_default()
+}
+
+/// The sync client run by *process*, if any.
+fn client_of_process(process: &impl ProcessExt) -> Option<SyncClient> {
+    let name = process.name().to_lowercase();
+    [SyncClient::OneDrive, SyncClient::Dropbox, SyncClient::GoogleDrive]
+        .iter()
+        .copied()
+        .find(|client| client.process_names().contains(&name.as_str()))
+}
+
+/// Suspends the processes of *clients*.
+pub fn pause_clients(config: &Config, clients: &[SyncClient]) {
+    let mut system = System::new();
+    system.refresh_processes();
+    let mut paused = PAUSED.lock().unwrap_or_else(PoisonError::into_inner);
+    for (pid, process) in system.processes() {
+        if let Some(client) = client_of_process(process).filter(|client| clients.contains(client)) {
+            let pid = *pid as u32;
+            if !paused.contains(&pid) {
+                os::suspend_pid(pid);
+                paused.push(pid);
+                warn!(pid, %client, "Sync client paused");
+            }
+        }
+    }
+    let path = config.get_path(Param::DebugPath).join(PAUSED_FILE_NAME);
+    match serde_json::to_string(&*paused) {
+        Ok(json) => fs::write(&path, json).unwrap_or_else(|e| error!("Cannot write {}: {}", path.display(), e)),
+        Err(e) => error!("Cannot serialize the paused sync clients: {}", e),
+    }
+}
+
+/// Resumes the sync clients suspended by [pause_clients], in this run or in a run which crashed.
+/// Only the pids still running a sync client are resumed.
+pub fn resume_clients(config: &Config) {
+    let path = config.get_path(Param::DebugPath).join(PAUSED_FILE_NAME);
+    let mut pids: Vec<u32> = PAUSED.lock().unwrap_or_else(PoisonError::into_inner).drain(..).collect();
+    if let Ok(json) = fs::read_to_string(&path) {
+        pids.extend(serde_json::from_str::<Vec<u32>>(&json).unwrap_or_default());
+        fs::remove_file(&path).unwrap_or(());
+    }
+    pids.sort_unstable();
+    pids.dedup();
+    if pids.is_empty() {
+        return;
+    }
+    let mut system = System::new();
+    system.refresh_processes();
+    for pid in pids {
+        if system.process(pid as sysinfo::Pid).is_some_and(|process| client_of_process(process).is_some()) {
+            os::resume_pid(pid, false);
+            info!(pid, "Sync client resumed");
+        }
+    }
+}
+
+#[cfg(windows)]
+fn platform_roots() -> Vec<(PathBuf, SyncClient)> {
+    use registry::{Hive, Security};
+
+    let mut roots = Vec::new();
+    let profiles = match Hive::LocalMachine.open(r"SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList", Security::Read) {
+        Ok(profiles) => profiles,
+        Err(e) => {
+            warn!("Cannot read the user profiles: {}", e);
+            return roots;
+        }
+    };
+    for sid in profiles.keys().flatten() {
+        let sid = sid.to_string();
+        // loaded only while the user is logged on
+        if let Ok(accounts) = Hive::Users.open(format!(r"{}\Software\Microsoft\OneDrive\Accounts", sid), Security::Read) {
+            for account in accounts.keys().flatten() {
+                if let Some(folder) = account.open(Security::Read).ok().and_then(|key| key.value("UserFolder").ok()) {
+                    let folder = folder.to_string();
+                    let folder = folder.trim_matches(char::from(0));
+                    if !folder.is_empty() {
+                        roots.push((PathBuf::from(folder), SyncClient::OneDrive));
+                    }
+                }
+            }
+        }
+        let profile = Hive::LocalMachine
+            .open(format!(r"SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList\{}", sid), Security::Read)
+            .ok()
+            .and_then(|key| key.value("ProfileImagePath").ok());
+        if let Some(profile) = profile {
+            let profile = PathBuf::from(profile.to_string().trim_matches(char::from(0)));
+            for info in [r"AppData\Local\Dropbox\info.json", r"AppData\Roaming\Dropbox\info.json"].iter() {
+                if let Ok(json) = std::fs::read_to_string(profile.join(info)) {
+                    roots.extend(parse_dropbox_info(&json).into_iter().map(|p| (p, SyncClient::Dropbox)));
+                }
+            }
+        }
+    }
+    if let Ok(drivefs) = Hive::LocalMachine.open(r"SOFTWARE\Google\DriveFS", Security::Read) {
+        // without a mount point, the drive letter is not known: a guess would tag a local drive
+        if let Ok(mount) = drivefs.value("DefaultMountPoint") {
+            let mount = mount.to_string();
+            let mount = mount.trim_matches(char::from(0)).trim_end_matches(':');
+            if !mount.is_empty() {
+                roots.push((PathBuf::from(format!(r"{}:\", mount)), SyncClient::GoogleDrive));
+            }
+        }
+    }
+    roots
+}
+
+#[cfg(target_os = "linux")]
+fn platform_roots() -> Vec<(PathBuf, SyncClient)> {
+    let homes: Vec<PathBuf> = std::fs::read_dir("/home").map(|dir| dir.flatten().map(|e| e.path()).collect()).unwrap_or_else(|_| Vec::new());
+    homes
+        .iter()
+        .filter_map(|home| std::fs::read_to_string(home.join(".dropbox/info.json")).ok())
+        .flat_map(|json| parse_dropbox_info(&json))
+        .map(|path| (path, SyncClient::Dropbox))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::cloudsync::{parse_dropbox_info, SyncClient, SyncRoots};
+
+    #[test]
+    fn paths_under_sync_roots_should_be_tagged() {
+        let json = r#"{"personal": {"path": "C:\\Users\\bob\\Dropbox", "host": 42, "is_team": false}}"#;
+        let mut roots = SyncRoots::default();
+        for path in parse_dropbox_info(json) {
+            roots.add(&path, SyncClient::Dropbox);
+        }
+        roots.add(Path::new(r"C:\Users\bob\OneDrive - Acme"), SyncClient::OneDrive);
+        assert_eq!(roots.client_of(r"c:\users\bob\dropbox\taxes\2021.xlsx"), Some(SyncClient::Dropbox));
+        assert_eq!(roots.client_of(r"C:\Users\bob\OneDrive - Acme\report.docx"), Some(SyncClient::OneDrive));
+        assert_eq!(roots.client_of(r"C:\Users\bob\Dropbox2\a.txt"), None);
+        assert_eq!(roots.client_of(r"C:\Users\bob\Documents\a.txt"), None);
+    }
+}
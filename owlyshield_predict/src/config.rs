@@ -1,14 +1,52 @@
+//! Layered configuration of owlyshield_predict.
+//!
+//! Values are looked up in several sources, the last one having precedence:
+//! 1. Defaults (relative to the install directory, see [Param::default_value]),
+//! 2. ```owlyshield.toml``` in the *ConfigPath* directory,
+//! 3. The registry key ```HKLM\SOFTWARE\Owlyshield``` (written by the installer),
+//! 4. Environment variables prefixed by ```OWLYSHIELD_``` (ex: ```OWLYSHIELD_KILL_POLICY```),
+//! 5. Command line flags (ex: ```--kill-policy SUSPEND```),
+//! 6. Group Policies, in ```HKLM\SOFTWARE\Policies\Owlyshield``` (see [crate::admx] to generate the
+//! templates). Policies are managed by the AD admins and always win over local settings.
+//!
+//! With ```--portable <dir>```, for a run from a removable drive, the registry sources (3 and 6) are
+//! skipped, and *ConfigPath*, *DebugPath* and *UtilsPath* default to the ```config```, ```debug```
+//! and ```utils``` subdirectories of *dir*, created if needed: the configuration, the reports and
+//! the state of the agent all live under *dir* ([Config::portable_dir]).
+//!
+//! All values are validated once, when the [Config] is built, so that typed accessors can not fail.
+//!
+//! A few values (*MODE*, *THRESHOLD_PREDICTION*) can then be overridden at runtime by the active
+//! scheduled profile (see [crate::profiles]), and are read with [Config::get_scheduled]. A value
+//! set by a policy (GPO) is never overridden.
+
 use std::collections::HashMap;
-use std::ops::Index;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
+#[cfg(windows)]
 use registry::*;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
-use crate::config::KillPolicy::Kill;
 
 use crate::extensions::ExtensionList;
 
-#[derive(Debug, EnumIter, PartialEq, Eq, Hash, Clone)]
+/// Flag of the portable mode, followed by its directory.
+pub static PORTABLE_FLAG: &str = "--portable";
+/// Name of the optional configuration file, looked up in *ConfigPath*.
+pub static CONFIG_FILE_NAME: &str = "owlyshield.toml";
+/// Registry key written by the installer.
+pub static REGISTRY_KEY: &str = r"SOFTWARE\Owlyshield";
+/// Registry key written by Group Policies.
+pub static POLICY_REGISTRY_KEY: &str = r"SOFTWARE\Policies\Owlyshield";
+/// Prefix of the environment variables overriding the configuration.
+pub static ENV_PREFIX: &str = "OWLYSHIELD_";
+
+#[derive(Debug, EnumIter, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Param {
     DebugPath,
     ConfigPath,
@@ -16,6 +54,99 @@ pub enum Param {
     UtilsPath,
     AppId,
     KillPolicy,
+    ThresholdDriverMsgs,
+    ThresholdPrediction,
+    SelfProtection,
+    WatchdogTimeout,
+    LogLevel,
+    LogFormat,
+    LogMaxSize,
+    LogMaxAge,
+    AuditSampling,
+    AuditCompression,
+    PipelineThreads,
+    HistoryMaxEntries,
+    HistoryMaxMsgs,
+    HistorySpill,
+    GidExpiryGrace,
+    RansomExtensions,
+    ExtensionBurstFiles,
+    ExtensionBurstSecs,
+    RawDiskAudit,
+    WatchedMounts,
+    LinuxEventSource,
+    ApiPort,
+    AdminGroup,
+    HeartbeatUrl,
+    HeartbeatInterval,
+    Mode,
+    BaselineDays,
+    BackupWriteRelax,
+    DriverMute,
+    BrokerPort,
+    ReputationWeight,
+    ScriptAmsi,
+    WiperDeletedFiles,
+    WiperWindowSecs,
+    ExfilDocsRead,
+    ExfilArchiveMb,
+    CloudSyncThreshold,
+    CloudSyncPause,
+    NetworkShareMinFiles,
+    NetworkShareThreshold,
+    KillVerifySecs,
+    KillRetries,
+    Quarantine,
+    NetworkIsolation,
+    IsolationMinutes,
+    SelfTestMinutes,
+    PersistState,
+    GidMerge,
+    BackpressureQueueDepth,
+    ExtensionProfiles,
+    AnomalyModel,
+    AnomalyWeight,
+    AvCoexistence,
+    Sysmon,
+    StixExport,
+    MispUrl,
+    UpdateUrl,
+    UpdateInterval,
+    EscalationWatch,
+    EscalationPreAlert,
+    EscalationAlertDwell,
+    EscalationCooldown,
+    DroppedScanRate,
+    YaraPath,
+    YaraRules,
+    Slack,
+    Teams,
+    ReportUrl,
+    Paging,
+    Enrichment,
+    EventStore,
+    EventStoreDays,
+    EventStoreMaxEvents,
+    IntegrityWeight,
+    Capture,
+    CaptureMaxFileKb,
+    CaptureQuotaMb,
+    InputCapture,
+    CategoryModels,
+    WiperKill,
+    CaptureRetentionDays,
+    ExfilWindowSecs,
+}
+
+/// Expected type of a [Param] value, checked by [Config::validate].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ParamKind {
+    Path,
+    Str,
+    Int,
+    Float,
+    Bool,
+    Choice(&'static [&'static str]),
 }
 
 #[derive(PartialEq)]
@@ -24,8 +155,31 @@ pub enum KillPolicy {
     Kill,
 }
 
+/// What is done with the malicious gids.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Mode {
+    /// Kill or suspend, according to the *KILL_POLICY*
+    Protect,
+    /// Predict and report, but never kill nor suspend
+    Audit,
+    /// As Audit, and record every prediction and what would have been killed, for the calibration
+    /// of the threshold
+    Learning,
+}
+
+/// Where the value of a [Param] comes from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub enum ConfigSource {
+    Default,
+    File,
+    Registry,
+    Env,
+    Cli,
+    Policy,
+}
+
 impl Param {
-    fn convert_to_str(param: &Param) -> &str {
+    pub fn convert_to_str(param: &Param) -> &'static str {
         match param {
             Param::ConfigPath => "CONFIG_PATH", // incidents reports, exclusions list
             Param::NumVersion => "NUM_VERSION",
@@ -33,52 +187,891 @@ impl Param {
             Param::UtilsPath => "UTILS_PATH", // toast.exe
             Param::AppId => "APP_ID",         // AppUserModelID for toast notifications
             Param::KillPolicy => "KILL_POLICY",  // SUSPEND / KILL
+            Param::ThresholdDriverMsgs => "THRESHOLD_DRIVERMSGS", // drivermsgs between two predictions rows
+            Param::ThresholdPrediction => "THRESHOLD_PREDICTION", // above this score, a gid is malicious
+            Param::SelfProtection => "SELF_PROTECTION", // protect the agent process, config and registry
+            Param::WatchdogTimeout => "WATCHDOG_TIMEOUT", // seconds before the protection loop is considered hung
+            Param::LogLevel => "LOG_LEVEL",       // TRACE / DEBUG / INFO / WARN / ERROR
+            Param::LogFormat => "LOG_FORMAT",     // TEXT / JSON
+            Param::LogMaxSize => "LOG_MAX_SIZE",  // MB before the log file is rotated
+            Param::LogMaxAge => "LOG_MAX_AGE",    // days before a rotated log file is deleted
+            Param::AuditSampling => "AUDIT_SAMPLING", // fraction of gids written to the audit files
+            Param::AuditCompression => "AUDIT_COMPRESSION", // NONE / ZSTD
+            Param::PipelineThreads => "PIPELINE_THREADS", // workers of the protection loop, 0 for one per core
+            Param::HistoryMaxEntries => "HISTORY_MAX_ENTRIES", // files or dirs retained per set of a gid
+            Param::HistoryMaxMsgs => "HISTORY_MAX_MSGS", // raw driver messages retained per gid
+            Param::HistorySpill => "HISTORY_SPILL", // older driver messages are written to DebugPath\history
+            Param::GidExpiryGrace => "GID_EXPIRY_GRACE", // seconds a gid state is kept after its processes exit
+            Param::RansomExtensions => "RANSOM_EXTENSIONS", // comma separated, kill on sight
+            Param::ExtensionBurstFiles => "EXTENSION_BURST_FILES", // files renamed to a new extension...
+            Param::ExtensionBurstSecs => "EXTENSION_BURST_SECS",   // ...within these seconds: kill
+            Param::RawDiskAudit => "RAW_DISK_AUDIT", // look for disks opened for writing by the monitored processes
+            Param::WatchedMounts => "WATCHED_MOUNTS", // comma separated mount points marked with fanotify (Linux)
+            Param::LinuxEventSource => "LINUX_EVENT_SOURCE", // EBPF / FANOTIFY
+            Param::ApiPort => "API_PORT", // local management API, 0 to disable
+            Param::AdminGroup => "ADMIN_GROUP", // SID or DOMAIN\name allowed to pause, exclude... from the API
+            Param::HeartbeatUrl => "HEARTBEAT_URL", // fleet management endpoint, NONE to disable
+            Param::HeartbeatInterval => "HEARTBEAT_INTERVAL", // seconds
+            Param::Mode => "MODE", // PROTECT / AUDIT / LEARNING
+            Param::BaselineDays => "BASELINE_DAYS", // observation period of the LEARNING mode
+            Param::BackupWriteRelax => "BACKUP_WRITE_RELAX", // factor of the write volumes of the backup jobs, 1 to disable
+            Param::DriverMute => "DRIVER_MUTE", // the minifilter stops forwarding the events of the gids excluded by a verified signer
+            Param::BrokerPort => "BROKER_PORT", // republishes the driver messages to local tools, 0 to disable
+            Param::ReputationWeight => "REPUTATION_WEIGHT", // weight of the reputation prior in the score, 0 to disable
+            Param::ScriptAmsi => "SCRIPT_AMSI", // scans the scripts of powershell, wscript, mshta with AMSI
+            Param::WiperDeletedFiles => "WIPER_DELETED_FILES", // files deleted without encryption...
+            Param::WiperWindowSecs => "WIPER_WINDOW_SECS",     // ...within these seconds: wiper
+            Param::ExfilDocsRead => "EXFIL_DOCS_READ",   // documents read, then...
+            Param::ExfilArchiveMb => "EXFIL_ARCHIVE_MB", // ...an archive of this size written: PreAlert
+            Param::CloudSyncThreshold => "CLOUD_SYNC_THRESHOLD", // threshold of the gids writing in OneDrive, Dropbox...
+            Param::CloudSyncPause => "CLOUD_SYNC_PAUSE",         // suspends the sync clients on alert
+            Param::NetworkShareMinFiles => "NETWORK_SHARE_MIN_FILES",   // files written on network shares...
+            Param::NetworkShareThreshold => "NETWORK_SHARE_THRESHOLD", // ...before this threshold is used
+            Param::KillVerifySecs => "KILL_VERIFY_SECS", // delay for the killed processes to exit
+            Param::KillRetries => "KILL_RETRIES",         // re-kills of the survivors and respawns
+            Param::Quarantine => "QUARANTINE",            // executables of the killed gids
+            Param::NetworkIsolation => "NETWORK_ISOLATION", // OFF / EXECUTABLE / MACHINE
+            Param::IsolationMinutes => "ISOLATION_MINUTES", // after the last Critical alert
+            Param::SelfTestMinutes => "SELF_TEST_MINUTES", // 0 to disable the self-test
+            Param::PersistState => "PERSIST_STATE",        // state of the gids kept across restarts
+            Param::GidMerge => "GID_MERGE",                // gids of a same attack aggregated
+            Param::BackpressureQueueDepth => "BACKPRESSURE_QUEUE_DEPTH", // queued messages before the driver aggregates
+            Param::ExtensionProfiles => "EXTENSION_PROFILES", // extensions written by each executable learnt
+            Param::AnomalyModel => "ANOMALY_MODEL",   // OFF / STANDALONE / ENSEMBLE
+            Param::AnomalyWeight => "ANOMALY_WEIGHT", // weight of the anomaly score in the ENSEMBLE
+            Param::AvCoexistence => "AV_COEXISTENCE", // status and detections of the antivirus
+            Param::Sysmon => "SYSMON",                 // Sysmon events as auxiliary features
+            Param::StixExport => "STIX_EXPORT",        // incidents written as STIX 2.1 bundles
+            Param::MispUrl => "MISP_URL",              // MISP instance the bundles are pushed to
+            Param::UpdateUrl => "UPDATE_URL",          // manifest of the agent updates, NONE to disable
+            Param::UpdateInterval => "UPDATE_INTERVAL", // seconds
+            Param::EscalationWatch => "ESCALATION_WATCH", // fraction of the threshold, 0 to disable
+            Param::EscalationPreAlert => "ESCALATION_PRE_ALERT", // fraction of the threshold, 0 to disable
+            Param::EscalationAlertDwell => "ESCALATION_ALERT_DWELL", // seconds
+            Param::EscalationCooldown => "ESCALATION_COOLDOWN", // seconds
+            Param::DroppedScanRate => "DROPPED_SCAN_RATE", // payloads scanned per gid and minute
+            Param::YaraPath => "YARA_PATH",                // yara64.exe, NONE to disable
+            Param::YaraRules => "YARA_RULES",              // .yar or compiled .yarc
+            Param::Slack => "SLACK",                       // webhook in ConfigPath\slack_webhook
+            Param::Teams => "TEAMS",                       // webhook in ConfigPath\teams_webhook
+            Param::ReportUrl => "REPORT_URL",              // where ConfigPath\threats is published
+            Param::Paging => "PAGING",                     // key in ConfigPath\paging_key
+            Param::Enrichment => "ENRICHMENT",             // providers in ConfigPath\enrichment.toml
+            Param::EventStore => "EVENT_STORE",            // DebugPath\events.db
+            Param::EventStoreDays => "EVENT_STORE_DAYS",
+            Param::EventStoreMaxEvents => "EVENT_STORE_MAX_EVENTS",
+            Param::IntegrityWeight => "INTEGRITY_WEIGHT", // pull of the score towards 1 of the hollowed processes and spoofed parents
+            Param::Capture => "CAPTURE",                  // files of the watched gids, into ConfigPath\capture
+            Param::CaptureMaxFileKb => "CAPTURE_MAX_FILE_KB",
+            Param::CaptureQuotaMb => "CAPTURE_QUOTA_MB",
+            Param::InputCapture => "INPUT_CAPTURE",       // keyboard hooks and clipboard reads as auxiliary features
+            Param::CategoryModels => "CATEGORY_MODELS",   // models per process category, in ConfigPath\models
+            Param::WiperKill => "WIPER_KILL",             // kill the wipers at once, instead of an alert only
+            Param::CaptureRetentionDays => "CAPTURE_RETENTION_DAYS", // captures of a gid deleted after these days
+            Param::ExfilWindowSecs => "EXFIL_WINDOW_SECS", // time window of EXFIL_DOCS_READ
+        }
+    }
+
+    /// Key used in ```owlyshield.toml``` (ex: *kill_policy*).
+    pub fn toml_key(&self) -> String {
+        Param::convert_to_str(self).to_lowercase()
+    }
+
+    /// Name of the environment variable (ex: *OWLYSHIELD_KILL_POLICY*).
+    pub fn env_key(&self) -> String {
+        format!("{}{}", ENV_PREFIX, Param::convert_to_str(self))
+    }
+
+    /// Command line flag (ex: *--kill-policy*).
+    pub fn cli_flag(&self) -> String {
+        format!("--{}", self.toml_key().replace("_", "-"))
+    }
+
+    pub fn kind(&self) -> ParamKind {
+        match self {
+            Param::DebugPath | Param::ConfigPath | Param::UtilsPath => ParamKind::Path,
+            Param::NumVersion
+            | Param::AppId
+            | Param::RansomExtensions
+            | Param::WatchedMounts
+            | Param::AdminGroup
+            | Param::HeartbeatUrl
+            | Param::MispUrl
+            | Param::UpdateUrl
+            | Param::YaraPath
+            | Param::YaraRules
+            | Param::ReportUrl => ParamKind::Str,
+            Param::KillPolicy => ParamKind::Choice(&["KILL", "SUSPEND"]),
+            Param::LogLevel => ParamKind::Choice(&["TRACE", "DEBUG", "INFO", "WARN", "ERROR"]),
+            Param::LogFormat => ParamKind::Choice(&["TEXT", "JSON"]),
+            Param::AuditCompression => ParamKind::Choice(&["NONE", "ZSTD"]),
+            Param::LinuxEventSource => ParamKind::Choice(&["EBPF", "FANOTIFY"]),
+            Param::Mode => ParamKind::Choice(&["PROTECT", "AUDIT", "LEARNING"]),
+            Param::NetworkIsolation => ParamKind::Choice(&["OFF", "EXECUTABLE", "MACHINE"]),
+            Param::AnomalyModel => ParamKind::Choice(&["OFF", "STANDALONE", "ENSEMBLE"]),
+            Param::Paging => ParamKind::Choice(&["NONE", "PAGERDUTY", "OPSGENIE", "OPSGENIE_EU"]),
+            Param::ThresholdDriverMsgs
+            | Param::BaselineDays
+            | Param::WatchdogTimeout
+            | Param::LogMaxSize
+            | Param::LogMaxAge
+            | Param::PipelineThreads
+            | Param::HistoryMaxEntries
+            | Param::HistoryMaxMsgs
+            | Param::GidExpiryGrace
+            | Param::ExtensionBurstFiles
+            | Param::ExtensionBurstSecs
+            | Param::ApiPort
+            | Param::HeartbeatInterval
+            | Param::BrokerPort
+            | Param::WiperDeletedFiles
+            | Param::WiperWindowSecs
+            | Param::ExfilDocsRead
+            | Param::ExfilArchiveMb
+            | Param::NetworkShareMinFiles
+            | Param::KillVerifySecs
+            | Param::KillRetries
+            | Param::IsolationMinutes
+            | Param::SelfTestMinutes
+            | Param::BackpressureQueueDepth
+            | Param::UpdateInterval
+            | Param::EscalationAlertDwell
+            | Param::EscalationCooldown
+            | Param::DroppedScanRate
+            | Param::EventStoreDays
+            | Param::EventStoreMaxEvents
+            | Param::CaptureMaxFileKb
+            | Param::CaptureQuotaMb
+            | Param::CaptureRetentionDays
+            | Param::ExfilWindowSecs => ParamKind::Int,
+            Param::ThresholdPrediction
+            | Param::AuditSampling
+            | Param::BackupWriteRelax
+            | Param::ReputationWeight
+            | Param::CloudSyncThreshold
+            | Param::NetworkShareThreshold
+            | Param::AnomalyWeight
+            | Param::EscalationWatch
+            | Param::EscalationPreAlert
+            | Param::IntegrityWeight => ParamKind::Float,
+            Param::SelfProtection
+            | Param::HistorySpill
+            | Param::RawDiskAudit
+            | Param::DriverMute
+            | Param::ScriptAmsi
+            | Param::CloudSyncPause
+            | Param::Quarantine
+            | Param::PersistState
+            | Param::GidMerge
+            | Param::ExtensionProfiles
+            | Param::AvCoexistence
+            | Param::Sysmon
+            | Param::StixExport
+            | Param::Slack
+            | Param::Teams
+            | Param::Enrichment
+            | Param::EventStore
+            | Param::Capture
+            | Param::InputCapture
+            | Param::CategoryModels
+            | Param::WiperKill => ParamKind::Bool,
         }
     }
+
+    /// Default values. Paths are relative to the install dir, which is the parent of the directory
+    /// containing this exe (see the innosetup script).
+    pub fn default_value(&self) -> Option<String> {
+        let install_dir = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().and_then(|d| d.parent()).map(|d| d.to_path_buf()));
+        let in_install_dir =
+            |dir: &str| install_dir.as_ref().map(|d| d.join(dir).to_string_lossy().to_string());
+        match self {
+            Param::DebugPath => in_install_dir("debug"),
+            Param::ConfigPath => in_install_dir("config"),
+            Param::UtilsPath => in_install_dir("utils"),
+            Param::NumVersion => Some(String::from(env!("CARGO_PKG_VERSION"))),
+            Param::AppId => Some(String::from("8C19967B-1D27-4E6A-85CD-5059912C2788")),
+            Param::KillPolicy => Some(String::from("KILL")),
+            Param::ThresholdDriverMsgs => Some(String::from("100")),
+            Param::ThresholdPrediction => Some(String::from("0.65")),
+            Param::SelfProtection => Some(String::from("true")),
+            Param::WatchdogTimeout => Some(String::from("120")),
+            Param::LogLevel => Some(String::from("INFO")),
+            Param::LogFormat => Some(String::from("TEXT")),
+            Param::LogMaxSize => Some(String::from("10")),
+            Param::LogMaxAge => Some(String::from("30")),
+            Param::AuditSampling => Some(String::from("1.0")),
+            Param::AuditCompression => Some(String::from("NONE")),
+            Param::PipelineThreads => Some(String::from("0")),
+            Param::HistoryMaxEntries => Some(String::from("20000")),
+            Param::HistoryMaxMsgs => Some(String::from("256")),
+            Param::HistorySpill => Some(String::from("false")),
+            Param::GidExpiryGrace => Some(String::from("60")),
+            Param::RansomExtensions => Some(String::from(
+                "locky,zepto,odin,thor,aesir,cerber,cerber3,wncry,wnry,wcry,lockbit,crypt,crypted,cryptolocker,crinf,xtbl,ryk,djvu",
+            )),
+            Param::ExtensionBurstFiles => Some(String::from("30")),
+            Param::ExtensionBurstSecs => Some(String::from("10")),
+            Param::RawDiskAudit => Some(String::from("true")),
+            Param::WatchedMounts => Some(String::from("/")),
+            Param::LinuxEventSource => Some(String::from("EBPF")),
+            Param::ApiPort => Some(String::from("7478")),
+            Param::AdminGroup => Some(String::from(if cfg!(windows) { "S-1-5-32-544" } else { "root" })),
+            Param::HeartbeatUrl => Some(String::from("NONE")),
+            Param::HeartbeatInterval => Some(String::from("60")),
+            Param::Mode => Some(String::from("PROTECT")),
+            Param::BaselineDays => Some(String::from("7")),
+            Param::BackupWriteRelax => Some(String::from("0.25")),
+            Param::DriverMute => Some(String::from("false")),
+            Param::BrokerPort => Some(String::from("0")),
+            Param::ReputationWeight => Some(String::from("0")),
+            Param::ScriptAmsi => Some(String::from("false")),
+            Param::WiperDeletedFiles => Some(String::from("0")),
+            Param::WiperWindowSecs => Some(String::from("30")),
+            Param::ExfilDocsRead => Some(String::from("100")),
+            Param::ExfilArchiveMb => Some(String::from("10")),
+            Param::CloudSyncThreshold => Some(String::from("0")),
+            Param::CloudSyncPause => Some(String::from("false")),
+            Param::NetworkShareMinFiles => Some(String::from("50")),
+            Param::NetworkShareThreshold => Some(String::from("0")),
+            Param::KillVerifySecs => Some(String::from("10")),
+            Param::KillRetries => Some(String::from("3")),
+            Param::Quarantine => Some(String::from("false")),
+            Param::NetworkIsolation => Some(String::from("OFF")),
+            Param::IsolationMinutes => Some(String::from("60")),
+            Param::SelfTestMinutes => Some(String::from("60")),
+            Param::PersistState => Some(String::from("true")),
+            Param::GidMerge => Some(String::from("true")),
+            Param::BackpressureQueueDepth => Some(String::from("20000")),
+            Param::ExtensionProfiles => Some(String::from("true")),
+            Param::AnomalyModel => Some(String::from("OFF")),
+            Param::AnomalyWeight => Some(String::from("0.3")),
+            Param::AvCoexistence => Some(String::from("true")),
+            Param::Sysmon => Some(String::from("false")),
+            Param::StixExport => Some(String::from("false")),
+            Param::MispUrl => Some(String::from("NONE")),
+            Param::UpdateUrl => Some(String::from("NONE")),
+            Param::UpdateInterval => Some(String::from("21600")),
+            Param::EscalationWatch => Some(String::from("0.5")),
+            Param::EscalationPreAlert => Some(String::from("0.8")),
+            Param::EscalationAlertDwell => Some(String::from("0")),
+            Param::EscalationCooldown => Some(String::from("300")),
+            Param::DroppedScanRate => Some(String::from("10")),
+            Param::YaraPath => Some(String::from("NONE")),
+            Param::YaraRules => Some(String::from("NONE")),
+            Param::Slack => Some(String::from("false")),
+            Param::Teams => Some(String::from("false")),
+            Param::ReportUrl => Some(String::from("NONE")),
+            Param::Paging => Some(String::from("NONE")),
+            Param::Enrichment => Some(String::from("false")),
+            Param::EventStore => Some(String::from("true")),
+            Param::EventStoreDays => Some(String::from("90")),
+            Param::EventStoreMaxEvents => Some(String::from("100000")),
+            Param::IntegrityWeight => Some(String::from("0.5")),
+            Param::Capture => Some(String::from("false")),
+            Param::CaptureMaxFileKb => Some(String::from("4096")),
+            Param::CaptureQuotaMb => Some(String::from("1024")),
+            Param::InputCapture => Some(String::from("false")),
+            Param::CategoryModels => Some(String::from("false")),
+            Param::WiperKill => Some(String::from("false")),
+            Param::CaptureRetentionDays => Some(String::from("7")),
+            Param::ExfilWindowSecs => Some(String::from("600")),
+        }
+    }
+
+    /// Short description, used in the ADML template.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Param::DebugPath => "Directory where debug files (prediction.csv, records...) are written",
+            Param::ConfigPath => "Directory of the configuration files, exclusions and incidents reports",
+            Param::NumVersion => "Version number of the agent",
+            Param::UtilsPath => "Directory of the utilities (toast notifications...)",
+            Param::AppId => "AppUserModelID used for toast notifications",
+            Param::KillPolicy => "What to do with a process identified as a ransomware",
+            Param::ThresholdDriverMsgs => "Number of driver messages between two predictions steps",
+            Param::ThresholdPrediction => "Score above which a process is considered malicious",
+            Param::SelfProtection => "Protect the agent process, its configuration and registry keys against tampering",
+            Param::WatchdogTimeout => "Seconds without activity before the protection loop is considered hung and the service restarted",
+            Param::LogLevel => "Minimum level of the logged events",
+            Param::LogFormat => "Format of the log files in DebugPath\\logs, JSON for log shippers",
+            Param::LogMaxSize => "Size in MB above which the log file is rotated",
+            Param::LogMaxAge => "Days after which rotated log files are deleted",
+            Param::AuditSampling => "Fraction (0 to 1) of the process families whose predictions are written to the audit files, 0 to disable",
+            Param::AuditCompression => "Compression of the daily audit files in DebugPath\\audit",
+            Param::PipelineThreads => "Number of threads processing the driver messages, 0 for one per CPU core",
+            Param::HistoryMaxEntries => "Files or directories retained in each set of a process family, beyond which they are only counted (estimated)",
+            Param::HistoryMaxMsgs => "Last driver messages retained in memory for each process family, for the incident reports",
+            Param::HistorySpill => "Write the older driver messages of each process family to DebugPath\\history, kept for the incidents",
+            Param::GidExpiryGrace => "Seconds the state of a process family is kept after all its processes have exited",
+            Param::RansomExtensions => "Comma separated extensions of known ransomwares: a process family writing such a file is killed without waiting for the model",
+            Param::ExtensionBurstFiles => "Number of files renamed to the same new extension, within EXTENSION_BURST_SECS, for a process family to be killed without waiting for the model (0 to disable)",
+            Param::ExtensionBurstSecs => "Time window in seconds of EXTENSION_BURST_FILES",
+            Param::RawDiskAudit => "Audit the handles of the monitored processes to detect the physical disks opened for writing (MBR overwrite), which the driver cannot see",
+            Param::WatchedMounts => "Comma separated mount points whose file events are monitored on Linux",
+            Param::LinuxEventSource => "Source of the file events on Linux: EBPF adds the entropy of the writes, the renames and the deletions to FANOTIFY (needs owlyshield.bpf.o in UtilsPath)",
+            Param::ApiPort => "Port of the management API on 127.0.0.1, authenticated with the token of ConfigPath\\api_token (0 to disable)",
+            Param::AdminGroup => "Group (SID or DOMAIN\\name) whose members may pause the protection, add exclusions and kill or awake suspended processes from the API, besides the local Administrators",
+            Param::HeartbeatUrl => "URL receiving the health of the agent, and answering with the pending commands (NONE to disable)",
+            Param::HeartbeatInterval => "Seconds between two heartbeats",
+            Param::Mode => "PROTECT kills or suspends the malicious processes. AUDIT only predicts and reports, for the first deployments on production servers. LEARNING also audits all the predictions and records what would have been killed, for the calibration of the threshold, and learns the baseline of the benign processes",
+            Param::BaselineDays => "Days observed in the LEARNING mode before proposing the recurring processes for the never_kill exclusions",
+            Param::BackupWriteRelax => "Factor applied to the write volume features (bytes, files and directories written) of the processes of the backup agents (Veeam, Acronis, Windows Backup) while they run a job. 1 disables it",
+            Param::DriverMute => "Ask the minifilter to stop forwarding the events of the processes excluded from the monitoring by a signers rule, with a signature verified by WinVerifyTrust, until they start a child or load an image outside of SystemRoot. Cuts the traffic on busy database and backup servers",
+            Param::BrokerPort => "Port on 127.0.0.1 republishing the driver messages as JSON lines to the local tools (recorders, debuggers...), which cannot connect to the filter port used by the agent. Authenticated with the token of ConfigPath\\api_token (0 to disable)",
+            Param::ReputationWeight => "Weight of the reputation of the executable (signer, number of executables with its hash on this machine, age, install location) mixed into the score of the models, between 0 (disabled, the default) and 1",
+            Param::ScriptAmsi => "Scans the scripts and commands run by powershell, pwsh, wscript, cscript and mshta with the antimalware provider (AMSI, Windows only): the verdict is logged and reported, a detection is the script_amsi_detected feature, used only by the models trained on it",
+            Param::WiperDeletedFiles => "Number of files deleted within WIPER_WINDOW_SECS, out of the temporary and build directories and with almost no encrypted write, for a process family to be reported as a wiper without waiting for the model (0 to disable)",
+            Param::WiperWindowSecs => "Time window in seconds of WIPER_DELETED_FILES",
+            Param::ExfilDocsRead => "Number of documents read by a process family within EXFIL_WINDOW_SECS before its large compressed archives are reported as staged for exfiltration, in a PreAlert (0 to disable)",
+            Param::ExfilArchiveMb => "Minimum size in megabytes of the ZIP, RAR or 7z archives of EXFIL_DOCS_READ",
+            Param::CloudSyncThreshold => "Prediction threshold of the process families writing in a folder synchronized by OneDrive, Dropbox or Google Drive, used if lower than their threshold: the encrypted files would be propagated to the cloud (0 to disable)",
+            Param::CloudSyncPause => "Suspends the sync clients (OneDrive, Dropbox, Google Drive) when a process family writing in their folders is detected, until the agent stops or its protection loop crashes",
+            Param::NetworkShareMinFiles => "Number of files written on network shares (UNC paths, mapped drives, NFS or SMB mounts) by a process family before NETWORK_SHARE_THRESHOLD applies to it",
+            Param::NetworkShareThreshold => "Prediction threshold of the process families mass-writing on network shares, used if lower than their threshold: a file server is shared by many users (0 to disable)",
+            Param::KillVerifySecs => "Delay in seconds for the processes of a killed family to exit, and during which its respawns (children of the killed processes, or the same executable) are killed too. The Kill event sent to the connectors tells the outcome",
+            Param::KillRetries => "Number of re-kills of the processes surviving KILL_VERIFY_SECS after a kill, or respawning, before the kill is reported as failed",
+            Param::Quarantine => "Moves the executable of a killed process family, and the copies it dropped, into ConfigPath\\quarantine, encrypted with DPAPI. See the quarantine list and restore commands",
+            Param::NetworkIsolation => "Blocks the outbound connections with Windows Filtering Platform filters when a process family is detected in the PROTECT mode: of its EXECUTABLE only, or of the whole MACHINE but the loopback, the agent and the DNS client service. Lifted after ISOLATION_MINUTES, or with the isolation lift command or POST /isolation/lift",
+            Param::IsolationMinutes => "Duration in minutes of the NETWORK_ISOLATION, from the last Critical alert",
+            Param::SelfTestMinutes => "Interval in minutes of the end-to-end self-test of the driver (0 to disable)",
+            Param::PersistState => "Saves the behavioural history of the monitored process families in DebugPath, so that a restart of the service (crash, update) does not reset it for the processes still running",
+            Param::GidMerge => "Merges the process families split by the driver, children of a family or the same executable restarted by a scheduler, so that their features are aggregated",
+            Param::BackpressureQueueDepth => "Number of driver messages waiting to be processed above which the minifilter is asked to send per-process summaries instead of the messages, until the queue is back under half of it (0 to disable)",
+            Param::ExtensionProfiles => "Learns the extensions written by each executable of the machine, in DebugPath, and gives the model the share of the writes of a process family on extensions its executable never wrote",
+            Param::AnomalyModel => "Unsupervised anomaly model (ConfigPath\\anomaly.tflite and anomaly.json), trained on the benign activity only: STANDALONE scores the process families with it instead of the embedded model, for the environments without labels, ENSEMBLE mixes its score into the prediction",
+            Param::AnomalyWeight => "Weight of the anomaly score in the prediction with ANOMALY_MODEL = ENSEMBLE, between 0 and 1",
+            Param::AvCoexistence => "Reports the antivirus products of the Windows Security Center, the status of Windows Defender and the AMSI providers in the heartbeat and the API, and does not repeat the alerts of the executables already detected by Defender",
+            Param::Sysmon => "Subscribes to the Sysmon events of process creations, network connections and file creations, correlated with the process families as features and in the incident reports",
+            Param::StixExport => "Writes each incident as a STIX 2.1 bundle (indicator on the hash of the executable, malware instance, files observed) in ConfigPath\\threats",
+            Param::MispUrl => "URL of a MISP instance the STIX bundles are pushed to, authenticated by the key in ConfigPath\\misp_key (NONE: disabled)",
+            Param::UpdateUrl => "URL of the manifest of the agent updates, checked by the service which installs the signed updates of its rollout (NONE: disabled)",
+            Param::UpdateInterval => "Seconds between two checks of UPDATE_URL",
+            Param::EscalationWatch => "Score, as a fraction of the threshold, from which a process is watched: its memory and the executables it drops are scanned (0: disabled)",
+            Param::EscalationPreAlert => "Score, as a fraction of the threshold, from which a PreAlert is sent to the connectors (0: disabled)",
+            Param::EscalationAlertDwell => "Seconds a process must stay in PreAlert before being killed when above the threshold (0: at once)",
+            Param::EscalationCooldown => "Seconds below a level before a process goes down one level of the escalation",
+            Param::DroppedScanRate => "Executables and DLLs dropped by a process family scanned per minute with the static model and YARA, beyond which they are sampled; the highest score is a feature (0: disabled)",
+            Param::YaraPath => "Path of the YARA command line scanner (yara64.exe) the dropped executables are scanned with (NONE: disabled)",
+            Param::YaraRules => "YARA rules the dropped executables are scanned with: a source file, or compiled by yarac with a .yarc extension (NONE: disabled)",
+            Param::Slack => "Posts the detections and PreAlerts to the Slack incoming webhook in ConfigPath\\slack_webhook, with buttons to acknowledge them or mark them as false positives, whose clicks are received by POST /slack/interactions of the API and verified with the signing secret in ConfigPath\\slack_signing_secret",
+            Param::Teams => "Posts the detections, PreAlerts and critical events to the Microsoft Teams incoming webhook or workflow in ConfigPath\\teams_webhook as Adaptive Cards colored by severity, batched to respect the rate limits of Teams",
+            Param::ReportUrl => "URL where ConfigPath\\threats is published (a web server or a share), under which the messages of the connectors link to the HTML incident reports (NONE: no link)",
+            Param::Paging => "Opens an incident per machine and gid in PagerDuty (Events API v2) or Opsgenie, authenticated by the routing or API key in ConfigPath\\paging_key, with the severity of the escalation level, acknowledged and resolved with the alert reviews (NONE: disabled)",
+            Param::Enrichment => "Tags the alerts with the answers of the providers of ConfigPath\\enrichment.toml (EDR APIs, CMDB...), queried by HTTP or a local command with the hash of the executable and the hostname. The answers are cached, and an alert waits for them half a second at most",
+            Param::EventStore => "Records the events sent to the connectors, and a summary per gid, into the SQLite database DebugPath\\events.db, queried with: owlyshield_ransom events",
+            Param::EventStoreDays => "Days the events are kept in the local event store",
+            Param::EventStoreMaxEvents => "Events kept in the local event store at most, the oldest ones being deleted first",
+            Param::IntegrityWeight => "Weight pulling the score towards 1 when the image of the root of a gid differs from its executable (process hollowing) or when one of its processes was created with a spoofed parent, between 0 (disabled) and 1",
+            Param::Capture => "Copies the files a process family in Watch or PreAlert is about to overwrite or delete into ConfigPath\\capture, before the minifilter lets the write or the deletion through, so that the files hit before the kill can be recovered",
+            Param::CaptureMaxFileKb => "Size in KB above which the files are not captured",
+            Param::CaptureQuotaMb => "Size in MB of ConfigPath\\capture above which no more files are captured, until older captures are deleted (see CAPTURE_RETENTION_DAYS)",
+            Param::InputCapture => "Traces the keyboard hooks, key state polling, raw input registrations and clipboard reads of the process families through the Win32k ETW provider, as features and in the incident reports, many ransomware operators also stealing data",
+            Param::CategoryModels => "Scores the process families with the model of their category (interactive, service, script_host, browser) from ConfigPath\\models\\<category>: model.tflite with its mean.json, std.json and features.json. The categories without a model use the embedded one",
+            Param::WiperKill => "Kills the process families detected as wipers (see WIPER_DELETED_FILES) at once, instead of only sending a Critical event",
+            Param::CaptureRetentionDays => "Days after which the files captured from a process family (see CAPTURE) are deleted, freeing the quota of CAPTURE_QUOTA_MB",
+            Param::ExfilWindowSecs => "Time window in seconds of EXFIL_DOCS_READ: the documents read before are not counted",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Param> {
+        Param::iter().find(|p| Param::convert_to_str(p).eq_ignore_ascii_case(key))
+    }
 }
 
+/// Any error in the configuration, naming the offending key.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// No source defines this key.
+    Missing(String),
+    /// The value cannot be parsed to the expected type.
+    Invalid {
+        key: String,
+        value: String,
+        expected: String,
+    },
+    /// The configuration file exists but cannot be read or parsed.
+    File { path: PathBuf, details: String },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing(key) => write!(f, "Missing configuration key {}", key),
+            ConfigError::Invalid { key, value, expected } => write!(
+                f,
+                "Invalid value '{}' for configuration key {} (expected {})",
+                value, key, expected
+            ),
+            ConfigError::File { path, details } => {
+                write!(f, "Cannot read configuration file {}: {}", path.display(), details)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl ConfigError {
+    /// The offending key, None for a file.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            ConfigError::Missing(key) | ConfigError::Invalid { key, .. } => Some(key),
+            ConfigError::File { .. } => None,
+        }
+    }
+}
+
+/// Values of one configuration source.
+type Layer = HashMap<Param, String>;
+
 #[derive(Debug)]
 pub struct Config {
     params: HashMap<Param, String>,
+    sources: HashMap<Param, ConfigSource>,
     pub extensions_list: ExtensionList,
     pub threshold_drivermsgs: usize,
     pub threshold_prediction: f32,
+    /// Name and values of the active scheduled profile
+    profile: RwLock<Option<(String, Layer)>>,
+    /// Directory of the portable mode
+    portable: Option<PathBuf>,
 }
 
 impl Config {
-    pub fn new() -> Config {
+    /// Builds the configuration from all sources, with the command line of this process.
+    pub fn new() -> Result<Config, ConfigError> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        Self::from_args(&args)
+    }
+
+    pub fn from_args(args: &[String]) -> Result<Config, ConfigError> {
+        let portable = Self::portable_arg(args);
+        let mut config = Self::from_layers(Self::layers(args, portable.as_deref())?)?;
+        config.portable = portable;
+        Ok(config)
+    }
+
+    /// All the errors of the configuration given by *args* and the other sources, where
+    /// [Self::from_args] stops at the first one.
+    pub fn check_args(args: &[String]) -> Vec<ConfigError> {
+        let portable = Self::portable_arg(args);
+        match Self::layers(args, portable.as_deref()) {
+            Ok(layers) => Self::check_layers(layers),
+            Err(e) => vec![e],
+        }
+    }
+
+    /// The layers of all sources, from the lowest precedence.
+    fn layers(args: &[String], portable: Option<&Path>) -> Result<Vec<(ConfigSource, Layer)>, ConfigError> {
+        let (defaults, registry, policy) = match portable {
+            Some(dir) => {
+                Self::create_portable_dirs(dir)?;
+                (Self::portable_defaults_layer(dir), Layer::new(), Layer::new())
+            }
+            None => (
+                Self::defaults_layer(),
+                Self::registry_layer(REGISTRY_KEY),
+                Self::registry_layer(POLICY_REGISTRY_KEY),
+            ),
+        };
+        let env = Self::env_layer();
+        let cli = Self::cli_layer(args);
+
+        // The config file location itself can be overridden by any other source
+        let config_path = [&policy, &cli, &env, &registry, &defaults]
+            .iter()
+            .find_map(|l| l.get(&Param::ConfigPath).cloned());
+        let file = match config_path {
+            Some(dir) => Self::file_layer(&Path::new(&dir).join(CONFIG_FILE_NAME))?,
+            None => Layer::new(),
+        };
+
+        Ok(vec![
+            (ConfigSource::Default, defaults),
+            (ConfigSource::File, file),
+            (ConfigSource::Registry, registry),
+            (ConfigSource::Env, env),
+            (ConfigSource::Cli, cli),
+            (ConfigSource::Policy, policy),
+        ])
+    }
+
+    /// Merges the layers, the last ones having precedence.
+    fn merge(layers: Vec<(ConfigSource, Layer)>) -> (HashMap<Param, String>, HashMap<Param, ConfigSource>) {
         let mut params: HashMap<Param, String> = HashMap::new();
-        for param in Param::iter() {
-            let regkey = Hive::LocalMachine
-                .open(r"SOFTWARE\Owlyshield", Security::Read)
-                .expect("Cannot open registry hive");
-            let val = regkey
-                .value(Param::convert_to_str(&param))
-                .expect(&format!("Cannot open registry key {:?}", param))
-                .to_string();
-            params.insert(param, val);
-        }
-        Config {
+        let mut sources: HashMap<Param, ConfigSource> = HashMap::new();
+        for (source, layer) in layers {
+            for (param, val) in layer {
+                params.insert(param, val);
+                sources.insert(param, source);
+            }
+        }
+        (params, sources)
+    }
+
+    /// Merges the layers and validates the result.
+    fn from_layers(layers: Vec<(ConfigSource, Layer)>) -> Result<Config, ConfigError> {
+        let (params, sources) = Self::merge(layers);
+        Self::validate(&params)?;
+        let mut config = Config {
             params,
+            sources,
             extensions_list: ExtensionList::new(),
-            threshold_drivermsgs: 100,
-            threshold_prediction: 0.65,
+            threshold_drivermsgs: 0,
+            threshold_prediction: 0.0,
+            profile: RwLock::new(None),
+            portable: None,
+        };
+        config.threshold_drivermsgs = config.get_usize(Param::ThresholdDriverMsgs);
+        config.threshold_prediction = config.get_f32(Param::ThresholdPrediction);
+        config.extensions_list = ExtensionList::with_ransom_extensions(config.get_str(Param::RansomExtensions));
+        Ok(config)
+    }
+
+    fn validate(params: &HashMap<Param, String>) -> Result<(), ConfigError> {
+        for param in Param::iter() {
+            let key = Param::convert_to_str(&param);
+            let val = params
+                .get(&param)
+                .ok_or(ConfigError::Missing(String::from(key)))?;
+            Self::validate_value(param, val)?;
+        }
+        Ok(())
+    }
+
+    /// The errors of the merged *layers*, one per missing or invalid value.
+    fn check_layers(layers: Vec<(ConfigSource, Layer)>) -> Vec<ConfigError> {
+        let (params, _) = Self::merge(layers);
+        Param::iter()
+            .filter_map(|param| match params.get(&param) {
+                Some(val) => Self::validate_value(param, val).err(),
+                None => Some(ConfigError::Missing(String::from(Param::convert_to_str(&param)))),
+            })
+            .collect()
+    }
+
+    /// Checks that *val* is of the [ParamKind] of *param*.
+    pub fn validate_value(param: Param, val: &str) -> Result<(), ConfigError> {
+        let invalid = |expected: &str| ConfigError::Invalid {
+            key: String::from(Param::convert_to_str(&param)),
+            value: String::from(val),
+            expected: String::from(expected),
+        };
+        match param.kind() {
+            ParamKind::Path | ParamKind::Str => {
+                if val.trim().is_empty() {
+                    return Err(invalid("a non empty string"));
+                }
+            }
+            ParamKind::Int => {
+                val.trim().parse::<usize>().map_err(|_| invalid("a positive integer"))?;
+            }
+            ParamKind::Float => {
+                val.trim().parse::<f32>().map_err(|_| invalid("a float"))?;
+            }
+            ParamKind::Bool => {
+                Self::parse_bool(val).ok_or(invalid("true or false"))?;
+            }
+            ParamKind::Choice(choices) => {
+                if !choices.contains(&val.trim().to_uppercase().as_str()) {
+                    return Err(invalid(&choices.join(" or ")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn defaults_layer() -> Layer {
+        Param::iter()
+            .filter_map(|p| p.default_value().map(|v| (p, v)))
+            .collect()
+    }
+
+    /// Whether this process runs in the portable mode, before its [Config] is built.
+    pub fn is_portable_process() -> bool {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        Self::portable_arg(&args).is_some()
+    }
+
+    /// The directory following [PORTABLE_FLAG] in *args*.
+    fn portable_arg(args: &[String]) -> Option<PathBuf> {
+        let prefix = format!("{}=", PORTABLE_FLAG);
+        args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix(&prefix) {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None if arg == PORTABLE_FLAG => args.get(i + 1).map(PathBuf::from),
+            None => None,
+        })
+    }
+
+    /// The defaults, with the directories under the portable *dir*.
+    fn portable_defaults_layer(dir: &Path) -> Layer {
+        let mut layer = Self::defaults_layer();
+        for (param, subdir) in [(Param::ConfigPath, "config"), (Param::DebugPath, "debug"), (Param::UtilsPath, "utils")] {
+            layer.insert(param, dir.join(subdir).to_string_lossy().to_string());
+        }
+        layer
+    }
+
+    fn create_portable_dirs(dir: &Path) -> Result<(), ConfigError> {
+        for subdir in [Path::new("config").join("threats"), PathBuf::from("debug"), PathBuf::from("utils")] {
+            let path = dir.join(subdir);
+            fs::create_dir_all(&path).map_err(|e| ConfigError::File {
+                path,
+                details: e.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Values in ```owlyshield.toml```. A missing file is not an error.
+    fn file_layer(path: &Path) -> Result<Layer, ConfigError> {
+        let mut layer = Layer::new();
+        if !path.exists() {
+            return Ok(layer);
+        }
+        let file_error = |details: String| ConfigError::File {
+            path: path.to_path_buf(),
+            details,
+        };
+        let content = fs::read_to_string(path).map_err(|e| file_error(e.to_string()))?;
+        let table = content
+            .parse::<toml::Value>()
+            .map_err(|e| file_error(e.to_string()))?;
+        if let Some(table) = table.as_table() {
+            for (key, val) in table {
+                // Sub-tables are used by other modules (connectors...)
+                if val.is_table() {
+                    continue;
+                }
+                let param = Param::from_key(key).ok_or(file_error(format!("Unknown key {}", key)))?;
+                let strval = match val {
+                    toml::Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                };
+                layer.insert(param, strval);
+            }
+        }
+        Ok(layer)
+    }
+
+    /// Values written in the registry by the installer or by Group Policies. The key is optional.
+    #[cfg(windows)]
+    fn registry_layer(key: &str) -> Layer {
+        let mut layer = Layer::new();
+        if let Ok(regkey) = Hive::LocalMachine.open(key, Security::Read) {
+            for param in Param::iter() {
+                if let Ok(val) = regkey.value(Param::convert_to_str(&param)) {
+                    layer.insert(param, val.to_string());
+                }
+            }
+        }
+        layer
+    }
+
+    /// There is no registry outside of Windows.
+    #[cfg(not(windows))]
+    fn registry_layer(_key: &str) -> Layer {
+        Layer::new()
+    }
+
+    fn env_layer() -> Layer {
+        Param::iter()
+            .filter_map(|p| std::env::var(p.env_key()).ok().map(|v| (p, v)))
+            .collect()
+    }
+
+    /// Parses ```--flag value``` and ```--flag=value``` pairs. Unknown args are ignored.
+    fn cli_layer(args: &[String]) -> Layer {
+        let mut layer = Layer::new();
+        for param in Param::iter() {
+            let flag = param.cli_flag();
+            for (i, arg) in args.iter().enumerate() {
+                if let Some(val) = arg.strip_prefix(&format!("{}=", flag)) {
+                    layer.insert(param, String::from(val));
+                } else if *arg == flag {
+                    if let Some(val) = args.get(i + 1) {
+                        layer.insert(param, val.clone());
+                    }
+                }
+            }
         }
+        layer
+    }
+
+    fn parse_bool(val: &str) -> Option<bool> {
+        match val.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Some(true),
+            "false" | "0" | "no" | "off" => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn get_str(&self, param: Param) -> &str {
+        self.params[&param].trim()
+    }
+
+    pub fn get_path(&self, param: Param) -> PathBuf {
+        PathBuf::from(self.get_str(param))
+    }
+
+    pub fn get_usize(&self, param: Param) -> usize {
+        self.get_str(param).parse().unwrap_or_default()
+    }
+
+    pub fn get_f32(&self, param: Param) -> f32 {
+        self.get_str(param).parse().unwrap_or_default()
+    }
+
+    pub fn get_bool(&self, param: Param) -> bool {
+        Self::parse_bool(self.get_str(param)).unwrap_or_default()
+    }
+
+    /// The directory of the portable mode, None in the installed mode.
+    pub fn portable_dir(&self) -> Option<&Path> {
+        self.portable.as_deref()
+    }
+
+    /// Which source the value of *param* comes from.
+    pub fn get_source(&self, param: Param) -> ConfigSource {
+        self.sources[&param]
     }
 
     pub fn get_kill_policy(&self) -> KillPolicy {
-        match self[Param::KillPolicy].as_str() {
+        match self.get_str(Param::KillPolicy).to_uppercase().as_str() {
             "KILL" => KillPolicy::Kill,
             "SUSPEND" => KillPolicy::Suspend,
             &_ => KillPolicy::Kill
         }
     }
+
+    pub fn get_mode(&self) -> Mode {
+        match self.get_scheduled(Param::Mode).to_uppercase().as_str() {
+            "AUDIT" => Mode::Audit,
+            "LEARNING" => Mode::Learning,
+            _ => Mode::Protect,
+        }
+    }
+
+    /// *THRESHOLD_PREDICTION*, of the active profile if it overrides it.
+    pub fn get_threshold_prediction(&self) -> f32 {
+        self.get_scheduled(Param::ThresholdPrediction).parse().unwrap_or(self.threshold_prediction)
+    }
+
+    /// The value of *param* in the active scheduled profile, else [Self::get_str]. The value of a
+    /// policy wins over the profile.
+    pub fn get_scheduled(&self, param: Param) -> String {
+        if self.get_source(param) == ConfigSource::Policy {
+            return self.get_str(param).to_string();
+        }
+        let profile = self.profile.read().unwrap();
+        match profile.as_ref().and_then(|(_, values)| values.get(&param)) {
+            Some(val) => val.trim().to_string(),
+            None => self.get_str(param).to_string(),
+        }
+    }
+
+    /// Activates the profile (name and validated values), or none.
+    pub fn set_profile(&self, profile: Option<(String, HashMap<Param, String>)>) {
+        *self.profile.write().unwrap() = profile;
+    }
+
+    pub fn active_profile(&self) -> Option<String> {
+        self.profile.read().unwrap().as_ref().map(|(name, _)| name.clone())
+    }
 }
 
-impl Index<Param> for Config {
-    type Output = String;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(vals: &[(Param, &str)]) -> Layer {
+        vals.iter().map(|(p, v)| (*p, String::from(*v))).collect()
+    }
+
+    #[test]
+    fn last_layer_should_win() {
+        let config = Config::from_layers(vec![
+            (ConfigSource::Default, Config::defaults_layer()),
+            (ConfigSource::Registry, layer(&[(Param::KillPolicy, "KILL")])),
+            (ConfigSource::Cli, layer(&[(Param::KillPolicy, "SUSPEND")])),
+        ])
+        .unwrap();
+        assert!(config.get_kill_policy() == KillPolicy::Suspend);
+        assert_eq!(config.get_source(Param::KillPolicy), ConfigSource::Cli);
+        assert_eq!(config.get_source(Param::AppId), ConfigSource::Default);
+    }
+
+    #[test]
+    fn mode_should_default_to_protect() {
+        let defaults = Config::from_layers(vec![(ConfigSource::Default, Config::defaults_layer())]).unwrap();
+        assert_eq!(defaults.get_mode(), Mode::Protect);
+        let learning = Config::from_layers(vec![
+            (ConfigSource::Default, Config::defaults_layer()),
+            (ConfigSource::Env, layer(&[(Param::Mode, "learning")])),
+        ])
+        .unwrap();
+        assert_eq!(learning.get_mode(), Mode::Learning);
+    }
+
+    #[test]
+    fn policy_values_should_win_over_the_profiles() {
+        let config = Config::from_layers(vec![
+            (ConfigSource::Default, Config::defaults_layer()),
+            (ConfigSource::Policy, layer(&[(Param::Mode, "PROTECT")])),
+        ])
+        .unwrap();
+        let night = layer(&[(Param::Mode, "AUDIT"), (Param::ThresholdPrediction, "0.55")]);
+        config.set_profile(Some((String::from("night"), night)));
+        assert_eq!(config.get_mode(), Mode::Protect);
+        assert_eq!(config.get_threshold_prediction(), 0.55);
+    }
+
+    #[test]
+    fn invalid_value_should_name_the_key() {
+        let res = Config::from_layers(vec![
+            (ConfigSource::Default, Config::defaults_layer()),
+            (ConfigSource::Env, layer(&[(Param::ThresholdPrediction, "high")])),
+        ]);
+        match res {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "THRESHOLD_PREDICTION"),
+            _ => panic!("Expected an invalid value error"),
+        }
+    }
+
+    #[test]
+    fn all_invalid_values_should_be_checked() {
+        let errors = Config::check_layers(vec![
+            (ConfigSource::Default, Config::defaults_layer()),
+            (ConfigSource::Env, layer(&[(Param::ThresholdPrediction, "high"), (Param::Mode, "PANIC")])),
+        ]);
+        let keys: Vec<&str> = errors.iter().filter_map(ConfigError::key).collect();
+        assert_eq!(keys, vec!["THRESHOLD_PREDICTION", "MODE"]);
+    }
+
+    #[test]
+    fn cli_flags_should_be_parsed() {
+        let args: Vec<String> = vec!["--kill-policy", "SUSPEND", "--debug-path=C:\\debug"]
+            .iter()
+            .map(|a| String::from(*a))
+            .collect();
+        let cli = Config::cli_layer(&args);
+        assert_eq!(cli[&Param::KillPolicy], "SUSPEND");
+        assert_eq!(cli[&Param::DebugPath], "C:\\debug");
+    }
+
+    #[test]
+    fn portable_dir_should_hold_the_paths() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| String::from(*a)).collect() };
+        assert_eq!(Config::portable_arg(&args(&["--mode", "AUDIT", "--portable", "E:\\owly"])), Some(PathBuf::from("E:\\owly")));
+        assert_eq!(Config::portable_arg(&args(&["--portable=E:\\owly"])), Some(PathBuf::from("E:\\owly")));
+        assert_eq!(Config::portable_arg(&args(&["--portable"])), None);
 
-    fn index(&self, index: Param) -> &Self::Output {
-        &self.params[&index]
+        let dir = Path::new("usb");
+        let config = Config::from_layers(vec![(ConfigSource::Default, Config::portable_defaults_layer(dir))]).unwrap();
+        assert_eq!(config.get_path(Param::ConfigPath), dir.join("config"));
+        assert_eq!(config.get_path(Param::DebugPath), dir.join("debug"));
+        assert_eq!(config.get_str(Param::KillPolicy), "KILL");
+        assert!(config.portable_dir().is_none());
     }
 }
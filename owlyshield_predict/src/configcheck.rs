@@ -0,0 +1,267 @@
+//! Checks of the configuration for the deployment tooling, by ```config validate``` (```--json```
+//! for a machine-readable [ConfigReport]):
+//! * the values of all the sources (registry, *owlyshield.toml*...), all the invalid ones rather
+//!   than the first one;
+//! * the directories and files the values reference, and the embedded models;
+//! * the syntax of the policy files: *exclusions.toml* and the scheduled profiles;
+//! * the credentials of the enabled connectors, and with ```--dry-run```, that their endpoints
+//!   answer (a GET, nothing is sent).
+//!
+//! The errors prevent the agent from starting, or disable a part of it (a connector, the
+//! exclusions, a profile...). The warnings are probably unintended, but harmless.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use crate::config::{Config, Param, CONFIG_FILE_NAME};
+use crate::connectors::http;
+use crate::connectors::paging::{self, Service};
+use crate::connectors::{slack, teams};
+use crate::exclusions::Exclusions;
+use crate::prediction_static::TfLiteStatic;
+use crate::profiles::ProfileSchedule;
+use crate::routing::ModelRouter;
+use crate::stix;
+
+/// Of the requests of ```--dry-run```.
+const DRY_RUN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The configuration key or the file, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl Finding {
+    fn error(key: &str, message: String) -> Finding {
+        Finding {
+            severity: Severity::Error,
+            key: Some(key.to_string()),
+            message,
+        }
+    }
+
+    fn warning(key: &str, message: String) -> Finding {
+        Finding {
+            severity: Severity::Warning,
+            key: Some(key.to_string()),
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigValue {
+    pub key: &'static str,
+    pub value: String,
+    /// Default, File, Registry, Env, Cli or Policy
+    pub source: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigReport {
+    /// Without error
+    pub valid: bool,
+    /// Empty if a value is invalid
+    pub values: Vec<ConfigValue>,
+    pub findings: Vec<Finding>,
+}
+
+impl ConfigReport {
+    /// Of the configuration given by *args* and the other sources.
+    pub fn of(args: &[String], dry_run: bool) -> ConfigReport {
+        let config = match Config::from_args(args) {
+            Ok(config) => config,
+            Err(_) => {
+                let findings = Config::check_args(args)
+                    .iter()
+                    .map(|e| Finding {
+                        severity: Severity::Error,
+                        key: e.key().map(String::from),
+                        message: e.to_string(),
+                    })
+                    .collect();
+                return ConfigReport::from(Vec::new(), findings);
+            }
+        };
+        let values = Param::iter()
+            .map(|param| ConfigValue {
+                key: Param::convert_to_str(&param),
+                value: config.get_str(param).to_string(),
+                source: format!("{:?}", config.get_source(param)),
+            })
+            .collect();
+        let mut findings = paths(&config);
+        findings.extend(models(&config));
+        findings.extend(policies(&config));
+        findings.extend(connectors(&config, dry_run));
+        ConfigReport::from(values, findings)
+    }
+
+    fn from(values: Vec<ConfigValue>, findings: Vec<Finding>) -> ConfigReport {
+        ConfigReport {
+            valid: !findings.iter().any(|f| f.severity == Severity::Error),
+            values,
+            findings,
+        }
+    }
+}
+
+/// The directories of the agent, and the files of the optional features.
+fn paths(config: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for param in [Param::ConfigPath, Param::DebugPath, Param::UtilsPath] {
+        let path = config.get_path(param);
+        if !path.is_dir() {
+            findings.push(Finding::error(Param::convert_to_str(&param), format!("Directory {} not found", path.display())));
+        }
+    }
+    for param in [Param::YaraPath, Param::YaraRules] {
+        let value = config.get_str(param);
+        if !value.eq_ignore_ascii_case("NONE") && !Path::new(value).is_file() {
+            findings.push(Finding::error(Param::convert_to_str(&param), format!("File {} not found", value)));
+        }
+    }
+    findings
+}
+
+/// The embedded models, which the agent cannot run without, and those of the categories.
+fn models(config: &Config) -> Vec<Finding> {
+    let errors = [ModelRouter::from(config).err().map(|e| e.to_string()), TfLiteStatic::new().err().map(|e| e.to_string())];
+    errors
+        .iter()
+        .flatten()
+        .map(|e| Finding {
+            severity: Severity::Error,
+            key: None,
+            message: e.clone(),
+        })
+        .collect()
+}
+
+/// *exclusions.toml* and the profiles of *owlyshield.toml*, both optional.
+fn policies(config: &Config) -> Vec<Finding> {
+    let dir = config.get_path(Param::ConfigPath);
+    let mut findings = Vec::new();
+    let exclusions = dir.join("exclusions.toml");
+    if exclusions.exists() {
+        if let Err(e) = Exclusions::check(&exclusions) {
+            findings.push(Finding::error("exclusions.toml", e));
+        }
+    }
+    if let Ok(content) = fs::read_to_string(dir.join(CONFIG_FILE_NAME)) {
+        for e in ProfileSchedule::check(&content) {
+            findings.push(Finding::error(CONFIG_FILE_NAME, e));
+        }
+    }
+    findings
+}
+
+/// The credentials of the enabled connectors and services, and the answer of their endpoints
+/// with *dry_run*. The URLs of the webhooks, secret, are not shown.
+fn connectors(config: &Config, dry_run: bool) -> Vec<Finding> {
+    let dir = config.get_path(Param::ConfigPath);
+    let credential = |file: &str| fs::read_to_string(dir.join(file)).ok().map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+    let url = |param: Param| Some(config.get_str(param).to_string()).filter(|url| !url.is_empty() && !url.eq_ignore_ascii_case("NONE"));
+
+    // (key, credential file if required, endpoint)
+    let mut endpoints: Vec<(Param, Option<&str>, Option<String>)> = Vec::new();
+    if config.get_bool(Param::Slack) {
+        endpoints.push((Param::Slack, Some(slack::WEBHOOK_FILE), credential(slack::WEBHOOK_FILE)));
+    }
+    if config.get_bool(Param::Teams) {
+        endpoints.push((Param::Teams, Some(teams::WEBHOOK_FILE), credential(teams::WEBHOOK_FILE)));
+    }
+    if let Some(service) = Service::from(config.get_str(Param::Paging)) {
+        endpoints.push((Param::Paging, Some(paging::KEY_FILE), Some(service.url().to_string())));
+    }
+    if let Some(misp) = url(Param::MispUrl) {
+        endpoints.push((Param::MispUrl, Some(stix::KEY_FILE), Some(misp)));
+    }
+    for param in [Param::HeartbeatUrl, Param::UpdateUrl] {
+        if let Some(url) = url(param) {
+            endpoints.push((param, None, Some(url)));
+        }
+    }
+
+    let mut findings = Vec::new();
+    for (param, file, endpoint) in endpoints {
+        let key = Param::convert_to_str(&param);
+        if let Some(file) = file {
+            if credential(file).is_none() {
+                findings.push(Finding::error(key, format!("{} not found or empty in {}", file, dir.display())));
+                continue;
+            }
+        }
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => continue,
+        };
+        if !endpoint.starts_with("https://") {
+            findings.push(Finding::warning(key, format!("Endpoint of {} not in HTTPS", key)));
+        }
+        if dry_run {
+            match http::get(&endpoint, &[], DRY_RUN_TIMEOUT) {
+                Ok((status, _)) if status >= 500 => {
+                    findings.push(Finding::warning(key, format!("Endpoint of {} answered HTTP {}", key, status)))
+                }
+                Ok(_) => {}
+                Err(e) => findings.push(Finding::error(key, format!("Endpoint of {} unreachable: {}", key, e))),
+            }
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::config::{Config, Param};
+    use crate::configcheck::{connectors, paths, policies, ConfigReport, Severity};
+
+    #[test]
+    fn report_should_list_the_errors_of_the_configuration() {
+        let dir = std::env::temp_dir().join(format!("owlyshield_configcheck_{}", std::process::id()));
+        let args = |extra: &[&str]| -> Vec<String> {
+            let mut args = vec![String::from("--portable"), dir.to_string_lossy().to_string()];
+            args.extend(extra.iter().map(|a| a.to_string()));
+            args
+        };
+
+        let report = ConfigReport::of(&args(&["--mode", "PANIC", "--threshold-prediction", "high"]), false);
+        assert!(!report.valid && report.values.is_empty());
+        let keys: Vec<&str> = report.findings.iter().filter_map(|f| f.key.as_deref()).collect();
+        assert_eq!(keys, vec!["THRESHOLD_PREDICTION", "MODE"]);
+
+        let config = Config::from_args(&args(&["--slack", "true", "--paging", "OPSGENIE", "--yara-rules", "rules.yar"])).unwrap();
+        let config_dir = config.get_path(Param::ConfigPath);
+        fs::write(config_dir.join("exclusions.toml"), "[never_kill\nsigners = []").unwrap();
+        fs::write(config_dir.join("owlyshield.toml"), "[profiles.night]\nstart = \"25:00\"\nend = \"07:00\"\n").unwrap();
+        fs::write(config_dir.join("paging_key"), "key\n").unwrap();
+        let keys = |findings: Vec<crate::configcheck::Finding>| -> Vec<(Severity, String)> {
+            findings.into_iter().map(|f| (f.severity, f.key.unwrap_or_default())).collect()
+        };
+        assert_eq!(keys(paths(&config)), vec![(Severity::Error, String::from("YARA_RULES"))]);
+        assert_eq!(
+            keys(policies(&config)),
+            vec![(Severity::Error, String::from("exclusions.toml")), (Severity::Error, String::from("owlyshield.toml"))]
+        );
+        assert_eq!(keys(connectors(&config, false)), vec![(Severity::Error, String::from("SLACK"))]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
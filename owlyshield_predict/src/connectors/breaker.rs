@@ -0,0 +1,88 @@
+//! Circuit breaker of a [crate::connectors::connector::Connector], so that an unreachable
+//! interface neither slows down nor floods the logs of the agent.
+//!
+//! After [MAX_FAILURES] consecutive failures, the connector is not called anymore for
+//! [COOL_DOWN], and a single *ConnectorDegraded* event is logged. Once the cool-down is over, one
+//! call is tried again: a success closes the circuit, a failure opens it for another cool-down
+//! without a new event.
+//!
+//! The shutdown is not guarded: each connector is always given a chance to flush.
+
+use std::time::{Duration, Instant};
+
+/// Consecutive failures opening the circuit.
+pub const MAX_FAILURES: u32 = 5;
+/// Time the connector is not called once the circuit is open.
+pub const COOL_DOWN: Duration = Duration::from_secs(300);
+
+/// What a call did to the circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    None,
+    /// The connector is degraded, from now on.
+    Opened,
+    /// The connector works again, after being degraded.
+    Closed,
+}
+
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Whether the connector may be called.
+    pub fn allows(&self, now: Instant) -> bool {
+        self.open_until.is_none_or(|until| now >= until)
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn record(&mut self, success: bool, now: Instant) -> Transition {
+        if success {
+            self.failures = 0;
+            return match self.open_until.take() {
+                Some(_) => Transition::Closed,
+                None => Transition::None,
+            };
+        }
+        self.failures += 1;
+        if self.failures < MAX_FAILURES {
+            return Transition::None;
+        }
+        let was_open = self.open_until.replace(now + COOL_DOWN).is_some();
+        if was_open {
+            Transition::None
+        } else {
+            Transition::Opened
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::connectors::breaker::{CircuitBreaker, Transition, COOL_DOWN, MAX_FAILURES};
+
+    #[test]
+    fn circuit_should_open_once_then_close_on_success() {
+        let mut breaker = CircuitBreaker::default();
+        let now = Instant::now();
+        for _ in 1..MAX_FAILURES {
+            assert_eq!(breaker.record(false, now), Transition::None);
+        }
+        assert_eq!(breaker.record(false, now), Transition::Opened);
+        assert!(!breaker.allows(now));
+        let later = now + COOL_DOWN;
+        assert!(breaker.allows(later));
+        // the retry fails: another cool-down, without a new event
+        assert_eq!(breaker.record(false, later), Transition::None);
+        assert!(!breaker.allows(later));
+        assert_eq!(breaker.record(true, later + COOL_DOWN), Transition::Closed);
+        assert_eq!(breaker.record(false, later + COOL_DOWN), Transition::None);
+    }
+}
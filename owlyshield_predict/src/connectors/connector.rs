@@ -1,12 +1,33 @@
 //! [Connector] allows to decouple connectors modules for interfaces.
 
-use crate::process::ProcessRecord;
+use crate::process::{ProcessRecord, ProcessTerminated};
 
+#[cfg(windows)]
 use crate::connectors::sitincloud::SitinCloud;
-use log::error;
-use std::fmt;
-use std::error::Error;
-use crate::config::Config;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tracing::{error, info, info_span};
+use crate::connectors::breaker;
+use crate::connectors::breaker::{CircuitBreaker, Transition};
+use crate::autostart::Persistence;
+use crate::config::{Config, Param};
+use crate::connectors::localstore::LocalStore;
+use crate::connectors::paging::Paging;
+use crate::connectors::slack::Slack;
+use crate::connectors::teams::Teams;
+use crate::enrichment::Enricher;
+use crate::error::OwlyError;
+use crate::escalation::Escalated;
+use crate::exfil::PreAlert;
+use crate::identity::AgentIdentity;
+use crate::killcheck::Kill;
+use crate::profiles::ProfileChange;
+use crate::ransomnote::RansomNoteDropped;
+use crate::rawdisk::RawDiskWrite;
+use crate::watchdog::Incident;
+use crate::wiper::MassDeletion;
 
 /// Contains the methods of the [Connector] interface.
 ///
@@ -17,71 +38,378 @@ use crate::config::Config;
 /// cs.add(MyConnector);
 /// cs.send_events(proc, prediction);
 /// ```
-/// Where `MyConnector` is a struct implementing the [Connector] trait.
+/// Where `MyConnector` is a struct implementing the [Connector] trait. Every method receives the
+/// [AgentIdentity] of the machine, to be attached to what is sent. The connectors sending JSON
+/// send the [crate::schema::Envelope] of the events, the published contract.
 pub trait Connector {
     /// Creates a new [Connector] instance.
     fn new() -> Self where Self: Sized;
     /// Returns the name of the interface.
     fn to_string(&self) -> String;
     /// Actions on service startup
-    fn on_startup(&self, config: &Config) -> Result<(), ConnectorError>;
+    fn on_startup(&self, config: &Config, identity: &AgentIdentity) -> Result<(), ConnectorError>;
     /// Send events to the interface.
-    fn send_event(&self, proc: &ProcessRecord, prediction: f32) -> Result<(), ConnectorError>;
+    fn send_event(&self, identity: &AgentIdentity, proc: &ProcessRecord, prediction: f32) -> Result<(), ConnectorError>;
+    /// Actions on service stop, like sending the pending events.
+    fn on_shutdown(&self) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send an incident of the agent itself (crash, hang...).
+    fn send_incident(&self, _identity: &AgentIdentity, _incident: &Incident) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send the summary of a process family whose processes have all exited.
+    fn send_process_terminated(&self, _identity: &AgentIdentity, _summary: &ProcessTerminated) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send a write to the raw disk or a volume (Critical).
+    fn send_raw_disk_write(&self, _identity: &AgentIdentity, _event: &RawDiskWrite) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send a mass deletion of files without encryption, by a wiper (Critical).
+    fn send_mass_deletion(&self, _identity: &AgentIdentity, _event: &MassDeletion) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send a ransom note dropped in many directories (Critical).
+    fn send_ransom_note(&self, _identity: &AgentIdentity, _event: &RansomNoteDropped) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send an archive of documents staged for exfiltration (PreAlert).
+    fn send_pre_alert(&self, _identity: &AgentIdentity, _event: &PreAlert) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send a scheduled task or a service created by a gid, see [crate::autostart].
+    fn send_persistence(&self, _identity: &AgentIdentity, _event: &Persistence) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send a gid entering the PreAlert level of its [crate::escalation], before the kill.
+    fn send_escalation(&self, _identity: &AgentIdentity, _event: &Escalated) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send a kill, once verified: the processes exited, were killed again or survived.
+    fn send_kill(&self, _identity: &AgentIdentity, _kill: &Kill) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send a change of the active scheduled profile.
+    fn send_profile_change(&self, _identity: &AgentIdentity, _change: &ProfileChange) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send the degradation of another connector, see [crate::connectors::breaker].
+    fn send_connector_degraded(&self, _identity: &AgentIdentity, _event: &ConnectorDegraded) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send an alert acknowledged, or cleared as a false positive, by a user.
+    fn send_review(&self, _identity: &AgentIdentity, _review: &AlertReview) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+    /// Send a synthetic event, by ```connectors test```, and wait for its delivery: its error is
+    /// the one the first real alert would meet.
+    fn send_test(&self, _identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        Err(ConnectorError::new(&self.to_string(), "No test event"))
+    }
+}
+
+/// Result of [Connectors::test] for a connector.
+#[derive(Debug, Clone)]
+pub struct ConnectorTest {
+    pub connector: String,
+    /// Of the test event, None if the connector did not start
+    pub latency: Option<Duration>,
+    pub error: Option<String>,
 }
 
-/// Struct containing the list of connectors.
+/// Struct containing the list of connectors, with the identity of the machine. The alerts are
+/// tagged by its [Enricher] first, if any.
 pub struct Connectors {
-    connectors: Vec<Box<dyn Connector>>,
+    connectors: Vec<Guarded>,
+    identity: AgentIdentity,
+    enricher: Option<Enricher>,
 }
 
+/// A connector with its [CircuitBreaker].
+struct Guarded {
+    connector: Box<dyn Connector>,
+    breaker: Mutex<CircuitBreaker>,
+}
+
+/// A connector not called anymore after [breaker::MAX_FAILURES] consecutive failures, sent once
+/// to the other connectors.
+#[derive(Debug, Clone)]
+pub struct ConnectorDegraded {
+    pub time: SystemTime,
+    pub connector: String,
+    pub failures: u32,
+    pub last_error: String,
+    /// Time before the connector is tried again
+    pub cool_down: Duration,
+}
 
 impl Connectors {
-    /// Creates a new [Connectors] list.
+    /// Creates a new [Connectors] list, with the [AgentIdentity::current] of the machine.
     pub fn new() -> Connectors {
         Connectors {
             connectors: Vec::new(),
+            identity: AgentIdentity::current(),
+            enricher: None,
+        }
+    }
+
+    /// The connectors enabled by *config*, not started yet.
+    pub fn from(config: &Config) -> Connectors {
+        let mut cs = Connectors::new();
+        // cs.add(SitinCloud);
+        if config.get_bool(Param::EventStore) {
+            cs.add(LocalStore::new());
+        }
+        if config.get_bool(Param::Slack) {
+            cs.add(Slack::new());
         }
+        if config.get_bool(Param::Teams) {
+            cs.add(Teams::new());
+        }
+        if config.get_str(Param::Paging) != "NONE" {
+            cs.add(Paging::new());
+        }
+        cs
     }
 
     /// Adds a [Connector] to [Connectors] list.
     pub fn add<T: 'static +Connector>(&mut self, connector: T) {
-        self.connectors.push(Box::new(connector));
+        self.connectors.push(Guarded {
+            connector: Box::new(connector),
+            breaker: Mutex::new(CircuitBreaker::default()),
+        });
     }
 
-    /// Launch on_startup method of all connectors at service startup.
-    pub fn on_startup(&self, config: &Config)
+    /// Launch on_startup method of all connectors at service startup. A connector which fails
+    /// to start is removed (see [crate::error::ErrorPolicy::Degrade]), the protection goes on without it.
+    /// So are the enrichment providers, if they cannot be loaded.
+    pub fn on_startup(&mut self, config: &Config)
     {
-        for connector in &self.connectors {
-            let result = connector.on_startup(config);
-            match result {
-                Ok(result) => result,
+        match Enricher::load(config, &self.identity.hostname) {
+            Ok(enricher) => self.enricher = enricher,
+            Err(e) => error!("{}, the alerts are not enriched", e),
+        }
+        let identity = &self.identity;
+        self.connectors.retain(|guarded| {
+            let _enter = info_span!("connector", name = %guarded.connector.to_string()).entered();
+            match guarded.connector.on_startup(config, identity) {
+                Ok(()) => true,
                 Err(e) => {
-                    error!("{}", e.to_string());
-                    println!("{}", e.to_string());
-                    panic!("{}", e.to_string());
+                    error!("{}, the connector is disabled", OwlyError::from(e));
+                    false
+                }
+            }
+        });
+    }
+
+    /// Starts each connector, sends it a test event ([Connector::send_test]) and stops it, for
+    /// ```connectors test```. Unlike with [Connectors::on_startup], the failures are returned.
+    pub fn test(&self, config: &Config) -> Vec<ConnectorTest> {
+        self.connectors
+            .iter()
+            .map(|guarded| {
+                let connector = guarded.connector.as_ref();
+                if let Err(e) = connector.on_startup(config, &self.identity) {
+                    return ConnectorTest { connector: connector.to_string(), latency: None, error: Some(e.to_string()) };
                 }
+                let start = Instant::now();
+                let sent = connector.send_test(&self.identity);
+                let latency = start.elapsed();
+                let res = sent.and(connector.on_shutdown());
+                ConnectorTest { connector: connector.to_string(), latency: Some(latency), error: res.err().map(|e| e.to_string()) }
+            })
+            .collect()
+    }
+
+    /// Launch on_shutdown method of all connectors when the service stops. Errors are only logged
+    /// so that every connector gets a chance to flush: the circuit breakers are bypassed, an open
+    /// circuit would lose the events buffered by its connector.
+    pub fn on_shutdown(&self) {
+        for guarded in &self.connectors {
+            let _enter = info_span!("connector", name = %guarded.connector).entered();
+            if let Err(e) = guarded.connector.on_shutdown() {
+                error!("{}", e.to_string());
             }
         }
     }
 
-    /// Send events using the send_event method of all connectors.
+    /// Send an incident using the send_incident method of all connectors. Errors are only logged:
+    /// the agent is already in a degraded state.
+    pub fn send_incident(&self, incident: &Incident) {
+        self.call(|connector, identity| connector.send_incident(identity, incident));
+    }
+
+    /// Send the summary of an expired process family to all connectors. Errors are only logged.
+    pub fn send_process_terminated(&self, summary: &ProcessTerminated) {
+        self.call(|connector, identity| connector.send_process_terminated(identity, summary));
+    }
+
+    /// Send a raw disk write to all connectors. Errors are only logged.
+    pub fn send_raw_disk_write(&self, event: &RawDiskWrite) {
+        self.call(|connector, identity| connector.send_raw_disk_write(identity, event));
+    }
+
+    /// Send a mass deletion to all connectors. Errors are only logged.
+    pub fn send_mass_deletion(&self, event: &MassDeletion) {
+        let event = &MassDeletion {
+            tags: self.tags(&event.exepath),
+            ..event.clone()
+        };
+        self.call(|connector, identity| connector.send_mass_deletion(identity, event));
+    }
+
+    /// Send a ransom note to all connectors. Errors are only logged.
+    pub fn send_ransom_note(&self, event: &RansomNoteDropped) {
+        let event = &RansomNoteDropped {
+            tags: self.tags(&event.exepath),
+            ..event.clone()
+        };
+        self.call(|connector, identity| connector.send_ransom_note(identity, event));
+    }
+
+    /// Send a PreAlert to all connectors. Errors are only logged.
+    pub fn send_pre_alert(&self, event: &PreAlert) {
+        let event = &PreAlert {
+            tags: self.tags(&event.exepath),
+            ..event.clone()
+        };
+        self.call(|connector, identity| connector.send_pre_alert(identity, event));
+    }
+
+    /// Send a persistence of a gid to all connectors. Errors are only logged.
+    pub fn send_persistence(&self, event: &Persistence) {
+        let event = &Persistence {
+            tags: self.tags(&event.exepath),
+            ..event.clone()
+        };
+        self.call(|connector, identity| connector.send_persistence(identity, event));
+    }
+
+    /// Send a gid entering PreAlert to all connectors. Errors are only logged.
+    pub fn send_escalation(&self, event: &Escalated) {
+        let event = &Escalated {
+            tags: self.tags(&event.exepath),
+            ..event.clone()
+        };
+        self.call(|connector, identity| connector.send_escalation(identity, event));
+    }
+
+    /// Send a verified kill to all connectors. Errors are only logged.
+    pub fn send_kill(&self, kill: &Kill) {
+        let mut kill = kill.clone();
+        kill.request.tags = self.tags(&kill.request.exepath);
+        let kill = &kill;
+        self.call(|connector, identity| connector.send_kill(identity, kill));
+    }
+
+    /// Send a change of the active scheduled profile to all connectors. Errors are only logged.
+    pub fn send_profile_change(&self, change: &ProfileChange) {
+        self.call(|connector, identity| connector.send_profile_change(identity, change));
+    }
+
+    /// Send an alert review to all connectors. Errors are only logged.
+    pub fn send_review(&self, review: &AlertReview) {
+        self.call(|connector, identity| connector.send_review(identity, review));
+    }
+
+    /// Send events using the send_event method of all connectors. Errors are only logged: the
+    /// process has already been handled.
     pub fn send_events(&self, proc: &ProcessRecord, prediction: f32)
     {
-        for connector in &self.connectors {
-            let result = connector.send_event(proc, prediction);
-            match result {
-                Ok(result) => result,
-                Err(e) => {
-                    error!("{}", e.to_string());
-                    println!("{}", e.to_string());
-                    panic!("{}", e.to_string());
-                }
+        self.call(|connector, identity| connector.send_event(identity, proc, prediction));
+    }
+
+    /// Of the machine, attached to the events.
+    pub fn identity(&self) -> &AgentIdentity {
+        &self.identity
+    }
+
+    /// Starts the enrichment of *exepath*, to have its tags when its alert is sent.
+    pub fn prefetch(&self, exepath: &Path) {
+        if let Some(enricher) = &self.enricher {
+            enricher.prefetch(exepath);
+        }
+    }
+
+    /// Tags of *exepath* by the [Enricher], empty without it.
+    fn tags(&self, exepath: &Path) -> Vec<String> {
+        self.enricher.as_ref().map_or(Vec::new(), |enricher| enricher.tags(exepath))
+    }
+
+    /// Calls *f* on the connectors whose circuit is closed. Errors are logged and counted by
+    /// their [CircuitBreaker].
+    fn call<F>(&self, f: F)
+    where
+        F: Fn(&dyn Connector, &AgentIdentity) -> Result<(), ConnectorError>,
+    {
+        for (i, guarded) in self.connectors.iter().enumerate() {
+            let name = guarded.connector.to_string();
+            let _enter = info_span!("connector", name = %name).entered();
+            if !guarded.breaker.lock().unwrap().allows(Instant::now()) {
+                continue;
+            }
+            let res = f(guarded.connector.as_ref(), &self.identity);
+            if let Err(e) = &res {
+                error!("{}", e.to_string());
+            }
+            let (transition, failures) = {
+                let mut breaker = guarded.breaker.lock().unwrap();
+                (breaker.record(res.is_ok(), Instant::now()), breaker.consecutive_failures())
+            };
+            match (transition, res) {
+                (Transition::Opened, Err(e)) => self.degraded(
+                    i,
+                    &ConnectorDegraded {
+                        time: SystemTime::now(),
+                        connector: name,
+                        failures,
+                        last_error: e.to_string(),
+                        cool_down: breaker::COOL_DOWN,
+                    },
+                ),
+                (Transition::Closed, _) => info!("Connector recovered"),
+                _ => {}
             }
         }
     }
+
+    /// Logs the degradation of the connector *index*, and sends it to the others.
+    fn degraded(&self, index: usize, event: &ConnectorDegraded) {
+        error!(
+            connector = %event.connector,
+            failures = event.failures,
+            cool_down_secs = event.cool_down.as_secs(),
+            "ConnectorDegraded: the connector is not called anymore until the end of the cool-down"
+        );
+        for (i, guarded) in self.connectors.iter().enumerate() {
+            if i == index || !guarded.breaker.lock().unwrap().allows(Instant::now()) {
+                continue;
+            }
+            if let Err(e) = guarded.connector.send_connector_degraded(&self.identity, event) {
+                error!("{}", e.to_string());
+            }
+        }
+    }
+}
+
+/// An alert acknowledged, or marked as a false positive, from the API or a chat connector (see
+/// [crate::status::AgentStatus::review]).
+#[derive(Debug, Clone)]
+pub struct AlertReview {
+    pub time: SystemTime,
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: String,
+    pub user: String,
+    pub false_positive: bool,
+    /// Id of the incident of the gid, see [crate::correlation]
+    pub incident: Option<String>,
 }
 
 /// Struct containing a custom error for [Connector] type.
+#[derive(Debug, Error)]
+#[error("{connector_name} : {details}")]
 pub struct ConnectorError {
     connector_name: String,
     details: String,
@@ -96,8 +424,77 @@ impl ConnectorError {
     }
 }
 
-impl fmt::Display for ConnectorError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} : {}", self.connector_name, self.details)
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::config::Config;
+    use crate::connectors::breaker::CircuitBreaker;
+    use crate::connectors::connector::{Connector, ConnectorError, Connectors, Guarded};
+    use crate::identity::AgentIdentity;
+    use crate::process::ProcessRecord;
+
+    /// Starts if named, and sends the test event unless it is "Silent".
+    struct Fake(&'static str);
+
+    impl Connector for Fake {
+        fn new() -> Fake {
+            Fake("")
+        }
+
+        fn to_string(&self) -> String {
+            String::from(self.0)
+        }
+
+        fn on_startup(&self, _config: &Config, _identity: &AgentIdentity) -> Result<(), ConnectorError> {
+            if self.0.is_empty() {
+                return Err(ConnectorError::new("Fake", "Cannot read fake_webhook"));
+            }
+            Ok(())
+        }
+
+        fn send_event(&self, _identity: &AgentIdentity, _proc: &ProcessRecord, _prediction: f32) -> Result<(), ConnectorError> {
+            Ok(())
+        }
+
+        fn send_test(&self, identity: &AgentIdentity) -> Result<(), ConnectorError> {
+            if self.0 == "Silent" {
+                return Err(ConnectorError::new(self.0, "No test event"));
+            }
+            assert_eq!(identity.hostname, "srv-files");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_should_report_each_connector() {
+        let dir = std::env::temp_dir().join(format!("owlyshield_connectors_{}", std::process::id()));
+        let config = Config::from_args(&[String::from("--portable"), dir.to_string_lossy().to_string()]).unwrap();
+        let cs = Connectors {
+            connectors: vec![Fake::new(), Fake("Silent"), Fake("Webhook")]
+                .into_iter()
+                .map(|fake| Guarded { connector: Box::new(fake), breaker: Mutex::new(CircuitBreaker::default()) })
+                .collect(),
+            identity: AgentIdentity {
+                machine_id: String::from("6f1c"),
+                hostname: String::from("srv-files"),
+                os_version: String::from("Windows 10 Pro 19044"),
+                domain: None,
+                agent_version: String::from("1.2.0"),
+            },
+            enricher: None,
+        };
+        let tests = cs.test(&config);
+        let results: Vec<(&str, bool, Option<&str>)> =
+            tests.iter().map(|t| (t.connector.as_str(), t.latency.is_some(), t.error.as_deref())).collect();
+        assert_eq!(
+            results,
+            vec![
+                ("", false, Some("Fake : Cannot read fake_webhook")),
+                ("Silent", true, Some("Silent : No test event")),
+                ("Webhook", true, None)
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,76 @@
+//! JSON requests of the connectors to chat and alerting services, and of the
+//! [crate::enrichment] providers.
+
+use std::io::Read;
+use std::time::Duration;
+
+use curl::easy::{Easy, List};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest answer kept by [get].
+const MAX_ANSWER: usize = 64 * 1024;
+
+/// Posts *body* to *url*, with the additional *headers*. Returns the status code and the body of
+/// the answer.
+pub fn post_json(url: &str, headers: &[String], body: &str) -> Result<(u32, String), curl::Error> {
+    let mut data = body.as_bytes();
+    let mut response = Vec::new();
+    let mut list = List::new();
+    list.append("Content-Type: application/json")?;
+    for header in headers {
+        list.append(header)?;
+    }
+    let mut easy = Easy::new();
+    easy.url(url)?;
+    easy.post(true)?;
+    easy.post_field_size(body.len() as u64)?;
+    easy.http_headers(list)?;
+    easy.timeout(REQUEST_TIMEOUT)?;
+    {
+        let mut transfer = easy.transfer();
+        transfer.read_function(|buf| Ok(data.read(buf).unwrap_or(0)))?;
+        transfer.write_function(|chunk| {
+            response.extend_from_slice(chunk);
+            Ok(chunk.len())
+        })?;
+        transfer.perform()?;
+    }
+    Ok((easy.response_code()?, String::from_utf8_lossy(&response).chars().take(200).collect()))
+}
+
+/// Gets *url*, with the additional *headers*, giving up after *timeout*. Returns the status code
+/// and the body of the answer, up to [MAX_ANSWER] bytes.
+pub fn get(url: &str, headers: &[String], timeout: Duration) -> Result<(u32, String), curl::Error> {
+    let mut response = Vec::new();
+    let mut list = List::new();
+    list.append("Accept: application/json")?;
+    for header in headers {
+        list.append(header)?;
+    }
+    let mut easy = Easy::new();
+    easy.url(url)?;
+    easy.get(true)?;
+    easy.http_headers(list)?;
+    easy.timeout(timeout)?;
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|chunk| {
+            let room = MAX_ANSWER.saturating_sub(response.len());
+            response.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            Ok(chunk.len())
+        })?;
+        transfer.perform()?;
+    }
+    Ok((easy.response_code()?, String::from_utf8_lossy(&response).to_string()))
+}
+
+/// *value* percent-encoded, to be part of an url.
+pub fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,132 @@
+//! [Connector] recording the events into the local [EventStore] (*EVENT_STORE*).
+
+use std::fs;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tracing::info;
+
+use crate::autostart::Persistence;
+use crate::config::Config;
+use crate::connectors::connector::{Connector, ConnectorDegraded, ConnectorError};
+use crate::escalation::Escalated;
+use crate::eventstore::{EventStore, Retention, PRUNE_EVERY};
+use crate::exfil::PreAlert;
+use crate::identity::AgentIdentity;
+use crate::killcheck::Kill;
+use crate::process::{ProcessRecord, ProcessTerminated};
+use crate::profiles::ProfileChange;
+use crate::ransomnote::RansomNoteDropped;
+use crate::rawdisk::RawDiskWrite;
+use crate::schema::{Detection, Envelope, Event};
+use crate::watchdog::Incident;
+use crate::wiper::MassDeletion;
+
+/// The store, once opened.
+struct Opened {
+    store: EventStore,
+    retention: Retention,
+    /// Since the last pruning
+    recorded: u64,
+}
+
+/// Struct of the [LocalStore] interface.
+pub struct LocalStore {
+    opened: Mutex<Option<Opened>>,
+}
+
+impl Connector for LocalStore {
+    fn new() -> LocalStore {
+        LocalStore { opened: Mutex::new(None) }
+    }
+
+    fn to_string(&self) -> String {
+        String::from("LocalStore")
+    }
+
+    fn on_startup(&self, config: &Config, _identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        let path = EventStore::path(config);
+        let error = |e: &dyn std::fmt::Display| ConnectorError::new(&self.to_string(), &format!("{}: {}", path.display(), e));
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| error(&e))?;
+        }
+        let store = EventStore::open(&path).map_err(|e| error(&e))?;
+        let retention = Retention::from(config);
+        let deleted = store.prune(&retention, SystemTime::now()).map_err(|e| error(&e))?;
+        info!(deleted, "Event store opened, the events beyond the retention are deleted");
+        *self.opened.lock().unwrap() = Some(Opened { store, retention, recorded: 0 });
+        Ok(())
+    }
+
+    fn send_event(&self, identity: &AgentIdentity, proc: &ProcessRecord, prediction: f32) -> Result<(), ConnectorError> {
+        self.record(identity, Event::Detection(Detection::from(proc, prediction)))
+    }
+
+    fn send_incident(&self, identity: &AgentIdentity, incident: &Incident) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(incident))
+    }
+
+    fn send_process_terminated(&self, identity: &AgentIdentity, summary: &ProcessTerminated) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(summary))
+    }
+
+    fn send_raw_disk_write(&self, identity: &AgentIdentity, event: &RawDiskWrite) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(event))
+    }
+
+    fn send_mass_deletion(&self, identity: &AgentIdentity, event: &MassDeletion) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(event))
+    }
+
+    fn send_ransom_note(&self, identity: &AgentIdentity, event: &RansomNoteDropped) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(event))
+    }
+
+    fn send_pre_alert(&self, identity: &AgentIdentity, event: &PreAlert) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(event))
+    }
+
+    fn send_persistence(&self, identity: &AgentIdentity, event: &Persistence) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(event))
+    }
+
+    fn send_escalation(&self, identity: &AgentIdentity, event: &Escalated) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(event))
+    }
+
+    fn send_kill(&self, identity: &AgentIdentity, kill: &Kill) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(kill))
+    }
+
+    fn send_profile_change(&self, identity: &AgentIdentity, change: &ProfileChange) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(change))
+    }
+
+    fn send_connector_degraded(&self, identity: &AgentIdentity, event: &ConnectorDegraded) -> Result<(), ConnectorError> {
+        self.record(identity, Event::from(event))
+    }
+
+    fn send_test(&self, _identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        // Not recorded, it would be listed with the alerts
+        let opened = self.opened.lock().unwrap();
+        let opened = opened.as_ref().ok_or_else(|| ConnectorError::new(&self.to_string(), "Not started"))?;
+        opened.store.check_writable().map_err(|e| ConnectorError::new(&self.to_string(), &e.to_string()))
+    }
+}
+
+impl LocalStore {
+    /// Records *event*, and prunes the store every [PRUNE_EVERY] events.
+    fn record(&self, identity: &AgentIdentity, event: Event) -> Result<(), ConnectorError> {
+        let mut opened = self.opened.lock().unwrap();
+        let opened = opened.as_mut().ok_or_else(|| ConnectorError::new(&self.to_string(), "Not started"))?;
+        let now = SystemTime::now();
+        let error = |e: rusqlite::Error| ConnectorError::new(&self.to_string(), &e.to_string());
+        opened.store.record(&Envelope::new(identity, event), now).map_err(error)?;
+        opened.recorded += 1;
+        if opened.recorded >= PRUNE_EVERY {
+            opened.recorded = 0;
+            opened.store.prune(&opened.retention, now).map_err(error)?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,12 @@
 //! Interfaces and connectors to share events with third party applications.
+pub mod breaker;
 pub mod connector;
+pub mod http;
 
 // List of interfaces
-pub mod sitincloud;
\ No newline at end of file
+pub mod localstore;
+pub mod paging;
+#[cfg(windows)]
+pub mod sitincloud;
+pub mod slack;
+pub mod teams;
@@ -0,0 +1,467 @@
+//! Interface inherited from [Connector] for the on-call services: an incident is opened in
+//! PagerDuty (Events API v2) or Opsgenie for each incident of the agent ([crate::correlation]), whose
+//! id is the dedup key (the alias of Opsgenie): the PreAlert, the detection and the critical events
+//! of the gid update it. The events without an incident fall back to the [dedup_key] of the gid.
+//!
+//! The severity follows the escalation ladder ([crate::escalation]): a PreAlert is a warning, a
+//! suspended or reported gid an error, a kill critical. The incident is acknowledged, or resolved
+//! when the gid is cleared as a false positive, with the reviews of the alert (see
+//! [crate::status::AgentStatus::review]).
+
+use std::fs;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+
+use crate::config::{Config, Param};
+use crate::connectors::connector::{AlertReview, Connector, ConnectorError};
+use crate::connectors::http::post_json;
+use crate::escalation::Escalated;
+use crate::exfil::PreAlert;
+use crate::identity::AgentIdentity;
+use crate::killcheck::{Kill, KillOutcome};
+use crate::process::{ProcessRecord, ProcessState};
+use crate::ransomnote::RansomNoteDropped;
+use crate::rawdisk::RawDiskWrite;
+use crate::wiper::MassDeletion;
+
+/// Name of the file of the routing key (PagerDuty) or API key (Opsgenie), in *ConfigPath*.
+pub const KEY_FILE: &str = "paging_key";
+const PAGERDUTY_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+const OPSGENIE_URL: &str = "https://api.opsgenie.com/v2/alerts";
+const OPSGENIE_EU_URL: &str = "https://api.eu.opsgenie.com/v2/alerts";
+/// Of the message of an Opsgenie alert.
+const MAX_MESSAGE_LEN: usize = 130;
+/// Of the tags of an Opsgenie alert, ours included, and of each tag.
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    PagerDuty,
+    Opsgenie,
+    OpsgenieEu,
+}
+
+impl Service {
+    /// Of a value of *PAGING*, None for *NONE*.
+    pub fn from(paging: &str) -> Option<Service> {
+        match paging {
+            "PAGERDUTY" => Some(Service::PagerDuty),
+            "OPSGENIE" => Some(Service::Opsgenie),
+            "OPSGENIE_EU" => Some(Service::OpsgenieEu),
+            _ => None,
+        }
+    }
+
+    /// Where the pages are sent.
+    pub fn url(self) -> &'static str {
+        match self {
+            Service::PagerDuty => PAGERDUTY_URL,
+            Service::Opsgenie => OPSGENIE_URL,
+            Service::OpsgenieEu => OPSGENIE_EU_URL,
+        }
+    }
+}
+
+/// Of the escalation level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Critical,
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn pagerduty(self) -> &'static str {
+        match self {
+            Severity::Critical => "critical",
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn opsgenie(self) -> &'static str {
+        match self {
+            Severity::Critical => "P1",
+            Severity::Error => "P2",
+            Severity::Warning => "P3",
+        }
+    }
+}
+
+/// What is done with the incident of a gid.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Page {
+    Trigger {
+        gid: u64,
+        summary: String,
+        severity: Severity,
+        appname: String,
+        details: Value,
+        /// Of the [crate::enrichment] providers
+        tags: Vec<String>,
+        /// Id of the [crate::correlation] incident
+        incident: Option<String>,
+    },
+    Acknowledge {
+        gid: u64,
+        user: String,
+        incident: Option<String>,
+    },
+    /// Cleared as a false positive
+    Resolve {
+        gid: u64,
+        user: String,
+        incident: Option<String>,
+    },
+}
+
+/// Struct of the [Paging] interface.
+pub struct Paging {
+    /// With the key
+    service: Mutex<Option<(Service, String)>>,
+}
+
+impl Connector for Paging {
+    fn new() -> Paging {
+        Paging { service: Mutex::new(None) }
+    }
+
+    fn to_string(&self) -> String {
+        match self.service.lock().unwrap().as_ref() {
+            Some((Service::PagerDuty, _)) => String::from("PagerDuty"),
+            Some(_) => String::from("Opsgenie"),
+            None => String::from("Paging"),
+        }
+    }
+
+    fn on_startup(&self, config: &Config, _identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        let service = match Service::from(config.get_str(Param::Paging)) {
+            Some(service) => service,
+            None => return Err(ConnectorError::new(&self.to_string(), &format!("Unknown service {}", config.get_str(Param::Paging)))),
+        };
+        let path = config.get_path(Param::ConfigPath).join(KEY_FILE);
+        let key = fs::read_to_string(&path)
+            .map_err(|e| ConnectorError::new(&self.to_string(), &format!("Cannot read {}: {}", path.display(), e)))?;
+        *self.service.lock().unwrap() = Some((service, key.trim().to_string()));
+        Ok(())
+    }
+
+    fn send_event(&self, identity: &AgentIdentity, proc: &ProcessRecord, prediction: f32) -> Result<(), ConnectorError> {
+        let (severity, action) = if proc.would_kill {
+            (Severity::Error, "reported")
+        } else if proc.process_state == ProcessState::Suspended {
+            (Severity::Error, "suspended")
+        } else {
+            (Severity::Critical, "killed")
+        };
+        self.page(
+            identity,
+            &Page::Trigger {
+                gid: proc.gid,
+                summary: format!("Ransomware {} on {}: {}", action, identity.hostname, proc.appname),
+                severity,
+                appname: proc.appname.clone(),
+                details: json!({
+                    "score": prediction,
+                    "threshold": proc.threshold_prediction,
+                    "exepath": proc.exepath.to_string_lossy(),
+                    "user": proc.user(),
+                    "pids": proc.pids.len(),
+                    "files_updated": proc.fpaths_updated.len(),
+                }),
+                tags: Vec::new(),
+                incident: None,
+            },
+        )
+    }
+
+    fn send_kill(&self, identity: &AgentIdentity, kill: &Kill) -> Result<(), ConnectorError> {
+        let request = &kill.request;
+        let action = if kill.verification.outcome == KillOutcome::Failed { "survived the kill" } else { "killed" };
+        self.page(
+            identity,
+            &Page::Trigger {
+                gid: request.gid,
+                summary: format!("Ransomware {} on {}: {}", action, identity.hostname, request.appname),
+                severity: Severity::Critical,
+                appname: request.appname.clone(),
+                details: json!({
+                    "score": request.prediction,
+                    "exepath": request.exepath.to_string_lossy(),
+                    "pids": request.pids.len(),
+                    "kill": kill.verification.outcome.to_string(),
+                    "survivors": kill.verification.survivors,
+                }),
+                tags: request.tags.clone(),
+                incident: request.incident.as_ref().map(|incident| incident.id.clone()),
+            },
+        )
+    }
+
+    fn send_raw_disk_write(&self, identity: &AgentIdentity, event: &RawDiskWrite) -> Result<(), ConnectorError> {
+        self.page(
+            identity,
+            &Page::Trigger {
+                gid: event.gid,
+                summary: format!("Raw disk write on {}: {}", identity.hostname, event.device),
+                severity: Severity::Critical,
+                appname: String::new(),
+                details: json!({ "pid": event.pid, "device": event.device, "mount_point": event.mount_point }),
+                tags: Vec::new(),
+                incident: None,
+            },
+        )
+    }
+
+    fn send_mass_deletion(&self, identity: &AgentIdentity, event: &MassDeletion) -> Result<(), ConnectorError> {
+        self.page(
+            identity,
+            &Page::Trigger {
+                gid: event.gid,
+                summary: format!("Mass deletion of {} files on {}: {}", event.deleted, identity.hostname, event.appname),
+                severity: Severity::Critical,
+                appname: event.appname.clone(),
+                details: json!({ "pid": event.pid, "exepath": event.exepath.to_string_lossy(), "deleted": event.deleted }),
+                tags: event.tags.clone(),
+                incident: event.incident.as_ref().map(|incident| incident.id.clone()),
+            },
+        )
+    }
+
+    fn send_ransom_note(&self, identity: &AgentIdentity, event: &RansomNoteDropped) -> Result<(), ConnectorError> {
+        self.page(
+            identity,
+            &Page::Trigger {
+                gid: event.gid,
+                summary: format!("Ransom note dropped on {}: {}", identity.hostname, event.appname),
+                severity: Severity::Critical,
+                appname: event.appname.clone(),
+                details: json!({
+                    "pid": event.pid,
+                    "exepath": event.exepath.to_string_lossy(),
+                    "note": event.path.normalized,
+                    "dirs": event.note.dirs,
+                    "suspected_families": event.note.analysis.families(),
+                }),
+                tags: event.tags.clone(),
+                incident: event.incident.as_ref().map(|incident| incident.id.clone()),
+            },
+        )
+    }
+
+    fn send_pre_alert(&self, identity: &AgentIdentity, event: &PreAlert) -> Result<(), ConnectorError> {
+        self.page(
+            identity,
+            &Page::Trigger {
+                gid: event.gid,
+                summary: format!("Documents staged for exfiltration on {}: {}", identity.hostname, event.appname),
+                severity: Severity::Warning,
+                appname: event.appname.clone(),
+                details: json!({ "pid": event.pid, "exepath": event.exepath.to_string_lossy() }),
+                tags: event.tags.clone(),
+                incident: event.incident.as_ref().map(|incident| incident.id.clone()),
+            },
+        )
+    }
+
+    fn send_escalation(&self, identity: &AgentIdentity, event: &Escalated) -> Result<(), ConnectorError> {
+        self.page(
+            identity,
+            &Page::Trigger {
+                gid: event.gid,
+                summary: format!("PreAlert on {}: {}", identity.hostname, event.appname),
+                severity: Severity::Warning,
+                appname: event.appname.clone(),
+                details: json!({ "score": event.score, "threshold": event.threshold, "exepath": event.exepath.to_string_lossy() }),
+                tags: event.tags.clone(),
+                incident: event.incident.as_ref().map(|incident| incident.id.clone()),
+            },
+        )
+    }
+
+    fn send_review(&self, identity: &AgentIdentity, review: &AlertReview) -> Result<(), ConnectorError> {
+        let page = if review.false_positive {
+            Page::Resolve { gid: review.gid, user: review.user.clone(), incident: review.incident.clone() }
+        } else {
+            Page::Acknowledge { gid: review.gid, user: review.user.clone(), incident: review.incident.clone() }
+        };
+        self.page(identity, &page)
+    }
+
+    fn send_test(&self, identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        // Resolved at once, not to leave an incident open
+        self.page(
+            identity,
+            &Page::Trigger {
+                gid: 0,
+                summary: format!("Test of the Owlyshield alerts of {}", identity.hostname),
+                severity: Severity::Warning,
+                appname: String::from("owlyshield"),
+                details: json!({ "test": true }),
+                tags: Vec::new(),
+                incident: None,
+            },
+        )?;
+        self.page(identity, &Page::Resolve { gid: 0, user: String::from("owlyshield"), incident: None })
+    }
+}
+
+impl Paging {
+    fn page(&self, identity: &AgentIdentity, page: &Page) -> Result<(), ConnectorError> {
+        let service = self.service.lock().unwrap().clone();
+        let (service, key) = service.ok_or_else(|| ConnectorError::new(&self.to_string(), "Not started"))?;
+        let (url, headers, body) = request(service, &key, identity, page, SystemTime::now());
+        match post_json(&url, &headers, &body.to_string()) {
+            Ok((200..=299, _)) => Ok(()),
+            Ok((code, body)) => Err(ConnectorError::new(&self.to_string(), &format!("Answered {}: {}", code, body))),
+            Err(e) => Err(ConnectorError::new(&self.to_string(), &e.to_string())),
+        }
+    }
+}
+
+/// Of the incident of *gid* on the machine, if the event has no [crate::correlation] incident.
+pub fn dedup_key(identity: &AgentIdentity, gid: u64) -> String {
+    format!("owlyshield-{}-{}", identity.machine_id, gid)
+}
+
+/// The id of *incident*, or the [dedup_key] of *gid*.
+fn incident_key(identity: &AgentIdentity, gid: u64, incident: &Option<String>) -> String {
+    incident.clone().unwrap_or_else(|| dedup_key(identity, gid))
+}
+
+/// URL, headers and body of *page*.
+pub fn request(service: Service, key: &str, identity: &AgentIdentity, page: &Page, now: SystemTime) -> (String, Vec<String>, Value) {
+    if service == Service::PagerDuty {
+        let body = match page {
+            Page::Trigger { gid, summary, severity, appname, details, tags, incident } => {
+                let mut details = details.clone();
+                if !tags.is_empty() {
+                    details["tags"] = json!(tags);
+                }
+                json!({
+                    "routing_key": key,
+                    "event_action": "trigger",
+                    "dedup_key": incident_key(identity, *gid, incident),
+                    "payload": {
+                        "summary": summary,
+                        "source": identity.hostname,
+                        "severity": severity.pagerduty(),
+                        "timestamp": DateTime::<Utc>::from(now).to_rfc3339_opts(SecondsFormat::Secs, true),
+                        "component": appname,
+                        "group": identity.domain,
+                        "class": "ransomware",
+                        "custom_details": details,
+                    },
+                })
+            }
+            Page::Acknowledge { gid, incident, .. } => {
+                json!({ "routing_key": key, "event_action": "acknowledge", "dedup_key": incident_key(identity, *gid, incident) })
+            }
+            Page::Resolve { gid, incident, .. } => {
+                json!({ "routing_key": key, "event_action": "resolve", "dedup_key": incident_key(identity, *gid, incident) })
+            }
+        };
+        return (String::from(service.url()), Vec::new(), body);
+    }
+    let base = service.url();
+    let headers = vec![format!("Authorization: GenieKey {}", key)];
+    match page {
+        Page::Trigger { gid, summary, severity, appname, details, tags, incident } => {
+            let mut message: String = summary.chars().take(MAX_MESSAGE_LEN).collect();
+            if message.len() < summary.len() {
+                message.pop();
+                message.push('…');
+            }
+            let tags: Vec<String> = ["owlyshield", "ransomware"]
+                .iter()
+                .map(|tag| tag.to_string())
+                .chain(tags.iter().map(|tag| tag.chars().take(MAX_TAG_LEN).collect()))
+                .take(MAX_TAGS)
+                .collect();
+            let body = json!({
+                "message": message,
+                "alias": incident_key(identity, *gid, incident),
+                "description": summary,
+                "priority": severity.opsgenie(),
+                "source": "Owlyshield",
+                "entity": identity.hostname,
+                "tags": tags,
+                "details": { "machine_id": identity.machine_id, "gid": gid.to_string(), "appname": appname, "details": details.to_string() },
+            });
+            (String::from(base), headers, body)
+        }
+        Page::Acknowledge { gid, user, incident } | Page::Resolve { gid, user, incident } => {
+            let (action, note) = match page {
+                Page::Acknowledge { .. } => ("acknowledge", "Acknowledged"),
+                _ => ("close", "Cleared as a false positive"),
+            };
+            let url = format!("{}/{}/{}?identifierType=alias", base, incident_key(identity, *gid, incident), action);
+            (url, headers, json!({ "user": user, "source": "Owlyshield", "note": note }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::json;
+
+    use crate::connectors::paging::{request, Page, Service, Severity};
+    use crate::identity::AgentIdentity;
+
+    #[test]
+    fn pages_should_share_the_key_of_the_gid() {
+        let identity = AgentIdentity {
+            machine_id: String::from("6f1c"),
+            hostname: String::from("srv-files"),
+            os_version: String::from("Windows 10 Pro 19044"),
+            domain: Some(String::from("CORP")),
+            agent_version: String::from("1.2.0"),
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let triggered = |incident: Option<String>| Page::Trigger {
+            gid: 42,
+            summary: format!("Ransomware killed on srv-files: {}", "x".repeat(200)),
+            severity: Severity::Critical,
+            appname: String::from("locker.exe"),
+            details: json!({ "score": 0.5 }),
+            tags: vec![String::from("owner:finance")],
+            incident,
+        };
+        let trigger = triggered(None);
+        let resolve = Page::Resolve { gid: 42, user: String::from("alice"), incident: None };
+
+        let (url, headers, body) = request(Service::PagerDuty, "R0UT1NG", &identity, &trigger, now);
+        assert_eq!(url, "https://events.pagerduty.com/v2/enqueue");
+        assert!(headers.is_empty());
+        assert_eq!(body["dedup_key"], "owlyshield-6f1c-42");
+        assert_eq!(body["payload"]["severity"], "critical");
+        assert_eq!(body["payload"]["custom_details"]["tags"], json!(["owner:finance"]));
+        assert_eq!(body["payload"]["timestamp"], "2023-11-14T22:13:20Z");
+        let (_, _, body) = request(Service::PagerDuty, "R0UT1NG", &identity, &resolve, now);
+        assert_eq!(body, json!({ "routing_key": "R0UT1NG", "event_action": "resolve", "dedup_key": "owlyshield-6f1c-42" }));
+
+        let (url, headers, body) = request(Service::OpsgenieEu, "K3Y", &identity, &trigger, now);
+        assert_eq!(url, "https://api.eu.opsgenie.com/v2/alerts");
+        assert_eq!(headers, vec![String::from("Authorization: GenieKey K3Y")]);
+        assert_eq!(body["alias"], "owlyshield-6f1c-42");
+        assert_eq!(body["priority"], "P1");
+        assert_eq!(body["tags"], json!(["owlyshield", "ransomware", "owner:finance"]));
+        assert_eq!(body["message"].as_str().unwrap().chars().count(), 130);
+        let (url, _, body) = request(Service::Opsgenie, "K3Y", &identity, &resolve, now);
+        assert_eq!(url, "https://api.opsgenie.com/v2/alerts/owlyshield-6f1c-42/close?identifierType=alias");
+        assert_eq!(body["user"], "alice");
+
+        let incident = Some(String::from("owlyshield-6f1c-42-a1b2c3d4e5f6"));
+        let (_, _, body) = request(Service::PagerDuty, "R0UT1NG", &identity, &triggered(incident.clone()), now);
+        assert_eq!(body["dedup_key"], "owlyshield-6f1c-42-a1b2c3d4e5f6");
+        let acknowledge = Page::Acknowledge { gid: 42, user: String::from("alice"), incident };
+        let (url, _, _) = request(Service::Opsgenie, "K3Y", &identity, &acknowledge, now);
+        assert_eq!(url, "https://api.opsgenie.com/v2/alerts/owlyshield-6f1c-42-a1b2c3d4e5f6/acknowledge?identifierType=alias");
+    }
+}
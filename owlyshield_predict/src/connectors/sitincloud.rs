@@ -8,9 +8,12 @@ use serde::Serialize;
 use std::io::Read;
 use curl::Error;
 use registry::{Hive, RegKey, Security};
+use tracing::debug;
 use crate::config::{Config, Param};
+use crate::identity::AgentIdentity;
 
 use crate::connectors::connector::{Connector, ConnectorError};
+use crate::secrets::get_secret;
 use crate::process::{FileId, ProcessRecord};
 
 /// Struct of the [SitinCloud] interface.
@@ -24,26 +27,31 @@ impl SitinCloud {
     /// Returns the host for the `[SitinCloud] interface.
     /// The value is stored in the registry of the local machine.
     fn get_host() -> String {
-        let regkey = Hive::LocalMachine.open(r"SOFTWARE\Owlyshield\SitinCloud", Security::Read).expect("Cannot open registry hive");
-        return regkey.value("API_HOST").expect(&format!("Cannot open registry key HOST")).to_string();
+        SitinCloud::get_credential("API_HOST")
     }
     /// Returns the client id for the [SitinCloud] interface.
     /// The value is stored in the registry of the local machine.
     fn get_client() -> String {
-        let regkey = Hive::LocalMachine.open(r"SOFTWARE\Owlyshield\SitinCloud", Security::Read).expect("Cannot open registry hive");
-        return regkey.value("CLIENT_ID").expect(&format!("Cannot open registry key CLIENT ID")).to_string();
+        SitinCloud::get_credential("CLIENT_ID")
     }
     /// Returns the license key for the [SitinCloud] interface.
-    /// The value is stored in the registry of the local machine.
+    /// The value is stored encrypted (see [crate::secrets]), or in the registry of the local machine.
     fn get_license_key() -> String {
-        let regkey = Hive::LocalMachine.open(r"SOFTWARE\Owlyshield\SitinCloud", Security::Read).expect("Cannot open registry hive");
-        return regkey.value("LICENSE_KEY").expect(&format!("Cannot open registry key CLIENT ID")).to_string();
+        SitinCloud::get_credential("LICENSE_KEY")
     }
     /// Returns the API key for the [SitinCloud] interface.
-    /// The value is stored in the registry of the local machine.
+    /// The value is stored encrypted (see [crate::secrets]), or in the registry of the local machine.
     fn get_api_key() -> String {
+        SitinCloud::get_credential("API_KEY")
+    }
+    /// Looks for the encrypted secret ```SitinCloud.<key>``` first, then falls back to the plaintext
+    /// registry value written by older installers.
+    fn get_credential(key: &str) -> String {
+        if let Ok(secret) = get_secret(&format!("{}.{}", SitinCloud::get_name(), key)) {
+            return secret;
+        }
         let regkey = Hive::LocalMachine.open(r"SOFTWARE\Owlyshield\SitinCloud", Security::Read).expect("Cannot open registry hive");
-        return regkey.value("API_KEY").expect(&format!("Cannot open registry key CLIENT ID")).to_string();
+        return regkey.value(key).expect(&format!("Cannot open registry key {}", key)).to_string();
     }
 }
 
@@ -77,11 +85,18 @@ struct SecurityEvent {
     sumWeightReadEntropy: f64,
     sumWeightWriteEntropy: f64,
     filesExtensionChangedCount: usize,
+    userSid: Option<String>,
+    userName: Option<String>,
+    sessionId: Option<u32>,
+    machineId: String,
+    osVersion: String,
+    domain: Option<String>,
+    agentVersion: String,
 }
 
 impl SecurityEvent {
     /// Creates [SecurityEvent] from [ProcessRecord] and prediction.
-    fn from(proc: &ProcessRecord, prediction: f32) -> SecurityEvent {
+    fn from(identity: &AgentIdentity, proc: &ProcessRecord, prediction: f32) -> SecurityEvent {
         let start: DateTime<Utc> = proc.time_started.into();
         let kill: DateTime<Utc> = proc.time_killed.unwrap().into();
         let now: DateTime<Utc> = SystemTime::now().into();
@@ -89,26 +104,33 @@ impl SecurityEvent {
         return SecurityEvent {
             appName: proc.appname.clone(),
             clientId: SitinCloud::get_client(),
-            hostname: hostname::get().unwrap().to_str().unwrap_or("Unknown host").to_string(),
+            hostname: identity.hostname.clone(),
             killTime: kill.to_rfc3339_opts(SecondsFormat::Micros, true),
             clientKey: SitinCloud::get_client(),
             pidsCount: proc.pids.len(),
             predScore: prediction,
             startTime: start.to_rfc3339_opts(SecondsFormat::Micros, true),
-            filesChanged: proc.fpaths_updated.clone(),
-            filesCreated: proc.fpaths_created.clone(),
+            filesChanged: proc.fpaths_updated.iter().map(|p| p.to_string()).collect(),
+            filesCreated: proc.fpaths_created.iter().map(|p| p.to_string()).collect(),
             filesMovedCount: proc.files_read.len(), // Files moved ?
             filesChangedCount: proc.files_written.len(),
             filesCreatedCount: proc.files_opened.len(),
             filesDeletedCount: proc.files_deleted.len(),
             filesRenamedCount: proc.files_renamed.len(),
             secondsSinceLaunch: (kill-start).num_seconds()*10,
-            dirWithFilesChanged: proc.dirs_with_files_updated.clone(),
-            dirWithFilesCreated: proc.dirs_with_files_created.clone(),
+            dirWithFilesChanged: proc.dirs_with_files_updated.iter().map(|p| p.to_string()).collect(),
+            dirWithFilesCreated: proc.dirs_with_files_created.iter().map(|p| p.to_string()).collect(),
             extensionsWriteCount: proc.extensions_written.count_all(), // doublon
             sumWeightReadEntropy: proc.entropy_read,
             sumWeightWriteEntropy: proc.entropy_written,
             filesExtensionChangedCount: proc.extensions_read.count_all(), // doublon
+            userSid: proc.owner.as_ref().map(|o| o.sid.clone()),
+            userName: proc.owner.as_ref().and_then(|o| o.username.clone()),
+            sessionId: proc.owner.as_ref().and_then(|o| o.session_id),
+            machineId: identity.machine_id.clone(),
+            osVersion: identity.os_version.clone(),
+            domain: identity.domain.clone(),
+            agentVersion: identity.agent_version.clone(),
         }
     }
 
@@ -126,16 +148,22 @@ struct PingData {
     numVersion : String,
     licenseKey: String,
     killPolicy: String,
+    machineId: String,
+    osVersion: String,
+    domain: Option<String>,
 }
 
 impl PingData {
-    fn from(config: &Config) -> PingData {
+    fn from(config: &Config, identity: &AgentIdentity) -> PingData {
        return PingData {
            clientId: SitinCloud::get_client(),
-           hostname: hostname::get().unwrap().to_str().unwrap_or("Unknown host").to_string(),
-           numVersion : config[Param::NumVersion].clone(),
+           hostname: identity.hostname.clone(),
+           numVersion : identity.agent_version.clone(),
            licenseKey: SitinCloud::get_license_key(),
-           killPolicy: config[Param::KillPolicy].clone(),
+           killPolicy: config.get_str(Param::KillPolicy).to_string(),
+           machineId: identity.machine_id.clone(),
+           osVersion: identity.os_version.clone(),
+           domain: identity.domain.clone(),
        }
     }
 
@@ -154,12 +182,12 @@ impl Connector for SitinCloud {
         return SitinCloud::get_name();
     }
 
-    fn on_startup(&self, config: &Config) -> Result<(), ConnectorError> {
+    fn on_startup(&self, config: &Config, identity: &AgentIdentity) -> Result<(), ConnectorError> {
         let host = "";
         let error = ConnectorError::new(SitinCloud::get_name().as_str(), "Connector error");
 
-        let event = PingData::from(config).to_json();
-        eprintln!("event = {:?}", event);
+        let event = PingData::from(config, identity).to_json();
+        debug!(%event, "Ping");
         let mut data = event.as_bytes();
         let mut easy = Easy::new();
         let mut api_url = SitinCloud::get_host();
@@ -179,11 +207,11 @@ impl Connector for SitinCloud {
         }
     }
 
-    fn send_event(&self, proc: &ProcessRecord, prediction: f32) -> Result<(), ConnectorError> {
+    fn send_event(&self, identity: &AgentIdentity, proc: &ProcessRecord, prediction: f32) -> Result<(), ConnectorError> {
         let host = "";
         let error = ConnectorError::new(SitinCloud::get_name().as_str(), "Connector error");
 
-        let event = SecurityEvent::from(proc, prediction).to_json();
+        let event = SecurityEvent::from(identity, proc, prediction).to_json();
         let mut data = event.as_bytes();
         let mut easy = Easy::new();
         let mut api_url = SitinCloud::get_host();
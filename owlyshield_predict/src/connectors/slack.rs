@@ -0,0 +1,488 @@
+//! Interface inherited from [Connector] for Slack: the kills, PreAlerts and mass deletions are
+//! posted to an incoming webhook as Block Kit messages, with the score, the executable and the
+//! machine, and buttons to acknowledge them or to mark them as false positives. The repeated alerts
+//! of an incident are not posted again ([crate::correlation]).
+//!
+//! Slack sends the clicks to the request URL of the interactivity of the Slack app, which must
+//! forward them to *POST /slack/interactions* of the [crate::api] (a reverse proxy or a tunnel to
+//! the loopback). They are authenticated by their signature ([verify]) instead of the token of the
+//! API, and the messages are then updated with the review.
+
+use std::fs;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+
+use crate::config::{Config, Param};
+use crate::connectors::connector::{Connector, ConnectorError};
+use crate::connectors::http::post_json;
+use crate::correlation::{is_repeated, IncidentRef};
+use crate::escalation::Escalated;
+use crate::identity::AgentIdentity;
+use crate::killcheck::{Kill, KillOutcome};
+use crate::process::ProcessRecord;
+use crate::ransomnote::RansomNoteDropped;
+use crate::schema::Detection;
+use crate::utils::constant_time_eq;
+use crate::wiper::MassDeletion;
+
+/// Name of the file of the incoming webhook URL, in *ConfigPath*.
+pub const WEBHOOK_FILE: &str = "slack_webhook";
+/// Name of the file of the signing secret of the Slack app, in *ConfigPath*.
+pub const SIGNING_SECRET_FILE: &str = "slack_signing_secret";
+/// Path of the API the clicks are forwarded to.
+pub const INTERACTIONS_PATH: &str = "/slack/interactions";
+/// Older requests are rejected, against replays.
+const MAX_REQUEST_AGE: Duration = Duration::from_secs(300);
+/// Only Slack is answered, whatever the payload says.
+const RESPONSE_URL_PREFIX: &str = "https://hooks.slack.com/";
+const BLOCK_ID: &str = "owlyshield_review";
+/// Of a header block.
+const MAX_TITLE_LEN: usize = 150;
+/// Of a field of a section block (3000 for the whole text).
+const MAX_FIELD_LEN: usize = 1900;
+
+/// Struct of the [Slack] interface.
+pub struct Slack {
+    webhook: Mutex<Option<String>>,
+}
+
+impl Connector for Slack {
+    fn new() -> Slack {
+        Slack { webhook: Mutex::new(None) }
+    }
+
+    fn to_string(&self) -> String {
+        String::from("Slack")
+    }
+
+    fn on_startup(&self, config: &Config, _identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        let path = config.get_path(Param::ConfigPath).join(WEBHOOK_FILE);
+        let url = fs::read_to_string(&path)
+            .map_err(|e| ConnectorError::new(&self.to_string(), &format!("Cannot read {}: {}", path.display(), e)))?;
+        *self.webhook.lock().unwrap() = Some(url.trim().to_string());
+        Ok(())
+    }
+
+    fn send_event(&self, identity: &AgentIdentity, proc: &ProcessRecord, prediction: f32) -> Result<(), ConnectorError> {
+        let detection = Detection::from(proc, prediction);
+        self.post(&message(
+            identity,
+            &Notice {
+                title: format!("Ransomware {} on {}", detection.action, identity.hostname),
+                gid: detection.family.gid,
+                exepath: detection.family.exepath,
+                score: Some(prediction),
+                tags: Vec::new(),
+                incident: None,
+            },
+        ))
+    }
+
+    fn send_kill(&self, identity: &AgentIdentity, kill: &Kill) -> Result<(), ConnectorError> {
+        if is_repeated(kill.request.incident.as_ref()) {
+            return Ok(());
+        }
+        let title = if kill.verification.outcome == KillOutcome::Failed {
+            format!("Ransomware survived the kill on {}", identity.hostname)
+        } else {
+            format!("Ransomware killed on {}", identity.hostname)
+        };
+        self.post(&message(
+            identity,
+            &Notice {
+                title,
+                gid: kill.request.gid,
+                exepath: kill.request.exepath.to_string_lossy().to_string(),
+                score: Some(kill.request.prediction),
+                tags: kill.request.tags.clone(),
+                incident: kill.request.incident.clone(),
+            },
+        ))
+    }
+
+    fn send_mass_deletion(&self, identity: &AgentIdentity, event: &MassDeletion) -> Result<(), ConnectorError> {
+        if is_repeated(event.incident.as_ref()) {
+            return Ok(());
+        }
+        self.post(&message(
+            identity,
+            &Notice {
+                title: format!("Mass deletion of {} files on {}", event.deleted, identity.hostname),
+                gid: event.gid,
+                exepath: event.exepath.to_string_lossy().to_string(),
+                score: None,
+                tags: event.tags.clone(),
+                incident: event.incident.clone(),
+            },
+        ))
+    }
+
+    fn send_ransom_note(&self, identity: &AgentIdentity, event: &RansomNoteDropped) -> Result<(), ConnectorError> {
+        if is_repeated(event.incident.as_ref()) {
+            return Ok(());
+        }
+        self.post(&message(
+            identity,
+            &Notice {
+                title: format!("Ransom note dropped in {} directories on {}", event.note.dirs, identity.hostname),
+                gid: event.gid,
+                exepath: event.exepath.to_string_lossy().to_string(),
+                score: None,
+                tags: event.tags.clone(),
+                incident: event.incident.clone(),
+            },
+        ))
+    }
+
+    fn send_escalation(&self, identity: &AgentIdentity, event: &Escalated) -> Result<(), ConnectorError> {
+        if is_repeated(event.incident.as_ref()) {
+            return Ok(());
+        }
+        self.post(&message(
+            identity,
+            &Notice {
+                title: format!("PreAlert on {}", identity.hostname),
+                gid: event.gid,
+                exepath: event.exepath.to_string_lossy().to_string(),
+                score: Some(event.score),
+                tags: event.tags.clone(),
+                incident: event.incident.clone(),
+            },
+        ))
+    }
+
+    fn send_test(&self, identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        self.post(&json!({ "text": format!("Test of the Owlyshield alerts of {}", identity.machine()) }))
+    }
+}
+
+impl Slack {
+    fn post(&self, message: &Value) -> Result<(), ConnectorError> {
+        let url = self.webhook.lock().unwrap().clone();
+        let url = url.ok_or_else(|| ConnectorError::new(&self.to_string(), "Not started"))?;
+        match post_json(&url, &[], &message.to_string()) {
+            Ok((200..=299, _)) => Ok(()),
+            Ok((code, body)) => Err(ConnectorError::new(&self.to_string(), &format!("Slack answered {}: {}", code, body))),
+            Err(e) => Err(ConnectorError::new(&self.to_string(), &e.to_string())),
+        }
+    }
+}
+
+/// What a message tells, of a process family.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notice {
+    pub title: String,
+    pub gid: u64,
+    pub exepath: String,
+    pub score: Option<f32>,
+    /// Of the [crate::enrichment] providers
+    pub tags: Vec<String>,
+    /// Of the [crate::correlation]
+    pub incident: Option<IncidentRef>,
+}
+
+/// The Block Kit message of *notice*, with the review buttons.
+pub fn message(identity: &AgentIdentity, notice: &Notice) -> Value {
+    let value = format!("{}/{}", identity.machine_id, notice.gid);
+    let mut fields = vec![
+        field("Machine", &format!("{} ({})", identity.hostname, identity.machine_id)),
+        field("Gid", &notice.gid.to_string()),
+        field("Executable", &format!("`{}`", notice.exepath)),
+    ];
+    if let Some(score) = notice.score {
+        fields.insert(0, field("Score", &format!("{:.2}", score)));
+    }
+    if !notice.tags.is_empty() {
+        fields.push(field("Tags", &notice.tags.join(", ")));
+    }
+    if let Some(incident) = &notice.incident {
+        fields.push(field("Incident", &incident.to_string()));
+    }
+    json!({
+        "text": format!("{}: {}", notice.title, notice.exepath),
+        "blocks": [
+            {
+                "type": "header",
+                "text": { "type": "plain_text", "text": truncate(&notice.title, MAX_TITLE_LEN) },
+            },
+            { "type": "section", "fields": fields },
+            {
+                "type": "actions",
+                "block_id": BLOCK_ID,
+                "elements": [
+                    {
+                        "type": "button",
+                        "action_id": "acknowledge",
+                        "text": { "type": "plain_text", "text": "Acknowledge" },
+                        "style": "primary",
+                        "value": value,
+                    },
+                    {
+                        "type": "button",
+                        "action_id": "false_positive",
+                        "text": { "type": "plain_text", "text": "Mark false positive" },
+                        "style": "danger",
+                        "value": value,
+                        "confirm": {
+                            "title": { "type": "plain_text", "text": "Mark as false positive?" },
+                            "text": { "type": "mrkdwn", "text": "The executable will never be killed on this machine again." },
+                            "confirm": { "type": "plain_text", "text": "Mark" },
+                            "deny": { "type": "plain_text", "text": "Cancel" },
+                        },
+                    },
+                ],
+            },
+        ],
+    })
+}
+
+fn field(name: &str, value: &str) -> Value {
+    json!({ "type": "mrkdwn", "text": format!("*{}*\n{}", name, escape(&truncate(value, MAX_FIELD_LEN))) })
+}
+
+/// The control characters of mrkdwn.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        text.to_string()
+    } else {
+        text.chars().take(max - 1).chain(std::iter::once('…')).collect()
+    }
+}
+
+/// What the button clicked asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Review {
+    Acknowledge,
+    FalsePositive,
+}
+
+/// A click on a button of a [message].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interaction {
+    pub review: Review,
+    pub machine_id: String,
+    pub gid: u64,
+    /// Slack user name
+    pub user: String,
+    /// Slack user id, for the audit trail
+    pub user_id: String,
+    /// Where the message is updated
+    pub response_url: Option<String>,
+    /// Of the message clicked
+    pub blocks: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    user: PayloadUser,
+    #[serde(default)]
+    actions: Vec<PayloadAction>,
+    response_url: Option<String>,
+    message: Option<PayloadMessage>,
+}
+
+#[derive(Deserialize)]
+struct PayloadUser {
+    id: String,
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PayloadAction {
+    action_id: String,
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PayloadMessage {
+    #[serde(default)]
+    blocks: Vec<Value>,
+}
+
+/// Checks the *X-Slack-Signature* of *body*, an HMAC-SHA256 of *v0:{timestamp}:{body}* with the
+/// signing secret, sent less than [MAX_REQUEST_AGE] ago.
+pub fn verify(secret: &str, timestamp: &str, signature: &str, body: &str, now: SystemTime) -> bool {
+    let sent = match timestamp.parse::<u64>() {
+        Ok(sent) => UNIX_EPOCH + Duration::from_secs(sent),
+        Err(_) => return false,
+    };
+    let age = now.duration_since(sent).unwrap_or_else(|e| e.duration());
+    if age > MAX_REQUEST_AGE {
+        return false;
+    }
+    let mac = hmac_sha256(secret.as_bytes(), format!("v0:{}:{}", timestamp, body).as_bytes());
+    let expected = format!("v0={}", mac.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    constant_time_eq(expected.as_bytes(), signature.trim().as_bytes())
+}
+
+/// RFC 2104, on SHA-256.
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK_LEN: usize = 64;
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = Sha256::new().chain(pad(0x36)).chain(data).finalize();
+    let outer = Sha256::new().chain(pad(0x5c)).chain(inner).finalize();
+    outer.into()
+}
+
+/// The [Interaction] of the form *body* posted by Slack (its *payload* field).
+pub fn parse_interaction(body: &str) -> Result<Interaction, String> {
+    let payload = body
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "payload")
+        .map(|(_, value)| form_decode(value))
+        .ok_or("No payload")?;
+    let payload: Payload = serde_json::from_str(&payload).map_err(|e| format!("Invalid payload: {}", e))?;
+    let action = payload.actions.first().ok_or("No action")?;
+    let review = match action.action_id.as_str() {
+        "acknowledge" => Review::Acknowledge,
+        "false_positive" => Review::FalsePositive,
+        other => return Err(format!("Unknown action {}", other)),
+    };
+    let (machine_id, gid) = action
+        .value
+        .as_deref()
+        .and_then(|value| value.rsplit_once('/'))
+        .and_then(|(machine_id, gid)| Some((machine_id.to_string(), gid.parse().ok()?)))
+        .ok_or("Invalid action value")?;
+    Ok(Interaction {
+        review,
+        machine_id,
+        gid,
+        user: payload.user.username.unwrap_or_else(|| payload.user.id.clone()),
+        user_id: payload.user.id,
+        response_url: payload.response_url,
+        blocks: payload.message.map(|m| m.blocks).unwrap_or_default(),
+    })
+}
+
+/// *application/x-www-form-urlencoded* value.
+fn form_decode(value: &str) -> String {
+    let hex = |b: Option<&u8>| b.and_then(|b| (*b as char).to_digit(16)).map(|d| d as u8);
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], hex(bytes.get(i + 1)), hex(bytes.get(i + 2))) {
+            (b'+', _, _) => decoded.push(b' '),
+            (b'%', Some(high), Some(low)) => {
+                decoded.push(high << 4 | low);
+                i += 2;
+            }
+            (b, _, _) => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// The message of *blocks* without its buttons, with *note* at the end.
+pub fn reviewed(blocks: &[Value], note: &str) -> Value {
+    let mut blocks: Vec<Value> = blocks.iter().filter(|b| b["type"] != "actions").cloned().collect();
+    blocks.push(json!({ "type": "context", "elements": [{ "type": "mrkdwn", "text": escape(note) }] }));
+    json!({ "replace_original": true, "text": note, "blocks": blocks })
+}
+
+/// Updates the message of *interaction* with *note*, in the background: Slack waits 3 seconds at
+/// most for the answer to the click.
+pub fn respond(interaction: &Interaction, note: &str) {
+    let url = match &interaction.response_url {
+        Some(url) if url.starts_with(RESPONSE_URL_PREFIX) => url.clone(),
+        Some(url) => {
+            warn!(%url, "Slack response URL ignored");
+            return;
+        }
+        None => return,
+    };
+    let message = reviewed(&interaction.blocks, note).to_string();
+    thread::spawn(move || match post_json(&url, &[], &message) {
+        Ok((200..=299, _)) => {}
+        Ok((code, body)) => error!("Slack answered {}: {}", code, body),
+        Err(e) => error!("Cannot update the Slack message: {}", e),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::connectors::slack::{form_decode, hmac_sha256, message, parse_interaction, verify, Notice, Review};
+    use crate::identity::AgentIdentity;
+
+    #[test]
+    fn interactions_should_be_verified_and_parsed() {
+        let mac: String = hmac_sha256(b"Jefe", b"what do ya want for nothing?").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(mac, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+        let identity = AgentIdentity {
+            machine_id: String::from("6f1c"),
+            hostname: String::from("srv-files"),
+            os_version: String::from("Windows 10 Pro 19044"),
+            domain: None,
+            agent_version: String::from("1.2.0"),
+        };
+        let notice = Notice {
+            title: String::from("Ransomware killed on srv-files"),
+            gid: 42,
+            exepath: String::from(r"C:\Users\bob\a<b>.exe"),
+            score: Some(0.97),
+            tags: vec![String::from("owner:finance")],
+            incident: None,
+        };
+        let message = message(&identity, &notice);
+        assert_eq!(message["blocks"][1]["fields"][0]["text"], "*Score*\n0.97");
+        assert_eq!(message["blocks"][1]["fields"][3]["text"], "*Executable*\n`C:\\Users\\bob\\a&lt;b&gt;.exe`");
+        assert_eq!(message["blocks"][1]["fields"][4]["text"], "*Tags*\nowner:finance");
+        let buttons = &message["blocks"][2]["elements"];
+        assert_eq!(buttons[1]["action_id"], "false_positive");
+
+        let payload = serde_json::json!({
+            "type": "block_actions",
+            "user": { "id": "U1", "username": "alice" },
+            "actions": [{ "action_id": "false_positive", "value": buttons[1]["value"] }],
+            "response_url": "https://hooks.slack.com/actions/T1/1/x",
+            "message": { "blocks": message["blocks"] },
+        });
+        let encoded: String = payload
+            .to_string()
+            .bytes()
+            .map(|b| match b {
+                b' ' => String::from("+"),
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (b as char).to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect();
+        let body = format!("payload={}", encoded);
+        assert_eq!(form_decode("a+b%2Fc%zz%4"), "a b/c%zz%4");
+        let interaction = parse_interaction(&body).unwrap();
+        assert_eq!(interaction.review, Review::FalsePositive);
+        assert_eq!((interaction.machine_id.as_str(), interaction.gid, interaction.user.as_str()), ("6f1c", 42, "alice"));
+        assert_eq!(interaction.user_id, "U1");
+        assert_eq!(interaction.blocks.len(), 3);
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mac: String = hmac_sha256(b"secret", format!("v0:1700000000:{}", body).as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+        let signature = format!("v0={}", mac);
+        assert!(verify("secret", "1700000000", &signature, &body, now));
+        assert!(!verify("other", "1700000000", &signature, &body, now));
+        assert!(!verify("secret", "1700000000", &signature, &body[1..], now));
+        assert!(!verify("secret", "1700000000", &signature, &body, now + Duration::from_secs(600)));
+        assert!(!verify("secret", "soon", &signature, &body, SystemTime::now()));
+    }
+}
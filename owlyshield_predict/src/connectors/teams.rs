@@ -0,0 +1,498 @@
+//! Interface inherited from [Connector] for Microsoft Teams: the kills, PreAlerts, persistences
+//! ([crate::autostart]) and critical events are posted to an incoming webhook (or a workflow) as
+//! Adaptive Cards, colored by severity, with a link to the HTML incident report if *REPORT_URL* is
+//! set. The repeated alerts of an incident are not posted again ([crate::correlation]).
+//!
+//! Teams throttles a webhook above a few requests per second. The events are queued and posted by
+//! a background thread, which gathers those of a [BATCH_WINDOW] into one card, waits
+//! [MIN_INTERVAL] between two posts, and retries the throttled ones.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tracing::{error, warn};
+
+use crate::autostart::{AutostartKind, AutostartSource, Persistence};
+use crate::config::{Config, Param};
+use crate::connectors::connector::{Connector, ConnectorError};
+use crate::connectors::http::{encode, post_json};
+use crate::correlation::{is_repeated, IncidentRef};
+use crate::escalation::Escalated;
+use crate::exfil::PreAlert;
+use crate::identity::AgentIdentity;
+use crate::killcheck::{Kill, KillOutcome};
+use crate::process::{ProcessRecord, ProcessState};
+use crate::ransomnote::RansomNoteDropped;
+use crate::rawdisk::RawDiskWrite;
+use crate::watchdog::{Incident, IncidentKind};
+use crate::wiper::MassDeletion;
+
+/// Name of the file of the webhook URL, in *ConfigPath*.
+pub const WEBHOOK_FILE: &str = "teams_webhook";
+/// Events gathered into one card, after the first one.
+const BATCH_WINDOW: Duration = Duration::from_secs(2);
+/// Events of a card, at most (the size of a card is limited to 28 KB).
+const MAX_BATCH: usize = 10;
+/// Between two posts.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Before each retry of a throttled or failed post.
+const RETRY_DELAYS: [Duration; 3] = [Duration::from_secs(5), Duration::from_secs(30), Duration::from_secs(120)];
+/// Events waiting to be posted, beyond which they are rejected.
+const QUEUE_LEN: usize = 256;
+/// Of the executables in the facts.
+const MAX_VALUE_LEN: usize = 512;
+
+/// Color of the card of an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// A process killed, or writing the raw disk...
+    Critical,
+    /// A PreAlert, a process suspended or reported only...
+    Warning,
+}
+
+impl Severity {
+    /// Style of the container, color of the text.
+    fn style(self) -> (&'static str, &'static str) {
+        match self {
+            Severity::Critical => ("attention", "Attention"),
+            Severity::Warning => ("warning", "Warning"),
+        }
+    }
+}
+
+/// An event, as a part of a card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub severity: Severity,
+    pub title: String,
+    pub facts: Vec<(&'static str, String)>,
+    /// URL of the HTML report
+    pub report: Option<String>,
+}
+
+/// Struct of the [Teams] interface.
+pub struct Teams {
+    sender: Mutex<Option<SyncSender<Item>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    /// Of the test event, posted without the queue
+    webhook: Mutex<Option<String>>,
+    /// *ConfigPath\threats* and *REPORT_URL*
+    reports: Mutex<Option<(PathBuf, String)>>,
+}
+
+impl Connector for Teams {
+    fn new() -> Teams {
+        Teams {
+            sender: Mutex::new(None),
+            worker: Mutex::new(None),
+            webhook: Mutex::new(None),
+            reports: Mutex::new(None),
+        }
+    }
+
+    fn to_string(&self) -> String {
+        String::from("Teams")
+    }
+
+    fn on_startup(&self, config: &Config, identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        let path = config.get_path(Param::ConfigPath).join(WEBHOOK_FILE);
+        let url = fs::read_to_string(&path)
+            .map_err(|e| ConnectorError::new(&self.to_string(), &format!("Cannot read {}: {}", path.display(), e)))?;
+        let report_url = config.get_str(Param::ReportUrl).trim().trim_end_matches('/').to_string();
+        if !report_url.is_empty() && !report_url.eq_ignore_ascii_case("NONE") {
+            *self.reports.lock().unwrap() = Some((config.get_path(Param::ConfigPath).join("threats"), report_url));
+        }
+        let (sender, receiver) = sync_channel(QUEUE_LEN);
+        let (url, hostname) = (url.trim().to_string(), identity.hostname.clone());
+        *self.webhook.lock().unwrap() = Some(url.clone());
+        let worker = thread::Builder::new()
+            .name(String::from("teams"))
+            .spawn(move || deliver(&url, &hostname, &receiver))
+            .map_err(|e| ConnectorError::new(&self.to_string(), &e.to_string()))?;
+        *self.sender.lock().unwrap() = Some(sender);
+        *self.worker.lock().unwrap() = Some(worker);
+        Ok(())
+    }
+
+    fn send_event(&self, identity: &AgentIdentity, proc: &ProcessRecord, prediction: f32) -> Result<(), ConnectorError> {
+        let (severity, action) = if proc.would_kill {
+            (Severity::Warning, "reported")
+        } else if proc.process_state == ProcessState::Suspended {
+            (Severity::Warning, "suspended")
+        } else {
+            (Severity::Critical, "killed")
+        };
+        let report = self.report(proc.gid);
+        self.queue(Item {
+            severity,
+            title: format!("Ransomware {} on {}", action, identity.hostname),
+            facts: vec![
+                ("Score", format!("{:.2}", prediction)),
+                ("Executable", proc.exepath.to_string_lossy().to_string()),
+                ("User", proc.user()),
+                ("Machine", identity.machine()),
+                ("Gid", proc.gid.to_string()),
+            ],
+            report,
+        })
+    }
+
+    fn send_kill(&self, identity: &AgentIdentity, kill: &Kill) -> Result<(), ConnectorError> {
+        if is_repeated(kill.request.incident.as_ref()) {
+            return Ok(());
+        }
+        let title = if kill.verification.outcome == KillOutcome::Failed {
+            format!("Ransomware survived the kill on {}", identity.hostname)
+        } else {
+            format!("Ransomware killed on {}", identity.hostname)
+        };
+        self.queue(Item {
+            severity: Severity::Critical,
+            title,
+            facts: tagged(
+                vec![
+                    ("Score", format!("{:.2}", kill.request.prediction)),
+                    ("Executable", kill.request.exepath.to_string_lossy().to_string()),
+                    ("Kill", kill.verification.outcome.to_string()),
+                    ("Machine", identity.machine()),
+                    ("Gid", kill.request.gid.to_string()),
+                ],
+                &kill.request.tags,
+                kill.request.incident.as_ref(),
+            ),
+            report: self.report(kill.request.gid),
+        })
+    }
+
+    fn on_shutdown(&self) -> Result<(), ConnectorError> {
+        // Flushes the queue
+        self.sender.lock().unwrap().take();
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            worker.join().map_err(|_| ConnectorError::new(&self.to_string(), "Delivery thread panicked"))?;
+        }
+        Ok(())
+    }
+
+    fn send_test(&self, identity: &AgentIdentity) -> Result<(), ConnectorError> {
+        let url = self.webhook.lock().unwrap().clone();
+        let url = url.ok_or_else(|| ConnectorError::new(&self.to_string(), "Not started"))?;
+        let item = Item {
+            severity: Severity::Warning,
+            title: format!("Test of the Owlyshield alerts of {}", identity.hostname),
+            facts: vec![("Machine", identity.machine())],
+            report: None,
+        };
+        match post_json(&url, &[], &card(&identity.hostname, &[item]).to_string()) {
+            Ok((200..=299, _)) => Ok(()),
+            Ok((code, body)) => Err(ConnectorError::new(&self.to_string(), &format!("Teams answered {}: {}", code, body))),
+            Err(e) => Err(ConnectorError::new(&self.to_string(), &e.to_string())),
+        }
+    }
+
+    fn send_incident(&self, identity: &AgentIdentity, incident: &Incident) -> Result<(), ConnectorError> {
+        self.queue(Item {
+            severity: if incident.kind == IncidentKind::TamperAttempt { Severity::Critical } else { Severity::Warning },
+            title: format!("Agent incident on {}", identity.hostname),
+            facts: vec![("Kind", format!("{:?}", incident.kind)), ("Message", incident.message.clone()), ("Machine", identity.machine())],
+            report: None,
+        })
+    }
+
+    fn send_raw_disk_write(&self, identity: &AgentIdentity, event: &RawDiskWrite) -> Result<(), ConnectorError> {
+        self.queue(Item {
+            severity: Severity::Critical,
+            title: format!("Raw disk write on {}", identity.hostname),
+            facts: vec![
+                ("Device", event.device.clone()),
+                ("Mount point", event.mount_point.clone().unwrap_or_default()),
+                ("Machine", identity.machine()),
+                ("Gid", event.gid.to_string()),
+            ],
+            report: None,
+        })
+    }
+
+    fn send_mass_deletion(&self, identity: &AgentIdentity, event: &MassDeletion) -> Result<(), ConnectorError> {
+        if is_repeated(event.incident.as_ref()) {
+            return Ok(());
+        }
+        self.queue(Item {
+            severity: Severity::Critical,
+            title: format!("Mass deletion of {} files on {}", event.deleted, identity.hostname),
+            facts: tagged(
+                vec![
+                    ("Executable", event.exepath.to_string_lossy().to_string()),
+                    ("Machine", identity.machine()),
+                    ("Gid", event.gid.to_string()),
+                ],
+                &event.tags,
+                event.incident.as_ref(),
+            ),
+            report: None,
+        })
+    }
+
+    fn send_ransom_note(&self, identity: &AgentIdentity, event: &RansomNoteDropped) -> Result<(), ConnectorError> {
+        if is_repeated(event.incident.as_ref()) {
+            return Ok(());
+        }
+        let mut facts = vec![
+            ("Executable", event.exepath.to_string_lossy().to_string()),
+            ("Machine", identity.machine()),
+            ("Gid", event.gid.to_string()),
+            ("Note", event.path.to_string()),
+        ];
+        let families = event.note.analysis.families();
+        if !families.is_empty() {
+            facts.push(("Suspected families", families.join(", ")));
+        }
+        self.queue(Item {
+            severity: Severity::Critical,
+            title: format!("Ransom note dropped in {} directories on {}", event.note.dirs, identity.hostname),
+            facts: tagged(facts, &event.tags, event.incident.as_ref()),
+            report: None,
+        })
+    }
+
+    fn send_pre_alert(&self, identity: &AgentIdentity, event: &PreAlert) -> Result<(), ConnectorError> {
+        if is_repeated(event.incident.as_ref()) {
+            return Ok(());
+        }
+        self.queue(Item {
+            severity: Severity::Warning,
+            title: format!("Documents staged for exfiltration on {}", identity.hostname),
+            facts: tagged(
+                vec![
+                    ("Executable", event.exepath.to_string_lossy().to_string()),
+                    ("Machine", identity.machine()),
+                    ("Gid", event.gid.to_string()),
+                ],
+                &event.tags,
+                event.incident.as_ref(),
+            ),
+            report: None,
+        })
+    }
+
+    fn send_persistence(&self, identity: &AgentIdentity, event: &Persistence) -> Result<(), ConnectorError> {
+        if is_repeated(event.incident.as_ref()) {
+            return Ok(());
+        }
+        self.queue(Item {
+            severity: Severity::Warning,
+            title: match event.entry.kind {
+                AutostartKind::ScheduledTask => format!("Scheduled task created on {}", identity.hostname),
+                AutostartKind::Service => format!("Service created on {}", identity.hostname),
+            },
+            facts: tagged(
+                vec![
+                    ("Executable", event.exepath.to_string_lossy().to_string()),
+                    (match event.entry.source {
+                        AutostartSource::TaskFile => "Task file",
+                        AutostartSource::CommandLine => "Command line",
+                        AutostartSource::Registry => "Registry key",
+                    }, truncate(&event.entry.target)),
+                    ("Machine", identity.machine()),
+                    ("Gid", event.gid.to_string()),
+                ],
+                &event.tags,
+                event.incident.as_ref(),
+            ),
+            report: None,
+        })
+    }
+
+    fn send_escalation(&self, identity: &AgentIdentity, event: &Escalated) -> Result<(), ConnectorError> {
+        if is_repeated(event.incident.as_ref()) {
+            return Ok(());
+        }
+        self.queue(Item {
+            severity: Severity::Warning,
+            title: format!("PreAlert on {}", identity.hostname),
+            facts: tagged(
+                vec![
+                    ("Score", format!("{:.2}", event.score)),
+                    ("Executable", event.exepath.to_string_lossy().to_string()),
+                    ("Machine", identity.machine()),
+                    ("Gid", event.gid.to_string()),
+                ],
+                &event.tags,
+                event.incident.as_ref(),
+            ),
+            report: None,
+        })
+    }
+}
+
+impl Teams {
+    /// Link to the last HTML report of *gid*, if they are published.
+    fn report(&self, gid: u64) -> Option<String> {
+        let reports = self.reports.lock().unwrap();
+        let (dir, url) = reports.as_ref()?;
+        latest_report(dir, gid).map(|file| report_link(url, &file))
+    }
+
+    fn queue(&self, item: Item) -> Result<(), ConnectorError> {
+        let sender = self.sender.lock().unwrap();
+        let sender = sender.as_ref().ok_or_else(|| ConnectorError::new(&self.to_string(), "Not started"))?;
+        match sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(ConnectorError::new(&self.to_string(), "Queue full, event dropped")),
+            Err(TrySendError::Disconnected(_)) => Err(ConnectorError::new(&self.to_string(), "Delivery thread stopped")),
+        }
+    }
+}
+
+/// Posts the items of *receiver* in batches until it is disconnected.
+fn deliver(url: &str, hostname: &str, receiver: &Receiver<Item>) {
+    let mut last_post: Option<Instant> = None;
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + BATCH_WINDOW;
+        while batch.len() < MAX_BATCH {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(item) => batch.push(item),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        if let Some(wait) = last_post.and_then(|t| MIN_INTERVAL.checked_sub(t.elapsed())) {
+            thread::sleep(wait);
+        }
+        let body = card(hostname, &batch).to_string();
+        for (attempt, delay) in std::iter::once(Duration::ZERO).chain(RETRY_DELAYS).enumerate() {
+            thread::sleep(delay);
+            last_post = Some(Instant::now());
+            match post_json(url, &[], &body) {
+                Ok((200..=299, _)) => break,
+                Ok((code, _)) if code == 429 || code >= 500 => warn!(code, attempt, "Teams throttled or failed, the card is retried"),
+                Ok((code, body)) => {
+                    error!("Teams answered {}: {}, {} events dropped", code, body, batch.len());
+                    break;
+                }
+                Err(e) => warn!(attempt, "Cannot reach Teams: {}", e),
+            }
+            if attempt == RETRY_DELAYS.len() {
+                error!("{} events not posted to Teams", batch.len());
+            }
+        }
+    }
+}
+
+/// The message of an Adaptive Card of *items*.
+pub fn card(hostname: &str, items: &[Item]) -> Value {
+    let mut body = Vec::new();
+    if items.len() > 1 {
+        body.push(json!({ "type": "TextBlock", "text": format!("{} events on {}", items.len(), hostname), "size": "Large", "weight": "Bolder", "wrap": true }));
+    }
+    for item in items {
+        let (style, color) = item.severity.style();
+        let mut container = vec![
+            json!({ "type": "TextBlock", "text": item.title, "size": "Medium", "weight": "Bolder", "color": color, "wrap": true }),
+            json!({
+                "type": "FactSet",
+                "facts": item.facts.iter().map(|(title, value)| json!({ "title": title, "value": truncate(value) })).collect::<Vec<Value>>(),
+            }),
+        ];
+        if let Some(report) = &item.report {
+            container.push(json!({
+                "type": "ActionSet",
+                "actions": [{ "type": "Action.OpenUrl", "title": "Incident report", "url": report }],
+            }));
+        }
+        body.push(json!({ "type": "Container", "style": style, "bleed": true, "spacing": "Medium", "items": container }));
+    }
+    json!({
+        "type": "message",
+        "attachments": [{
+            "contentType": "application/vnd.microsoft.card.adaptive",
+            "contentUrl": null,
+            "content": {
+                "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                "type": "AdaptiveCard",
+                "version": "1.4",
+                "msteams": { "width": "Full" },
+                "body": body,
+            },
+        }],
+    })
+}
+
+fn truncate(value: &str) -> String {
+    if value.chars().count() <= MAX_VALUE_LEN {
+        value.to_string()
+    } else {
+        value.chars().take(MAX_VALUE_LEN - 1).chain(std::iter::once('…')).collect()
+    }
+}
+
+/// The most recent HTML report of *gid* in *dir*, see [crate::actions_on_kill].
+fn latest_report(dir: &Path, gid: u64) -> Option<String> {
+    let suffix = format!("_report_{}.html", gid);
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(&suffix))
+        .max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+}
+
+/// *facts*, with the tags of the [crate::enrichment] providers and the incident, if any.
+fn tagged(mut facts: Vec<(&'static str, String)>, tags: &[String], incident: Option<&IncidentRef>) -> Vec<(&'static str, String)> {
+    if !tags.is_empty() {
+        facts.push(("Tags", tags.join(", ")));
+    }
+    if let Some(incident) = incident {
+        facts.push(("Incident", incident.to_string()));
+    }
+    facts
+}
+
+/// URL of the report *file* published under *base*.
+fn report_link(base: &str, file: &str) -> String {
+    format!("{}/{}", base, encode(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connectors::teams::{card, report_link, Item, Severity};
+
+    #[test]
+    fn items_should_be_batched_in_a_card() {
+        let killed = Item {
+            severity: Severity::Critical,
+            title: String::from("Ransomware killed on srv-files"),
+            facts: vec![("Score", String::from("0.97")), ("Executable", "x".repeat(600))],
+            report: Some(report_link("https://reports.corp/threats", "~locker exe_2026_report_42.html")),
+        };
+        let pre_alert = Item {
+            severity: Severity::Warning,
+            title: String::from("PreAlert on srv-files"),
+            facts: vec![("Score", String::from("0.71"))],
+            report: None,
+        };
+
+        let single = card("srv-files", std::slice::from_ref(&killed));
+        let body = &single["attachments"][0]["content"]["body"];
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["style"], "attention");
+        assert_eq!(body[0]["items"][0]["color"], "Attention");
+        assert_eq!(body[0]["items"][1]["facts"][1]["value"].as_str().unwrap().chars().count(), 512);
+        assert_eq!(
+            body[0]["items"][2]["actions"][0]["url"],
+            "https://reports.corp/threats/~locker%20exe_2026_report_42.html"
+        );
+
+        let batch = card("srv-files", &[killed, pre_alert]);
+        let body = &batch["attachments"][0]["content"]["body"];
+        assert_eq!(body[0]["text"], "2 events on srv-files");
+        assert_eq!(body[2]["style"], "warning");
+        assert_eq!(body[2]["items"].as_array().unwrap().len(), 2);
+    }
+}
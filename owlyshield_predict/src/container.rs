@@ -0,0 +1,126 @@
+//! Isolation of the root process of a gid: Windows job objects and AppContainers, and the
+//! containers (a Windows Sandbox, a Windows container, or the cgroup of a container runtime on
+//! Linux).
+//!
+//! A ransomware run in a container or a sandbox only reaches the files shared with it, and a
+//! benign application in an AppContainer is confined by its capabilities: the feature
+//! *runs_in_container* ([Containment::runs_in_container]) lets the model weigh it. The kills are
+//! job-aware: see [crate::os::terminate_group].
+
+#[cfg(target_os = "linux")]
+use std::fs;
+
+use serde::Serialize;
+#[cfg(windows)]
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+
+#[cfg(windows)]
+use crate::os;
+#[cfg(windows)]
+use crate::token;
+
+/// Ancestors hosting a container or a sandbox on Windows, lowercase.
+#[cfg(windows)]
+const CONTAINER_HOSTS: [&str; 4] = ["cexecsvc.exe", "windowssandbox.exe", "windowssandboxclient.exe", "vmcompute.exe"];
+/// Ancestors looked up, at most.
+#[cfg(windows)]
+const MAX_DEPTH: usize = 16;
+/// Container runtimes, by the prefix of a component of the cgroup path on Linux, or by the whole
+/// component without the dash (*/docker/<id>* with the cgroups v1).
+#[cfg(any(target_os = "linux", test))]
+const RUNTIMES: [(&str, &str); 6] = [
+    ("docker-", "docker"),
+    ("kubepods", "kubernetes"),
+    ("libpod-", "podman"),
+    ("lxc-", "lxc"),
+    ("lxc.payload.", "lxc"),
+    ("machine-", "systemd-nspawn"),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Containment {
+    /// Runs in a job object
+    pub in_job: bool,
+    /// Sandboxed in an AppContainer (Store applications, browser renderers)
+    pub app_container: bool,
+    /// Host of its container or sandbox (*CExecSvc.exe*...), or container runtime on Linux
+    pub container: Option<String>,
+}
+
+impl Containment {
+    #[cfg(windows)]
+    pub fn of(pid: u32) -> Containment {
+        Containment {
+            in_job: os::is_in_job(pid),
+            app_container: token::is_app_container(pid),
+            container: container_host(pid),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn of(pid: u32) -> Containment {
+        Containment {
+            in_job: false,
+            app_container: false,
+            container: fs::read_to_string(format!("/proc/{}/cgroup", pid))
+                .ok()
+                .and_then(|cgroup| runtime_of_cgroup(&cgroup)),
+        }
+    }
+
+    pub fn runs_in_container(&self) -> bool {
+        self.app_container || self.container.is_some()
+    }
+}
+
+/// The first ancestor of *pid* hosting a container.
+#[cfg(windows)]
+fn container_host(pid: u32) -> Option<String> {
+    let mut system = System::new();
+    let mut pid = pid as Pid;
+    for _ in 0..MAX_DEPTH {
+        system.refresh_process(pid);
+        let process = system.process(pid)?;
+        let name = process.name().to_lowercase();
+        if CONTAINER_HOSTS.contains(&name.as_str()) {
+            return Some(process.name().to_string());
+        }
+        pid = process.parent()?;
+    }
+    None
+}
+
+/// The container runtime of a */proc/pid/cgroup* file, None outside of a container.
+#[cfg(any(target_os = "linux", test))]
+pub fn runtime_of_cgroup(cgroup: &str) -> Option<String> {
+    cgroup
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .flat_map(|path| path.split('/'))
+        .find_map(|component| {
+            RUNTIMES
+                .iter()
+                .find(|(prefix, _)| component.starts_with(prefix) || component == prefix.trim_end_matches('-'))
+                .map(|(_, runtime)| runtime.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container::runtime_of_cgroup;
+
+    #[test]
+    fn container_runtime_should_be_read_from_the_cgroup() {
+        assert_eq!(
+            runtime_of_cgroup("0::/system.slice/docker-4b1e4c3f9a.scope\n").as_deref(),
+            Some("docker")
+        );
+        assert_eq!(
+            runtime_of_cgroup("12:pids:/kubepods/besteffort/pod1f2e/3c4d\n0::/\n").as_deref(),
+            Some("kubernetes")
+        );
+        assert_eq!(runtime_of_cgroup("0::/user.slice/user-1000.slice/session-2.scope\n"), None);
+        // the daemon of the runtime
+        assert_eq!(runtime_of_cgroup("0::/system.slice/docker.service\n"), None);
+    }
+}
@@ -0,0 +1,174 @@
+//! Incidents shared by the notifications of a process family: the toast, the reports and the
+//! connectors refer to the same incident, identified by the machine, the gid and the hash of its
+//! executable ([incident_id]). It is also the dedup key of the on-call services
+//! ([crate::connectors::paging]).
+//!
+//! The first alert of a gid opens its incident, the next ones are its updates. An update is
+//! notified again (toast, chat connectors) only if it tells something new: another type of alert,
+//! or a score higher by [SCORE_STEP]. The repeated ones are only recorded, by the SIEM and the
+//! local event store, with the incident and the number of the update.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::utils::sha256_file;
+
+/// Rise of the highest score of an incident for an update to be notified again.
+pub const SCORE_STEP: f32 = 0.05;
+/// Incidents kept in memory. Beyond, the least recently updated one is forgotten.
+const MAX_INCIDENTS: usize = 1024;
+/// Of the sha256 of the executable, in the incident id.
+const HASH_LEN: usize = 12;
+
+/// The incident of an alert.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncidentRef {
+    pub id: String,
+    /// 0 for the alert opening the incident
+    pub update: u32,
+    /// False for a repeated alert, not notified again
+    pub notify: bool,
+}
+
+impl fmt::Display for IncidentRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.update {
+            0 => write!(f, "{}", self.id),
+            update => write!(f, "{} (update {})", self.id, update),
+        }
+    }
+}
+
+/// True if *incident* is a repeated alert, which is not notified.
+pub fn is_repeated(incident: Option<&IncidentRef>) -> bool {
+    incident.is_some_and(|incident| !incident.notify)
+}
+
+/// An open incident.
+#[derive(Debug)]
+struct Tracked {
+    id: String,
+    updates: u32,
+    /// Types of the alerts, as in [crate::schema::Event]
+    kinds: Vec<&'static str>,
+    max_score: f32,
+    last_time: SystemTime,
+}
+
+/// The incidents of the gids, shared by the workers (toasts, reports) and the connectors.
+#[derive(Debug)]
+pub struct Correlator {
+    incidents: Mutex<HashMap<u64, Tracked>>,
+}
+
+impl Correlator {
+    pub fn new() -> Correlator {
+        Correlator {
+            incidents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The incident of an alert of *gid*, of type *kind* and with its *score* if any. The first
+    /// alert opens it, with the id given by *open* (called without the lock: it hashes the
+    /// executable).
+    pub fn correlate<F>(&self, gid: u64, kind: &'static str, score: Option<f32>, now: SystemTime, open: F) -> IncidentRef
+    where
+        F: FnOnce() -> String,
+    {
+        let known = self.incidents.lock().unwrap().get(&gid).map(|tracked| tracked.id.clone());
+        let id = known.unwrap_or_else(open);
+        let mut incidents = self.incidents.lock().unwrap();
+        if let Some(tracked) = incidents.get_mut(&gid) {
+            tracked.updates += 1;
+            tracked.last_time = now;
+            let new_kind = !tracked.kinds.contains(&kind);
+            if new_kind {
+                tracked.kinds.push(kind);
+            }
+            let rise = score.is_some_and(|score| score >= tracked.max_score + SCORE_STEP);
+            if let Some(score) = score {
+                tracked.max_score = tracked.max_score.max(score);
+            }
+            return IncidentRef {
+                id: tracked.id.clone(),
+                update: tracked.updates,
+                notify: new_kind || rise,
+            };
+        }
+        if incidents.len() >= MAX_INCIDENTS {
+            if let Some(oldest) = incidents.iter().min_by_key(|(_, tracked)| tracked.last_time).map(|(gid, _)| *gid) {
+                incidents.remove(&oldest);
+            }
+        }
+        incidents.insert(
+            gid,
+            Tracked {
+                id: id.clone(),
+                updates: 0,
+                kinds: vec![kind],
+                max_score: score.unwrap_or(0.0),
+                last_time: now,
+            },
+        );
+        IncidentRef { id, update: 0, notify: true }
+    }
+
+    /// The id of the incident of *gid*, if it has one.
+    pub fn id_of(&self, gid: u64) -> Option<String> {
+        self.incidents.lock().unwrap().get(&gid).map(|tracked| tracked.id.clone())
+    }
+}
+
+impl Default for Correlator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// *owlyshield-<machine id>-<gid>-<hash>*, where *hash* starts the sha256 of *exepath* (0 if it
+/// cannot be read).
+pub fn incident_id(machine_id: &str, gid: u64, exepath: &Path) -> String {
+    let hash = sha256_file(exepath).map_or_else(|_| String::from("0"), |sha256| sha256.chars().take(HASH_LEN).collect());
+    format!("owlyshield-{}-{}-{}", machine_id, gid, hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::correlation::{is_repeated, Correlator};
+
+    #[test]
+    fn repeated_alerts_should_update_the_incident() {
+        let correlator = Correlator::new();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let open = || String::from("owlyshield-6f1c-42-a1b2c3d4e5f6");
+
+        let first = correlator.correlate(42, "escalation", Some(0.62), now, open);
+        assert_eq!((first.update, first.notify), (0, true));
+        assert_eq!(first.to_string(), "owlyshield-6f1c-42-a1b2c3d4e5f6");
+
+        let repeated = correlator.correlate(42, "escalation", Some(0.64), now, || unreachable!());
+        assert_eq!(repeated.id, first.id);
+        assert_eq!((repeated.update, repeated.notify), (1, false));
+        assert!(is_repeated(Some(&repeated)));
+
+        let higher = correlator.correlate(42, "escalation", Some(0.7), now, || unreachable!());
+        assert!(higher.notify);
+        let detection = correlator.correlate(42, "detection", Some(0.7), now, || unreachable!());
+        assert!(detection.notify);
+        assert_eq!(detection.to_string(), "owlyshield-6f1c-42-a1b2c3d4e5f6 (update 3)");
+        assert!(correlator.correlate(42, "mass_deletion", None, now, || unreachable!()).notify);
+        let again = correlator.correlate(42, "mass_deletion", None, now, || unreachable!());
+        assert_eq!((again.update, again.notify), (5, false));
+
+        let other = correlator.correlate(7, "kill", Some(0.9), now, || String::from("owlyshield-6f1c-7-0"));
+        assert_eq!((other.id.as_str(), other.update), ("owlyshield-6f1c-7-0", 0));
+        assert_eq!(correlator.id_of(42), Some(first.id));
+        assert_eq!(correlator.id_of(8), None);
+        assert!(!is_repeated(None));
+    }
+}
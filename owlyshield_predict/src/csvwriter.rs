@@ -1,13 +1,18 @@
 //! Used to dump ProcessRecords to a csv file to create Learning samples that will be used to train the model.
 
 use std::fs;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::os::raw::c_ulonglong;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use crate::config::{Config, Param};
-use crate::prediction::input_tensors::PredictionRow;
+use crate::driver_com::shared_def::IOMessage;
+
+/// Separator between two serialized [IOMessage] in the records file written by
+/// [CsvWriter::write_irp_csv_files].
+/// A strange chain is used to avoid collisions with the windows fileid.
+pub static IRP_RECORD_SEPARATOR: [u8; 4] = [255u8, 0u8, 13u8, 10u8];
 
 #[derive(Debug)]
 pub struct CsvWriter {
@@ -21,7 +26,7 @@ impl CsvWriter {
         CsvWriter {
             last_write_time: None,
             //path: Path::new(&config[Param::PredPath]).to_path_buf(),
-            path: Path::new(&config[Param::DebugPath])
+            path: config.get_path(Param::DebugPath)
                 .join(Path::new("learn.csv"))
                 .to_path_buf(),
             separator: String::from(";"),
@@ -40,12 +45,11 @@ impl CsvWriter {
         &mut self,
         appname: &str,
         gid: c_ulonglong,
-        predrow: &PredictionRow,
+        predrow: &[f32],
     ) -> Result<(), std::io::Error> {
         //        println!("CALLED");
-        let predrow_vec = predrow.to_vec_f32();
         let mut process_vec = vec![String::from(appname), gid.to_string()];
-        process_vec.append(&mut Self::vec_to_vecstring(&predrow_vec));
+        process_vec.append(&mut Self::vec_to_vecstring(predrow));
 
         let process_vec_csv =
             Self::vec_to_string_sep(&self, &process_vec).unwrap() + &*String::from("\n");
@@ -74,7 +78,7 @@ impl CsvWriter {
             .open(&self.path)?;
 
         file.write_all(process_vec_csv.as_slice())?;
-        file.write(&vec![255u8, 0u8, 13u8, 10u8])
+        file.write(&IRP_RECORD_SEPARATOR)
             .expect("Error marshalling driver message");
         self.last_write_time = Some(SystemTime::now());
         Ok(())
@@ -99,6 +103,48 @@ impl CsvWriter {
     }
 }
 
+/// Reads back the [IOMessage] records written with ```--features record```.
+#[derive(Debug)]
+pub struct IrpRecordsReader {
+    buf: Vec<u8>,
+    cursor: usize,
+}
+
+impl IrpRecordsReader {
+    pub fn from_path(path: &Path) -> Result<IrpRecordsReader, std::io::Error> {
+        let mut buf = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut buf)?;
+        Ok(IrpRecordsReader { buf, cursor: 0 })
+    }
+
+    fn next_record_end(&self) -> usize {
+        let sep_len = IRP_RECORD_SEPARATOR.len();
+        let mut i = self.cursor;
+        while i + sep_len <= self.buf.len() {
+            if self.buf[i..i + sep_len] == IRP_RECORD_SEPARATOR {
+                return i;
+            }
+            i += 1;
+        }
+        self.buf.len()
+    }
+}
+
+impl Iterator for IrpRecordsReader {
+    /// A record which cannot be deserialized is returned as an error with its offset in the file.
+    type Item = Result<IOMessage, usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor >= self.buf.len() {
+            return None;
+        }
+        let start = self.cursor;
+        let end = self.next_record_end();
+        self.cursor = end + IRP_RECORD_SEPARATOR.len();
+        Some(rmp_serde::from_read_ref(&self.buf[start..end]).map_err(|_| start))
+    }
+}
+
 // #[cfg(test)]
 // mod test {
 //     use super::*;
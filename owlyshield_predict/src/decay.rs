@@ -0,0 +1,147 @@
+//! Recent activity of a gid, at several time scales: the cumulative counters of the
+//! [crate::process::ProcessRecord] only grow, so that a long-lived benign process (a backup, an
+//! indexer) eventually looks like a mass writer. The features of [DecayedActivity] follow the
+//! rate of the writes, deletions and renames, and the entropy of the bytes written, over the last
+//! minute, ten minutes and hour ([SCALES]).
+//!
+//! Each window is an exponentially decayed sum: an event weighs *exp(-age / scale)*, so that a
+//! steady rate *r* gives about *r × scale*. The windows are evaluated at the time of the last
+//! event (the reception time of the driver message, which makes the replays deterministic).
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+/// Time scales of the windows.
+pub const SCALES: [Duration; 3] = [Duration::from_secs(60), Duration::from_secs(600), Duration::from_secs(3600)];
+/// Suffixes of the names of the features, see [crate::features::REGISTRY].
+pub const SCALES_NAMES: [&str; 3] = ["1m", "10m", "1h"];
+/// Number of features of [DecayedActivity::features].
+pub const FEATURES_COUNT: usize = 4 * SCALES.len();
+
+/// A sum decayed at each of the [SCALES].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Decayed {
+    values: [f64; 3],
+    last: Option<SystemTime>,
+}
+
+impl Decayed {
+    pub fn add(&mut self, amount: f64, now: SystemTime) {
+        self.values = self.at(now);
+        for value in self.values.iter_mut() {
+            *value += amount;
+        }
+        self.last = Some(self.last.map_or(now, |last| last.max(now)));
+    }
+
+    /// The sums at *now*.
+    pub fn at(&self, now: SystemTime) -> [f64; 3] {
+        let elapsed = match self.last {
+            Some(last) => now.duration_since(last).unwrap_or(Duration::ZERO).as_secs_f64(),
+            None => return [0.0; 3],
+        };
+        let mut values = self.values;
+        for (value, scale) in values.iter_mut().zip(SCALES.iter()) {
+            *value *= (-elapsed / scale.as_secs_f64()).exp();
+        }
+        values
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DecayedActivity {
+    writes: Decayed,
+    deletes: Decayed,
+    renames: Decayed,
+    bytes_written: Decayed,
+    /// Entropy of the writes weighted by their size
+    entropy_written: Decayed,
+    last: Option<SystemTime>,
+}
+
+impl DecayedActivity {
+    pub fn new() -> DecayedActivity {
+        DecayedActivity::default()
+    }
+
+    pub fn on_write(&mut self, entropy: f64, bytes: u64, now: SystemTime) {
+        self.writes.add(1.0, now);
+        self.bytes_written.add(bytes as f64, now);
+        self.entropy_written.add(entropy * bytes as f64, now);
+        self.touch(now);
+    }
+
+    pub fn on_delete(&mut self, now: SystemTime) {
+        self.deletes.add(1.0, now);
+        self.touch(now);
+    }
+
+    pub fn on_rename(&mut self, now: SystemTime) {
+        self.renames.add(1.0, now);
+        self.touch(now);
+    }
+
+    /// Writes, deletions and renames summarized by the minifilter, see [crate::backpressure].
+    pub fn on_aggregate(&mut self, writes: u32, deletes: u32, renames: u32, now: SystemTime) {
+        self.writes.add(writes as f64, now);
+        self.deletes.add(deletes as f64, now);
+        self.renames.add(renames as f64, now);
+        self.touch(now);
+    }
+
+    fn touch(&mut self, now: SystemTime) {
+        self.last = Some(self.last.map_or(now, |last| last.max(now)));
+    }
+
+    /// Writes, deletions and renames, then the mean entropy of the bytes written, at each of the
+    /// [SCALES], at the time of the last event.
+    pub fn features(&self) -> [f32; FEATURES_COUNT] {
+        let mut features = [0.0; FEATURES_COUNT];
+        let now = match self.last {
+            Some(last) => last,
+            None => return features,
+        };
+        let bytes = self.bytes_written.at(now);
+        let entropy = self.entropy_written.at(now);
+        let counters = [self.writes.at(now), self.deletes.at(now), self.renames.at(now)];
+        for (i, values) in counters.iter().enumerate() {
+            for (j, value) in values.iter().enumerate() {
+                features[i * SCALES.len() + j] = *value as f32;
+            }
+        }
+        for j in 0..SCALES.len() {
+            if bytes[j] > 0.0 {
+                features[3 * SCALES.len() + j] = (entropy[j] / bytes[j]) as f32;
+            }
+        }
+        features
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::decay::DecayedActivity;
+
+    #[test]
+    fn old_activity_should_fade_from_the_short_windows() {
+        let start = SystemTime::now();
+        let mut activity = DecayedActivity::new();
+        for i in 0..600 {
+            activity.on_write(7.9, 4096, start + Duration::from_millis(100 * i));
+        }
+        let burst = activity.features();
+        assert!(burst[0] > 350.0 && burst[2] > 590.0);
+        assert!((burst[9] - 7.9).abs() < 0.01);
+
+        // one delete 30 minutes later
+        activity.on_delete(start + Duration::from_secs(1800));
+        let later = activity.features();
+        assert!(later[0] < 0.01);
+        assert!(later[1] < burst[1] / 10.0);
+        assert!(later[2] > burst[2] / 2.0);
+        assert!((later[3] - 1.0).abs() < 0.01);
+    }
+}
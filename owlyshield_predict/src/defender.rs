@@ -0,0 +1,279 @@
+//! Coexistence with the antivirus of the machine (Windows only).
+//!
+//! With *AV_COEXISTENCE*, the antivirus products registered in the Windows Security Center, the
+//! status of Windows Defender and the number of AMSI providers ([AvStatus]) are queried at startup
+//! and every [STATUS_INTERVAL], for the heartbeat, the API and the diag bundles.
+//!
+//! The detections of Defender (events 1116 and 1117 of its *Operational* event log) are read every
+//! [DETECTIONS_INTERVAL] and kept for [DETECTION_TTL]. A process family whose executable was
+//! detected by Defender is still killed, but the alert is not repeated by the incident reports and
+//! the notifications: see [Coexistence::detection_of].
+//!
+//! The queries run PowerShell in a background thread of the [crate::pipeline].
+
+use std::path::Path;
+#[cfg(windows)]
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+#[cfg(windows)]
+use tracing::debug;
+
+/// Period of the query of the antivirus products and of Defender.
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Period of the read of the detections of Defender.
+pub const DETECTIONS_INTERVAL: Duration = Duration::from_secs(60);
+/// Detections are matched with the alerts during this delay.
+pub const DETECTION_TTL: Duration = Duration::from_secs(3600);
+#[cfg(windows)]
+const STATUS_SCRIPT: &str = r#"$ErrorActionPreference = 'SilentlyContinue'
+$av = Get-CimInstance -Namespace root/SecurityCenter2 -ClassName AntiVirusProduct | ForEach-Object { [pscustomobject]@{name = $_.displayName; state = [int64]$_.productState} }
+$mp = Get-MpComputerStatus | Select-Object AMRunningMode, AntivirusEnabled, RealTimeProtectionEnabled, BehaviorMonitorEnabled, IsTamperProtected, AntivirusSignatureAge
+$amsi = @(Get-ChildItem HKLM:\SOFTWARE\Microsoft\AMSI\Providers).Count
+[pscustomobject]@{products = @($av); defender = $mp; amsi_providers = $amsi} | ConvertTo-Json -Compress -Depth 3"#;
+/// Takes the seconds to look back.
+#[cfg(windows)]
+const DETECTIONS_SCRIPT: &str = r#"$events = Get-WinEvent -FilterHashtable @{LogName = 'Microsoft-Windows-Windows Defender/Operational'; Id = 1116, 1117; StartTime = (Get-Date).AddSeconds(-{secs})} -ErrorAction SilentlyContinue | ForEach-Object { $d = @{}; ([xml]$_.ToXml()).Event.EventData.Data | ForEach-Object { $d[$_.Name] = $_.'#text' }; [pscustomobject]@{time = $_.TimeCreated.ToUniversalTime().ToString('o'); id = $_.Id; threat = $d['Threat Name']; action = $d['Action Name']; path = $d['Path']} }
+ConvertTo-Json -Compress -InputObject @($events)"#;
+
+/// An antivirus registered in the Windows Security Center.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AvProduct {
+    pub name: String,
+    pub enabled: bool,
+    pub up_to_date: bool,
+}
+
+/// As given by *Get-MpComputerStatus*.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DefenderStatus {
+    /// Normal, Passive (another antivirus is active) or EDR Block Mode
+    #[serde(rename = "AMRunningMode", default)]
+    pub running_mode: Option<String>,
+    #[serde(default)]
+    pub antivirus_enabled: bool,
+    #[serde(default)]
+    pub real_time_protection_enabled: bool,
+    #[serde(default)]
+    pub behavior_monitor_enabled: bool,
+    #[serde(default)]
+    pub is_tamper_protected: bool,
+    /// Days
+    #[serde(default)]
+    pub antivirus_signature_age: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AvStatus {
+    pub products: Vec<AvProduct>,
+    /// None if Defender is not installed or its status cannot be read
+    pub defender: Option<DefenderStatus>,
+    pub amsi_providers: usize,
+}
+
+/// A detection of Defender.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Detection {
+    /// RFC 3339, UTC
+    pub time: String,
+    pub threat: String,
+    /// Quarantine, Remove... empty for a detection without action yet (1116)
+    pub action: String,
+    /// Files detected, lowercase
+    pub paths: Vec<String>,
+}
+
+/// The result of a query, see [query].
+#[derive(Debug, Default, Serialize)]
+pub struct Refresh {
+    pub status: Option<AvStatus>,
+    pub detections: Vec<Detection>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    status: Option<AvStatus>,
+    detections: Vec<(Instant, Detection)>,
+}
+
+/// Shared through the [crate::status::AgentStatus].
+#[derive(Debug, Default)]
+pub struct Coexistence {
+    state: Mutex<State>,
+}
+
+impl Coexistence {
+    pub fn new() -> Coexistence {
+        Coexistence::default()
+    }
+
+    pub fn update(&self, refresh: Refresh) {
+        let mut state = self.state.lock().unwrap();
+        if refresh.status.is_some() {
+            state.status = refresh.status;
+        }
+        let now = Instant::now();
+        state.detections.retain(|(seen, _)| now.duration_since(*seen) < DETECTION_TTL);
+        for detection in refresh.detections {
+            if !state.detections.iter().any(|(_, d)| *d == detection) {
+                state.detections.push((now, detection));
+            }
+        }
+    }
+
+    /// None before the first query, or without *AV_COEXISTENCE*.
+    pub fn status(&self) -> Option<AvStatus> {
+        self.state.lock().unwrap().status.clone()
+    }
+
+    /// The last detection by Defender of *exepath*.
+    pub fn detection_of(&self, exepath: &Path) -> Option<Detection> {
+        let exepath = exepath.to_string_lossy().to_lowercase();
+        let state = self.state.lock().unwrap();
+        state
+            .detections
+            .iter()
+            .rev()
+            .map(|(_, detection)| detection)
+            .find(|detection| detection.paths.contains(&exepath))
+            .cloned()
+    }
+}
+
+/// Queries the antivirus products and Defender if *with_status*, and the detections of the last
+/// *since*.
+#[cfg(windows)]
+pub fn query(with_status: bool, since: Duration) -> Refresh {
+    Refresh {
+        status: with_status.then(|| powershell(STATUS_SCRIPT).map(|json| parse_status(&json)).unwrap_or_default()),
+        detections: powershell(&DETECTIONS_SCRIPT.replace("{secs}", &since.as_secs().to_string()))
+            .map(|json| parse_detections(&json))
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn query(_with_status: bool, _since: Duration) -> Refresh {
+    Refresh::default()
+}
+
+#[cfg(windows)]
+fn powershell(script: &str) -> Option<String> {
+    let output = Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| debug!("Cannot run powershell.exe: {}", e))
+        .ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_status(json: &str) -> AvStatus {
+    let value: Value = serde_json::from_str(json).unwrap_or_default();
+    AvStatus {
+        products: value["products"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|product| {
+                let state = product["state"].as_i64()?;
+                Some(AvProduct {
+                    name: product["name"].as_str()?.to_string(),
+                    // the second byte of productState is 0x10 when enabled, the third 0x10 when
+                    // the signatures are out of date
+                    enabled: state & 0x1000 != 0,
+                    up_to_date: state & 0x10 == 0,
+                })
+            })
+            .collect(),
+        defender: serde_json::from_value(value["defender"].clone()).ok(),
+        amsi_providers: value["amsi_providers"].as_u64().unwrap_or_default() as usize,
+    }
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_detections(json: &str) -> Vec<Detection> {
+    let value: Value = serde_json::from_str(json).unwrap_or_default();
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|event| Detection {
+            time: event["time"].as_str().unwrap_or_default().to_string(),
+            threat: event["threat"].as_str().unwrap_or_default().to_string(),
+            action: if event["id"].as_u64() == Some(1117) {
+                event["action"].as_str().unwrap_or_default().to_string()
+            } else {
+                String::new()
+            },
+            paths: parse_resources(event["path"].as_str().unwrap_or_default()),
+        })
+        .filter(|detection| !detection.paths.is_empty())
+        .collect()
+}
+
+/// The files of the *Path* of a detection: ```file:_C:\a.exe; containerfile:_C:\b.zip;
+/// file:_C:\b.zip->x.exe; process:_pid:42```. The files in an archive are given by the archive.
+fn parse_resources(resources: &str) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    for resource in resources.split(';').map(str::trim) {
+        let path = match resource.split_once(":_") {
+            Some((kind, path)) if kind.eq_ignore_ascii_case("file") || kind.eq_ignore_ascii_case("containerfile") => path,
+            _ => continue,
+        };
+        let path = path.split("->").next().unwrap_or_default().trim().to_lowercase();
+        if !path.is_empty() && !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::defender::{parse_detections, parse_status, Coexistence, Refresh};
+
+    #[test]
+    fn defender_detections_should_match_the_executables() {
+        let status = parse_status(
+            r#"{"products":[{"name":"Windows Defender","state":397568},{"name":"Acme AV","state":262160}],
+                "defender":{"AMRunningMode":"Passive Mode","AntivirusEnabled":true,"IsTamperProtected":false,"AntivirusSignatureAge":2},
+                "amsi_providers":2}"#,
+        );
+        assert_eq!((status.products[0].enabled, status.products[0].up_to_date), (true, true));
+        assert_eq!((status.products[1].enabled, status.products[1].up_to_date), (false, false));
+        let defender = status.defender.unwrap();
+        assert_eq!((defender.running_mode.as_deref(), defender.antivirus_signature_age), (Some("Passive Mode"), Some(2)));
+        assert_eq!(status.amsi_providers, 2);
+
+        let detections = parse_detections(
+            r#"[{"time":"2022-05-02T10:00:00.0000000Z","id":1117,"threat":"Ransom:Win32/Lockbit","action":"Quarantine",
+                 "path":"file:_C:\\Users\\bob\\Downloads\\invoice.exe; containerfile:_C:\\Users\\bob\\Downloads\\invoice.zip; file:_C:\\Users\\bob\\Downloads\\invoice.zip->invoice.scr; process:_pid:4242,ProcessStart:1"},
+                {"time":"2022-05-02T10:00:01.0000000Z","id":1116,"threat":"Trojan:Script/Wacatac","action":"Quarantine","path":"process:_pid:42"}]"#,
+        );
+        assert_eq!(detections.len(), 1);
+        assert_eq!(detections[0].paths, vec![r"c:\users\bob\downloads\invoice.exe", r"c:\users\bob\downloads\invoice.zip"]);
+
+        let coexistence = Coexistence::new();
+        coexistence.update(Refresh {
+            status: None,
+            detections: detections.clone(),
+        });
+        coexistence.update(Refresh {
+            status: None,
+            detections,
+        });
+        assert_eq!(coexistence.state.lock().unwrap().detections.len(), 1);
+        let detection = coexistence.detection_of(Path::new(r"C:\Users\bob\Downloads\Invoice.exe")).unwrap();
+        assert_eq!((detection.threat.as_str(), detection.action.as_str()), ("Ransom:Win32/Lockbit", "Quarantine"));
+        assert!(coexistence.detection_of(Path::new(r"C:\Windows\explorer.exe")).is_none());
+    }
+}
@@ -0,0 +1,457 @@
+//! Debug bundle for the support tickets (```owlyshield_ransom diag collect```): a zip of
+//!
+//! | Entry           | Content                                                          |
+//! |-----------------|------------------------------------------------------------------|
+//! | summary.txt     | identity of the machine, versions of the agent and of the models |
+//! | config/         | values with their sources, and the config files, secrets redacted|
+//! | driver.txt      | loaded minifilters and their conflicts (event source on Linux)   |
+//! | av.json         | antivirus products and recent detections of Defender             |
+//! | logs/           | the logs of the last [RECENT_LOGS_DAYS] days                     |
+//! | audit/          | the predictions CSVs of the last [RECENT_AUDIT_DAYS] days        |
+//! | threats/        | the incident reports of the last [RECENT_REPORTS_DAYS] days      |
+//! | journal/        | the journal of the kills and suspensions ([journal])             |
+//!
+//! The tokens of the API and of the heartbeat, and the encrypted secrets, are never collected.
+//!
+//! The conflicts of the minifilter with the others ([coexistence]) are also shown by
+//! ```owlyshield_ransom status```.
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use chrono::Local;
+use strum::IntoEnumIterator;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::config::{Config, Param, CONFIG_FILE_NAME};
+use crate::identity::AgentIdentity;
+use crate::service_ctl::FILTER_SERVICE_NAME;
+use crate::{defender, driver_setup, journal, prediction, prediction_static};
+
+const RECENT_LOGS_DAYS: u64 = 7;
+const RECENT_AUDIT_DAYS: u64 = 3;
+const RECENT_REPORTS_DAYS: u64 = 30;
+/// Keys whose values are replaced by [REDACTED], compared in lowercase.
+const SECRET_KEYS: &[&str] = &["key", "token", "secret", "password", "pwd", "credential"];
+const REDACTED: &str = "<redacted>";
+/// Minifilters known to disturb the agent, by their name in *fltmc filters*, with the issue.
+const KNOWN_FILTERS: &[(&str, &str)] = &[
+    ("file_protector", "Acronis Active Protection, may suspend or roll back the same processes"),
+    ("MBAMFarflt", "Malwarebytes Anti-Ransomware, may suspend the same processes"),
+    ("SentinelMonitor", "SentinelOne, may kill or roll back the same processes"),
+];
+
+/// Writes the bundle to *dest*, and returns the number of entries.
+pub fn collect(config: &Config, dest: &Path) -> io::Result<usize> {
+    let mut bundle = Bundle {
+        zip: ZipWriter::new(File::create(dest)?),
+        entries: 0,
+    };
+    let config_path = config.get_path(Param::ConfigPath);
+    let debug_path = config.get_path(Param::DebugPath);
+
+    bundle.add("summary.txt", summary(config).as_bytes())?;
+    bundle.add("config/values.txt", config_values(config).as_bytes())?;
+    for name in &[CONFIG_FILE_NAME, "exclusions.toml", "exclusions.txt"] {
+        if let Ok(content) = fs::read_to_string(config_path.join(name)) {
+            bundle.add(&format!("config/{}", name), redact(&content).as_bytes())?;
+        }
+    }
+    bundle.add("driver.txt", driver_status(config).as_bytes())?;
+    let av = defender::query(true, defender::DETECTION_TTL);
+    bundle.add("av.json", serde_json::to_string_pretty(&av).unwrap_or_default().as_bytes())?;
+    bundle.add_recent_files(&debug_path.join("logs"), "logs", RECENT_LOGS_DAYS)?;
+    bundle.add_recent_files(&debug_path.join("audit"), "audit", RECENT_AUDIT_DAYS)?;
+    bundle.add_recent_files(&config_path.join("threats"), "threats", RECENT_REPORTS_DAYS)?;
+    bundle.add_recent_files(&debug_path.join(journal::JOURNAL_DIR), "journal", RECENT_REPORTS_DAYS)?;
+    bundle.zip.finish()?;
+    Ok(bundle.entries)
+}
+
+struct Bundle {
+    zip: ZipWriter<File>,
+    entries: usize,
+}
+
+impl Bundle {
+    fn add(&mut self, name: &str, content: &[u8]) -> io::Result<()> {
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .large_file(content.len() as u64 >= u32::MAX as u64);
+        self.zip.start_file(name, options)?;
+        self.zip.write_all(content)?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// The files of *dir* (not its subdirectories) modified during the last *days*. A missing
+    /// directory is skipped.
+    fn add_recent_files(&mut self, dir: &Path, prefix: &str, days: u64) -> io::Result<()> {
+        let since = SystemTime::now() - Duration::from_secs(days * 24 * 3600);
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_recent = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified >= since);
+            if path.is_file() && is_recent {
+                // a log being written may be locked: skip it rather than fail the bundle
+                if let Ok(content) = fs::read(&path) {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    self.add(&format!("{}/{}", prefix, name), &content)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn summary(config: &Config) -> String {
+    let identity = AgentIdentity::load(config);
+    format!(
+        "Collected: {}\nMachine: {}\nOS: {}\nDomain: {}\nAgent version: {}\nModel: {}\nStatic model: {}\n",
+        Local::now().to_rfc3339(),
+        identity.machine(),
+        identity.os_version,
+        identity.domain.as_deref().unwrap_or("-"),
+        identity.agent_version,
+        prediction::model_version(),
+        prediction_static::model_version(),
+    )
+}
+
+fn config_values(config: &Config) -> String {
+    Param::iter()
+        .map(|param| {
+            let key = Param::convert_to_str(&param);
+            let value = if is_secret(key) { REDACTED } else { config.get_str(param) };
+            format!("{} = {} ({:?})\n", key, value, config.get_source(param))
+        })
+        .collect()
+}
+
+fn is_secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SECRET_KEYS.iter().any(|k| key.contains(k))
+}
+
+/// *content* of a config file, with the values of the secret keys (```key = value``` lines)
+/// replaced.
+fn redact(content: &str) -> String {
+    content
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, _)) if is_secret(key.trim()) => format!("{}= \"{}\"\n", key, REDACTED),
+            _ => format!("{}\n", line),
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+fn driver_status(_config: &Config) -> String {
+    match fltmc::filters() {
+        Ok(filters) => {
+            let mut res = format!("{:<24}{:>12}{:>12}{:>8}\n", "Filter name", "Instances", "Altitude", "Frame");
+            for filter in filters {
+                res.push_str(&format!(
+                    "{:<24}{:>12}{:>12}{:>8}\n",
+                    filter.name, filter.instances, filter.altitude, filter.frame
+                ));
+            }
+            res.push('\n');
+            for problem in coexistence_problems(&filters) {
+                res.push_str(&format!("{}\n", problem));
+            }
+            res
+        }
+        Err(e) => format!("Cannot list the minifilters: {}\n", e),
+    }
+}
+
+/// The problems of coexistence of the loaded *filters* with the minifilter, at its altitude when
+/// loaded, else at the one of its registry key.
+#[cfg(windows)]
+pub fn coexistence_problems(filters: &[MiniFilter]) -> Vec<String> {
+    let altitude = filters
+        .iter()
+        .find(|filter| filter.name.eq_ignore_ascii_case(FILTER_SERVICE_NAME))
+        .map(|filter| filter.altitude.clone())
+        .or_else(|| fltmc::registered_altitude(FILTER_SERVICE_NAME));
+    coexistence(filters, altitude.as_deref()).iter().map(ToString::to_string).collect()
+}
+
+#[cfg(target_os = "linux")]
+fn driver_status(config: &Config) -> String {
+    let bpf_object = config.get_path(Param::UtilsPath).join("owlyshield.bpf.o");
+    format!(
+        "Event source: {}\neBPF object: {} ({})\nWatched mounts: {}\n",
+        config.get_str(Param::LinuxEventSource),
+        bpf_object.display(),
+        if bpf_object.exists() { "present" } else { "missing" },
+        config.get_str(Param::WatchedMounts),
+    )
+}
+
+/// A minifilter, as listed by *fltmc filters*.
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+pub struct MiniFilter {
+    pub name: String,
+    pub instances: u32,
+    pub altitude: String,
+    pub frame: u32,
+}
+
+/// A problem of coexistence of the minifilter with the others.
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+pub enum Coexistence<'a> {
+    NotLoaded,
+    /// At the same altitude: their instances cannot attach to the same volumes
+    AltitudeConflict(&'a MiniFilter),
+    /// One of [KNOWN_FILTERS], with its issue
+    Incompatible(&'a MiniFilter, &'static str),
+}
+
+impl fmt::Display for Coexistence<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Coexistence::NotLoaded => write!(f, "{} is not loaded", FILTER_SERVICE_NAME),
+            Coexistence::AltitudeConflict(filter) => write!(
+                f,
+                "{} uses the same altitude {}: their instances cannot attach to the same volumes",
+                filter.name, filter.altitude
+            ),
+            Coexistence::Incompatible(filter, issue) => {
+                write!(f, "{} (altitude {}): {}", filter.name, filter.altitude, issue)
+            }
+        }
+    }
+}
+
+/// The problems of coexistence of the minifilter, at *altitude* if known, with the loaded
+/// *filters*.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn coexistence<'a>(filters: &'a [MiniFilter], altitude: Option<&str>) -> Vec<Coexistence<'a>> {
+    let mut problems = Vec::new();
+    if !filters.iter().any(|filter| filter.name.eq_ignore_ascii_case(FILTER_SERVICE_NAME)) {
+        problems.push(Coexistence::NotLoaded);
+    }
+    if let Some(altitude) = altitude {
+        let conflicts = driver_setup::conflicts(filters, FILTER_SERVICE_NAME, altitude);
+        problems.extend(conflicts.into_iter().map(Coexistence::AltitudeConflict));
+    }
+    for filter in filters {
+        if let Some((_, issue)) = KNOWN_FILTERS.iter().find(|(name, _)| name.eq_ignore_ascii_case(&filter.name)) {
+            problems.push(Coexistence::Incompatible(filter, issue));
+        }
+    }
+    problems
+}
+
+/// Parses the chained *FILTER_AGGREGATE_BASIC_INFORMATION* entries of *buffer*. The legacy
+/// filters are skipped.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_filters(buffer: &[u8]) -> Vec<MiniFilter> {
+    const FLTFL_AGGREGATE_INFO_IS_MINIFILTER: u32 = 1;
+    let mut filters = Vec::new();
+    let mut offset = 0;
+    while let Some(entry) = buffer.get(offset..) {
+        let u16_at = |at: usize| entry.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let u32_at = |at: usize| entry.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        // offset and length in bytes, from the start of the entry
+        let utf16_at = |at: Option<u16>, len: Option<u16>| {
+            let (at, len) = (at? as usize, len? as usize);
+            let chars: Vec<u16> = entry
+                .get(at..at + len)?
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            Some(String::from_utf16_lossy(&chars))
+        };
+        let (next, flags) = match (u32_at(0), u32_at(4)) {
+            (Some(next), Some(flags)) => (next, flags),
+            _ => break,
+        };
+        if flags & FLTFL_AGGREGATE_INFO_IS_MINIFILTER != 0 {
+            let filter = (|| {
+                Some(MiniFilter {
+                    frame: u32_at(8)?,
+                    instances: u32_at(12)?,
+                    name: utf16_at(u16_at(18), u16_at(16))?,
+                    altitude: utf16_at(u16_at(22), u16_at(20))?,
+                })
+            })();
+            filters.extend(filter);
+        }
+        if next == 0 {
+            break;
+        }
+        offset += next as usize;
+    }
+    filters
+}
+
+/// Enumeration of the minifilters with *fltlib*.
+#[cfg(windows)]
+pub mod fltmc {
+    use std::ffi::c_void;
+
+    use registry::{Hive, Security};
+
+    use crate::diag::{parse_filters, MiniFilter};
+
+    const FILTER_AGGREGATE_BASIC_INFORMATION: u32 = 1;
+    const ERROR_NO_MORE_ITEMS: i32 = 0x8007_0103_u32 as i32;
+    const ERROR_INSUFFICIENT_BUFFER: i32 = 0x8007_007A_u32 as i32;
+
+    #[link(name = "fltlib")]
+    extern "system" {
+        fn FilterFindFirst(class: u32, buffer: *mut c_void, size: u32, returned: *mut u32, find: *mut isize) -> i32;
+        fn FilterFindNext(find: isize, class: u32, buffer: *mut c_void, size: u32, returned: *mut u32) -> i32;
+        fn FilterFindClose(find: isize) -> i32;
+    }
+
+    /// Altitude of the default instance of *service*, in its registry key.
+    pub fn registered_altitude(service: &str) -> Option<String> {
+        let key = format!(r"SYSTEM\CurrentControlSet\Services\{}\Instances", service);
+        let value = |key: &str, name: &str| {
+            let value = Hive::LocalMachine.open(key, Security::Read).ok()?.value(name).ok()?.to_string();
+            Some(value.trim_matches(char::from(0)).to_string())
+        };
+        let instance = value(&key, "DefaultInstance")?;
+        value(&format!(r"{}\{}", key, instance), "Altitude")
+    }
+
+    /// Needs the administrator rights.
+    pub fn filters() -> Result<Vec<MiniFilter>, String> {
+        let mut filters = Vec::new();
+        let mut buffer: Vec<u8> = vec![0; 4096];
+        let mut returned: u32 = 0;
+        let mut find: isize = 0;
+        let res = unsafe {
+            FilterFindFirst(
+                FILTER_AGGREGATE_BASIC_INFORMATION,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len() as u32,
+                &mut returned,
+                &mut find,
+            )
+        };
+        if res == ERROR_NO_MORE_ITEMS {
+            return Ok(filters);
+        }
+        if res < 0 {
+            return Err(format!("FilterFindFirst failed: {:#x}", res));
+        }
+        let res = loop {
+            filters.extend(parse_filters(&buffer[..returned as usize]));
+            let mut next = |buffer: &mut Vec<u8>, returned: &mut u32| unsafe {
+                FilterFindNext(
+                    find,
+                    FILTER_AGGREGATE_BASIC_INFORMATION,
+                    buffer.as_mut_ptr() as *mut c_void,
+                    buffer.len() as u32,
+                    returned,
+                )
+            };
+            let mut res = next(&mut buffer, &mut returned);
+            if res == ERROR_INSUFFICIENT_BUFFER {
+                buffer.resize(returned as usize, 0);
+                res = next(&mut buffer, &mut returned);
+            }
+            if res < 0 {
+                break res;
+            }
+        };
+        unsafe { FilterFindClose(find) };
+        if res == ERROR_NO_MORE_ITEMS {
+            Ok(filters)
+        } else {
+            Err(format!("FilterFindNext failed: {:#x}", res))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diag::{coexistence, parse_filters, redact, Coexistence, MiniFilter, REDACTED};
+
+    #[test]
+    fn secrets_should_be_redacted() {
+        let content = "kill_policy = \"KILL\"\napi_key = \"abcd\"\n# comment\nHeartbeat_Token=xyz\n";
+        let redacted = redact(content);
+        assert!(redacted.contains("kill_policy = \"KILL\""));
+        assert!(redacted.contains("# comment"));
+        assert!(!redacted.contains("abcd") && !redacted.contains("xyz"));
+        assert_eq!(redacted.matches(REDACTED).count(), 2);
+    }
+
+    #[test]
+    fn coexistence_problems_should_be_found() {
+        let filter = |name: &str, altitude: &str| MiniFilter {
+            name: name.to_string(),
+            instances: 2,
+            altitude: altitude.to_string(),
+            frame: 0,
+        };
+        let filters = vec![
+            filter("WdFilter", "328010"),
+            filter("OwlyshieldRansomFilter", "378781"),
+            filter("mbamfarflt", "328800"),
+            filter("bindflt", "409800"),
+        ];
+        assert_eq!(
+            coexistence(&filters, Some("378781")),
+            vec![Coexistence::Incompatible(&filters[2], "Malwarebytes Anti-Ransomware, may suspend the same processes")]
+        );
+
+        let filters = vec![filter("WdFilter", "328010"), filter("Other", "378781")];
+        let problems = coexistence(&filters, Some("378781"));
+        assert_eq!(problems, vec![Coexistence::NotLoaded, Coexistence::AltitudeConflict(&filters[1])]);
+        assert!(problems[1].to_string().starts_with("Other uses the same altitude 378781"));
+        assert_eq!(coexistence(&filters, None), vec![Coexistence::NotLoaded]);
+    }
+
+    #[test]
+    fn minifilter_should_be_parsed() {
+        let utf16 = |s: &str| s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect::<Vec<u8>>();
+        let entry = |next: u32, flags: u32, name: &str| {
+            let (name, altitude) = (utf16(name), utf16("371100"));
+            let mut entry: Vec<u8> = Vec::new();
+            for v in &[next, flags, 0, 3] {
+                entry.extend(&v.to_le_bytes());
+            }
+            for v in &[name.len() as u16, 24, altitude.len() as u16, 24 + name.len() as u16] {
+                entry.extend(&v.to_le_bytes());
+            }
+            entry.extend(&name);
+            entry.extend(&altitude);
+            entry
+        };
+        let legacy = entry(0, 2, "Legacy");
+        let mut buffer = entry(0, 1, "OwlyshieldRansomFilter");
+        let next = buffer.len() as u32;
+        buffer[..4].copy_from_slice(&next.to_le_bytes());
+        buffer.extend(&legacy);
+        assert_eq!(
+            parse_filters(&buffer),
+            vec![MiniFilter {
+                name: String::from("OwlyshieldRansomFilter"),
+                instances: 3,
+                altitude: String::from("371100"),
+                frame: 0,
+            }]
+        );
+    }
+}
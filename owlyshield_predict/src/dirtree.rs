@@ -0,0 +1,118 @@
+//! Shape of the directory tree touched by a gid.
+//!
+//! A ransomware walks the tree (user profile, shares, other drives) and updates files in many
+//! directories far apart, whereas an application rewriting many files usually stays in one
+//! folder (its cache, a project, a database). [DirTree] tracks, for the directories with files
+//! updated:
+//! * the number of distinct top-level directories (*C:\Users*, *D:\Data*...);
+//! * the maximum depth;
+//! * the depth of their deepest common ancestor, and how many times it moved up (its drift).
+//!
+//! Each update is O(depth of the directory).
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::sketch::BoundedSet;
+
+#[derive(Debug)]
+pub struct DirTree {
+    top_level: BoundedSet<PathBuf>,
+    max_depth: usize,
+    /// Deepest common ancestor of the directories seen, None before the first one
+    ancestor: Option<PathBuf>,
+    ancestor_drift: usize,
+    /// Last directory added, to skip the repeated updates in a directory
+    last: Option<Arc<str>>,
+}
+
+impl DirTree {
+    pub fn new(cap: usize) -> DirTree {
+        DirTree {
+            top_level: BoundedSet::new(cap),
+            max_depth: 0,
+            ancestor: None,
+            ancestor_drift: 0,
+            last: None,
+        }
+    }
+
+    pub fn add(&mut self, dir: &Arc<str>) {
+        if self.last.as_ref().map_or(false, |last| Arc::ptr_eq(last, dir) || **last == **dir) {
+            return;
+        }
+        self.last = Some(Arc::clone(dir));
+        let path = Path::new(&**dir);
+
+        let mut top_level = PathBuf::new();
+        for component in path.components() {
+            top_level.push(component);
+            if let Component::Normal(_) = component {
+                break;
+            }
+        }
+        self.top_level.insert(top_level);
+
+        self.max_depth = self.max_depth.max(depth(path));
+
+        match self.ancestor.as_mut() {
+            None => self.ancestor = Some(path.to_path_buf()),
+            Some(ancestor) => {
+                while !path.starts_with(&ancestor) {
+                    if !ancestor.pop() {
+                        // another drive
+                        *ancestor = PathBuf::new();
+                    }
+                    self.ancestor_drift += 1;
+                }
+            }
+        }
+    }
+
+    pub fn top_level_count(&self) -> usize {
+        self.top_level.len()
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn common_ancestor_depth(&self) -> usize {
+        self.ancestor.as_deref().map_or(0, depth)
+    }
+
+    pub fn ancestor_drift(&self) -> usize {
+        self.ancestor_drift
+    }
+}
+
+/// Number of named components (the drive and root are not counted).
+fn depth(path: &Path) -> usize {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::dirtree::DirTree;
+
+    #[test]
+    fn traversal_should_drift_ancestor() {
+        let mut tree = DirTree::new(100);
+        for dir in [r"C:\Users\bob\Documents\a", r"C:\Users\bob\Documents\b"] {
+            tree.add(&Arc::from(dir));
+        }
+        assert_eq!(tree.common_ancestor_depth(), 3);
+        assert_eq!(tree.top_level_count(), 1);
+        for dir in [r"C:\Users\alice\Pictures", r"D:\Data\Sales\2021\Q1"] {
+            tree.add(&Arc::from(dir));
+        }
+        assert_eq!(tree.common_ancestor_depth(), 0);
+        assert_eq!(tree.max_depth(), 4);
+        assert_eq!(tree.top_level_count(), 2);
+        assert_eq!(tree.ancestor_drift(), 5);
+    }
+}
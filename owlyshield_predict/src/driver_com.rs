@@ -1,28 +1,74 @@
 //! Low-level communication with the minifilter.
+//!
+//! The [shared_def::IOMessage] and the operations codes are the model of the i/o events on all
+//! platforms (see [crate::iosource]). The [Driver] itself is only available on Windows.
 
+#[cfg(windows)]
 use core::ffi::c_void;
+#[cfg(windows)]
+use std::io;
+#[cfg(windows)]
 use std::mem;
+#[cfg(windows)]
 use std::os::raw::*;
+#[cfg(windows)]
 use std::ptr;
+#[cfg(windows)]
+use std::time::Duration;
 
+#[cfg(windows)]
 use bindings::Windows::Win32::Foundation::CloseHandle;
+#[cfg(windows)]
 use bindings::Windows::Win32::Foundation::{HANDLE, PWSTR};
+#[cfg(windows)]
 use bindings::Windows::Win32::Storage::InstallableFileSystems::{
     FilterConnectCommunicationPort, FilterSendMessage,
 };
+#[cfg(windows)]
 use sysinfo::{get_current_pid, Pid};
+#[cfg(windows)]
+use tracing::{debug, error};
+#[cfg(windows)]
 use wchar::wchar_t;
+#[cfg(windows)]
 use widestring::U16CString;
+#[cfg(windows)]
 use windows::HRESULT;
 
-use crate::driver_com::shared_def::ReplyIrp;
+#[cfg(windows)]
+use crate::capture::CaptureRequest;
+#[cfg(windows)]
+use crate::config::Config;
+#[cfg(windows)]
+use crate::connectors::connector::Connectors;
+#[cfg(windows)]
+use crate::driver_com::shared_def::{
+    AggregatesReply, CaptureMessage, FileChangeInfo, IOMessage, IrpAggregate, SpoofedParent, TamperAttempt, MAX_AGGREGATES,
+    MAX_CAPTURE_PATH, MAX_SPOOFED_PARENTS, MAX_TAMPER_ATTEMPTS,
+};
 use crate::driver_com::IrpMajorOp::{IrpCreate, IrpNone, IrpRead, IrpSetInfo, IrpWrite};
-
+#[cfg(windows)]
+use crate::driver_reply;
+#[cfg(windows)]
+use crate::driver_reply::DriverMsg;
+#[cfg(windows)]
+use crate::backpressure::Aggregates;
+#[cfg(windows)]
+use crate::iosource::{IoEventSource, IoSourceError};
+#[cfg(windows)]
+use crate::process::FileId;
+#[cfg(windows)]
+use crate::selfprotect;
+
+/// Path of a [DriverComMessage] (*COM_MESSAGE*), NUL terminated: only for the scan directories and
+/// the system root, the paths of the events are sized by the minifilter (see [driver_reply]).
+#[cfg(windows)]
 type BufPath = [wchar_t; 520];
 
 /// The usermode app (this app) can send several messages types to the driver. See [DriverComMessageType]
 /// for details.
 /// Depending on the message type, the *pid*, *gid* and *path* fields can be optional.
+#[cfg(windows)]
 #[derive(Debug)]
 #[repr(C)]
 struct DriverComMessage {
@@ -37,6 +83,7 @@ struct DriverComMessage {
 
 /// A minifilter is identified by a port (know in advance), like a named pipe used for communication,
 /// and a handle, retrieved by [Self::open_kernel_driver_com].
+#[cfg(windows)]
 #[derive(Debug)]
 pub struct Driver {
     com_port_name: *mut u16,
@@ -44,17 +91,48 @@ pub struct Driver {
 }
 
 /// Messages types to send directives to the minifilter, by using te [DriverComMessage] struct.
+#[cfg(windows)]
 enum DriverComMessageType {
     /// Not used yet. The minifilter has the ability to monitor a specific part of the fs.
     MessageAddScanDirectory,
     /// Not used yet. The minifilter has the ability to monitor a specific part of the fs.
     MessageRemScanDirectory,
-    /// Ask for a [ReplyIrp], if any available.
+    /// Ask for the pending [DriverMsg]s, if any available.
     MessageGetOps,
     /// Set this app pid to the minifilter (related IRPs will be ignored);
     MessageSetPid,
     /// Instruct the minifilter to kill all pids in the family designated by a given gid.
     MessageKillGid,
+    /// Add a pid to the list of processes protected by the minifilter (see [crate::selfprotect]).
+    MessageAddProtectedPid,
+    /// Ask for the [shared_def::TamperAttempt] recorded since the last call.
+    MessageGetTamperAttempts,
+    /// Instruct the minifilter to stop forwarding the i/o of a benign gid, until it ends.
+    MessageMuteGid,
+    /// Tell the minifilter the depth of the queue of this app, and whether to aggregate the irps.
+    MessageSetBackpressure,
+    /// Ask for the [shared_def::IrpAggregate]s recorded since the last call.
+    MessageGetAggregates,
+    /// Ask for the [shared_def::SpoofedParent]s recorded since the last call.
+    MessageGetSpoofedParents,
+    /// Instruct the minifilter to start or stop sending the [shared_def::CaptureMessage]s of a gid.
+    MessageCaptureGid,
+}
+
+// The port handle can be used by several threads at once (see crate::pipeline) and
+// com_port_name is never modified.
+#[cfg(windows)]
+unsafe impl Send for Driver {}
+#[cfg(windows)]
+unsafe impl Sync for Driver {}
+
+/// The minifilter accepts only one connection: the port must be closed if the protection loop
+/// is restarted by the [crate::watchdog].
+#[cfg(windows)]
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.close_kernel_communication();
+    }
 }
 
 /// See [shared_def::IOMessage] struct and [this doc](https://docs.microsoft.com/en-us/windows-hardware/drivers/kernel/irp-major-function-codes).
@@ -88,6 +166,7 @@ impl IrpMajorOp {
     }
 }
 
+#[cfg(windows)]
 impl Driver {
     /// Can be used to properly close the communication (and unregister) with the minifilter.
     /// If this fn is not used and the program has stopped, the handle is automatically closed,
@@ -143,10 +222,11 @@ impl Driver {
         Ok(res)
     }
 
-    /// Ask the driver for a [ReplyIrp], if any. This is a low-level function and the returned object
-    /// uses C pointers. Managing C pointers requires a special care, because of the Rust timelines.
-    /// [ReplyIrp] is optional since the minifilter returns null if there is no new activity.
-    pub fn get_irp(&self, vecnew: &mut Vec<u8>) -> Option<ReplyIrp> {
+    /// Ask the driver for its pending messages, written in *buffer* (of [driver_reply::BUFFER_SIZE]
+    /// bytes) and parsed by [driver_reply::parse]. A malformed reply is logged and dropped.
+    /// Returns None if the minifilter returned nothing.
+    /// Fails if the message cannot be sent, for instance if the port has been closed.
+    pub fn get_irp(&self, buffer: &mut [u8]) -> Result<Option<Vec<DriverMsg>>, windows::Error> {
         let mut get_irp_msg = Driver::build_irp_msg(
             DriverComMessageType::MessageGetOps,
             get_current_pid().unwrap(),
@@ -159,20 +239,22 @@ impl Driver {
                 self.handle,
                 ptr::addr_of_mut!(get_irp_msg) as *mut c_void,
                 mem::size_of::<DriverComMessage>() as c_ulong,
-                vecnew.as_ptr() as *mut c_void,
-                65536 as u32,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len() as u32,
                 ptr::addr_of_mut!(tmp) as *mut u32,
-            )
-            .expect("Cannot get driver message from driver");
+            )?;
         }
         if tmp != 0 {
-            let reply_irp: shared_def::ReplyIrp;
-            unsafe {
-                reply_irp = std::ptr::read_unaligned(vecnew.as_ptr() as *const ReplyIrp);
-            }
-            return Some(reply_irp);
+            // the pointers of the reply are addresses in buffer
+            return match driver_reply::parse(buffer, buffer.as_ptr() as u64) {
+                Ok(drivermsgs) => Ok(Some(drivermsgs)),
+                Err(e) => {
+                    error!("Malformed reply of the minifilter: {}", e);
+                    Ok(Some(Vec::new()))
+                }
+            };
         }
-        None
+        Ok(None)
     }
 
     /// Ask the minifilter to kill all pids related to the given *gid*. Pids are killed in drivermode
@@ -203,11 +285,221 @@ impl Driver {
         return Ok(hres);
     }
 
+    /// Protects *pid* against termination, suspension and memory writes by other processes.
+    /// This app pid is protected when it registers with [Self::driver_set_app_pid].
+    pub fn add_protected_pid(&self, pid: u32) -> Result<(), windows::Error> {
+        let mut msg = Driver::build_irp_msg(DriverComMessageType::MessageAddProtectedPid, pid as Pid, 0, "");
+        let mut tmp: u32 = 0;
+        unsafe {
+            FilterSendMessage(
+                self.handle,
+                ptr::addr_of_mut!(msg) as *mut c_void,
+                mem::size_of::<DriverComMessage>() as c_ulong,
+                ptr::null_mut(),
+                0,
+                &mut tmp as *mut u32,
+            )
+        }
+    }
+
+    /// Stops the [IOMessage]s of *gid*. Fails if the minifilter does not know the gid anymore.
+    pub fn try_mute(&self, gid: c_ulonglong) -> Result<(), windows::Error> {
+        let mut msg = Driver::build_irp_msg(DriverComMessageType::MessageMuteGid, 0, gid, "");
+        let mut tmp: u32 = 0;
+        unsafe {
+            FilterSendMessage(
+                self.handle,
+                ptr::addr_of_mut!(msg) as *mut c_void,
+                mem::size_of::<DriverComMessage>() as c_ulong,
+                ptr::null_mut(),
+                0,
+                &mut tmp as *mut u32,
+            )
+        }
+    }
+
+    /// Returns the tamper attempts blocked by the minifilter since the last call.
+    pub fn get_tamper_attempts(&self) -> Result<Vec<TamperAttempt>, windows::Error> {
+        let mut msg = Driver::build_irp_msg(
+            DriverComMessageType::MessageGetTamperAttempts,
+            get_current_pid().unwrap(),
+            0,
+            "",
+        );
+        let mut buf: Vec<TamperAttempt> = vec![TamperAttempt::default(); MAX_TAMPER_ATTEMPTS];
+        let mut res_size: u32 = 0;
+        unsafe {
+            FilterSendMessage(
+                self.handle,
+                ptr::addr_of_mut!(msg) as *mut c_void,
+                mem::size_of::<DriverComMessage>() as c_ulong,
+                buf.as_mut_ptr() as *mut c_void,
+                (MAX_TAMPER_ATTEMPTS * mem::size_of::<TamperAttempt>()) as u32,
+                ptr::addr_of_mut!(res_size) as *mut u32,
+            )?;
+        }
+        buf.truncate(res_size as usize / mem::size_of::<TamperAttempt>());
+        Ok(buf)
+    }
+
+    /// Returns the processes created with another parent than their creator since the last call.
+    pub fn get_spoofed_parents(&self) -> Result<Vec<SpoofedParent>, windows::Error> {
+        let mut msg = Driver::build_irp_msg(
+            DriverComMessageType::MessageGetSpoofedParents,
+            get_current_pid().unwrap(),
+            0,
+            "",
+        );
+        let mut buf: Vec<SpoofedParent> = vec![SpoofedParent::default(); MAX_SPOOFED_PARENTS];
+        let mut res_size: u32 = 0;
+        unsafe {
+            FilterSendMessage(
+                self.handle,
+                ptr::addr_of_mut!(msg) as *mut c_void,
+                mem::size_of::<DriverComMessage>() as c_ulong,
+                buf.as_mut_ptr() as *mut c_void,
+                (MAX_SPOOFED_PARENTS * mem::size_of::<SpoofedParent>()) as u32,
+                ptr::addr_of_mut!(res_size) as *mut u32,
+            )?;
+        }
+        buf.truncate(res_size as usize / mem::size_of::<SpoofedParent>());
+        Ok(buf)
+    }
+
+    /// Reports the number of *queued* messages of this app to the minifilter, which aggregates the
+    /// irps if *aggregate*.
+    pub fn set_backpressure(&self, aggregate: bool, queued: usize) -> Result<(), windows::Error> {
+        let mut msg = Driver::build_irp_msg(DriverComMessageType::MessageSetBackpressure, aggregate as Pid, queued as u64, "");
+        let mut tmp: u32 = 0;
+        unsafe {
+            FilterSendMessage(
+                self.handle,
+                ptr::addr_of_mut!(msg) as *mut c_void,
+                mem::size_of::<DriverComMessage>() as c_ulong,
+                ptr::null_mut(),
+                0,
+                &mut tmp as *mut u32,
+            )
+        }
+    }
+
+    /// Returns the irps aggregated by the minifilter since the last call.
+    pub fn get_aggregates(&self) -> Result<(AggregatesReply, Vec<IrpAggregate>), windows::Error> {
+        #[repr(C)]
+        struct Reply {
+            header: AggregatesReply,
+            aggregates: [IrpAggregate; MAX_AGGREGATES],
+        }
+        let mut msg = Driver::build_irp_msg(DriverComMessageType::MessageGetAggregates, get_current_pid().unwrap(), 0, "");
+        let mut reply = Box::new(Reply {
+            header: AggregatesReply::default(),
+            aggregates: [IrpAggregate::default(); MAX_AGGREGATES],
+        });
+        let mut res_size: u32 = 0;
+        unsafe {
+            FilterSendMessage(
+                self.handle,
+                ptr::addr_of_mut!(msg) as *mut c_void,
+                mem::size_of::<DriverComMessage>() as c_ulong,
+                ptr::addr_of_mut!(*reply) as *mut c_void,
+                mem::size_of::<Reply>() as u32,
+                ptr::addr_of_mut!(res_size) as *mut u32,
+            )?;
+        }
+        let count = (reply.header.num_aggregates as usize).min(MAX_AGGREGATES);
+        Ok((reply.header, reply.aggregates[..count].to_vec()))
+    }
+
+    /// Starts or stops the [shared_def::CaptureMessage]s of *gid*. Fails if the minifilter does not
+    /// know the gid anymore.
+    pub fn set_capture(&self, gid: c_ulonglong, capture: bool) -> Result<(), windows::Error> {
+        let mut msg = Driver::build_irp_msg(DriverComMessageType::MessageCaptureGid, capture as Pid, gid, "");
+        let mut tmp: u32 = 0;
+        unsafe {
+            FilterSendMessage(
+                self.handle,
+                ptr::addr_of_mut!(msg) as *mut c_void,
+                mem::size_of::<DriverComMessage>() as c_ulong,
+                ptr::null_mut(),
+                0,
+                &mut tmp as *mut u32,
+            )
+        }
+    }
+
+    /// Waits *timeout* at most for a [shared_def::CaptureMessage], sent by the minifilter before a
+    /// write or a deletion by a captured gid, which waits for [Self::reply_capture].
+    pub fn get_capture_request(&self, timeout: Duration) -> io::Result<Option<CaptureRequest>> {
+        #[repr(C)]
+        struct Message {
+            header: ffi::MessageHeader,
+            request: CaptureMessage,
+        }
+        let mut message: Box<Message> = Box::new(unsafe { mem::zeroed() });
+        let received = unsafe {
+            let event = ffi::CreateEventW(ptr::null(), 1, 0, ptr::null());
+            if event == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut overlapped = ffi::Overlapped { event, ..Default::default() };
+            let mut transferred: u32 = 0;
+            let hr = ffi::FilterGetMessage(self.handle.0, &mut message.header, mem::size_of::<Message>() as u32, &mut overlapped);
+            let received = if hr < 0 && hr != ffi::E_IO_PENDING {
+                Err(io::Error::from_raw_os_error(hr & 0xFFFF))
+            } else if ffi::GetOverlappedResultEx(self.handle.0, &mut overlapped, &mut transferred, timeout.as_millis() as u32, 0) != 0 {
+                Ok(true)
+            } else if ffi::GetLastError() == ffi::WAIT_TIMEOUT {
+                // a message received in between is kept
+                ffi::CancelIoEx(self.handle.0, &mut overlapped);
+                Ok(ffi::GetOverlappedResult(self.handle.0, &mut overlapped, &mut transferred, 1) != 0)
+            } else {
+                Err(io::Error::last_os_error())
+            };
+            ffi::CloseHandle(event);
+            received?
+        };
+        if !received {
+            return Ok(None);
+        }
+        let request = &message.request;
+        let len = (request.path_length as usize).min(MAX_CAPTURE_PATH);
+        Ok(Some(CaptureRequest {
+            message_id: message.header.message_id,
+            gid: request.gid,
+            pid: request.pid,
+            file_id: FileId::new(request.file_id_vsn, &request.file_id_id),
+            path: driver_reply::decode_path(&request.path[..len]),
+            deleted: request.file_change == FileChangeInfo::FileChangeDeleteFile as u8,
+        }))
+    }
+
+    /// Lets the write or the deletion of a [shared_def::CaptureMessage] through.
+    pub fn reply_capture(&self, message_id: u64, captured: bool) -> io::Result<()> {
+        #[repr(C)]
+        struct Reply {
+            header: ffi::ReplyHeader,
+            captured: u8,
+        }
+        let reply = Reply {
+            header: ffi::ReplyHeader { status: 0, message_id },
+            captured: captured as u8,
+        };
+        // the header and the CAPTURE_REPLY, without the padding
+        let size = (mem::size_of::<ffi::ReplyHeader>() + 1) as u32;
+        let hr = unsafe { ffi::FilterReplyMessage(self.handle.0, &reply.header, size) };
+        if hr < 0 {
+            Err(io::Error::from_raw_os_error(hr & 0xFFFF))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Truncated at the first NUL and to fit with its own NUL, instead of panicking on a longer path.
     fn string_to_commessage_buffer(bufstr: &str) -> BufPath {
-        let temp = U16CString::from_str(&bufstr).unwrap();
         let mut buf: BufPath = [0; 520];
-        for (i, c) in temp.as_slice_with_nul().iter().enumerate() {
-            buf[i] = c.clone() as wchar_t;
+        let units = bufstr.encode_utf16().take_while(|c| *c != 0).take(buf.len() - 1);
+        for (i, c) in units.enumerate() {
+            buf[i] = c as wchar_t;
         }
         buf
     }
@@ -223,15 +515,145 @@ impl Driver {
     }
 }
 
+#[cfg(windows)]
+impl IoEventSource for Driver {
+    fn fetch(&self, events: &mut Vec<IOMessage>) -> Result<(), IoSourceError> {
+        let mut buffer = vec![0u8; driver_reply::BUFFER_SIZE];
+        match self.get_irp(&mut buffer) {
+            Ok(Some(drivermsgs)) => {
+                events.extend(drivermsgs.iter().map(IOMessage::from));
+                Ok(())
+            }
+            Ok(None) => Err(IoSourceError::Closed),
+            Err(e) => Err(IoSourceError::Receive(e.code().0 as i32)),
+        }
+    }
+
+    fn kill_gid(&self, gid: u64) -> Result<(), IoSourceError> {
+        match self.try_kill(gid) {
+            Ok(hres) if hres.is_ok() => Ok(()),
+            Ok(hres) => Err(IoSourceError::Kill(hres.0 as i32)),
+            Err(e) => Err(IoSourceError::Kill(e.code().0 as i32)),
+        }
+    }
+
+    fn mute_gid(&self, gid: u64) -> Result<(), IoSourceError> {
+        self.try_mute(gid).map_err(|e| IoSourceError::Mute(e.code().0 as i32))
+    }
+
+    fn report_tamper_attempts(&self, config: &Config, connectors: &Connectors) {
+        selfprotect::report_tamper_attempts(self, config, connectors);
+    }
+
+    fn set_backpressure(&self, aggregate: bool, queued: usize) -> Result<(), IoSourceError> {
+        self.set_backpressure(aggregate, queued).map_err(|e| IoSourceError::Receive(e.code().0 as i32))
+    }
+
+    fn aggregates(&self) -> Result<Aggregates, IoSourceError> {
+        match self.get_aggregates() {
+            Ok((header, aggregates)) => Ok(Aggregates {
+                aggregated: header.aggregated_irps,
+                dropped: header.dropped_irps,
+                aggregates,
+            }),
+            Err(e) => Err(IoSourceError::Receive(e.code().0 as i32)),
+        }
+    }
+
+    fn spoofed_parents(&self) -> Vec<SpoofedParent> {
+        self.get_spoofed_parents().unwrap_or_else(|e| {
+            error!("Cannot get spoofed parents: {}", e);
+            Vec::new()
+        })
+    }
+
+    fn capture_gid(&self, gid: u64, capture: bool) -> Result<(), IoSourceError> {
+        self.set_capture(gid, capture).map_err(|e| IoSourceError::Capture(e.code().0 as i32))
+    }
+
+    fn next_capture_request(&self, timeout: Duration) -> Option<CaptureRequest> {
+        self.get_capture_request(timeout).unwrap_or_else(|e| {
+            error!("Cannot get capture requests: {}", e);
+            std::thread::sleep(timeout);
+            None
+        })
+    }
+
+    fn reply_capture(&self, request: &CaptureRequest, captured: bool) {
+        // the minifilter stops waiting after CAPTURE_TIMEOUT_MS
+        if let Err(e) = self.reply_capture(request.message_id, captured) {
+            debug!(gid = request.gid, "Capture reply not delivered: {}", e);
+        }
+    }
+}
+
+/// The port functions of *fltlib* which are not in the bindings, to receive the messages of the
+/// minifilter with a timeout.
+#[cfg(windows)]
+mod ffi {
+    use core::ffi::c_void;
+
+    /// *HRESULT_FROM_WIN32(ERROR_IO_PENDING)*
+    pub const E_IO_PENDING: i32 = 0x8007_03E5_u32 as i32;
+    pub const WAIT_TIMEOUT: u32 = 258;
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct Overlapped {
+        pub internal: usize,
+        pub internal_high: usize,
+        pub offset: u32,
+        pub offset_high: u32,
+        pub event: isize,
+    }
+
+    /// *FILTER_MESSAGE_HEADER*
+    #[repr(C)]
+    pub struct MessageHeader {
+        pub reply_length: u32,
+        pub message_id: u64,
+    }
+
+    /// *FILTER_REPLY_HEADER*
+    #[repr(C)]
+    pub struct ReplyHeader {
+        pub status: i32,
+        pub message_id: u64,
+    }
+
+    #[link(name = "fltlib")]
+    extern "system" {
+        pub fn FilterGetMessage(port: isize, buffer: *mut MessageHeader, size: u32, overlapped: *mut Overlapped) -> i32;
+        pub fn FilterReplyMessage(port: isize, buffer: *const ReplyHeader, size: u32) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn CreateEventW(attributes: *const c_void, manual_reset: i32, initial_state: i32, name: *const u16) -> isize;
+        pub fn GetOverlappedResultEx(file: isize, overlapped: *mut Overlapped, transferred: *mut u32, millis: u32, alertable: i32) -> i32;
+        pub fn GetOverlappedResult(file: isize, overlapped: *mut Overlapped, transferred: *mut u32, wait: i32) -> i32;
+        pub fn CancelIoEx(file: isize, overlapped: *mut Overlapped) -> i32;
+        pub fn GetLastError() -> u32;
+        pub fn CloseHandle(handle: isize) -> i32;
+    }
+}
+
 /// Contains all definitions shared between this usermode app and the minifilter in order
-/// to communicate properly. Those are C-representation of structures sent or received from the minifilter.
+/// to communicate properly. The replies to *MessageGetOps* are parsed by [crate::driver_reply].
 pub mod shared_def {
-    use std::os::raw::{c_uchar, c_ulong, c_ulonglong, c_ushort};
+    #[cfg(windows)]
+    use std::os::raw::c_ulong;
+    use std::os::raw::{c_uchar, c_ulonglong};
     use std::path::PathBuf;
+    use std::time::SystemTime;
 
-    use bindings::Windows::Win32::Storage::FileSystem::FILE_ID_INFO;
     use serde::{Deserialize, Serialize};
-    use wchar::wchar_t;
+
+    use crate::clock;
+    use crate::cloudsync::SyncClient;
+    use crate::driver_reply::DriverMsg;
+    use crate::privileges::Privileges;
+    use crate::utils::extended_path;
 
     /// See [IOMessage] struct. Used with [crate::driver_com::IrpMajorOp::IrpSetInfo]
     #[derive(FromPrimitive)]
@@ -246,6 +668,9 @@ pub mod shared_def {
         /// Temp file: created and deleted on close
         FileChangeDeleteNewFile,
         FileChangeOverwriteFile,
+        /// Write on a volume handle, or a raw disk handle found by [crate::rawdisk]. The path is
+        /// the device (*\Device\HarddiskVolume3*)
+        FileChangeRawDiskWrite,
     }
 
     /// See [IOMessage] struct.
@@ -257,28 +682,89 @@ pub mod shared_def {
         FileMovedOut,
     }
 
-    /// Low-level C-like object to communicate with the minifilter.
-    /// The minifilter yields ReplyIrp objects (retrieved by [crate::driver_com::Driver::get_irp] to manage the fixed size of the *data buffer.
-    /// In other words, a ReplyIrp is a collection of [CDriverMsg] with a capped size.
-    #[derive(Debug, Copy, Clone)]
+    /// Max number of [TamperAttempt] kept by the minifilter between two calls.
+    #[cfg(windows)]
+    pub const MAX_TAMPER_ATTEMPTS: usize = 64;
+
+    /// A process tried to open a protected process with a dangerous access (terminate, suspend,
+    /// write memory...). The minifilter removed those rights from the handle.
+    #[cfg(windows)]
+    #[derive(Debug, Default, Copy, Clone)]
+    #[repr(C)]
+    pub struct TamperAttempt {
+        pub source_pid: c_ulong,
+        pub target_pid: c_ulong,
+        /// Access mask asked, before it was stripped.
+        pub desired_access: c_ulong,
+    }
+
+    /// Max number of [SpoofedParent] kept by the minifilter between two calls.
+    #[cfg(windows)]
+    pub const MAX_SPOOFED_PARENTS: usize = 64;
+
+    /// A process was created with another parent than its creator, see [crate::integrity].
+    #[derive(Debug, Default, Copy, Clone)]
+    #[repr(C)]
+    pub struct SpoofedParent {
+        pub pid: u32,
+        /// Parent given at the creation.
+        pub parent_pid: u32,
+        pub creator_pid: u32,
+    }
+
+    /// Max length of the path of a [CaptureMessage], in UTF-16 units (*MAX_FILE_NAME_LENGTH*).
+    #[cfg(windows)]
+    pub const MAX_CAPTURE_PATH: usize = 520;
+
+    /// Sent by the minifilter, after a *FILTER_MESSAGE_HEADER*, before a write or a deletion by a
+    /// captured gid, see [crate::capture].
+    #[cfg(windows)]
+    #[derive(Copy, Clone)]
+    #[repr(C)]
+    pub struct CaptureMessage {
+        pub gid: c_ulonglong,
+        pub file_id_vsn: c_ulonglong,
+        pub file_id_id: [u8; 16],
+        pub pid: u32,
+        /// *FILE_CHANGE_WRITE* or *FILE_CHANGE_DELETE_FILE*
+        pub file_change: u8,
+        /// In UTF-16 units
+        pub path_length: u16,
+        pub path: [u16; MAX_CAPTURE_PATH],
+    }
+
+    /// Max number of [IrpAggregate] kept by the minifilter between two calls.
+    #[cfg(windows)]
+    pub const MAX_AGGREGATES: usize = 256;
+
+    /// The irps of a pid summarized by the minifilter instead of sent as [IOMessage]s, while this
+    /// app falls behind (see [crate::backpressure]).
+    #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
     #[repr(C)]
-    pub struct ReplyIrp {
-        /// The size od the collection.
-        pub data_size: c_ulonglong,
-        /// The C pointer to the buffer containinf the [CDriverMsg] events.
-        pub data: *const CDriverMsg,
-        /// The number of different operations in this collection.
-        pub num_ops: u64,
-    }
-
-    /// This class is the straight Rust translation of the Win32 API [UNICODE_STRING](https://docs.microsoft.com/en-us/windows/win32/api/ntdef/ns-ntdef-_unicode_string),
-    /// returned by the driver.
-    #[derive(Debug, Copy, Clone)]
+    pub struct IrpAggregate {
+        pub gid: c_ulonglong,
+        pub bytes_read: c_ulonglong,
+        pub bytes_written: c_ulonglong,
+        pub pid: u32,
+        /// Count by [crate::driver_com::IrpMajorOp]
+        pub ops: [u32; 6],
+        /// Count by [FileChangeInfo]
+        pub file_changes: [u32; 10],
+        /// Writes with an entropy above 7
+        pub high_entropy_writes: u32,
+    }
+
+    /// Header of the reply to *MessageGetAggregates*, followed by *num_aggregates*
+    /// [IrpAggregate]s. The counts are since the last call.
+    #[cfg(windows)]
+    #[derive(Debug, Default, Copy, Clone)]
     #[repr(C)]
-    pub struct UnicodeString {
-        pub length: c_ushort,
-        pub maximum_length: c_ushort,
-        pub buffer: *const wchar_t,
+    pub struct AggregatesReply {
+        pub aggregated_irps: c_ulonglong,
+        /// Irps lost: the aggregates were full
+        pub dropped_irps: c_ulonglong,
+        pub num_aggregates: c_ulong,
+        pub backpressure: u8,
     }
 
     /// Represents a driver message.
@@ -307,6 +793,7 @@ pub mod shared_def {
     ///     * FILE_CHANGE_DELETE_FILE (6)
     ///     * FILE_CHANGE_DELETE_NEW_FILE (7)
     ///     * FILE_CHANGE_OVERWRITE_FILE (8)
+    ///     * FILE_CHANGE_RAW_DISK_WRITE (9)
     /// - file_location_info: the driver has the ability to monitor specific directories only (feature currently not used):
     ///     * FILE_NOT_PROTECTED (0): Monitored dirs do not contained this file
     ///     * FILE_PROTECTED (1)
@@ -319,12 +806,12 @@ pub mod shared_def {
     #[derive(Debug, Clone, Serialize, Deserialize)]
     #[repr(C)]
     pub struct IOMessage {
-        pub extension: [wchar_t; 12],
+        pub extension: [u16; 12],
         pub file_id_vsn: c_ulonglong,
         pub file_id_id: [u8; 16],
         pub mem_sized_used: c_ulonglong,
         pub entropy: f64,
-        pub pid: c_ulong,
+        pub pid: u32,
         pub irp_op: c_uchar,
         pub is_entropy_calc: u8,
         pub file_change: c_uchar,
@@ -339,136 +826,51 @@ pub mod shared_def {
     ///
     /// - exepath: The path of the gid root process
     /// - exe_exists: Did the root exe file still existed (at the moment of this specific *DriverMessage* operation)?
+    /// - time: When the *DriverMessage* was seen by the minifilter, aligned on the clock of this app
+    ///   (see [crate::clock]), or when it was received (None in records made before it was added)
+    /// - sync_client: The cloud client synchronizing the file, see [crate::cloudsync]
+    /// - remote: Is the file on a network share, see [crate::netshare]?
+    /// - driver_gid: The gid given by the driver, when merged into another one, see [crate::gidmerge]
+    /// - privileges: The privileges enabled by the root of the gid, when probed, see [crate::privileges]
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct RuntimeFeatures {
         pub exepath: PathBuf,
         pub exe_still_exists: bool,
-    }
-
-    /// The C object returned by the minifilter, available through [ReplyIrp].
-    /// It is low level and use C pointers logic which is
-    /// not always compatible with RUST (in particular the lifetime of *next). That's why we convert
-    /// it asap to a plain Rust [IOMessage] object.
-    /// ```next``` is null (0x0) when there is no [IOMessage] remaining
-    #[derive(Debug, Copy, Clone)]
-    #[repr(C)]
-    pub struct CDriverMsg {
-        pub extension: [wchar_t; 12],
-        pub file_id: FILE_ID_INFO,
-        pub mem_sized_used: c_ulonglong,
-        pub entropy: f64,
-        pub pid: c_ulong,
-        pub irp_op: c_uchar,
-        pub is_entropy_calc: u8,
-        pub file_change: c_uchar,
-        pub file_location_info: c_uchar,
-        pub filepath: UnicodeString,
-        pub gid: c_ulonglong,
-        /// null (0x0) when there is no [IOMessage] remaining
-        pub next: *const CDriverMsg,
-    }
-
-    /// To iterate easily over a collection of [IOMessage] received from the minifilter, before they
-    /// are converted to [IOMessage]
-    pub struct CDriverMsgs<'a> {
-        drivermsgs: Vec<&'a CDriverMsg>,
-        index: usize,
-    }
-
-    impl UnicodeString {
-        pub fn to_string(&self) -> String {
-            unsafe {
-                let str_slice = std::slice::from_raw_parts(self.buffer, self.length as usize);
-                let mut first_zero_index = 0;
-                for (i, c) in str_slice.iter().enumerate() {
-                    if *c == 0 {
-                        first_zero_index = i;
-                        break;
-                    }
-                }
-                String::from_utf16_lossy(&str_slice[..first_zero_index])
-            }
-        }
-
-        /// Get the file path from the UnicodeString path and the extension returned by the driver.
-        pub fn to_string_ext(&self, extension: [wchar_t; 12]) -> String {
-            unsafe {
-                let str_slice = std::slice::from_raw_parts(self.buffer, self.length as usize);
-                let mut first_zero_index = 0;
-                let mut last_dot_index = 0;
-                let mut first_zero_index_ext = 0;
-
-                // Filepath
-                for (i, c) in str_slice.iter().enumerate() {
-                    if *c == 46 {
-                        last_dot_index = i + 1;
-                    }
-                    if *c == 0 {
-                        first_zero_index = i;
-                        break;
-                    }
-                }
-
-                if first_zero_index_ext > 0 && last_dot_index > 0 {
-                    // Extension
-                    for (i, c) in extension.iter().enumerate() {
-                        if *c == 0 {
-                            first_zero_index_ext = i;
-                            break;
-                        } else {
-                            if *c != str_slice[last_dot_index + i] {
-                                first_zero_index_ext = 0;
-                                break;
-                            }
-                        }
-                    }
-                    String::from_utf16_lossy(&[&str_slice[..last_dot_index], &extension[..first_zero_index_ext]].concat())
-                } else {
-                    String::from_utf16_lossy(&str_slice[..first_zero_index])
-                }
-            }
-        }
-    }
-
-    impl ReplyIrp {
-        /// Iterate through ```self.data``` and returns the collection of [CDriverMsg]
-        fn unpack_drivermsg(&self) -> Vec<&CDriverMsg> {
-            let mut res = vec![];
-            unsafe {
-                let mut msg = &*self.data;
-                res.push(msg);
-                for _ in 0..(self.num_ops) {
-                    if msg.next.is_null() {
-                        break;
-                    }
-                    msg = &*msg.next;
-                    res.push(msg);
-                }
-            }
-            res
-        }
+        #[serde(default)]
+        pub time: Option<SystemTime>,
+        #[serde(default)]
+        pub sync_client: Option<SyncClient>,
+        #[serde(default)]
+        pub remote: bool,
+        #[serde(default)]
+        pub driver_gid: Option<u64>,
+        #[serde(default)]
+        pub privileges: Option<Privileges>,
     }
 
     impl IOMessage {
-        pub fn from(c_drivermsg: &CDriverMsg) -> IOMessage {
+        pub fn from(drivermsg: &DriverMsg) -> IOMessage {
             IOMessage {
-                extension: c_drivermsg.extension,
-                file_id_vsn: c_drivermsg.file_id.VolumeSerialNumber,
-                file_id_id: c_drivermsg.file_id.FileId.Identifier,
-                mem_sized_used: c_drivermsg.mem_sized_used,
-                entropy: c_drivermsg.entropy,
-                pid: c_drivermsg.pid,
-                irp_op: c_drivermsg.irp_op,
-                is_entropy_calc: c_drivermsg.is_entropy_calc,
-                file_change: c_drivermsg.file_change,
-                file_location_info: c_drivermsg.file_location_info,
-                filepathstr: c_drivermsg.filepath.to_string_ext(c_drivermsg.extension),
-                gid: c_drivermsg.gid,
-                runtime_features: RuntimeFeatures::new(),
-                file_size: match PathBuf::from(&c_drivermsg.filepath.to_string_ext(c_drivermsg.extension)).metadata() {
+                extension: drivermsg.extension,
+                file_id_vsn: drivermsg.file_id_vsn,
+                file_id_id: drivermsg.file_id_id,
+                mem_sized_used: drivermsg.mem_sized_used,
+                entropy: drivermsg.entropy,
+                pid: drivermsg.pid,
+                irp_op: drivermsg.irp_op,
+                is_entropy_calc: drivermsg.is_entropy_calc,
+                file_change: drivermsg.file_change,
+                file_location_info: drivermsg.file_location_info,
+                filepathstr: drivermsg.filepath.clone(),
+                gid: drivermsg.gid,
+                runtime_features: RuntimeFeatures {
+                    time: Some(clock::align(drivermsg.timestamp, SystemTime::now())),
+                    ..RuntimeFeatures::new()
+                },
+                file_size: match extended_path(&PathBuf::from(&drivermsg.filepath)).metadata() {
                     Ok(f) => f.len() as i64,
-                    Err(e) => -1,
-                }
+                    Err(_) => -1,
+                },
             }
         }
     }
@@ -478,29 +880,11 @@ pub mod shared_def {
             RuntimeFeatures {
                 exepath: PathBuf::new(),
                 exe_still_exists: true,
-            }
-        }
-    }
-
-    impl CDriverMsgs<'_> {
-        pub fn new(irp: &ReplyIrp) -> CDriverMsgs {
-            CDriverMsgs {
-                drivermsgs: irp.unpack_drivermsg(),
-                index: 0,
-            }
-        }
-    }
-
-    impl Iterator for CDriverMsgs<'_> {
-        type Item = CDriverMsg;
-
-        fn next(&mut self) -> Option<Self::Item> {
-            if self.index == self.drivermsgs.len() {
-                None
-            } else {
-                let res = *self.drivermsgs[self.index];
-                self.index += 1;
-                Some(res)
+                time: Some(SystemTime::now()),
+                sync_client: None,
+                remote: false,
+                driver_gid: None,
+                privileges: None,
             }
         }
     }
@@ -0,0 +1,293 @@
+//! Parser of the replies of the minifilter to *MessageGetOps*.
+//!
+//! The reply is written by the minifilter in the buffer of the agent: a *RWD_REPLY_IRPS* header,
+//! then the *DRIVER_MESSAGE*s, each followed by its file path (see *SharedDefs.h*). The messages
+//! are chained by their *next* pointer and point to their path, with addresses in the buffer.
+//!
+//! Instead of dereferencing these pointers, [parse] turns them into offsets in the buffer and
+//! checks them: a malformed reply is an error, and cannot make the agent read out of its buffer.
+//! Only the standard library is used, so that the fuzz targets (*fuzz/*) include this file.
+
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/// Size of the buffer of a reply (*MAX_COMM_BUFFER_SIZE*), checked by the minifilter. Holds at
+/// least a message with a path of [MAX_PATH_LENGTH].
+pub const BUFFER_SIZE: usize = 0x20000;
+/// Size of the *RWD_REPLY_IRPS* header, at the start of the buffer.
+pub const HEADER_SIZE: usize = 24;
+/// Size of a *DRIVER_MESSAGE*, without its path.
+pub const MSG_SIZE: usize = 112;
+/// Max length of a path in UTF-16 units (*MAX_PATH_SIZE*), the max of a *UNICODE_STRING*.
+pub const MAX_PATH_LENGTH: usize = 32767;
+
+// Offsets in the header
+const HEADER_DATA: usize = 8;
+const HEADER_NUM_OPS: usize = 16;
+
+// Offsets in a DRIVER_MESSAGE (x64 layout)
+const MSG_EXTENSION: usize = 0;
+const MSG_FILE_ID_VSN: usize = 24;
+const MSG_FILE_ID_ID: usize = 32;
+const MSG_MEM_SIZED_USED: usize = 48;
+const MSG_ENTROPY: usize = 56;
+const MSG_PID: usize = 64;
+const MSG_IRP_OP: usize = 68;
+const MSG_IS_ENTROPY_CALC: usize = 69;
+const MSG_FILE_CHANGE: usize = 70;
+const MSG_FILE_LOCATION_INFO: usize = 71;
+const MSG_PATH_LENGTH: usize = 72;
+const MSG_PATH_MAXIMUM_LENGTH: usize = 74;
+const MSG_PATH_BUFFER: usize = 80;
+const MSG_GID: usize = 88;
+const MSG_NEXT: usize = 96;
+const MSG_TIMESTAMP: usize = 104;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplyError {
+    /// The buffer is shorter than the header.
+    Truncated(usize),
+    /// A pointer of the reply is not in the buffer, or the object it points to does not fit.
+    OutOfBounds { field: &'static str, address: u64 },
+}
+
+impl Display for ReplyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplyError::Truncated(len) => write!(f, "Reply of {} bytes, shorter than its header", len),
+            ReplyError::OutOfBounds { field, address } => {
+                write!(f, "Pointer {} of the reply out of its buffer: {:#x}", field, address)
+            }
+        }
+    }
+}
+
+impl Error for ReplyError {}
+
+/// A *DRIVER_MESSAGE*, with its path decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriverMsg {
+    pub extension: [u16; 12],
+    pub file_id_vsn: u64,
+    pub file_id_id: [u8; 16],
+    pub mem_sized_used: u64,
+    pub entropy: f64,
+    pub pid: u32,
+    pub irp_op: u8,
+    pub is_entropy_calc: u8,
+    pub file_change: u8,
+    pub file_location_info: u8,
+    pub filepath: String,
+    pub gid: u64,
+    /// FILETIME of the irp in the minifilter, 0 for the older ones
+    pub timestamp: i64,
+}
+
+/// Parses the reply in *buffer*, whose first byte is at the address *base* for the minifilter.
+/// At most *num_ops* messages are read, and the chain ends with a null *next*.
+pub fn parse(buffer: &[u8], base: u64) -> Result<Vec<DriverMsg>, ReplyError> {
+    if buffer.len() < HEADER_SIZE {
+        return Err(ReplyError::Truncated(buffer.len()));
+    }
+    let num_ops = read_u64(buffer, HEADER_NUM_OPS);
+    let mut address = read_u64(buffer, HEADER_DATA);
+    // a message takes at least MSG_SIZE bytes: more would be a loop in the chain
+    let max_msgs = ((buffer.len() - HEADER_SIZE) / MSG_SIZE) as u64;
+    let mut msgs = Vec::new();
+    while address != 0 && (msgs.len() as u64) < num_ops.min(max_msgs) {
+        let (msg, next) = parse_msg(buffer, base, address)?;
+        msgs.push(msg);
+        address = next;
+    }
+    Ok(msgs)
+}
+
+/// Parses the message at *address*, and returns it with the address of the next one.
+pub fn parse_msg(buffer: &[u8], base: u64, address: u64) -> Result<(DriverMsg, u64), ReplyError> {
+    let offset = offset(buffer, base, address, MSG_SIZE).ok_or(ReplyError::OutOfBounds { field: "data", address })?;
+    let msg = &buffer[offset..offset + MSG_SIZE];
+    let mut extension = [0u16; 12];
+    for (i, c) in extension.iter_mut().enumerate() {
+        *c = read_u16(msg, MSG_EXTENSION + 2 * i);
+    }
+    let drivermsg = DriverMsg {
+        extension,
+        file_id_vsn: read_u64(msg, MSG_FILE_ID_VSN),
+        file_id_id: msg[MSG_FILE_ID_ID..MSG_FILE_ID_ID + 16].try_into().unwrap(),
+        mem_sized_used: read_u64(msg, MSG_MEM_SIZED_USED),
+        entropy: f64::from_bits(read_u64(msg, MSG_ENTROPY)),
+        pid: u32::from_le_bytes(msg[MSG_PID..MSG_PID + 4].try_into().unwrap()),
+        irp_op: msg[MSG_IRP_OP],
+        is_entropy_calc: msg[MSG_IS_ENTROPY_CALC],
+        file_change: msg[MSG_FILE_CHANGE],
+        file_location_info: msg[MSG_FILE_LOCATION_INFO],
+        filepath: parse_path(buffer, base, msg)?,
+        gid: read_u64(msg, MSG_GID),
+        timestamp: read_u64(msg, MSG_TIMESTAMP) as i64,
+    };
+    Ok((drivermsg, read_u64(msg, MSG_NEXT)))
+}
+
+/// Decodes the *UNICODE_STRING* path of *msg*: its length is in bytes, and the minifilter copies
+/// at most [MAX_PATH_LENGTH] units. A null buffer is an empty path. See [decode_path].
+fn parse_path(buffer: &[u8], base: u64, msg: &[u8]) -> Result<String, ReplyError> {
+    let address = read_u64(msg, MSG_PATH_BUFFER);
+    let units = (read_u16(msg, MSG_PATH_LENGTH) as usize / 2).min(MAX_PATH_LENGTH);
+    if address == 0 || units == 0 {
+        return Ok(String::new());
+    }
+    let offset = offset(buffer, base, address, units * 2).ok_or(ReplyError::OutOfBounds { field: "filePath", address })?;
+    let path: Vec<u16> = buffer[offset..offset + units * 2]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    Ok(decode_path(&path))
+}
+
+/// Decodes the UTF-16 *units* of a path, whose length is given: the NULs at its end are padding,
+/// but not one inside, which NT allows. A high surrogate at the end is the half of a pair cut by
+/// the minifilter at [MAX_PATH_LENGTH] and is dropped; the other unpaired surrogates are replaced
+/// by U+FFFD.
+pub fn decode_path(units: &[u16]) -> String {
+    let mut len = units.iter().rposition(|c| *c != 0).map_or(0, |last| last + 1);
+    if len > 0 && (0xD800..0xDC00).contains(&units[len - 1]) {
+        len -= 1;
+    }
+    String::from_utf16_lossy(&units[..len])
+}
+
+/// Offset in *buffer* of the *size* bytes at *address*, if they fit.
+fn offset(buffer: &[u8], base: u64, address: u64, size: usize) -> Option<usize> {
+    let offset = usize::try_from(address.checked_sub(base)?).ok()?;
+    (offset.checked_add(size)? <= buffer.len()).then_some(offset)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Writes *msgs* as the minifilter would, for a buffer at the address *base*. Used by the tests,
+/// the benchmarks and the fuzz targets.
+pub fn encode(msgs: &[DriverMsg], base: u64) -> Vec<u8> {
+    let mut buffer = vec![0u8; HEADER_SIZE];
+    let mut previous_next: Option<usize> = None;
+    for drivermsg in msgs {
+        let start = buffer.len();
+        if let Some(next) = previous_next {
+            buffer[next..next + 8].copy_from_slice(&(base + start as u64).to_le_bytes());
+        }
+        let path: Vec<u16> = drivermsg.filepath.encode_utf16().take(MAX_PATH_LENGTH).collect();
+        let mut msg = [0u8; MSG_SIZE];
+        for (i, c) in drivermsg.extension.iter().enumerate() {
+            msg[MSG_EXTENSION + 2 * i..MSG_EXTENSION + 2 * i + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        msg[MSG_FILE_ID_VSN..MSG_FILE_ID_VSN + 8].copy_from_slice(&drivermsg.file_id_vsn.to_le_bytes());
+        msg[MSG_FILE_ID_ID..MSG_FILE_ID_ID + 16].copy_from_slice(&drivermsg.file_id_id);
+        msg[MSG_MEM_SIZED_USED..MSG_MEM_SIZED_USED + 8].copy_from_slice(&drivermsg.mem_sized_used.to_le_bytes());
+        msg[MSG_ENTROPY..MSG_ENTROPY + 8].copy_from_slice(&drivermsg.entropy.to_bits().to_le_bytes());
+        msg[MSG_PID..MSG_PID + 4].copy_from_slice(&drivermsg.pid.to_le_bytes());
+        msg[MSG_IRP_OP] = drivermsg.irp_op;
+        msg[MSG_IS_ENTROPY_CALC] = drivermsg.is_entropy_calc;
+        msg[MSG_FILE_CHANGE] = drivermsg.file_change;
+        msg[MSG_FILE_LOCATION_INFO] = drivermsg.file_location_info;
+        let length = (path.len() * 2) as u16;
+        msg[MSG_PATH_LENGTH..MSG_PATH_LENGTH + 2].copy_from_slice(&length.to_le_bytes());
+        msg[MSG_PATH_MAXIMUM_LENGTH..MSG_PATH_MAXIMUM_LENGTH + 2].copy_from_slice(&length.to_le_bytes());
+        if !path.is_empty() {
+            let path_address = base + (start + MSG_SIZE) as u64;
+            msg[MSG_PATH_BUFFER..MSG_PATH_BUFFER + 8].copy_from_slice(&path_address.to_le_bytes());
+        }
+        msg[MSG_GID..MSG_GID + 8].copy_from_slice(&drivermsg.gid.to_le_bytes());
+        msg[MSG_TIMESTAMP..MSG_TIMESTAMP + 8].copy_from_slice(&drivermsg.timestamp.to_le_bytes());
+        buffer.extend_from_slice(&msg);
+        buffer.extend(path.iter().flat_map(|c| c.to_le_bytes()));
+        previous_next = Some(start + MSG_NEXT);
+    }
+    let data = if msgs.is_empty() { 0 } else { base + HEADER_SIZE as u64 };
+    let data_size = (buffer.len() - HEADER_SIZE) as u64;
+    buffer[0..8].copy_from_slice(&data_size.to_le_bytes());
+    buffer[HEADER_DATA..HEADER_DATA + 8].copy_from_slice(&data.to_le_bytes());
+    buffer[HEADER_NUM_OPS..HEADER_NUM_OPS + 8].copy_from_slice(&(msgs.len() as u64).to_le_bytes());
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver_reply::{
+        decode_path, encode, parse, DriverMsg, ReplyError, BUFFER_SIZE, HEADER_SIZE, MAX_PATH_LENGTH, MSG_NEXT,
+        MSG_SIZE,
+    };
+
+    fn drivermsg(gid: u64, filepath: &str) -> DriverMsg {
+        DriverMsg {
+            extension: [0; 12],
+            file_id_vsn: 7,
+            file_id_id: [gid as u8; 16],
+            mem_sized_used: 4096,
+            entropy: 7.9,
+            pid: 4242,
+            irp_op: 2,
+            is_entropy_calc: 1,
+            file_change: 2,
+            file_location_info: 0,
+            filepath: String::from(filepath),
+            gid,
+            timestamp: 133_000_000_000_000_000 + gid as i64,
+        }
+    }
+
+    #[test]
+    fn malformed_replies_should_be_rejected() {
+        let base = 0x7ff0_0000;
+        let msgs = vec![drivermsg(1, r"\Device\HarddiskVolume3\a.docx"), drivermsg(2, ""), drivermsg(3, "b")];
+        let mut buffer = encode(&msgs, base);
+        assert_eq!(parse(&buffer, base), Ok(msgs));
+        assert_eq!(parse(&buffer[..10], base), Err(ReplyError::Truncated(10)));
+
+        // the chain loops on its first message: bounded by num_ops
+        let first = (base + HEADER_SIZE as u64).to_le_bytes();
+        buffer[HEADER_SIZE + MSG_NEXT..HEADER_SIZE + MSG_NEXT + 8].copy_from_slice(&first);
+        assert_eq!(parse(&buffer, base).unwrap().len(), 3);
+
+        // the next message is beyond the buffer
+        let beyond = (base + buffer.len() as u64 - MSG_SIZE as u64 / 2).to_le_bytes();
+        buffer[HEADER_SIZE + MSG_NEXT..HEADER_SIZE + MSG_NEXT + 8].copy_from_slice(&beyond);
+        assert!(matches!(parse(&buffer, base), Err(ReplyError::OutOfBounds { field: "data", .. })));
+        assert!(matches!(parse(&buffer, base + 1_000_000), Err(ReplyError::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn exotic_paths_should_be_decoded() {
+        let base = 0x7ff0_0000;
+        // longer than the former 520 units, with pairs, RTL and combining characters
+        let dirs = "dossier-\u{1f600}-e\u{301}\u{5d0}\u{5d1}\\".repeat(600);
+        let long = format!("C:\\Users\\bob\\{}\u{202e}fdp.exe", dirs);
+        assert!(long.encode_utf16().count() > 520);
+        let msgs = vec![drivermsg(1, &long), drivermsg(2, "\\\\?\\C:\\\u{1f4c1}\\report.docx")];
+        let buffer = encode(&msgs, base);
+        assert!(buffer.len() <= BUFFER_SIZE);
+        assert_eq!(parse(&buffer, base), Ok(msgs));
+
+        // the longest path fits in a reply, its last pair cut by the minifilter
+        let longest = "\u{1f600}".repeat(MAX_PATH_LENGTH);
+        let buffer = encode(&[drivermsg(3, &longest)], base);
+        assert!(buffer.len() <= BUFFER_SIZE);
+        let filepath = &parse(&buffer, base).unwrap()[0].filepath;
+        assert_eq!(filepath.encode_utf16().count(), MAX_PATH_LENGTH - 1);
+        assert!(filepath.chars().all(|c| c == '\u{1f600}'));
+
+        let units = |s: &str| s.encode_utf16().collect::<Vec<u16>>();
+        // padding NULs, but not the inner ones
+        assert_eq!(decode_path(&units("a\0b\0\0")), "a\0b");
+        assert_eq!(decode_path(&[0, 0]), "");
+        // a cut pair at the end, and a lone low surrogate inside
+        assert_eq!(decode_path(&[units("x\u{1f600}"), vec![0xD83D]].concat()), "x\u{1f600}");
+        assert_eq!(decode_path(&[0x61, 0xDE00, 0x62]), "a\u{fffd}b");
+    }
+}
@@ -0,0 +1,606 @@
+//! Installation of the minifilter from its *.inf*, its upgrade and its removal
+//! (```owlyshield_ransom driver install|upgrade|remove```), instead of sc.exe and rundll32:
+//! * install: the *.inf* is installed, then the minifilter is loaded (*FilterLoad*);
+//! * upgrade: the same, after the unload of the installed minifilter (*FilterUnload*), if the
+//!   *DriverVer* of the *.inf* is newer than the version of its *.sys* (or with ```--force```);
+//! * remove: the unload, then the uninstallation by the *.inf*, or else the deletion of the
+//!   service and of the *.sys*.
+//!
+//! A primitive driver package (*DefaultInstall.NT<arch>* sections) is installed with
+//! *DiInstallDriver*, into the driver store. A legacy one (*DefaultInstall* section, as
+//! *OwlyshieldRansomFilter.inf*) by its *DefaultInstall* section, as done by a right click on it.
+//!
+//! Two minifilters cannot share an altitude: the second one fails to load. The altitude of the
+//! *.inf* is checked first against the loaded minifilters ([conflicts]).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::diag::MiniFilter;
+
+#[cfg(windows)]
+use crate::diag::fltmc;
+#[cfg(windows)]
+use crate::service_ctl;
+
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, Error)]
+pub enum DriverSetupError {
+    #[error("{0}: {1}")]
+    Inf(PathBuf, String),
+    #[error("altitude {altitude} already used by {filters}")]
+    AltitudeConflict { altitude: String, filters: String },
+    #[error("already installed, version {0} (see driver upgrade)")]
+    AlreadyInstalled(DriverVersion),
+    #[error("installed version {installed} is newer than {inf} (see --force)")]
+    Downgrade { installed: DriverVersion, inf: DriverVersion },
+    #[error("{0}: {1}")]
+    File(PathBuf, std::io::Error),
+    #[error("cannot list the minifilters: {0}")]
+    Filters(String),
+    #[error("{0} failed: {1}")]
+    Win32(&'static str, std::io::Error),
+    #[error("{0} failed: {1:#x}")]
+    HResult(&'static str, i32),
+    #[cfg(windows)]
+    #[error("service: {0}")]
+    Service(#[from] windows_service::Error),
+}
+
+/// Version of a driver, as *DriverVer* in an *.inf* or the file version of a *.sys*.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DriverVersion(pub [u16; 4]);
+
+impl FromStr for DriverVersion {
+    type Err = String;
+
+    /// *a.b.c.d*, the missing parts being 0.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut version = [0; 4];
+        let parts: Vec<&str> = s.trim().split('.').collect();
+        if parts.len() > 4 {
+            return Err(format!("invalid version {}", s));
+        }
+        for (part, value) in parts.iter().zip(version.iter_mut()) {
+            *value = part.trim().parse().map_err(|_| format!("invalid version {}", s))?;
+        }
+        Ok(DriverVersion(version))
+    }
+}
+
+impl fmt::Display for DriverVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// What an *.inf* installs.
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+pub struct InfInfo {
+    pub service: String,
+    pub version: DriverVersion,
+    pub altitude: String,
+    /// Installed by its *DefaultInstall* section, not *DiInstallDriver*
+    pub legacy: bool,
+}
+
+/// Parses the content of an *.inf*: *DriverVer* in *[Version]*, the service added by
+/// *AddService*, and the *Altitude* of its *AddReg* lines, the *%strings%* being replaced.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn parse_inf(content: &str) -> Result<InfInfo, String> {
+    let mut sections: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    let mut section = String::new();
+    for line in content.lines() {
+        let line = strip_comment(line).trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_lowercase();
+            sections.entry(section.clone()).or_default();
+        } else if !line.is_empty() {
+            let (key, value) = line.split_once('=').unwrap_or(("", line));
+            sections
+                .entry(section.clone())
+                .or_default()
+                .push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let strings: HashMap<String, String> = sections
+        .get("strings")
+        .map(|entries| entries.iter().map(|(k, v)| (k.to_lowercase(), unquote(v).to_string())).collect())
+        .unwrap_or_default();
+    // the fields of a value, %strings% replaced
+    let fields = |value: &str| -> Vec<String> {
+        value
+            .split(',')
+            .map(|field| {
+                let field = unquote(field.trim());
+                match field.strip_prefix('%').and_then(|f| f.strip_suffix('%')) {
+                    Some(name) => strings.get(&name.to_lowercase()).cloned().unwrap_or_else(|| field.to_string()),
+                    None => field.to_string(),
+                }
+            })
+            .collect()
+    };
+    let entries = || sections.values().flatten();
+
+    let version = sections
+        .get("version")
+        .and_then(|entries| entries.iter().find(|(k, _)| k.eq_ignore_ascii_case("DriverVer")))
+        .ok_or("no DriverVer in [Version]")?;
+    let version = fields(&version.1).get(1).ok_or("no version in DriverVer")?.parse()?;
+    let service = entries()
+        .find(|(k, _)| k.eq_ignore_ascii_case("AddService"))
+        .and_then(|(_, v)| fields(v).into_iter().next())
+        .ok_or("no AddService")?;
+    let altitude = entries()
+        .map(|(_, v)| fields(v))
+        .find(|fields| fields.len() == 5 && fields[2].eq_ignore_ascii_case("Altitude"))
+        .map(|fields| fields[4].clone())
+        .ok_or("no Altitude")?;
+    Ok(InfInfo {
+        service,
+        version,
+        altitude,
+        legacy: sections.contains_key("defaultinstall"),
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(s)
+}
+
+/// Reads and parses *inf*, in UTF-8 or UTF-16 with its BOM.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn read_inf(inf: &Path) -> Result<InfInfo, DriverSetupError> {
+    let error = |e: String| DriverSetupError::Inf(inf.to_path_buf(), e);
+    let bytes = fs::read(inf).map_err(|e| error(e.to_string()))?;
+    let content = match bytes.as_slice() {
+        [0xFF, 0xFE, rest @ ..] => {
+            let chars: Vec<u16> = rest.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            String::from_utf16_lossy(&chars)
+        }
+        _ => String::from_utf8_lossy(&bytes).trim_start_matches('\u{feff}').to_string(),
+    };
+    parse_inf(&content).map_err(error)
+}
+
+/// The loaded minifilters, other than *service*, at *altitude*.
+pub fn conflicts<'a>(filters: &'a [MiniFilter], service: &str, altitude: &str) -> Vec<&'a MiniFilter> {
+    filters
+        .iter()
+        .filter(|filter| filter.altitude.trim() == altitude.trim() && !filter.name.eq_ignore_ascii_case(service))
+        .collect()
+}
+
+/// What [upgrade] does.
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+pub enum Plan {
+    Install,
+    /// From the installed version
+    Upgrade(DriverVersion),
+    UpToDate,
+}
+
+/// Compares the *installed* version, if any, with the one of the *.inf*. A downgrade needs *force*.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn plan(installed: Option<DriverVersion>, inf: DriverVersion, force: bool) -> Result<Plan, DriverSetupError> {
+    match installed {
+        None => Ok(Plan::Install),
+        Some(installed) if force || installed < inf => Ok(Plan::Upgrade(installed)),
+        Some(installed) if installed == inf => Ok(Plan::UpToDate),
+        Some(installed) => Err(DriverSetupError::Downgrade { installed, inf }),
+    }
+}
+
+/// The *.sys* of the *ImagePath* of a driver service.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn image_file(image_path: &str, system_root: &Path) -> PathBuf {
+    let path = unquote(image_path.trim());
+    let prefix = r"\SystemRoot\";
+    if path.len() > prefix.len() && path[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        system_root.join(&path[prefix.len()..])
+    } else if let Some(path) = path.strip_prefix(r"\??\") {
+        PathBuf::from(path)
+    } else if path.get(1..2) == Some(":") {
+        PathBuf::from(path)
+    } else {
+        system_root.join(path)
+    }
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Installed(DriverVersion),
+    Upgraded { from: DriverVersion, to: DriverVersion },
+    UpToDate(DriverVersion),
+    Removed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Installed(version) => write!(f, "{} installed", version),
+            Outcome::Upgraded { from, to } => write!(f, "upgraded from {} to {}", from, to),
+            Outcome::UpToDate(version) => write!(f, "{} already installed", version),
+            Outcome::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/// Installs the minifilter of *inf* and loads it. It must not be installed yet.
+#[cfg(windows)]
+pub fn install(inf: &Path) -> Result<Outcome, DriverSetupError> {
+    let info = read_inf(inf)?;
+    check_altitude(&info)?;
+    if let Some(installed) = ffi::installed_version(&info.service) {
+        return Err(DriverSetupError::AlreadyInstalled(installed));
+    }
+    ffi::install_package(inf, &info, false)?;
+    ffi::load(&info.service)?;
+    Ok(Outcome::Installed(info.version))
+}
+
+/// Replaces the installed minifilter by the one of *inf*, if it is newer or with *force*, then
+/// loads it. The agent service is stopped meanwhile if it runs. Installs it if needed.
+#[cfg(windows)]
+pub fn upgrade(inf: &Path, force: bool) -> Result<Outcome, DriverSetupError> {
+    use windows_service::service::ServiceState;
+
+    let info = read_inf(inf)?;
+    check_altitude(&info)?;
+    let from = match plan(ffi::installed_version(&info.service), info.version, force)? {
+        Plan::Install => {
+            ffi::install_package(inf, &info, force)?;
+            ffi::load(&info.service)?;
+            return Ok(Outcome::Installed(info.version));
+        }
+        Plan::UpToDate => return Ok(Outcome::UpToDate(info.version)),
+        Plan::Upgrade(from) => from,
+    };
+    let agent_running = matches!(service_ctl::query_state(service_ctl::SERVICE_NAME), Ok(ServiceState::Running));
+    if agent_running {
+        service_ctl::stop()?;
+    }
+    ffi::unload(&info.service)?;
+    ffi::install_package(inf, &info, force)?;
+    ffi::load(&info.service)?;
+    if agent_running {
+        service_ctl::start_service(service_ctl::SERVICE_NAME)?;
+    }
+    Ok(Outcome::Upgraded { from, to: info.version })
+}
+
+/// Unloads the minifilter, the agent service being stopped, and uninstalls it by *inf* if given.
+/// Else its service ([service_ctl::FILTER_SERVICE_NAME]) and its *.sys* are deleted.
+#[cfg(windows)]
+pub fn remove(inf: Option<&Path>) -> Result<Outcome, DriverSetupError> {
+    use windows_service::service::{ServiceAccess, ServiceState};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    let info = inf.map(read_inf).transpose()?;
+    let service = info.as_ref().map_or(service_ctl::FILTER_SERVICE_NAME, |info| info.service.as_str());
+    if matches!(service_ctl::query_state(service_ctl::SERVICE_NAME), Ok(state) if state != ServiceState::Stopped) {
+        service_ctl::stop()?;
+    }
+    ffi::unload(service)?;
+    match (inf, &info) {
+        (Some(inf), Some(info)) => ffi::uninstall_package(inf, info)?,
+        _ => {
+            let sys = ffi::image_path(service);
+            let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+            manager.open_service(service, ServiceAccess::DELETE)?.delete()?;
+            if let Some(sys) = sys {
+                fs::remove_file(&sys).map_err(|e| DriverSetupError::File(sys, e))?;
+            }
+        }
+    }
+    Ok(Outcome::Removed)
+}
+
+#[cfg(windows)]
+fn check_altitude(info: &InfInfo) -> Result<(), DriverSetupError> {
+    let filters = fltmc::filters().map_err(DriverSetupError::Filters)?;
+    let conflicts = conflicts(&filters, &info.service, &info.altitude);
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    Err(DriverSetupError::AltitudeConflict {
+        altitude: info.altitude.clone(),
+        filters: conflicts.iter().map(|filter| filter.name.as_str()).collect::<Vec<&str>>().join(", "),
+    })
+}
+
+/// *fltlib*, *newdev* and *setupapi*, and the version of the installed *.sys*.
+#[cfg(windows)]
+mod ffi {
+    use std::ffi::c_void;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::{io, ptr};
+
+    use registry::{Hive, Security};
+
+    use crate::driver_setup::{image_file, DriverSetupError, DriverVersion, InfInfo};
+
+    const DIIRFLAG_FORCE_INF: u32 = 0x2;
+    /// The default path is the directory of the *.inf*, reboot if needed
+    const INSTALL_MODE: &str = "132";
+    const ERROR_SERVICE_ALREADY_RUNNING: i32 = 0x8007_0420_u32 as i32;
+    const ERROR_SERVICE_NOT_ACTIVE: i32 = 0x8007_0426_u32 as i32;
+    const ERROR_FLT_FILTER_NOT_FOUND: i32 = 0x801F_0013_u32 as i32;
+    const TOKEN_ADJUST_PRIVILEGES: u32 = 0x20;
+    const SE_PRIVILEGE_ENABLED: u32 = 0x2;
+    const VS_FFI_SIGNATURE: u32 = 0xFEEF_04BD;
+
+    #[repr(C)]
+    struct Luid {
+        low: u32,
+        high: i32,
+    }
+
+    #[repr(C)]
+    struct TokenPrivileges {
+        count: u32,
+        luid: Luid,
+        attributes: u32,
+    }
+
+    /// The start of *VS_FIXEDFILEINFO*.
+    #[repr(C)]
+    struct FixedFileInfo {
+        signature: u32,
+        struc_version: u32,
+        file_version_ms: u32,
+        file_version_ls: u32,
+    }
+
+    #[link(name = "fltlib")]
+    extern "system" {
+        fn FilterLoad(name: *const u16) -> i32;
+        fn FilterUnload(name: *const u16) -> i32;
+    }
+
+    #[link(name = "newdev")]
+    extern "system" {
+        fn DiInstallDriverW(parent: isize, inf: *const u16, flags: u32, need_reboot: *mut i32) -> i32;
+        fn DiUninstallDriverW(parent: isize, inf: *const u16, flags: u32, need_reboot: *mut i32) -> i32;
+    }
+
+    #[link(name = "setupapi")]
+    extern "system" {
+        fn InstallHinfSectionW(window: isize, module: isize, command_line: *const u16, show: i32);
+    }
+
+    #[link(name = "version")]
+    extern "system" {
+        fn GetFileVersionInfoSizeW(file: *const u16, handle: *mut u32) -> u32;
+        fn GetFileVersionInfoW(file: *const u16, handle: u32, len: u32, data: *mut c_void) -> i32;
+        fn VerQueryValueW(block: *const c_void, sub_block: *const u16, buffer: *mut *mut c_void, len: *mut u32) -> i32;
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn OpenProcessToken(process: isize, access: u32, token: *mut isize) -> i32;
+        fn LookupPrivilegeValueW(system: *const u16, name: *const u16, luid: *mut Luid) -> i32;
+        fn AdjustTokenPrivileges(
+            token: isize,
+            disable_all: i32,
+            new: *const TokenPrivileges,
+            len: u32,
+            previous: *mut c_void,
+            returned: *mut u32,
+        ) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> isize;
+        fn CloseHandle(handle: isize) -> i32;
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+
+    fn wide_path(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().chain(Some(0)).collect()
+    }
+
+    fn canonical(inf: &Path) -> Result<PathBuf, DriverSetupError> {
+        inf.canonicalize().map_err(|e| DriverSetupError::Inf(inf.to_path_buf(), e.to_string()))
+    }
+
+    /// *SeLoadDriverPrivilege*, held but disabled in the token of the administrators.
+    fn enable_load_driver_privilege() -> Result<(), DriverSetupError> {
+        let error = |call| DriverSetupError::Win32(call, io::Error::last_os_error());
+        let mut token = 0;
+        if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES, &mut token) } == 0 {
+            return Err(error("OpenProcessToken"));
+        }
+        let mut privileges = TokenPrivileges {
+            count: 1,
+            luid: Luid { low: 0, high: 0 },
+            attributes: SE_PRIVILEGE_ENABLED,
+        };
+        let name = wide("SeLoadDriverPrivilege");
+        let res = if unsafe { LookupPrivilegeValueW(ptr::null(), name.as_ptr(), &mut privileges.luid) } == 0 {
+            Err(error("LookupPrivilegeValue"))
+        } else if unsafe { AdjustTokenPrivileges(token, 0, &privileges, 0, ptr::null_mut(), ptr::null_mut()) } == 0
+            // succeeds with ERROR_NOT_ALL_ASSIGNED if the privilege is not held
+            || io::Error::last_os_error().raw_os_error() != Some(0)
+        {
+            Err(error("AdjustTokenPrivileges"))
+        } else {
+            Ok(())
+        };
+        unsafe { CloseHandle(token) };
+        res
+    }
+
+    pub fn load(service: &str) -> Result<(), DriverSetupError> {
+        enable_load_driver_privilege()?;
+        match unsafe { FilterLoad(wide(service).as_ptr()) } {
+            res if res >= 0 || res == ERROR_SERVICE_ALREADY_RUNNING => Ok(()),
+            res => Err(DriverSetupError::HResult("FilterLoad", res)),
+        }
+    }
+
+    /// Does nothing if the minifilter is not loaded.
+    pub fn unload(service: &str) -> Result<(), DriverSetupError> {
+        enable_load_driver_privilege()?;
+        match unsafe { FilterUnload(wide(service).as_ptr()) } {
+            res if res >= 0 || res == ERROR_FLT_FILTER_NOT_FOUND || res == ERROR_SERVICE_NOT_ACTIVE => Ok(()),
+            res => Err(DriverSetupError::HResult("FilterUnload", res)),
+        }
+    }
+
+    /// Installs *inf*, *force* replacing an installed package even if it is not older.
+    pub fn install_package(inf: &Path, info: &InfInfo, force: bool) -> Result<(), DriverSetupError> {
+        let inf = canonical(inf)?;
+        if info.legacy {
+            return install_section(&inf, "DefaultInstall", &info.service);
+        }
+        let mut need_reboot = 0;
+        let flags = if force { DIIRFLAG_FORCE_INF } else { 0 };
+        if unsafe { DiInstallDriverW(0, wide_path(&inf).as_ptr(), flags, &mut need_reboot) } == 0 {
+            return Err(DriverSetupError::Win32("DiInstallDriver", io::Error::last_os_error()));
+        }
+        if need_reboot != 0 {
+            println!("A reboot is needed to complete the installation of {}", inf.display());
+        }
+        Ok(())
+    }
+
+    pub fn uninstall_package(inf: &Path, info: &InfInfo) -> Result<(), DriverSetupError> {
+        let inf = canonical(inf)?;
+        if info.legacy {
+            return install_section(&inf, "DefaultUninstall", &info.service);
+        }
+        let mut need_reboot = 0;
+        if unsafe { DiUninstallDriverW(0, wide_path(&inf).as_ptr(), 0, &mut need_reboot) } == 0 {
+            return Err(DriverSetupError::Win32("DiUninstallDriver", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Runs *section* of *inf*, as rundll32 setupapi.dll,InstallHinfSection. It reports no error:
+    /// the service must exist after *DefaultInstall*, and not after *DefaultUninstall*.
+    fn install_section(inf: &Path, section: &str, service: &str) -> Result<(), DriverSetupError> {
+        let command_line = wide(&format!("{} {} {}", section, INSTALL_MODE, inf.display()));
+        unsafe { InstallHinfSectionW(0, 0, command_line.as_ptr(), 0) };
+        let installed = image_path(service).is_some();
+        if installed != (section == "DefaultInstall") {
+            return Err(DriverSetupError::Inf(inf.to_path_buf(), format!("{} section failed", section)));
+        }
+        Ok(())
+    }
+
+    /// The *.sys* of *service*, None if it is not installed.
+    pub fn image_path(service: &str) -> Option<PathBuf> {
+        let key = format!(r"SYSTEM\CurrentControlSet\Services\{}", service);
+        let image_path = Hive::LocalMachine.open(key, Security::Read).ok()?.value("ImagePath").ok()?.to_string();
+        let system_root = std::env::var_os("SystemRoot").map_or_else(|| PathBuf::from(r"C:\Windows"), PathBuf::from);
+        Some(image_file(image_path.trim_matches(char::from(0)), &system_root))
+    }
+
+    /// The file version of the *.sys* of *service*, 0.0.0.0 if unreadable, None if it is not
+    /// installed.
+    pub fn installed_version(service: &str) -> Option<DriverVersion> {
+        image_path(service).map(|sys| file_version(&sys).unwrap_or_default())
+    }
+
+    fn file_version(file: &Path) -> Option<DriverVersion> {
+        let file = wide_path(file);
+        let mut handle = 0;
+        let len = unsafe { GetFileVersionInfoSizeW(file.as_ptr(), &mut handle) };
+        if len == 0 {
+            return None;
+        }
+        let mut data = vec![0u8; len as usize];
+        if unsafe { GetFileVersionInfoW(file.as_ptr(), 0, len, data.as_mut_ptr() as *mut c_void) } == 0 {
+            return None;
+        }
+        let mut info: *mut c_void = ptr::null_mut();
+        let mut info_len = 0;
+        let root = wide("\\");
+        if unsafe { VerQueryValueW(data.as_ptr() as *const c_void, root.as_ptr(), &mut info, &mut info_len) } == 0
+            || (info_len as usize) < std::mem::size_of::<FixedFileInfo>()
+        {
+            return None;
+        }
+        let info = unsafe { &*(info as *const FixedFileInfo) };
+        if info.signature != VS_FFI_SIGNATURE {
+            return None;
+        }
+        let (ms, ls) = (info.file_version_ms, info.file_version_ls);
+        Some(DriverVersion([(ms >> 16) as u16, ms as u16, (ls >> 16) as u16, ls as u16]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::diag::MiniFilter;
+    use crate::driver_setup::{conflicts, image_file, parse_inf, plan, DriverVersion, Plan};
+
+    const INF: &str = include_str!("../../owlyshield_minifilter/OwlyshieldRansomFilter/OwlyshieldRansomFilter.inf");
+
+    #[test]
+    fn inf_should_be_parsed_and_compared_with_the_installed_driver() {
+        let info = parse_inf(INF).unwrap();
+        assert_eq!(info.service, "OwlyshieldRansomFilter");
+        assert_eq!(info.version, DriverVersion([1, 0, 0, 0]));
+        assert_eq!(info.altitude, "378781");
+        assert!(info.legacy);
+        assert!(parse_inf("[Version]\nDriverVer = 07/10/2021\n").is_err());
+
+        let newer: DriverVersion = "1.2".parse().unwrap();
+        assert_eq!(newer.to_string(), "1.2.0.0");
+        assert!(newer > info.version && newer < "1.10.0.0".parse().unwrap());
+        assert!("1.a".parse::<DriverVersion>().is_err());
+        assert_eq!(plan(None, newer, false).unwrap(), Plan::Install);
+        assert_eq!(plan(Some(info.version), newer, false).unwrap(), Plan::Upgrade(info.version));
+        assert_eq!(plan(Some(newer), newer, false).unwrap(), Plan::UpToDate);
+        assert!(plan(Some(newer), info.version, false).is_err());
+        assert_eq!(plan(Some(newer), info.version, true).unwrap(), Plan::Upgrade(newer));
+
+        let filter = |name: &str, altitude: &str| MiniFilter {
+            name: name.to_string(),
+            instances: 1,
+            altitude: altitude.to_string(),
+            frame: 0,
+        };
+        let filters = vec![filter("owlyshieldransomfilter", "378781"), filter("WdFilter", "328010"), filter("Other", "378781")];
+        let found = conflicts(&filters, &info.service, &info.altitude);
+        assert_eq!(found, vec![&filters[2]]);
+
+        let root = Path::new(r"C:\Windows");
+        assert_eq!(
+            image_file(r"\SystemRoot\system32\drivers\OwlyshieldRansomFilter.sys", root),
+            root.join(r"system32\drivers\OwlyshieldRansomFilter.sys")
+        );
+        assert_eq!(image_file(r"system32\DRIVERS\a.sys", root), root.join(r"system32\DRIVERS\a.sys"));
+        assert_eq!(image_file(r"\??\D:\drivers\a.sys", root), PathBuf::from(r"D:\drivers\a.sys"));
+    }
+}
@@ -0,0 +1,275 @@
+//! Linux [IoEventSource] based on eBPF, for the events fanotify cannot see.
+//!
+//! The program of *owlyshield_ebpf* (loaded from *UtilsPath/owlyshield.bpf.o*) hooks the
+//! syscalls tracepoints and sends, through a perf event array, once the syscall has succeeded:
+//! * the writes, with the first [SAMPLE_LEN] bytes of the buffer, whose entropy is computed here
+//!   as the minifilter does ([IrpMajorOp::IrpWrite]);
+//! * the renames ([FileChangeInfo::FileChangeRenameFile], or
+//!   [FileChangeInfo::FileChangeExtensionChanged]) and the deletions
+//!   ([FileChangeInfo::FileChangeDeleteFile]).
+//!
+//! The opens, reads and closes are still reported by a [FanotifySource], whose writes are
+//! ignored. The paths are resolved through */proc/pid*, after the fact: the events of a process
+//! which has already exited, on anything else than a regular file, or out of the mounts of
+//! *WATCHED_MOUNTS* (as marked by fanotify), are dropped.
+
+use std::collections::HashSet;
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use aya::maps::perf::PerfEventArrayBuffer;
+use aya::maps::{Array, MapRefMut, PerfEventArray};
+use aya::programs::TracePoint;
+use aya::util::online_cpus;
+use aya::Bpf;
+use bytes::BytesMut;
+use tracing::{error, warn};
+
+use crate::config::{Config, Param};
+use crate::driver_com::shared_def::{FileChangeInfo, IOMessage};
+use crate::driver_com::IrpMajorOp;
+use crate::fanotify::{iomessage, FanotifySource};
+use crate::iosource::{IoEventSource, IoSourceError};
+use crate::os;
+
+/// Bytes of the written buffers sampled by the eBPF program.
+pub const SAMPLE_LEN: usize = 512;
+const NAME_LEN: usize = 256;
+const AT_FDCWD: i32 = -100;
+const OP_WRITE: u32 = 1;
+const OP_RENAME: u32 = 2;
+const OP_UNLINK: u32 = 3;
+/// Programs of the object file, attached to *syscalls/<name>*.
+static TRACEPOINTS: [&str; 14] = [
+    "sys_enter_write",
+    "sys_enter_pwrite64",
+    "sys_enter_rename",
+    "sys_enter_renameat",
+    "sys_enter_renameat2",
+    "sys_enter_unlink",
+    "sys_enter_unlinkat",
+    "sys_exit_write",
+    "sys_exit_pwrite64",
+    "sys_exit_rename",
+    "sys_exit_renameat",
+    "sys_exit_renameat2",
+    "sys_exit_unlink",
+    "sys_exit_unlinkat",
+];
+/// Events read at once from the buffer of a CPU.
+const READ_BATCH: usize = 64;
+
+/// Same layout as *struct event* in owlyshield.bpf.c
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawEvent {
+    pid: u32,
+    op: u32,
+    fd: i32,
+    new_fd: i32,
+    count: u64,
+    sample_len: u32,
+    padding: u32,
+    path: [u8; NAME_LEN],
+    new_path: [u8; NAME_LEN],
+    sample: [u8; SAMPLE_LEN],
+}
+
+pub struct EbpfSource {
+    /// Owns the programs: they are detached when dropped
+    _bpf: Bpf,
+    /// One buffer by CPU, with the batch they are read into
+    buffers: Mutex<(Vec<PerfEventArrayBuffer<MapRefMut>>, Vec<BytesMut>)>,
+    /// Of the mounts of *WATCHED_MOUNTS*
+    devices: HashSet<u64>,
+    fanotify: FanotifySource,
+}
+
+impl EbpfSource {
+    /// Loads and attaches the eBPF programs, and opens fanotify.
+    pub fn open(config: &Config) -> Result<EbpfSource, IoSourceError> {
+        let object_path = config.get_path(Param::UtilsPath).join("owlyshield.bpf.o");
+        let mut bpf = Bpf::load_file(&object_path).map_err(|e| open_error(&object_path.display(), e))?;
+        for name in TRACEPOINTS.iter() {
+            let program: &mut TracePoint = bpf
+                .program_mut(name)
+                .ok_or_else(|| IoSourceError::Open(format!("eBPF program {} not found", name)))?
+                .try_into()
+                .map_err(|e| open_error(name, e))?;
+            program.load().map_err(|e| open_error(name, e))?;
+            program.attach("syscalls", name).map_err(|e| open_error(name, e))?;
+        }
+        let mut agent_pid: Array<MapRefMut, u32> =
+            Array::try_from(bpf.map_mut("CONFIG").map_err(|e| open_error(&"CONFIG", e))?)
+                .map_err(|e| open_error(&"CONFIG", e))?;
+        agent_pid.set(0, std::process::id(), 0).map_err(|e| open_error(&"CONFIG", e))?;
+        let mut events = PerfEventArray::try_from(bpf.map_mut("EVENTS").map_err(|e| open_error(&"EVENTS", e))?)
+            .map_err(|e| open_error(&"EVENTS", e))?;
+        let mut buffers = Vec::new();
+        for cpu in online_cpus().map_err(|e| open_error(&"online cpus", e))? {
+            buffers.push(events.open(cpu, None).map_err(|e| open_error(&"EVENTS", e))?);
+        }
+        let batch = (0..READ_BATCH)
+            .map(|_| BytesMut::with_capacity(std::mem::size_of::<RawEvent>()))
+            .collect();
+        let devices = config
+            .get_str(Param::WatchedMounts)
+            .split(',')
+            .map(str::trim)
+            .filter(|mount| !mount.is_empty())
+            .filter_map(|mount| fs::metadata(mount).ok().map(|m| m.dev()))
+            .collect();
+        Ok(EbpfSource {
+            _bpf: bpf,
+            buffers: Mutex::new((buffers, batch)),
+            devices,
+            fanotify: FanotifySource::open(config, false)?,
+        })
+    }
+}
+
+impl IoEventSource for EbpfSource {
+    fn fetch(&self, events: &mut Vec<IOMessage>) -> Result<(), IoSourceError> {
+        self.fanotify.fetch(events)?;
+        let mut guard = self.buffers.lock().unwrap();
+        let (buffers, batch) = &mut *guard;
+        for buffer in buffers.iter_mut() {
+            while buffer.readable() {
+                let read = buffer.read_events(batch).map_err(|e| {
+                    error!("Cannot read eBPF events: {}", e);
+                    IoSourceError::Closed
+                })?;
+                if read.lost > 0 {
+                    warn!(lost = read.lost, "eBPF buffer overflow: file events have been lost");
+                }
+                for raw in &batch[..read.read] {
+                    if raw.len() < std::mem::size_of::<RawEvent>() {
+                        continue;
+                    }
+                    let event: RawEvent = unsafe { std::ptr::read_unaligned(raw.as_ptr() as *const RawEvent) };
+                    if let Some(iomsg) = to_iomessage(&event, &self.devices) {
+                        events.push(iomsg);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn kill_gid(&self, gid: u64) -> Result<(), IoSourceError> {
+        self.fanotify.kill_gid(gid)
+    }
+}
+
+fn open_error(what: &dyn std::fmt::Display, e: impl std::fmt::Display) -> IoSourceError {
+    IoSourceError::Open(format!("eBPF {}: {}", what, e))
+}
+
+/// The event, if it is on a regular file of one of the watched *devices*.
+fn to_iomessage(event: &RawEvent, devices: &HashSet<u64>) -> Option<IOMessage> {
+    // The process may have exited already: its events cannot be attributed
+    let gid = os::process_group(event.pid)? as u64;
+    match event.op {
+        OP_WRITE => {
+            let path = fs::read_link(format!("/proc/{}/fd/{}", event.pid, event.fd)).ok()?;
+            let file = fs::metadata(&path).ok().filter(|m| m.is_file() && devices.contains(&m.dev()))?;
+            let mut iomsg = iomessage(
+                event.pid,
+                gid,
+                &path,
+                Some(&file),
+                IrpMajorOp::IrpWrite,
+                FileChangeInfo::FileChangeWrite,
+                event.count,
+            );
+            let sample = &event.sample[..(event.sample_len as usize).min(SAMPLE_LEN)];
+            if !sample.is_empty() {
+                iomsg.entropy = shannon_entropy(sample);
+                iomsg.is_entropy_calc = 1;
+            }
+            Some(iomsg)
+        }
+        OP_RENAME => {
+            let path = resolve_at(event.pid, event.fd, &event.path)?;
+            let new_path = resolve_at(event.pid, event.new_fd, &event.new_path)?;
+            let file = fs::metadata(&new_path).ok().filter(|m| m.is_file() && devices.contains(&m.dev()))?;
+            let file_change = if path.extension() == new_path.extension() {
+                FileChangeInfo::FileChangeRenameFile
+            } else {
+                FileChangeInfo::FileChangeExtensionChanged
+            };
+            Some(iomessage(event.pid, gid, &new_path, Some(&file), IrpMajorOp::IrpSetInfo, file_change, 0))
+        }
+        OP_UNLINK => {
+            let path = resolve_at(event.pid, event.fd, &event.path)?;
+            // the file is gone: the device of its directory
+            fs::metadata(path.parent()?).ok().filter(|m| devices.contains(&m.dev()))?;
+            Some(iomessage(
+                event.pid,
+                gid,
+                &path,
+                None,
+                IrpMajorOp::IrpSetInfo,
+                FileChangeInfo::FileChangeDeleteFile,
+                0,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Absolute path of *name*, relative to the directory *dirfd* of *pid* (or its current
+/// directory).
+fn resolve_at(pid: u32, dirfd: i32, name: &[u8]) -> Option<PathBuf> {
+    let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    let name = Path::new(std::str::from_utf8(&name[..len]).ok()?);
+    if name.as_os_str().is_empty() {
+        return None;
+    }
+    if name.is_absolute() {
+        return Some(name.to_path_buf());
+    }
+    let dir = if dirfd == AT_FDCWD {
+        fs::read_link(format!("/proc/{}/cwd", pid)).ok()?
+    } else {
+        fs::read_link(format!("/proc/{}/fd/{}", pid, dirfd)).ok()?
+    };
+    Some(dir.join(name))
+}
+
+/// Between 0.0 (constant) and 8.0 (random or encrypted) bits by byte, as computed by the
+/// minifilter.
+pub fn shannon_entropy(buffer: &[u8]) -> f64 {
+    if buffer.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for b in buffer {
+        counts[*b as usize] += 1;
+    }
+    let len = buffer.len() as f64;
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ebpf::shannon_entropy;
+
+    #[test]
+    fn entropy_should_be_between_constant_and_random() {
+        assert_eq!(shannon_entropy(&[0x41; 512]), 0.0);
+        let all_bytes: Vec<u8> = (0..=255).collect();
+        assert!((shannon_entropy(&all_bytes) - 8.0).abs() < 1e-9);
+        assert!((shannon_entropy(b"abab") - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,328 @@
+//! Enrichment of the alerts by external providers (EDR APIs, CMDB...), listed in
+//! *ConfigPath\enrichment.toml* and enabled by *ENRICHMENT*. Each provider is queried with the
+//! SHA-256 of the executable and the hostname, by HTTP or with a local command, and answers tags
+//! merged into the event:
+//! ```toml
+//! [[providers]]
+//! name = "cmdb"
+//! url = "https://cmdb.corp/api/assets?host={hostname}&sha256={sha256}"
+//! headers = ["Authorization: Bearer 0123"]
+//! timeout_secs = 2
+//!
+//! [[providers]]
+//! name = "edr"
+//! command = ['C:\Tools\edr-lookup.exe', "{sha256}"]
+//! ttl_secs = 3600
+//! ```
+//! The answer is ```{"tags": ["vip", "finance"]}``` or ```{"tags": {"owner": "finance"}}```, the
+//! latter giving *owner:finance*.
+//!
+//! The lookups run in their own threads and are cached by executable for *ttl_secs* (the failed
+//! ones for [FAILURE_TTL]). An event waits for them [MAX_WAIT] at most, and is sent without tags
+//! if they are late: the next events of the executable get them. The kills are issued by the
+//! workers before, and never wait.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::config::{Config, Param};
+use crate::connectors::http;
+use crate::utils::{extended_path, sha256_file};
+
+pub static ENRICHMENT_FILE_NAME: &str = "enrichment.toml";
+/// Longest wait of an event for its tags.
+const MAX_WAIT: Duration = Duration::from_millis(500);
+/// Cache duration of a lookup where a provider failed, to query it again sooner.
+const FAILURE_TTL: Duration = Duration::from_secs(60);
+/// Executables cached. Beyond, the expired entries are removed, then all of them.
+const MAX_ENTRIES: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum EnrichmentError {
+    #[error("cannot read {ENRICHMENT_FILE_NAME}: {0}")]
+    File(String),
+    #[error("provider {0}: needs either url or command")]
+    Kind(String),
+    #[error("request failed: {0}")]
+    Http(String),
+    #[error("HTTP status {0}")]
+    Status(u32),
+    #[error("cannot run the command: {0}")]
+    Io(std::io::Error),
+    #[error("no answer after {0:?}")]
+    Timeout(Duration),
+    #[error("exit code {0:?}")]
+    Failed(Option<i32>),
+    #[error("invalid answer: {0}")]
+    Answer(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct EnrichmentFile {
+    #[serde(default)]
+    providers: Vec<Provider>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Provider {
+    pub name: String,
+    /// Queried with a GET
+    pub url: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// Program and arguments, the answer being its output
+    pub command: Option<Vec<String>>,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_ttl")]
+    pub ttl_secs: u64,
+}
+
+fn default_timeout() -> u64 {
+    2
+}
+
+fn default_ttl() -> u64 {
+    600
+}
+
+/// Tags of an executable, or None while it is looked up.
+struct Entry {
+    tags: Option<Vec<String>>,
+    expires: Instant,
+}
+
+pub struct Enricher {
+    providers: Arc<Vec<Provider>>,
+    hostname: String,
+    cache: Arc<Mutex<HashMap<PathBuf, Entry>>>,
+}
+
+impl Enricher {
+    /// The providers of *enrichment.toml*, None if *ENRICHMENT* is false or there is none.
+    pub fn load(config: &Config, hostname: &str) -> Result<Option<Enricher>, EnrichmentError> {
+        if !config.get_bool(Param::Enrichment) {
+            return Ok(None);
+        }
+        let path = config.get_path(Param::ConfigPath).join(ENRICHMENT_FILE_NAME);
+        let content = fs::read_to_string(&path).map_err(|e| EnrichmentError::File(e.to_string()))?;
+        let file: EnrichmentFile = toml::from_str(&content).map_err(|e| EnrichmentError::File(e.to_string()))?;
+        if let Some(provider) = file.providers.iter().find(|p| p.url.is_some() == p.command.is_some()) {
+            return Err(EnrichmentError::Kind(provider.name.clone()));
+        }
+        if file.providers.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Enricher {
+            providers: Arc::new(file.providers),
+            hostname: String::from(hostname),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }))
+    }
+
+    /// Starts the lookup of *exepath* if it is not cached, without waiting for it.
+    pub fn prefetch(&self, exepath: &Path) {
+        let now = Instant::now();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.get(exepath).is_some_and(|entry| entry.expires > now) {
+                return;
+            }
+            if cache.len() >= MAX_ENTRIES {
+                cache.retain(|_, entry| entry.expires > now);
+                if cache.len() >= MAX_ENTRIES {
+                    cache.clear();
+                }
+            }
+            let lookup = self.providers.iter().map(|p| p.timeout_secs).sum::<u64>();
+            cache.insert(
+                exepath.to_path_buf(),
+                Entry {
+                    tags: None,
+                    expires: now + Duration::from_secs(lookup + 10),
+                },
+            );
+        }
+        let providers = self.providers.clone();
+        let cache = self.cache.clone();
+        let hostname = self.hostname.clone();
+        let exepath = exepath.to_path_buf();
+        thread::spawn(move || {
+            let (tags, ttl) = lookup(&providers, &exepath, &hostname);
+            cache.lock().unwrap().insert(
+                exepath,
+                Entry {
+                    tags: Some(tags),
+                    expires: Instant::now() + ttl,
+                },
+            );
+        });
+    }
+
+    /// Tags of *exepath*, waiting [MAX_WAIT] at most for its lookup.
+    pub fn tags(&self, exepath: &Path) -> Vec<String> {
+        self.prefetch(exepath);
+        let started = Instant::now();
+        loop {
+            if let Some(tags) = self.cache.lock().unwrap().get(exepath).and_then(|entry| entry.tags.clone()) {
+                return tags;
+            }
+            if started.elapsed() >= MAX_WAIT {
+                debug!(exepath = %exepath.display(), "Enrichment still running, the event is sent without tags");
+                return Vec::new();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Queries the providers, returning their merged tags and how long to cache them.
+fn lookup(providers: &[Provider], exepath: &Path, hostname: &str) -> (Vec<String>, Duration) {
+    let sha256 = match sha256_file(&extended_path(exepath)) {
+        Ok(sha256) => Some(sha256),
+        Err(e) => {
+            warn!(exepath = %exepath.display(), "Cannot hash the executable to enrich it: {}", e);
+            None
+        }
+    };
+    let mut answers = Vec::new();
+    let mut ttl = providers.iter().map(|p| p.ttl_secs).min().map_or(FAILURE_TTL, Duration::from_secs);
+    for provider in providers {
+        let sha256 = sha256.as_deref();
+        let res = match (&provider.url, &provider.command) {
+            (Some(url), _) => match fill(url, sha256, hostname, true) {
+                Some(url) => query_url(provider, &url),
+                None => continue,
+            },
+            (None, Some(command)) => match command.iter().map(|arg| fill(arg, sha256, hostname, false)).collect::<Option<Vec<String>>>() {
+                Some(args) => run_command(provider, &args),
+                None => continue,
+            },
+            (None, None) => continue,
+        };
+        match res.and_then(|answer| parse_tags(&answer)) {
+            Ok(tags) => answers.push(tags),
+            Err(e) => {
+                warn!(provider = %provider.name, "Enrichment failed: {}", e);
+                ttl = ttl.min(FAILURE_TTL);
+            }
+        }
+    }
+    (merge(answers), ttl)
+}
+
+/// *template* with its {sha256} and {hostname}, percent-encoded for an url. None if it needs the
+/// hash and the executable could not be read.
+fn fill(template: &str, sha256: Option<&str>, hostname: &str, url: bool) -> Option<String> {
+    let encode = |value: &str| if url { http::encode(value) } else { String::from(value) };
+    let filled = template.replace("{hostname}", &encode(hostname));
+    if filled.contains("{sha256}") {
+        return sha256.map(|sha256| filled.replace("{sha256}", sha256));
+    }
+    Some(filled)
+}
+
+fn query_url(provider: &Provider, url: &str) -> Result<String, EnrichmentError> {
+    let timeout = Duration::from_secs(provider.timeout_secs);
+    match http::get(url, &provider.headers, timeout) {
+        Ok((200, body)) => Ok(body),
+        Ok((status, _)) => Err(EnrichmentError::Status(status)),
+        Err(e) if e.is_operation_timedout() => Err(EnrichmentError::Timeout(timeout)),
+        Err(e) => Err(EnrichmentError::Http(e.to_string())),
+    }
+}
+
+/// Runs *args*, killed after the timeout of the provider like the scans of [crate::yara].
+fn run_command(provider: &Provider, args: &[String]) -> Result<String, EnrichmentError> {
+    let timeout = Duration::from_secs(provider.timeout_secs);
+    let (program, args) = args.split_first().ok_or_else(|| EnrichmentError::Kind(provider.name.clone()))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(EnrichmentError::Io)?;
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(EnrichmentError::Io)? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(EnrichmentError::Timeout(timeout));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let mut stdout = String::new();
+    if let Some(mut out) = child.stdout.take() {
+        out.read_to_string(&mut stdout).map_err(EnrichmentError::Io)?;
+    }
+    if !status.success() {
+        return Err(EnrichmentError::Failed(status.code()));
+    }
+    Ok(stdout)
+}
+
+/// Tags of an answer: a list of strings, or an object whose entries become *key:value*.
+fn parse_tags(answer: &str) -> Result<Vec<String>, EnrichmentError> {
+    let value: Value = serde_json::from_str(answer).map_err(|e| EnrichmentError::Answer(e.to_string()))?;
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    };
+    match value.get("tags") {
+        Some(Value::Array(tags)) => Ok(tags.iter().filter_map(scalar).collect()),
+        Some(Value::Object(tags)) => Ok(tags
+            .iter()
+            .filter_map(|(key, value)| scalar(value).map(|value| format!("{}:{}", key, value)))
+            .collect()),
+        Some(Value::Null) | None => Ok(Vec::new()),
+        Some(_) => Err(EnrichmentError::Answer(String::from("tags is neither a list nor an object"))),
+    }
+}
+
+/// Tags of all the providers, sorted and without duplicates or blanks.
+fn merge(answers: Vec<Vec<String>>) -> Vec<String> {
+    answers
+        .into_iter()
+        .flatten()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::enrichment::{fill, merge, parse_tags};
+
+    #[test]
+    fn answers_should_be_merged_into_tags() {
+        let cmdb = parse_tags(r#"{"tags": {"owner": "finance", "critical": true}, "id": 3}"#).unwrap();
+        let edr = parse_tags(r#"{"tags": ["vip", " owner:finance", "", {"nested": 1}]}"#).unwrap();
+        assert_eq!(parse_tags(r#"{"asset": "none"}"#).unwrap(), Vec::<String>::new());
+        assert!(parse_tags(r#"{"tags": "vip"}"#).is_err());
+        assert!(parse_tags("<html>").is_err());
+        assert_eq!(merge(vec![cmdb, edr]), vec!["critical:true", "owner:finance", "vip"]);
+
+        let url = "https://cmdb/api?host={hostname}&sha256={sha256}";
+        assert_eq!(fill(url, Some("ab12"), "PC 01", true).unwrap(), "https://cmdb/api?host=PC%2001&sha256=ab12");
+        assert_eq!(fill(url, None, "PC01", true), None);
+        assert_eq!(fill("{hostname}", None, "PC 01", false).unwrap(), "PC 01");
+    }
+}
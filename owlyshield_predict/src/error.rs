@@ -0,0 +1,94 @@
+//! Errors of the agent, with what to do about them.
+//!
+//! Each module keeps its own error type ([IoSourceError] for the driver, [ConfigError],
+//! [ModelError], [ConnectorError]). They are gathered in [OwlyError] where a decision is taken,
+//! following its [ErrorPolicy]:
+//! * the driver messages which cannot be received are retried a few times, then the error is
+//!   returned to the [crate::watchdog], which restarts the protection loop, as when a thread
+//!   cannot be started;
+//! * an invalid configuration or model ends the process: a restart would fail the same way;
+//! * a failing connector, kill or mute is logged, and the protection goes on without it.
+
+use thiserror::Error;
+use tracing::error;
+
+use crate::config::ConfigError;
+use crate::connectors::connector::ConnectorError;
+use crate::iosource::IoSourceError;
+
+/// The embedded models cannot be loaded, or run.
+#[derive(Debug, Error)]
+pub enum ModelError {
+    #[error("cannot load the {0} model")]
+    Model(&'static str),
+    #[error("invalid standard scaling or imports of the {0} model: {1}")]
+    Data(&'static str, String),
+    /// See [crate::features::FeatureRegistry::validate].
+    #[error("the {0} model does not fit the features: {1}")]
+    Schema(&'static str, String),
+    /// The interpreter of the model {0} (its version) cannot be built or invoked.
+    #[error("cannot run the model {0}")]
+    Inference(String),
+}
+
+#[derive(Debug, Error)]
+pub enum OwlyError {
+    /// The minifilter on Windows, the fanotify or eBPF source on Linux.
+    #[error("driver: {0}")]
+    Driver(#[from] IoSourceError),
+    #[error("configuration: {0}")]
+    Config(#[from] ConfigError),
+    #[error("model: {0}")]
+    Model(#[from] ModelError),
+    #[error("connector {0}")]
+    Connector(#[from] ConnectorError),
+    #[error("cannot start the {0} thread: {1}")]
+    Thread(&'static str, std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Transient: tried again, then escalated to a restart of the protection loop.
+    Retry,
+    /// The feature is lost, the protection goes on.
+    Degrade,
+    /// Ends the process with an error.
+    Exit,
+}
+
+impl OwlyError {
+    pub fn policy(&self) -> ErrorPolicy {
+        match self {
+            OwlyError::Driver(IoSourceError::Receive(_)) | OwlyError::Driver(IoSourceError::Closed) => ErrorPolicy::Retry,
+            OwlyError::Driver(IoSourceError::Open(_)) => ErrorPolicy::Exit,
+            OwlyError::Driver(IoSourceError::Kill(_))
+            | OwlyError::Driver(IoSourceError::Mute(_))
+            | OwlyError::Driver(IoSourceError::Capture(_)) => ErrorPolicy::Degrade,
+            OwlyError::Config(_) | OwlyError::Model(_) => ErrorPolicy::Exit,
+            OwlyError::Connector(_) => ErrorPolicy::Degrade,
+            OwlyError::Thread(..) => ErrorPolicy::Retry,
+        }
+    }
+
+    /// Logs the error and ends the process, for the [ErrorPolicy::Exit] errors: the watchdog would
+    /// restart the protection loop in vain.
+    pub fn exit(self) -> ! {
+        error!("Critical: {}", self);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ConfigError;
+    use crate::error::{ErrorPolicy, OwlyError};
+    use crate::iosource::IoSourceError;
+
+    #[test]
+    fn only_transient_driver_errors_should_be_retried() {
+        assert_eq!(OwlyError::from(IoSourceError::Receive(-2147024890)).policy(), ErrorPolicy::Retry);
+        assert_eq!(OwlyError::from(IoSourceError::Kill(5)).policy(), ErrorPolicy::Degrade);
+        assert_eq!(OwlyError::from(ConfigError::Missing(String::from("MODE"))).policy(), ErrorPolicy::Exit);
+        assert_eq!(OwlyError::Thread("api", std::io::Error::from(std::io::ErrorKind::OutOfMemory)).policy(), ErrorPolicy::Retry);
+    }
+}
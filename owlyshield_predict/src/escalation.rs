@@ -0,0 +1,293 @@
+//! Escalation ladder of a gid, instead of a single jump to the kill at the threshold.
+//!
+//! The scores of a gid move it between four levels, with bands given as fractions of its
+//! threshold:
+//! * *Normal*;
+//! * *Watch*, from *ESCALATION_WATCH*: the memory of its processes ([crate::memscan]) and the
+//!   executables it dropped ([TfLiteStatic]) are scanned;
+//! * *PreAlert*, from *ESCALATION_PRE_ALERT*: an [Escalated] event is sent to the connectors;
+//! * *Alert*, above the threshold: the gid is killed or suspended.
+//!
+//! A gid goes up at once, and down one level at a time after *ESCALATION_COOLDOWN* seconds below
+//! its level, so that a score oscillating around a band does not repeat the notifications. With
+//! *ESCALATION_ALERT_DWELL*, a gid above the threshold first stays that many seconds in PreAlert.
+//! Alert is final.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tracing::warn;
+
+use crate::config::{Config, Param};
+use crate::correlation::IncidentRef;
+use crate::memscan;
+use crate::prediction_static::TfLiteStatic;
+use crate::process::ProcessRecord;
+use crate::scripthost::AmsiVerdict;
+
+/// Lowercase extensions of the dropped executables scanned in Watch.
+const DROPPED_EXTENSIONS: [&str; 4] = ["exe", "dll", "scr", "sys"];
+/// Beyond, the other dropped executables are not scanned.
+const MAX_DROPPED_SCANNED: usize = 16;
+/// Static prediction from which a dropped executable is suspicious.
+const DROPPED_SUSPICIOUS: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Normal,
+    Watch,
+    PreAlert,
+    Alert,
+}
+
+impl Level {
+    fn lower(self) -> Level {
+        match self {
+            Level::Normal | Level::Watch => Level::Normal,
+            Level::PreAlert => Level::Watch,
+            Level::Alert => Level::PreAlert,
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Normal => write!(f, "normal"),
+            Level::Watch => write!(f, "watch"),
+            Level::PreAlert => write!(f, "pre-alert"),
+            Level::Alert => write!(f, "alert"),
+        }
+    }
+}
+
+/// A change of level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub from: Level,
+    pub to: Level,
+}
+
+impl Transition {
+    /// Going up to *level* or above, from below.
+    pub fn entered(&self, level: Level) -> bool {
+        self.from < level && self.to >= level
+    }
+}
+
+/// Level of a gid.
+#[derive(Debug)]
+pub struct Escalation {
+    watch: f32,
+    pre_alert: f32,
+    alert_dwell: Duration,
+    cooldown: Duration,
+    level: Level,
+    /// Time the level was entered
+    since: SystemTime,
+    /// Last score at or above the level
+    last_seen: SystemTime,
+    /// Collected when entering Watch
+    pub evidence: Evidence,
+}
+
+impl Escalation {
+    pub fn from(config: &Config) -> Escalation {
+        Escalation::new(
+            config.get_f32(Param::EscalationWatch),
+            config.get_f32(Param::EscalationPreAlert),
+            Duration::from_secs(config.get_usize(Param::EscalationAlertDwell) as u64),
+            Duration::from_secs(config.get_usize(Param::EscalationCooldown) as u64),
+        )
+    }
+
+    pub fn new(watch: f32, pre_alert: f32, alert_dwell: Duration, cooldown: Duration) -> Escalation {
+        Escalation {
+            watch,
+            pre_alert,
+            alert_dwell,
+            cooldown,
+            level: Level::Normal,
+            since: SystemTime::UNIX_EPOCH,
+            last_seen: SystemTime::UNIX_EPOCH,
+            evidence: Evidence::default(),
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Moves the gid according to its new *score*. Returns the change of level, if any.
+    pub fn on_prediction(&mut self, score: f32, threshold: f32, now: SystemTime) -> Option<Transition> {
+        if self.level == Level::Alert {
+            return None;
+        }
+        let mut band = self.band(score, threshold);
+        if band == Level::Alert
+            && (self.level < Level::PreAlert || now.duration_since(self.since).unwrap_or_default() < self.alert_dwell)
+            && !self.alert_dwell.is_zero()
+        {
+            band = Level::PreAlert;
+        }
+        let to = if band > self.level {
+            band
+        } else if band == self.level {
+            self.last_seen = now;
+            return None;
+        } else if now.duration_since(self.last_seen).unwrap_or_default() >= self.cooldown {
+            self.level.lower()
+        } else {
+            return None;
+        };
+        let transition = Transition { from: self.level, to };
+        self.level = to;
+        self.since = now;
+        self.last_seen = now;
+        Some(transition)
+    }
+
+    fn band(&self, score: f32, threshold: f32) -> Level {
+        if score > threshold {
+            Level::Alert
+        } else if self.pre_alert > 0.0 && score >= self.pre_alert * threshold {
+            Level::PreAlert
+        } else if self.watch > 0.0 && score >= self.watch * threshold {
+            Level::Watch
+        } else {
+            Level::Normal
+        }
+    }
+}
+
+/// What the scans of Watch found.
+#[derive(Debug, Clone, Default)]
+pub struct Evidence {
+    /// Worst verdict of [memscan] on the processes of the gid
+    pub memory: Option<AmsiVerdict>,
+    /// Static predictions of the executables dropped by the gid
+    pub dropped: Vec<(PathBuf, f32)>,
+}
+
+impl Evidence {
+    /// Scans the memory of the processes of *proc* and the executables it dropped. Slow.
+    pub fn collect(proc: &ProcessRecord, tflite_static: &TfLiteStatic) -> Evidence {
+        let mut memory = None;
+        for pid in proc.pids.iter() {
+            match memscan::scan(*pid) {
+                Ok(Some(verdict)) => {
+                    memory = Some(verdict);
+                    if verdict == AmsiVerdict::Detected {
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!(pid, "Memory scan failed: {}", e),
+            }
+        }
+        let mut dropped: Vec<&str> = proc
+            .fpaths_created
+            .retained()
+            .iter()
+            .map(|fpath| fpath.as_ref())
+            .filter(|fpath| is_executable(Path::new(fpath)))
+            .collect();
+        dropped.sort_unstable();
+        let dropped = dropped
+            .into_iter()
+            .take(MAX_DROPPED_SCANNED)
+            .filter_map(|fpath| {
+                let path = PathBuf::from(fpath);
+                let prediction = tflite_static.make_prediction(&path)?;
+                Some((path, prediction))
+            })
+            .collect();
+        Evidence { memory, dropped }
+    }
+
+    pub fn is_suspicious(&self) -> bool {
+        self.memory == Some(AmsiVerdict::Detected)
+            || self.dropped.iter().any(|(_, prediction)| *prediction >= DROPPED_SUSPICIOUS)
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| DROPPED_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// A gid entering PreAlert, sent before it is killed, if ever.
+#[derive(Debug, Clone)]
+pub struct Escalated {
+    pub time: SystemTime,
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub score: f32,
+    pub threshold: f32,
+    pub evidence: Evidence,
+    /// Of the [crate::enrichment] providers, added when sent to the connectors
+    pub tags: Vec<String>,
+    /// Of the [crate::correlation], added when sent to the connectors
+    pub incident: Option<IncidentRef>,
+    /// See [crate::fingerprint]
+    pub suspected_family: Option<String>,
+}
+
+impl Escalated {
+    pub fn from(proc: &ProcessRecord, score: f32) -> Escalated {
+        Escalated {
+            time: SystemTime::now(),
+            gid: proc.gid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            score,
+            threshold: proc.threshold_prediction,
+            evidence: proc.escalation.evidence.clone(),
+            tags: Vec::new(),
+            incident: None,
+            suspected_family: proc.fingerprint.suspected_family().map(|suspected| suspected.family.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::escalation::{Escalation, Level, Transition};
+
+    #[test]
+    fn escalation_should_climb_and_cool_down() {
+        let t0 = SystemTime::now();
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+        let mut escalation = Escalation::new(0.5, 0.8, Duration::from_secs(10), Duration::from_secs(60));
+        assert_eq!(escalation.on_prediction(0.2, 0.7, at(0)), None);
+        assert_eq!(
+            escalation.on_prediction(0.4, 0.7, at(1)),
+            Some(Transition { from: Level::Normal, to: Level::Watch })
+        );
+        // above the threshold: PreAlert first, for the dwell
+        assert_eq!(
+            escalation.on_prediction(0.9, 0.7, at(2)),
+            Some(Transition { from: Level::Watch, to: Level::PreAlert })
+        );
+        assert_eq!(escalation.on_prediction(0.9, 0.7, at(5)), None);
+        // below for less than the cooldown
+        assert_eq!(escalation.on_prediction(0.1, 0.7, at(30)), None);
+        assert_eq!(escalation.on_prediction(0.1, 0.7, at(66)), Some(Transition { from: Level::PreAlert, to: Level::Watch }));
+        assert_eq!(escalation.on_prediction(0.6, 0.7, at(70)), Some(Transition { from: Level::Watch, to: Level::PreAlert }));
+        assert_eq!(escalation.on_prediction(0.9, 0.7, at(75)), None);
+        let alert = escalation.on_prediction(0.9, 0.7, at(80)).unwrap();
+        assert!(alert.entered(Level::Alert) && !alert.entered(Level::PreAlert));
+        assert_eq!(escalation.on_prediction(0.0, 0.7, at(1000)), None);
+        assert_eq!(escalation.level(), Level::Alert);
+
+        // without a dwell, the threshold kills at once, as before
+        let mut escalation = Escalation::new(0.5, 0.8, Duration::ZERO, Duration::from_secs(60));
+        let alert = escalation.on_prediction(0.9, 0.7, at(0)).unwrap();
+        assert!(alert.entered(Level::Watch) && alert.entered(Level::Alert));
+    }
+}
@@ -0,0 +1,96 @@
+//! Events detected by the pipeline workers, which cannot call the [Connectors] themselves: they
+//! are queued, then sent by the fetch stage. The kills are verified there first, see
+//! [crate::killcheck], while their executable is enriched (see [crate::enrichment]). They are
+//! correlated into the incidents of their gid first ([crate::correlation]).
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tracing::warn;
+
+use crate::autostart::Persistence;
+use crate::connectors::connector::Connectors;
+use crate::correlation::{incident_id, Correlator, IncidentRef};
+use crate::escalation::Escalated;
+use crate::exfil::PreAlert;
+use crate::killcheck::{KillRequest, KillVerifier};
+use crate::ransomnote::RansomNoteDropped;
+use crate::wiper::MassDeletion;
+
+/// Events waiting to be sent. Beyond, the oldest ones are dropped.
+const MAX_PENDING: usize = 100;
+
+#[derive(Debug, Clone)]
+pub enum WorkerEvent {
+    MassDeletion(MassDeletion),
+    RansomNote(RansomNoteDropped),
+    PreAlert(PreAlert),
+    Persistence(Persistence),
+    Escalated(Escalated),
+    KillIssued(KillRequest),
+}
+
+pub struct WorkerEvents {
+    pending: Mutex<VecDeque<WorkerEvent>>,
+}
+
+impl WorkerEvents {
+    pub fn new() -> WorkerEvents {
+        WorkerEvents {
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn push(&self, event: WorkerEvent) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            warn!("Too many pending events, the oldest one is dropped");
+            pending.pop_front();
+        }
+        pending.push_back(event);
+    }
+
+    /// Sends the pending events to the connectors, with their incident in *incidents*, but the
+    /// kills, handed to *kills*.
+    pub fn send(&self, connectors: &Connectors, incidents: &Correlator, kills: &mut KillVerifier) {
+        let events: Vec<WorkerEvent> = self.pending.lock().unwrap().drain(..).collect();
+        for event in events {
+            match event {
+                WorkerEvent::MassDeletion(mut event) => {
+                    event.incident = correlate(connectors, incidents, event.gid, &event.exepath, "mass_deletion", None);
+                    connectors.send_mass_deletion(&event)
+                }
+                WorkerEvent::RansomNote(mut event) => {
+                    event.incident = correlate(connectors, incidents, event.gid, &event.exepath, "ransom_note", None);
+                    connectors.send_ransom_note(&event)
+                }
+                WorkerEvent::PreAlert(mut event) => {
+                    event.incident = correlate(connectors, incidents, event.gid, &event.exepath, "exfil_pre_alert", None);
+                    connectors.send_pre_alert(&event)
+                }
+                WorkerEvent::Persistence(mut event) => {
+                    event.incident = correlate(connectors, incidents, event.gid, &event.exepath, "persistence", None);
+                    connectors.send_persistence(&event)
+                }
+                WorkerEvent::Escalated(mut event) => {
+                    event.incident = correlate(connectors, incidents, event.gid, &event.exepath, "escalation", Some(event.score));
+                    connectors.send_escalation(&event)
+                }
+                WorkerEvent::KillIssued(mut request) => {
+                    request.incident = correlate(connectors, incidents, request.gid, &request.exepath, "kill", Some(request.prediction));
+                    connectors.prefetch(&request.exepath);
+                    kills.watch(request)
+                }
+            }
+        }
+    }
+}
+
+/// The incident of an alert of type *kind* of *gid*.
+fn correlate(connectors: &Connectors, incidents: &Correlator, gid: u64, exepath: &Path, kind: &'static str, score: Option<f32>) -> Option<IncidentRef> {
+    Some(incidents.correlate(gid, kind, score, SystemTime::now(), || {
+        incident_id(&connectors.identity().machine_id, gid, exepath)
+    }))
+}
@@ -0,0 +1,386 @@
+//! Local store of the events, in the SQLite database *DebugPath\events.db* (*EVENT_STORE*), so
+//! that the history can be reviewed on a standalone machine:
+//! ```owlyshield_ransom events --since 24h --min-score 0.5```.
+//!
+//! Every [Envelope] sent to the connectors is recorded by [crate::connectors::localstore], with
+//! its type, gid and score, and the summary of its gid is updated: first and last events, count,
+//! highest score and outcome. The events older than *EVENT_STORE_DAYS*, and the oldest ones beyond
+//! *EVENT_STORE_MAX_EVENTS*, are deleted at startup then every [PRUNE_EVERY] events, with the
+//! summaries of the gids without recent events.
+
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, Row};
+
+use crate::config::{Config, Param};
+use crate::schema::{Envelope, Event, Family, KillOutcome};
+
+pub static STORE_FILE_NAME: &str = "events.db";
+/// Events recorded between two prunings.
+pub const PRUNE_EVERY: u64 = 1000;
+/// Types of the events counted as alerts by [EventStore::alerts].
+pub const ALERT_TYPES: [&str; 8] = ["detection", "escalation", "exfil_pre_alert", "mass_deletion", "ransom_note", "persistence", "raw_disk_write", "kill"];
+
+const TABLES: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    time INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    gid INTEGER,
+    appname TEXT,
+    score REAL,
+    envelope TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_time ON events (time);
+CREATE INDEX IF NOT EXISTS events_gid ON events (gid);
+CREATE TABLE IF NOT EXISTS gids (
+    gid INTEGER NOT NULL,
+    appname TEXT NOT NULL,
+    exepath TEXT NOT NULL,
+    first_time INTEGER NOT NULL,
+    last_time INTEGER NOT NULL,
+    events INTEGER NOT NULL,
+    max_score REAL,
+    outcome TEXT,
+    PRIMARY KEY (gid, appname)
+);";
+
+/// How long, and how many, events are kept.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub max_age: Duration,
+    pub max_events: u64,
+}
+
+impl Retention {
+    pub fn from(config: &Config) -> Retention {
+        Retention {
+            max_age: Duration::from_secs(config.get_usize(Param::EventStoreDays) as u64 * 24 * 3600),
+            max_events: config.get_usize(Param::EventStoreMaxEvents) as u64,
+        }
+    }
+}
+
+/// Criteria of the events, or of the gids, listed.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Age of the oldest event
+    pub since: Option<Duration>,
+    pub min_score: Option<f32>,
+    pub gid: Option<u64>,
+    /// Type of the events, ex: kill (ignored for the gids)
+    pub kind: Option<String>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredEvent {
+    pub time: SystemTime,
+    pub kind: String,
+    pub gid: Option<u64>,
+    pub appname: Option<String>,
+    pub score: Option<f32>,
+    /// As sent to the connectors
+    pub envelope: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GidSummary {
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: String,
+    pub first: SystemTime,
+    pub last: SystemTime,
+    pub events: u64,
+    pub max_score: Option<f32>,
+    /// Last action on the family: killed, suspended, exited...
+    pub outcome: Option<String>,
+}
+
+pub struct EventStore {
+    conn: Connection,
+}
+
+impl EventStore {
+    pub fn path(config: &Config) -> PathBuf {
+        config.get_path(Param::DebugPath).join(STORE_FILE_NAME)
+    }
+
+    /// Opens the database, created if needed. The agent writes while the CLI reads, hence the WAL
+    /// journal and the wait on the locks.
+    pub fn open(path: &std::path::Path) -> Result<EventStore, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        conn.execute_batch(TABLES)?;
+        Ok(EventStore { conn })
+    }
+
+    pub fn record(&self, envelope: &Envelope, now: SystemTime) -> Result<(), rusqlite::Error> {
+        let (kind, gid, family, score, outcome) = index(&envelope.event);
+        let time = secs(now);
+        let json = serde_json::to_string(envelope).unwrap_or_else(|_| String::from("{}"));
+        self.conn.execute(
+            "INSERT INTO events (time, type, gid, appname, score, envelope) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![time, kind, gid.map(|gid| gid as i64), family.map(|f| &f.appname), score.map(f64::from), json],
+        )?;
+        if let Some(family) = family {
+            self.conn.execute(
+                "INSERT INTO gids (gid, appname, exepath, first_time, last_time, events, max_score, outcome)
+                 VALUES (?1, ?2, ?3, ?4, ?4, 1, ?5, ?6)
+                 ON CONFLICT (gid, appname) DO UPDATE SET
+                     last_time = excluded.last_time,
+                     events = events + 1,
+                     max_score = CASE WHEN max_score IS NULL OR excluded.max_score > max_score
+                                 THEN excluded.max_score ELSE max_score END,
+                     outcome = IFNULL(excluded.outcome, outcome)",
+                params![family.gid as i64, family.appname, family.exepath, time, score.map(f64::from), outcome],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Takes the write lock of the database and releases it, without writing.
+    pub fn check_writable(&self) -> Result<(), rusqlite::Error> {
+        self.conn.execute_batch("BEGIN IMMEDIATE; ROLLBACK;")
+    }
+
+    /// Deletes what is beyond the *retention*, returning the count of events deleted.
+    pub fn prune(&self, retention: &Retention, now: SystemTime) -> Result<usize, rusqlite::Error> {
+        let cutoff = secs(now.checked_sub(retention.max_age).unwrap_or(UNIX_EPOCH));
+        let mut deleted = self.conn.execute("DELETE FROM events WHERE time < ?1", params![cutoff])?;
+        deleted += self.conn.execute(
+            "DELETE FROM events WHERE id <= (SELECT id FROM events ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+            params![retention.max_events as i64],
+        )?;
+        self.conn.execute("DELETE FROM gids WHERE last_time < ?1", params![cutoff])?;
+        Ok(deleted)
+    }
+
+    /// The events matching *filter*, the most recent first.
+    pub fn events(&self, filter: &Filter, now: SystemTime) -> Result<Vec<StoredEvent>, rusqlite::Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT time, type, gid, appname, score, envelope FROM events
+             WHERE time >= ?1 AND (?2 IS NULL OR score >= ?2) AND (?3 IS NULL OR gid = ?3)
+                 AND (?4 IS NULL OR type = ?4)
+             ORDER BY time DESC, id DESC LIMIT ?5",
+        )?;
+        let rows = statement.query_map(
+            params![
+                since(filter, now),
+                filter.min_score.map(f64::from),
+                filter.gid.map(|gid| gid as i64),
+                filter.kind,
+                filter.limit as i64
+            ],
+            |row: &Row| {
+                Ok(StoredEvent {
+                    time: time(row.get(0)?),
+                    kind: row.get(1)?,
+                    gid: row.get::<_, Option<i64>>(2)?.map(|gid| gid as u64),
+                    appname: row.get(3)?,
+                    score: row.get::<_, Option<f64>>(4)?.map(|score| score as f32),
+                    envelope: row.get(5)?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// The summaries of the gids matching *filter*, the most recent first.
+    pub fn gids(&self, filter: &Filter, now: SystemTime) -> Result<Vec<GidSummary>, rusqlite::Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT gid, appname, exepath, first_time, last_time, events, max_score, outcome FROM gids
+             WHERE last_time >= ?1 AND (?2 IS NULL OR max_score >= ?2) AND (?3 IS NULL OR gid = ?3)
+             ORDER BY last_time DESC LIMIT ?4",
+        )?;
+        let rows = statement.query_map(
+            params![since(filter, now), filter.min_score.map(f64::from), filter.gid.map(|gid| gid as i64), filter.limit as i64],
+            |row: &Row| {
+                Ok(GidSummary {
+                    gid: row.get::<_, i64>(0)? as u64,
+                    appname: row.get(1)?,
+                    exepath: row.get(2)?,
+                    first: time(row.get(3)?),
+                    last: time(row.get(4)?),
+                    events: row.get::<_, i64>(5)? as u64,
+                    max_score: row.get::<_, Option<f64>>(6)?.map(|score| score as f32),
+                    outcome: row.get(7)?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// Count of the alerts per type and per *bucket* of time (UTC), between *from* and *to*.
+    pub fn alerts(&self, from: SystemTime, to: SystemTime, bucket: Duration) -> Result<Vec<(String, SystemTime, u64)>, rusqlite::Error> {
+        let types = ALERT_TYPES.iter().map(|kind| format!("'{}'", kind)).collect::<Vec<String>>().join(", ");
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT type, time / ?3 * ?3 AS bucket, COUNT(*) FROM events
+             WHERE time >= ?1 AND time < ?2 AND type IN ({})
+             GROUP BY type, bucket ORDER BY bucket, type",
+            types
+        ))?;
+        let rows = statement.query_map(params![secs(from), secs(to), bucket.as_secs().max(1) as i64], |row: &Row| {
+            Ok((row.get(0)?, time(row.get(1)?), row.get::<_, i64>(2)? as u64))
+        })?;
+        rows.collect()
+    }
+
+    /// The *limit* gids which wrote the most between *from* and *to*, with their bytes written (the
+    /// last count of their detections and summaries).
+    pub fn top_writers(&self, from: SystemTime, to: SystemTime, limit: usize) -> Result<Vec<(u64, String, u64)>, rusqlite::Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT gid, appname, MAX(CAST(json_extract(envelope, '$.event.bytes_written') AS INTEGER)) AS bytes
+             FROM events
+             WHERE time >= ?1 AND time < ?2 AND type IN ('detection', 'process_terminated') AND gid IS NOT NULL
+             GROUP BY gid, appname HAVING bytes IS NOT NULL
+             ORDER BY bytes DESC LIMIT ?3",
+        )?;
+        let rows = statement.query_map(params![secs(from), secs(to), limit as i64], |row: &Row| {
+            Ok((row.get::<_, i64>(0)? as u64, row.get(1)?, row.get::<_, i64>(2)? as u64))
+        })?;
+        rows.collect()
+    }
+
+    /// Count of the process families terminated between *from* and *to*, per tenth of their
+    /// highest score.
+    pub fn score_histogram(&self, from: SystemTime, to: SystemTime) -> Result<[u64; 10], rusqlite::Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT CAST(score * 10 AS INTEGER) AS tenth, COUNT(*) FROM events
+             WHERE time >= ?1 AND time < ?2 AND type = 'process_terminated' AND score IS NOT NULL
+             GROUP BY tenth",
+        )?;
+        let mut histogram = [0; 10];
+        let rows = statement.query_map(params![secs(from), secs(to)], |row: &Row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+        for row in rows {
+            let (tenth, count) = row?;
+            histogram[tenth.clamp(0, 9) as usize] += count;
+        }
+        Ok(histogram)
+    }
+}
+
+/// Type, gid, family, score and outcome of an event, as indexed.
+fn index(event: &Event) -> (&'static str, Option<u64>, Option<&Family>, Option<f32>, Option<String>) {
+    match event {
+        Event::Detection(e) => ("detection", Some(e.family.gid), Some(&e.family), Some(e.prediction), Some(e.action.clone())),
+        Event::Escalation(e) => ("escalation", Some(e.family.gid), Some(&e.family), Some(e.score), None),
+        Event::ExfilPreAlert(e) => ("exfil_pre_alert", Some(e.family.gid), Some(&e.family), None, None),
+        Event::MassDeletion(e) => ("mass_deletion", Some(e.family.gid), Some(&e.family), None, None),
+        Event::RansomNote(e) => ("ransom_note", Some(e.family.gid), Some(&e.family), None, None),
+        Event::Persistence(e) => ("persistence", Some(e.family.gid), Some(&e.family), None, None),
+        Event::RawDiskWrite(e) => ("raw_disk_write", Some(e.gid), None, None, None),
+        Event::Kill(e) => {
+            let outcome = match e.outcome {
+                KillOutcome::Exited | KillOutcome::Rekilled => "killed",
+                KillOutcome::Failed => "survived the kill",
+            };
+            ("kill", Some(e.family.gid), Some(&e.family), Some(e.prediction), Some(String::from(outcome)))
+        }
+        Event::ProcessTerminated(e) => {
+            let outcome = if e.process_state == "RUNNING" { String::from("exited") } else { e.process_state.to_lowercase() };
+            ("process_terminated", Some(e.family.gid), Some(&e.family), e.max_prediction, Some(outcome))
+        }
+        Event::ProfileChange(_) => ("profile_change", None, None, None, None),
+        Event::ConnectorDegraded(_) => ("connector_degraded", None, None, None, None),
+        Event::AgentIncident(_) => ("agent_incident", None, None, None, None),
+    }
+}
+
+/// *30m*, *24h*, *7d*, *2w*...
+pub fn parse_age(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let count: u64 = value[..split].parse().map_err(|_| format!("{}: expected a number and a unit, ex: 24h", value))?;
+    let unit = match &value[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        "w" => 7 * 24 * 3600,
+        unit => return Err(format!("{}: unknown unit {:?}, expected s, m, h, d or w", value, unit)),
+    };
+    Ok(Duration::from_secs(count * unit))
+}
+
+fn since(filter: &Filter, now: SystemTime) -> i64 {
+    filter.since.map_or(0, |since| secs(now.checked_sub(since).unwrap_or(UNIX_EPOCH)))
+}
+
+fn secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+fn time(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::eventstore::{parse_age, EventStore, Filter, Retention};
+    use crate::schema::{Envelope, Escalation, Event, Family, RawDiskSource, RawDiskWrite};
+
+    #[test]
+    fn events_should_be_queried_and_pruned() {
+        let envelope = |event: Event| Envelope {
+            schema_version: String::from("1.1"),
+            agent: serde_json::from_value(serde_json::json!({
+                "machine_id": "6f1c", "hostname": "srv-files", "os_version": "Windows 10", "domain": null, "agent_version": "1.2.0"
+            }))
+            .unwrap(),
+            event,
+        };
+        let escalation = |gid: u64, score: f32| {
+            envelope(Event::Escalation(Escalation {
+                time: String::from("2026-10-16T09:12:46Z"),
+                family: Family { gid, appname: String::from("locker.exe"), exepath: String::from(r"C:\locker.exe") },
+                score,
+                threshold: 0.65,
+                memory_verdict: None,
+                dropped: Vec::new(),
+                tags: Vec::new(),
+                incident: None,
+                suspected_family: None,
+            }))
+        };
+        let raw_disk = envelope(Event::RawDiskWrite(RawDiskWrite {
+            time: String::from("2026-10-16T09:12:47Z"),
+            gid: 42,
+            pid: 4242,
+            device: String::from(r"\Device\Harddisk0\DR0"),
+            mount_point: None,
+            source: RawDiskSource::Driver,
+        }));
+        let store = EventStore::open(std::path::Path::new(":memory:")).unwrap();
+        store.check_writable().unwrap();
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 3600);
+        store.record(&escalation(7, 0.4), now - 3 * day).unwrap();
+        store.record(&escalation(42, 0.3), now - day / 2).unwrap();
+        store.record(&escalation(42, 0.8), now - day / 4).unwrap();
+        store.record(&raw_disk, now).unwrap();
+
+        let filter = Filter { since: Some(parse_age("24h").unwrap()), min_score: Some(0.5), limit: 10, ..Filter::default() };
+        let events = store.events(&filter, now).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].kind.as_str(), events[0].gid, events[0].score), ("escalation", Some(42), Some(0.8)));
+        assert_eq!(serde_json::from_str::<Envelope>(&events[0].envelope).unwrap(), escalation(42, 0.8));
+        let all = store.events(&Filter { limit: 10, ..Filter::default() }, now).unwrap();
+        assert_eq!(all.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>(), ["raw_disk_write", "escalation", "escalation", "escalation"]);
+        let gids = store.gids(&Filter { limit: 10, ..Filter::default() }, now).unwrap();
+        assert_eq!(gids.iter().map(|g| (g.gid, g.events, g.max_score)).collect::<Vec<_>>(), [(42, 2, Some(0.8)), (7, 1, Some(0.4))]);
+
+        let deleted = store.prune(&Retention { max_age: 2 * day, max_events: 2 }, now).unwrap();
+        assert_eq!(deleted, 2);
+        assert_eq!(store.events(&Filter { limit: 10, ..Filter::default() }, now).unwrap().len(), 2);
+        assert_eq!(store.gids(&Filter { limit: 10, ..Filter::default() }, now).unwrap().len(), 1);
+        assert_eq!(parse_age("7d").unwrap(), 7 * day);
+        assert!(parse_age("7y").is_err() && parse_age("h").is_err());
+    }
+}
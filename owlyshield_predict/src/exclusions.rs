@@ -0,0 +1,486 @@
+//! Exclusion rules for processes that should never be killed, or not even monitored.
+//!
+//! Rules are read from ```exclusions.toml``` in *ConfigPath* and reloaded when the file changes:
+//! ```toml
+//! [never_monitor]
+//! paths = ['C:\Program Files\Veeam\**\*.exe']
+//!
+//! [never_kill]
+//! signers = ["Microsoft Corporation"]
+//! sha256 = ["e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"]
+//! users = ["S-1-5-18"]
+//!
+//! [[user_policies]]
+//! service_accounts = true
+//! threshold_prediction = 0.5
+//!
+//! [[user_policies]]
+//! users = ['CORP\svc_backup']
+//! threshold_prediction = 0.6
+//!
+//! [[user_policies]]
+//! wsl = true
+//! threshold_prediction = 0.8
+//! ```
+//! They are checked once per gid, at first sight, before any feature is computed. Criteria are
+//! evaluated from the cheapest (path) to the most expensive (hash), and only if rules need them.
+//! The *signers* rules only match the signatures verified by WinVerifyTrust (once per version of
+//! each executable), and never the LOLBins ([crate::lolbin]).
+//!
+//! Users are given by SID or *DOMAIN\user*. The first user policy matching the owner of a gid
+//! overrides its thresholds (stricter ones for the service accounts, typically). A policy with
+//! *wsl* matches the WSL hosts, whose gid aggregates all the Linux processes ([crate::wsl]).
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use std::{thread, time};
+
+use glob::{MatchOptions, Pattern};
+use tracing::error;
+use serde::Deserialize;
+
+#[cfg(windows)]
+pub(crate) use crate::signer::{is_trusted, signer_subject};
+use crate::lolbin;
+use crate::token::{owner_from_pid, ProcessOwner};
+use crate::utils::sha256_file;
+use crate::wsl;
+
+/// Authenticode signatures only exist on Windows: the *signers* rules never match elsewhere.
+#[cfg(not(windows))]
+pub(crate) fn signer_subject(_path: &Path) -> Option<String> {
+    None
+}
+
+#[cfg(not(windows))]
+pub(crate) fn is_trusted(_path: &Path) -> bool {
+    false
+}
+
+/// What an exclusion rule allows.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExclusionScope {
+    /// The gid is ignored: no [crate::process::ProcessRecord] is created.
+    NeverMonitor,
+    /// The gid is monitored and predictions are made, but it is never killed nor suspended.
+    NeverKill,
+}
+
+/// Rules as written in the toml file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RulesFile {
+    paths: Vec<String>,
+    signers: Vec<String>,
+    sha256: Vec<String>,
+    users: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct UserPolicyFile {
+    users: Vec<String>,
+    service_accounts: bool,
+    wsl: bool,
+    threshold_prediction: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ExclusionsFile {
+    never_monitor: RulesFile,
+    never_kill: RulesFile,
+    user_policies: Vec<UserPolicyFile>,
+}
+
+/// Overrides of the configuration for the processes of some users.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UserPolicy {
+    users: HashSet<String>,
+    service_accounts: bool,
+    wsl: bool,
+    /// Instead of *THRESHOLD_PREDICTION*
+    pub threshold_prediction: Option<f32>,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    paths: Vec<Pattern>,
+    signers: HashSet<String>,
+    sha256: HashSet<String>,
+    users: HashSet<String>,
+}
+
+/// The results of WinVerifyTrust, by executable, with its modification time and size.
+#[derive(Debug, Default)]
+struct TrustCache(Mutex<HashMap<PathBuf, (Option<SystemTime>, u64, bool)>>);
+
+#[derive(Debug, Default)]
+struct ExclusionSet {
+    never_monitor: Rules,
+    never_kill: Rules,
+    user_policies: Vec<UserPolicy>,
+    file_time: Option<SystemTime>,
+}
+
+/// The process to check against the rules. Expensive attributes are computed once, on demand.
+pub struct ExclusionSubject<'a> {
+    pub exepath: &'a Path,
+    pub pid: u32,
+    sha256: Option<Option<String>>,
+    signer: Option<Option<String>>,
+    trusted: Option<bool>,
+    owner: Option<Option<ProcessOwner>>,
+}
+
+impl<'a> ExclusionSubject<'a> {
+    pub fn new(exepath: &'a Path, pid: u32) -> ExclusionSubject<'a> {
+        ExclusionSubject {
+            exepath,
+            pid,
+            sha256: None,
+            signer: None,
+            trusted: None,
+            owner: None,
+        }
+    }
+
+    fn sha256(&mut self) -> Option<&String> {
+        if self.sha256.is_none() {
+            self.sha256 = Some(sha256_file(self.exepath).ok());
+        }
+        self.sha256.as_ref().unwrap().as_ref()
+    }
+
+    /// Lowercase subject of the signer. None for the LOLBins, whose signature says nothing of what
+    /// they run (see [crate::lolbin]).
+    pub fn signer(&mut self) -> Option<&String> {
+        if self.signer.is_none() {
+            let signer = if lolbin::is_lolbin(self.exepath) { None } else { signer_subject(self.exepath) };
+            self.signer = Some(signer.map(|s| s.to_lowercase()));
+        }
+        self.signer.as_ref().unwrap().as_ref()
+    }
+
+    pub fn owner(&mut self) -> Option<&ProcessOwner> {
+        if self.owner.is_none() {
+            self.owner = Some(owner_from_pid(self.pid));
+        }
+        self.owner.as_ref().unwrap().as_ref()
+    }
+}
+
+impl TrustCache {
+    /// Whether the signature of the subject is verified, see [crate::signer::is_trusted].
+    fn is_trusted(&self, subject: &mut ExclusionSubject) -> bool {
+        if let Some(trusted) = subject.trusted {
+            return trusted;
+        }
+        let metadata = fs::metadata(subject.exepath).ok();
+        let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+        let len = metadata.as_ref().map_or(0, |m| m.len());
+        let cached = self
+            .0
+            .lock()
+            .unwrap()
+            .get(subject.exepath)
+            .filter(|(cached_modified, cached_len, _)| (*cached_modified, *cached_len) == (modified, len))
+            .map(|(_, _, trusted)| *trusted);
+        // WinVerifyTrust may check the revocations online: not under the lock
+        let trusted = cached.unwrap_or_else(|| {
+            let trusted = is_trusted(subject.exepath);
+            self.0.lock().unwrap().insert(subject.exepath.to_path_buf(), (modified, len, trusted));
+            trusted
+        });
+        subject.trusted = Some(trusted);
+        trusted
+    }
+}
+
+impl UserPolicy {
+    fn from(policy_file: &UserPolicyFile) -> UserPolicy {
+        UserPolicy {
+            users: policy_file.users.iter().map(|u| u.to_uppercase()).collect(),
+            service_accounts: policy_file.service_accounts,
+            wsl: policy_file.wsl,
+            threshold_prediction: policy_file.threshold_prediction,
+        }
+    }
+
+    fn matches(&self, subject: &mut ExclusionSubject) -> bool {
+        if self.wsl && wsl::is_wsl_host(subject.exepath) {
+            return true;
+        }
+        match subject.owner() {
+            Some(owner) => (self.service_accounts && owner.is_service_account()) || is_user_in(&self.users, owner),
+            None => false,
+        }
+    }
+}
+
+/// Whether the SID or the name of *owner* is in *users* (uppercase).
+fn is_user_in(users: &HashSet<String>, owner: &ProcessOwner) -> bool {
+    users.contains(&owner.sid.to_uppercase())
+        || owner
+            .username
+            .as_ref()
+            .map_or(false, |name| users.contains(&name.to_uppercase()))
+}
+
+impl Rules {
+    fn from(rules_file: &RulesFile) -> Rules {
+        Rules {
+            paths: rules_file
+                .paths
+                .iter()
+                .filter_map(|p| match Pattern::new(p) {
+                    Ok(pattern) => Some(pattern),
+                    Err(e) => {
+                        error!("Invalid exclusion path pattern {}: {}", p, e);
+                        None
+                    }
+                })
+                .collect(),
+            signers: rules_file.signers.iter().map(|s| s.to_lowercase()).collect(),
+            sha256: rules_file.sha256.iter().map(|s| s.to_lowercase()).collect(),
+            users: rules_file.users.iter().map(|s| s.to_uppercase()).collect(),
+        }
+    }
+
+    fn matches(&self, subject: &mut ExclusionSubject, trust: &TrustCache) -> bool {
+        let options = MatchOptions {
+            case_sensitive: false,
+            require_literal_separator: false,
+            require_literal_leading_dot: false,
+        };
+        if self.paths.iter().any(|p| p.matches_path_with(subject.exepath, options)) {
+            return true;
+        }
+        if !self.users.is_empty() {
+            if let Some(owner) = subject.owner() {
+                if is_user_in(&self.users, owner) {
+                    return true;
+                }
+            }
+        }
+        if !self.signers.is_empty() {
+            let listed = subject.signer().is_some_and(|signer| self.signers.contains(signer));
+            if listed && trust.is_trusted(subject) {
+                return true;
+            }
+        }
+        if !self.sha256.is_empty() {
+            if let Some(hash) = subject.sha256() {
+                if self.sha256.contains(hash) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl ExclusionSet {
+    fn load(path: &Path) -> Result<ExclusionSet, String> {
+        let file_time = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let file: ExclusionsFile = toml::from_str(&content).map_err(|e| e.to_string())?;
+        Ok(ExclusionSet {
+            never_monitor: Rules::from(&file.never_monitor),
+            never_kill: Rules::from(&file.never_kill),
+            user_policies: file.user_policies.iter().map(UserPolicy::from).collect(),
+            file_time,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Exclusions {
+    set: Arc<Mutex<ExclusionSet>>,
+    path: Arc<PathBuf>,
+    /// Incremented at each reload, so that callers can invalidate their caches.
+    version: Arc<AtomicU64>,
+    trust: TrustCache,
+}
+
+impl Exclusions {
+    /// A missing file means no exclusions.
+    pub fn from(path: &Path) -> Exclusions {
+        let set = if path.exists() {
+            ExclusionSet::load(path).unwrap_or_else(|e| {
+                error!("Cannot load exclusions file {}: {}", path.display(), e);
+                ExclusionSet::default()
+            })
+        } else {
+            ExclusionSet::default()
+        };
+        Exclusions {
+            set: Arc::new(Mutex::new(set)),
+            path: Arc::new(PathBuf::from(path)),
+            version: Arc::new(AtomicU64::new(0)),
+            trust: TrustCache::default(),
+        }
+    }
+
+    /// The error of the file at *path*, which [Self::from] logs and replaces by no exclusions.
+    pub fn check(path: &Path) -> Result<(), String> {
+        ExclusionSet::load(path).map(|_| ())
+    }
+
+    /// Returns the most permissive scope matching the subject, if any.
+    pub fn get_scope(&self, subject: &mut ExclusionSubject) -> Option<ExclusionScope> {
+        let set = self.set.lock().unwrap();
+        if set.never_monitor.matches(subject, &self.trust) {
+            Some(ExclusionScope::NeverMonitor)
+        } else if set.never_kill.matches(subject, &self.trust) {
+            Some(ExclusionScope::NeverKill)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the subject is never monitored because of its signer, with a verified signature:
+    /// the only exclusion trusted enough to mute the gid in the driver.
+    pub fn is_never_monitored_signer(&self, subject: &mut ExclusionSubject) -> bool {
+        let set = self.set.lock().unwrap();
+        let listed = subject.signer().is_some_and(|signer| set.never_monitor.signers.contains(signer));
+        listed && self.trust.is_trusted(subject)
+    }
+
+    /// Returns the first user policy matching the owner of the subject (or the subject, for
+    /// *wsl*), if any.
+    pub fn get_user_policy(&self, subject: &mut ExclusionSubject) -> Option<UserPolicy> {
+        let set = self.set.lock().unwrap();
+        set.user_policies.iter().find(|p| p.matches(subject)).cloned()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    /// Adds a rule to the file and reloads it. *kind* is *paths*, *signers*, *sha256* or *users*.
+    /// The comments of the file are not kept.
+    pub fn add(&self, scope: ExclusionScope, kind: &str, value: &str) -> Result<(), String> {
+        let content = if self.path.exists() {
+            fs::read_to_string(self.path.as_path()).map_err(|e| e.to_string())?
+        } else {
+            String::new()
+        };
+        let content = add_rule(&content, scope, kind, value)?;
+        fs::write(self.path.as_path(), content).map_err(|e| e.to_string())?;
+        let set = ExclusionSet::load(&self.path)?;
+        *self.set.lock().unwrap() = set;
+        self.version.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Replaces the whole file, if *content* is valid, and reloads it.
+    pub fn replace(&self, content: &str) -> Result<(), String> {
+        toml::from_str::<ExclusionsFile>(content).map_err(|e| e.to_string())?;
+        fs::write(self.path.as_path(), content).map_err(|e| e.to_string())?;
+        let set = ExclusionSet::load(&self.path)?;
+        *self.set.lock().unwrap() = set;
+        self.version.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn refresh_periodically(&self) {
+        let set_bis = Arc::clone(&self.set);
+        let path_bis = Arc::clone(&self.path);
+        let version_bis = Arc::clone(&self.version);
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(10));
+            let file_time = fs::metadata(path_bis.as_path()).and_then(|m| m.modified()).ok();
+            if file_time != set_bis.lock().unwrap().file_time {
+                if file_time.is_none() {
+                    // The file has been removed
+                    *set_bis.lock().unwrap() = ExclusionSet::default();
+                    version_bis.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                match ExclusionSet::load(&path_bis) {
+                    Ok(set) => {
+                        *set_bis.lock().unwrap() = set;
+                        version_bis.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => error!("Cannot reload exclusions file {}: {}", path_bis.display(), e),
+                }
+            }
+        });
+    }
+}
+
+/// *content* of the exclusions file, with *value* added to the *kind* rules of *scope*.
+fn add_rule(content: &str, scope: ExclusionScope, kind: &str, value: &str) -> Result<String, String> {
+    if !["paths", "signers", "sha256", "users"].contains(&kind) {
+        return Err(format!("Unknown kind of rule {}", kind));
+    }
+    if kind == "paths" {
+        Pattern::new(value).map_err(|e| format!("Invalid path pattern {}: {}", value, e))?;
+    }
+    let mut file: toml::Value = if content.trim().is_empty() {
+        toml::Value::Table(toml::value::Table::new())
+    } else {
+        content.parse().map_err(|e: toml::de::Error| e.to_string())?
+    };
+    let section = match scope {
+        ExclusionScope::NeverMonitor => "never_monitor",
+        ExclusionScope::NeverKill => "never_kill",
+    };
+    let rules = file
+        .as_table_mut()
+        .ok_or("Invalid exclusions file")?
+        .entry(section)
+        .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| format!("{} is not a table", section))?
+        .entry(kind)
+        .or_insert_with(|| toml::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| format!("{}.{} is not an array", section, kind))?;
+    let value = toml::Value::String(String::from(value));
+    if !rules.contains(&value) {
+        rules.push(value);
+    }
+    toml::to_string(&file).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::exclusions::{add_rule, ExclusionScope, ExclusionSubject, Rules, RulesFile, TrustCache};
+
+    #[test]
+    fn rule_should_be_added_once() {
+        let content = "[never_kill]\nsigners = [\"Microsoft Corporation\"]\n";
+        let content = add_rule(content, ExclusionScope::NeverKill, "paths", r"C:\Tools\*.exe").unwrap();
+        let content = add_rule(&content, ExclusionScope::NeverKill, "paths", r"C:\Tools\*.exe").unwrap();
+        let file: toml::Value = content.parse().unwrap();
+        assert_eq!(file["never_kill"]["paths"].as_array().unwrap().len(), 1);
+        assert_eq!(file["never_kill"]["signers"][0].as_str(), Some("Microsoft Corporation"));
+        assert!(add_rule("", ExclusionScope::NeverMonitor, "names", "foo").is_err());
+    }
+
+    #[test]
+    fn signers_should_match_the_verified_signatures_only() {
+        let rules = Rules::from(&RulesFile {
+            signers: vec![String::from("Acme Corp")],
+            ..RulesFile::default()
+        });
+        let trust = TrustCache::default();
+        let subject = |signer: &str, trusted: bool| {
+            let mut subject = ExclusionSubject::new(Path::new(r"C:\Program Files\Acme\acme.exe"), 42);
+            subject.signer = Some(Some(signer.to_lowercase()));
+            subject.trusted = Some(trusted);
+            subject
+        };
+        assert!(rules.matches(&mut subject("Acme Corp", true), &trust));
+        assert!(!rules.matches(&mut subject("Acme Corp", false), &trust));
+        assert!(!rules.matches(&mut subject("Evil Corp", true), &trust));
+    }
+}
@@ -0,0 +1,215 @@
+//! Detection of the staging of documents for exfiltration: many user documents read, then packed
+//! into a large compressed archive, ready to be uploaded (double extortion).
+//!
+//! Once a gid has read *EXFIL_DOCS_READ* documents within *EXFIL_WINDOW_SECS* seconds, the files
+//! it writes with a high entropy are inspected when closed. Those of at least *EXFIL_ARCHIVE_MB*
+//! megabytes with a ZIP, RAR or 7z signature ([crate::magic::archive_format]) are reported in a
+//! [PreAlert], with the path of the archive so that it can be quarantined before the upload. The
+//! documents themselves are not archives: the writes of the Docs extensions are ignored by the
+//! caller, and the OOXML and ODF packages by their content. The gid is not killed.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Config, Param};
+use crate::correlation::IncidentRef;
+use crate::magic;
+use crate::process::ProcessRecord;
+use crate::utils::extended_path;
+
+/// Entropy (bits per byte) of the writes of a compressed archive.
+const ARCHIVE_ENTROPY: f64 = 7.0;
+/// Archives being written, waiting to be closed.
+const MAX_CANDIDATES: usize = 16;
+const MB: u64 = 1024 * 1024;
+
+/// An archive staged for exfiltration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagedArchive {
+    pub path: PathBuf,
+    /// *zip*, *rar* or *7z*
+    pub format: &'static str,
+    pub size: u64,
+    pub docs_read: usize,
+}
+
+/// The documents read and archives written by a gid.
+#[derive(Debug)]
+pub struct ExfilMonitor {
+    min_docs: usize,
+    min_size: u64,
+    window: Duration,
+    /// Time of the last read by hash of the path of the documents, at most twice *min_docs*
+    docs_read: HashMap<u64, SystemTime>,
+    candidates: HashSet<Arc<str>>,
+    reported: HashSet<PathBuf>,
+    staged: Vec<StagedArchive>,
+}
+
+impl ExfilMonitor {
+    pub fn from(config: &Config) -> ExfilMonitor {
+        ExfilMonitor::new(
+            config.get_usize(Param::ExfilDocsRead),
+            config.get_usize(Param::ExfilArchiveMb) as u64 * MB,
+            Duration::from_secs(config.get_usize(Param::ExfilWindowSecs) as u64),
+        )
+    }
+
+    pub fn new(min_docs: usize, min_size: u64, window: Duration) -> ExfilMonitor {
+        ExfilMonitor {
+            min_docs,
+            min_size,
+            window,
+            docs_read: HashMap::new(),
+            candidates: HashSet::new(),
+            reported: HashSet::new(),
+            staged: Vec::new(),
+        }
+    }
+
+    /// The documents read within the window before *now*.
+    fn docs_read_in_window(&self, now: SystemTime) -> usize {
+        let window = self.window;
+        self.docs_read.values().filter(|t| now.duration_since(**t).unwrap_or(Duration::ZERO) <= window).count()
+    }
+
+    fn is_armed(&self, now: SystemTime) -> bool {
+        self.min_docs > 0 && self.docs_read_in_window(now) >= self.min_docs
+    }
+
+    /// A document has been read.
+    pub fn on_doc_read(&mut self, path: &str, now: SystemTime) {
+        if self.min_docs == 0 {
+            return;
+        }
+        let mut hasher = DefaultHasher::new();
+        path.to_lowercase().hash(&mut hasher);
+        let hash = hasher.finish();
+        if !self.docs_read.contains_key(&hash) && self.docs_read.len() >= 2 * self.min_docs {
+            let window = self.window;
+            self.docs_read.retain(|_, t| now.duration_since(*t).unwrap_or(Duration::ZERO) <= window);
+            if self.docs_read.len() >= 2 * self.min_docs {
+                if let Some(oldest) = self.docs_read.iter().min_by_key(|(_, t)| **t).map(|(hash, _)| *hash) {
+                    self.docs_read.remove(&oldest);
+                }
+            }
+        }
+        self.docs_read.insert(hash, now);
+    }
+
+    /// A write of *entropy* bits per byte to *path*.
+    pub fn on_write(&mut self, path: &Arc<str>, entropy: f64, now: SystemTime) {
+        if entropy >= ARCHIVE_ENTROPY && self.candidates.len() < MAX_CANDIDATES && self.is_armed(now) {
+            self.candidates.insert(path.clone());
+        }
+    }
+
+    pub fn is_pending(&self, path: &str) -> bool {
+        self.candidates.contains(path)
+    }
+
+    /// *path* has been closed: reported if it is a large archive.
+    pub fn on_closed(&mut self, path: &str, now: SystemTime) {
+        if !self.candidates.remove(path) {
+            return;
+        }
+        let path = Path::new(path);
+        let size = fs::metadata(extended_path(path)).map_or(0, |m| m.len());
+        if size < self.min_size || self.reported.contains(path) {
+            return;
+        }
+        if let Some(format) = magic::archive_format(path) {
+            self.reported.insert(path.to_path_buf());
+            self.staged.push(StagedArchive {
+                path: path.to_path_buf(),
+                format,
+                size,
+                docs_read: self.docs_read_in_window(now),
+            });
+        }
+    }
+
+    /// The archives detected since the last call.
+    pub fn take_staged(&mut self) -> Vec<StagedArchive> {
+        std::mem::take(&mut self.staged)
+    }
+
+    /// The archives detected, for the reports.
+    pub fn reported(&self) -> impl Iterator<Item = &PathBuf> {
+        self.reported.iter()
+    }
+}
+
+/// An archive of documents about to be exfiltrated, sent before the gid is killed, if ever.
+#[derive(Debug, Clone)]
+pub struct PreAlert {
+    pub time: SystemTime,
+    pub gid: u64,
+    pub pid: u32,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub archive: StagedArchive,
+    /// Of the [crate::enrichment] providers, added when sent to the connectors
+    pub tags: Vec<String>,
+    /// Of the [crate::correlation], added when sent to the connectors
+    pub incident: Option<IncidentRef>,
+}
+
+impl PreAlert {
+    pub fn from(proc: &ProcessRecord, pid: u32, archive: StagedArchive) -> PreAlert {
+        PreAlert {
+            time: SystemTime::now(),
+            gid: proc.gid,
+            pid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            archive,
+            tags: Vec::new(),
+            incident: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use crate::exfil::ExfilMonitor;
+
+    #[test]
+    fn large_archive_after_many_documents_should_be_staged() {
+        let path = std::env::temp_dir().join("owlyshield_exfil_test.bin");
+        let mut file = fs::File::create(&path).unwrap();
+        file.write_all(b"7z\xBC\xAF\x27\x1C").unwrap();
+        file.write_all(&[0x5A; 2048]).unwrap();
+        drop(file);
+        let archive: Arc<str> = Arc::from(path.to_str().unwrap());
+
+        let now = SystemTime::now();
+        let mut exfil = ExfilMonitor::new(3, 1024, Duration::from_secs(60));
+        exfil.on_write(&archive, 7.9, now);
+        assert!(!exfil.is_pending(&archive));
+        for doc in ["a.docx", "b.xlsx", "A.DOCX", "c.pdf"] {
+            exfil.on_doc_read(doc, now);
+        }
+        // the documents were read too long ago
+        exfil.on_write(&archive, 7.9, now + Duration::from_secs(120));
+        assert!(!exfil.is_pending(&archive));
+        exfil.on_write(&archive, 7.9, now);
+        exfil.on_closed(&archive, now);
+        exfil.on_write(&archive, 7.9, now);
+        exfil.on_closed(&archive, now);
+        let staged = exfil.take_staged();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(staged.len(), 1);
+        assert_eq!((staged[0].format, staged[0].size, staged[0].docs_read), ("7z", 2054, 3));
+    }
+}
@@ -9,6 +9,8 @@ use crate::extensions::ExtensionCategory::*;
 pub struct ExtensionsCount<'a> {
     pub categories_set: HashMap<ExtensionCategory, HashSet<String>>,
     extensionlist: &'a ExtensionList,
+    /// Number of extensions in all the categories, maintained by [Self::add_cat_extension]
+    total: usize,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, EnumIter)]
@@ -25,6 +27,10 @@ pub enum ExtensionCategory {
 #[derive(Debug)]
 pub struct ExtensionList {
     pub categories: HashMap<ExtensionCategory, Vec<&'static str>>,
+    /// Reverse index of *categories*
+    by_extension: HashMap<&'static str, ExtensionCategory>,
+    /// Extensions of known ransomwares (lowercase, without dot), see [crate::fastpath]
+    ransom_extensions: HashSet<String>,
 }
 
 impl ExtensionList {
@@ -57,19 +63,38 @@ impl ExtensionList {
         categories.insert(Exe, exe);
         categories.insert(Others, others);
 
+        let mut by_extension = HashMap::new();
+        for (cat, extensions) in &categories {
+            for extension in extensions {
+                by_extension.insert(*extension, *cat);
+            }
+        }
+
         ExtensionList {
             categories: categories,
+            by_extension: by_extension,
+            ransom_extensions: HashSet::new(),
         }
     }
 
+    /// *ransom_extensions* is a comma separated list, like *RANSOM_EXTENSIONS*.
+    pub fn with_ransom_extensions(ransom_extensions: &str) -> ExtensionList {
+        let mut list = ExtensionList::new();
+        list.ransom_extensions = ransom_extensions
+            .split(',')
+            .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+            .filter(|e| !e.is_empty())
+            .collect();
+        list
+    }
+
+    pub fn is_ransom_extension(&self, extension: &str) -> bool {
+        self.ransom_extensions.contains(&extension.to_lowercase())
+    }
+
     pub fn get_extension_category(&self, extension: &str) -> ExtensionCategory {
         let extension_low = &extension.to_lowercase();
-        for (k, v) in &self.categories {
-            if v.contains(&&**extension_low) {
-                return *k;
-            }
-        }
-        return Others;
+        self.by_extension.get(&**extension_low).copied().unwrap_or(Others)
     }
 }
 
@@ -82,13 +107,12 @@ impl ExtensionsCount<'_> {
         ExtensionsCount {
             categories_set: cats_entries,
             extensionlist: extensionlist,
+            total: 0,
         }
     }
 
     pub fn count_all(&self) -> usize {
-        ExtensionCategory::iter()
-            .map(|c| self.categories_set[&c].len())
-            .sum()
+        self.total
     }
 
     pub fn count_category(&self, cat: ExtensionCategory) -> usize {
@@ -100,7 +124,10 @@ impl ExtensionsCount<'_> {
         if !extension.is_empty() {
             let extension_category = self.extensionlist.get_extension_category(extension);
             let val = self.categories_set.get_mut(&extension_category).unwrap();
-            val.insert(String::from(extension));
+            if !val.contains(extension) {
+                val.insert(String::from(extension));
+                self.total += 1;
+            }
         }
     }
 }
@@ -0,0 +1,214 @@
+//! Learned profiles of the extensions written by each executable of this machine. A process
+//! suddenly writing extensions its executable never wrote before (*explorer.exe* writing *.locked*
+//! files after an injection, a backup tool encrypting in place) is a generic anomaly, whatever the
+//! ransomware family.
+//!
+//! The writes and the extension changes of each gid are counted by extension ([ExtensionUsage]).
+//! When a gid exits without having been found malicious, its counts are learnt in the profile of
+//! its executable, persisted in *DebugPath\extension_profiles.json*. Once a profile has learnt
+//! [MATURITY_WRITES] writes, the share of the writes of a new gid on extensions never seen in it
+//! is the feature *extensions_divergence* ([ExtensionUsage::divergence]). Disabled with
+//! *EXTENSION_PROFILES*.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::config::{Config, Param};
+
+pub static EXTENSION_PROFILES_FILE_NAME: &str = "extension_profiles.json";
+/// Period of the saves of the profiles.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// Writes learnt before a profile is compared with.
+pub const MATURITY_WRITES: u64 = 200;
+/// Extensions kept per profile and per gid, the most written ones.
+const MAX_EXTENSIONS: usize = 256;
+/// Executables profiled, at most.
+const MAX_PROFILES: usize = 4096;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Profile {
+    /// Writes by extension, lowercase
+    writes: BTreeMap<String, u64>,
+    total: u64,
+    gids: u64,
+}
+
+impl Profile {
+    fn learn(&mut self, usage: &ExtensionUsage) {
+        for (extension, writes) in &usage.writes {
+            *self.writes.entry(extension.clone()).or_insert(0) += writes;
+        }
+        self.total += usage.total;
+        self.gids += 1;
+        if self.writes.len() > MAX_EXTENSIONS {
+            let mut writes: Vec<u64> = self.writes.values().copied().collect();
+            writes.sort_unstable_by(|a, b| b.cmp(a));
+            let min = writes[MAX_EXTENSIONS - 1];
+            self.writes.retain(|_, w| *w >= min);
+        }
+    }
+}
+
+/// The extensions written by a gid.
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionUsage {
+    /// Extensions of the profile of the executable, if mature
+    known: Option<HashSet<String>>,
+    writes: HashMap<String, u64>,
+    total: u64,
+    /// Writes on extensions not in *known*
+    novel: u64,
+}
+
+impl ExtensionUsage {
+    /// *extension* as sent by the driver, without its dot.
+    pub fn on_write(&mut self, extension: &str) {
+        let extension = extension.trim_matches(char::from(0)).to_lowercase();
+        if extension.is_empty() {
+            return;
+        }
+        self.total += 1;
+        if self.known.as_ref().is_some_and(|known| !known.contains(&extension)) {
+            self.novel += 1;
+        }
+        if let Some(writes) = self.writes.get_mut(&extension) {
+            *writes += 1;
+        } else if self.writes.len() < MAX_EXTENSIONS {
+            self.writes.insert(extension, 1);
+        }
+    }
+
+    /// Share of the writes on extensions the executable never wrote, 0 without a mature profile.
+    pub fn divergence(&self) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.novel as f32 / self.total as f32
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// By lowercase path of the executable
+    profiles: HashMap<String, Profile>,
+    dirty: bool,
+}
+
+/// Shared by the workers of the pipeline.
+pub struct ExtensionProfiles {
+    path: PathBuf,
+    enabled: bool,
+    state: Mutex<State>,
+}
+
+impl ExtensionProfiles {
+    pub fn from(config: &Config) -> ExtensionProfiles {
+        let path = config.get_path(Param::DebugPath).join(EXTENSION_PROFILES_FILE_NAME);
+        let profiles = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                error!("Invalid {}, the extension profiles are reset: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        ExtensionProfiles {
+            path,
+            enabled: config.get_bool(Param::ExtensionProfiles),
+            state: Mutex::new(State {
+                profiles,
+                ..State::default()
+            }),
+        }
+    }
+
+    /// The usage of a new gid of *exepath*, compared with the profile of *exepath* if mature.
+    pub fn usage_of(&self, exepath: &Path) -> ExtensionUsage {
+        let known = self.enabled.then(|| {
+            let state = self.state.lock().unwrap();
+            state
+                .profiles
+                .get(&key(exepath))
+                .filter(|profile| profile.total >= MATURITY_WRITES)
+                .map(|profile| profile.writes.keys().cloned().collect())
+        });
+        ExtensionUsage {
+            known: known.flatten(),
+            ..ExtensionUsage::default()
+        }
+    }
+
+    /// Learns the extensions written by a gid of *exepath*, which exited without being found
+    /// malicious.
+    pub fn learn(&self, exepath: &Path, usage: &ExtensionUsage) {
+        if !self.enabled || usage.total == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let key = key(exepath);
+        if state.profiles.len() >= MAX_PROFILES && !state.profiles.contains_key(&key) {
+            return;
+        }
+        state.profiles.entry(key).or_default().learn(usage);
+        state.dirty = true;
+    }
+
+    /// Writes the profiles, if they changed.
+    pub fn save(&self) {
+        let json = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return;
+            }
+            state.dirty = false;
+            serde_json::to_string(&state.profiles)
+        };
+        match json {
+            Ok(json) => {
+                if let Err(e) = fs::write(&self.path, json) {
+                    error!("Cannot write {}: {}", self.path.display(), e);
+                }
+            }
+            Err(e) => error!("Cannot serialize the extension profiles: {}", e),
+        }
+    }
+}
+
+fn key(exepath: &Path) -> String {
+    exepath.to_string_lossy().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extprofiles::{ExtensionUsage, Profile, MATURITY_WRITES};
+
+    #[test]
+    fn new_extensions_should_diverge_from_a_mature_profile() {
+        let mut usage = ExtensionUsage::default();
+        for _ in 0..MATURITY_WRITES {
+            usage.on_write("docx\0\0");
+            usage.on_write("TMP");
+        }
+        // no profile yet
+        assert_eq!(usage.divergence(), 0.0);
+        let mut profile = Profile::default();
+        profile.learn(&usage);
+        assert_eq!((profile.total, profile.writes["tmp"]), (2 * MATURITY_WRITES, MATURITY_WRITES));
+
+        let mut usage = ExtensionUsage {
+            known: Some(profile.writes.keys().cloned().collect()),
+            ..ExtensionUsage::default()
+        };
+        usage.on_write("docx");
+        usage.on_write("locked");
+        usage.on_write("locked");
+        usage.on_write("");
+        assert_eq!(usage.divergence(), 2.0 / 3.0);
+    }
+}
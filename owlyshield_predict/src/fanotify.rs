@@ -0,0 +1,235 @@
+//! Linux [IoEventSource], based on fanotify.
+//!
+//! The mounts of *WATCHED_MOUNTS* are marked in notification mode: the events are reported after
+//! the fact and never blocked. fanotify gives the pid and an open descriptor of each file, which
+//! are converted to [IOMessage]s:
+//! * *FAN_OPEN* is an [IrpMajorOp::IrpCreate] and *FAN_ACCESS* an [IrpMajorOp::IrpRead];
+//! * *FAN_CLOSE_WRITE* is an [IrpMajorOp::IrpWrite] of the size of the file, followed by an
+//!   [IrpMajorOp::IrpCleanUp].
+//!
+//! The renames, the deletions and the entropy of the written buffers are not visible with mount
+//! marks: they are reported by [crate::ebpf], which then replaces the writes above.
+//!
+//! The gid of a process is its process group ([crate::os::process_group]): a shell job, or a
+//! service and the children it did not move to another group. Unlike the session, it does not
+//! hold the whole terminal or login session, all killed with the family.
+//!
+//! The agent needs *CAP_SYS_ADMIN*.
+
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::{c_void, CString};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use tracing::{error, warn};
+
+use crate::config::{Config, Param};
+use crate::driver_com::shared_def::{FileChangeInfo, IOMessage, RuntimeFeatures};
+use crate::driver_com::IrpMajorOp;
+use crate::iosource::{IoEventSource, IoSourceError};
+use crate::os;
+
+/// Size of the buffer of each read, as suggested by fanotify(7).
+const EVENTS_BUFFER_LEN: usize = 64 * 1024;
+
+pub struct FanotifySource {
+    fd: libc::c_int,
+    /// The agent itself, whose events are ignored and which is never killed
+    pid: u32,
+    group: Option<u32>,
+    /// False if the writes are reported by another source
+    report_writes: bool,
+}
+
+impl FanotifySource {
+    /// Marks all the mounts of *WATCHED_MOUNTS*.
+    pub fn open(config: &Config, report_writes: bool) -> Result<FanotifySource, IoSourceError> {
+        let fd = unsafe {
+            libc::fanotify_init(
+                libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+                (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC) as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(IoSourceError::Open(format!("fanotify_init: {}", io::Error::last_os_error())));
+        }
+        // from now on, fd is closed by drop
+        let source = FanotifySource {
+            fd,
+            pid: std::process::id(),
+            group: os::process_group(std::process::id()),
+            report_writes,
+        };
+        let mounts = config.get_str(Param::WatchedMounts);
+        for mount in mounts.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            let path = CString::new(mount).map_err(|_| IoSourceError::Open(format!("Invalid mount {}", mount)))?;
+            let res = unsafe {
+                libc::fanotify_mark(
+                    fd,
+                    libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT,
+                    libc::FAN_OPEN | libc::FAN_ACCESS | libc::FAN_CLOSE_WRITE,
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                )
+            };
+            if res < 0 {
+                return Err(IoSourceError::Open(format!(
+                    "fanotify_mark {}: {}",
+                    mount,
+                    io::Error::last_os_error()
+                )));
+            }
+        }
+        Ok(source)
+    }
+
+    /// Converts one event. Its file descriptor is closed by the caller.
+    fn push_event(&self, metadata: &libc::fanotify_event_metadata, events: &mut Vec<IOMessage>) {
+        if metadata.mask & libc::FAN_Q_OVERFLOW != 0 {
+            warn!("fanotify queue overflow: file events have been lost");
+        }
+        let pid = metadata.pid as u32;
+        if metadata.fd < 0 || pid == self.pid {
+            return;
+        }
+        // The process may have exited already: its events cannot be attributed
+        let gid = match os::process_group(pid) {
+            Some(gid) => gid as u64,
+            None => return,
+        };
+        let path = match fs::read_link(format!("/proc/self/fd/{}", metadata.fd)) {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        // the magic link follows the file, even if it has been deleted or renamed
+        let file = match fs::metadata(format!("/proc/self/fd/{}", metadata.fd)) {
+            Ok(file) if file.is_file() => file,
+            _ => return,
+        };
+        let iomsg = |irp_op: IrpMajorOp, file_change: FileChangeInfo, mem_sized_used: u64| {
+            iomessage(pid, gid, &path, Some(&file), irp_op, file_change, mem_sized_used)
+        };
+        if metadata.mask & libc::FAN_OPEN != 0 {
+            events.push(iomsg(IrpMajorOp::IrpCreate, FileChangeInfo::FileChangeNotSet, 0));
+        }
+        if metadata.mask & libc::FAN_ACCESS != 0 {
+            // the size of the read is not known
+            events.push(iomsg(IrpMajorOp::IrpRead, FileChangeInfo::FileChangeNotSet, 0));
+        }
+        if metadata.mask & libc::FAN_CLOSE_WRITE != 0 {
+            if self.report_writes {
+                events.push(iomsg(IrpMajorOp::IrpWrite, FileChangeInfo::FileChangeWrite, file.size()));
+            }
+            events.push(iomsg(IrpMajorOp::IrpCleanUp, FileChangeInfo::FileChangeNotSet, 0));
+        }
+    }
+}
+
+impl IoEventSource for FanotifySource {
+    fn fetch(&self, events: &mut Vec<IOMessage>) -> Result<(), IoSourceError> {
+        let mut buffer: Vec<u8> = vec![0; EVENTS_BUFFER_LEN];
+        let len = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(()),
+                _ => {
+                    error!("Cannot read fanotify events: {}", e);
+                    Err(IoSourceError::Closed)
+                }
+            };
+        }
+        let len = len as usize;
+        let metadata_len = mem::size_of::<libc::fanotify_event_metadata>();
+        let mut offset = 0;
+        while offset + metadata_len <= len {
+            let metadata: libc::fanotify_event_metadata =
+                unsafe { std::ptr::read_unaligned(buffer.as_ptr().add(offset) as *const _) };
+            if metadata.vers != libc::FANOTIFY_METADATA_VERSION || (metadata.event_len as usize) < metadata_len {
+                error!("Unsupported fanotify event version {}", metadata.vers);
+                return Err(IoSourceError::Closed);
+            }
+            self.push_event(&metadata, events);
+            if metadata.fd >= 0 {
+                unsafe {
+                    libc::close(metadata.fd);
+                }
+            }
+            offset += metadata.event_len as usize;
+        }
+        Ok(())
+    }
+
+    /// Kills all the processes of the group *gid*, but the group of the agent and of init.
+    fn kill_gid(&self, gid: u64) -> Result<(), IoSourceError> {
+        if gid <= 1 || self.group.map(u64::from) == Some(gid) {
+            return Err(IoSourceError::Kill(libc::EPERM));
+        }
+        match os::kill_group(gid as u32) {
+            // the processes may have exited meanwhile
+            Err(code) if code != libc::ESRCH => Err(IoSourceError::Kill(code)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for FanotifySource {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// The file id is the device and the inode. Without *file* (deleted), it is a hash of the path.
+pub fn iomessage(
+    pid: u32,
+    gid: u64,
+    path: &Path,
+    file: Option<&fs::Metadata>,
+    irp_op: IrpMajorOp,
+    file_change: FileChangeInfo,
+    mem_sized_used: u64,
+) -> IOMessage {
+    let mut file_id_id = [0u8; 16];
+    let (file_id_vsn, inode, file_size) = match file {
+        Some(file) => (file.dev(), file.ino(), file.size() as i64),
+        None => {
+            let mut hasher = DefaultHasher::new();
+            path.hash(&mut hasher);
+            (0, hasher.finish(), -1)
+        }
+    };
+    file_id_id[..8].copy_from_slice(&inode.to_le_bytes());
+    IOMessage {
+        extension: extension_utf16(path),
+        file_id_vsn,
+        file_id_id,
+        mem_sized_used,
+        entropy: 0.0,
+        pid,
+        irp_op: irp_op as u8,
+        is_entropy_calc: 0,
+        file_change: file_change as u8,
+        file_location_info: 0,
+        filepathstr: path.to_string_lossy().to_string(),
+        gid,
+        runtime_features: RuntimeFeatures::new(),
+        file_size,
+    }
+}
+
+/// The extension as sent by the minifilter: UTF-16, truncated and padded with zeros.
+pub fn extension_utf16(path: &Path) -> [u16; 12] {
+    let mut extension = [0u16; 12];
+    if let Some(ext) = path.extension() {
+        for (c, dst) in ext.to_string_lossy().encode_utf16().zip(extension.iter_mut().take(11)) {
+            *dst = c;
+        }
+    }
+    extension
+}
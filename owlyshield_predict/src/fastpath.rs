@@ -0,0 +1,148 @@
+//! Obvious ransomware behaviours, escalated immediately without waiting for the model (which
+//! needs a few hundred driver messages):
+//! * a file is given an extension of a known ransomware (*RANSOM_EXTENSIONS*);
+//! * more than *EXTENSION_BURST_FILES* files are renamed to the same new extension within
+//!   *EXTENSION_BURST_SECS* seconds (*report.docx* to *report.docx.x7k2*);
+//! * the raw disk or a volume is written, bypassing the files (see [crate::rawdisk]).
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Config, Param};
+use crate::extensions::ExtensionList;
+
+/// New extensions tracked by gid. Beyond, the new ones are ignored.
+const MAX_EXTENSIONS: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FastPathVerdict {
+    KnownExtension(String),
+    ExtensionBurst { extension: String, files: usize },
+    /// The device written
+    RawDiskWrite(String),
+}
+
+impl fmt::Display for FastPathVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FastPathVerdict::KnownExtension(ext) => write!(f, "known ransomware extension .{}", ext),
+            FastPathVerdict::ExtensionBurst { extension, files } => {
+                write!(f, "{} files renamed to .{} in a burst", files, extension)
+            }
+            FastPathVerdict::RawDiskWrite(device) => write!(f, "raw write to {}", device),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FastPath {
+    burst_files: usize,
+    burst_window: Duration,
+    /// Times of the last renames, by new extension
+    renames: HashMap<String, VecDeque<SystemTime>>,
+    verdict: Option<FastPathVerdict>,
+    escalated: bool,
+}
+
+impl FastPath {
+    pub fn from(config: &Config) -> FastPath {
+        FastPath::new(
+            config.get_usize(Param::ExtensionBurstFiles),
+            Duration::from_secs(config.get_usize(Param::ExtensionBurstSecs) as u64),
+        )
+    }
+
+    pub fn new(burst_files: usize, burst_window: Duration) -> FastPath {
+        FastPath {
+            burst_files,
+            burst_window,
+            renames: HashMap::new(),
+            verdict: None,
+            escalated: false,
+        }
+    }
+
+    /// A file has been written with *extension* (without dot). *renamed* is true if its
+    /// extension has just been changed.
+    pub fn on_file(&mut self, extensions: &ExtensionList, extension: &str, renamed: bool, now: SystemTime) {
+        if self.verdict.is_some() || extension.is_empty() {
+            return;
+        }
+        if extensions.is_ransom_extension(extension) {
+            self.verdict = Some(FastPathVerdict::KnownExtension(extension.to_lowercase()));
+            return;
+        }
+        if !renamed || self.burst_files == 0 {
+            return;
+        }
+        let extension = extension.to_lowercase();
+        if !self.renames.contains_key(&extension) && self.renames.len() >= MAX_EXTENSIONS {
+            return;
+        }
+        let window = self.burst_window;
+        let times = self.renames.entry(extension.clone()).or_insert_with(VecDeque::new);
+        times.push_back(now);
+        while times
+            .front()
+            .map_or(false, |t| now.duration_since(*t).unwrap_or(Duration::ZERO) > window)
+        {
+            times.pop_front();
+        }
+        if times.len() > self.burst_files {
+            self.verdict = Some(FastPathVerdict::ExtensionBurst { extension, files: times.len() });
+        }
+    }
+
+    /// A write on *device*, or a handle opened to write it.
+    pub fn on_raw_disk_write(&mut self, device: &str) {
+        if self.verdict.is_none() {
+            self.verdict = Some(FastPathVerdict::RawDiskWrite(device.to_string()));
+        }
+    }
+
+    /// The verdict, returned only once for the escalation.
+    pub fn take_escalation(&mut self) -> Option<FastPathVerdict> {
+        if self.escalated {
+            return None;
+        }
+        self.escalated = self.verdict.is_some();
+        self.verdict.clone()
+    }
+
+    pub fn verdict(&self) -> Option<&FastPathVerdict> {
+        self.verdict.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::extensions::ExtensionList;
+    use crate::fastpath::{FastPath, FastPathVerdict};
+
+    #[test]
+    fn burst_of_renames_should_escalate_once() {
+        let extensions = ExtensionList::with_ransom_extensions(".locky, crypt");
+        let mut fastpath = FastPath::new(3, Duration::from_secs(10));
+        let start = SystemTime::now();
+        for i in 0..3 {
+            fastpath.on_file(&extensions, "x7k2", true, start + Duration::from_secs(20 * i));
+        }
+        fastpath.on_file(&extensions, "docx", false, start);
+        assert_eq!(fastpath.take_escalation(), None);
+        for i in 0..4 {
+            fastpath.on_file(&extensions, "X7K2", true, start + Duration::from_secs(60 + i));
+        }
+        assert_eq!(
+            fastpath.take_escalation(),
+            Some(FastPathVerdict::ExtensionBurst { extension: String::from("x7k2"), files: 4 })
+        );
+        assert_eq!(fastpath.take_escalation(), None);
+
+        let mut fastpath = FastPath::new(3, Duration::from_secs(10));
+        fastpath.on_file(&extensions, "LOCKY", false, start);
+        assert_eq!(fastpath.take_escalation(), Some(FastPathVerdict::KnownExtension(String::from("locky"))));
+    }
+}
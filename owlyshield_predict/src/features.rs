@@ -0,0 +1,534 @@
+//! The features of a gid given to the models, computed by the [FeatureExtractor]s of the
+//! [REGISTRY]: each one declares its columns and appends their values to the row of the
+//! prediction matrix ([crate::prediction::input_tensors::RollingFeatures]).
+//!
+//! The columns of the matrix are those of the extractors, in the order of the [REGISTRY]: the
+//! models only use the first ones (the length of their standard scaling vectors). Each model is
+//! bundled with the names of its inputs, which [FeatureRegistry::validate_schema] checks when it
+//! is loaded: a model trained on other columns would score misaligned values.
+//!
+//! A new feature (network, registry, canary hits...) is an extractor of its module, appended to
+//! the [REGISTRY], so that the columns of the models already trained do not move.
+
+use crate::autostart::AutostartKind;
+use crate::decay;
+use crate::error::ModelError;
+use crate::extensions::ExtensionCategory;
+use crate::process::ProcessRecord;
+
+/// Computes some columns of the row of a gid.
+pub trait FeatureExtractor: Sync {
+    /// Identifies the extractor, in the errors.
+    fn name(&self) -> &'static str;
+    /// Names of the columns, in the order of [Self::extract].
+    fn columns(&self) -> &'static [&'static str];
+    /// Appends the values of the [Self::columns] of *proc* to *row*.
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>);
+}
+
+/// The extractors of the rows, see [REGISTRY].
+pub struct FeatureRegistry {
+    extractors: &'static [&'static dyn FeatureExtractor],
+}
+
+/// The extractors of the prediction matrix, the features added last.
+pub static REGISTRY: FeatureRegistry = FeatureRegistry {
+    extractors: &[
+        &DriverActivity,
+        &Clusters,
+        &DirTree,
+        &Magic,
+        &RansomNote,
+        &ScriptHost,
+        &CloudSync,
+        &NetShare,
+        &Decay,
+        &Container,
+        &ExtProfiles,
+        &Sysmon,
+        &Payloads,
+        &Integrity,
+        &Privileges,
+        &Autostart,
+        &InputCapture,
+    ],
+};
+
+impl FeatureRegistry {
+    /// Number of columns of a row.
+    pub fn len(&self) -> usize {
+        self.extractors.iter().map(|extractor| extractor.columns().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Names of the columns of a row, the model input schema.
+    pub fn columns(&self) -> impl Iterator<Item = &'static str> + Clone {
+        self.extractors.iter().flat_map(|extractor| extractor.columns().iter().copied())
+    }
+
+    /// Index of the column *name*.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.columns().position(|column| column == name)
+    }
+
+    /// The row of *proc*.
+    pub fn extract(&self, proc: &ProcessRecord) -> Vec<f32> {
+        let mut row = Vec::with_capacity(self.len());
+        for extractor in self.extractors {
+            let start = row.len();
+            extractor.extract(proc, &mut row);
+            debug_assert_eq!(row.len() - start, extractor.columns().len(), "columns of {}", extractor.name());
+        }
+        row
+    }
+
+    /// Checks that the *inputs* of the *model* (the first columns) are extracted.
+    pub fn validate(&self, model: &'static str, inputs: usize) -> Result<(), ModelError> {
+        if inputs == 0 || inputs > self.len() {
+            return Err(ModelError::Schema(model, format!("{} inputs, {} features extracted", inputs, self.len())));
+        }
+        Ok(())
+    }
+
+    /// Checks that the *schema* of the *model*, the names of its inputs, are the first columns, in
+    /// the same order.
+    pub fn validate_schema(&self, model: &'static str, schema: &[String]) -> Result<(), ModelError> {
+        self.validate(model, schema.len())?;
+        match schema.iter().zip(self.columns()).enumerate().find(|(_, (input, column))| input != column) {
+            Some((i, (input, column))) => Err(ModelError::Schema(
+                model,
+                format!("input {} is {}, the feature extracted is {}", i, input, column),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Multiplies a volume written by *factor*, for the jobs of the [crate::backup] agents.
+fn relax(value: u64, factor: Option<f32>) -> f32 {
+    match factor {
+        Some(factor) => (value as f64 * factor as f64) as u64 as f32,
+        None => value as f32,
+    }
+}
+
+#[inline]
+fn order_magnitude(a: f64) -> u32 {
+    if a <= 0f64 {
+        0
+    } else {
+        a.log10() as u32
+    }
+}
+
+/// Counters of the driver messages: operations, bytes, entropy (order of magnitude), files,
+/// extensions and directories, with the volumes written relaxed during the backup jobs.
+struct DriverActivity;
+
+impl FeatureExtractor for DriverActivity {
+    fn name(&self) -> &'static str {
+        "driver"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &[
+            "ops_read",
+            "ops_setinfo",
+            "ops_written",
+            "ops_open",
+            "bytes_read",
+            "bytes_written",
+            "entropy_read",
+            "entropy_written",
+            "files_opened",
+            "files_deleted",
+            "files_read",
+            "files_renamed",
+            "files_written",
+            "extensions_read",
+            "extensions_written",
+            "extensions_written_doc",
+            "extensions_written_archives",
+            "extensions_written_db",
+            "extensions_written_code",
+            "extensions_written_exe",
+            "dirs_with_files_created",
+            "dirs_with_files_updated",
+            "pids",
+            "exe_exists",
+        ]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        let factor = proc.backup_write_relax();
+        let written = &proc.extensions_written;
+        row.extend_from_slice(&[
+            proc.ops_read as f32,
+            proc.ops_setinfo as f32,
+            relax(proc.ops_written, factor),
+            proc.ops_open as f32,
+            proc.bytes_read as f32,
+            relax(proc.bytes_written, factor),
+            order_magnitude(proc.entropy_read) as f32,
+            order_magnitude(proc.entropy_written) as f32,
+            proc.files_opened.len() as f32,
+            proc.files_deleted.len() as f32,
+            proc.files_read.len() as f32,
+            proc.files_renamed.len() as f32,
+            relax(proc.files_written.len() as u64, factor),
+            proc.extensions_read.count_all() as f32,
+            written.count_all() as f32,
+            written.count_category(ExtensionCategory::Docs) as f32,
+            written.count_category(ExtensionCategory::Archives) as f32,
+            written.count_category(ExtensionCategory::Database) as f32,
+            written.count_category(ExtensionCategory::Code) as f32,
+            written.count_category(ExtensionCategory::Exe) as f32,
+            relax(proc.dirs_with_files_created.len() as u64, factor),
+            relax(proc.dirs_with_files_updated.len() as u64, factor),
+            proc.pids.len() as f32,
+            proc.exe_exists as u8 as f32,
+        ]);
+    }
+}
+
+/// Clusters of the directories with files updated.
+struct Clusters;
+
+impl FeatureExtractor for Clusters {
+    fn name(&self) -> &'static str {
+        "clusters"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["clusters", "clusters_max_size"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.extend_from_slice(&[proc.clusters as f32, proc.clusters_max_size as f32]);
+    }
+}
+
+/// Spread of the directories with files updated, see [crate::dirtree].
+struct DirTree;
+
+impl FeatureExtractor for DirTree {
+    fn name(&self) -> &'static str {
+        "dirtree"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["dirs_top_level", "dirs_max_depth", "dirs_common_ancestor_depth", "dirs_ancestor_drift"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.extend_from_slice(&[
+            proc.dir_tree.top_level_count() as f32,
+            proc.dir_tree.max_depth() as f32,
+            proc.dir_tree.common_ancestor_depth() as f32,
+            proc.dir_tree.ancestor_drift() as f32,
+        ]);
+    }
+}
+
+/// Headers no longer matching the extensions, see [crate::magic].
+struct Magic;
+
+impl FeatureExtractor for Magic {
+    fn name(&self) -> &'static str {
+        "magic"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["files_magic_mismatch_ratio"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.push(proc.magic_mismatch_ratio());
+    }
+}
+
+/// Identical text files in many directories, see [crate::ransomnote].
+struct RansomNote;
+
+impl FeatureExtractor for RansomNote {
+    fn name(&self) -> &'static str {
+        "ransomnote"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["ransom_note_score"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.push(proc.ransom_note.score());
+    }
+}
+
+/// The script run by the root of the gid is detected by AMSI, see [crate::scripthost].
+struct ScriptHost;
+
+impl FeatureExtractor for ScriptHost {
+    fn name(&self) -> &'static str {
+        "scripthost"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["script_amsi_detected"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.push(proc.script.as_ref().map_or(0.0, |s| s.amsi_detected()));
+    }
+}
+
+/// Files written in the folders of the sync clients, see [crate::cloudsync].
+struct CloudSync;
+
+impl FeatureExtractor for CloudSync {
+    fn name(&self) -> &'static str {
+        "cloudsync"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["files_written_cloud_sync"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.push(proc.files_written_sync.len() as f32);
+    }
+}
+
+/// Writes on the network shares, see [crate::netshare].
+struct NetShare;
+
+impl FeatureExtractor for NetShare {
+    fn name(&self) -> &'static str {
+        "netshare"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["ops_written_remote", "files_written_remote"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.extend_from_slice(&[proc.ops_written_remote as f32, proc.files_written_remote.len() as f32]);
+    }
+}
+
+/// Writes, deletions, renames and mean entropy written over the last minute, ten minutes and
+/// hour, see [crate::decay], with the writes relaxed during the backup jobs.
+struct Decay;
+
+impl FeatureExtractor for Decay {
+    fn name(&self) -> &'static str {
+        "decay"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &[
+            "writes_1m",
+            "writes_10m",
+            "writes_1h",
+            "deletes_1m",
+            "deletes_10m",
+            "deletes_1h",
+            "renames_1m",
+            "renames_10m",
+            "renames_1h",
+            "entropy_written_1m",
+            "entropy_written_10m",
+            "entropy_written_1h",
+        ]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        let mut decayed = proc.decayed.features();
+        if let Some(factor) = proc.backup_write_relax() {
+            for writes in decayed[..decay::SCALES.len()].iter_mut() {
+                *writes *= factor;
+            }
+        }
+        row.extend_from_slice(&decayed);
+    }
+}
+
+/// The root of the gid runs in an AppContainer or a container, see [crate::container].
+struct Container;
+
+impl FeatureExtractor for Container {
+    fn name(&self) -> &'static str {
+        "container"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["runs_in_container"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.push(proc.containment.runs_in_container() as u8 as f32);
+    }
+}
+
+/// Share of the writes on extensions never written by the executable, see [crate::extprofiles].
+struct ExtProfiles;
+
+impl FeatureExtractor for ExtProfiles {
+    fn name(&self) -> &'static str {
+        "extprofiles"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["extensions_divergence"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.push(proc.extension_usage.divergence());
+    }
+}
+
+/// Processes created and remote hosts connected to, seen by Sysmon, see [crate::sysmon].
+struct Sysmon;
+
+impl FeatureExtractor for Sysmon {
+    fn name(&self) -> &'static str {
+        "sysmon"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["sysmon_processes_created", "sysmon_remote_hosts"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.extend_from_slice(&[proc.sysmon.processes_created as f32, proc.sysmon.remote_hosts.len() as f32]);
+    }
+}
+
+/// Highest score of the executables dropped by the gid, see [crate::payloads].
+struct Payloads;
+
+impl FeatureExtractor for Payloads {
+    fn name(&self) -> &'static str {
+        "payloads"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["dropped_payload_score"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.push(proc.payloads.max_score());
+    }
+}
+
+/// The image of the root differs from its executable, or a process was created with another
+/// parent than its creator, see [crate::integrity].
+struct Integrity;
+
+impl FeatureExtractor for Integrity {
+    fn name(&self) -> &'static str {
+        "integrity"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["image_mismatch", "parent_spoofed"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.extend_from_slice(&[
+            proc.integrity.image_mismatch.is_some() as u8 as f32,
+            proc.integrity.spoofed_by.is_some() as u8 as f32,
+        ]);
+    }
+}
+
+/// Privileges enabled by the root during the run, see [crate::privileges].
+struct Privileges;
+
+impl FeatureExtractor for Privileges {
+    fn name(&self) -> &'static str {
+        "privileges"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["privileges_escalated"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.push(proc.privileges.escalated.names().len() as f32);
+    }
+}
+
+/// Scheduled tasks and services created, see [crate::autostart].
+struct Autostart;
+
+impl FeatureExtractor for Autostart {
+    fn name(&self) -> &'static str {
+        "autostart"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["persistence_tasks", "persistence_services"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.extend_from_slice(&[
+            proc.autostart.count(AutostartKind::ScheduledTask) as f32,
+            proc.autostart.count(AutostartKind::Service) as f32,
+        ]);
+    }
+}
+
+/// Calls to the keyboard hooks, key state and raw input APIs, and to the clipboard APIs, see
+/// [crate::inputcapture].
+struct InputCapture;
+
+impl FeatureExtractor for InputCapture {
+    fn name(&self) -> &'static str {
+        "inputcapture"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["input_keyboard_capture", "input_clipboard_reads"]
+    }
+
+    fn extract(&self, proc: &ProcessRecord, row: &mut Vec<f32>) {
+        row.extend_from_slice(&[proc.input_capture.keyboard_capture() as f32, proc.input_capture.clipboard_reads as f32]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::decay;
+    use crate::features::REGISTRY;
+
+    #[test]
+    fn registry_should_declare_unique_columns() {
+        let columns: Vec<&str> = REGISTRY.columns().collect();
+        assert_eq!(columns.len(), REGISTRY.len());
+        assert_eq!(columns.iter().collect::<HashSet<_>>().len(), columns.len());
+        assert_eq!(&columns[..3], &["ops_read", "ops_setinfo", "ops_written"]);
+        assert_eq!(REGISTRY.position("writes_1m"), Some(36));
+        assert_eq!(REGISTRY.position("entropy_written_1h"), Some(36 + decay::FEATURES_COUNT - 1));
+        assert_eq!(REGISTRY.position("input_clipboard_reads"), Some(REGISTRY.len() - 1));
+
+        assert!(REGISTRY.validate("dynamic", 24).is_ok());
+        assert!(REGISTRY.validate("dynamic", REGISTRY.len()).is_ok());
+        assert!(REGISTRY.validate("dynamic", REGISTRY.len() + 1).is_err());
+        assert!(REGISTRY.validate("dynamic", 0).is_err());
+
+        let schema: Vec<String> = REGISTRY.columns().take(26).map(String::from).collect();
+        assert!(REGISTRY.validate_schema("dynamic", &schema).is_ok());
+        let mut swapped = schema.clone();
+        swapped.swap(2, 3);
+        assert_eq!(
+            REGISTRY.validate_schema("dynamic", &swapped).unwrap_err().to_string(),
+            "the dynamic model does not fit the features: input 2 is ops_open, the feature extracted is ops_written"
+        );
+        assert!(REGISTRY.validate_schema("dynamic", &[]).is_err());
+    }
+}
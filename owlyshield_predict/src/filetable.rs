@@ -0,0 +1,183 @@
+//! Files touched by a gid, keyed by their file id (*file_id_vsn* and *file_id_id* of the driver,
+//! see [FileId]) instead of their path: a file renamed or moved while it is encrypted
+//! (*report.docx* becoming *report.docx.locked*) stays the same entry, with its original and last
+//! known paths.
+//!
+//! The [FileTable] of a gid gives its affected-files manifest ([FileTable::manifest]), written with
+//! the incident reports: the original path of a file is where a restore puts it back, its last
+//! path the copy to replace. It is bounded by *HISTORY_MAX_ENTRIES*: beyond, the new files are
+//! only counted.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::process::FileId;
+use crate::schema::rfc3339;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOp {
+    Read,
+    Created,
+    Written,
+    Renamed,
+    Deleted,
+}
+
+impl FileOp {
+    const ALL: [FileOp; 5] = [FileOp::Read, FileOp::Created, FileOp::Written, FileOp::Renamed, FileOp::Deleted];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FileOp::Read => "read",
+            FileOp::Created => "created",
+            FileOp::Written => "written",
+            FileOp::Renamed => "renamed",
+            FileOp::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Path when the gid first touched the file
+    pub original_path: Arc<str>,
+    /// Last known path
+    pub path: Arc<str>,
+    ops: u8,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+impl FileEntry {
+    fn has(&self, op: FileOp) -> bool {
+        self.ops & op.bit() != 0
+    }
+
+    /// Only read: not affected.
+    fn is_affected(&self) -> bool {
+        self.ops & !FileOp::Read.bit() != 0
+    }
+}
+
+/// A line of the affected-files manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AffectedFile {
+    pub volume_serial: u64,
+    /// Hexadecimal
+    pub file_id: String,
+    pub original_path: String,
+    pub path: String,
+    pub operations: Vec<&'static str>,
+    /// RFC 3339, in UTC
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+#[derive(Debug)]
+pub struct FileTable {
+    files: HashMap<FileId, FileEntry>,
+    max_entries: usize,
+    /// Operations on files beyond the cap
+    pub untracked: u64,
+}
+
+impl FileTable {
+    pub fn new(max_entries: usize) -> FileTable {
+        FileTable {
+            files: HashMap::new(),
+            max_entries,
+            untracked: 0,
+        }
+    }
+
+    /// Records *op* on the file *id*, now at *path*. The files without id (raw disk writes,
+    /// aggregates) are ignored.
+    pub fn record(&mut self, id: FileId, path: &Arc<str>, op: FileOp, time: SystemTime) {
+        if id.is_null() {
+            return;
+        }
+        if let Some(entry) = self.files.get_mut(&id) {
+            if entry.path != *path {
+                entry.path = path.clone();
+            }
+            entry.ops |= op.bit();
+            entry.last_seen = time;
+        } else if self.files.len() < self.max_entries {
+            self.files.insert(
+                id,
+                FileEntry {
+                    original_path: path.clone(),
+                    path: path.clone(),
+                    ops: op.bit(),
+                    first_seen: time,
+                    last_seen: time,
+                },
+            );
+        } else {
+            self.untracked += 1;
+        }
+    }
+
+    /// The files created, written, renamed or deleted, by original path.
+    pub fn manifest(&self) -> Vec<AffectedFile> {
+        let mut manifest: Vec<AffectedFile> = self
+            .files
+            .iter()
+            .filter(|(_, entry)| entry.is_affected())
+            .map(|(id, entry)| AffectedFile {
+                volume_serial: id.volume_serial,
+                file_id: id.file_id.iter().map(|b| format!("{:02x}", b)).collect(),
+                original_path: entry.original_path.to_string(),
+                path: entry.path.to_string(),
+                operations: FileOp::ALL.iter().filter(|op| entry.has(**op)).map(|op| op.name()).collect(),
+                first_seen: rfc3339(entry.first_seen),
+                last_seen: rfc3339(entry.last_seen),
+            })
+            .collect();
+        manifest.sort_by(|a, b| a.original_path.cmp(&b.original_path));
+        manifest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use crate::filetable::{FileOp, FileTable};
+    use crate::process::FileId;
+
+    #[test]
+    fn renamed_files_should_keep_their_entry() {
+        let id = |n: u8| FileId::new(0x1234, &[n, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let later = time + Duration::from_secs(2);
+        let docx: Arc<str> = Arc::from(r"C:\Users\bob\Documents\report.docx");
+        let locked: Arc<str> = Arc::from(r"C:\Users\bob\Documents\report.docx.locked");
+        let notes: Arc<str> = Arc::from(r"C:\Users\bob\Documents\notes.txt");
+
+        let mut table = FileTable::new(2);
+        table.record(id(1), &docx, FileOp::Read, time);
+        table.record(id(1), &docx, FileOp::Written, time);
+        table.record(id(1), &locked, FileOp::Renamed, later);
+        table.record(id(2), &notes, FileOp::Read, time);
+        table.record(id(3), &notes, FileOp::Written, time);
+        table.record(id(0), &notes, FileOp::Written, time);
+        assert_eq!(table.untracked, 1);
+
+        let manifest = table.manifest();
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(manifest[0].original_path, &*docx);
+        assert_eq!(manifest[0].path, &*locked);
+        assert_eq!(manifest[0].file_id, "01000000000000000000000000000000");
+        assert_eq!(manifest[0].operations, vec!["read", "written", "renamed"]);
+        assert_eq!(manifest[0].last_seen, "2023-11-14T22:13:22.000000Z");
+    }
+}
@@ -0,0 +1,406 @@
+//! Fingerprints of the well known ransomware families, to tell the responders which one they are
+//! likely facing (its negotiators, decryptors and leak sites): the *suspected_family* of the
+//! events.
+//!
+//! A gid is matched against [FINGERPRINTS] by four kinds of [Clue]:
+//! * the names of the files it creates or renames: the extensions of the encrypted files and the
+//!   names of the ransom notes, as they come;
+//! * the named mutexes held by its processes, found by the enumeration of their handles, and the
+//!   sha256 of its executable: when it enters Watch (see [crate::escalation]) and before it is
+//!   killed.
+//!
+//! The weights of the clues of a family add up, and the family of the best score is suspected
+//! from [MIN_SCORE]: a note name alone (*readme.txt*...) is not enough.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::utils::sha256_file;
+
+/// Score of a family before it is suspected.
+pub const MIN_SCORE: f32 = 0.7;
+/// Length of the prefixes of the sha256 in [FINGERPRINTS].
+const HASH_PREFIX_LEN: usize = 16;
+
+pub struct Fingerprint {
+    pub family: &'static str,
+    /// Names of the encrypted files, lowercase, with * for any characters
+    pub extensions: &'static [&'static str],
+    /// Names of the ransom notes, lowercase, with * for any characters
+    pub notes: &'static [&'static str],
+    /// Without their namespace, lowercase
+    pub mutexes: &'static [&'static str],
+    /// Prefixes of the sha256 of known samples, lowercase
+    pub hashes: &'static [&'static str],
+}
+
+pub static FINGERPRINTS: [Fingerprint; 16] = [
+    Fingerprint {
+        family: "WannaCry",
+        extensions: &["*.wncry", "*.wnry", "*.wcry"],
+        notes: &["@please_read_me@.txt", "@wanadecryptor@.exe"],
+        mutexes: &["mswinzonescachecountermutexa", "mswinzonescachecountermutexa0"],
+        hashes: &["ed01ebfbc9eb5bbe", "24d004a104d4d540"],
+    },
+    Fingerprint {
+        family: "NotPetya",
+        extensions: &[],
+        notes: &[],
+        mutexes: &[],
+        hashes: &["027cc450ef5f8c5f"],
+    },
+    Fingerprint {
+        family: "LockBit",
+        extensions: &["*.lockbit"],
+        notes: &["restore-my-files.txt"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Conti",
+        extensions: &["*.conti"],
+        notes: &["conti_readme.txt"],
+        mutexes: &["kjsidugidf99439"],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Ryuk",
+        extensions: &["*.ryk", "*.ryuk"],
+        notes: &["ryukreadme.txt", "ryukreadme.html"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "REvil",
+        extensions: &[],
+        notes: &["*-readme.txt"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "STOP/Djvu",
+        extensions: &[],
+        notes: &["_readme.txt"],
+        mutexes: &["{1d6fc66e-d1f3-422c-8a53-c0bbcf3d900d}"],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Phobos",
+        extensions: &["*.id[*].[*].*"],
+        notes: &["info.hta", "info.txt"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Dharma",
+        extensions: &["*.id-*.[*].*"],
+        notes: &["files encrypted.txt", "info.hta"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "GandCrab",
+        extensions: &["*.gdcb", "*.krab", "*.crab"],
+        notes: &["gdcb-decrypt.txt", "krab-decrypt.txt", "crab-decrypt.txt"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Hive",
+        extensions: &["*.hive"],
+        notes: &["how_to_decrypt.txt"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Babuk",
+        extensions: &["*.babyk", "*.__nist_k571__"],
+        notes: &["how to restore your files.txt"],
+        mutexes: &["doyouwanttohavesexwithcuongdong"],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Black Basta",
+        extensions: &["*.basta"],
+        notes: &[],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Akira",
+        extensions: &["*.akira"],
+        notes: &["akira_readme.txt"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Locky",
+        extensions: &["*.locky", "*.zepto", "*.odin"],
+        notes: &["_locky_recover_instructions.txt", "_help_instructions.html"],
+        mutexes: &[],
+        hashes: &[],
+    },
+    Fingerprint {
+        family: "Cerber",
+        extensions: &["*.cerber", "*.cerber2", "*.cerber3"],
+        notes: &["# decrypt my files #.txt", "# decrypt my files #.html"],
+        mutexes: &[],
+        hashes: &[],
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clue {
+    Hash,
+    Mutex,
+    Extension,
+    NoteName,
+}
+
+impl Clue {
+    fn weight(self) -> f32 {
+        match self {
+            Clue::Hash => 1.0,
+            Clue::Mutex => 0.9,
+            Clue::Extension => 0.7,
+            Clue::NoteName => 0.4,
+        }
+    }
+}
+
+impl fmt::Display for Clue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Clue::Hash => write!(f, "hash"),
+            Clue::Mutex => write!(f, "mutex"),
+            Clue::Extension => write!(f, "extension"),
+            Clue::NoteName => write!(f, "note name"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuspectedFamily {
+    pub family: &'static str,
+    /// Sum of the weights of its clues, at most 1
+    pub score: f32,
+    /// The first value matched by each kind of clue
+    pub clues: Vec<(Clue, String)>,
+}
+
+impl fmt::Display for SuspectedFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let clues: Vec<String> = self.clues.iter().map(|(clue, value)| format!("{} {}", clue, value)).collect();
+        write!(f, "{} ({:.2}: {})", self.family, self.score, clues.join(", "))
+    }
+}
+
+/// The clues of a gid.
+#[derive(Debug, Default)]
+pub struct FamilyFingerprinter {
+    /// Index in [FINGERPRINTS], once per kind of clue
+    matches: Vec<(usize, Clue, String)>,
+    hashed: bool,
+}
+
+impl FamilyFingerprinter {
+    pub fn new() -> FamilyFingerprinter {
+        FamilyFingerprinter::default()
+    }
+
+    /// A file created or renamed by the gid.
+    pub fn on_file(&mut self, fpath: &str) {
+        let name = match fpath.rsplit(['\\', '/']).next() {
+            Some(name) if !name.is_empty() => name.to_lowercase(),
+            _ => return,
+        };
+        for (i, fingerprint) in FINGERPRINTS.iter().enumerate() {
+            if fingerprint.extensions.iter().any(|pattern| glob(pattern, &name)) {
+                self.add(i, Clue::Extension, &name);
+            }
+            if fingerprint.notes.iter().any(|pattern| glob(pattern, &name)) {
+                self.add(i, Clue::NoteName, &name);
+            }
+        }
+    }
+
+    /// Named mutexes held by the processes of the gid.
+    pub fn on_mutexes(&mut self, names: &[String]) {
+        for name in names {
+            let short = name.rsplit('\\').next().unwrap_or(name).to_lowercase();
+            for (i, fingerprint) in FINGERPRINTS.iter().enumerate() {
+                if fingerprint.mutexes.contains(&short.as_str()) {
+                    self.add(i, Clue::Mutex, name);
+                }
+            }
+        }
+    }
+
+    pub fn on_sha256(&mut self, sha256: &str) {
+        let prefix = sha256.get(..HASH_PREFIX_LEN).unwrap_or(sha256).to_lowercase();
+        for (i, fingerprint) in FINGERPRINTS.iter().enumerate() {
+            if fingerprint.hashes.contains(&prefix.as_str()) {
+                self.add(i, Clue::Hash, sha256);
+            }
+        }
+    }
+
+    /// Looks for the mutexes of *pids*, and hashes *exepath* the first time. Slow.
+    pub fn scan(&mut self, pids: &[u32], exepath: &Path) {
+        self.on_mutexes(&mutex_names(pids));
+        if !self.hashed {
+            self.hashed = true;
+            if let Ok(sha256) = sha256_file(exepath) {
+                self.on_sha256(&sha256);
+            }
+        }
+    }
+
+    fn add(&mut self, index: usize, clue: Clue, value: &str) {
+        if !self.matches.iter().any(|(i, c, _)| *i == index && *c == clue) {
+            self.matches.push((index, clue, value.to_string()));
+        }
+    }
+
+    /// The family of the best score, from [MIN_SCORE].
+    pub fn suspected_family(&self) -> Option<SuspectedFamily> {
+        let mut best: Option<(usize, f32)> = None;
+        for (index, _, _) in &self.matches {
+            let score: f32 = self.matches.iter().filter(|(i, _, _)| i == index).map(|(_, clue, _)| clue.weight()).sum();
+            if !matches!(best, Some((_, best_score)) if best_score >= score) {
+                best = Some((*index, score));
+            }
+        }
+        let (index, score) = best.filter(|(_, score)| *score >= MIN_SCORE)?;
+        Some(SuspectedFamily {
+            family: FINGERPRINTS[index].family,
+            score: score.min(1.0),
+            clues: self
+                .matches
+                .iter()
+                .filter(|(i, _, _)| *i == index)
+                .map(|(_, clue, value)| (*clue, value.clone()))
+                .collect(),
+        })
+    }
+}
+
+/// Whether *text* matches *pattern*, where * is any characters.
+fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // no *
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Names of the mutexes opened by *pids*.
+#[cfg(windows)]
+fn mutex_names(pids: &[u32]) -> Vec<String> {
+    use crate::rawdisk::{object_name, system_handles, HandlesInformation};
+    use bindings::Windows::Win32::Foundation::{CloseHandle, DuplicateHandle, DUPLICATE_SAME_ACCESS, HANDLE};
+    use bindings::Windows::Win32::System::Threading::{GetCurrentProcess, GetCurrentProcessId, OpenProcess, PROCESS_DUP_HANDLE};
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateMutexW(attributes: *const std::ffi::c_void, initial_owner: i32, name: *const u16) -> isize;
+    }
+
+    if pids.is_empty() {
+        return Vec::new();
+    }
+    let mut names = Vec::new();
+    unsafe {
+        // A mutex of ours, to know the type index of the mutexes
+        let marker = CreateMutexW(std::ptr::null(), 0, std::ptr::null());
+        if marker == 0 {
+            return names;
+        }
+        let buffer = match system_handles() {
+            Some(buffer) => buffer,
+            None => {
+                CloseHandle(HANDLE(marker));
+                return names;
+            }
+        };
+        let info = &*(buffer.as_ptr() as *const HandlesInformation);
+        let entries = std::slice::from_raw_parts(info.handles.as_ptr(), info.count);
+        let current_pid = GetCurrentProcessId() as usize;
+        let mutant_type = entries
+            .iter()
+            .find(|e| e.pid == current_pid && e.handle == marker as usize)
+            .map(|e| e.object_type_index);
+        CloseHandle(HANDLE(marker));
+        let mutant_type = match mutant_type {
+            Some(mutant_type) => mutant_type,
+            None => return names,
+        };
+        for pid in pids {
+            let process = OpenProcess(PROCESS_DUP_HANDLE, false, *pid);
+            if process.is_invalid() || process.0 == 0 {
+                continue;
+            }
+            for entry in entries.iter().filter(|e| e.pid == *pid as usize && e.object_type_index == mutant_type) {
+                let mut handle = HANDLE(0);
+                if DuplicateHandle(process, HANDLE(entry.handle as isize), GetCurrentProcess(), &mut handle, 0, false, DUPLICATE_SAME_ACCESS)
+                    .as_bool()
+                {
+                    if let Some(name) = object_name(handle).filter(|name| !name.is_empty()) {
+                        names.push(name);
+                    }
+                    CloseHandle(handle);
+                }
+            }
+            CloseHandle(process);
+        }
+    }
+    names
+}
+
+#[cfg(not(windows))]
+fn mutex_names(_pids: &[u32]) -> Vec<String> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fingerprint::{glob, Clue, FamilyFingerprinter};
+
+    #[test]
+    fn clues_should_add_up_to_a_suspected_family() {
+        assert!(glob("*.id[*].[*].*", "report.docx.id[1e2f-2275].[decrypt@mail.cc].eking"));
+        assert!(!glob("*.id-*.[*].*", "report.docx.locked"));
+        assert!(glob("_readme.txt", "_readme.txt") && !glob("_readme.txt", "x_readme.txt"));
+
+        let mut fingerprinter = FamilyFingerprinter::new();
+        // a note name alone is not enough
+        fingerprinter.on_file(r"C:\Users\bob\Documents\_readme.txt");
+        fingerprinter.on_file(r"C:\Users\bob\Documents\report.docx");
+        assert_eq!(fingerprinter.suspected_family(), None);
+
+        fingerprinter.on_mutexes(&[String::from(r"\Sessions\1\BaseNamedObjects\{1D6FC66E-D1F3-422C-8A53-C0BBCF3D900D}")]);
+        let suspected = fingerprinter.suspected_family().unwrap();
+        assert_eq!(suspected.family, "STOP/Djvu");
+        assert_eq!(suspected.score, 1.0);
+        assert_eq!(suspected.clues[0], (Clue::NoteName, String::from("_readme.txt")));
+
+        let mut fingerprinter = FamilyFingerprinter::new();
+        fingerprinter.on_file(r"\\nas\finance\q3.xlsx.lockbit");
+        fingerprinter.on_sha256("ED01EBFBC9EB5BBEA545AF4D01BF5F1071661840480439C6E5BABE8E080E41AA");
+        assert_eq!(fingerprinter.suspected_family().unwrap().family, "WannaCry");
+    }
+}
@@ -0,0 +1,362 @@
+//! Follow mode, to diagnose why a process family is flagged or not: a live trace of one gid.
+//!
+//! ```owlyshield_ransom follow --pid <pid>``` (or ```--gid```) starts the trace with
+//! ```POST /follow``` on the management API ([crate::api]), then polls ```GET /follow?since=<seq>```
+//! and prints the [TraceEntry]s: the driver messages of the gid, recorded by the fetch stage, and
+//! its features, predictions and alerts, recorded by the workers. A pid is resolved to its gid by
+//! its first driver message.
+//!
+//! One gid is followed at a time. The trace stops with ```DELETE /follow```, or after
+//! [IDLE_TIMEOUT] without a poll (the command was interrupted). At most [MAX_ENTRIES] entries are
+//! kept between two polls, the oldest ones are dropped.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use curl::easy::{Easy, List};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, Param};
+use crate::driver_com::shared_def::IOMessage;
+use crate::features::REGISTRY;
+
+/// Entries kept between two polls.
+pub const MAX_ENTRIES: usize = 10_000;
+/// A trace without poll for longer is stopped.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay between two polls of the command.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The process family to follow, by the pid of one of its processes or by gid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Pid(u32),
+    Gid(u64),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceEntry {
+    pub seq: u64,
+    pub time: String,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TraceEvent {
+    DriverMsg {
+        pid: u32,
+        irp_op: String,
+        file_change: u8,
+        entropy: f64,
+        size: u64,
+        path: String,
+    },
+    /// The last row of the prediction matrix, see [REGISTRY]
+    Prediction {
+        features: Vec<f32>,
+        prediction: f32,
+        threshold: f32,
+    },
+    Alert {
+        prediction: f32,
+        state: String,
+        simulated: bool,
+    },
+}
+
+/// Response of ```GET /follow```.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TracePage {
+    pub target: Target,
+    /// None until the pid is resolved
+    pub gid: Option<u64>,
+    /// Entries lost since the last poll
+    pub dropped: u64,
+    pub entries: Vec<TraceEntry>,
+}
+
+#[derive(Debug)]
+struct Session {
+    target: Target,
+    gid: Option<u64>,
+    entries: VecDeque<TraceEntry>,
+    next_seq: u64,
+    last_poll: Instant,
+}
+
+/// The trace in progress, shared by the pipeline, which writes, and the API, which reads.
+#[derive(Debug, Default)]
+pub struct Follow {
+    /// Read in the hot path without taking the lock
+    active: AtomicBool,
+    /// The followed gid, 0 while the pid is not resolved
+    gid: AtomicU64,
+    session: Mutex<Option<Session>>,
+}
+
+impl Follow {
+    pub fn new() -> Follow {
+        Follow::default()
+    }
+
+    /// Starts a trace of *target*, replacing the current one.
+    pub fn start(&self, target: Target) {
+        let gid = match target {
+            Target::Gid(gid) => Some(gid),
+            Target::Pid(_) => None,
+        };
+        *self.session.lock().unwrap() = Some(Session {
+            target,
+            gid,
+            entries: VecDeque::new(),
+            next_seq: 1,
+            last_poll: Instant::now(),
+        });
+        self.gid.store(gid.unwrap_or(0), Ordering::SeqCst);
+        self.active.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops the trace. False if there was none.
+    pub fn stop(&self) -> bool {
+        self.active.store(false, Ordering::SeqCst);
+        self.gid.store(0, Ordering::SeqCst);
+        self.session.lock().unwrap().take().is_some()
+    }
+
+    /// The entries after *since*, which are forgotten. None without a trace.
+    pub fn poll(&self, since: u64) -> Option<TracePage> {
+        let mut session = self.session.lock().unwrap();
+        let session = session.as_mut()?;
+        session.last_poll = Instant::now();
+        while session.entries.front().is_some_and(|e| e.seq <= since) {
+            session.entries.pop_front();
+        }
+        Some(TracePage {
+            target: session.target,
+            gid: session.gid,
+            dropped: session.entries.front().map_or(0, |e| e.seq.saturating_sub(since + 1)),
+            entries: session.entries.drain(..).collect(),
+        })
+    }
+
+    /// Records *iomsg*, if it belongs to the followed gid.
+    pub fn on_driver_msg(&self, iomsg: &IOMessage) {
+        self.observe(iomsg.pid, iomsg.gid, || TraceEvent::DriverMsg {
+            pid: iomsg.pid,
+            irp_op: String::from(irp_name(iomsg.irp_op)),
+            file_change: iomsg.file_change,
+            entropy: iomsg.entropy,
+            size: iomsg.mem_sized_used,
+            path: iomsg.filepathstr.clone(),
+        });
+    }
+
+    pub fn on_prediction(&self, gid: u64, features: &[f32], prediction: f32, threshold: f32) {
+        if self.is_followed(gid) {
+            self.observe(0, gid, || TraceEvent::Prediction {
+                features: features.to_vec(),
+                prediction,
+                threshold,
+            });
+        }
+    }
+
+    pub fn on_alert(&self, gid: u64, prediction: f32, state: &str, simulated: bool) {
+        if self.is_followed(gid) {
+            self.observe(0, gid, || TraceEvent::Alert {
+                prediction,
+                state: state.to_string(),
+                simulated,
+            });
+        }
+    }
+
+    fn is_followed(&self, gid: u64) -> bool {
+        self.active.load(Ordering::Relaxed) && self.gid.load(Ordering::Relaxed) == gid
+    }
+
+    /// Records the event of *pid* in *gid* if followed. The pid of the target resolves its gid.
+    fn observe<F>(&self, pid: u32, gid: u64, event: F)
+    where
+        F: FnOnce() -> TraceEvent,
+    {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let followed = self.gid.load(Ordering::Relaxed);
+        if followed != 0 && followed != gid {
+            return;
+        }
+        let mut guard = self.session.lock().unwrap();
+        let session = match guard.as_mut() {
+            Some(session) => session,
+            None => return,
+        };
+        if session.last_poll.elapsed() >= IDLE_TIMEOUT {
+            *guard = None;
+            self.active.store(false, Ordering::SeqCst);
+            self.gid.store(0, Ordering::SeqCst);
+            return;
+        }
+        if session.gid.is_none() {
+            if session.target != Target::Pid(pid) {
+                return;
+            }
+            session.gid = Some(gid);
+            self.gid.store(gid, Ordering::SeqCst);
+        }
+        if session.entries.len() >= MAX_ENTRIES {
+            session.entries.pop_front();
+        }
+        session.entries.push_back(TraceEntry {
+            seq: session.next_seq,
+            time: Local::now().format("%H:%M:%S%.3f").to_string(),
+            event: event(),
+        });
+        session.next_seq += 1;
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.event {
+            TraceEvent::DriverMsg {
+                pid,
+                irp_op,
+                file_change,
+                entropy,
+                size,
+                path,
+            } => write!(
+                f,
+                "{} pid {:<6} {:<8} change {} entropy {:.2} size {} {}",
+                self.time, pid, irp_op, file_change, entropy, size, path
+            ),
+            TraceEvent::Prediction {
+                features,
+                prediction,
+                threshold,
+            } => {
+                write!(f, "{} PREDICTION {:.4} (threshold {:.4})", self.time, prediction, threshold)?;
+                for (name, value) in REGISTRY.columns().zip(features) {
+                    write!(f, "\n    {:<32} {}", name, value)?;
+                }
+                Ok(())
+            }
+            TraceEvent::Alert {
+                prediction,
+                state,
+                simulated,
+            } => write!(
+                f,
+                "{} ALERT {:.4}, {}{}",
+                self.time,
+                prediction,
+                state,
+                if *simulated { " (simulated)" } else { "" }
+            ),
+        }
+    }
+}
+
+/// See [crate::driver_com::IrpMajorOp].
+fn irp_name(irp_op: u8) -> &'static str {
+    match irp_op {
+        1 => "READ",
+        2 => "WRITE",
+        3 => "SETINFO",
+        4 => "CREATE",
+        5 => "CLEANUP",
+        _ => "NONE",
+    }
+}
+
+/// Sends a request to the management API of the local agent, returns the body of the response.
+pub fn request(config: &Config, method: &str, path: &str, body: Option<&str>) -> Result<String, String> {
+    let token_path = config.get_path(Param::ConfigPath).join(crate::api::TOKEN_FILE);
+    let token = std::fs::read_to_string(&token_path)
+        .map_err(|e| format!("Cannot read the API token {}: {}", token_path.display(), e))?;
+    let url = format!("http://127.0.0.1:{}{}", config.get_usize(Param::ApiPort), path);
+    let mut headers = List::new();
+    let mut easy = Easy::new();
+    let mut response = Vec::new();
+    let mut data = body.unwrap_or("").as_bytes();
+    (|| -> Result<(), curl::Error> {
+        headers.append("Content-Type: application/json")?;
+        headers.append(&format!("Authorization: Bearer {}", token.trim()))?;
+        easy.url(&url)?;
+        easy.custom_request(method)?;
+        if body.is_some() {
+            easy.post(true)?;
+            easy.post_field_size(data.len() as u64)?;
+        }
+        easy.http_headers(headers)?;
+        easy.timeout(REQUEST_TIMEOUT)?;
+        let mut transfer = easy.transfer();
+        transfer.read_function(|buf| Ok(data.read(buf).unwrap_or(0)))?;
+        transfer.write_function(|buf| {
+            response.extend_from_slice(buf);
+            Ok(buf.len())
+        })?;
+        transfer.perform()
+    })()
+    .map_err(|e| e.to_string())?;
+    let response = String::from_utf8_lossy(&response).to_string();
+    match easy.response_code() {
+        Ok(code) if (200..300).contains(&code) => Ok(response),
+        Ok(code) => Err(format!("HTTP status {}: {}", code, response)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::follow::{Follow, Target, TraceEvent};
+
+    fn event(pid: u32) -> TraceEvent {
+        TraceEvent::DriverMsg {
+            pid,
+            irp_op: String::from("WRITE"),
+            file_change: 2,
+            entropy: 7.9,
+            size: 4096,
+            path: String::from(r"C:\Users\bob\a.docx"),
+        }
+    }
+
+    #[test]
+    fn only_the_gid_of_the_pid_should_be_traced() {
+        let follow = Follow::new();
+        follow.observe(4242, 12, || event(4242));
+        assert!(follow.poll(0).is_none());
+
+        follow.start(Target::Pid(4242));
+        follow.observe(17, 3, || event(17));
+        follow.observe(4242, 12, || event(4242));
+        // another process of the family
+        follow.observe(4243, 12, || event(4243));
+        follow.observe(17, 3, || event(17));
+        follow.on_prediction(12, &[1.0, 2.0], 0.42, 0.65);
+        follow.on_prediction(3, &[1.0, 2.0], 0.99, 0.65);
+
+        let page = follow.poll(0).unwrap();
+        assert_eq!(page.gid, Some(12));
+        assert_eq!(page.dropped, 0);
+        assert_eq!(page.entries.iter().map(|e| e.seq).collect::<Vec<u64>>(), vec![1, 2, 3]);
+        assert!(matches!(page.entries[2].event, TraceEvent::Prediction { .. }));
+        assert!(follow.poll(3).unwrap().entries.is_empty());
+
+        assert!(follow.stop());
+        follow.observe(4242, 12, || event(4242));
+        assert!(follow.poll(0).is_none());
+    }
+}
@@ -0,0 +1,263 @@
+//! Merging of the gids of a same attack. The minifilter gives a new gid to a process started
+//! outside of a monitored family: a ransomware restarting itself for each directory through a
+//! scheduled task, a WMI call or a service, keeps each of its gids under the threshold.
+//!
+//! The fetch stage resolves the parent and the executable of the first process of each new gid,
+//! which is merged into the family of an already seen gid:
+//! * [MergeReason::Child]: its parent belongs to that family (and does not run from a system
+//!   directory, like *explorer.exe*: the applications started by the shell are not merged);
+//! * [MergeReason::Scheduled]: its parent is a scheduler ([SCHEDULERS]), and it runs the same
+//!   executable (same sha256) as a family started by a scheduler less than [MERGE_WINDOW] ago.
+//!
+//! The messages of a merged gid are then queued under the gid of the family, so that a single
+//! [crate::process::ProcessRecord] aggregates the features of all of them. The gid given by the
+//! driver is kept in [crate::driver_com::shared_def::RuntimeFeatures::driver_gid], so that a kill
+//! stops every gid of the family. The families are forgotten [MERGE_WINDOW] after their last
+//! message. Disabled with *GID_MERGE*.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+use tracing::info;
+
+use crate::config::{Config, Param};
+use crate::driver_com::shared_def::IOMessage;
+use crate::utils::sha256_file;
+
+/// Families without message for longer are forgotten, and the executables started by a scheduler
+/// are merged within this delay.
+pub const MERGE_WINDOW: Duration = Duration::from_secs(600);
+/// Executables of the schedulers, lowercase.
+pub const SCHEDULERS: [&str; 8] = [
+    "svchost.exe",
+    "taskeng.exe",
+    "taskhostw.exe",
+    "wmiprvse.exe",
+    "services.exe",
+    "cron",
+    "crond",
+    "atd",
+];
+/// Parents whose children are never merged in their family, lowercase.
+const SYSTEM_DIRS: [&str; 4] = [r"c:\windows\", "/usr/sbin/", "/sbin/", "/usr/lib/systemd/"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeReason {
+    Child,
+    Scheduled,
+}
+
+impl fmt::Display for MergeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeReason::Child => write!(f, "started by a process of the family"),
+            MergeReason::Scheduled => write!(f, "same executable started by a scheduler"),
+        }
+    }
+}
+
+/// How the first process of a new gid was started.
+#[derive(Debug, Clone)]
+pub struct Spawn {
+    pub parent: Option<u32>,
+    pub parent_exe: Option<PathBuf>,
+    pub exe: PathBuf,
+}
+
+#[derive(Debug)]
+struct Family {
+    first_seen: Instant,
+    last_seen: Instant,
+    /// Of the executables started by a scheduler
+    sha256: Option<String>,
+}
+
+pub struct GidMerger {
+    enabled: bool,
+    /// Gid of the family of each gid seen
+    families_by_gid: HashMap<u64, u64>,
+    /// Gid of the family of each pid seen
+    families_by_pid: HashMap<u32, u64>,
+    families: HashMap<u64, Family>,
+    system: System,
+}
+
+impl GidMerger {
+    pub fn from(config: &Config) -> GidMerger {
+        GidMerger {
+            enabled: config.get_bool(Param::GidMerge),
+            families_by_gid: HashMap::new(),
+            families_by_pid: HashMap::new(),
+            families: HashMap::new(),
+            system: System::new(),
+        }
+    }
+
+    /// Replaces the gid of *iomsg* by the gid of its family. The families are not merged into
+    /// the *ignored* gids (excluded from monitoring).
+    pub fn merge<F>(&mut self, iomsg: &mut IOMessage, ignored: F)
+    where
+        F: Fn(u64) -> bool,
+    {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let family = match self.families_by_gid.get(&iomsg.gid) {
+            Some(family) => *family,
+            None => {
+                let spawn = self.spawn_of(iomsg.pid);
+                self.on_new_gid(iomsg.gid, spawn.as_ref(), |exe| sha256_file(exe).ok(), &ignored, now)
+            }
+        };
+        self.families_by_pid.entry(iomsg.pid).or_insert(family);
+        if let Some(family) = self.families.get_mut(&family) {
+            family.last_seen = now;
+        }
+        if family != iomsg.gid {
+            iomsg.runtime_features.driver_gid = Some(iomsg.gid);
+            iomsg.gid = family;
+        }
+    }
+
+    /// The gid of the family of *gid*, itself if not merged.
+    pub fn family_of(&self, gid: u64) -> u64 {
+        self.families_by_gid.get(&gid).copied().unwrap_or(gid)
+    }
+
+    /// Forgets the families without message for [MERGE_WINDOW].
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        self.families.retain(|_, family| now.duration_since(family.last_seen) < MERGE_WINDOW);
+        let families = &self.families;
+        self.families_by_gid.retain(|_, family| families.contains_key(family));
+        self.families_by_pid.retain(|_, family| families.contains_key(family));
+    }
+
+    fn spawn_of(&mut self, pid: u32) -> Option<Spawn> {
+        self.system.refresh_process(pid as Pid);
+        let process = self.system.process(pid as Pid)?;
+        let (exe, parent) = (process.exe().to_path_buf(), process.parent());
+        let parent_exe = parent.and_then(|parent| {
+            self.system.refresh_process(parent);
+            self.system.process(parent).map(|p| p.exe().to_path_buf())
+        });
+        Some(Spawn {
+            parent: parent.map(|p| p as u32),
+            parent_exe,
+            exe,
+        })
+    }
+
+    /// Registers *gid*, and returns the gid of its family.
+    fn on_new_gid<H>(&mut self, gid: u64, spawn: Option<&Spawn>, sha256: H, ignored: &dyn Fn(u64) -> bool, now: Instant) -> u64
+    where
+        H: FnOnce(&Path) -> Option<String>,
+    {
+        let mut merged: Option<(u64, MergeReason)> = None;
+        let mut hash = None;
+        if let Some(spawn) = spawn {
+            let parent_family = spawn.parent.and_then(|parent| self.families_by_pid.get(&parent).copied());
+            if let Some(family) = parent_family.filter(|f| *f != gid && !ignored(*f)) {
+                if !spawn.parent_exe.as_deref().is_some_and(is_system) {
+                    merged = Some((family, MergeReason::Child));
+                }
+            }
+            if merged.is_none() && spawn.parent_exe.as_deref().is_some_and(is_scheduler) {
+                hash = sha256(&spawn.exe);
+                merged = hash.as_ref().and_then(|hash| {
+                    self.families
+                        .iter()
+                        .filter(|(family, f)| {
+                            f.sha256.as_ref() == Some(hash) && now.duration_since(f.first_seen) < MERGE_WINDOW && !ignored(**family)
+                        })
+                        .min_by_key(|(_, f)| f.first_seen)
+                        .map(|(family, _)| (*family, MergeReason::Scheduled))
+                });
+            }
+        }
+        let family = match merged {
+            Some((family, reason)) => {
+                info!(gid, family, exepath = ?spawn.map(|s| &s.exe), %reason, "Gid merged into its family");
+                family
+            }
+            None => {
+                self.families.insert(
+                    gid,
+                    Family {
+                        first_seen: now,
+                        last_seen: now,
+                        sha256: hash,
+                    },
+                );
+                gid
+            }
+        };
+        self.families_by_gid.insert(gid, family);
+        family
+    }
+}
+
+fn is_scheduler(exe: &Path) -> bool {
+    let exe = exe.to_string_lossy().to_lowercase();
+    // the paths of the driver are Windows paths
+    let name = exe.rsplit(['\\', '/']).next().unwrap_or_default();
+    SCHEDULERS.contains(&name)
+}
+
+fn is_system(exe: &Path) -> bool {
+    let exe = exe.to_string_lossy().to_lowercase();
+    SYSTEM_DIRS.iter().any(|dir| exe.starts_with(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use sysinfo::{System, SystemExt};
+
+    use crate::gidmerge::{GidMerger, Spawn};
+
+    fn spawn(parent: u32, parent_exe: &str, exe: &str) -> Spawn {
+        Spawn {
+            parent: Some(parent),
+            parent_exe: Some(PathBuf::from(parent_exe)),
+            exe: PathBuf::from(exe),
+        }
+    }
+
+    #[test]
+    fn relaunched_ransomware_should_stay_in_one_family() {
+        let mut merger = GidMerger {
+            enabled: true,
+            families_by_gid: HashMap::new(),
+            families_by_pid: HashMap::new(),
+            families: HashMap::new(),
+            system: System::new(),
+        };
+        let hash = |_: &std::path::Path| Some(String::from("e3b0c442"));
+        let none = |_: u64| false;
+        let now = Instant::now();
+        let explorer = r"C:\Windows\explorer.exe";
+        let svchost = r"C:\Windows\System32\svchost.exe";
+
+        assert_eq!(merger.on_new_gid(10, Some(&spawn(1, explorer, r"C:\Temp\evil.exe")), hash, &none, now), 10);
+        merger.families_by_pid.insert(100, 10);
+        // started by the shell, or by a process of the family
+        assert_eq!(merger.on_new_gid(11, Some(&spawn(1, explorer, r"C:\Tools\app.exe")), hash, &none, now), 11);
+        assert_eq!(merger.on_new_gid(12, Some(&spawn(100, r"C:\Temp\evil.exe", r"C:\Temp\b.exe")), hash, &none, now), 10);
+        assert_eq!(merger.on_new_gid(13, Some(&spawn(100, r"C:\Temp\evil.exe", r"C:\Temp\c.exe")), hash, &|g| g == 10, now), 13);
+
+        // respawned by the task scheduler, within the window
+        assert_eq!(merger.on_new_gid(20, Some(&spawn(2, svchost, r"C:\Temp\evil.exe")), hash, &none, now), 20);
+        assert_eq!(merger.on_new_gid(21, Some(&spawn(2, svchost, r"C:\Temp\copy.exe")), hash, &none, now + Duration::from_secs(60)), 20);
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(merger.on_new_gid(22, Some(&spawn(2, svchost, r"C:\Temp\evil.exe")), hash, &none, later), 22);
+        assert_eq!(merger.on_new_gid(23, None, hash, &none, later), 23);
+    }
+}
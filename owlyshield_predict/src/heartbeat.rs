@@ -0,0 +1,347 @@
+//! Fleet heartbeat, for a basic centralized management of the agents.
+//!
+//! Every *HEARTBEAT_INTERVAL* seconds, the health of the agent is posted as JSON to
+//! *HEARTBEAT_URL* (*NONE* disables it, and it must be https): identity, driver connection, models versions, depth of the
+//! queue and backpressure ([crate::backpressure]), antivirus ([crate::defender]), last alert, and the results of the previous commands. If *ConfigPath/heartbeat_token*
+//! exists, its content is sent as a bearer token.
+//!
+//! The response carries the pending commands:
+//! ```json
+//! {"commands": [{"id": "42", "time": 1700000000, "command": "add_exclusion",
+//!   "args": {"scope": "never_kill", "kind": "signers", "value": "Acme"}, "signature": "..."}]}
+//! ```
+//!
+//! | Command            | Args                            |                                             |
+//! |--------------------|---------------------------------|---------------------------------------------|
+//! | add_exclusion      | ```{"scope", "kind", "value"}```| see [Exclusions::add]                       |
+//! | update_exclusions  | ```{"content"}```               | replaces *exclusions.toml*                  |
+//! | pull_debug_bundle  |                                 | status, gids and alerts in the next results |
+//!
+//! The certificate of the endpoint is verified by curl. Each command is further signed with the
+//! key of *ConfigPath/heartbeat_key*, shared with the management server: *signature* is the hex
+//! HMAC-SHA256 of *v1:{machine_id}:{time}:{id}:{command}:{args}*, *args* in compact JSON with
+//! sorted keys, and *time* is in seconds since the epoch. The commands without a valid signature,
+//! older than [MAX_COMMAND_AGE] or already run are refused, as all of them without a key. Each
+//! command, run or refused, is written to the audit trail of [AdminAuthz].
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use curl::easy::{Easy, List};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use strum::IntoEnumIterator;
+use tracing::{debug, error, info, warn};
+
+use crate::authz::AdminAuthz;
+use crate::config::{Config, Param};
+use crate::connectors::slack::hmac_sha256;
+use crate::exclusions::{ExclusionScope, Exclusions};
+use crate::identity::AgentIdentity;
+use crate::service_ctl::Lifecycle;
+use crate::status::{rfc3339, AgentStatus};
+use crate::utils::constant_time_eq;
+use crate::{prediction, prediction_static};
+
+/// Name of the file of the token, in *ConfigPath*.
+const TOKEN_FILE: &str = "heartbeat_token";
+/// Name of the file of the key of the signatures of the commands, in *ConfigPath*.
+const KEY_FILE: &str = "heartbeat_key";
+/// Older commands are refused, and the ids of the more recent ones are kept against replays.
+pub const MAX_COMMAND_AGE: Duration = Duration::from_secs(300);
+/// Without a beat of the protection loop for longer, the driver is considered disconnected.
+const DISCONNECTED_AFTER: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+struct Command {
+    id: String,
+    #[serde(default)]
+    time: u64,
+    command: String,
+    #[serde(default)]
+    args: Value,
+    #[serde(default)]
+    signature: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Commands {
+    #[serde(default)]
+    commands: Vec<Command>,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandResult {
+    id: String,
+    ok: bool,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+/// Sends the heartbeats and runs the commands until *done* is set. Returns immediately if
+/// disabled.
+pub fn run(config: &Config, lifecycle: &Lifecycle, exclusions: &Exclusions, status: &AgentStatus, done: &AtomicBool) {
+    let url = config.get_str(Param::HeartbeatUrl).trim().to_string();
+    if url.eq_ignore_ascii_case("NONE") {
+        return;
+    }
+    if !url.to_lowercase().starts_with("https://") {
+        error!(%url, "Heartbeat disabled: the url must be https");
+        return;
+    }
+    let interval = Duration::from_secs(config.get_usize(Param::HeartbeatInterval).max(1) as u64);
+    let read = |file: &str| {
+        fs::read_to_string(config.get_path(Param::ConfigPath).join(file))
+            .ok()
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+    };
+    let token = read(TOKEN_FILE);
+    let key = read(KEY_FILE);
+    if key.is_none() {
+        warn!("No {}: the remote commands will be refused", KEY_FILE);
+    }
+    let heartbeat = Heartbeat {
+        config,
+        lifecycle,
+        exclusions,
+        status,
+        identity: AgentIdentity::load(config),
+        authz: AdminAuthz::from(config),
+        origin: url.clone(),
+        key,
+    };
+    info!(%url, "Heartbeat started");
+    let mut results = Vec::new();
+    let mut seen: HashMap<String, u64> = HashMap::new();
+    let mut last_sent: Option<Instant> = None;
+    while !done.load(Ordering::SeqCst) {
+        if last_sent.is_none_or(|t| t.elapsed() >= interval) {
+            let body = heartbeat.health(&results).to_string();
+            match post(&url, token.as_deref(), &body) {
+                Ok(response) => {
+                    results.clear();
+                    let now = SystemTime::now();
+                    let oldest = now.duration_since(UNIX_EPOCH).unwrap_or_default().saturating_sub(MAX_COMMAND_AGE).as_secs();
+                    seen.retain(|_, time| *time >= oldest);
+                    for command in parse_commands(&response) {
+                        results.push(heartbeat.check_and_execute(&command, &mut seen, now));
+                    }
+                }
+                // the results are sent again with the next heartbeat
+                Err(e) => warn!(%url, "Cannot send the heartbeat: {}", e),
+            }
+            last_sent = Some(Instant::now());
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+struct Heartbeat<'a> {
+    config: &'a Config,
+    lifecycle: &'a Lifecycle,
+    exclusions: &'a Exclusions,
+    status: &'a AgentStatus,
+    identity: AgentIdentity,
+    authz: AdminAuthz,
+    /// The url of the heartbeat, in the audit trail
+    origin: String,
+    /// Of the signatures of the commands
+    key: Option<String>,
+}
+
+impl Heartbeat<'_> {
+    fn health(&self, results: &[CommandResult]) -> Value {
+        json!({
+            "identity": self.identity,
+            "time": rfc3339(SystemTime::now()),
+            "uptime_secs": SystemTime::now().duration_since(self.status.time_started).unwrap_or_default().as_secs(),
+            "driver_connected": self.lifecycle.since_last_beat() < DISCONNECTED_AFTER,
+            "paused": self.lifecycle.is_paused(),
+            "mode": self.config.get_scheduled(Param::Mode),
+            "profile": self.config.active_profile(),
+            "kill_policy": self.config.get_str(Param::KillPolicy),
+            "models": {
+                "dynamic": prediction::model_version(),
+                "static": prediction_static::model_version(),
+            },
+            "gids": self.status.gids().len(),
+            "queued_msgs": self.status.queued_msgs(),
+            "backpressure": self.status.backpressure(),
+            "av": self.status.av.status(),
+            "last_alert": self.status.alerts().first(),
+            "results": results,
+        })
+    }
+
+    /// Runs *command* if it is signed, recent and not in *seen*, the ids of the commands already
+    /// run.
+    fn check_and_execute(&self, command: &Command, seen: &mut HashMap<String, u64>, now: SystemTime) -> CommandResult {
+        let allowed = match &self.key {
+            Some(_) if seen.contains_key(&command.id) => Err(String::from("Command already run")),
+            Some(key) => verify(key, &self.identity.machine_id, command, now),
+            None => Err(String::from("No key to verify the commands")),
+        };
+        self.authz.record_remote(&self.origin, &command.command, allowed.is_ok());
+        if let Err(e) = allowed {
+            warn!(id = %command.id, command = %command.command, "Remote command refused: {}", e);
+            return CommandResult {
+                id: command.id.clone(),
+                ok: false,
+                message: e,
+                data: None,
+            };
+        }
+        seen.insert(command.id.clone(), command.time);
+        self.execute(command)
+    }
+
+    fn execute(&self, command: &Command) -> CommandResult {
+        info!(id = %command.id, command = %command.command, "Remote command");
+        let res = match command.command.as_str() {
+            "add_exclusion" => self.add_exclusion(&command.args).map(|_| None),
+            "update_exclusions" => match command.args["content"].as_str() {
+                Some(content) => self.exclusions.replace(content).map(|_| None),
+                None => Err(String::from("Missing content")),
+            },
+            "pull_debug_bundle" => Ok(Some(self.debug_bundle())),
+            _ => Err(format!("Unknown command {}", command.command)),
+        };
+        if let Err(e) = &res {
+            warn!(id = %command.id, command = %command.command, "Remote command failed: {}", e);
+        }
+        CommandResult {
+            id: command.id.clone(),
+            ok: res.is_ok(),
+            message: res.as_ref().err().cloned().unwrap_or_default(),
+            data: res.ok().flatten(),
+        }
+    }
+
+    fn add_exclusion(&self, args: &Value) -> Result<(), String> {
+        let arg = |key: &str| args[key].as_str().ok_or_else(|| format!("Missing {}", key));
+        let scope = match arg("scope")? {
+            "never_monitor" => ExclusionScope::NeverMonitor,
+            "never_kill" => ExclusionScope::NeverKill,
+            scope => return Err(format!("Unknown scope {}", scope)),
+        };
+        self.exclusions.add(scope, arg("kind")?, arg("value")?)
+    }
+
+    fn debug_bundle(&self) -> Value {
+        json!({
+            "config": Param::iter()
+                .map(|p| (Param::convert_to_str(&p), self.config.get_str(p)))
+                .collect::<BTreeMap<_, _>>(),
+            "gids": self.status.gids(),
+            "alerts": self.status.alerts(),
+        })
+    }
+}
+
+/// Checks the *signature* of *command*, sent to *machine_id* less than [MAX_COMMAND_AGE] ago.
+fn verify(key: &str, machine_id: &str, command: &Command, now: SystemTime) -> Result<(), String> {
+    let sent = UNIX_EPOCH + Duration::from_secs(command.time);
+    if now.duration_since(sent).unwrap_or_else(|e| e.duration()) > MAX_COMMAND_AGE {
+        return Err(String::from("Command expired"));
+    }
+    let message = format!("v1:{}:{}:{}:{}:{}", machine_id, command.time, command.id, command.command, command.args);
+    let expected: String = hmac_sha256(key.as_bytes(), message.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+    if constant_time_eq(expected.as_bytes(), command.signature.trim().as_bytes()) {
+        Ok(())
+    } else {
+        Err(String::from("Invalid signature"))
+    }
+}
+
+/// Posts *body*, returns the body of the response.
+fn post(url: &str, token: Option<&str>, body: &str) -> Result<String, String> {
+    let mut headers = List::new();
+    let mut easy = Easy::new();
+    let mut response = Vec::new();
+    let mut data = body.as_bytes();
+    (|| -> Result<(), curl::Error> {
+        headers.append("Content-Type: application/json")?;
+        if let Some(token) = token {
+            headers.append(&format!("Authorization: Bearer {}", token))?;
+        }
+        easy.url(url)?;
+        easy.post(true)?;
+        easy.post_field_size(data.len() as u64)?;
+        easy.http_headers(headers)?;
+        easy.timeout(REQUEST_TIMEOUT)?;
+        let mut transfer = easy.transfer();
+        transfer.read_function(|buf| Ok(data.read(buf).unwrap_or(0)))?;
+        transfer.write_function(|buf| {
+            response.extend_from_slice(buf);
+            Ok(buf.len())
+        })?;
+        transfer.perform()
+    })()
+    .map_err(|e| e.to_string())?;
+    match easy.response_code() {
+        Ok(code) if (200..300).contains(&code) => Ok(String::from_utf8_lossy(&response).to_string()),
+        Ok(code) => Err(format!("HTTP status {}", code)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The commands of a response. An empty body means no command.
+fn parse_commands(response: &str) -> Vec<Command> {
+    if response.trim().is_empty() {
+        return Vec::new();
+    }
+    match serde_json::from_str::<Commands>(response) {
+        Ok(commands) => commands.commands,
+        Err(e) => {
+            debug!(%response, "Invalid heartbeat response: {}", e);
+            warn!("Invalid heartbeat response: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::connectors::slack::hmac_sha256;
+    use crate::heartbeat::{parse_commands, verify};
+
+    #[test]
+    fn commands_should_be_parsed() {
+        let commands = parse_commands(
+            r#"{"commands": [{"id": "1", "command": "pull_debug_bundle"},
+                {"id": "2", "command": "update_exclusions", "args": {"content": "[never_kill]"}}]}"#,
+        );
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[1].command, "update_exclusions");
+        assert_eq!(commands[1].args["content"], "[never_kill]");
+        assert!(parse_commands("").is_empty());
+        assert!(parse_commands("<html>").is_empty());
+    }
+
+    #[test]
+    fn only_recent_commands_signed_for_this_machine_should_be_verified() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_100);
+        let message = r#"v1:m1:1700000000:7:add_exclusion:{"kind":"signers","scope":"never_kill","value":"Acme"}"#;
+        let signature: String = hmac_sha256(b"key", message.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+        let response = format!(
+            r#"{{"commands": [{{"id": "7", "time": 1700000000, "command": "add_exclusion",
+                "args": {{"value": "Acme", "scope": "never_kill", "kind": "signers"}}, "signature": "{}"}}]}}"#,
+            signature
+        );
+        let command = &parse_commands(&response)[0];
+        assert_eq!(verify("key", "m1", command, now), Ok(()));
+        assert!(verify("other", "m1", command, now).is_err());
+        assert!(verify("key", "m2", command, now).is_err());
+        assert!(verify("key", "m1", command, now + Duration::from_secs(600)).is_err());
+        assert!(verify("key", "m1", &parse_commands(&response.replace("Acme", "Evil"))[0], now).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! Writes of a gid by directory over time, shown in the HTML report as a heatmap: which folders
+//! and shares were being encrypted, and in what order.
+//!
+//! The writes are counted in [BUCKETS] time buckets per directory, from the first write of the
+//! gid. The buckets are one second wide at first, and double (their pairs are merged) whenever the
+//! activity outlasts them, so that the memory does not grow with the lifetime of the gid. Beyond
+//! [MAX_DIRS] directories, the writes are counted together.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+
+use crate::schema::rfc3339;
+
+/// Buckets per directory.
+pub const BUCKETS: usize = 60;
+/// Directories counted apart, the others are counted together.
+pub const MAX_DIRS: usize = 256;
+/// Directories shown in the report, the most written ones.
+const MAX_ROWS: usize = 50;
+const INITIAL_BUCKET: Duration = Duration::from_secs(1);
+const OTHER_DIRS: &str = "(other directories)";
+
+#[derive(Debug, Clone)]
+struct DirWrites {
+    first: SystemTime,
+    total: u64,
+    /// Up to the last bucket written
+    buckets: Vec<u32>,
+}
+
+impl DirWrites {
+    fn new(first: SystemTime) -> DirWrites {
+        DirWrites {
+            first,
+            total: 0,
+            buckets: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, index: usize) {
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+        self.total += 1;
+    }
+
+    fn merge_pairs(&mut self) {
+        self.buckets = self.buckets.chunks(2).map(|pair| pair.iter().sum()).collect();
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteHeatmap {
+    start: Option<SystemTime>,
+    bucket: Duration,
+    dirs: HashMap<Arc<str>, DirWrites>,
+    other: Option<DirWrites>,
+}
+
+/// The heatmap of a gid, embedded in the HTML report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeatmapData {
+    /// RFC 3339, in UTC: start of the first bucket
+    pub start: String,
+    pub bucket_secs: u64,
+    /// By first write
+    pub rows: Vec<HeatmapRow>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeatmapRow {
+    pub dir: String,
+    pub first_write: String,
+    pub writes: u64,
+    pub buckets: Vec<u32>,
+}
+
+impl WriteHeatmap {
+    pub fn new() -> WriteHeatmap {
+        WriteHeatmap {
+            start: None,
+            bucket: INITIAL_BUCKET,
+            dirs: HashMap::new(),
+            other: None,
+        }
+    }
+
+    pub fn on_write(&mut self, dir: &Arc<str>, time: SystemTime) {
+        let start = *self.start.get_or_insert(time);
+        let elapsed = time.duration_since(start).unwrap_or(Duration::ZERO);
+        while elapsed.as_secs() / self.bucket.as_secs() >= BUCKETS as u64 {
+            self.widen();
+        }
+        let index = (elapsed.as_secs() / self.bucket.as_secs()) as usize;
+        let full = self.dirs.len() >= MAX_DIRS;
+        match self.dirs.get_mut(dir) {
+            Some(writes) => writes.add(index),
+            None if !full => {
+                let mut writes = DirWrites::new(time);
+                writes.add(index);
+                self.dirs.insert(dir.clone(), writes);
+            }
+            None => self.other.get_or_insert_with(|| DirWrites::new(time)).add(index),
+        }
+    }
+
+    pub fn dirs_len(&self) -> usize {
+        self.dirs.len()
+    }
+
+    fn widen(&mut self) {
+        self.bucket *= 2;
+        for writes in self.dirs.values_mut().chain(self.other.iter_mut()) {
+            writes.merge_pairs();
+        }
+    }
+
+    /// The [MAX_ROWS] most written directories, by first write, then the others together.
+    pub fn data(&self) -> HeatmapData {
+        let row = |dir: &str, writes: &DirWrites| HeatmapRow {
+            dir: dir.to_string(),
+            first_write: rfc3339(writes.first),
+            writes: writes.total,
+            buckets: writes.buckets.clone(),
+        };
+        let mut dirs: Vec<(&Arc<str>, &DirWrites)> = self.dirs.iter().collect();
+        dirs.sort_by_key(|(_, writes)| Reverse(writes.total));
+        dirs.truncate(MAX_ROWS);
+        dirs.sort_by(|a, b| a.1.first.cmp(&b.1.first).then_with(|| a.0.cmp(b.0)));
+        let mut rows: Vec<HeatmapRow> = dirs.iter().map(|(dir, writes)| row(dir, writes)).collect();
+        if let Some(other) = &self.other {
+            rows.push(row(OTHER_DIRS, other));
+        }
+        HeatmapData {
+            start: self.start.map(rfc3339).unwrap_or_default(),
+            bucket_secs: self.bucket.as_secs(),
+            rows,
+        }
+    }
+
+    /// A table of the directories, with a cell per bucket shaded by its writes, followed by the
+    /// dataset as JSON (*heatmap_data*).
+    pub fn to_html(&self) -> String {
+        let data = self.data();
+        let max = data.rows.iter().flat_map(|row| row.buckets.iter()).max().copied().unwrap_or(0).max(1);
+        let mut html = format!(
+            "<table><tr><td>Directory</td><td>Writes</td><td>First write</td><td>From {}, by {} s</td></tr>\n",
+            data.start, data.bucket_secs
+        );
+        for row in &data.rows {
+            html.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td><td style='white-space: nowrap;'>", escape(&row.dir), row.writes, row.first_write));
+            for (i, count) in row.buckets.iter().enumerate() {
+                html.push_str(&format!(
+                    "<span title='+{} s: {} writes' style='display: inline-block; width: 6px; height: 14px; background-color: rgba(255, 0, 0, {:.2});'></span>",
+                    i as u64 * data.bucket_secs,
+                    count,
+                    *count as f32 / max as f32
+                ));
+            }
+            html.push_str("</td></tr>\n");
+        }
+        html.push_str("</table>\n");
+        let json = serde_json::to_string(&data).unwrap_or_default().replace("</", "<\\/");
+        html.push_str(&format!("<script type='application/json' id='heatmap_data'>{}</script>\n", json));
+        html
+    }
+}
+
+impl Default for WriteHeatmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use crate::heatmap::{WriteHeatmap, BUCKETS};
+
+    #[test]
+    fn directories_should_be_ordered_by_first_write_with_widening_buckets() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let docs: Arc<str> = Arc::from(r"C:\Users\bob\Documents");
+        let share: Arc<str> = Arc::from(r"\\nas\finance");
+
+        let mut heatmap = WriteHeatmap::new();
+        heatmap.on_write(&docs, at(0));
+        heatmap.on_write(&share, at(5));
+        heatmap.on_write(&docs, at(1));
+        let data = heatmap.data();
+        assert_eq!(data.bucket_secs, 1);
+        assert_eq!(data.rows[1].buckets, vec![0, 0, 0, 0, 0, 1]);
+
+        // beyond the buckets: they double
+        heatmap.on_write(&docs, at(BUCKETS as u64 + 10));
+        let data = heatmap.data();
+        assert_eq!(data.bucket_secs, 2);
+        let dirs: Vec<&str> = data.rows.iter().map(|row| row.dir.as_str()).collect();
+        assert_eq!(dirs, vec![&*docs, &*share]);
+        assert_eq!(data.rows[0].writes, 3);
+        assert_eq!(data.rows[0].buckets.len(), 36);
+        assert_eq!((data.rows[0].buckets[0], data.rows[0].buckets[35]), (2, 1));
+        assert_eq!(data.rows[1].buckets, vec![0, 0, 1]);
+        assert!(heatmap.to_html().contains(r"\\\\nas\\finance"));
+    }
+}
@@ -0,0 +1,103 @@
+//! Raw driver messages history of a gid, kept for the incident reports.
+//!
+//! Only the last *HISTORY_MAX_MSGS* messages are kept in memory. With *HISTORY_SPILL*, the older
+//! ones are appended to *DebugPath\history\gid_<gid>_<time>.bin* (same format as the records
+//! files of ```--features record```, readable by the *replay* and *timeline* commands). The file
+//! is deleted with the gid, unless it has been kept by [MsgHistory::keep] for an incident.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use tracing::error;
+
+use crate::config::{Config, Param};
+use crate::csvwriter::CsvWriter;
+use crate::driver_com::shared_def::IOMessage;
+use crate::utils::FILE_TIME_FORMAT;
+
+#[derive(Debug)]
+pub struct MsgHistory {
+    recent: VecDeque<IOMessage>,
+    cap: usize,
+    /// Set if spilling is enabled.
+    spill_path: Option<PathBuf>,
+    spilled: usize,
+    kept: bool,
+}
+
+impl MsgHistory {
+    pub fn from(config: &Config, gid: u64) -> MsgHistory {
+        let spill_path = if config.get_bool(Param::HistorySpill) {
+            Some(
+                config
+                    .get_path(Param::DebugPath)
+                    .join("history")
+                    .join(format!("gid_{}_{}.bin", gid, Local::now().format(FILE_TIME_FORMAT))),
+            )
+        } else {
+            None
+        };
+        MsgHistory {
+            recent: VecDeque::new(),
+            cap: config.get_usize(Param::HistoryMaxMsgs),
+            spill_path,
+            spilled: 0,
+            kept: false,
+        }
+    }
+
+    pub fn push(&mut self, iomsg: &IOMessage) {
+        if self.cap == 0 {
+            return;
+        }
+        if self.recent.len() == self.cap {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.spill(&oldest);
+            }
+        }
+        self.recent.push_back(iomsg.clone());
+    }
+
+    fn spill(&mut self, iomsg: &IOMessage) {
+        if let Some(path) = &self.spill_path {
+            let res = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| rmp_serde::to_vec(iomsg).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)))
+                .and_then(|buf| CsvWriter::from_path(path).write_irp_csv_files(&buf));
+            match res {
+                Ok(()) => self.spilled += 1,
+                Err(e) => {
+                    error!("Cannot spill history to {}: {}", path.display(), e);
+                    // do not retry for each message
+                    self.spill_path = None;
+                }
+            }
+        }
+    }
+
+    /// The last messages, oldest first.
+    pub fn recent(&self) -> &VecDeque<IOMessage> {
+        &self.recent
+    }
+
+    /// The file with the older messages, if any were spilled.
+    pub fn spill_path(&self) -> Option<&Path> {
+        self.spill_path.as_deref().filter(|_| self.spilled > 0)
+    }
+
+    /// Keeps the spill file after the gid is gone, for the incident report.
+    pub fn keep(&mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for MsgHistory {
+    fn drop(&mut self) {
+        if let (Some(path), false) = (self.spill_path(), self.kept) {
+            fs::remove_file(path).unwrap_or(());
+        }
+    }
+}
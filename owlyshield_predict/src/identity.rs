@@ -0,0 +1,104 @@
+//! Identity of the machine and of the agent, attached to every connector event and report so that
+//! the backends can correlate them by machine.
+//!
+//! The machine id is a random UUID, generated on the first start and persisted in
+//! *ConfigPath/machine_id*: it survives the renaming of the host and the updates of the agent.
+
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+use sysinfo::{System, SystemExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::{Config, Param};
+use crate::os;
+
+/// Name of the file of the machine id, in *ConfigPath*.
+const MACHINE_ID_FILE: &str = "machine_id";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentIdentity {
+    pub machine_id: String,
+    pub hostname: String,
+    /// *Windows 10 Pro 19044*, *Linux 22.04 Ubuntu*...
+    pub os_version: String,
+    /// None if the machine is not joined to a domain
+    pub domain: Option<String>,
+    pub agent_version: String,
+}
+
+impl AgentIdentity {
+    /// Collects the metadata, and creates the machine id if needed.
+    pub fn load(config: &Config) -> AgentIdentity {
+        let id_path = config.get_path(Param::ConfigPath).join(MACHINE_ID_FILE);
+        AgentIdentity::collect(load_or_create_machine_id(&id_path), config.get_str(Param::NumVersion))
+    }
+
+    /// Same as [AgentIdentity::load], with a temporary machine id if the configuration cannot be
+    /// read.
+    pub fn current() -> AgentIdentity {
+        match Config::new() {
+            Ok(config) => AgentIdentity::load(&config),
+            Err(e) => {
+                warn!("{}: the machine id is not persisted", e);
+                AgentIdentity::collect(Uuid::new_v4().to_string(), env!("CARGO_PKG_VERSION"))
+            }
+        }
+    }
+
+    fn collect(machine_id: String, agent_version: &str) -> AgentIdentity {
+        AgentIdentity {
+            machine_id,
+            hostname: hostname::get()
+                .ok()
+                .and_then(|h| h.to_str().map(String::from))
+                .unwrap_or_else(|| String::from("Unknown host")),
+            os_version: System::new()
+                .long_os_version()
+                .unwrap_or_else(|| String::from(std::env::consts::OS)),
+            domain: os::domain(),
+            agent_version: String::from(agent_version),
+        }
+    }
+
+    /// *hostname (machine id)*, for the reports.
+    pub fn machine(&self) -> String {
+        format!("{} ({})", self.hostname, self.machine_id)
+    }
+}
+
+/// The UUID stored in *path*, or a new one written there. If it cannot be written, the id changes
+/// on each start.
+fn load_or_create_machine_id(path: &Path) -> String {
+    if let Ok(content) = fs::read_to_string(path) {
+        if let Ok(id) = Uuid::parse_str(content.trim()) {
+            return id.to_string();
+        }
+        warn!("Invalid machine id in {}, generating a new one", path.display());
+    }
+    let id = Uuid::new_v4().to_string();
+    match fs::write(path, &id) {
+        Ok(()) => info!(machine_id = %id, "New machine id"),
+        Err(e) => warn!("Cannot write the machine id to {}: {}", path.display(), e),
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::identity::load_or_create_machine_id;
+
+    #[test]
+    fn machine_id_should_be_stable() {
+        let path = std::env::temp_dir().join(format!("owlyshield_machine_id_{}", std::process::id()));
+        fs::write(&path, "not an uuid").unwrap();
+        let id = load_or_create_machine_id(&path);
+        assert_ne!(id, "not an uuid");
+        assert_eq!(load_or_create_machine_id(&path), id);
+        fs::remove_file(&path).unwrap();
+    }
+}
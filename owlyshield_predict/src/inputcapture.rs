@@ -0,0 +1,413 @@
+//! Keyboard and clipboard capture by the gids, as auxiliary features: many ransomware operators
+//! also steal the credentials and the data, with the keyloggers and clipboard stealers of the
+//! infostealers (Windows only).
+//!
+//! With *INPUT_CAPTURE*, the agent starts a real-time ETW session on the *Microsoft-Windows-Win32k*
+//! provider, with its *AuditApiCalls* keyword: Win32k then reports the calls to
+//! *SetWindowsHookEx*, *GetAsyncKeyState*, *RegisterRawInputDevices* and to the clipboard, in the
+//! context of the caller. The events are named after the API by the manifest of the provider: they
+//! are classified by their task and event names ([InputApi::of]), those of the other APIs are
+//! ignored. As for [crate::sysmon], they are queued by the session thread ([InputCaptureFeed]),
+//! then attributed by the fetch stage of the [crate::pipeline] to the gids of their pids. Each gid
+//! counts them in its [InputCaptureActivity], which gives the features *input_keyboard_capture*
+//! and *input_clipboard_reads*, and the lines of the incident reports.
+//!
+//! The audit of Win32k depends on the version of Windows: without it, the session starts but no
+//! event comes, and the features stay at 0.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+#[cfg(windows)]
+use std::thread;
+
+#[cfg(windows)]
+use tracing::{info, warn};
+
+use crate::config::Config;
+#[cfg(windows)]
+use crate::config::Param;
+use crate::process::procs::Procs;
+
+/// Name of the ETW session.
+#[cfg_attr(not(windows), allow(dead_code))]
+const SESSION_NAME: &str = "Owlyshield-InputCapture";
+/// Events waiting to be attributed, beyond which they are dropped.
+const MAX_PENDING: usize = 10_000;
+
+/// An API of keyboard or clipboard capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputApi {
+    /// *SetWindowsHookEx*, whatever the type of the hook
+    Hook,
+    /// *GetAsyncKeyState*, *GetKeyState*, *GetKeyboardState*
+    KeyState,
+    /// *RegisterRawInputDevices*
+    RawInput,
+    /// *OpenClipboard*, *GetClipboardData*, *AddClipboardFormatListener*, *SetClipboardViewer*
+    Clipboard,
+}
+
+impl InputApi {
+    /// Of the task or event name of a Win32k event, None for the other APIs.
+    pub fn of(name: &str) -> Option<InputApi> {
+        let name = name.to_lowercase();
+        let has = |apis: &[&str]| apis.iter().any(|api| name.contains(api));
+        if has(&["setwindowshookex"]) {
+            Some(InputApi::Hook)
+        } else if has(&["getasynckeystate", "getkeystate", "getkeyboardstate"]) {
+            Some(InputApi::KeyState)
+        } else if has(&["registerrawinputdevices"]) {
+            Some(InputApi::RawInput)
+        } else if has(&["openclipboard", "getclipboarddata", "addclipboardformatlistener", "setclipboardviewer"]) {
+            Some(InputApi::Clipboard)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for InputApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            InputApi::Hook => "hook",
+            InputApi::KeyState => "key state",
+            InputApi::RawInput => "raw input",
+            InputApi::Clipboard => "clipboard",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputCaptureEvent {
+    /// Of the caller
+    pub pid: u32,
+    pub api: InputApi,
+}
+
+/// The calls of a gid to the keyboard and clipboard APIs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputCaptureActivity {
+    pub hooks: usize,
+    pub key_state_polls: usize,
+    pub raw_input_registrations: usize,
+    pub clipboard_reads: usize,
+}
+
+impl InputCaptureActivity {
+    pub fn on_event(&mut self, event: &InputCaptureEvent) {
+        let count = match event.api {
+            InputApi::Hook => &mut self.hooks,
+            InputApi::KeyState => &mut self.key_state_polls,
+            InputApi::RawInput => &mut self.raw_input_registrations,
+            InputApi::Clipboard => &mut self.clipboard_reads,
+        };
+        *count += 1;
+    }
+
+    /// Of the keyboard APIs.
+    pub fn keyboard_capture(&self) -> usize {
+        self.hooks + self.key_state_polls + self.raw_input_registrations
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keyboard_capture() == 0 && self.clipboard_reads == 0
+    }
+}
+
+impl fmt::Display for InputCaptureActivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hooks, {} key state polls, {} raw input registrations, {} clipboard reads",
+            self.hooks, self.key_state_polls, self.raw_input_registrations, self.clipboard_reads
+        )
+    }
+}
+
+/// Events queued by the session.
+#[derive(Debug, Default)]
+pub struct InputCaptureFeed {
+    pending: Mutex<Vec<InputCaptureEvent>>,
+}
+
+impl InputCaptureFeed {
+    pub fn push(&self, event: InputCaptureEvent) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() < MAX_PENDING {
+            pending.push(event);
+        }
+    }
+
+    pub fn take(&self) -> Vec<InputCaptureEvent> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+/// The ETW session [SESSION_NAME], stopped on drop.
+pub struct InputCapture {
+    feed: Arc<InputCaptureFeed>,
+    #[cfg(windows)]
+    session: session::Session,
+}
+
+impl InputCapture {
+    /// None without *INPUT_CAPTURE*, or if the session cannot be started.
+    #[cfg(windows)]
+    pub fn from(config: &Config) -> Option<InputCapture> {
+        if !config.get_bool(Param::InputCapture) {
+            return None;
+        }
+        let feed = Arc::new(InputCaptureFeed::default());
+        match session::Session::start(SESSION_NAME) {
+            Ok(session) => {
+                let consumer = session.consumer();
+                let thread_feed = feed.clone();
+                let spawned = thread::Builder::new().name(String::from("input-capture")).spawn(move || consumer.process(thread_feed));
+                if let Err(e) = spawned {
+                    warn!("Cannot start the consumer of the ETW session {}: {}", SESSION_NAME, e);
+                    session.stop();
+                    return None;
+                }
+                info!("Tracing the keyboard and clipboard APIs");
+                Some(InputCapture { feed, session })
+            }
+            Err(e) => {
+                warn!("Cannot start the ETW session {}: {}", SESSION_NAME, e);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(windows))]
+    pub fn from(_config: &Config) -> Option<InputCapture> {
+        None
+    }
+
+    /// Attributes the events queued to the gids of *procs*.
+    pub fn attribute(&self, procs: &mut Procs) {
+        let events = self.feed.take();
+        if events.is_empty() {
+            return;
+        }
+        let gids = procs.gids_by_pid();
+        for event in &events {
+            let index = gids.get(&event.pid).and_then(|gid| procs.get_by_gid_index(*gid));
+            if let Some(index) = index {
+                procs.procs[index].input_capture.on_event(event);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for InputCapture {
+    fn drop(&mut self) {
+        // ProcessTrace returns once the session is stopped
+        self.session.stop();
+    }
+}
+
+#[cfg(windows)]
+mod session {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::ffi::c_void;
+    use std::mem;
+    use std::ptr;
+    use std::sync::Arc;
+
+    use bindings::Windows::Win32::Foundation::PWSTR;
+    use bindings::Windows::Win32::System::Diagnostics::Etw::{
+        CloseTrace, ControlTraceW, EnableTraceEx2, OpenTraceW, ProcessTrace, StartTraceW, TdhGetEventInformation,
+        EVENT_CONTROL_CODE_ENABLE_PROVIDER, EVENT_RECORD, EVENT_TRACE_CONTROL_STOP, EVENT_TRACE_LOGFILEW,
+        EVENT_TRACE_PROPERTIES, EVENT_TRACE_REAL_TIME_MODE, PROCESS_TRACE_MODE_EVENT_RECORD,
+        PROCESS_TRACE_MODE_REAL_TIME, TRACE_EVENT_INFO, WNODE_FLAG_TRACED_GUID,
+    };
+    use windows::Guid;
+
+    use crate::inputcapture::{InputApi, InputCaptureEvent, InputCaptureFeed};
+
+    /// *Microsoft-Windows-Win32k*.
+    const WIN32K: Guid = Guid::from_values(0x8c416c79, 0xd49b, 0x4f01, [0xa4, 0x67, 0xe5, 0x6d, 0x3a, 0xa8, 0x23, 0x4c]);
+    /// *AuditApiCalls* keyword of [WIN32K].
+    const AUDIT_API_CALLS: u64 = 0x400;
+    const TRACE_LEVEL_INFORMATION: u8 = 4;
+    const ERROR_ALREADY_EXISTS: u32 = 183;
+    const ERROR_INSUFFICIENT_BUFFER: u32 = 122;
+    const INVALID_PROCESSTRACE_HANDLE: u64 = u64::MAX;
+
+    #[derive(Debug)]
+    pub struct Session {
+        name: Vec<u16>,
+    }
+
+    /// The consumer of a [Session], run in its own thread.
+    pub struct Consumer {
+        name: Vec<u16>,
+    }
+
+    /// Of the callback: the event names are only looked up once per event id.
+    struct Context {
+        feed: Arc<InputCaptureFeed>,
+        apis: RefCell<HashMap<u16, Option<InputApi>>>,
+    }
+
+    impl Session {
+        /// Starts the real-time session *name* and enables [WIN32K] in it. A session of the same name
+        /// left by a previous run is stopped first.
+        pub fn start(name: &str) -> Result<Session, windows::Error> {
+            let name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+            let mut handle = 0u64;
+            let mut status = unsafe { StartTraceW(&mut handle, PWSTR(name.as_ptr() as *mut u16), properties(&name).as_mut_ptr() as *mut EVENT_TRACE_PROPERTIES) };
+            if status == ERROR_ALREADY_EXISTS {
+                control_stop(&name);
+                status = unsafe { StartTraceW(&mut handle, PWSTR(name.as_ptr() as *mut u16), properties(&name).as_mut_ptr() as *mut EVENT_TRACE_PROPERTIES) };
+            }
+            check(status)?;
+            let session = Session { name };
+            let status = unsafe {
+                EnableTraceEx2(
+                    handle,
+                    &WIN32K,
+                    EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+                    TRACE_LEVEL_INFORMATION,
+                    AUDIT_API_CALLS,
+                    0,
+                    0,
+                    ptr::null(),
+                )
+            };
+            if let Err(e) = check(status) {
+                session.stop();
+                return Err(e);
+            }
+            Ok(session)
+        }
+
+        pub fn consumer(&self) -> Consumer {
+            Consumer { name: self.name.clone() }
+        }
+
+        pub fn stop(&self) {
+            control_stop(&self.name);
+        }
+    }
+
+    impl Consumer {
+        /// Blocks until the session is stopped.
+        pub fn process(mut self, feed: Arc<InputCaptureFeed>) {
+            let context = Box::new(Context {
+                feed,
+                apis: RefCell::new(HashMap::new()),
+            });
+            let mut logfile: EVENT_TRACE_LOGFILEW = unsafe { mem::zeroed() };
+            logfile.LoggerName = PWSTR(self.name.as_mut_ptr());
+            logfile.Anonymous1.ProcessTraceMode = PROCESS_TRACE_MODE_REAL_TIME | PROCESS_TRACE_MODE_EVENT_RECORD;
+            logfile.Anonymous2.EventRecordCallback = Some(on_event);
+            logfile.Context = &*context as *const Context as *mut c_void;
+            unsafe {
+                let trace = OpenTraceW(&mut logfile);
+                if trace == INVALID_PROCESSTRACE_HANDLE {
+                    return;
+                }
+                ProcessTrace(&trace, 1, ptr::null(), ptr::null());
+                CloseTrace(trace);
+            }
+        }
+    }
+
+    fn check(status: u32) -> Result<(), windows::Error> {
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(windows::HRESULT::from_win32(status).into())
+        }
+    }
+
+    /// An *EVENT_TRACE_PROPERTIES* followed by room for the session name, as a buffer of u64 for
+    /// its alignment.
+    fn properties(name: &[u16]) -> Vec<u64> {
+        let size = mem::size_of::<EVENT_TRACE_PROPERTIES>() + name.len() * 2;
+        let mut buffer = vec![0u64; (size + 7) / 8];
+        let properties = unsafe { &mut *(buffer.as_mut_ptr() as *mut EVENT_TRACE_PROPERTIES) };
+        properties.Wnode.BufferSize = size as u32;
+        properties.Wnode.Flags = WNODE_FLAG_TRACED_GUID;
+        // QueryPerformanceCounter
+        properties.Wnode.ClientContext = 1;
+        properties.LogFileMode = EVENT_TRACE_REAL_TIME_MODE;
+        properties.LoggerNameOffset = mem::size_of::<EVENT_TRACE_PROPERTIES>() as u32;
+        buffer
+    }
+
+    fn control_stop(name: &[u16]) {
+        let mut properties = properties(name);
+        unsafe {
+            ControlTraceW(
+                0,
+                PWSTR(name.as_ptr() as *mut u16),
+                properties.as_mut_ptr() as *mut EVENT_TRACE_PROPERTIES,
+                EVENT_TRACE_CONTROL_STOP,
+            );
+        }
+    }
+
+    unsafe extern "system" fn on_event(record: *mut EVENT_RECORD) {
+        let record = &*record;
+        let context = &*(record.UserContext as *const Context);
+        let id = record.EventHeader.EventDescriptor.Id;
+        let api = *context.apis.borrow_mut().entry(id).or_insert_with(|| names(record).and_then(|names| InputApi::of(&names)));
+        if let Some(api) = api {
+            context.feed.push(InputCaptureEvent {
+                pid: record.EventHeader.ProcessId,
+                api,
+            });
+        }
+    }
+
+    /// The task and event names of *record*, from the manifest of its provider.
+    unsafe fn names(record: &EVENT_RECORD) -> Option<String> {
+        let mut size = 0u32;
+        let status = TdhGetEventInformation(record, 0, ptr::null(), ptr::null_mut(), &mut size);
+        if status != ERROR_INSUFFICIENT_BUFFER {
+            return None;
+        }
+        let mut buffer = vec![0u64; (size as usize + 7) / 8];
+        let info = buffer.as_mut_ptr() as *mut TRACE_EVENT_INFO;
+        if TdhGetEventInformation(record, 0, ptr::null(), info, &mut size) != 0 {
+            return None;
+        }
+        let bytes = std::slice::from_raw_parts(buffer.as_ptr() as *const u8, size as usize);
+        let name = |offset: u32| -> String {
+            let offset = offset as usize;
+            if offset == 0 || offset >= bytes.len() {
+                return String::new();
+            }
+            let chars: Vec<u16> = bytes[offset..].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|c| *c != 0).collect();
+            String::from_utf16_lossy(&chars)
+        };
+        Some(format!("{} {}", name((*info).TaskNameOffset), name((*info).Anonymous1.EventNameOffset)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inputcapture::{InputApi, InputCaptureActivity, InputCaptureEvent};
+
+    #[test]
+    fn keyboard_and_clipboard_calls_should_be_counted() {
+        assert_eq!(InputApi::of("SetWindowsHookEx "), Some(InputApi::Hook));
+        assert_eq!(InputApi::of("Win32kApiCall GetAsyncKeyState"), Some(InputApi::KeyState));
+        assert_eq!(InputApi::of(" RegisterRawInputDevices"), Some(InputApi::RawInput));
+        assert_eq!(InputApi::of("GetClipboardData "), Some(InputApi::Clipboard));
+        assert_eq!(InputApi::of("FocusedWindowChange "), None);
+
+        let mut activity = InputCaptureActivity::default();
+        assert!(activity.is_empty());
+        for api in [InputApi::Hook, InputApi::KeyState, InputApi::KeyState, InputApi::Clipboard] {
+            activity.on_event(&InputCaptureEvent { pid: 4242, api });
+        }
+        assert_eq!((activity.keyboard_capture(), activity.clipboard_reads), (3, 1));
+        assert_eq!(activity.to_string(), "1 hooks, 2 key state polls, 0 raw input registrations, 1 clipboard reads");
+    }
+}
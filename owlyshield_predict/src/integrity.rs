@@ -0,0 +1,401 @@
+//! Integrity of the processes: process hollowing and parent pid spoofing (Windows).
+//!
+//! The image of the root of a gid is compared with its executable when the gid is first seen
+//! ([Integrity::of]): the file mapped at the image base of its PEB, the image path of its process
+//! parameters and its PE header in memory must all match the executable. A hollowed process (a
+//! process created suspended, whose image was replaced by another one) fails at least one of them.
+//!
+//! The minifilter reports the processes created with another parent than their creator (the
+//! *PROC_THREAD_ATTRIBUTE_PARENT_PROCESS* attribute of *CreateProcess*), see
+//! [crate::iosource::IoEventSource::spoofed_parents]. [SpoofedParents] attributes them to their
+//! gid, except those created by the services of the system creating processes on behalf of others
+//! (elevations by *AppInfo*, *runas* by *seclogon*).
+//!
+//! Both are features of the model (*image_mismatch* and *parent_spoofed*), and pull the score
+//! towards 1 by *INTEGRITY_WEIGHT* (see [crate::process]).
+
+use std::fmt;
+#[cfg(any(windows, test))]
+use std::fs::File;
+#[cfg(any(windows, test))]
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::driver_com::shared_def::SpoofedParent;
+use crate::iosource::IoEventSource;
+use crate::os;
+use crate::process::procs::Procs;
+
+/// Bytes read at the start of an image for its PE header.
+#[cfg_attr(not(windows), allow(dead_code))]
+const HEADER_LEN: usize = 4096;
+/// Spoofed parents waiting for the gid of their process, at most.
+const MAX_PENDING: usize = 256;
+/// Beyond, a spoofed parent whose process did not show up is forgotten.
+const PENDING_TTL: Duration = Duration::from_secs(60);
+/// Pid of the *System* process, which creates the minimal processes (*Registry*...).
+const SYSTEM_PID: u32 = 4;
+
+/// How the image of a process differs from its executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// No file is mapped at the image base: the image was replaced by private memory
+    UnmappedImage,
+    /// Another file is mapped at the image base
+    MappedFile,
+    /// The image path of the process parameters is another file
+    ImagePath,
+    /// The PE header in memory is not the one of the executable
+    Header,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Mismatch::UnmappedImage => "no file mapped at the image base",
+            Mismatch::MappedFile => "another file mapped at the image base",
+            Mismatch::ImagePath => "another image path in the PEB",
+            Mismatch::Header => "another PE header in memory",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The fields of a PE header which the loader does not change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeHeader {
+    pub time_date_stamp: u32,
+    pub entry_point: u32,
+    pub size_of_image: u32,
+}
+
+impl PeHeader {
+    /// Parses the start of an image, in memory or on disk (the offsets are the same for PE32 and
+    /// PE32+).
+    pub fn parse(bytes: &[u8]) -> Option<PeHeader> {
+        let u32_at = |offset: usize| -> Option<u32> {
+            bytes.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        if bytes.get(..2)? != b"MZ" {
+            return None;
+        }
+        let pe = u32_at(0x3C)? as usize;
+        if bytes.get(pe..pe + 4)? != b"PE\0\0" {
+            return None;
+        }
+        let optional = pe + 24;
+        Some(PeHeader {
+            time_date_stamp: u32_at(pe + 8)?,
+            entry_point: u32_at(optional + 16)?,
+            size_of_image: u32_at(optional + 56)?,
+        })
+    }
+}
+
+/// The image of a process, as seen in its memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryImage {
+    /// NT path of the file mapped at the image base, None if there is none
+    pub mapped_file: Option<String>,
+    /// *ImagePathName* of the process parameters
+    pub image_path: Option<String>,
+    pub header: Option<PeHeader>,
+}
+
+impl MemoryImage {
+    /// The first difference with the executable *exepath*, whose header is *on_disk*.
+    pub fn mismatch(&self, exepath: &Path, on_disk: Option<PeHeader>) -> Option<Mismatch> {
+        let exepath = exepath.to_string_lossy();
+        match &self.mapped_file {
+            None => return Some(Mismatch::UnmappedImage),
+            Some(mapped) if !base_name(mapped).eq_ignore_ascii_case(base_name(&exepath)) => {
+                return Some(Mismatch::MappedFile)
+            }
+            _ => {}
+        }
+        if self.image_path.as_deref().is_some_and(|path| !strip_prefix(path).eq_ignore_ascii_case(strip_prefix(&exepath))) {
+            return Some(Mismatch::ImagePath);
+        }
+        match (self.header, on_disk) {
+            (Some(in_memory), Some(on_disk)) if in_memory != on_disk => Some(Mismatch::Header),
+            _ => None,
+        }
+    }
+}
+
+/// Integrity of a gid.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Integrity {
+    /// The image of the root of the gid is not its executable
+    pub image_mismatch: Option<Mismatch>,
+    /// Pid of the creator of a process of the gid, which gave it another parent
+    pub spoofed_by: Option<u32>,
+}
+
+impl Integrity {
+    /// Compares the image of *pid* with its executable *exepath*. A process whose memory cannot be
+    /// read (protected, exited) is not suspicious.
+    #[cfg(windows)]
+    pub fn of(pid: u32, exepath: &Path) -> Integrity {
+        let image_mismatch = ffi::memory_image(pid).and_then(|image| image.mismatch(exepath, file_header(exepath)));
+        if let Some(mismatch) = image_mismatch {
+            warn!(pid, exepath = %exepath.display(), %mismatch, "Image of the process differs from its executable");
+        }
+        Integrity {
+            image_mismatch,
+            spoofed_by: None,
+        }
+    }
+
+    /// Hollowing is a Windows technique.
+    #[cfg(not(windows))]
+    pub fn of(_pid: u32, _exepath: &Path) -> Integrity {
+        Integrity::default()
+    }
+
+    pub fn is_suspicious(&self) -> bool {
+        self.image_mismatch.is_some() || self.spoofed_by.is_some()
+    }
+}
+
+/// The spoofed parents reported by the source, until the gids of their processes are known.
+#[derive(Debug)]
+pub struct SpoofedParents {
+    pending: Vec<(SpoofedParent, Instant)>,
+    system_root: PathBuf,
+}
+
+impl SpoofedParents {
+    pub fn new() -> SpoofedParents {
+        SpoofedParents {
+            pending: Vec::new(),
+            system_root: std::env::var_os("SystemRoot").map_or_else(|| PathBuf::from(r"C:\Windows"), PathBuf::from),
+        }
+    }
+
+    /// Takes the spoofed parents reported by *source* and marks the gids of *procs* their
+    /// processes belong to. The others are kept for [PENDING_TTL].
+    pub fn attribute(&mut self, source: &dyn IoEventSource, procs: &mut Procs) {
+        for spoofed in source.spoofed_parents() {
+            let creator = os::exepath_from_pid(spoofed.creator_pid);
+            if is_broker(spoofed.creator_pid, creator.as_deref(), &self.system_root) {
+                continue;
+            }
+            warn!(
+                pid = spoofed.pid,
+                parent_pid = spoofed.parent_pid,
+                creator_pid = spoofed.creator_pid,
+                creator = %creator.as_ref().map_or(String::from("unknown"), |p| p.display().to_string()),
+                "Process created with a spoofed parent"
+            );
+            if self.pending.len() == MAX_PENDING {
+                self.pending.remove(0);
+            }
+            self.pending.push((spoofed, Instant::now()));
+        }
+        if self.pending.is_empty() {
+            return;
+        }
+        let gids = procs.gids_by_pid();
+        self.pending.retain(|(spoofed, time)| {
+            match gids.get(&spoofed.pid).and_then(|gid| procs.get_by_gid_index(*gid)) {
+                Some(index) => {
+                    procs.procs[index].integrity.spoofed_by = Some(spoofed.creator_pid);
+                    false
+                }
+                None => time.elapsed() < PENDING_TTL,
+            }
+        });
+    }
+}
+
+impl Default for SpoofedParents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The processes of the system which create processes on behalf of another one, set as their
+/// parent: *System*, and *svchost.exe* for *AppInfo* (elevations) and *seclogon* (*runas*).
+fn is_broker(creator_pid: u32, creator: Option<&Path>, system_root: &Path) -> bool {
+    let svchost = format!(r"{}\System32\svchost.exe", system_root.display());
+    creator_pid == SYSTEM_PID || creator.is_some_and(|creator| creator.to_string_lossy().eq_ignore_ascii_case(&svchost))
+}
+
+/// The PE header of the executable *path*.
+#[cfg(any(windows, test))]
+fn file_header(path: &Path) -> Option<PeHeader> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    File::open(path).ok()?.take(HEADER_LEN as u64).read_to_end(&mut bytes).ok()?;
+    PeHeader::parse(&bytes)
+}
+
+fn base_name(path: &str) -> &str {
+    path.rsplit(['\\', '/']).next().unwrap_or(path)
+}
+
+fn strip_prefix(path: &str) -> &str {
+    path.strip_prefix(r"\\?\").unwrap_or(path)
+}
+
+#[cfg(windows)]
+mod ffi {
+    use std::ffi::c_void;
+    use std::mem::size_of;
+    use std::ptr;
+
+    use bindings::Windows::Win32::Foundation::{CloseHandle, HANDLE};
+    use bindings::Windows::Win32::System::Diagnostics::Debug::ReadProcessMemory;
+    use bindings::Windows::Win32::System::Threading::{OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ};
+
+    use crate::integrity::{MemoryImage, PeHeader, HEADER_LEN};
+
+    const PROCESS_BASIC_INFORMATION: u32 = 0;
+    /// Offsets in the PEB and the *RTL_USER_PROCESS_PARAMETERS* of a 64 bits process (the native
+    /// ones of a WOW64 process).
+    const PEB_IMAGE_BASE: usize = 0x10;
+    const PEB_PROCESS_PARAMETERS: usize = 0x20;
+    const PARAMETERS_IMAGE_PATH: usize = 0x60;
+    const MAX_PATH_LEN: usize = 32_767;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ProcessBasicInformation {
+        exit_status: i32,
+        peb_base_address: usize,
+        affinity_mask: usize,
+        base_priority: i32,
+        unique_process_id: usize,
+        inherited_from_unique_process_id: usize,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct UnicodeString {
+        length: u16,
+        maximum_length: u16,
+        buffer: usize,
+    }
+
+    #[link(name = "ntdll")]
+    extern "system" {
+        fn NtQueryInformationProcess(process: isize, class: u32, info: *mut c_void, len: u32, return_len: *mut u32) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn K32GetMappedFileNameW(process: isize, address: *const c_void, filename: *mut u16, size: u32) -> u32;
+    }
+
+    /// The image of *pid*, None if its memory cannot be read.
+    pub fn memory_image(pid: u32) -> Option<MemoryImage> {
+        unsafe {
+            let handle = OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid);
+            if handle.is_invalid() || handle.0 == 0 {
+                return None;
+            }
+            let image = read_image(handle);
+            CloseHandle(handle);
+            image
+        }
+    }
+
+    unsafe fn read_image(handle: HANDLE) -> Option<MemoryImage> {
+        let mut info = ProcessBasicInformation::default();
+        let mut len = 0u32;
+        let status = NtQueryInformationProcess(
+            handle.0,
+            PROCESS_BASIC_INFORMATION,
+            ptr::addr_of_mut!(info) as *mut c_void,
+            size_of::<ProcessBasicInformation>() as u32,
+            &mut len,
+        );
+        if status < 0 {
+            return None;
+        }
+        let image_base: usize = read(handle, info.peb_base_address + PEB_IMAGE_BASE)?;
+        let parameters: usize = read(handle, info.peb_base_address + PEB_PROCESS_PARAMETERS)?;
+        let image_path: UnicodeString = read(handle, parameters + PARAMETERS_IMAGE_PATH)?;
+
+        let mut path = vec![0u16; image_path.length as usize / 2];
+        let image_path = read_into(handle, image_path.buffer, &mut path).then(|| String::from_utf16_lossy(&path));
+        let mut header = vec![0u8; HEADER_LEN];
+        let header = if read_into(handle, image_base, &mut header) { PeHeader::parse(&header) } else { None };
+        let mut mapped = vec![0u16; MAX_PATH_LEN];
+        let len = K32GetMappedFileNameW(handle.0, image_base as *const c_void, mapped.as_mut_ptr(), mapped.len() as u32);
+        let mapped_file = (len > 0).then(|| String::from_utf16_lossy(&mapped[..len as usize]));
+        Some(MemoryImage {
+            mapped_file,
+            image_path,
+            header,
+        })
+    }
+
+    unsafe fn read<T: Default>(handle: HANDLE, address: usize) -> Option<T> {
+        let mut value = T::default();
+        let mut read = 0usize;
+        let ok = ReadProcessMemory(handle, address as *const c_void, ptr::addr_of_mut!(value) as *mut c_void, size_of::<T>(), &mut read);
+        (ok.as_bool() && read == size_of::<T>()).then(|| value)
+    }
+
+    unsafe fn read_into<T: Copy>(handle: HANDLE, address: usize, buffer: &mut [T]) -> bool {
+        let len = buffer.len() * size_of::<T>();
+        let mut read = 0usize;
+        let ok = ReadProcessMemory(handle, address as *const c_void, buffer.as_mut_ptr() as *mut c_void, len, &mut read);
+        ok.as_bool() && read == len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::Path;
+
+    use crate::integrity::{file_header, is_broker, MemoryImage, Mismatch, PeHeader};
+
+    #[test]
+    fn image_mismatches_and_spoofed_parents_should_be_found() {
+        let mut image = vec![0u8; 512];
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3C..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        image[0x88..0x8C].copy_from_slice(&0x6523_1A00u32.to_le_bytes());
+        image[0xA8..0xAC].copy_from_slice(&0x1_4F20u32.to_le_bytes());
+        image[0xD0..0xD4].copy_from_slice(&0x2_C000u32.to_le_bytes());
+        let header = PeHeader::parse(&image).unwrap();
+        assert_eq!((header.time_date_stamp, header.entry_point, header.size_of_image), (0x6523_1A00, 0x1_4F20, 0x2_C000));
+        assert_eq!(PeHeader::parse(&image[..0x84]), None);
+        assert_eq!(PeHeader::parse(b"#!/bin/sh\n"), None);
+
+        let file = std::env::temp_dir().join(format!("owlyshield_integrity_{}.exe", std::process::id()));
+        std::fs::File::create(&file).unwrap().write_all(&image).unwrap();
+        assert_eq!(file_header(&file), Some(header));
+        std::fs::remove_file(&file).unwrap();
+
+        let exepath = Path::new(r"C:\Windows\System32\svchost.exe");
+        let genuine = MemoryImage {
+            mapped_file: Some(String::from(r"\Device\HarddiskVolume3\Windows\System32\SVCHOST.EXE")),
+            image_path: Some(String::from(r"C:\WINDOWS\system32\svchost.exe")),
+            header: Some(header),
+        };
+        assert_eq!(genuine.mismatch(exepath, Some(header)), None);
+        let hollowed = MemoryImage { mapped_file: None, ..genuine.clone() };
+        assert_eq!(hollowed.mismatch(exepath, Some(header)), Some(Mismatch::UnmappedImage));
+        let doppelganged = MemoryImage { mapped_file: Some(String::from(r"\Device\HarddiskVolume3\Users\bob\AppData\Local\Temp\tx.tmp")), ..genuine.clone() };
+        assert_eq!(doppelganged.mismatch(exepath, Some(header)), Some(Mismatch::MappedFile));
+        let masqueraded = MemoryImage { image_path: Some(String::from(r"C:\Windows\explorer.exe")), ..genuine.clone() };
+        assert_eq!(masqueraded.mismatch(exepath, Some(header)), Some(Mismatch::ImagePath));
+        let other = PeHeader { entry_point: 0x1000, ..header };
+        assert_eq!(genuine.mismatch(exepath, Some(other)), Some(Mismatch::Header));
+        assert_eq!(genuine.mismatch(exepath, None), None);
+
+        let root = Path::new(r"C:\Windows");
+        assert!(is_broker(1204, Some(Path::new(r"C:\WINDOWS\system32\svchost.exe")), root));
+        assert!(is_broker(4, None, root));
+        assert!(!is_broker(5120, Some(Path::new(r"C:\Users\bob\Downloads\svchost.exe")), root));
+        assert!(!is_broker(5120, None, root));
+    }
+}
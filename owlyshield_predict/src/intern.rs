@@ -0,0 +1,125 @@
+//! Interning of the file paths of a gid, to reduce the allocations of [ProcessRecord].
+//!
+//! Each driver message carries the path of its file as a new String. A [ProcessRecord] stores
+//! it in several sets (paths updated, files by size...), with its directory. With a
+//! [PathInterner], the same file touched many times (a ransomware reads, writes and renames each
+//! file) shares a single allocation, found by its file id, and so do the files of a directory.
+//!
+//! [ProcessRecord]: crate::process::ProcessRecord
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::driver_com::shared_def::IOMessage;
+
+/// Counters of all the interners, see [stats].
+static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+static HITS: AtomicUsize = AtomicUsize::new(0);
+static BYTES_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static BYTES_SAVED: AtomicUsize = AtomicUsize::new(0);
+
+/// Memory statistics of the interners since the start of the agent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InternerStats {
+    pub lookups: usize,
+    pub hits: usize,
+    /// Bytes of the strings allocated by the interners
+    pub bytes_allocated: usize,
+    /// Bytes which would have been allocated without interning
+    pub bytes_saved: usize,
+}
+
+pub fn stats() -> InternerStats {
+    InternerStats {
+        lookups: LOOKUPS.load(Ordering::Relaxed),
+        hits: HITS.load(Ordering::Relaxed),
+        bytes_allocated: BYTES_ALLOCATED.load(Ordering::Relaxed),
+        bytes_saved: BYTES_SAVED.load(Ordering::Relaxed),
+    }
+}
+
+/// Paths of a gid, by file id, and directories. At most *cap* entries of each are kept, then
+/// the interner starts over (the strings already shared stay valid).
+#[derive(Debug)]
+pub struct PathInterner {
+    files: HashMap<(u64, [u8; 16]), Arc<str>>,
+    dirs: HashSet<Arc<str>>,
+    cap: usize,
+}
+
+impl PathInterner {
+    pub fn new(cap: usize) -> PathInterner {
+        PathInterner {
+            files: HashMap::new(),
+            dirs: HashSet::new(),
+            cap,
+        }
+    }
+
+    /// The path of the file of *iomsg*. A renamed file gets a new string.
+    pub fn file(&mut self, iomsg: &IOMessage) -> Arc<str> {
+        let key = (iomsg.file_id_vsn, iomsg.file_id_id);
+        if let Some(path) = self.files.get(&key) {
+            if **path == *iomsg.filepathstr {
+                return hit(path);
+            }
+        }
+        if self.files.len() >= self.cap {
+            self.files.clear();
+        }
+        let path = alloc(&iomsg.filepathstr);
+        self.files.insert(key, Arc::clone(&path));
+        path
+    }
+
+    /// The parent directory of *fpath* (```.\``` if none).
+    pub fn dir(&mut self, fpath: &str) -> Arc<str> {
+        let dir = Path::new(fpath)
+            .parent()
+            .unwrap_or_else(|| Path::new(r".\"))
+            .to_string_lossy();
+        if let Some(dir) = self.dirs.get(&*dir) {
+            return hit(dir);
+        }
+        if self.dirs.len() >= self.cap {
+            self.dirs.clear();
+        }
+        let dir = alloc(&dir);
+        self.dirs.insert(Arc::clone(&dir));
+        dir
+    }
+}
+
+fn hit(s: &Arc<str>) -> Arc<str> {
+    LOOKUPS.fetch_add(1, Ordering::Relaxed);
+    HITS.fetch_add(1, Ordering::Relaxed);
+    BYTES_SAVED.fetch_add(s.len(), Ordering::Relaxed);
+    Arc::clone(s)
+}
+
+fn alloc(s: &str) -> Arc<str> {
+    LOOKUPS.fetch_add(1, Ordering::Relaxed);
+    BYTES_ALLOCATED.fetch_add(s.len(), Ordering::Relaxed);
+    Arc::from(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::intern::PathInterner;
+
+    #[test]
+    fn dirs_should_share_allocation() {
+        let mut paths = PathInterner::new(2);
+        let a = paths.dir(r"C:\Users\doc\a.txt");
+        let b = paths.dir(r"C:\Users\doc\b.txt");
+        assert_eq!(&*a, r"C:\Users\doc");
+        assert!(Arc::ptr_eq(&a, &b));
+        paths.dir(r"C:\Users\img\c.png");
+        paths.dir(r"C:\d.txt");
+        assert!(!Arc::ptr_eq(&a, &paths.dir(r"C:\Users\doc\e.txt")));
+    }
+}
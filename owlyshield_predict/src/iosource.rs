@@ -0,0 +1,92 @@
+//! Platform abstraction of the file i/o events fed to the [crate::pipeline].
+//!
+//! An [IoEventSource] yields [IOMessage]s, whatever the platform, and kills process families:
+//! * on Windows, the minifilter ([crate::driver_com::Driver]), whose gids are maintained by the
+//!   driver;
+//! * on Linux, fanotify ([crate::fanotify::FanotifySource]), whose gids are the process groups,
+//!   completed by eBPF ([crate::ebpf::EbpfSource]) if available.
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::thread;
+use std::time::Duration;
+
+use crate::backpressure::Aggregates;
+use crate::capture::CaptureRequest;
+use crate::config::Config;
+use crate::connectors::connector::Connectors;
+use crate::driver_com::shared_def::{IOMessage, SpoofedParent};
+
+#[derive(Debug)]
+pub enum IoSourceError {
+    /// The source cannot be opened (driver not started, missing privileges...).
+    Open(String),
+    /// The source does not deliver events anymore.
+    Closed,
+    /// The events could not be received, with the OS error code.
+    Receive(i32),
+    /// The processes of a gid could not be killed, with the OS error code.
+    Kill(i32),
+    /// The source could not mute a gid, with the OS error code.
+    Mute(i32),
+    /// The source could not start or stop the capture of a gid, with the OS error code.
+    Capture(i32),
+}
+
+impl Display for IoSourceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            IoSourceError::Open(details) => write!(f, "Cannot open the i/o events source: {}", details),
+            IoSourceError::Closed => write!(f, "The i/o events source is closed"),
+            IoSourceError::Receive(code) => write!(f, "Cannot receive the i/o events: error {}", code),
+            IoSourceError::Kill(code) => write!(f, "Cannot kill the process family: error {}", code),
+            IoSourceError::Mute(code) => write!(f, "Cannot mute the process family: error {}", code),
+            IoSourceError::Capture(code) => write!(f, "Cannot set the capture of the process family: error {}", code),
+        }
+    }
+}
+
+impl Error for IoSourceError {}
+
+/// Shared by the fetch thread and the workers of the pipeline.
+pub trait IoEventSource: Sync {
+    /// Appends the pending events to *events*, none if there is no new activity.
+    fn fetch(&self, events: &mut Vec<IOMessage>) -> Result<(), IoSourceError>;
+    /// Kills all the processes of the family *gid*.
+    fn kill_gid(&self, gid: u64) -> Result<(), IoSourceError>;
+    /// Stops the events of the benign family *gid* at the source, until it starts a child or
+    /// loads an image outside of *SystemRoot*. Only the minifilter filters them.
+    fn mute_gid(&self, _gid: u64) -> Result<(), IoSourceError> {
+        Ok(())
+    }
+    /// Reports the tamper attempts blocked since the last call to the *connectors*. Only the
+    /// minifilter protects the agent.
+    fn report_tamper_attempts(&self, _config: &Config, _connectors: &Connectors) {}
+    /// Tells the source that *queued* events wait to be processed, and whether it should
+    /// *aggregate* them. Only the minifilter aggregates.
+    fn set_backpressure(&self, _aggregate: bool, _queued: usize) -> Result<(), IoSourceError> {
+        Ok(())
+    }
+    /// Takes the events aggregated since the last call.
+    fn aggregates(&self) -> Result<Aggregates, IoSourceError> {
+        Ok(Aggregates::default())
+    }
+    /// Takes the processes created with another parent than their creator since the last call.
+    /// Only the minifilter sees the creators.
+    fn spoofed_parents(&self) -> Vec<SpoofedParent> {
+        Vec::new()
+    }
+    /// Starts or stops the capture of the files of *gid* before they are written or deleted, see
+    /// [crate::capture]. Only the minifilter holds the operations.
+    fn capture_gid(&self, _gid: u64, _capture: bool) -> Result<(), IoSourceError> {
+        Ok(())
+    }
+    /// Waits *timeout* at most for a file to capture.
+    fn next_capture_request(&self, timeout: Duration) -> Option<CaptureRequest> {
+        thread::sleep(timeout);
+        None
+    }
+    /// Lets the write or the deletion of *request* through.
+    fn reply_capture(&self, _request: &CaptureRequest, _captured: bool) {}
+}
@@ -0,0 +1,584 @@
+//! Network isolation on a Critical alert, so that a ransomware cannot exfiltrate nor spread while
+//! it is being handled.
+//!
+//! With *NETWORK_ISOLATION*, when a gid is detected in the *PROTECT* mode, Windows Filtering
+//! Platform filters block the new outbound connections (ALE connect layers, IPv4 and IPv6):
+//! * *EXECUTABLE*: of the executable of the gid only;
+//! * *MACHINE*: of the whole machine, but the loopback, the agent itself, for its connectors,
+//!   and the DNS queries of the DNS client service (*svchost.exe*, port 53), for the name
+//!   resolution of the agent.
+//!
+//! The filters are in the sublayer of the Owlyshield provider: a block there is not overridden
+//! by the permits of the other sublayers (Windows Firewall...).
+//!
+//! The filters are not persistent (they are removed on reboot) and are recorded in
+//! *ConfigPath\isolation.json*, with their expiry, *ISOLATION_MINUTES* after the last alert. They
+//! are lifted when expired, checked every [CHECK_INTERVAL], or on demand with
+//! ```owlyshield_ransom isolation lift``` or ```POST /isolation/lift```.
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::config::{Config, Param};
+use crate::process::ProcessRecord;
+
+/// Period of the checks of the expiry.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Name of the state file, in *ConfigPath*.
+const STATE_FILE: &str = "isolation.json";
+
+/// The workers may isolate concurrently.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IsolationScope {
+    Executable,
+    Machine,
+}
+
+impl IsolationScope {
+    /// The scope of *NETWORK_ISOLATION*, None if *OFF*.
+    pub fn from(config: &Config) -> Option<IsolationScope> {
+        match config.get_str(Param::NetworkIsolation).as_str() {
+            "EXECUTABLE" => Some(IsolationScope::Executable),
+            "MACHINE" => Some(IsolationScope::Machine),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IsolationError {
+    Io(io::Error),
+    /// The filtering engine failed, with the error code.
+    Wfp(u32),
+    /// Windows only.
+    Unsupported,
+}
+
+impl Display for IsolationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            IsolationError::Io(e) => write!(f, "{}", e),
+            IsolationError::Wfp(code) => write!(f, "Windows Filtering Platform error {:#x}", code),
+            IsolationError::Unsupported => write!(f, "Network isolation is only supported on Windows"),
+        }
+    }
+}
+
+impl Error for IsolationError {}
+
+impl From<io::Error> for IsolationError {
+    fn from(e: io::Error) -> Self {
+        IsolationError::Io(e)
+    }
+}
+
+/// Filters added for an alert.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IsolationEntry {
+    pub scope: IsolationScope,
+    pub gid: u64,
+    /// Blocked executable, for the *EXECUTABLE* scope
+    pub exepath: Option<PathBuf>,
+    pub filter_ids: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IsolationState {
+    /// Seconds since the epoch
+    pub until: u64,
+    pub entries: Vec<IsolationEntry>,
+}
+
+impl IsolationState {
+    /// True if the filters of *scope* for *exepath* are already there.
+    pub fn covers(&self, scope: IsolationScope, exepath: &Path) -> bool {
+        self.entries.iter().any(|e| {
+            e.scope == IsolationScope::Machine || (scope == IsolationScope::Executable && e.exepath.as_deref() == Some(exepath))
+        })
+    }
+
+    /// Delays the expiry to *until*, if later.
+    pub fn extend(&mut self, until: u64) {
+        self.until = self.until.max(until);
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.until
+    }
+}
+
+/// Isolates the executable of *proc*, or the machine, according to *NETWORK_ISOLATION*. An
+/// isolation in place is extended.
+pub fn isolate(config: &Config, proc: &ProcessRecord) {
+    let scope = match IsolationScope::from(config) {
+        Some(scope) => scope,
+        None => return,
+    };
+    let _lock = LOCK.lock().unwrap();
+    let path = state_path(config);
+    let mut state = load(&path).unwrap_or_default();
+    let until = now() + config.get_usize(Param::IsolationMinutes) as u64 * 60;
+    if !state.covers(scope, &proc.exepath) {
+        let exepath = (scope == IsolationScope::Executable).then(|| proc.exepath.clone());
+        match add_filters(scope, exepath.as_deref()) {
+            Ok(filter_ids) => {
+                error!(gid = proc.gid, ?scope, exepath = %proc.exepath.display(), "Critical: network isolated");
+                state.entries.push(IsolationEntry {
+                    scope,
+                    gid: proc.gid,
+                    exepath,
+                    filter_ids,
+                });
+            }
+            Err(e) => {
+                error!(gid = proc.gid, ?scope, "Cannot isolate the network: {}", e);
+                return;
+            }
+        }
+    }
+    state.extend(until);
+    if let Err(e) = save(&path, &state) {
+        error!("Cannot save the network isolation in {}: {}", path.display(), e);
+    }
+}
+
+/// Removes all the filters. Returns the number of alerts whose isolation has been lifted.
+pub fn lift(config: &Config) -> Result<usize, IsolationError> {
+    let _lock = LOCK.lock().unwrap();
+    let path = state_path(config);
+    let state = match load(&path) {
+        Some(state) => state,
+        None => return Ok(0),
+    };
+    let filter_ids: Vec<u64> = state.entries.iter().flat_map(|e| e.filter_ids.iter().copied()).collect();
+    remove_filters(&filter_ids)?;
+    fs::remove_file(&path)?;
+    info!(alerts = state.entries.len(), "Network isolation lifted");
+    Ok(state.entries.len())
+}
+
+/// Lifts the isolation if it has expired.
+pub fn lift_if_expired(config: &Config) {
+    let expired = load(&state_path(config)).is_some_and(|state| state.is_expired(now()));
+    if expired {
+        if let Err(e) = lift(config) {
+            warn!("Cannot lift the expired network isolation: {}", e);
+        }
+    }
+}
+
+/// The isolation in place, if any.
+pub fn state(config: &Config) -> Option<IsolationState> {
+    load(&state_path(config))
+}
+
+fn state_path(config: &Config) -> PathBuf {
+    config.get_path(Param::ConfigPath).join(STATE_FILE)
+}
+
+fn load(path: &Path) -> Option<IsolationState> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+fn save(path: &Path, state: &IsolationState) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec_pretty(state)?)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(windows)]
+fn add_filters(scope: IsolationScope, exepath: Option<&Path>) -> Result<Vec<u64>, IsolationError> {
+    wfp::add_filters(scope, exepath).map_err(IsolationError::Wfp)
+}
+
+#[cfg(windows)]
+fn remove_filters(filter_ids: &[u64]) -> Result<(), IsolationError> {
+    wfp::remove_filters(filter_ids).map_err(IsolationError::Wfp)
+}
+
+#[cfg(not(windows))]
+fn add_filters(_scope: IsolationScope, _exepath: Option<&Path>) -> Result<Vec<u64>, IsolationError> {
+    Err(IsolationError::Unsupported)
+}
+
+#[cfg(not(windows))]
+fn remove_filters(filter_ids: &[u64]) -> Result<(), IsolationError> {
+    if filter_ids.is_empty() {
+        Ok(())
+    } else {
+        Err(IsolationError::Unsupported)
+    }
+}
+
+/// The subset of the Windows Filtering Platform management API (*fwpuclnt.dll*) used here.
+#[cfg(windows)]
+mod wfp {
+    use std::ffi::{c_void, OsString};
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    use crate::isolation::IsolationScope;
+
+    const RPC_C_AUTHN_DEFAULT: u32 = 0xFFFF_FFFF;
+    const FWP_EMPTY: u32 = 0;
+    const FWP_UINT8: u32 = 1;
+    const FWP_UINT16: u32 = 2;
+    const FWP_UINT32: u32 = 3;
+    const FWP_BYTE_BLOB_TYPE: u32 = 12;
+    const FWP_MATCH_EQUAL: u32 = 0;
+    const FWP_MATCH_FLAGS_ALL_SET: u32 = 6;
+    const FWP_ACTION_BLOCK: u32 = 0x1001;
+    const FWP_ACTION_PERMIT: u32 = 0x1002;
+    const FWP_CONDITION_FLAG_IS_LOOPBACK: u32 = 0x1;
+    /// FWP_E_ALREADY_EXISTS, the provider or sublayer added by a previous isolation
+    const ALREADY_EXISTS: u32 = 0x8032_0009;
+    const DNS_PORT: u16 = 53;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Guid(u32, u16, u16, [u8; 8]);
+
+    const FWPM_LAYER_ALE_AUTH_CONNECT_V4: Guid =
+        Guid(0xc38d57d1, 0x05a7, 0x4c33, [0x90, 0x4f, 0x7f, 0xbc, 0xee, 0xe6, 0x0e, 0x82]);
+    const FWPM_LAYER_ALE_AUTH_CONNECT_V6: Guid =
+        Guid(0x4a72393b, 0x319f, 0x44bc, [0x84, 0xc3, 0xba, 0x54, 0xdc, 0xb3, 0xb6, 0xb4]);
+    const FWPM_CONDITION_ALE_APP_ID: Guid =
+        Guid(0xd78e1e87, 0x8644, 0x4ea5, [0x94, 0x37, 0xd8, 0x09, 0xec, 0xef, 0xc9, 0x71]);
+    const FWPM_CONDITION_FLAGS: Guid =
+        Guid(0x632ce23b, 0x5167, 0x435c, [0x86, 0xd7, 0xe9, 0x03, 0x68, 0x4a, 0xa8, 0x0c]);
+    const FWPM_CONDITION_IP_REMOTE_PORT: Guid =
+        Guid(0xc35a604d, 0xd22b, 0x4e1a, [0x91, 0xb4, 0x68, 0xf6, 0x74, 0xee, 0x67, 0x4b]);
+    const NULL_GUID: Guid = Guid(0, 0, 0, [0; 8]);
+    /// The Owlyshield provider and its sublayer, holding the filters.
+    const PROVIDER: Guid = Guid(0xb5ef3f95, 0xa600, 0x423a, [0x90, 0x7d, 0xcc, 0xb8, 0xe1, 0x46, 0x9d, 0x66]);
+    const SUBLAYER: Guid = Guid(0x5a79fbbc, 0x16d7, 0x4168, [0xad, 0xc4, 0x2b, 0x1c, 0xd8, 0x02, 0x26, 0x4a]);
+    const SUBLAYER_WEIGHT: u16 = 0x8000;
+
+    #[repr(C)]
+    struct ByteBlob {
+        size: u32,
+        data: *mut u8,
+    }
+
+    /// *FWP_VALUE0* and *FWP_CONDITION_VALUE0*: the union is a pointer or a value up to 32 bits.
+    #[repr(C)]
+    struct Value {
+        r#type: u32,
+        value: usize,
+    }
+
+    #[repr(C)]
+    struct FilterCondition {
+        field_key: Guid,
+        match_type: u32,
+        condition_value: Value,
+    }
+
+    #[repr(C)]
+    struct DisplayData {
+        name: *const u16,
+        description: *const u16,
+    }
+
+    #[repr(C)]
+    struct Provider {
+        provider_key: Guid,
+        display_data: DisplayData,
+        flags: u32,
+        provider_data: ByteBlob,
+        service_name: *const u16,
+    }
+
+    #[repr(C)]
+    struct SubLayer {
+        sub_layer_key: Guid,
+        display_data: DisplayData,
+        flags: u32,
+        provider_key: *const Guid,
+        provider_data: ByteBlob,
+        weight: u16,
+    }
+
+    #[repr(C)]
+    struct Action {
+        r#type: u32,
+        filter_type: Guid,
+    }
+
+    #[repr(C)]
+    struct Filter {
+        filter_key: Guid,
+        display_data: DisplayData,
+        flags: u32,
+        provider_key: *const Guid,
+        provider_data: ByteBlob,
+        layer_key: Guid,
+        sub_layer_key: Guid,
+        weight: Value,
+        num_filter_conditions: u32,
+        filter_condition: *const FilterCondition,
+        action: Action,
+        provider_context: [u64; 2],
+        reserved: *const Guid,
+        filter_id: u64,
+        effective_weight: Value,
+    }
+
+    #[link(name = "fwpuclnt")]
+    extern "system" {
+        fn FwpmEngineOpen0(server: *const u16, authn: u32, identity: *const c_void, session: *const c_void, engine: *mut isize) -> u32;
+        fn FwpmEngineClose0(engine: isize) -> u32;
+        fn FwpmProviderAdd0(engine: isize, provider: *const Provider, sd: *const c_void) -> u32;
+        fn FwpmSubLayerAdd0(engine: isize, sub_layer: *const SubLayer, sd: *const c_void) -> u32;
+        fn FwpmFilterAdd0(engine: isize, filter: *const Filter, sd: *const c_void, id: *mut u64) -> u32;
+        fn FwpmFilterDeleteById0(engine: isize, id: u64) -> u32;
+        fn FwpmGetAppIdFromFileName0(file: *const u16, app_id: *mut *mut ByteBlob) -> u32;
+        fn FwpmFreeMemory0(p: *mut *mut c_void);
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+
+    struct Engine(isize);
+
+    impl Engine {
+        fn open() -> Result<Engine, u32> {
+            let mut handle = 0;
+            match unsafe { FwpmEngineOpen0(ptr::null(), RPC_C_AUTHN_DEFAULT, ptr::null(), ptr::null(), &mut handle) } {
+                0 => Ok(Engine(handle)),
+                code => Err(code),
+            }
+        }
+
+        /// Adds the Owlyshield provider and sublayer, if not there yet. Like the filters, they are
+        /// not persistent.
+        fn register(&self) -> Result<(), u32> {
+            let name = wide("Owlyshield");
+            let display_data = || DisplayData {
+                name: name.as_ptr(),
+                description: ptr::null(),
+            };
+            let empty = || ByteBlob {
+                size: 0,
+                data: ptr::null_mut(),
+            };
+            let provider = Provider {
+                provider_key: PROVIDER,
+                display_data: display_data(),
+                flags: 0,
+                provider_data: empty(),
+                service_name: ptr::null(),
+            };
+            match unsafe { FwpmProviderAdd0(self.0, &provider, ptr::null()) } {
+                0 | ALREADY_EXISTS => {}
+                code => return Err(code),
+            }
+            let sub_layer = SubLayer {
+                sub_layer_key: SUBLAYER,
+                display_data: display_data(),
+                flags: 0,
+                provider_key: &PROVIDER,
+                provider_data: empty(),
+                weight: SUBLAYER_WEIGHT,
+            };
+            match unsafe { FwpmSubLayerAdd0(self.0, &sub_layer, ptr::null()) } {
+                0 | ALREADY_EXISTS => Ok(()),
+                code => Err(code),
+            }
+        }
+
+        fn add(&self, layer: Guid, action: u32, weight: u8, conditions: &[FilterCondition]) -> Result<u64, u32> {
+            let name = wide("Owlyshield network isolation");
+            let filter = Filter {
+                filter_key: NULL_GUID,
+                display_data: DisplayData {
+                    name: name.as_ptr(),
+                    description: ptr::null(),
+                },
+                flags: 0,
+                provider_key: &PROVIDER,
+                provider_data: ByteBlob {
+                    size: 0,
+                    data: ptr::null_mut(),
+                },
+                layer_key: layer,
+                sub_layer_key: SUBLAYER,
+                weight: Value {
+                    r#type: FWP_UINT8,
+                    value: weight as usize,
+                },
+                num_filter_conditions: conditions.len() as u32,
+                filter_condition: if conditions.is_empty() { ptr::null() } else { conditions.as_ptr() },
+                action: Action {
+                    r#type: action,
+                    filter_type: NULL_GUID,
+                },
+                provider_context: [0; 2],
+                reserved: ptr::null(),
+                filter_id: 0,
+                effective_weight: Value {
+                    r#type: FWP_EMPTY,
+                    value: 0,
+                },
+            };
+            let mut id = 0;
+            match unsafe { FwpmFilterAdd0(self.0, &filter, ptr::null(), &mut id) } {
+                0 => Ok(id),
+                code => Err(code),
+            }
+        }
+    }
+
+    impl Drop for Engine {
+        fn drop(&mut self) {
+            unsafe {
+                FwpmEngineClose0(self.0);
+            }
+        }
+    }
+
+    /// The application id of *exepath*, freed when dropped.
+    struct AppId(*mut ByteBlob);
+
+    impl AppId {
+        fn of(exepath: &Path) -> Result<AppId, u32> {
+            let path: Vec<u16> = exepath.as_os_str().encode_wide().chain(Some(0)).collect();
+            let mut blob = ptr::null_mut();
+            match unsafe { FwpmGetAppIdFromFileName0(path.as_ptr(), &mut blob) } {
+                0 => Ok(AppId(blob)),
+                code => Err(code),
+            }
+        }
+
+        fn condition(&self) -> FilterCondition {
+            FilterCondition {
+                field_key: FWPM_CONDITION_ALE_APP_ID,
+                match_type: FWP_MATCH_EQUAL,
+                condition_value: Value {
+                    r#type: FWP_BYTE_BLOB_TYPE,
+                    value: self.0 as usize,
+                },
+            }
+        }
+    }
+
+    impl Drop for AppId {
+        fn drop(&mut self) {
+            unsafe {
+                FwpmFreeMemory0(&mut self.0 as *mut *mut ByteBlob as *mut *mut c_void);
+            }
+        }
+    }
+
+    /// Adds the filters of *scope*. On error, those already added are removed.
+    pub fn add_filters(scope: IsolationScope, exepath: Option<&Path>) -> Result<Vec<u64>, u32> {
+        let engine = Engine::open()?;
+        engine.register()?;
+        let mut ids = Vec::new();
+        let res = add_scope_filters(&engine, scope, exepath, &mut ids);
+        if let Err(code) = res {
+            for id in &ids {
+                unsafe {
+                    FwpmFilterDeleteById0(engine.0, *id);
+                }
+            }
+            return Err(code);
+        }
+        Ok(ids)
+    }
+
+    fn add_scope_filters(engine: &Engine, scope: IsolationScope, exepath: Option<&Path>, ids: &mut Vec<u64>) -> Result<(), u32> {
+        let layers = [FWPM_LAYER_ALE_AUTH_CONNECT_V4, FWPM_LAYER_ALE_AUTH_CONNECT_V6];
+        match (scope, exepath) {
+            (IsolationScope::Executable, Some(exepath)) => {
+                let app_id = AppId::of(exepath)?;
+                for layer in layers.iter() {
+                    ids.push(engine.add(*layer, FWP_ACTION_BLOCK, 15, &[app_id.condition()])?);
+                }
+            }
+            _ => {
+                let agent = std::env::current_exe().map_err(|e| e.raw_os_error().unwrap_or(0) as u32)?;
+                let agent_id = AppId::of(&agent)?;
+                let system_root = std::env::var_os("SystemRoot").unwrap_or_else(|| OsString::from(r"C:\Windows"));
+                let svchost_id = AppId::of(&Path::new(&system_root).join(r"System32\svchost.exe"))?;
+                let loopback = FilterCondition {
+                    field_key: FWPM_CONDITION_FLAGS,
+                    match_type: FWP_MATCH_FLAGS_ALL_SET,
+                    condition_value: Value {
+                        r#type: FWP_UINT32,
+                        value: FWP_CONDITION_FLAG_IS_LOOPBACK as usize,
+                    },
+                };
+                let dns = || FilterCondition {
+                    field_key: FWPM_CONDITION_IP_REMOTE_PORT,
+                    match_type: FWP_MATCH_EQUAL,
+                    condition_value: Value {
+                        r#type: FWP_UINT16,
+                        value: DNS_PORT as usize,
+                    },
+                };
+                for layer in layers.iter() {
+                    ids.push(engine.add(*layer, FWP_ACTION_PERMIT, 15, &[agent_id.condition()])?);
+                    ids.push(engine.add(*layer, FWP_ACTION_PERMIT, 15, std::slice::from_ref(&loopback))?);
+                    ids.push(engine.add(*layer, FWP_ACTION_PERMIT, 15, &[svchost_id.condition(), dns()])?);
+                    ids.push(engine.add(*layer, FWP_ACTION_BLOCK, 0, &[])?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the filters *filter_ids*, ignoring those already gone (reboot).
+    pub fn remove_filters(filter_ids: &[u64]) -> Result<(), u32> {
+        /// FWP_E_FILTER_NOT_FOUND
+        const FILTER_NOT_FOUND: u32 = 0x8032_0003;
+        let engine = Engine::open()?;
+        for id in filter_ids {
+            match unsafe { FwpmFilterDeleteById0(engine.0, *id) } {
+                0 | FILTER_NOT_FOUND => {}
+                code => return Err(code),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::isolation::{IsolationEntry, IsolationScope, IsolationState};
+
+    #[test]
+    fn isolations_should_be_extended_and_not_duplicated() {
+        let mut state = IsolationState::default();
+        state.entries.push(IsolationEntry {
+            scope: IsolationScope::Executable,
+            gid: 7,
+            exepath: Some(PathBuf::from(r"C:\Temp\evil.exe")),
+            filter_ids: vec![1, 2],
+        });
+        state.extend(1_000);
+        state.extend(600);
+        assert_eq!(state.until, 1_000);
+        assert!(!state.is_expired(999) && state.is_expired(1_000));
+        assert!(state.covers(IsolationScope::Executable, Path::new(r"C:\Temp\evil.exe")));
+        assert!(!state.covers(IsolationScope::Executable, Path::new(r"C:\Temp\other.exe")));
+        assert!(!state.covers(IsolationScope::Machine, Path::new(r"C:\Temp\evil.exe")));
+    }
+}
@@ -0,0 +1,179 @@
+//! Journal of the decisions to kill or suspend, written before acting.
+//!
+//! Each kill or suspension is first appended as a JSON line to
+//! *DebugPath\journal\decisions.jsonl* and flushed to the disk: the gid, the score and threshold,
+//! the mode and kill policy, the model and the features of the last prediction. If the service
+//! crashes right after the action (or during it), the journal still tells why it was done.
+//!
+//! The file is only appended to. A line cut by a crash is skipped by [load]. A failure to write
+//! the journal is logged, and does not prevent the action.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::config::{Config, Param};
+use crate::prediction;
+use crate::prediction::input_tensors::RollingFeatures;
+use crate::process::ProcessRecord;
+use crate::status::rfc3339;
+
+pub static JOURNAL_DIR: &str = "journal";
+pub static JOURNAL_FILE_NAME: &str = "decisions.jsonl";
+
+/// Serializes the appends of the workers.
+static APPEND: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Kill,
+    Suspend,
+}
+
+/// What led to the decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// The prediction exceeded the threshold
+    Model,
+    /// A verdict of [crate::fastpath]
+    FastPath,
+    /// See [crate::wiper]
+    MassDeletion,
+    /// Suspended for too long
+    SuspendTimeout,
+    /// A kill command file of the user interface
+    Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Decision {
+    pub time: String,
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub pids: Vec<u32>,
+    pub action: Action,
+    pub trigger: Trigger,
+    pub score: f32,
+    pub threshold: f32,
+    pub mode: String,
+    pub kill_policy: String,
+    pub profile: Option<String>,
+    pub model_version: String,
+    /// The last row of the prediction matrix, see [RollingFeatures::last_named]
+    pub features: BTreeMap<String, f32>,
+}
+
+impl Decision {
+    pub fn of(
+        config: &Config,
+        proc: &ProcessRecord,
+        action: Action,
+        trigger: Trigger,
+        score: f32,
+        predmtrx: &RollingFeatures,
+    ) -> Decision {
+        let mut pids: Vec<u32> = proc.pids.iter().copied().collect();
+        pids.sort_unstable();
+        let features = predmtrx.last_named().map(|(name, value)| (name.to_string(), value)).collect();
+        Decision {
+            time: rfc3339(SystemTime::now()),
+            gid: proc.gid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            pids,
+            action,
+            trigger,
+            score,
+            threshold: proc.threshold_prediction,
+            mode: config.get_scheduled(Param::Mode),
+            kill_policy: config.get_str(Param::KillPolicy),
+            profile: config.active_profile(),
+            model_version: prediction::model_version(),
+            features,
+        }
+    }
+}
+
+/// Appends *decision* to the journal and flushes it, before the action. Errors are logged.
+pub fn record(config: &Config, decision: &Decision) {
+    let path = config.get_path(Param::DebugPath).join(JOURNAL_DIR).join(JOURNAL_FILE_NAME);
+    if let Err(e) = append(&path, decision) {
+        error!(gid = decision.gid, "Cannot journal the decision into {}: {}", path.display(), e);
+    }
+}
+
+fn append(path: &Path, decision: &Decision) -> io::Result<()> {
+    let mut line = serde_json::to_vec(decision)?;
+    line.push(b'\n');
+    let _append = APPEND.lock().unwrap();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    file.sync_all()
+}
+
+/// The decisions of the journal *path*, the oldest first.
+pub fn load(path: &Path) -> io::Result<Vec<Decision>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use crate::journal::{append, load, Action, Decision, Trigger};
+
+    #[test]
+    fn journal_should_survive_a_cut_line() {
+        let path = std::env::temp_dir().join(format!("owlyshield_journal_{}", std::process::id())).join("decisions.jsonl");
+        let mut decision = Decision {
+            time: String::from("2022-04-15T07:20:30+02:00"),
+            gid: 42,
+            appname: String::from("locker.exe"),
+            exepath: PathBuf::from(r"C:\Users\bob\locker.exe"),
+            pids: vec![4242, 4250],
+            action: Action::Suspend,
+            trigger: Trigger::Model,
+            score: 0.97,
+            threshold: 0.65,
+            mode: String::from("PROTECT"),
+            kill_policy: String::from("SUSPEND"),
+            profile: None,
+            model_version: String::from("0a1b2c3d4e5f"),
+            features: BTreeMap::from([(String::from("ops_written"), 120.0)]),
+        };
+        append(&path, &decision).unwrap();
+        decision.action = Action::Kill;
+        decision.trigger = Trigger::SuspendTimeout;
+        append(&path, &decision).unwrap();
+        // a crash while writing the third one
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"time":"2022-04-15T07:22:31+02:00","gid":43,"app"#).unwrap();
+
+        let decisions = load(&path).unwrap();
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[1], decision);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains(r#""action":"suspend","trigger":"model""#));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+//! Verification of the kills: a process may survive the kill of its family (protected, stuck in a
+//! driver), or be respawned at once by a watchdog, a scheduled task or a service.
+//!
+//! The workers queue a [KillRequest] after each kill, which the fetch stage watches: every
+//! [POLL_INTERVAL], the processes of the family still running are listed, with the respawns, new
+//! processes started after the kill whose parent was killed or which run the same executable
+//! (same path or same sha256, never for the system and shared executables, see
+//! [crate::utils::is_system_path] and [crate::lolbin]). The pids are matched with their start
+//! time, so that a reused pid is not taken for a killed process. The respawns are killed at once,
+//! the survivors after *KILL_VERIFY_SECS*, up to *KILL_RETRIES* times, except the excluded ones
+//! ([crate::exclusions]). The outcome is sent to the connectors in a [Kill] event, once no process has been alive
+//! for *KILL_VERIFY_SECS*, or when the retries are exhausted. The executables of the verified
+//! kills are then quarantined, with *QUARANTINE* ([crate::quarantine]).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sysinfo::{ProcessExt, System, SystemExt};
+use tracing::{error, info, warn};
+
+use crate::config::{Config, Param};
+use crate::connectors::connector::Connectors;
+use crate::correlation::IncidentRef;
+use crate::exclusions::{ExclusionSubject, Exclusions};
+use crate::iosource::IoEventSource;
+use crate::lolbin;
+use crate::os;
+use crate::process::ProcessRecord;
+use crate::quarantine;
+use crate::quarantine::{Quarantine, QuarantineItem};
+use crate::utils::{is_system_path, sha256_file};
+
+/// Period of the checks of the processes of the killed families.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillOutcome {
+    /// All the processes exited after the first kill
+    Exited,
+    /// All the processes exited, but some had to be killed again
+    Rekilled,
+    /// Some processes were still running after the last retry
+    Failed,
+}
+
+impl fmt::Display for KillOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KillOutcome::Exited => write!(f, "EXITED"),
+            KillOutcome::Rekilled => write!(f, "REKILLED"),
+            KillOutcome::Failed => write!(f, "FAILED"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KillVerification {
+    pub outcome: KillOutcome,
+    /// Kills issued, the first one included
+    pub attempts: u32,
+    pub respawns: Vec<u32>,
+    /// Processes still running at the end of the verification
+    pub survivors: Vec<u32>,
+    pub duration: Duration,
+}
+
+/// A kill by a worker, to be verified.
+#[derive(Debug, Clone)]
+pub struct KillRequest {
+    pub time: SystemTime,
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub pids: Vec<u32>,
+    pub prediction: f32,
+    /// Error of the first kill, if any
+    pub error: Option<String>,
+    /// The executable and its copies dropped by the gid
+    pub executables: Vec<PathBuf>,
+    /// Of the [crate::enrichment] providers, added when sent to the connectors
+    pub tags: Vec<String>,
+    /// Of the [crate::correlation], added when sent to the connectors
+    pub incident: Option<IncidentRef>,
+    /// See [crate::fingerprint]
+    pub suspected_family: Option<String>,
+}
+
+impl KillRequest {
+    pub fn from(proc: &ProcessRecord, prediction: f32, error: Option<String>) -> KillRequest {
+        KillRequest {
+            time: SystemTime::now(),
+            gid: proc.gid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            pids: proc.pids.iter().copied().collect(),
+            prediction,
+            error,
+            executables: quarantine::executables(proc),
+            tags: Vec::new(),
+            incident: None,
+            suspected_family: proc.fingerprint.suspected_family().map(|suspected| suspected.family.to_string()),
+        }
+    }
+}
+
+/// A verified kill, sent to the connectors.
+#[derive(Debug, Clone)]
+pub struct Kill {
+    pub request: KillRequest,
+    pub verification: KillVerification,
+    pub quarantined: Vec<QuarantineItem>,
+}
+
+/// What to do with a watched kill.
+#[derive(Debug, PartialEq)]
+enum Step {
+    Wait,
+    Rekill(Vec<u32>),
+    Done(KillVerification),
+}
+
+#[derive(Debug)]
+struct Watched {
+    request: KillRequest,
+    started: Instant,
+    last_kill: Instant,
+    attempts: u32,
+    /// Of the kill, in seconds since the epoch: the respawns are started later
+    kill_time: u64,
+    /// The processes of the family and the respawns, with their latest possible start time (a
+    /// process started later reuses the pid): their children are respawns too
+    killed: HashMap<u32, u64>,
+    respawns: Vec<u32>,
+    /// A system or shared executable, never matched by path nor hash
+    shared: bool,
+    exe_size: Option<u64>,
+    sha256: Option<String>,
+}
+
+impl Watched {
+    fn new(request: KillRequest, now: Instant) -> Watched {
+        let kill_time = request.time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let shared = is_system_path(&request.exepath) || lolbin::is_lolbin(&request.exepath);
+        Watched {
+            kill_time,
+            killed: request.pids.iter().map(|pid| (*pid, kill_time)).collect(),
+            shared,
+            exe_size: request.exepath.metadata().ok().map(|m| m.len()),
+            sha256: if shared { None } else { sha256_file(&request.exepath).ok() },
+            request,
+            started: now,
+            last_kill: now,
+            attempts: 1,
+            respawns: Vec::new(),
+        }
+    }
+
+    /// Whether the process *pid* started at *start_time* is one of the killed ones.
+    fn is_killed(&self, pid: u32, start_time: u64) -> bool {
+        self.killed.get(&pid).is_some_and(|latest| start_time <= *latest)
+    }
+
+    fn is_same_exe(&self, exe: &Path) -> bool {
+        if self.shared || is_system_path(exe) || lolbin::is_lolbin(exe) {
+            return false;
+        }
+        if exe == self.request.exepath {
+            return true;
+        }
+        // only the executables of the same size are hashed
+        self.sha256.is_some()
+            && exe.metadata().ok().map(|m| m.len()) == self.exe_size
+            && sha256_file(exe).ok() == self.sha256
+    }
+
+    /// The next step, given the killed processes still *alive* and the new *respawned* ones.
+    fn step(&mut self, alive: Vec<u32>, respawned: Vec<(u32, u64)>, now: Instant, verify: Duration, retries: u32) -> Step {
+        self.killed.extend(respawned.iter().copied());
+        let respawned: Vec<u32> = respawned.into_iter().map(|(pid, _)| pid).collect();
+        self.respawns.extend(&respawned);
+        let expired = now.duration_since(self.last_kill) >= verify;
+        let mut running = alive;
+        running.extend(respawned.iter());
+        if running.is_empty() {
+            if !expired {
+                return Step::Wait;
+            }
+            let outcome = if self.attempts == 1 { KillOutcome::Exited } else { KillOutcome::Rekilled };
+            return Step::Done(self.verification(outcome, running, now));
+        }
+        if respawned.is_empty() && !expired {
+            return Step::Wait;
+        }
+        if self.attempts > retries {
+            return Step::Done(self.verification(KillOutcome::Failed, running, now));
+        }
+        self.attempts += 1;
+        self.last_kill = now;
+        Step::Rekill(running)
+    }
+
+    fn verification(&self, outcome: KillOutcome, survivors: Vec<u32>, now: Instant) -> KillVerification {
+        KillVerification {
+            outcome,
+            attempts: self.attempts,
+            respawns: self.respawns.clone(),
+            survivors,
+            duration: now.duration_since(self.started),
+        }
+    }
+}
+
+/// The kills being verified, in the fetch stage.
+pub struct KillVerifier {
+    verify: Duration,
+    retries: u32,
+    watched: Vec<Watched>,
+    system: System,
+    last_poll: Instant,
+    quarantine: Option<Quarantine>,
+}
+
+impl KillVerifier {
+    pub fn from(config: &Config) -> KillVerifier {
+        KillVerifier {
+            verify: Duration::from_secs(config.get_usize(Param::KillVerifySecs) as u64),
+            retries: config.get_usize(Param::KillRetries) as u32,
+            watched: Vec::new(),
+            system: System::new(),
+            last_poll: Instant::now(),
+            quarantine: config.get_bool(Param::Quarantine).then(|| Quarantine::from(config)),
+        }
+    }
+
+    pub fn watch(&mut self, request: KillRequest) {
+        self.watched.retain(|w| w.request.gid != request.gid);
+        self.watched.push(Watched::new(request, Instant::now()));
+    }
+
+    /// Checks the watched kills, kills the survivors and the respawns not excluded, and sends the
+    /// [Kill] events of the verified ones.
+    pub fn poll(&mut self, source: &dyn IoEventSource, exclusions: &Exclusions, connectors: &Connectors) {
+        if self.watched.is_empty() || self.last_poll.elapsed() < POLL_INTERVAL {
+            return;
+        }
+        self.last_poll = Instant::now();
+        self.system.refresh_processes();
+        let me = std::process::id();
+        let running: HashMap<u32, (Option<u32>, PathBuf, u64)> = self
+            .system
+            .processes()
+            .iter()
+            .map(|(pid, process)| {
+                (*pid as u32, (process.parent().map(|p| p as u32), process.exe().to_path_buf(), process.start_time()))
+            })
+            .filter(|(pid, _)| *pid != me)
+            .collect();
+        let (verify, retries) = (self.verify, self.retries);
+        let mut i = 0;
+        while i < self.watched.len() {
+            let watched = &mut self.watched[i];
+            let alive: Vec<u32> = running
+                .iter()
+                .filter(|(pid, (_, _, start_time))| watched.is_killed(**pid, *start_time))
+                .map(|(pid, _)| *pid)
+                .collect();
+            // a parent which exited cannot be checked against a reuse of its pid
+            let killed_parent = |parent: u32| match running.get(&parent) {
+                Some((_, _, start_time)) => watched.is_killed(parent, *start_time),
+                None => watched.killed.contains_key(&parent),
+            };
+            let respawned: Vec<(u32, u64)> = running
+                .iter()
+                .filter(|(pid, (parent, exe, start_time))| {
+                    *start_time >= watched.kill_time
+                        && !watched.is_killed(**pid, *start_time)
+                        && (parent.is_some_and(killed_parent) || watched.is_same_exe(exe))
+                })
+                .map(|(pid, (_, _, start_time))| (*pid, *start_time))
+                .collect();
+            if !respawned.is_empty() {
+                warn!(gid = watched.request.gid, appname = %watched.request.appname, ?respawned, "Killed process family respawned");
+            }
+            match watched.step(alive, respawned, Instant::now(), verify, retries) {
+                Step::Wait => i += 1,
+                Step::Rekill(pids) => {
+                    let (pids, excluded): (Vec<u32>, Vec<u32>) = pids.into_iter().partition(|pid| {
+                        running.get(pid).map_or(true, |(_, exe, _)| {
+                            exclusions.get_scope(&mut ExclusionSubject::new(exe, *pid)).is_none()
+                        })
+                    });
+                    if !excluded.is_empty() {
+                        info!(gid = watched.request.gid, ?excluded, "Excluded processes not killed again");
+                    }
+                    if !pids.is_empty() {
+                        warn!(gid = watched.request.gid, attempt = watched.attempts, ?pids, "Killing again");
+                        rekill(source, watched.request.gid, &pids);
+                    }
+                    i += 1;
+                }
+                Step::Done(verification) => {
+                    let request = self.watched.swap_remove(i).request;
+                    let quarantined = match &self.quarantine {
+                        Some(quarantine) if verification.outcome != KillOutcome::Failed => quarantine_all(quarantine, &request),
+                        _ => Vec::new(),
+                    };
+                    let kill = Kill {
+                        request,
+                        verification,
+                        quarantined,
+                    };
+                    report(connectors, &kill);
+                }
+            }
+        }
+    }
+}
+
+/// The respawns may belong to other families: they are killed one by one.
+fn rekill(source: &dyn IoEventSource, gid: u64, pids: &[u32]) {
+    if let Err(code) = os::terminate_group(pids) {
+        warn!(gid, code, "Cannot terminate the process group");
+    }
+    if let Err(e) = source.kill_gid(gid) {
+        warn!(gid, "{}", e);
+    }
+}
+
+fn quarantine_all(quarantine: &Quarantine, request: &KillRequest) -> Vec<QuarantineItem> {
+    let mut quarantined = Vec::new();
+    for path in &request.executables {
+        match quarantine.store(path, request) {
+            Ok(item) => {
+                warn!(gid = request.gid, path = %path.display(), id = %item.id, "Quarantined");
+                quarantined.push(item);
+            }
+            Err(e) => error!(gid = request.gid, path = %path.display(), "Cannot quarantine: {}", e),
+        }
+    }
+    quarantined
+}
+
+fn report(connectors: &Connectors, kill: &Kill) {
+    let (request, verification) = (&kill.request, &kill.verification);
+    if verification.outcome == KillOutcome::Failed {
+        error!(
+            gid = request.gid,
+            appname = %request.appname,
+            attempts = verification.attempts,
+            survivors = ?verification.survivors,
+            "Kill failed: processes still running"
+        );
+    } else {
+        info!(
+            gid = request.gid,
+            appname = %request.appname,
+            outcome = %verification.outcome,
+            attempts = verification.attempts,
+            respawns = verification.respawns.len(),
+            "Kill verified"
+        );
+    }
+    connectors.send_kill(kill);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use crate::killcheck::{KillOutcome, KillRequest, Step, Watched};
+
+    #[test]
+    fn respawns_and_survivors_should_be_killed_again_up_to_the_retries() {
+        let request = KillRequest {
+            time: SystemTime::now(),
+            gid: 7,
+            appname: String::from("evil.exe"),
+            exepath: PathBuf::from(r"C:\Users\bob\AppData\Local\Temp\evil.exe"),
+            pids: vec![100, 101],
+            prediction: 0.9,
+            error: None,
+            executables: Vec::new(),
+            tags: Vec::new(),
+            incident: None,
+            suspected_family: None,
+        };
+        let verify = Duration::from_secs(10);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let mut clean = Watched::new(request.clone(), start);
+        assert_eq!(clean.step(vec![101], vec![], at(1), verify, 2), Step::Wait);
+        assert_eq!(clean.step(vec![], vec![], at(5), verify, 2), Step::Wait);
+        match clean.step(vec![], vec![], at(10), verify, 2) {
+            Step::Done(v) => assert_eq!((v.outcome, v.attempts), (KillOutcome::Exited, 1)),
+            step => panic!("{:?}", step),
+        }
+
+        let mut respawning = Watched::new(request.clone(), start);
+        assert!(respawning.is_killed(101, respawning.kill_time));
+        assert!(!respawning.is_killed(101, respawning.kill_time + 1));
+        assert_eq!(respawning.step(vec![], vec![(200, respawning.kill_time + 1)], at(1), verify, 2), Step::Rekill(vec![200]));
+        assert_eq!(respawning.step(vec![200], vec![], at(5), verify, 2), Step::Wait);
+        assert_eq!(respawning.step(vec![200], vec![], at(11), verify, 2), Step::Rekill(vec![200]));
+        match respawning.step(vec![200], vec![], at(21), verify, 2) {
+            Step::Done(v) => {
+                assert_eq!((v.outcome, v.attempts), (KillOutcome::Failed, 3));
+                assert_eq!((v.respawns, v.survivors), (vec![200], vec![200]));
+            }
+            step => panic!("{:?}", step),
+        }
+
+        let shared = KillRequest {
+            exepath: PathBuf::from(r"C:\Windows\System32\WindowsPowerShell\v1.0\powershell.exe"),
+            ..request
+        };
+        assert!(!Watched::new(shared, start).is_same_exe(Path::new(r"C:\Windows\System32\WindowsPowerShell\v1.0\powershell.exe")));
+    }
+}
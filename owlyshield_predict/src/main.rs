@@ -12,61 +12,158 @@ use std::ffi::OsString;
 use std::fs::File;
 use std::io::Read;
 use std::io::{Seek, SeekFrom};
+#[cfg(windows)]
 use std::os::raw::c_ulong;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time;
 use std::time::{Duration, Instant};
 
-use log::{error, info};
-use sysinfo::SystemExt;
-use windows_service::service::{ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType};
+use tracing::{error, info, warn};
+#[cfg(windows)]
+use windows_service::service::{ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus};
+#[cfg(windows)]
 use windows_service::{define_windows_service, service_control_handler, service_dispatcher};
+#[cfg(windows)]
 use windows_service::service_control_handler::ServiceControlHandlerResult;
-use crate::config::KillPolicy;
 use crate::connectors::connector::Connectors;
-use crate::connectors::sitincloud::SitinCloud;
+use crate::cli::Cli;
+use crate::service_ctl::Lifecycle;
+#[cfg(windows)]
+use crate::service_ctl::{SERVICE_NAME, SERVICE_TYPE};
 
-use crate::driver_com::shared_def::{CDriverMsgs, IOMessage};
+use crate::error::OwlyError;
 use crate::notifications::toast;
-use crate::prediction::TfLite;
-use crate::prediction_static::TfLiteStatic;
-use crate::process::procs::Procs;
-use crate::worker::{process_drivermessage, process_drivermessage_replay, process_suspended_procs, record_drivermessage};
+#[cfg(windows)]
+use crate::worker::record_drivermessage;
 
 mod actions_on_kill;
+mod admx;
+mod anomaly;
+mod api;
+mod audit;
+mod authz;
+mod autostart;
+mod backpressure;
+mod backup;
+mod baseline;
+mod broker;
+mod calibrate;
+mod capture;
+mod cli;
+mod clock;
+mod cloudsync;
 mod config;
+mod configcheck;
+mod container;
+mod correlation;
 mod csvwriter;
+mod decay;
+mod defender;
+mod diag;
+mod dirtree;
 mod driver_com;
+mod driver_reply;
+mod driver_setup;
+#[cfg(target_os = "linux")]
+mod ebpf;
+mod enrichment;
+mod error;
+mod escalation;
+mod events;
+mod eventstore;
+mod exclusions;
+mod exfil;
 mod extensions;
+mod extprofiles;
+#[cfg(target_os = "linux")]
+mod fanotify;
+mod fastpath;
+mod features;
+mod filetable;
+mod fingerprint;
+mod follow;
+mod gidmerge;
+mod heartbeat;
+mod heatmap;
+mod history;
+mod identity;
+mod inputcapture;
+mod intern;
+mod integrity;
+mod iosource;
+mod isolation;
+mod journal;
+mod killcheck;
+mod logging;
+mod lolbin;
+mod magic;
+mod memscan;
+mod netshare;
+mod notelang;
 mod notifications;
+mod os;
+mod payloads;
+mod persistence;
+mod pipeline;
 mod prediction;
+mod privileges;
 mod process;
+mod profiles;
+mod quarantine;
+mod ransomnote;
+mod rawdisk;
+mod reputation;
+mod routing;
+mod schema;
+mod scripthost;
+mod selftest;
 mod utils;
+mod volumes;
 mod whitelist;
+mod wiper;
 mod worker;
+mod wsl;
+mod yara;
 mod connectors;
 mod prediction_static;
+#[cfg(windows)]
+mod secrets;
+#[cfg(windows)]
+mod selfprotect;
+mod service_ctl;
+mod setup;
+mod sketch;
+mod stats;
+mod status;
+mod stix;
+mod sysmon;
+#[cfg(windows)]
+mod signer;
+mod timeline;
+mod token;
+mod updater;
+mod watchdog;
 
 pub fn to_hex_string(bytes: Vec<u8>) -> String {
     let strs: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
     strs.join(" ")
 }
 
-#[cfg(feature = "service")]
-const SERVICE_NAME: &str = "Owlyshield Service";
-#[cfg(feature = "service")]
-const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+/// Time given to the protection loop to drain the driver queue when the service is stopped.
+#[cfg(all(windows, feature = "service"))]
+const STOP_WAIT_HINT: Duration = Duration::from_secs(10);
 
-#[cfg(feature = "service")]
+#[cfg(all(windows, feature = "service"))]
 define_windows_service!(ffi_service_main, service_main);
 
 // examples at https://github.com/mullvad/windows-service-rs/tree/master/examples
-#[cfg(feature = "service")]
+#[cfg(all(windows, feature = "service"))]
 fn service_main(arguments: Vec<OsString>) {
     std::panic::set_hook(Box::new(|pi| {
         error!("Critical error: {}", pi);
-        println!("{}", pi);
     }));
     let log_source = "Owlyshield Ransom Rust";
     winlog::register(&log_source);
@@ -78,16 +175,29 @@ fn service_main(arguments: Vec<OsString>) {
     }
 }
 
-#[cfg(feature = "service")]
+/// Events received by the service main loop.
+#[cfg(all(windows, feature = "service"))]
+enum ServiceEvent {
+    Control(ServiceControl),
+    /// The protection loop has returned, or panicked (false).
+    WorkerExited(bool),
+}
+
+#[cfg(all(windows, feature = "service"))]
 fn run_service(arguments: Vec<OsString>) -> Result<(), windows_service::Error> {
-    let (shutdown_tx, shutdown_rx) = mpsc::channel();
-    let shutdown_tx1 = shutdown_tx.clone();
+    let (events_tx, events_rx) = mpsc::channel();
+    let events_tx1 = events_tx.clone();
+    let lifecycle = Arc::new(Lifecycle::default());
 
     let event_handler = move |control_event| -> ServiceControlHandlerResult {
         match control_event {
-            ServiceControl::Stop | ServiceControl::Interrogate => {
-                shutdown_tx.send(()).unwrap();
-                info!("Stop event received");
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            ServiceControl::Stop
+            | ServiceControl::Shutdown
+            | ServiceControl::Pause
+            | ServiceControl::Continue => {
+                info!("{:?} event received", control_event);
+                events_tx.send(ServiceEvent::Control(control_event)).unwrap();
                 ServiceControlHandlerResult::NoError
             }
             _ => ServiceControlHandlerResult::NotImplemented,
@@ -95,63 +205,97 @@ fn run_service(arguments: Vec<OsString>) -> Result<(), windows_service::Error> {
     };
 
     let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
-
-    let next_status = ServiceStatus {
-        service_type: SERVICE_TYPE,
-        current_state: ServiceState::Running,
-        controls_accepted: ServiceControlAccept::STOP,
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: Duration::default(),
-        process_id: None,
+    let set_state = |current_state: ServiceState, exit_code: u32, wait_hint: Duration| {
+        let controls_accepted = match current_state {
+            ServiceState::Running | ServiceState::Paused => {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN | ServiceControlAccept::PAUSE_CONTINUE
+            }
+            _ => ServiceControlAccept::empty(),
+        };
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state,
+            controls_accepted,
+            exit_code: if exit_code == 0 { ServiceExitCode::Win32(0) } else { ServiceExitCode::ServiceSpecific(exit_code) },
+            checkpoint: 0,
+            wait_hint,
+            process_id: None,
+        })
     };
 
     // Tell the system that the service is running now
-    status_handle.set_service_status(next_status)?;
+    set_state(ServiceState::Running, 0, Duration::default())?;
 
+    let lifecycle_bis = Arc::clone(&lifecycle);
     std::thread::spawn(move || {
         let t = std::thread::spawn(move || {
-            run();
+            watchdog::supervise(lifecycle_bis, &Connectors::new(), run);
         })
         .join();
-        if t.is_err() {
-            shutdown_tx1.send(()).unwrap();
-        }
+        events_tx1.send(ServiceEvent::WorkerExited(t.is_ok())).unwrap();
     });
 
+    let mut exit_code = 0;
+    let mut stop_deadline: Option<Instant> = None;
     loop {
-        // Poll shutdown event.
-        match shutdown_rx.recv_timeout(Duration::from_secs(1)) {
-            // Break the loop either upon stop or channel disconnect
-            Ok(_) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
-
-            // Continue work if no events were received within the timeout
-            Err(mpsc::RecvTimeoutError::Timeout) => (),
+        match events_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ServiceEvent::Control(ServiceControl::Pause)) => {
+                lifecycle.set_paused(true);
+                info!("Service paused: kills are disabled");
+                set_state(ServiceState::Paused, 0, Duration::default())?;
+            }
+            Ok(ServiceEvent::Control(ServiceControl::Continue)) => {
+                lifecycle.set_paused(false);
+                info!("Service resumed");
+                set_state(ServiceState::Running, 0, Duration::default())?;
+            }
+            Ok(ServiceEvent::Control(_)) => {
+                // Stop or Shutdown: let the protection loop drain the driver queue
+                if stop_deadline.is_none() {
+                    set_state(ServiceState::StopPending, 0, STOP_WAIT_HINT)?;
+                    lifecycle.request_stop();
+                    stop_deadline = Some(Instant::now() + STOP_WAIT_HINT);
+                }
+            }
+            Ok(ServiceEvent::WorkerExited(is_ok)) => {
+                if lifecycle.is_restart_requested() {
+                    // without the stopped state, the Service Control Manager starts the service again
+                    info!("Agent updated, relaunched by the Service Control Manager");
+                    std::process::exit(updater::RELAUNCH_EXIT_CODE);
+                }
+                if !is_ok {
+                    error!("Protection loop exited unexpectedly");
+                    exit_code = 1;
+                }
+                break;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if stop_deadline.map_or(false, |deadline| Instant::now() > deadline) {
+                    error!("Protection loop did not stop in time");
+                    break;
+                }
+            }
         };
     }
 
-    status_handle.set_service_status(ServiceStatus {
-        service_type: SERVICE_TYPE,
-        current_state: ServiceState::Stopped,
-        controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: Duration::default(),
-        process_id: None,
-    })?;
+    set_state(ServiceState::Stopped, exit_code, Duration::default())?;
 
     Ok(())
 }
 
-#[cfg(feature = "service")]
+#[cfg(all(windows, feature = "service"))]
 fn main() -> Result<(), windows_service::Error> {
+    if let Some(command) = Cli::parse_with_config_flags().command {
+        std::process::exit(cli::run_command(command));
+    }
     // Register generated `ffi_service_main` with the system and start the service, blocking
     // this thread until the service is stopped.
     service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
     Ok(())
 }
 
-#[cfg(not(feature = "service"))]
+#[cfg(not(all(windows, feature = "service")))]
 fn main() {
     //https://patorjk.com/software/taag/#p=display&f=Bloody&t=Owlyshield
     let banner = r#"
@@ -171,102 +315,106 @@ fn main() {
     "#;
     println!("{}", banner);
 
-    run();
+    if let Some(command) = Cli::parse_with_config_flags().command {
+        std::process::exit(cli::run_command(command));
+    }
+
+    let lifecycle = Arc::new(Lifecycle::default());
+    watchdog::supervise(Arc::clone(&lifecycle), &Connectors::new(), run);
+    if lifecycle.is_restart_requested() {
+        updater::relaunch();
+    }
 }
 
-fn run() {
-    std::panic::set_hook(Box::new(|pi| {
-        error!("Critical error: {}", pi);
-        println!("{}", pi);
-    }));
-    let log_source = "Owlyshield Ransom Rust";
-    winlog::register(&log_source);
-    winlog::init(&log_source).unwrap_or(());
+/// The protection loop, supervised by [watchdog::supervise], to which its errors are returned.
+fn run(lifecycle: &Lifecycle) -> Result<(), OwlyError> {
+    // the registration of the event source writes into the registry
+    #[cfg(windows)]
+    if !config::Config::is_portable_process() {
+        let log_source = "Owlyshield Ransom Rust";
+        winlog::register(&log_source);
+        winlog::init(&log_source).unwrap_or(());
+    }
     info!("Program started.");
 
-    let driver =
-        driver_com::Driver::open_kernel_driver_com().expect("Cannot open driver communication (is the minifilter started?)");
+    let config = config::Config::new()?;
+    // before anything which could fail in a new version
+    if !updater::on_start(&config) {
+        lifecycle.request_restart();
+        return Ok(());
+    }
+    #[cfg(windows)]
+    let driver = driver_com::Driver::open_kernel_driver_com()
+        .map_err(|e| iosource::IoSourceError::Open(format!("{} (is the minifilter started?)", e)))?;
+    #[cfg(windows)]
     driver
         .driver_set_app_pid()
-        .expect("Cannot set driver app pid");
-    let mut vecnew: Vec<u8> = Vec::with_capacity(65536);
-    let mut procs: Procs = Procs::new();
-    let mut predictions_static: HashMap<String, f32> = HashMap::new();
-
-    let tflite = TfLite::new();
-    let tflite_static = TfLiteStatic::new();
-    let config = config::Config::new();
-    let whitelist = whitelist::WhiteList::from(
-        &Path::new(&config[config::Param::ConfigPath]).join(Path::new("exclusions.txt")),
-    )
-    .expect("Cannot open exclusions.txt");
+        .map_err(|e| iosource::IoSourceError::Open(format!("cannot set the pid of the agent: {}", e)))?;
+    if let Some(dir) = config.portable_dir() {
+        info!("Portable mode, in {}", dir.display());
+    }
+    let whitelist_path = config.get_path(config::Param::ConfigPath).join(Path::new("exclusions.txt"));
+    let whitelist = whitelist::WhiteList::from(&whitelist_path)
+        .map_err(|e| config::ConfigError::File { path: whitelist_path.clone(), details: e.to_string() })?;
     whitelist.refresh_periodically();
+    let exclusions = exclusions::Exclusions::from(
+        &config.get_path(config::Param::ConfigPath).join(Path::new("exclusions.toml")),
+    );
+    exclusions.refresh_periodically();
+    #[cfg(windows)]
+    selfprotect::apply(&driver, &config, &[]);
+    #[cfg(target_os = "linux")]
+    let driver = open_linux_source(&config)?;
 
     toast(&config, &"Program Started", "");
 
     // SAVE_IRP_CSV
+    #[cfg(windows)]
     if cfg!(feature = "record") {
-        println!("Record Driver Messages");
+        info!("Record Driver Messages");
+        let mut buffer = vec![0u8; driver_reply::BUFFER_SIZE];
         let filename =
-            &Path::new(&config[config::Param::DebugPath]).join(Path::new("drivermessages.txt"));
+            &config.get_path(config::Param::DebugPath).join(Path::new("drivermessages.txt"));
         let mut pids_exepaths: HashMap<c_ulong, PathBuf> = HashMap::new();
+        let mut failures = 0;
         loop {
-            if let Some(reply_irp) = driver.get_irp(&mut vecnew) {
-                if reply_irp.num_ops > 0 {
-                    let drivermsgs = CDriverMsgs::new(&reply_irp);
-                    for drivermsg in drivermsgs {
-                        record_drivermessage(filename, &mut pids_exepaths, &drivermsg);
+            lifecycle.beat();
+            let stopping = lifecycle.is_stop_requested();
+            let drivermsgs = match driver.get_irp(&mut buffer) {
+                Ok(Some(drivermsgs)) => drivermsgs,
+                res => {
+                    let e = OwlyError::from(match res {
+                        Err(e) => iosource::IoSourceError::Receive(e.code().0 as i32),
+                        _ => iosource::IoSourceError::Closed,
+                    });
+                    failures += 1;
+                    if failures > pipeline::FETCH_RETRIES {
+                        return Err(e);
                     }
-                } else {
-                    std::thread::sleep(time::Duration::from_millis(100));
+                    warn!(attempt = failures, "{}, retrying", e);
+                    std::thread::sleep(pipeline::FETCH_RETRY_DELAY * failures);
+                    continue;
+                }
+            };
+            failures = 0;
+            if !drivermsgs.is_empty() {
+                for drivermsg in &drivermsgs {
+                    record_drivermessage(filename, &mut pids_exepaths, drivermsg);
                 }
             } else {
-                panic!("Can't receive Driver Message?");
+                if stopping {
+                    break;
+                }
+                std::thread::sleep(time::Duration::from_millis(100));
             }
         }
     }
 
     if cfg!(feature = "replay") {
-        println!("Replay Driver Messages");
+        info!("Replay Driver Messages");
         let filename =
-            &Path::new(&config[config::Param::DebugPath]).join(Path::new("drivermessages.txt"));
-        let mut file = File::open(Path::new(filename)).unwrap();
-        let file_len = file.metadata().unwrap().len() as usize;
-
-        let buf_size = 1000;
-        let mut buf: Vec<u8> = Vec::new();
-        buf.resize(buf_size, 0);
-        let mut cursor_index = 0 as usize;
-
-        while cursor_index + buf_size < file_len {
-            //TODO ToFix! last 1000 buffer ignored
-            buf.fill(0);
-            file.seek(SeekFrom::Start(cursor_index as u64)).unwrap();
-            file.read_exact(&mut buf).unwrap();
-            let mut cursor_record_end = buf_size;
-            for i in 0..(buf_size - 3) {
-                // A strange chain is used to avoid collisions with the windows fileid
-                if buf[i] == 255u8 && buf[i + 1] == 0u8 && buf[i + 2] == 13u8 && buf[i + 3] == 10u8
-                {
-                    cursor_record_end = i;
-                    break;
-                }
-            }
-            //let dms: IOMessage = rmp_serde::from_read_ref(&buf[0..cursor_record_end]).unwrap();
-            let res_iomsg = rmp_serde::from_read_ref(&buf[0..cursor_record_end]);
-            match res_iomsg {
-                Ok(iomsg) => {
-                    process_drivermessage_replay(&config, &mut procs, &tflite, &iomsg);
-                }
-                Err(_e) => {
-                    println!("Error deserializeing buffer {}", cursor_index); //buffer is too small
-                }
-            }
-
-            //process_irp_deser(&config, &whitelist, &mut procs, &dms);
-            //println!("DMS {:?}", dms);
-            cursor_index += cursor_record_end + 4;
-        }
+            &config.get_path(config::Param::DebugPath).join(Path::new("drivermessages.txt"));
+        cli::replay(&config, filename);
     }
 
     // PROCESS_IRP (Live)
@@ -274,52 +422,55 @@ fn run() {
         feature = "record",
         feature = "replay"
     ))) {
-        println!("\nLIVE PROTECTION MODE");
-        println!("Interactive - can also work as a service.\n");
-        let mut system = sysinfo::System::new_all();
-        let mut iteration = 0;
-        let kill_policy = config.get_kill_policy();
-
-        // let mut cs = Connectors::new();
-        // cs.add(SitinCloud);
-        // cs.on_startup(&config);
-
-        loop {
-                iteration += 1;
-                if &iteration % 10 == 0 && kill_policy == KillPolicy::Suspend {
-                    process_suspended_procs(&driver, &config, &mut procs);
-                }
-            if let Some(reply_irp) = driver.get_irp(&mut vecnew) {
-                if reply_irp.num_ops > 0 {
-                    let drivermsgs = CDriverMsgs::new(&reply_irp);
-                    for drivermsg in drivermsgs {
-                        let mut iomsg = IOMessage::from(&drivermsg);
-                        let continue_loop = process_drivermessage(
-                            &driver, &config, &whitelist, &mut procs, &mut predictions_static, &tflite, &tflite_static, &mut iomsg,
-                        ).is_ok();
-                        if !continue_loop {
-                            break;
-                        }
-                    }
-                } else {
-                    let purge_start = Instant::now();
-                    if procs.len() > 50 {
-                        system.refresh_all();
-                        procs.purge(&system);
-                    }
-                    if purge_start.elapsed() < time::Duration::from_millis(100) {
-                        std::thread::sleep(time::Duration::from_millis(100) - purge_start.elapsed());
-                    }
-                }
-            } else {
-                panic!("Can't receive DriverMessage?");
-            }
+        info!("LIVE PROTECTION MODE - Interactive - can also work as a service.");
+        if config.get_mode() != config::Mode::Protect {
+            warn!("{} mode: the malicious processes are reported but never killed", config.get_str(config::Param::Mode));
         }
+        let audit = audit::AuditLog::from(&config);
+
+        let mut cs = Connectors::from(&config);
+        cs.on_startup(&config);
+
+        let status = status::AgentStatus::new();
+        let done = AtomicBool::new(false);
+        let res = std::thread::scope(|s| {
+            // also stops the threads already started if another one cannot be
+            let _done_guard = api::StopOnDrop(&done);
+            std::thread::Builder::new()
+                .name(String::from("api"))
+                .spawn_scoped(s, || api::serve(&config, lifecycle, &exclusions, &status, &done))
+                .map_err(|e| OwlyError::Thread("API", e))?;
+            std::thread::Builder::new()
+                .name(String::from("heartbeat"))
+                .spawn_scoped(s, || heartbeat::run(&config, lifecycle, &exclusions, &status, &done))
+                .map_err(|e| OwlyError::Thread("heartbeat", e))?;
+            std::thread::Builder::new()
+                .name(String::from("updater"))
+                .spawn_scoped(s, || updater::run(&config, lifecycle, &done))
+                .map_err(|e| OwlyError::Thread("updater", e))?;
+            pipeline::run(&driver, &config, &whitelist, &exclusions, lifecycle, &audit, &status, &cs)
+        });
+        cs.on_shutdown();
+        res?;
     }
 
-    //driver.close_kernel_communication();
+    drop(driver); // closes the driver port (or detaches the Linux sources)
+    info!("Program stopped.");
+    Ok(())
 
     //println!("{:?}", config);
     //println!("{:?}", config[config::Param::ApiAddr]);
     //println!("end");
 }
+
+/// The eBPF source if configured and loadable (needs a recent kernel), fanotify otherwise.
+#[cfg(target_os = "linux")]
+fn open_linux_source(config: &config::Config) -> Result<Box<dyn iosource::IoEventSource>, OwlyError> {
+    if config.get_str(config::Param::LinuxEventSource) == "EBPF" {
+        match ebpf::EbpfSource::open(config) {
+            Ok(source) => return Ok(Box::new(source)),
+            Err(e) => error!("{}, falling back to fanotify", e),
+        }
+    }
+    Ok(Box::new(fanotify::FanotifySource::open(config, true)?))
+}
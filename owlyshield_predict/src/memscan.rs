@@ -0,0 +1,85 @@
+//! Scan of the memory of a process by the antimalware provider, through AMSI (Windows).
+//!
+//! The payloads unpacked or injected in memory are never written to the disk: the private
+//! executable regions of the process (allocated by itself, not mapped from an image) are read and
+//! scanned as buffers, see [crate::scripthost::amsi_scan]. Only used on the processes watched by
+//! the [crate::escalation] ladder, as it is slow.
+
+use crate::scripthost::AmsiVerdict;
+
+/// Larger regions are only partially scanned.
+#[cfg_attr(not(windows), allow(dead_code))]
+const MAX_REGION_LEN: usize = 4 * 1024 * 1024;
+/// Beyond, the remaining regions of the process are not scanned.
+#[cfg_attr(not(windows), allow(dead_code))]
+const MAX_SCANNED_LEN: usize = 32 * 1024 * 1024;
+
+/// The worst verdict on the private executable regions of *pid*, None if none could be scanned.
+#[cfg(windows)]
+pub fn scan(pid: u32) -> Result<Option<AmsiVerdict>, windows::Error> {
+    use std::ffi::c_void;
+    use std::mem::size_of;
+
+    use bindings::Windows::Win32::Foundation::CloseHandle;
+    use bindings::Windows::Win32::System::Diagnostics::Debug::ReadProcessMemory;
+    use bindings::Windows::Win32::System::Memory::{
+        VirtualQueryEx, MEMORY_BASIC_INFORMATION, MEM_COMMIT, MEM_PRIVATE, PAGE_EXECUTE, PAGE_EXECUTE_READ,
+        PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY,
+    };
+    use bindings::Windows::Win32::System::Threading::{OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ};
+
+    use crate::scripthost::amsi_scan;
+
+    let executable = PAGE_EXECUTE.0 | PAGE_EXECUTE_READ.0 | PAGE_EXECUTE_READWRITE.0 | PAGE_EXECUTE_WRITECOPY.0;
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid);
+        if handle.is_invalid() || handle.0 == 0 {
+            return Err(windows::Error::from_win32());
+        }
+        let mut verdict = None;
+        let mut scanned = 0;
+        let mut address = 0usize;
+        let mut info = MEMORY_BASIC_INFORMATION::default();
+        while scanned < MAX_SCANNED_LEN
+            && VirtualQueryEx(handle, address as *const c_void, &mut info, size_of::<MEMORY_BASIC_INFORMATION>()) != 0
+        {
+            address = info.BaseAddress as usize + info.RegionSize;
+            if info.State != MEM_COMMIT || info.Type != MEM_PRIVATE || info.Protect.0 & executable == 0 {
+                continue;
+            }
+            let mut buffer = vec![0u8; info.RegionSize.min(MAX_REGION_LEN).min(MAX_SCANNED_LEN - scanned)];
+            let mut read = 0usize;
+            if !ReadProcessMemory(handle, info.BaseAddress, buffer.as_mut_ptr() as *mut c_void, buffer.len(), &mut read)
+                .as_bool()
+            {
+                continue;
+            }
+            buffer.truncate(read);
+            scanned += read;
+            let region = amsi_scan(&buffer, &format!("pid {} at {:#x}", pid, info.BaseAddress as usize));
+            if let Ok(Some(region)) = region {
+                verdict = Some(worst(verdict, region));
+                if region == AmsiVerdict::Detected {
+                    break;
+                }
+            }
+        }
+        CloseHandle(handle);
+        Ok(verdict)
+    }
+}
+
+/// AMSI is a Windows API.
+#[cfg(not(windows))]
+pub fn scan(_pid: u32) -> Result<Option<AmsiVerdict>, String> {
+    Ok(None)
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+fn worst(verdict: Option<AmsiVerdict>, region: AmsiVerdict) -> AmsiVerdict {
+    match (verdict, region) {
+        (Some(AmsiVerdict::Detected), _) | (_, AmsiVerdict::Detected) => AmsiVerdict::Detected,
+        (Some(AmsiVerdict::NotDetected), _) | (_, AmsiVerdict::NotDetected) => AmsiVerdict::NotDetected,
+        _ => AmsiVerdict::Clean,
+    }
+}
@@ -0,0 +1,119 @@
+//! Writes to the file servers: a ransomware on a workstation encrypts the shares it can reach,
+//! through UNC paths (*\\server\share*) or mapped drives, which hurts far more users than the
+//! workstation itself.
+//!
+//! The driver messages on a remote volume are tagged by the fetch stage. The remote volumes are:
+//! * the UNC paths and the network redirectors (*\Device\Mup*);
+//! * on Windows, the drives mapped by the users (*HKEY_USERS\<sid>\Network*);
+//! * on Linux, the NFS, SMB and SSHFS mount points of */proc/mounts*.
+//!
+//! The last two are refreshed every [REFRESH_INTERVAL]. The remote writes of a gid are features of
+//! the model, and once it has written *NETWORK_SHARE_MIN_FILES* remote files, it is judged with
+//! *NETWORK_SHARE_THRESHOLD* if it is lower than its threshold.
+
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::driver_com::shared_def::IOMessage;
+
+/// Period of the detection of the mapped drives and mount points.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Prefixes (lowercase) of the paths of the network redirectors.
+const REDIRECTORS: [&str; 3] = [r"\device\mup\", r"\device\lanmanredirector\", r"\device\webdavredirector\"];
+/// File systems of the remote mount points, on Linux.
+const REMOTE_FS: [&str; 7] = ["nfs", "nfs4", "cifs", "smb3", "smbfs", "fuse.sshfs", "9p"];
+
+#[derive(Debug, Default)]
+pub struct RemoteVolumes {
+    /// Mapped drives (*z:\\*) and mount points (*/mnt/share/*), lowercase
+    prefixes: Vec<String>,
+}
+
+impl RemoteVolumes {
+    pub fn detect() -> RemoteVolumes {
+        let volumes = RemoteVolumes {
+            prefixes: platform_prefixes(),
+        };
+        debug!(prefixes = ?volumes.prefixes, "Remote volumes");
+        volumes
+    }
+
+    pub fn is_remote(&self, path: &str) -> bool {
+        if path.starts_with(r"\\") && !path.starts_with(r"\\?\") && !path.starts_with(r"\\.\") {
+            return true;
+        }
+        let path = path.to_lowercase();
+        REDIRECTORS.iter().any(|r| path.starts_with(r)) || self.prefixes.iter().any(|p| path.starts_with(p.as_str()))
+    }
+
+    /// Tags *iomsg* if its file is on a remote volume.
+    pub fn tag(&self, iomsg: &mut IOMessage) {
+        iomsg.runtime_features.remote = self.is_remote(&iomsg.filepathstr);
+    }
+}
+
+/// The remote mount points of */proc/mounts*, with a trailing slash.
+pub fn parse_mounts(mounts: &str) -> Vec<String> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (_device, mount_point, fs) = (fields.next()?, fields.next()?, fields.next()?);
+            if !REMOTE_FS.contains(&fs) {
+                return None;
+            }
+            // spaces are escaped as \040
+            let mount_point = mount_point.replace(r"\040", " ").to_lowercase();
+            Some(format!("{}/", mount_point.trim_end_matches('/')))
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+fn platform_prefixes() -> Vec<String> {
+    use registry::{Hive, Security};
+
+    let mut prefixes = Vec::new();
+    let users = match Hive::Users.open("", Security::Read) {
+        Ok(users) => users,
+        Err(_) => return prefixes,
+    };
+    for sid in users.keys().flatten() {
+        if let Ok(network) = Hive::Users.open(format!(r"{}\Network", sid), Security::Read) {
+            for drive in network.keys().flatten() {
+                let prefix = format!(r"{}:\", drive.to_string().to_lowercase());
+                if !prefixes.contains(&prefix) {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+    }
+    prefixes
+}
+
+#[cfg(target_os = "linux")]
+fn platform_prefixes() -> Vec<String> {
+    std::fs::read_to_string("/proc/mounts").map(|mounts| parse_mounts(&mounts)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::netshare::{parse_mounts, RemoteVolumes};
+
+    #[test]
+    fn unc_paths_and_network_mounts_should_be_remote() {
+        let mounts = "/dev/sda1 / ext4 rw 0 0\n//fs01/finance /mnt/finance\\040share cifs rw 0 0\nfs02:/export /srv/nfs nfs4 rw 0 0\n";
+        let volumes = RemoteVolumes {
+            prefixes: parse_mounts(mounts),
+        };
+        assert_eq!(volumes.prefixes, vec!["/mnt/finance share/", "/srv/nfs/"]);
+        assert!(volumes.is_remote("/mnt/finance share/q3.xlsx"));
+        assert!(!volumes.is_remote("/srv/nfsdata/a.txt"));
+        assert!(volumes.is_remote(r"\\fs01\finance\q3.xlsx"));
+        assert!(volumes.is_remote(r"\Device\Mup\fs01\finance\q3.xlsx"));
+        assert!(!volumes.is_remote(r"\\?\C:\Users\bob\a.docx"));
+        assert!(!volumes.is_remote(r"C:\Users\bob\a.docx"));
+    }
+}
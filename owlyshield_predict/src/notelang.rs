@@ -0,0 +1,164 @@
+//! What a ransom note tells of its authors: its language, the ransom vocabulary it uses, and the
+//! family its known phrases point to. Reported with the note, and exported to the threat
+//! intelligence platforms ([crate::stix]).
+//!
+//! The language is guessed from the script of the letters (Cyrillic, CJK, Hangul, Arabic) or, for
+//! the Latin ones, from the most frequent words of each language. The family hints are only
+//! hints: the phrases of a note are easily copied by another family.
+
+use serde::Serialize;
+
+/// Stop words of a Latin language before it is guessed.
+const MIN_WORDS: usize = 3;
+/// Share of the letters in a script before its language is guessed.
+const MIN_SCRIPT_SHARE: f32 = 0.3;
+
+static STOP_WORDS: [(&str, &[&str]); 8] = [
+    ("en", &["the", "and", "your", "you", "are", "have", "to", "of", "will", "all", "is", "for", "with", "we"]),
+    ("fr", &["le", "la", "les", "vos", "vous", "et", "des", "est", "pour", "nous", "été", "avec", "une", "sont"]),
+    ("de", &["die", "der", "und", "ihre", "sie", "sind", "wir", "nicht", "mit", "werden", "alle", "ist", "zu", "das"]),
+    ("es", &["el", "los", "sus", "su", "archivos", "y", "que", "para", "han", "sido", "todos", "con", "una", "por"]),
+    ("it", &["il", "i", "tuoi", "sono", "che", "per", "di", "stati", "tutti", "con", "non", "una", "gli", "noi"]),
+    ("pt", &["os", "seus", "foram", "que", "para", "não", "com", "uma", "todos", "nós", "você", "ao", "em", "do"]),
+    ("nl", &["de", "het", "uw", "zijn", "en", "van", "niet", "met", "wij", "alle", "een", "voor", "worden", "je"]),
+    ("tr", &["ve", "bir", "tüm", "için", "bu", "dosyalarınız", "ile", "olarak", "sizin", "biz", "değil", "da", "de", "çok"]),
+];
+
+/// Ransom vocabulary, in the languages of [STOP_WORDS] and Russian.
+static KEYWORDS: [&str; 28] = [
+    "bitcoin", "btc", "monero", "xmr", "wallet", "tor browser", ".onion", "tox", "jabber", "telegram",
+    "decrypt", "private key", "encrypted", "ransom", "payment", "deadline", "discount", "leak", "stolen",
+    "chiffr", "rançon", "verschlüsselt", "lösegeld", "cifrad", "rescate", "crittograf", "зашифрован", "выкуп",
+];
+
+/// Phrases of the notes of the well known families, lowercase.
+static FAMILY_PHRASES: [(&str, &str); 18] = [
+    ("LockBit", "lockbit"),
+    ("LockBit", "all your important files are stolen and encrypted"),
+    ("Conti", "all of your files are currently encrypted by conti strain"),
+    ("REvil", "[+] whats happen? [+]"),
+    ("REvil", "your files are encrypted, and currently unavailable"),
+    ("Ryuk", "balance of shadow universe"),
+    ("WannaCry", "ooops, your important files are encrypted"),
+    ("WannaCry", "wana decrypt0r"),
+    ("STOP/Djvu", "don't worry, you can return all your files!"),
+    ("STOP/Djvu", "price of private key and decrypt software is"),
+    ("Phobos", "all your files have been encrypted due to a security problem with your pc"),
+    ("BlackCat", "important files on your network was encrypted"),
+    ("Hive", "your network has been breached and all data were encrypted"),
+    ("Babuk", "your computers and servers are encrypted, backups are deleted"),
+    ("Black Basta", "your data are stolen and encrypted"),
+    ("Maze", "maze ransomware"),
+    ("GandCrab", "gandcrab"),
+    ("Akira", "akira"),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NoteLanguage {
+    /// ISO 639-1
+    pub code: &'static str,
+    /// From 0 to 1
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FamilyHint {
+    pub family: &'static str,
+    /// Found in the note
+    pub phrase: &'static str,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NoteAnalysis {
+    pub language: Option<NoteLanguage>,
+    pub keywords: Vec<&'static str>,
+    pub family_hints: Vec<FamilyHint>,
+}
+
+impl NoteAnalysis {
+    pub fn of(text: &str) -> NoteAnalysis {
+        let lowercase = text.to_lowercase();
+        let mut family_hints: Vec<FamilyHint> = Vec::new();
+        for (family, phrase) in FAMILY_PHRASES.iter() {
+            if lowercase.contains(phrase) && !family_hints.iter().any(|hint| hint.family == *family) {
+                family_hints.push(FamilyHint { family, phrase });
+            }
+        }
+        NoteAnalysis {
+            language: language(&lowercase),
+            keywords: KEYWORDS.iter().filter(|keyword| lowercase.contains(*keyword)).copied().collect(),
+            family_hints,
+        }
+    }
+
+    pub fn families(&self) -> Vec<&'static str> {
+        self.family_hints.iter().map(|hint| hint.family).collect()
+    }
+}
+
+/// Of a lowercase text.
+fn language(text: &str) -> Option<NoteLanguage> {
+    let (mut letters, mut cyrillic, mut han, mut kana, mut hangul, mut arabic) = (0, 0, 0, 0, 0, 0);
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        match c {
+            '\u{0400}'..='\u{04ff}' => cyrillic += 1,
+            '\u{3040}'..='\u{30ff}' => kana += 1,
+            '\u{4e00}'..='\u{9fff}' => han += 1,
+            '\u{ac00}'..='\u{d7af}' => hangul += 1,
+            '\u{0600}'..='\u{06ff}' => arabic += 1,
+            _ => {}
+        }
+    }
+    let ukrainian = text.contains(['і', 'ї', 'є']);
+    let scripts = [
+        (if ukrainian { "uk" } else { "ru" }, cyrillic),
+        (if kana > 0 { "ja" } else { "zh" }, han + kana),
+        ("ko", hangul),
+        ("ar", arabic),
+    ];
+    if let Some((code, count)) = scripts.iter().max_by_key(|(_, count)| *count) {
+        let share = *count as f32 / letters.max(1) as f32;
+        if share >= MIN_SCRIPT_SHARE {
+            return Some(NoteLanguage { code, confidence: share.min(1.0) });
+        }
+    }
+
+    let words: Vec<&str> = text.split(|c: char| !c.is_alphabetic()).filter(|w| !w.is_empty()).collect();
+    let hits: Vec<(&'static str, usize)> = STOP_WORDS
+        .iter()
+        .map(|(code, stop_words)| (*code, words.iter().filter(|w| stop_words.contains(w)).count()))
+        .collect();
+    let total: usize = hits.iter().map(|(_, count)| count).sum();
+    let (code, best) = hits.iter().copied().max_by_key(|(_, count)| *count)?;
+    if best < MIN_WORDS {
+        return None;
+    }
+    Some(NoteLanguage { code, confidence: best as f32 / total as f32 })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notelang::NoteAnalysis;
+
+    #[test]
+    fn notes_should_give_their_language_and_family() {
+        let lockbit = "~~~ LockBit 3.0 ~~~\nAll your important files are stolen and encrypted!\n\
+            You must find the restore-my-files.txt file and follow the instructions. Pay in Bitcoin with the Tor Browser.";
+        let analysis = NoteAnalysis::of(lockbit);
+        assert_eq!(analysis.language.as_ref().unwrap().code, "en");
+        assert_eq!(analysis.families(), vec!["LockBit"]);
+        assert_eq!(analysis.family_hints[0].phrase, "lockbit");
+        assert_eq!(analysis.keywords, vec!["bitcoin", "tor browser", "encrypted", "stolen"]);
+
+        let french = "Tous vos fichiers ont été chiffrés. Pour les récupérer, vous devez payer une rançon avec le lien ci-dessous.";
+        let analysis = NoteAnalysis::of(french);
+        assert_eq!(analysis.language.unwrap().code, "fr");
+        assert!(analysis.family_hints.is_empty());
+        assert_eq!(analysis.keywords, vec!["chiffr", "rançon"]);
+
+        let russian = "Все ваши файлы зашифрованы. Для получения ключа заплатите выкуп.";
+        assert_eq!(NoteAnalysis::of(russian).language.unwrap().code, "ru");
+        assert_eq!(NoteAnalysis::of("0123456789 !!!").language, None);
+    }
+}
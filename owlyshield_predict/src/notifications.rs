@@ -1,23 +1,39 @@
+#[cfg(windows)]
 use std::path::Path;
+#[cfg(windows)]
 use std::ptr::null_mut;
 
+#[cfg(windows)]
 use bindings::Windows::Win32::Foundation::{CloseHandle, BOOL, HANDLE, PWSTR};
+#[cfg(windows)]
 use bindings::Windows::Win32::Security::*;
+#[cfg(windows)]
 use bindings::Windows::Win32::System::Diagnostics::Debug::GetLastError;
+#[cfg(windows)]
 use bindings::Windows::Win32::System::RemoteDesktop::*;
+#[cfg(windows)]
 use bindings::Windows::Win32::System::Threading::CreateProcessAsUserW;
+#[cfg(windows)]
 use bindings::Windows::Win32::System::Threading::CREATE_NEW_CONSOLE;
+#[cfg(windows)]
 use bindings::Windows::Win32::System::Threading::{PROCESS_INFORMATION, STARTUPINFOW};
-use log::error;
+#[cfg(windows)]
+use tracing::error;
+#[cfg(not(windows))]
+use tracing::info;
+#[cfg(windows)]
 use widestring::{U16CString, UCString};
 
-use crate::config::{Config, Param};
+#[cfg(windows)]
+use crate::config::Param;
+use crate::config::Config;
 
+#[cfg(windows)]
 pub fn toast(config: &Config, message: &str, report_path: &str) {
-    let toastapp_dir = Path::new(&config[Param::UtilsPath]);
+    let toastapp_dir = config.get_path(Param::UtilsPath);
     let toastapp_path = toastapp_dir.join("RustWindowsToast.exe");
-    let app_id = &config[Param::AppId];
-    let logo_path = Path::new(&config[Param::ConfigPath])
+    let app_id = config.get_str(Param::AppId);
+    let logo_path = config.get_path(Param::ConfigPath)
         .parent()
         .unwrap()
         .join("logo.ico");
@@ -74,6 +90,13 @@ pub fn toast(config: &Config, message: &str, report_path: &str) {
     }
 }
 
+#[cfg(windows)]
 pub fn str_to_pwstr(str: &str) -> UCString<u16> {
     U16CString::from_str(str).unwrap()
 }
+
+/// There are no toasts outside of Windows: the message is only logged.
+#[cfg(not(windows))]
+pub fn toast(_config: &Config, message: &str, report_path: &str) {
+    info!(report = report_path, "{}", message);
+}
@@ -0,0 +1,95 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// Path of the executable of *pid*, None if the process has exited or is a kernel thread.
+pub fn exepath_from_pid(pid: u32) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
+
+/// Freezes *pid* with *SIGSTOP*.
+pub fn suspend_pid(pid: u32) {
+    let _ = signal(pid, libc::SIGSTOP);
+}
+
+/// Resumes a process frozen by [suspend_pid] with *SIGCONT*. If *kill_on_exit*, the process is
+/// killed instead.
+pub fn resume_pid(pid: u32, kill_on_exit: bool) {
+    let _ = signal(pid, if kill_on_exit { libc::SIGKILL } else { libc::SIGCONT });
+}
+
+/// Kills *pid* with *SIGKILL*. Returns the errno on failure.
+pub fn kill_pid(pid: u32) -> Result<(), i32> {
+    signal(pid, libc::SIGKILL)
+}
+
+/// Kills *pids* with *SIGKILL*, there are no job objects on Linux. Returns the number of
+/// processes killed, or the last errno if none.
+pub fn terminate_group(pids: &[u32]) -> Result<usize, i32> {
+    let mut killed = 0;
+    let mut error = 0;
+    for pid in pids {
+        match kill_pid(*pid) {
+            Ok(()) => killed += 1,
+            Err(errno) => error = errno,
+        }
+    }
+    if killed == 0 {
+        Err(error)
+    } else {
+        Ok(killed)
+    }
+}
+
+/// Sends *sig* to *pid*. Returns the errno on failure.
+pub fn signal(pid: u32, sig: libc::c_int) -> Result<(), i32> {
+    if unsafe { libc::kill(pid as libc::pid_t, sig) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(0))
+    }
+}
+
+/// Process group of *pid* (field 5 of */proc/pid/stat*), the gid of its family on Linux: a shell
+/// job or a process and the children it did not move to another group.
+pub fn process_group(pid: u32) -> Option<u32> {
+    parse_process_group(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+/// Kills all the processes of the group *pgid* with *SIGKILL*. Returns the errno on failure.
+pub fn kill_group(pgid: u32) -> Result<(), i32> {
+    if unsafe { libc::killpg(pgid as libc::pid_t, libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(0))
+    }
+}
+
+/// The command name (field 2) is between parentheses and may contain spaces or parentheses.
+fn parse_process_group(stat: &str) -> Option<u32> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    // state, ppid, pgrp
+    fields.split_whitespace().nth(2)?.parse().ok()
+}
+
+/// NIS domain of the machine (*/proc/sys/kernel/domainname*), None if unset.
+pub fn domain() -> Option<String> {
+    let domain = fs::read_to_string("/proc/sys/kernel/domainname").ok()?;
+    let domain = domain.trim();
+    if domain.is_empty() || domain == "(none)" {
+        None
+    } else {
+        Some(domain.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::os::linux::parse_process_group;
+
+    #[test]
+    fn process_group_should_follow_the_command_name() {
+        let stat = "4242 (my (evil) cmd) S 1 4241 4200 34817 4242 4194560 130 0 0 0";
+        assert_eq!(parse_process_group(stat), Some(4241));
+        assert_eq!(parse_process_group("4242 (cmd"), None);
+    }
+}
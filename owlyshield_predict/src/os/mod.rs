@@ -0,0 +1,13 @@
+//! Operations on the monitored processes which depend on the platform: path of the executable,
+//! suspension, resumption and termination, alone or as a group (job objects). Also the domain of the machine, for
+//! [crate::identity].
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(target_os = "linux")]
+pub use self::linux::*;
+#[cfg(windows)]
+pub use self::windows::*;
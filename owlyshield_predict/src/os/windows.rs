@@ -0,0 +1,142 @@
+use std::path::PathBuf;
+
+use registry::{Hive, Security};
+
+use bindings::Windows::Win32::Foundation::{CloseHandle, BOOL, HANDLE, HINSTANCE, PSTR, PWSTR};
+use bindings::Windows::Win32::System::Diagnostics::Debug::{
+    DebugActiveProcess, DebugActiveProcessStop, DebugSetProcessKillOnExit, GetLastError,
+};
+use bindings::Windows::Win32::System::JobObjects::{
+    AssignProcessToJobObject, CreateJobObjectW, IsProcessInJob, TerminateJobObject,
+};
+use bindings::Windows::Win32::System::ProcessStatus::K32GetModuleFileNameExA;
+use bindings::Windows::Win32::System::Threading::{
+    OpenProcess, TerminateProcess, PROCESS_QUERY_INFORMATION, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_SET_QUOTA,
+    PROCESS_TERMINATE, PROCESS_VM_READ,
+};
+
+/// Path of the executable of *pid*, None if the process has exited or cannot be opened.
+pub fn exepath_from_pid(pid: u32) -> Option<PathBuf> {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid);
+        if handle.is_invalid() || handle.0 == 0 {
+            //println!("ERROR: Invalid Handle: {} - {}", drivermsg.pid, GetLastError().0);
+        } else {
+            let mut buffer: Vec<u8> = Vec::new();
+            buffer.resize(1024, 0);
+            // let res = GetModuleFileNameA(HINSTANCE(handle.0), PSTR(buffer.as_mut_ptr()), 1024);
+            let res =
+                K32GetModuleFileNameExA(handle, HINSTANCE(0), PSTR(buffer.as_mut_ptr()), 1024);
+            CloseHandle(handle);
+            if res == 0 {
+                let _errorcode = GetLastError().0;
+            } else {
+                let pathbuf =
+                    PathBuf::from(String::from_utf8_unchecked(buffer).trim_matches(char::from(0)));
+                return Some(pathbuf);
+            }
+        }
+    }
+    None
+}
+
+/// Freezes *pid* by attaching to it as a debugger.
+pub fn suspend_pid(pid: u32) {
+    unsafe {
+        DebugActiveProcess(pid);
+    }
+}
+
+/// Detaches from a process frozen by [suspend_pid]. If *kill_on_exit*, the process is killed
+/// instead of resumed.
+pub fn resume_pid(pid: u32, kill_on_exit: bool) {
+    unsafe {
+        DebugSetProcessKillOnExit(kill_on_exit);
+        DebugActiveProcessStop(pid);
+    }
+}
+
+/// Terminates *pid*. Returns the last error on failure.
+pub fn kill_pid(pid: u32) -> Result<(), i32> {
+    unsafe {
+        let handle = OpenProcess(PROCESS_TERMINATE, false, pid);
+        if handle.is_invalid() || handle.0 == 0 {
+            return Err(GetLastError().0 as i32);
+        }
+        let res = if TerminateProcess(handle, 1).as_bool() { Ok(()) } else { Err(GetLastError().0 as i32) };
+        CloseHandle(handle);
+        res
+    }
+}
+
+/// Is *pid* in a job object (see [crate::container])?
+pub fn is_in_job(pid: u32) -> bool {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid);
+        if handle.is_invalid() || handle.0 == 0 {
+            return false;
+        }
+        let mut in_job = BOOL(0);
+        let res = IsProcessInJob(handle, HANDLE(0), &mut in_job).as_bool() && in_job.as_bool();
+        CloseHandle(handle);
+        res
+    }
+}
+
+/// Terminates *pids* at once: they are assigned to a new job object, which is terminated, so that
+/// the children they start meanwhile are terminated too. The processes already in a job are
+/// assigned to a nested job, those which cannot be assigned are terminated one by one. Returns the
+/// number of processes terminated, or the last error if none.
+pub fn terminate_group(pids: &[u32]) -> Result<usize, i32> {
+    unsafe {
+        let job = CreateJobObjectW(std::ptr::null_mut(), PWSTR(std::ptr::null_mut()));
+        if job.is_invalid() || job.0 == 0 {
+            return Err(GetLastError().0 as i32);
+        }
+        let mut assigned = 0;
+        let mut terminated = 0;
+        let mut error = 0;
+        for pid in pids {
+            let handle = OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, false, *pid);
+            let in_job = !handle.is_invalid() && handle.0 != 0 && AssignProcessToJobObject(job, handle).as_bool();
+            if handle.0 != 0 {
+                CloseHandle(handle);
+            }
+            if in_job {
+                assigned += 1;
+            } else {
+                match kill_pid(*pid) {
+                    Ok(()) => terminated += 1,
+                    Err(code) => error = code,
+                }
+            }
+        }
+        if assigned > 0 {
+            if TerminateJobObject(job, 1).as_bool() {
+                terminated += assigned;
+            } else {
+                error = GetLastError().0 as i32;
+            }
+        }
+        CloseHandle(job);
+        if terminated == 0 {
+            Err(error)
+        } else {
+            Ok(terminated)
+        }
+    }
+}
+
+/// DNS domain of the machine, None if it is not joined to a domain.
+pub fn domain() -> Option<String> {
+    let regkey = Hive::LocalMachine
+        .open(r"SYSTEM\CurrentControlSet\Services\Tcpip\Parameters", Security::Read)
+        .ok()?;
+    let domain = regkey.value("Domain").ok()?.to_string();
+    let domain = domain.trim_matches(char::from(0)).trim();
+    if domain.is_empty() {
+        None
+    } else {
+        Some(domain.to_string())
+    }
+}
@@ -0,0 +1,220 @@
+//! Scan of the executables dropped by a gid: the loaders and droppers write their payload before
+//! running it.
+//!
+//! The files created (*FILE_CHANGE_NEW_FILE*) with a PE extension are queued once closed, with
+//! their content. A worker scans them with the static model ([TfLiteStatic::make_prediction]) and
+//! the YARA rules of *YARA_RULES* ([crate::yara]). The highest score of the payloads, 1 for a YARA
+//! match, is the *dropped_payload_score* feature of the gid.
+//!
+//! At most *DROPPED_SCAN_RATE* payloads of a gid are queued per minute: beyond, in a burst of
+//! creations, only one in *DROPPED_SCAN_RATE* is sampled.
+
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+use crate::config::{Config, Param};
+use crate::prediction_static::TfLiteStatic;
+use crate::process::ProcessRecord;
+use crate::utils::extended_path;
+use crate::yara;
+
+/// Lowercase extensions of the executables scanned.
+const PE_EXTENSIONS: [&str; 6] = ["exe", "dll", "scr", "sys", "cpl", "ocx"];
+/// Payloads created and not closed yet. Beyond, the new ones are ignored.
+const MAX_PENDING: usize = 100;
+/// Payloads waiting to be scanned. Beyond, the new ones are skipped.
+const MAX_QUEUED: usize = 32;
+/// Scanned payloads kept for the incident reports.
+const MAX_KEPT: usize = 16;
+/// Payloads scanned by a worker per driver message of the gid, so as not to delay it.
+const MAX_SCANNED_PER_MESSAGE: usize = 1;
+/// Score from which a payload is logged.
+const SUSPICIOUS_SCORE: f32 = 0.5;
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A scanned payload.
+#[derive(Debug, Clone)]
+pub struct Payload {
+    pub path: PathBuf,
+    /// Of the static model, None if the file is not a valid PE
+    pub prediction: Option<f32>,
+    /// Names of the YARA rules matched
+    pub rules: Vec<String>,
+}
+
+impl Payload {
+    pub fn score(&self) -> f32 {
+        if self.rules.is_empty() {
+            self.prediction.unwrap_or(0.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Payloads dropped by a gid.
+#[derive(Debug)]
+pub struct DroppedPayloads {
+    rate: usize,
+    pending: HashSet<Arc<str>>,
+    queued: VecDeque<Arc<str>>,
+    window_start: SystemTime,
+    in_window: usize,
+    /// Beyond the rate, payloads closed since the last sampled one
+    unsampled: usize,
+    /// Not queued, because of the sampling or of a full queue
+    pub skipped: usize,
+    /// The last scanned ones
+    pub scanned: VecDeque<Payload>,
+    max_score: f32,
+}
+
+impl DroppedPayloads {
+    pub fn from(config: &Config) -> DroppedPayloads {
+        DroppedPayloads::new(config.get_usize(Param::DroppedScanRate))
+    }
+
+    pub fn new(rate: usize) -> DroppedPayloads {
+        DroppedPayloads {
+            rate,
+            pending: HashSet::new(),
+            queued: VecDeque::new(),
+            window_start: SystemTime::UNIX_EPOCH,
+            in_window: 0,
+            unsampled: 0,
+            skipped: 0,
+            scanned: VecDeque::new(),
+            max_score: 0.0,
+        }
+    }
+
+    /// A file has been created.
+    pub fn on_created(&mut self, fpath: &Arc<str>) {
+        if self.rate > 0 && self.pending.len() < MAX_PENDING && is_pe(Path::new(&**fpath)) {
+            self.pending.insert(fpath.clone());
+        }
+    }
+
+    pub fn is_pending(&self, fpath: &str) -> bool {
+        self.pending.contains(fpath)
+    }
+
+    /// A created file has been closed: it is queued, unless sampled out.
+    pub fn on_closed(&mut self, fpath: &str, now: SystemTime) {
+        let fpath = match self.pending.take(fpath) {
+            Some(fpath) => fpath,
+            None => return,
+        };
+        if now.duration_since(self.window_start).unwrap_or_default() >= WINDOW {
+            self.window_start = now;
+            self.in_window = 0;
+            self.unsampled = 0;
+        }
+        self.in_window += 1;
+        let sampled = if self.in_window <= self.rate {
+            true
+        } else {
+            self.unsampled += 1;
+            self.unsampled == self.rate
+        };
+        if sampled {
+            self.unsampled = 0;
+        }
+        if sampled && self.queued.len() < MAX_QUEUED && !self.queued.contains(&fpath) {
+            self.queued.push_back(fpath);
+        } else {
+            self.skipped += 1;
+        }
+    }
+
+    /// The next payloads to scan.
+    pub fn take_queued(&mut self, max: usize) -> Vec<Arc<str>> {
+        let n = max.min(self.queued.len());
+        self.queued.drain(..n).collect()
+    }
+
+    pub fn on_scanned(&mut self, payload: Payload) {
+        self.max_score = self.max_score.max(payload.score());
+        if self.scanned.len() >= MAX_KEPT {
+            self.scanned.pop_front();
+        }
+        self.scanned.push_back(payload);
+    }
+
+    /// Value of the *dropped_payload_score* feature.
+    pub fn max_score(&self) -> f32 {
+        self.max_score
+    }
+}
+
+/// Scans the next payloads queued by *proc*.
+pub fn scan_queued(config: &Config, proc: &mut ProcessRecord, tflite_static: &TfLiteStatic) {
+    for fpath in proc.payloads.take_queued(MAX_SCANNED_PER_MESSAGE) {
+        let path = PathBuf::from(&*fpath);
+        let rules = match yara::scan(config, &extended_path(&path)) {
+            Ok(rules) => rules,
+            Err(e) => {
+                warn!(path = %path.display(), "YARA scan failed: {}", e);
+                Vec::new()
+            }
+        };
+        let payload = Payload {
+            prediction: tflite_static.make_prediction(&extended_path(&path)),
+            path,
+            rules,
+        };
+        if payload.score() >= SUSPICIOUS_SCORE {
+            info!(
+                gid = proc.gid,
+                appname = %proc.appname,
+                path = %payload.path.display(),
+                prediction = payload.prediction.unwrap_or(0.0),
+                rules = %payload.rules.join(", "),
+                "Suspicious payload dropped"
+            );
+        }
+        proc.payloads.on_scanned(payload);
+    }
+}
+
+fn is_pe(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| PE_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use crate::payloads::{DroppedPayloads, Payload};
+
+    #[test]
+    fn payload_bursts_should_be_sampled() {
+        let now = SystemTime::now();
+        let mut payloads = DroppedPayloads::new(3);
+        for i in 0..10 {
+            let fpath: Arc<str> = Arc::from(format!(r"C:\Users\bob\AppData\Local\Temp\{}.DLL", i));
+            payloads.on_created(&fpath);
+            payloads.on_closed(&fpath, now + Duration::from_millis(i));
+        }
+        let ignored: Arc<str> = Arc::from(r"C:\Users\bob\Documents\report.docx");
+        payloads.on_created(&ignored);
+        assert!(!payloads.is_pending(&ignored));
+        // the first 3, then one in 3: the 6th and the 9th
+        let queued = payloads.take_queued(10);
+        let names: Vec<&str> = queued.iter().map(|fpath| &fpath[fpath.len() - 5..]).collect();
+        assert_eq!(names, vec!["0.DLL", "1.DLL", "2.DLL", "5.DLL", "8.DLL"]);
+        assert_eq!(payloads.skipped, 5);
+
+        payloads.on_scanned(Payload { path: PathBuf::from(&*queued[0]), prediction: Some(0.3), rules: Vec::new() });
+        payloads.on_scanned(Payload { path: PathBuf::from(&*queued[1]), prediction: None, rules: vec![String::from("CobaltStrike_Beacon")] });
+        payloads.on_scanned(Payload { path: PathBuf::from(&*queued[2]), prediction: Some(0.6), rules: Vec::new() });
+        assert_eq!(payloads.max_score(), 1.0);
+    }
+}
@@ -0,0 +1,354 @@
+//! State of the monitored gids kept across the restarts of the service (crash, update), so that
+//! an attack in progress does not start over with a blank behavioural history.
+//!
+//! With *PERSIST_STATE*, the fetch stage writes a [GidSnapshot] of each gid to
+//! *DebugPath\gids_state.msgpack* every [SAVE_INTERVAL] and when the service stops: the counters,
+//! the retained elements of the sets of files and directories, the prediction matrix and the past
+//! predictions. On startup, the snapshots younger than [MAX_AGE] whose executable is still running
+//! under one of their pids are loaded. The record created for the first driver message of such a
+//! gid starts from its snapshot: same gid (the driver kept running), or one of its pids with the
+//! same executable (the driver was restarted too, and numbers the gids again).
+//!
+//! The sets beyond *HISTORY_MAX_ENTRIES* are estimated (see [crate::sketch]): only their retained
+//! elements are saved, so their counts may restart lower.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sysinfo::{ProcessExt, System, SystemExt};
+use tracing::{error, info, warn};
+
+use crate::config::{Config, Param};
+use crate::decay::DecayedActivity;
+use crate::prediction::input_tensors::RollingFeatures;
+use crate::prediction::{PredictionValues, Predictions};
+use crate::process::{FileId, ProcessRecord};
+use crate::sketch::BoundedSet;
+
+pub static STATE_FILE_NAME: &str = "gids_state.msgpack";
+/// Period of the saves.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Older snapshots are not loaded.
+pub const MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// The behavioural history of a gid.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GidSnapshot {
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub pids: Vec<u32>,
+    pub time_started: SystemTime,
+    pub driver_msg_count: usize,
+    /// ops_read, ops_setinfo, ops_written, ops_open, ops_written_remote
+    pub ops: [u64; 5],
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub entropy_read: f64,
+    pub entropy_written: f64,
+    /// bytes_size_empty to bytes_size_huge
+    pub bytes_sizes: [u64; 6],
+    pub files_magic_checked: usize,
+    pub files_magic_mismatch: usize,
+    pub clusters: usize,
+    pub clusters_max_size: usize,
+    #[serde(default)]
+    pub decayed: DecayedActivity,
+    /// files_read, files_renamed, files_opened, files_written, files_deleted
+    pub file_ids: [Vec<(u64, Vec<u8>)>; 5],
+    /// fpaths_created, fpaths_updated, dirs_with_files_created, dirs_with_files_updated,
+    /// dirs_with_files_opened
+    pub paths: [Vec<String>; 5],
+    pub prediction_matrix: Vec<Vec<f32>>,
+    pub predictions: Vec<PredictionValues>,
+    pub is_malicious: bool,
+    pub would_kill: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    saved: SystemTime,
+    gids: Vec<GidSnapshot>,
+}
+
+impl GidSnapshot {
+    pub fn of(proc: &ProcessRecord) -> GidSnapshot {
+        let file_ids = |set: &BoundedSet<FileId>| -> Vec<(u64, Vec<u8>)> {
+            set.iter().map(|id| (id.volume_serial, id.file_id.clone())).collect()
+        };
+        let paths = |set: &BoundedSet<Arc<str>>| -> Vec<String> { set.iter().map(|p| p.to_string()).collect() };
+        GidSnapshot {
+            gid: proc.gid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            pids: proc.pids.iter().copied().collect(),
+            time_started: proc.time_started,
+            driver_msg_count: proc.driver_msg_count,
+            ops: [proc.ops_read, proc.ops_setinfo, proc.ops_written, proc.ops_open, proc.ops_written_remote],
+            bytes_read: proc.bytes_read,
+            bytes_written: proc.bytes_written,
+            entropy_read: proc.entropy_read,
+            entropy_written: proc.entropy_written,
+            bytes_sizes: [
+                proc.bytes_size_empty,
+                proc.bytes_size_tiny,
+                proc.bytes_size_small,
+                proc.bytes_size_medium,
+                proc.bytes_size_large,
+                proc.bytes_size_huge,
+            ],
+            files_magic_checked: proc.files_magic_checked,
+            files_magic_mismatch: proc.files_magic_mismatch,
+            clusters: proc.clusters,
+            clusters_max_size: proc.clusters_max_size,
+            decayed: proc.decayed.clone(),
+            file_ids: [
+                file_ids(&proc.files_read),
+                file_ids(&proc.files_renamed),
+                file_ids(&proc.files_opened),
+                file_ids(&proc.files_written),
+                file_ids(&proc.files_deleted),
+            ],
+            paths: [
+                paths(&proc.fpaths_created),
+                paths(&proc.fpaths_updated),
+                paths(&proc.dirs_with_files_created),
+                paths(&proc.dirs_with_files_updated),
+                paths(&proc.dirs_with_files_opened),
+            ],
+            prediction_matrix: proc.prediction_matrix.iter().map(<[f32]>::to_vec).collect(),
+            predictions: proc.predictions.to_vec(),
+            is_malicious: proc.is_malicious,
+            would_kill: proc.would_kill,
+        }
+    }
+
+    /// Adds the history to *proc*, a record just created for the same process family.
+    pub fn restore(self, proc: &mut ProcessRecord) {
+        proc.time_started = self.time_started;
+        proc.pids.extend(self.pids);
+        proc.driver_msg_count += self.driver_msg_count;
+        let [ops_read, ops_setinfo, ops_written, ops_open, ops_written_remote] = self.ops;
+        proc.ops_read += ops_read;
+        proc.ops_setinfo += ops_setinfo;
+        proc.ops_written += ops_written;
+        proc.ops_open += ops_open;
+        proc.ops_written_remote += ops_written_remote;
+        proc.bytes_read += self.bytes_read;
+        proc.bytes_written += self.bytes_written;
+        proc.entropy_read += self.entropy_read;
+        proc.entropy_written += self.entropy_written;
+        let [empty, tiny, small, medium, large, huge] = self.bytes_sizes;
+        proc.bytes_size_empty += empty;
+        proc.bytes_size_tiny += tiny;
+        proc.bytes_size_small += small;
+        proc.bytes_size_medium += medium;
+        proc.bytes_size_large += large;
+        proc.bytes_size_huge += huge;
+        proc.files_magic_checked += self.files_magic_checked;
+        proc.files_magic_mismatch += self.files_magic_mismatch;
+        proc.clusters = proc.clusters.max(self.clusters);
+        proc.clusters_max_size = proc.clusters_max_size.max(self.clusters_max_size);
+        // restored before the first event of the record
+        proc.decayed = self.decayed;
+        let [read, renamed, opened, written, deleted] = self.file_ids;
+        for (set, ids) in [
+            (&mut proc.files_read, read),
+            (&mut proc.files_renamed, renamed),
+            (&mut proc.files_opened, opened),
+            (&mut proc.files_written, written),
+            (&mut proc.files_deleted, deleted),
+        ] {
+            for (volume_serial, file_id) in ids {
+                set.insert(FileId { volume_serial, file_id });
+            }
+        }
+        let [fpaths_created, fpaths_updated, dirs_created, dirs_updated, dirs_opened] = self.paths;
+        for (set, paths) in [
+            (&mut proc.fpaths_created, fpaths_created),
+            (&mut proc.fpaths_updated, fpaths_updated),
+            (&mut proc.dirs_with_files_created, dirs_created),
+            (&mut proc.dirs_with_files_updated, dirs_updated),
+            (&mut proc.dirs_with_files_opened, dirs_opened),
+        ] {
+            for path in paths {
+                set.insert(Arc::from(path));
+            }
+        }
+        // the rows computed since the restart are the most recent ones
+        let recent: Vec<Vec<f32>> = proc.prediction_matrix.iter().map(<[f32]>::to_vec).collect();
+        let mut prediction_matrix = RollingFeatures::new(proc.prediction_matrix.capacity_rows());
+        for row in self.prediction_matrix.into_iter().chain(recent) {
+            if let Err(e) = prediction_matrix.push_row(row) {
+                warn!(gid = proc.gid, "Invalid row in the saved state: {}", e);
+            }
+        }
+        proc.prediction_matrix = prediction_matrix;
+        let recent = proc.predictions.to_vec();
+        proc.predictions = Predictions::new();
+        for (time, file_ids_u, prediction) in self.predictions.into_iter().chain(recent) {
+            proc.predictions.register_prediction(time, file_ids_u, prediction);
+        }
+        proc.is_malicious |= self.is_malicious;
+        proc.would_kill |= self.would_kill;
+    }
+}
+
+/// Replaces the saved state by *gids*.
+pub fn save(config: &Config, gids: Vec<GidSnapshot>) {
+    let path = config.get_path(Param::DebugPath).join(STATE_FILE_NAME);
+    let state = StateFile {
+        saved: SystemTime::now(),
+        gids,
+    };
+    let res = rmp_serde::to_vec_named(&state)
+        .map_err(|e| e.to_string())
+        .and_then(|buf| write_atomic(&path, &buf).map_err(|e| e.to_string()));
+    if let Err(e) = res {
+        error!("Cannot save the state of the gids to {}: {}", path.display(), e);
+    }
+}
+
+/// Writes a temporary file then renames it, so that a crash never leaves a truncated state.
+fn write_atomic(path: &Path, buf: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, buf)?;
+    fs::rename(&tmp, path)
+}
+
+/// Snapshots loaded on startup, waiting for their process family to be seen again.
+#[derive(Debug, Default)]
+pub struct SavedGids {
+    gids: Mutex<Vec<GidSnapshot>>,
+}
+
+impl SavedGids {
+    /// The snapshots of the last run, if *PERSIST_STATE*, whose executable is still running.
+    pub fn load(config: &Config) -> SavedGids {
+        if !config.get_bool(Param::PersistState) {
+            return SavedGids::default();
+        }
+        let path = config.get_path(Param::DebugPath).join(STATE_FILE_NAME);
+        let state: StateFile = match fs::read(&path) {
+            Ok(buf) => match rmp_serde::from_read_ref(&buf) {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("Invalid saved state {}, ignored: {}", path.display(), e);
+                    return SavedGids::default();
+                }
+            },
+            Err(_) => return SavedGids::default(),
+        };
+        let mut system = System::new();
+        system.refresh_processes();
+        let running: HashMap<u32, PathBuf> = system
+            .processes()
+            .iter()
+            .map(|(pid, process)| (*pid as u32, process.exe().to_path_buf()))
+            .collect();
+        let gids = alive(state, &running, SystemTime::now());
+        if !gids.is_empty() {
+            info!(gids = gids.len(), "State of the running process families loaded from {}", path.display());
+        }
+        SavedGids { gids: Mutex::new(gids) }
+    }
+
+    /// Takes the snapshot of the family of the new *gid*, seen from *pid* running *exepath*.
+    pub fn take(&self, gid: u64, pid: u32, exepath: &Path) -> Option<GidSnapshot> {
+        let mut gids = self.gids.lock().unwrap();
+        if gids.is_empty() {
+            return None;
+        }
+        let i = gids
+            .iter()
+            .position(|s| s.exepath == exepath && s.gid == gid)
+            .or_else(|| gids.iter().position(|s| s.exepath == exepath && s.pids.contains(&pid)))?;
+        Some(gids.swap_remove(i))
+    }
+}
+
+/// The snapshots of *state*, if not too old, with a pid still *running* its executable.
+fn alive(state: StateFile, running: &HashMap<u32, PathBuf>, now: SystemTime) -> Vec<GidSnapshot> {
+    if now.duration_since(state.saved).unwrap_or(Duration::ZERO) > MAX_AGE {
+        return Vec::new();
+    }
+    state
+        .gids
+        .into_iter()
+        .filter(|s| s.pids.iter().any(|pid| running.get(pid) == Some(&s.exepath)))
+        .map(|mut s| {
+            let pids: HashSet<u32> = s.pids.iter().copied().filter(|pid| running.get(pid) == Some(&s.exepath)).collect();
+            s.pids = pids.into_iter().collect();
+            s
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use crate::persistence::{alive, GidSnapshot, SavedGids, StateFile, MAX_AGE};
+
+    fn snapshot(gid: u64, pids: Vec<u32>, exepath: &str) -> GidSnapshot {
+        GidSnapshot {
+            gid,
+            appname: String::from("app.exe"),
+            exepath: PathBuf::from(exepath),
+            pids,
+            time_started: SystemTime::now(),
+            driver_msg_count: 0,
+            ops: [0; 5],
+            bytes_read: 0,
+            bytes_written: 0,
+            entropy_read: 0.0,
+            entropy_written: 0.0,
+            bytes_sizes: [0; 6],
+            files_magic_checked: 0,
+            files_magic_mismatch: 0,
+            clusters: 0,
+            clusters_max_size: 0,
+            decayed: Default::default(),
+            file_ids: Default::default(),
+            paths: Default::default(),
+            prediction_matrix: Vec::new(),
+            predictions: Vec::new(),
+            is_malicious: false,
+            would_kill: false,
+        }
+    }
+
+    #[test]
+    fn running_families_should_be_reassociated() {
+        let running: HashMap<u32, PathBuf> = vec![
+            (10, PathBuf::from(r"C:\Temp\evil.exe")),
+            // pid reused by another executable
+            (20, PathBuf::from(r"C:\Windows\notepad.exe")),
+        ]
+        .into_iter()
+        .collect();
+        let now = SystemTime::now();
+        let state = || StateFile {
+            saved: now,
+            gids: vec![snapshot(1, vec![10, 11], r"C:\Temp\evil.exe"), snapshot(2, vec![20], r"C:\Temp\other.exe")],
+        };
+        let gids = alive(state(), &running, now);
+        assert_eq!(gids.len(), 1);
+        assert_eq!(gids[0].pids, vec![10]);
+        assert!(alive(state(), &running, now + MAX_AGE + Duration::from_secs(1)).is_empty());
+
+        let saved = SavedGids {
+            gids: std::sync::Mutex::new(gids),
+        };
+        // the driver was restarted: new gid, same pid
+        assert!(saved.take(7, 10, &PathBuf::from(r"C:\Windows\notepad.exe")).is_none());
+        assert_eq!(saved.take(7, 10, &PathBuf::from(r"C:\Temp\evil.exe")).map(|s| s.gid), Some(1));
+        assert!(saved.take(1, 10, &PathBuf::from(r"C:\Temp\evil.exe")).is_none());
+    }
+}
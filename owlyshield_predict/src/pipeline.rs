@@ -0,0 +1,669 @@
+//! Multi-threaded protection loop, so that multi-core servers under heavy IO keep up with the
+//! driver.
+//!
+//! The work is staged:
+//! 1. *fetch and parse*: the calling thread gets the [IOMessage]s from the [IoEventSource] (the
+//!    driver on Windows), tags those in the cloud sync folders ([crate::cloudsync]) and on the
+//!    network shares ([netshare]), and queues them by gid in the [Scheduler];
+//! 2. *aggregation, features, inference and action*: a pool of *PIPELINE_THREADS* workers (one
+//!    per core by default) runs [worker::process_drivermessage] on the queued messages.
+//!
+//! Messages of a gid are processed in order, by one worker at a time: an idle worker steals the
+//! whole queue of any gid which is not being processed. The [ProcessRecord] of that gid is taken
+//! out of the shared [Procs] while it is processed.
+//!
+//! Every [REAP_INTERVAL], the states of the gids whose processes have all exited for more than
+//! *GID_EXPIRY_GRACE* seconds are dropped, and a [ProcessTerminated] summary is emitted. The
+//! memory statistics ([intern::stats]) are logged, and the jobs of the [BackupAgents] are looked
+//! up, at the same time.
+//!
+//! The raw disk writes ([crate::rawdisk]) are reported as soon as they are fetched, and the handles of
+//! the monitored processes are audited every [RAW_DISK_AUDIT_INTERVAL]. The events detected by
+//! the workers ([crate::events]) are sent to the connectors after each fetch, and their kills are
+//! verified by the [KillVerifier].
+//!
+//! The monitored gids and the number of queued messages are published to the [AgentStatus] every
+//! [STATUS_INTERVAL], for the local API and the [crate::heartbeat].
+//!
+//! When the queued messages pile up, the minifilter is told to aggregate the irps by pid instead
+//! ([Backpressure]). The aggregates are fetched with the tamper attempts, and at each fetch while
+//! the backpressure is engaged.
+//!
+//! The active scheduled profile ([ProfileSchedule]) is refreshed every [profiles::CHECK_INTERVAL],
+//! the prevalences of the [Reputation] are saved every [reputation::SAVE_INTERVAL], and the
+//! [ExtensionProfiles], learnt from the reaped gids, every [extprofiles::SAVE_INTERVAL]. The
+//! network isolation is lifted once expired, checked every [isolation::CHECK_INTERVAL].
+//!
+//! With *PERSIST_STATE*, the state of the gids is saved every [persistence::SAVE_INTERVAL] and
+//! when the service stops. The states saved by the previous run are [SavedGids], restored in the
+//! records of their process families by the workers.
+//!
+//! While a WSL host is monitored, the Linux processes writing on the Windows drives are listed
+//! every [wsl::REFRESH_INTERVAL], in a background thread, and attributed to its gid.
+//!
+//! With *AV_COEXISTENCE*, the antivirus of the machine and the detections of Defender are queried
+//! in a background thread too, every [defender::DETECTIONS_INTERVAL], and published to the
+//! [AgentStatus].
+//!
+//! With *SYSMON*, the Sysmon events queued since the previous fetch are attributed to the gids of
+//! their pids ([Sysmon::attribute]), as are the keyboard and clipboard API calls traced with
+//! *INPUT_CAPTURE* ([InputCapture::attribute]).
+//!
+//! The processes created with a spoofed parent, reported by the minifilter with the tamper
+//! attempts, are attributed to their gids by [SpoofedParents::attribute].
+//!
+//! The gids split by the driver for a same attack are merged by the [GidMerger] before they are
+//! queued, and the merged families are pruned with the exited gids.
+//!
+//! The messages are also stamped with the privileges of the root of their gid, read by the
+//! [PrivilegeProbe] every [crate::privileges::PROBE_INTERVAL].
+//!
+//! The [SelfTest] checks that the driver messages of its helper process arrive, and reports an
+//! incident otherwise. These messages are consumed by the fetch stage.
+//!
+//! In the *LEARNING* mode, the reaped gids, and the running ones every
+//! [baseline::OBSERVATION_INTERVAL], are observed by the [Baseline].
+//!
+//! The fetched messages are also republished to the subscribers of the [Broker], if enabled.
+//!
+//! With *CAPTURE*, a thread answers the capture requests of the minifilter ([capture::serve]).
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time;
+use std::time::Instant;
+
+use sysinfo::SystemExt;
+use tracing::{debug, error, info, warn};
+
+use crate::anomaly::AnomalyModel;
+use crate::api::StopOnDrop;
+use crate::audit::AuditLog;
+use crate::backpressure::Backpressure;
+use crate::backup::BackupAgents;
+use crate::baseline;
+use crate::cloudsync;
+use crate::cloudsync::SyncRoots;
+use crate::baseline::{Baseline, Observed};
+use crate::broker::Broker;
+use crate::capture;
+use crate::config::{Config, KillPolicy, Mode, Param};
+use crate::connectors::connector::Connectors;
+use crate::defender;
+use crate::defender::Refresh;
+use crate::driver_com::shared_def::IOMessage;
+use crate::error::{ErrorPolicy, OwlyError};
+use crate::events::WorkerEvents;
+use crate::exclusions::Exclusions;
+use crate::extprofiles;
+use crate::extprofiles::ExtensionProfiles;
+use crate::gidmerge::GidMerger;
+use crate::inputcapture::InputCapture;
+use crate::integrity::SpoofedParents;
+use crate::intern;
+use crate::isolation;
+use crate::killcheck::KillVerifier;
+use crate::netshare;
+use crate::netshare::RemoteVolumes;
+use crate::persistence;
+use crate::persistence::{GidSnapshot, SavedGids};
+use crate::iosource::IoEventSource;
+use crate::prediction_static::TfLiteStatic;
+use crate::privileges::PrivilegeProbe;
+use crate::process::procs::Procs;
+use crate::process::{ProcessRecord, ProcessTerminated};
+use crate::profiles;
+use crate::profiles::ProfileSchedule;
+use crate::rawdisk::{RawDiskMonitor, RawDiskWrite};
+use crate::reputation;
+use crate::reputation::Reputation;
+use crate::routing::ModelRouter;
+use crate::selftest::SelfTest;
+use crate::service_ctl::Lifecycle;
+use crate::status::{AgentStatus, GidStatus};
+use crate::sysmon::Sysmon;
+use crate::whitelist::WhiteList;
+use crate::worker;
+use crate::wsl;
+use crate::wsl::LinuxProcess;
+
+/// Period of the search for exited gids.
+const REAP_INTERVAL: time::Duration = time::Duration::from_secs(10);
+/// Period of the search for disks opened for writing.
+const RAW_DISK_AUDIT_INTERVAL: time::Duration = time::Duration::from_secs(2);
+/// Period of the snapshots of the gids for the [AgentStatus].
+const STATUS_INTERVAL: time::Duration = time::Duration::from_secs(1);
+/// Consecutive failures to receive the driver messages before the error is returned, for the
+/// protection loop to be restarted by the [crate::watchdog], with a delay growing by
+/// [FETCH_RETRY_DELAY].
+pub const FETCH_RETRIES: u32 = 5;
+pub const FETCH_RETRY_DELAY: time::Duration = time::Duration::from_millis(500);
+
+/// Queues of messages by gid, with at most one worker per gid.
+pub struct Scheduler<T> {
+    state: Mutex<SchedulerState<T>>,
+    ready_cond: Condvar,
+}
+
+struct SchedulerState<T> {
+    queues: HashMap<u64, VecDeque<T>>,
+    /// Gids with queued messages and no worker, in arrival order.
+    ready: VecDeque<u64>,
+    /// Gids being processed by a worker.
+    busy: HashSet<u64>,
+    closed: bool,
+}
+
+impl<T> Scheduler<T> {
+    pub fn new() -> Scheduler<T> {
+        Scheduler {
+            state: Mutex::new(SchedulerState {
+                queues: HashMap::new(),
+                ready: VecDeque::new(),
+                busy: HashSet::new(),
+                closed: false,
+            }),
+            ready_cond: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, gid: u64, msg: T) {
+        let mut state = self.state.lock().unwrap();
+        let queue = state.queues.entry(gid).or_insert_with(VecDeque::new);
+        queue.push_back(msg);
+        if queue.len() == 1 && !state.busy.contains(&gid) {
+            state.ready.push_back(gid);
+            self.ready_cond.notify_one();
+        }
+    }
+
+    /// Waits for a gid with queued messages and takes them all. The gid is leased to the caller
+    /// until [Self::release]. Returns None once closed and empty.
+    pub fn take(&self) -> Option<(u64, Vec<T>)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(gid) = state.ready.pop_front() {
+                let msgs = state.queues.remove(&gid).map(Vec::from).unwrap_or_default();
+                state.busy.insert(gid);
+                return Some((gid, msgs));
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready_cond.wait(state).unwrap();
+        }
+    }
+
+    /// Ends the lease of *gid*, which is made ready again if messages were queued meanwhile.
+    pub fn release(&self, gid: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.busy.remove(&gid);
+        if state.queues.contains_key(&gid) {
+            state.ready.push_back(gid);
+            self.ready_cond.notify_one();
+        }
+    }
+
+    /// The workers finish the queued messages, then [Self::take] returns None.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready_cond.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Messages waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.state.lock().unwrap().queues.values().map(VecDeque::len).sum()
+    }
+}
+
+/// Closes the [Scheduler] on a panic: if a worker panics, [fetch] panics too, and if [fetch]
+/// panics, the workers return. The paused sync clients are resumed, and the whole loop is then
+/// restarted by the [crate::watchdog].
+struct PanicGuard<'s, T>(&'s Scheduler<T>, &'s Config);
+
+impl<T> Drop for PanicGuard<'_, T> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.close();
+            cloudsync::resume_clients(self.1);
+        }
+    }
+}
+
+/// Runs the live protection until a stop is requested and the queue of the *source* is drained.
+/// The errors which stop it are returned to the [crate::watchdog].
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    source: &dyn IoEventSource,
+    config: &Config,
+    whitelist: &WhiteList,
+    exclusions: &Exclusions,
+    lifecycle: &Lifecycle,
+    audit: &AuditLog,
+    status: &AgentStatus,
+    connectors: &Connectors,
+) -> Result<(), OwlyError> {
+    let threads = match config.get_usize(Param::PipelineThreads) {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    };
+    info!("Protection pipeline started with {} workers", threads);
+    let scheduler: Scheduler<IOMessage> = Scheduler::new();
+    let procs: Mutex<Procs> = Mutex::new(Procs::new());
+    let backup = BackupAgents::new();
+    let reputation = Reputation::from(config);
+    let extension_profiles = ExtensionProfiles::from(config);
+    let broker = Broker::from(config);
+    let broker_done = AtomicBool::new(false);
+    let capture_done = AtomicBool::new(false);
+    let events = WorkerEvents::new();
+    let saved = SavedGids::load(config);
+    // left suspended by a crash
+    cloudsync::resume_clients(config);
+
+    thread::scope(|s| {
+        let _broker_guard = StopOnDrop(&broker_done);
+        let _capture_guard = StopOnDrop(&capture_done);
+        // the threads already started return once the scheduler is closed
+        let spawned = |res: io::Result<()>, name: &'static str| {
+            res.map_err(|e| {
+                scheduler.close();
+                OwlyError::Thread(name, e)
+            })
+        };
+        for i in 0..threads {
+            let (scheduler, procs, backup, reputation, extension_profiles, worker_events, saved) =
+                (&scheduler, &procs, &backup, &reputation, &extension_profiles, &events, &saved);
+            let res = thread::Builder::new()
+                .name(format!("pipeline-{}", i))
+                .spawn_scoped(s, move || {
+                    run_worker(source, config, whitelist, exclusions, backup, reputation, extension_profiles, lifecycle, audit, status, worker_events, saved, scheduler, procs)
+                });
+            spawned(res.map(drop), "pipeline worker")?;
+        }
+        if let Some(broker) = &broker {
+            let broker_done = &broker_done;
+            let res = thread::Builder::new()
+                .name(String::from("broker"))
+                .spawn_scoped(s, move || broker.serve(broker_done));
+            spawned(res.map(drop), "broker")?;
+        }
+        if config.get_bool(Param::Capture) {
+            let capture_done = &capture_done;
+            let res = thread::Builder::new()
+                .name(String::from("capture"))
+                .spawn_scoped(s, move || capture::serve(source, config, capture_done));
+            spawned(res.map(drop), "capture")?;
+        }
+        let _guard = PanicGuard(&scheduler, config);
+        let fetched = fetch(source, config, exclusions, &backup, &reputation, &extension_profiles, broker.as_ref(), lifecycle, status, connectors, &events, &scheduler, &procs);
+        scheduler.close();
+        fetched
+    })
+}
+
+/// First stage, in the calling thread. Also runs the periodic tasks. Returns the errors of the
+/// *source* which are not transient, or still there after [FETCH_RETRIES].
+#[allow(clippy::too_many_arguments)]
+fn fetch(
+    source: &dyn IoEventSource,
+    config: &Config,
+    exclusions: &Exclusions,
+    backup: &BackupAgents,
+    reputation: &Reputation,
+    extension_profiles: &ExtensionProfiles,
+    broker: Option<&Broker>,
+    lifecycle: &Lifecycle,
+    status: &AgentStatus,
+    connectors: &Connectors,
+    worker_events: &WorkerEvents,
+    scheduler: &Scheduler<IOMessage>,
+    procs: &Mutex<Procs>,
+) -> Result<(), OwlyError> {
+    let mut events: Vec<IOMessage> = Vec::new();
+    let mut system = sysinfo::System::new_all();
+    let mut iteration = 0;
+    let kill_policy = config.get_kill_policy();
+    let grace = time::Duration::from_secs(config.get_usize(Param::GidExpiryGrace) as u64);
+    let mut last_reap = Instant::now();
+    let mut raw_disk = RawDiskMonitor::new();
+    let raw_disk_audit = config.get_bool(Param::RawDiskAudit);
+    let mut last_raw_disk_audit = Instant::now();
+    let mut last_status = Instant::now();
+    let mut baseline = (config.get_mode() == Mode::Learning).then(|| Baseline::from(config));
+    let mut last_observation = Instant::now();
+    let schedule = ProfileSchedule::from(config);
+    let mut last_schedule_check: Option<Instant> = None;
+    let mut fetch_failures = 0;
+    let mut last_reputation_save = Instant::now();
+    let mut last_extension_profiles_save = Instant::now();
+    let mut sync_roots = SyncRoots::detect();
+    let mut last_sync_roots = Instant::now();
+    let mut remote_volumes = RemoteVolumes::detect();
+    let mut last_remote_volumes = Instant::now();
+    let mut kills = KillVerifier::from(config);
+    let mut last_isolation_check = Instant::now();
+    let mut self_test = SelfTest::from(config);
+    let persist_state = config.get_bool(Param::PersistState);
+    let mut last_state_save = Instant::now();
+    let mut gid_merger = GidMerger::from(config);
+    let mut last_wsl_inventory = Instant::now();
+    let mut wsl_inventory: Option<thread::JoinHandle<Vec<LinuxProcess>>> = None;
+    let mut backpressure = Backpressure::from(config);
+    let av_coexistence = config.get_bool(Param::AvCoexistence);
+    let mut last_av_status: Option<Instant> = None;
+    let mut last_av_detections: Option<Instant> = None;
+    let mut av_query: Option<thread::JoinHandle<Refresh>> = None;
+    let sysmon = Sysmon::from(config);
+    let input_capture = InputCapture::from(config);
+    let mut spoofed_parents = SpoofedParents::new();
+    let mut privilege_probe = PrivilegeProbe::new();
+    loop {
+        // Once a stop is requested, the messages already queued by the driver are still
+        // processed, until an empty reply.
+        lifecycle.beat();
+        let stopping = lifecycle.is_stop_requested();
+        if scheduler.is_closed() {
+            panic!("A pipeline worker has crashed");
+        }
+        iteration += 1;
+        if iteration % 10 == 0 && kill_policy == KillPolicy::Suspend && !lifecycle.is_paused() {
+            worker::process_suspended_procs(source, config, status, worker_events, &mut procs.lock().unwrap());
+        }
+        if iteration % 10 == 0 && config.get_bool(Param::SelfProtection) {
+            source.report_tamper_attempts(config, connectors);
+        }
+        if iteration % 10 == 0 {
+            spoofed_parents.attribute(source, &mut procs.lock().unwrap());
+        }
+        backpressure.update(source, scheduler.queued());
+        if iteration % 10 == 0 || backpressure.is_active() {
+            backpressure.collect(source, &gid_merger, &mut procs.lock().unwrap());
+        }
+        if last_reap.elapsed() >= REAP_INTERVAL {
+            system.refresh_processes();
+            backup.refresh_jobs(&system);
+            let (reaped, procs_count) = {
+                let mut procs = procs.lock().unwrap();
+                for proc in procs.procs.iter_mut() {
+                    proc.backup_job = proc.backup_agent.is_some_and(|agent| backup.is_job_running(agent));
+                }
+                (procs.reap(&system, grace), procs.len())
+            };
+            gid_merger.prune();
+            privilege_probe.prune();
+            for proc in reaped {
+                process_terminated(connectors, &proc);
+                if !proc.is_malicious && !proc.would_kill {
+                    extension_profiles.learn(&proc.exepath, &proc.extension_usage);
+                }
+                if let Some(baseline) = baseline.as_mut() {
+                    baseline.observe(&Observed::from(&proc));
+                }
+            }
+            let paths = intern::stats();
+            debug!(
+                procs = procs_count,
+                paths_lookups = paths.lookups,
+                paths_hits = paths.hits,
+                paths_bytes_allocated = paths.bytes_allocated,
+                paths_bytes_saved = paths.bytes_saved,
+                "Memory statistics"
+            );
+            last_reap = Instant::now();
+        }
+        if raw_disk_audit && last_raw_disk_audit.elapsed() >= RAW_DISK_AUDIT_INTERVAL {
+            let pids = procs.lock().unwrap().gids_by_pid();
+            for (event, iomsg) in raw_disk.audit(&pids) {
+                raw_disk_write(connectors, &event);
+                scheduler.push(iomsg.gid, iomsg);
+            }
+            last_raw_disk_audit = Instant::now();
+        }
+        if last_status.elapsed() >= STATUS_INTERVAL {
+            let gids = procs.lock().unwrap().procs.iter().map(GidStatus::from).collect();
+            status.set_gids(gids);
+            status.set_queued_msgs(scheduler.queued());
+            status.set_backpressure(backpressure.stats());
+            last_status = Instant::now();
+        }
+        if last_schedule_check.is_none_or(|t| t.elapsed() >= profiles::CHECK_INTERVAL) {
+            schedule.refresh(config, connectors);
+            last_schedule_check = Some(Instant::now());
+        }
+        if let Some(baseline) = baseline.as_mut() {
+            baseline.hash_pending();
+            if last_observation.elapsed() >= baseline::OBSERVATION_INTERVAL {
+                let observed: Vec<Observed> = procs.lock().unwrap().procs.iter().map(Observed::from).collect();
+                for proc in &observed {
+                    baseline.observe(proc);
+                }
+                baseline.save();
+                last_observation = Instant::now();
+            }
+        }
+        if last_sync_roots.elapsed() >= cloudsync::REFRESH_INTERVAL {
+            sync_roots = SyncRoots::detect();
+            last_sync_roots = Instant::now();
+        }
+        if last_remote_volumes.elapsed() >= netshare::REFRESH_INTERVAL {
+            remote_volumes = RemoteVolumes::detect();
+            last_remote_volumes = Instant::now();
+        }
+        if last_isolation_check.elapsed() >= isolation::CHECK_INTERVAL {
+            isolation::lift_if_expired(config);
+            last_isolation_check = Instant::now();
+        }
+        if wsl_inventory.as_ref().is_some_and(|inventory| inventory.is_finished()) {
+            if let Some(Ok(linux)) = wsl_inventory.take().map(|inventory| inventory.join()) {
+                for proc in procs.lock().unwrap().procs.iter_mut().filter(|proc| proc.wsl.is_some()) {
+                    proc.wsl = Some(linux.clone());
+                }
+            }
+        }
+        if wsl_inventory.is_none() && last_wsl_inventory.elapsed() >= wsl::REFRESH_INTERVAL {
+            if procs.lock().unwrap().procs.iter().any(|proc| proc.wsl.is_some()) {
+                wsl_inventory = Some(thread::spawn(wsl::inventory));
+            }
+            last_wsl_inventory = Instant::now();
+        }
+        if av_query.as_ref().is_some_and(|query| query.is_finished()) {
+            if let Some(Ok(refresh)) = av_query.take().map(|query| query.join()) {
+                status.av.update(refresh);
+            }
+        }
+        if av_coexistence && av_query.is_none() && last_av_detections.is_none_or(|t| t.elapsed() >= defender::DETECTIONS_INTERVAL) {
+            let with_status = last_av_status.is_none_or(|t| t.elapsed() >= defender::STATUS_INTERVAL);
+            // the detections already known are ignored
+            let since = if last_av_detections.is_some() { 2 * defender::DETECTIONS_INTERVAL } else { defender::DETECTION_TTL };
+            av_query = Some(thread::spawn(move || defender::query(with_status, since)));
+            if with_status {
+                last_av_status = Some(Instant::now());
+            }
+            last_av_detections = Some(Instant::now());
+        }
+        if let Some(sysmon) = sysmon.as_ref() {
+            sysmon.attribute(&mut procs.lock().unwrap());
+        }
+        if let Some(input_capture) = input_capture.as_ref() {
+            input_capture.attribute(&mut procs.lock().unwrap());
+        }
+        if persist_state && last_state_save.elapsed() >= persistence::SAVE_INTERVAL {
+            save_state(config, procs);
+            last_state_save = Instant::now();
+        }
+        if last_reputation_save.elapsed() >= reputation::SAVE_INTERVAL {
+            reputation.save();
+            last_reputation_save = Instant::now();
+        }
+        if last_extension_profiles_save.elapsed() >= extprofiles::SAVE_INTERVAL {
+            extension_profiles.save();
+            last_extension_profiles_save = Instant::now();
+        }
+        procs.lock().unwrap().refresh_ignored_gids(exclusions.version());
+        if let Err(e) = source.fetch(&mut events) {
+            let e = OwlyError::from(e);
+            fetch_failures += 1;
+            if e.policy() != ErrorPolicy::Retry || fetch_failures > FETCH_RETRIES {
+                cloudsync::resume_clients(config);
+                return Err(e);
+            }
+            warn!(attempt = fetch_failures, "{}, retrying", e);
+            thread::sleep(FETCH_RETRY_DELAY * fetch_failures);
+            continue;
+        }
+        fetch_failures = 0;
+        if events.is_empty() {
+            if stopping {
+                if let Some(baseline) = &baseline {
+                    baseline.save();
+                }
+                reputation.save();
+                extension_profiles.save();
+                if persist_state {
+                    save_state(config, procs);
+                }
+                cloudsync::resume_clients(config);
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        if let Some(broker) = broker {
+            broker.publish(&events);
+        }
+        for mut iomsg in events.drain(..) {
+            if self_test.observe(&iomsg) {
+                continue;
+            }
+            gid_merger.merge(&mut iomsg, |gid| procs.lock().unwrap().is_gid_ignored(gid));
+            status.follow.on_driver_msg(&iomsg);
+            sync_roots.tag(&mut iomsg);
+            remote_volumes.tag(&mut iomsg);
+            privilege_probe.tag(&mut iomsg);
+            if let Some(event) = raw_disk.on_driver_msg(&iomsg) {
+                raw_disk_write(connectors, &event);
+            }
+            scheduler.push(iomsg.gid, iomsg);
+        }
+        worker_events.send(connectors, &status.incidents, &mut kills);
+        kills.poll(source, exclusions, connectors);
+        self_test.tick(connectors);
+        for review in status.take_reviews() {
+            connectors.send_review(&review);
+        }
+    }
+    Ok(())
+}
+
+/// Saves the state of the gids, but the ones being processed by a worker.
+fn save_state(config: &Config, procs: &Mutex<Procs>) {
+    let gids: Vec<GidSnapshot> = procs.lock().unwrap().procs.iter().map(GidSnapshot::of).collect();
+    persistence::save(config, gids);
+}
+
+/// Emits the summary of a gid whose state is dropped.
+fn process_terminated(connectors: &Connectors, proc: &ProcessRecord) {
+    let summary = ProcessTerminated::from(proc);
+    info!(
+        gid = summary.gid,
+        appname = %summary.appname,
+        user = %summary.owner.as_ref().map_or(String::from("unknown"), |o| o.to_string()),
+        duration_secs = summary.duration().as_secs(),
+        pids = summary.pids_count,
+        driver_msgs = summary.driver_msg_count,
+        files_written = summary.files_written,
+        max_prediction = summary.max_prediction.unwrap_or(0.0),
+        state = %summary.process_state,
+        "Process family terminated"
+    );
+    connectors.send_process_terminated(&summary);
+}
+
+/// Emits a raw disk write, a Critical event.
+fn raw_disk_write(connectors: &Connectors, event: &RawDiskWrite) {
+    error!(
+        gid = event.gid,
+        pid = event.pid,
+        device = %event.device,
+        mount_point = event.mount_point.as_deref().unwrap_or("-"),
+        source = %event.source,
+        "Critical: raw disk write"
+    );
+    connectors.send_raw_disk_write(event);
+}
+
+/// Other stages, for the gids taken from the [Scheduler].
+#[allow(clippy::too_many_arguments)]
+fn run_worker<'a>(
+    source: &dyn IoEventSource,
+    config: &'a Config,
+    whitelist: &WhiteList,
+    exclusions: &Exclusions,
+    backup: &BackupAgents,
+    reputation: &Reputation,
+    extension_profiles: &ExtensionProfiles,
+    lifecycle: &Lifecycle,
+    audit: &AuditLog,
+    status: &AgentStatus,
+    worker_events: &WorkerEvents,
+    saved: &SavedGids,
+    scheduler: &Scheduler<IOMessage>,
+    procs: &Mutex<Procs<'a>>,
+) {
+    let _guard = PanicGuard(scheduler, config);
+    let models = ModelRouter::from(config).unwrap_or_else(|e| OwlyError::from(e).exit());
+    let tflite_static = TfLiteStatic::new().unwrap_or_else(|e| OwlyError::from(e).exit());
+    let anomaly = AnomalyModel::from(config).unwrap_or_else(|e| OwlyError::from(e).exit());
+    while let Some((gid, iomsgs)) = scheduler.take() {
+        let mut record: Option<ProcessRecord> = procs.lock().unwrap().take(gid);
+        for mut iomsg in iomsgs {
+            if record.is_none() {
+                if procs.lock().unwrap().is_gid_ignored(gid) {
+                    break;
+                }
+                record = worker::new_process_record(source, config, whitelist, exclusions, backup, reputation, extension_profiles, procs, &tflite_static, &mut iomsg);
+                if let Some(proc) = record.as_mut() {
+                    if let Some(snapshot) = saved.take(gid, iomsg.pid, &proc.exepath) {
+                        info!(gid, saved_gid = snapshot.gid, appname = %proc.appname, "State restored from the previous run");
+                        snapshot.restore(proc);
+                    }
+                }
+            }
+            if let Some(proc) = record.as_mut() {
+                worker::process_drivermessage(source, config, proc, &models, &tflite_static, anomaly.as_ref(), lifecycle, audit, status, worker_events, &iomsg);
+            }
+        }
+        if let Some(proc) = record {
+            procs.lock().unwrap().add_record(proc);
+        }
+        scheduler.release(gid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::Scheduler;
+
+    #[test]
+    fn gid_should_be_leased_to_one_worker() {
+        let scheduler = Scheduler::new();
+        scheduler.push(1, 'a');
+        scheduler.push(2, 'b');
+        scheduler.push(1, 'c');
+        assert_eq!(scheduler.take(), Some((1, vec!['a', 'c'])));
+        scheduler.push(1, 'd');
+        assert_eq!(scheduler.take(), Some((2, vec!['b'])));
+        scheduler.release(1);
+        scheduler.release(2);
+        assert_eq!(scheduler.take(), Some((1, vec!['d'])));
+        scheduler.close();
+        assert_eq!(scheduler.take(), None);
+    }
+}
@@ -9,26 +9,40 @@
 //! backpropagation through time (tbtt). But stateful lstm is not possible with TfLite and the state
 //! has to be manually propagated between epochs. That's why we limit the sequence length, capped to
 //! [PREDMTRXROWS]. See module [input_tensors] for details.
+//!
+//! The models may be quantized to int8, at a fraction of the CPU cost on the low-end endpoints: the
+//! standardized features are then quantized with the [QuantizationParams] of the input tensor, and
+//! the prediction dequantized with those of the output tensor (see [Encoding]).
 
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 use std::time::SystemTime;
 
 use byteorder::{ByteOrder, LittleEndian};
 use moonfire_tflite::*;
+use sha2::{Digest, Sha256};
+use tracing::error;
 
-use crate::prediction::input_tensors::VecvecCapped;
+use crate::error::ModelError;
+use crate::features::REGISTRY;
+use crate::prediction::input_tensors::RollingFeatures;
 
 /// The .tflite (converted from Tensorflow/Keras) model is included as a static variable.
 static MODEL: &'static [u8] = include_bytes!("../models/model.tflite");
-/// Features means vector, used by Standard Scaling.
+/// Features means vector, used by Standard Scaling. The input tensor of a model has dimensions
+/// *(None, n)*, where *n* is the length of its [MEANS]: a model only uses the first *n* columns of
+/// the [REGISTRY] (the embedded model does not use the directory tree, magic bytes, ransom note,
+/// time-decayed, container, Sysmon, dropped payload, integrity, privilege, persistence and input
+/// capture features yet).
 static MEANS: &'static [u8] = include_bytes!("../models/mean.json");
 /// Features standard deviations vector used by Standard Scaling.
 static STDVS: &'static [u8] = include_bytes!("../models/std.json");
+/// Names of the inputs of [MODEL], the columns it was trained on.
+static FEATURES: &'static [u8] = include_bytes!("../models/features.json");
 
-/// Our Input tensor has dimensions *(None, PREDMTRXCOLS)*
-pub static PREDMTRXCOLS: usize = 26;
 /// We cap the dimension1 of our input tensor (that is the length of the prediction sequence). See
-/// [VecvecCapped] for details about how and why.
+/// [input_tensors::VecvecCapped] for details about how and why.
 pub static PREDMTRXROWS: usize = 500;
 
 /// A record to describe a tflite model
@@ -38,64 +52,180 @@ pub struct TfLite {
     means: Vec<f32>,
     /// Needed by Standard Scaling and set to [STDVS]
     stdvs: Vec<f32>,
+    /// See [Self::version]
+    version: String,
+    /// Of the input tensor, float32 or int8
+    input: Encoding,
+    /// Of the output tensor
+    output: Encoding,
 }
 
 impl TfLite /*<T>*/
 /*where T: serde::de::Deserialize<'a> + num::Float*/
 {
-    pub fn new() -> TfLite {
-        let means = serde_json::from_slice(MEANS);
-        let stdvs = serde_json::from_slice(STDVS);
+    /// Loads the embedded model, whose inputs ([FEATURES]) must be extracted by the [REGISTRY].
+    pub fn new() -> Result<TfLite, ModelError> {
+        let model = Model::from_static(MODEL).map_err(|_| ModelError::Model("dynamic"))?;
+        TfLite::with("dynamic", model, MEANS, STDVS, FEATURES, model_version())
+    }
+
+    /// Loads the model *name* of *dir*: *model.tflite* (float32 or int8-quantized), with its
+    /// *mean.json*, *std.json* and *features.json* as the embedded one.
+    pub fn from_dir(name: &'static str, dir: &Path) -> Result<TfLite, ModelError> {
+        let read = |file: &str| {
+            let path = dir.join(file);
+            fs::read(&path).map_err(|e| ModelError::Data(name, format!("{}: {}", path.display(), e)))
+        };
+        let bytes = read("model.tflite")?;
+        let model = Model::from_file(&dir.join("model.tflite")).map_err(|_| ModelError::Model(name))?;
+        TfLite::with(name, model, &read("mean.json")?, &read("std.json")?, &read("features.json")?, short_digest(&bytes))
+    }
 
-        TfLite {
-            model: Model::from_static(MODEL).unwrap(),
-            means: means.unwrap(),
-            stdvs: stdvs.unwrap(),
+    fn with(name: &'static str, model: Model, means: &[u8], stdvs: &[u8], features: &[u8], version: String) -> Result<TfLite, ModelError> {
+        let data = |e: serde_json::Error| ModelError::Data(name, e.to_string());
+        let means: Vec<f32> = serde_json::from_slice(means).map_err(data)?;
+        let stdvs: Vec<f32> = serde_json::from_slice(stdvs).map_err(data)?;
+        let features: Vec<String> = serde_json::from_slice(features).map_err(data)?;
+        if stdvs.len() != means.len() || features.len() != means.len() {
+            return Err(ModelError::Data(
+                name,
+                format!("{} means, {} stdvs, {} features", means.len(), stdvs.len(), features.len()),
+            ));
         }
+        REGISTRY.validate_schema(name, &features)?;
+        let (input, output) = {
+            let mut interpreter = Interpreter::builder().build(&model, 1, means.len()).map_err(|_| ModelError::Model(name))?;
+            (Encoding::of(name, &interpreter.inputs()[0])?, Encoding::of(name, &interpreter.outputs()[0])?)
+        };
+        Ok(TfLite { model, means, stdvs, version, input, output })
+    }
+
+    /// Identifies the model, see [model_version].
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The model takes int8 inputs.
+    pub fn is_quantized(&self) -> bool {
+        matches!(self.input, Encoding::Int8(_))
+    }
+
+    /// Number of features used by the model, the first columns of the [REGISTRY].
+    fn features_count(&self) -> usize {
+        self.means.len()
     }
 
     /// Make a prediction on the sequence *predmtrx*. The prediction can be costly.
-    /// The model input tensor dimensions are (None, [PREDMTRXCOLS]) and is dimensioned accordingly
-    /// by the *InterpreterBuilder*.
+    /// The model input tensor dimensions are (None, [Self::features_count]) and is dimensioned
+    /// accordingly by the *InterpreterBuilder*.
     /// The model returns only the last prediction (it does not returns sequences).
-    pub fn make_prediction(&self, predmtrx: &VecvecCapped<f32>) -> f32 {
-        let inputmtrx = self.standardize(predmtrx).to_vec();
+    /// None, with the error logged, if the interpreter fails: the prediction is skipped.
+    pub fn make_prediction(&self, predmtrx: &RollingFeatures) -> Option<f32> {
+        match self.predict(predmtrx) {
+            Ok(y_pred) => Some(y_pred),
+            Err(e) => {
+                error!("{}, prediction skipped", e);
+                None
+            }
+        }
+    }
+
+    fn predict(&self, predmtrx: &RollingFeatures) -> Result<f32, ModelError> {
+        let inputmtrx = self.standardize(predmtrx);
         // println!("MEANS: {:?}", self.means);
         // println!("STDVS: {:?}", self.stdvs);
         // println!("NORMALIZED: {:?}", inputmtrx);
+        let inference = |_| ModelError::Inference(self.version.clone());
         let builder = Interpreter::builder();
         let mut interpreter = builder
-            .build(&self.model, predmtrx.rows_len(), PREDMTRXCOLS)
-            .unwrap();
+            .build(&self.model, predmtrx.rows_len(), self.features_count())
+            .map_err(inference)?;
 
         let mut inputs = interpreter.inputs();
 
-        let mut dst = inputs[0].bytes_mut();
-        LittleEndian::write_f32_into(inputmtrx.as_slice(), &mut dst);
-        interpreter.invoke().unwrap();
+        self.input.write(&inputmtrx, inputs[0].bytes_mut());
+        interpreter.invoke().map_err(inference)?;
         let outputs = interpreter.outputs();
 
-        let y_pred = outputs[0].f32s()[0];
+        let y_pred = self.output.read(&outputs[0]);
         //println!("YPRED: {}", y_pred);
-        y_pred
+        Ok(y_pred)
     }
 
-    /// Standard Scaling of the input vectors with [MEANS] and [STDVS].
-    fn standardize(&self, predmtrx: &VecvecCapped<f32>) -> VecvecCapped<f32> {
-        let mut res = predmtrx.clone();
+    /// Standard Scaling of the input vectors with [MEANS] and [STDVS], keeping the features
+    /// used by the model. Returns the rows flattened.
+    fn standardize(&self, predmtrx: &RollingFeatures) -> Vec<f32> {
+        let cols = self.features_count();
+        let mut res = Vec::with_capacity(predmtrx.rows_len() * cols);
         let epsilon = 0.0001f32;
-        for i in 0..predmtrx.rows_len() {
-            //predmtrx.capacity_rows {
-            for j in 0..predmtrx.capacity_cols {
+        for row in predmtrx {
+            for j in 0..cols {
                 let stdvs_j = self.stdvs[j];
                 let denominator = if stdvs_j < epsilon { epsilon } else { stdvs_j };
-                res[i][j] = (predmtrx[i][j] - self.means[j]) / denominator
+                res.push((row[j] - self.means[j]) / denominator)
             }
         }
         res
     }
 }
 
+/// How the values of a tensor are stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Float32,
+    /// Quantized: *real = scale * (q - zero_point)*
+    Int8(QuantizationParams),
+}
+
+impl Encoding {
+    fn of(name: &'static str, tensor: &Tensor) -> Result<Encoding, ModelError> {
+        match tensor.type_() {
+            Type::Float32 => Ok(Encoding::Float32),
+            Type::Int8 if tensor.quantization_params().scale > 0.0 => Ok(Encoding::Int8(tensor.quantization_params())),
+            Type::Int8 => Err(ModelError::Data(name, format!("no quantization scale for the tensor {}", tensor.name()))),
+            other => Err(ModelError::Data(name, format!("unsupported {:?} tensor {}", other, tensor.name()))),
+        }
+    }
+
+    /// Writes the standardized *values* in the bytes of an input tensor.
+    fn write(self, values: &[f32], dst: &mut [u8]) {
+        match self {
+            Encoding::Float32 => LittleEndian::write_f32_into(values, dst),
+            Encoding::Int8(params) => {
+                for (q, value) in dst.iter_mut().zip(values) {
+                    *q = quantize(params, *value) as u8;
+                }
+            }
+        }
+    }
+
+    /// The first value of an output tensor.
+    fn read(self, tensor: &Tensor) -> f32 {
+        match self {
+            Encoding::Float32 => tensor.f32s()[0],
+            Encoding::Int8(params) => dequantize(params, tensor.bytes()[0] as i8),
+        }
+    }
+}
+
+fn quantize(params: QuantizationParams, value: f32) -> i8 {
+    let q = (value / params.scale).round() + params.zero_point as f32;
+    q.clamp(i8::MIN as f32, i8::MAX as f32) as i8
+}
+
+fn dequantize(params: QuantizationParams, q: i8) -> f32 {
+    params.scale * (q as i32 - params.zero_point) as f32
+}
+
+/// Identifies the model: first 12 hex chars of the sha256 of [MODEL].
+pub fn model_version() -> String {
+    short_digest(MODEL)
+}
+
+pub(crate) fn short_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().take(6).map(|b| format!("{:02x}", b)).collect()
+}
+
 /// A record to maintain a history of past predictions. Values are
 /// (moment of prediction, how many fids with update //TODO, the prediction result)
 pub(crate) type PredictionValues = (SystemTime, usize, f32);
@@ -115,184 +245,46 @@ impl Predictions {
     }
 
     pub fn register_prediction(&mut self, now: SystemTime, file_ids_u: usize, pred: f32) {
-        let nextidx = self.predictions.keys().max().unwrap_or(&0u32).clone() + 1;
+        // keys are 1..=len
+        let nextidx = self.predictions.len() as u32 + 1;
         self.predictions.insert(nextidx, (now, file_ids_u, pred));
     }
 
+    /// The predictions, oldest first.
+    pub fn to_vec(&self) -> Vec<PredictionValues> {
+        (1..=self.predictions.len() as u32).filter_map(|i| self.predictions.get(&i).copied()).collect()
+    }
+
     pub fn predictions_count(&self) -> usize {
         self.predictions.len()
     }
 
     pub fn get_last_prediction(&self) -> Option<f32> {
-        let map_len = self.predictions_count() as u32;
-        if map_len == 0 {
-            None
-        } else {
-            Some(self.predictions[&(map_len-1)].2)
-        }
+        // keys are 1..=len
+        self.predictions.get(&(self.predictions_count() as u32)).map(|p| p.2)
+    }
+
+    pub fn get_max_prediction(&self) -> Option<f32> {
+        self.predictions.values().map(|p| p.2).reduce(f32::max)
     }
 }
 
 /// Contains structures to connect a [crate::process::ProcessRecord] with a [TfLite] input tensor.
 pub mod input_tensors {
-    use std::collections::VecDeque;
+    use std::collections::{vec_deque, VecDeque};
+    use std::convert::TryFrom;
     use std::error::Error;
     use std::fmt::{Debug, Display, Formatter};
+    use std::iter::Map;
     use std::ops::{Index, IndexMut};
 
-    use crate::extensions::ExtensionCategory;
-    use crate::process::ProcessRecord;
+    use serde::{Deserialize, Serialize};
+
+    use crate::features::REGISTRY;
 
     /// Typedef used by [VecvecCapped]
     type Matrix<T> = VecDeque<Vec<T>>;
 
-    /// Record of the features used to feed the input tensor with [super::TfLite::make_prediction].
-    /// Features are the results of aggregate functions (mainly *sum*, *max* and *count*) applied to:
-    /// 1. Data that comes from the driver (*ops_read*, *entropy_read*...)
-    /// 2. Calculations done in this project [crate::process] module (*clustering*)
-    #[derive(Debug)]
-    pub struct PredictionRow {
-        /// Count of Read operations [crate::driver_com::IrpMajorOp::IrpRead]
-        pub ops_read: u64,
-        /// Count of SetInfo operations [crate::driver_com::IrpMajorOp::IrpSetInfo]
-        pub ops_setinfo: u64,
-        /// Count of Write operations [crate::driver_com::IrpMajorOp::IrpWrite]
-        pub ops_written: u64,
-        /// Count of Handle Creation operations [crate::driver_com::IrpMajorOp::IrpCreate]
-        pub ops_open: u64,
-        /// Total bytes read (by gid)
-        pub bytes_read: u64,
-        /// Total bytes written (by gid)
-        pub bytes_written: u64,
-        /// Total entropy read
-        pub entropy_read: f32,
-        /// Total entropy write
-        pub entropy_written: f32,
-        /// File descriptors created
-        pub files_opened: usize,
-        /// File descriptors deleted
-        pub files_deleted: usize,
-        /// File descriptors read
-        pub files_read: usize,
-        /// File descriptors renamed
-        pub files_renamed: usize,
-        /// File descriptors written
-        pub files_written: usize,
-        /// Unique extensions read count
-        pub extensions_read: usize,
-        /// Unique extensions write count
-        pub extensions_written: usize,
-        /// Unique extensions written count (documents)
-        pub extensions_written_doc: usize,
-        /// Unique extensions written count (archives)
-        pub extensions_written_archives: usize,
-        /// Unique extensions written count (DB)
-        pub extensions_written_db: usize,
-        /// Unique extensions written count (code)
-        pub extensions_written_code: usize,
-        /// Unique extensions written count (executables)
-        pub extensions_written_exe: usize,
-        /// Directories having files created
-        pub dirs_with_files_created: usize,
-        /// Directories having files updated
-        pub dirs_with_files_updated: usize,
-        /// Number of pids in this gid process family
-        pub pids: usize,
-        /// Process exe file still exists (father)?
-        pub exe_exists: bool,
-        /// Number of directories (with files updated) clusters created
-        pub clusters: usize,
-        /// Deepest cluster size
-        pub clusters_max_size: usize,
-    }
-
-    impl PredictionRow {
-        pub fn from(proc: &ProcessRecord) -> PredictionRow {
-            PredictionRow {
-                bytes_read: proc.bytes_read,
-                bytes_written: proc.bytes_written,
-                ops_read: proc.ops_read,
-                ops_setinfo: proc.ops_setinfo,
-                ops_written: proc.ops_written,
-                ops_open: proc.ops_open,
-                entropy_read: Self::order_magnitude(proc.entropy_read) as f32,
-                entropy_written: Self::order_magnitude(proc.entropy_written) as f32,
-                extensions_read: proc.extensions_read.count_all(),
-                extensions_written: proc.extensions_written.count_all(),
-                files_opened: proc.files_opened.len(),
-                files_deleted: proc.files_deleted.len(),
-                files_read: proc.files_read.len(),
-                files_renamed: proc.files_renamed.len(),
-                files_written: proc.files_written.len(),
-                pids: proc.pids.len(),
-                extensions_written_doc: proc
-                    .extensions_written
-                    .count_category(ExtensionCategory::Docs),
-                extensions_written_archives: proc
-                    .extensions_written
-                    .count_category(ExtensionCategory::Archives),
-                extensions_written_db: proc
-                    .extensions_written
-                    .count_category(ExtensionCategory::Database),
-                extensions_written_code: proc
-                    .extensions_written
-                    .count_category(ExtensionCategory::Code),
-                extensions_written_exe: proc
-                    .extensions_written
-                    .count_category(ExtensionCategory::Exe),
-                dirs_with_files_created: proc.dirs_with_files_created.len(),
-                dirs_with_files_updated: proc.dirs_with_files_updated.len(),
-                exe_exists: proc.exe_exists,
-                clusters: proc.clusters,
-                clusters_max_size: proc.clusters_max_size,
-            }
-        }
-
-        pub fn to_vec_f32(&self) -> Vec<f32> {
-            let res: Vec<f32> = vec![
-                self.ops_read as f32,
-                self.ops_setinfo as f32,
-                self.ops_written as f32,
-                self.ops_open as f32,
-                self.bytes_read as f32,
-                self.bytes_written as f32,
-                self.entropy_read as f32,
-                self.entropy_written as f32,
-                self.files_opened as f32,
-                self.files_deleted as f32,
-                self.files_read as f32,
-                self.files_renamed as f32,
-                self.files_written as f32,
-                self.extensions_read as f32,
-                self.extensions_written as f32,
-                self.extensions_written_doc as f32,
-                self.extensions_written_archives as f32,
-                self.extensions_written_db as f32,
-                self.extensions_written_code as f32,
-                self.extensions_written_exe as f32,
-                self.dirs_with_files_created as f32,
-                self.dirs_with_files_updated as f32,
-                self.pids as f32,
-                self.exe_exists as u8 as f32,
-                self.clusters as f32,
-                self.clusters_max_size as f32,
-            ];
-            res
-        }
-
-        #[inline]
-        fn order_magnitude(a: f64) -> u32 {
-            if a <= 0f64 {
-                0
-            } else {
-                a.log10() as u32
-            }
-        }
-    }
-
-    /// Our [super::TfLite] model waits for f32, but [VecvecCapped] uses generics.
-    pub type VecvecCappedF32 = VecvecCapped<f32>;
-
     /// A matrix with fixed_size to feed the model's input tensors, because too long sequences
     /// (> 1000 steps) would deserve the predictions with RNN, unless tbtt is used.
     ///
@@ -386,11 +378,142 @@ pub mod input_tensors {
 
     impl Error for VecvecCappedError {}
 
+    /// The rows of features of a gid, as given to the model: one row of the [REGISTRY] per
+    /// prediction, the last [super::PREDMTRXROWS] ones (see [VecvecCapped]).
+    ///
+    /// It is the input of [super::TfLite::make_prediction] and of the actions on kill, so that the
+    /// reports, the connectors and the exports use the features the model saw. Serialized with
+    /// its schema:
+    /// ```json
+    /// {"columns": ["ops_read", "ops_setinfo", ...], "capacity_rows": 500, "rows": [[12.0, 0.0, ...]]}
+    /// ```
+    /// The columns must be the first ones of the [REGISTRY], in that order: the features added
+    /// since the serialization are 0.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(into = "RollingFeaturesData", try_from = "RollingFeaturesData")]
+    pub struct RollingFeatures {
+        rows: VecvecCapped<f32>,
+    }
+
+    /// The serialized form of [RollingFeatures].
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct RollingFeaturesData {
+        columns: Vec<String>,
+        capacity_rows: usize,
+        rows: Vec<Vec<f32>>,
+    }
+
+    impl RollingFeatures {
+        pub fn new(capacity_rows: usize) -> RollingFeatures {
+            RollingFeatures {
+                rows: VecvecCapped::new(REGISTRY.len(), capacity_rows),
+            }
+        }
+
+        /// The names of the columns, those of the [REGISTRY].
+        pub fn columns(&self) -> impl Iterator<Item = &'static str> {
+            REGISTRY.columns()
+        }
+
+        pub fn capacity_rows(&self) -> usize {
+            self.rows.capacity_rows
+        }
+
+        pub fn rows_len(&self) -> usize {
+            self.rows.rows_len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.rows.rows_len() == 0
+        }
+
+        /// Appends *row*, of [REGISTRY] values, forgetting the oldest row if full.
+        pub fn push_row(&mut self, row: Vec<f32>) -> Result<(), VecvecCappedError> {
+            self.rows.push_row(row)
+        }
+
+        /// The rows, the oldest first.
+        pub fn iter(&self) -> impl Iterator<Item = &[f32]> + '_ {
+            self.into_iter()
+        }
+
+        pub fn last(&self) -> Option<&[f32]> {
+            self.rows.elems.back().map(|row| row.as_slice())
+        }
+
+        /// The values of the last row, with their names.
+        pub fn last_named(&self) -> impl Iterator<Item = (&'static str, f32)> + '_ {
+            REGISTRY.columns().zip(self.last().unwrap_or(&[]).iter().copied())
+        }
+
+        /// The values of the feature *name* in each row, None if unknown.
+        pub fn column(&self, name: &str) -> Option<impl Iterator<Item = f32> + '_> {
+            let index = REGISTRY.position(name)?;
+            Some(self.iter().map(move |row| row[index]))
+        }
+
+        /// The rows flattened.
+        pub fn to_vec(&self) -> Vec<f32> {
+            self.rows.to_vec()
+        }
+    }
+
+    impl Index<usize> for RollingFeatures {
+        type Output = Vec<f32>;
+
+        fn index(&self, index: usize) -> &Self::Output {
+            &self.rows[index]
+        }
+    }
+
+    impl<'a> IntoIterator for &'a RollingFeatures {
+        type Item = &'a [f32];
+        type IntoIter = Map<vec_deque::Iter<'a, Vec<f32>>, fn(&Vec<f32>) -> &[f32]>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.rows.elems.iter().map(Vec::as_slice)
+        }
+    }
+
+    impl From<RollingFeatures> for RollingFeaturesData {
+        fn from(features: RollingFeatures) -> RollingFeaturesData {
+            RollingFeaturesData {
+                columns: REGISTRY.columns().map(String::from).collect(),
+                capacity_rows: features.capacity_rows(),
+                rows: features.rows.elems.into_iter().collect(),
+            }
+        }
+    }
+
+    impl TryFrom<RollingFeaturesData> for RollingFeatures {
+        type Error = String;
+
+        fn try_from(data: RollingFeaturesData) -> Result<RollingFeatures, String> {
+            if data.columns.len() > REGISTRY.len() || data.columns.iter().zip(REGISTRY.columns()).any(|(c, n)| c != n) {
+                return Err(format!("unknown schema, the columns must be the first ones of {:?}", REGISTRY.columns().collect::<Vec<_>>()));
+            }
+            let mut features = RollingFeatures::new(data.capacity_rows.max(1));
+            for mut row in data.rows {
+                if row.len() != data.columns.len() {
+                    return Err(format!("{} values in a row of {} columns", row.len(), data.columns.len()));
+                }
+                row.resize(REGISTRY.len(), 0.0);
+                features.push_row(row).map_err(|e| e.to_string())?;
+            }
+            Ok(features)
+        }
+    }
+
     //https://zhauniarovich.com/post/2021/2021-01-testing-errors-in-rust/
     #[cfg(test)]
     mod tests {
         use super::*;
 
+        #[test]
+        fn features_names_should_match_input_tensor() {
+            assert_eq!(RollingFeatures::new(1).columns().count(), REGISTRY.len());
+        }
+
         #[test]
         fn add_invalid_size_row_should_fail() {
             let mut mtrx = VecvecCapped::new(2, 3);
@@ -416,6 +539,36 @@ pub mod input_tensors {
             assert_eq!(mtrx, ctrl);
         }
 
+        #[test]
+        fn rolling_features_should_keep_their_schema() {
+            let mut features = RollingFeatures::new(2);
+            assert!(features.is_empty() && features.last_named().next().is_none());
+            for i in 0..3 {
+                let mut row = vec![0.0; REGISTRY.len()];
+                row[2] = i as f32;
+                features.push_row(row).unwrap();
+            }
+            assert!(features.push_row(vec![1.0]).is_err());
+            assert_eq!(features.rows_len(), 2);
+            assert_eq!(features.column("ops_written").unwrap().collect::<Vec<_>>(), vec![1.0, 2.0]);
+            assert!(features.column("ops_unknown").is_none());
+            assert_eq!(features.last_named().nth(2), Some(("ops_written", 2.0)));
+            assert_eq!((&features).into_iter().count(), 2);
+
+            let json = serde_json::to_value(&features).unwrap();
+            assert_eq!(json["columns"][2], "ops_written");
+            assert_eq!(serde_json::from_value::<RollingFeatures>(json).unwrap(), features);
+
+            // serialized before the last features were added
+            let older = serde_json::json!({"columns": ["ops_read", "ops_setinfo"], "capacity_rows": 5, "rows": [[3.0, 4.0]]});
+            let older: RollingFeatures = serde_json::from_value(older).unwrap();
+            assert_eq!((older.capacity_rows(), older[0][1], older[0].len()), (5, 4.0, REGISTRY.len()));
+            let unknown = serde_json::json!({"columns": ["ops_setinfo"], "capacity_rows": 5, "rows": []});
+            assert!(serde_json::from_value::<RollingFeatures>(unknown).is_err());
+            let short = serde_json::json!({"columns": ["ops_read", "ops_setinfo"], "capacity_rows": 5, "rows": [[3.0]]});
+            assert!(serde_json::from_value::<RollingFeatures>(short).is_err());
+        }
+
         #[test]
         fn test_square_bracket_op() {
             let mut mtrx = VecvecCapped::new(3, 2);
@@ -429,3 +582,36 @@ pub mod input_tensors {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use moonfire_tflite::QuantizationParams;
+
+    use crate::prediction::{dequantize, quantize, Predictions};
+
+    #[test]
+    fn quantization_should_round_trip_in_range() {
+        let params = QuantizationParams { scale: 0.05, zero_point: -3 };
+        assert_eq!(quantize(params, 0.0), -3);
+        assert_eq!(quantize(params, 1.0), 17);
+        assert_eq!(quantize(params, 100.0), i8::MAX);
+        assert_eq!(quantize(params, -100.0), i8::MIN);
+        for value in [-2.5f32, -0.33, 0.0, 0.42, 3.1] {
+            assert!((dequantize(params, quantize(params, value)) - value).abs() <= params.scale / 2.0 + f32::EPSILON);
+        }
+        assert_eq!(dequantize(QuantizationParams { scale: 1.0 / 256.0, zero_point: -128 }, 127), 255.0 / 256.0);
+    }
+
+    #[test]
+    fn last_prediction_should_be_the_last_registered() {
+        let mut predictions = Predictions::new();
+        assert_eq!(predictions.get_last_prediction(), None);
+        predictions.register_prediction(SystemTime::now(), 3, 0.2);
+        assert_eq!(predictions.get_last_prediction(), Some(0.2));
+        predictions.register_prediction(SystemTime::now(), 5, 0.7);
+        assert_eq!(predictions.get_last_prediction(), Some(0.7));
+        assert_eq!(predictions.get_max_prediction(), Some(0.7));
+    }
+}
@@ -1,9 +1,15 @@
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use byteorder::{ByteOrder, LittleEndian};
 use moonfire_tflite::{Interpreter, Model};
+use tracing::error;
 use win_pe_inspection::LibImport;
 
+use crate::error::ModelError;
+use crate::prediction::short_digest;
+
 
 static MALAPI: &'static [u8] = include_bytes!("../models/malapi.json");
 /// The .tflite (converted from Tensorflow/Keras) model is included as a static variable.
@@ -20,22 +26,28 @@ pub struct TfLiteStatic {
     /// Needed by Standard Scaling and set to [STDVS]
     stdvs: Vec<f32>,
     malapi: HashMap<String, Vec<String>>,
+    /// Interpreters kept allocated between the predictions (the input tensor has a fixed size),
+    /// one per concurrent caller at most
+    interpreters: Mutex<Vec<Interpreter<'static>>>,
 }
 
 impl TfLiteStatic {
-    pub fn new() -> TfLiteStatic {
-        let means = serde_json::from_slice(MEANS);
-        let stdvs = serde_json::from_slice(STDVS);
-        let malapi = serde_json::from_slice(MALAPI);
-
-        TfLiteStatic {
-            model: Model::from_static(MODEL).unwrap(),
-            means: means.unwrap(),
-            stdvs: stdvs.unwrap(),
-            malapi: malapi.unwrap(),
-        }
+    pub fn new() -> Result<TfLiteStatic, ModelError> {
+        let data = |e: serde_json::Error| ModelError::Data("static", e.to_string());
+        let model = Model::from_static(MODEL).map_err(|_| ModelError::Model("static"))?;
+        let means: Vec<f32> = serde_json::from_slice(MEANS).map_err(data)?;
+        // warm: the first prediction does not pay for the allocation of the tensors
+        let interpreter = Interpreter::builder().build(&model, 1, means.len()).map_err(|_| ModelError::Model("static"))?;
+        Ok(TfLiteStatic {
+            model,
+            means,
+            stdvs: serde_json::from_slice(STDVS).map_err(data)?,
+            malapi: serde_json::from_slice(MALAPI).map_err(data)?,
+            interpreters: Mutex::new(vec![interpreter]),
+        })
     }
 
+    /// None if *path* is not a PE, or if the interpreter fails (logged).
     pub fn make_prediction(&self, path: &Path) -> Option<f32> {
         if let Ok(static_features) = win_pe_inspection::inspect_pe(path) {
             let mut input_vec = vec![
@@ -47,24 +59,54 @@ impl TfLiteStatic {
             input_vec.append(&mut import_cats_cnt);
             let input_vec_scaled = self.stdscale_transform(&input_vec);
 
-            let builder = Interpreter::builder();
-            let mut interpreter = builder
-                .build(&self.model, 1, input_vec_scaled.len())
-                .unwrap();
+            let pooled = self.interpreters.lock().unwrap().pop();
+            let mut interpreter = match pooled {
+                Some(interpreter) => interpreter,
+                None => match Interpreter::builder().build(&self.model, 1, input_vec_scaled.len()) {
+                    Ok(interpreter) => interpreter,
+                    Err(()) => {
+                        error!("{}, prediction skipped", ModelError::Inference(model_version()));
+                        return None;
+                    }
+                },
+            };
 
             let mut inputs = interpreter.inputs();
             let mut dst = inputs[0].bytes_mut();
             LittleEndian::write_f32_into(input_vec_scaled.as_slice(), &mut dst);
-            interpreter.invoke().unwrap();
+            if interpreter.invoke().is_err() {
+                // not returned to the pool
+                error!("{}, prediction skipped", ModelError::Inference(model_version()));
+                return None;
+            }
             let outputs = interpreter.outputs();
 
             let y_pred = outputs[0].f32s()[0];
+            self.interpreters.lock().unwrap().push(interpreter);
             Some(y_pred)
         } else {
             None
         }
     }
 
+    /// Predictions of an executable, or of all the executables (*.exe*, *.dll*) of a directory
+    /// and its subdirectories. None for a file which is not a PE.
+    pub fn scan(&self, path: &Path) -> Vec<(PathBuf, Option<f32>)> {
+        let mut files = Vec::new();
+        if path.is_dir() {
+            collect_executables(path, &mut files);
+        } else {
+            files.push(path.to_path_buf());
+        }
+        files
+            .into_iter()
+            .map(|file| {
+                let prediction = self.make_prediction(&file);
+                (file, prediction)
+            })
+            .collect()
+    }
+
     fn count_imports_by_categories(&self, imports: &Vec<LibImport>) -> Vec<f32> {
         let keys_count = self.malapi.keys().len();
         let mut res = Vec::with_capacity(keys_count);
@@ -111,4 +153,23 @@ impl TfLiteStatic {
 
 }
 
+/// Identifies the static model, as [crate::prediction::model_version].
+pub fn model_version() -> String {
+    short_digest(MODEL)
+}
 
+fn collect_executables(path: &Path, files: &mut Vec<PathBuf>) {
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            let entry_path = entry.path();
+            if entry_path.is_dir() {
+                collect_executables(&entry_path, files);
+            } else {
+                let ext = entry_path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+                if ext == "exe" || ext == "dll" {
+                    files.push(entry_path);
+                }
+            }
+        }
+    }
+}
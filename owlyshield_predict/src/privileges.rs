@@ -0,0 +1,246 @@
+//! Privileges enabled in the token of the root of a gid: *SeDebugPrivilege*,
+//! *SeBackupPrivilege*, *SeRestorePrivilege* and *SeTakeOwnershipPrivilege*. A ransomware enables
+//! the backup and restore privileges to read and write the files whatever their ACLs, and the
+//! take ownership one to take the others.
+//!
+//! The fetch stage stamps the driver messages with the privileges of the root of their gid
+//! ([RuntimeFeatures::privileges](crate::driver_com::shared_def::RuntimeFeatures)), read from its
+//! token at most every [PROBE_INTERVAL] ([PrivilegeProbe]). The privileges enabled after the gid
+//! was first seen are a feature of the model (*privileges_escalated*), see [PrivilegeUse].
+//!
+//! On Linux, the effective capabilities stand for them: *CAP_SYS_PTRACE*, *CAP_DAC_READ_SEARCH*,
+//! *CAP_DAC_OVERRIDE* and *CAP_CHOWN*.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::driver_com::shared_def::IOMessage;
+use crate::token;
+
+/// Period of the reads of the token of the root of a gid.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+/// Beyond, a gid without message is forgotten.
+const PROBE_TTL: Duration = Duration::from_secs(300);
+/// *SE_PRIVILEGE_ENABLED*, in the attributes of a *LUID_AND_ATTRIBUTES*.
+#[cfg_attr(not(windows), allow(dead_code))]
+const SE_PRIVILEGE_ENABLED: u32 = 0x2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    Debug,
+    Backup,
+    Restore,
+    TakeOwnership,
+}
+
+impl Privilege {
+    const ALL: [Privilege; 4] = [Privilege::Debug, Privilege::Backup, Privilege::Restore, Privilege::TakeOwnership];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// The well-known LUID of the privilege (*SE_DEBUG_PRIVILEGE*...), the same on all the machines.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn luid(self) -> u32 {
+        match self {
+            Privilege::Debug => 20,
+            Privilege::Backup => 17,
+            Privilege::Restore => 18,
+            Privilege::TakeOwnership => 9,
+        }
+    }
+
+    /// Bit of the Linux capability standing for the privilege.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn capability(self) -> u32 {
+        match self {
+            Privilege::Debug => 19,
+            Privilege::Backup => 2,
+            Privilege::Restore => 1,
+            Privilege::TakeOwnership => 0,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Privilege::Debug => "SeDebugPrivilege",
+            Privilege::Backup => "SeBackupPrivilege",
+            Privilege::Restore => "SeRestorePrivilege",
+            Privilege::TakeOwnership => "SeTakeOwnershipPrivilege",
+        }
+    }
+}
+
+/// A set of enabled [Privilege]s.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Privileges(u8);
+
+impl Privileges {
+    pub fn contains(self, privilege: Privilege) -> bool {
+        self.0 & privilege.bit() != 0
+    }
+
+    pub fn insert(&mut self, privilege: Privilege) {
+        self.0 |= privilege.bit();
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Those not in *other*.
+    pub fn without(self, other: Privileges) -> Privileges {
+        Privileges(self.0 & !other.0)
+    }
+
+    pub fn union(self, other: Privileges) -> Privileges {
+        Privileges(self.0 | other.0)
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        Privilege::ALL.iter().filter(|p| self.contains(**p)).map(|p| p.name()).collect()
+    }
+
+    /// Parses a *TOKEN_PRIVILEGES*: a count, then as many *LUID_AND_ATTRIBUTES* (low and high parts
+    /// of the LUID, attributes).
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn from_token_privileges(bytes: &[u8]) -> Privileges {
+        let u32_at = |offset: usize| -> Option<u32> {
+            bytes.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let mut privileges = Privileges::default();
+        let count = u32_at(0).unwrap_or(0) as usize;
+        for i in 0..count {
+            let offset = 4 + i * 12;
+            let (low, high, attributes) = match (u32_at(offset), u32_at(offset + 4), u32_at(offset + 8)) {
+                (Some(low), Some(high), Some(attributes)) => (low, high, attributes),
+                _ => break,
+            };
+            if high != 0 || attributes & SE_PRIVILEGE_ENABLED == 0 {
+                continue;
+            }
+            if let Some(privilege) = Privilege::ALL.iter().find(|p| p.luid() == low) {
+                privileges.insert(*privilege);
+            }
+        }
+        privileges
+    }
+
+    /// From the effective capabilities (*CapEff:* of */proc/\<pid\>/status*).
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn from_capabilities(cap_eff: u64) -> Privileges {
+        let mut privileges = Privileges::default();
+        for privilege in Privilege::ALL.iter().filter(|p| cap_eff & (1 << p.capability()) != 0) {
+            privileges.insert(*privilege);
+        }
+        privileges
+    }
+}
+
+impl fmt::Display for Privileges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.names().join(", "))
+    }
+}
+
+/// The privileges of the root of a gid over its run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrivilegeUse {
+    /// When the gid was first probed
+    pub initial: Option<Privileges>,
+    /// Enabled since
+    pub escalated: Privileges,
+}
+
+impl PrivilegeUse {
+    /// Returns the privileges enabled since the last probes.
+    pub fn update(&mut self, current: Privileges) -> Privileges {
+        let initial = *self.initial.get_or_insert(current);
+        let enabled = current.without(initial).without(self.escalated);
+        self.escalated = self.escalated.union(enabled);
+        enabled
+    }
+}
+
+#[derive(Debug)]
+struct Probed {
+    root_pid: u32,
+    last_probe: Option<Instant>,
+    last_seen: Instant,
+}
+
+/// Reads the privileges of the roots of the gids, for the fetch stage.
+#[derive(Debug, Default)]
+pub struct PrivilegeProbe {
+    gids: HashMap<u64, Probed>,
+}
+
+impl PrivilegeProbe {
+    pub fn new() -> PrivilegeProbe {
+        PrivilegeProbe { gids: HashMap::new() }
+    }
+
+    /// Tags *iomsg* with the privileges of the root of its gid (its first pid seen), if they were
+    /// not read for [PROBE_INTERVAL].
+    pub fn tag(&mut self, iomsg: &mut IOMessage) {
+        let now = Instant::now();
+        let probed = self.gids.entry(iomsg.gid).or_insert(Probed {
+            root_pid: iomsg.pid,
+            last_probe: None,
+            last_seen: now,
+        });
+        probed.last_seen = now;
+        if probed.last_probe.is_some_and(|t| now.duration_since(t) < PROBE_INTERVAL) {
+            return;
+        }
+        probed.last_probe = Some(now);
+        iomsg.runtime_features.privileges = token::privileges_from_pid(probed.root_pid);
+    }
+
+    /// Forgets the gids without message for [PROBE_TTL].
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        self.gids.retain(|_, probed| now.duration_since(probed.last_seen) < PROBE_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::privileges::{Privilege, PrivilegeUse, Privileges};
+
+    #[test]
+    fn privileges_enabled_during_the_run_should_be_escalated() {
+        let entry = |luid: u32, attributes: u32| [luid.to_le_bytes(), 0u32.to_le_bytes(), attributes.to_le_bytes()].concat();
+        // SeChangeNotify (23) enabled by default, SeBackup and SeRestore present but disabled
+        let mut before = 3u32.to_le_bytes().to_vec();
+        before.extend(entry(23, 0x3));
+        before.extend(entry(17, 0));
+        before.extend(entry(18, 0));
+        let initial = Privileges::from_token_privileges(&before);
+        assert!(initial.is_empty());
+
+        let mut after = 3u32.to_le_bytes().to_vec();
+        after.extend(entry(23, 0x3));
+        after.extend(entry(17, 0x2));
+        after.extend(entry(18, 0x2));
+        let current = Privileges::from_token_privileges(&after);
+        assert_eq!(current.names(), vec!["SeBackupPrivilege", "SeRestorePrivilege"]);
+        // truncated
+        assert_eq!(Privileges::from_token_privileges(&after[..20]), Privileges::default());
+
+        let mut usage = PrivilegeUse::default();
+        assert!(usage.update(initial).is_empty());
+        assert_eq!(usage.update(current).to_string(), "SeBackupPrivilege, SeRestorePrivilege");
+        // once
+        assert!(usage.update(current).is_empty());
+        assert!(usage.escalated.contains(Privilege::Restore));
+
+        // CAP_DAC_READ_SEARCH and CAP_SYS_PTRACE
+        let capabilities = Privileges::from_capabilities(0x80004);
+        assert_eq!(capabilities.names(), vec!["SeDebugPrivilege", "SeBackupPrivilege"]);
+    }
+}
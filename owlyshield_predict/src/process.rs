@@ -26,9 +26,10 @@
 
 
 use std::collections::HashSet;
-use std::os::raw::{c_ulong, c_ulonglong};
+use std::os::raw::c_ulonglong;
 use std::path::{Display, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::{fmt, thread};
@@ -36,20 +37,54 @@ use std::fmt::Formatter;
 use std::ops::Mul;
 use std::time::{Instant, SystemTime, Duration};
 
-use bindings::Windows::Win32::Storage::FileSystem::FILE_ID_128;
-use bindings::Windows::Win32::Storage::FileSystem::FILE_ID_INFO;
-use log::debug;
+use tracing::{debug, warn};
 use slc_paths::clustering::clustering;
 use sysinfo::{System, Pid, ProcessExt, ProcessStatus, SystemExt};
 
-use crate::config::Config;
+use crate::anomaly::AnomalyModel;
+use crate::autostart::AutostartMonitor;
+use crate::cloudsync::SyncClient;
+use crate::config::{Config, Param};
+use crate::container::Containment;
 use crate::csvwriter::CsvWriter;
+use crate::decay::DecayedActivity;
+use crate::dirtree::DirTree;
 use crate::driver_com::shared_def::*;
 use crate::driver_com::IrpMajorOp;
-use crate::extensions::ExtensionsCount;
-use crate::prediction::input_tensors::{PredictionRow, VecvecCapped, VecvecCappedF32};
+use crate::extensions::{ExtensionCategory, ExtensionsCount};
+use crate::escalation::Escalation;
+use crate::exfil::ExfilMonitor;
+use crate::extprofiles::ExtensionUsage;
+use crate::fastpath::FastPath;
+use crate::filetable::{FileOp, FileTable};
+use crate::fingerprint::FamilyFingerprinter;
+use crate::heatmap::WriteHeatmap;
+use crate::inputcapture::InputCaptureActivity;
+use crate::history::MsgHistory;
+use crate::integrity::Integrity;
+use crate::lolbin::LolbinContext;
+use crate::magic;
+use crate::payloads::DroppedPayloads;
+use crate::intern::PathInterner;
+use crate::features::REGISTRY;
+use crate::prediction::input_tensors::RollingFeatures;
 use crate::prediction::{Predictions, TfLite};
-use crate::prediction::{PREDMTRXCOLS, PREDMTRXROWS};
+use crate::prediction::PREDMTRXROWS;
+use crate::privileges::{PrivilegeUse, Privileges};
+use crate::ransomnote::RansomNoteDetector;
+use crate::reputation;
+use crate::reputation::Reputation;
+use crate::routing::ProcessCategory;
+use crate::scripthost::ScriptInvocation;
+use crate::wiper::WipeMonitor;
+use crate::wsl::LinuxProcess;
+use crate::sketch::BoundedSet;
+use crate::sysmon::SysmonActivity;
+use crate::token::ProcessOwner;
+
+/// Overwritten files waiting to be closed to be checked by [crate::magic]. Beyond, the new ones
+/// are not checked.
+const MAGIC_MAX_PENDING: usize = 1000;
 
 /// GID state in real-time. This is a central structure.
 ///
@@ -65,7 +100,9 @@ pub struct ProcessRecord<'a> {
     /// Group Identifier: a unique number (maintained by the minifilter) identifying this family of precesses.
     pub gid: c_ulonglong,
     /// Set of pids in this family of processes.
-    pub pids: HashSet<c_ulong>,
+    pub pids: HashSet<u32>,
+    /// Gids of the driver merged into this one, see [crate::gidmerge]
+    pub merged_gids: HashSet<u64>,
     /// Count of Read operations [crate::driver_com::IrpMajorOp::IrpRead]
     pub ops_read: u64,
     /// Count of SetInfo operations [crate::driver_com::IrpMajorOp::IrpSetInfo]
@@ -83,29 +120,69 @@ pub struct ProcessRecord<'a> {
     /// Total entropy write
     pub entropy_written: f64,
     /// File descriptors read
-    pub files_read: HashSet<FileId>,
+    pub files_read: BoundedSet<FileId>,
     /// File descriptors renamed
-    pub files_renamed: HashSet<FileId>,
+    pub files_renamed: BoundedSet<FileId>,
     /// File descriptors created
-    pub files_opened: HashSet<FileId>,
+    pub files_opened: BoundedSet<FileId>,
     /// File descriptors written
-    pub files_written: HashSet<FileId>,
+    pub files_written: BoundedSet<FileId>,
     /// File descriptors deleted
-    pub files_deleted: HashSet<FileId>,
+    pub files_deleted: BoundedSet<FileId>,
     /// File paths created
-    pub fpaths_created: HashSet<String>,
+    pub fpaths_created: BoundedSet<Arc<str>>,
     /// File paths updated (by a *setinfo* operation)
-    pub fpaths_updated: HashSet<String>,
+    pub fpaths_updated: BoundedSet<Arc<str>>,
     /// Directories having files created
-    pub dirs_with_files_created: HashSet<String>,
+    pub dirs_with_files_created: BoundedSet<Arc<str>>,
     /// Directories having files updated
-    pub dirs_with_files_updated: HashSet<String>,
+    pub dirs_with_files_updated: BoundedSet<Arc<str>>,
     /// Directories having files opened (a file handle has been created)
-    pub dirs_with_files_opened: HashSet<String>,
+    pub dirs_with_files_opened: BoundedSet<Arc<str>>,
+    /// Shape of the tree of the directories having files updated
+    pub dir_tree: DirTree,
+    /// Files whose header has been checked against their extension, see [crate::magic]
+    pub files_magic_checked: usize,
+    /// Files whose header does not match their extension
+    pub files_magic_mismatch: usize,
+    /// Files overwritten, checked when closed (under their path then, if renamed meanwhile)
+    magic_pending: HashSet<FileId>,
+    /// Files touched by their file id, with their original and last known paths, see [crate::filetable]
+    pub file_table: FileTable,
+    /// Writes by directory over time, see [crate::heatmap]
+    pub heatmap: WriteHeatmap,
+    /// Identical text files dropped in many directories
+    pub ransom_note: RansomNoteDetector,
+    /// Clues of a known ransomware family, see [crate::fingerprint]
+    pub fingerprint: FamilyFingerprinter,
+    /// Scheduled tasks and services created, see [crate::autostart]
+    pub autostart: AutostartMonitor,
+    /// Escalation of the obvious cases, without the model
+    pub fast_path: FastPath,
+    /// Deletion rate, see [crate::wiper]
+    pub wiper: WipeMonitor,
+    /// Documents read and archived, see [crate::exfil]
+    pub exfil: ExfilMonitor,
+    /// Level of the scores, see [crate::escalation]
+    pub escalation: Escalation,
+    /// Executables created, scanned once closed, see [crate::payloads]
+    pub payloads: DroppedPayloads,
+    /// Files written, renamed or deleted in the folders of the sync clients, see [crate::cloudsync]
+    pub files_written_sync: BoundedSet<FileId>,
+    /// Sync clients of these files
+    pub sync_clients: Vec<SyncClient>,
+    /// Writes on the network shares, see [crate::netshare]
+    pub ops_written_remote: u64,
+    /// Files written, renamed or deleted on the network shares
+    pub files_written_remote: BoundedSet<FileId>,
+    /// Writes, deletions, renames and entropy over the last minute, ten minutes and hour
+    pub decayed: DecayedActivity,
     /// Unique extensions read count
     pub extensions_read: ExtensionsCount<'a>,
     /// Unique extensions written count
     pub extensions_written: ExtensionsCount<'a>,
+    /// Extensions written, compared with the profile of the executable, see [crate::extprofiles]
+    pub extension_usage: ExtensionUsage,
     /// Path to the exe of the main process (the root)
     pub exepath: PathBuf,
     /// Process exe file still exists (father)?
@@ -120,6 +197,8 @@ pub struct ProcessRecord<'a> {
     pub time_killed: Option<SystemTime>,
     /// Time of process suspended
     pub time_suspended: Option<SystemTime>,
+    /// Time all the processes of the family were first seen exited, see [procs::Procs::reap]
+    pub time_exited: Option<SystemTime>,
     /// Number of directories (with files updated) clusters created
     pub clusters: usize,
     /// Deepest cluster size
@@ -129,7 +208,7 @@ pub struct ProcessRecord<'a> {
 
     config: &'a Config,
     /// Our capped-size matric to feed the input tensors (in [Self::eval]).
-    pub prediction_matrix: VecvecCappedF32,
+    pub prediction_matrix: RollingFeatures,
     /// History of past predictions, mainly used by [Self::is_to_predict].
     pub predictions: Predictions,
     /// CSVWriter to create the files used to train the model. Used with ```--features replay``` only.
@@ -145,33 +224,72 @@ pub struct ProcessRecord<'a> {
     last_thread_clustering_duration: Duration,
 
     /// Files sorted by size according to steps, with the [sort_file_size](Self::sort_file_size) function.
-    pub file_size_empty: HashSet<String>,
+    pub file_size_empty: BoundedSet<Arc<str>>,
     /// Files sorted by size according to steps, with the [sort_file_size](Self::sort_file_size) function.
-    pub file_size_tiny: HashSet<String>,
+    pub file_size_tiny: BoundedSet<Arc<str>>,
     /// Files sorted by size according to steps, with the [sort_file_size](Self::sort_file_size) function.
-    pub file_size_small: HashSet<String>,
+    pub file_size_small: BoundedSet<Arc<str>>,
     /// Files sorted by size according to steps, with the [sort_file_size](Self::sort_file_size) function.
-    pub file_size_medium: HashSet<String>,
+    pub file_size_medium: BoundedSet<Arc<str>>,
     /// Files sorted by size according to steps, with the [sort_file_size](Self::sort_file_size) function.
-    pub file_size_large: HashSet<String>,
+    pub file_size_large: BoundedSet<Arc<str>>,
     /// Files sorted by size according to steps, with the [sort_file_size](Self::sort_file_size) function.
-    pub file_size_huge: HashSet<String>,
-
-    /// Number of bytes transferred sorted according to steps, with the [sort_bytes](Self::sort_bytes) function.
-    pub bytes_size_empty: Vec<c_ulonglong>,
-    /// Number of bytes transferred sorted according to steps, with the [sort_bytes](Self::sort_bytes) function.
-    pub bytes_size_tiny: Vec<c_ulonglong>,
-    /// Number of bytes transferred sorted according to steps, with the [sort_bytes](Self::sort_bytes) function.
-    pub bytes_size_small: Vec<c_ulonglong>,
-    /// Number of bytes transferred sorted according to steps, with the [sort_bytes](Self::sort_bytes) function.
-    pub bytes_size_medium: Vec<c_ulonglong>,
-    /// Number of bytes transferred sorted according to steps, with the [sort_bytes](Self::sort_bytes) function.
-    pub bytes_size_large: Vec<c_ulonglong>,
-    /// Number of bytes transferred sorted according to steps, with the [sort_bytes](Self::sort_bytes) function.
-    pub bytes_size_huge: Vec<c_ulonglong>,
+    pub file_size_huge: BoundedSet<Arc<str>>,
+
+    /// Number of transfers sorted by size according to steps, with the [sort_bytes](Self::sort_bytes) function.
+    pub bytes_size_empty: u64,
+    /// Number of transfers sorted by size according to steps, with the [sort_bytes](Self::sort_bytes) function.
+    pub bytes_size_tiny: u64,
+    /// Number of transfers sorted by size according to steps, with the [sort_bytes](Self::sort_bytes) function.
+    pub bytes_size_small: u64,
+    /// Number of transfers sorted by size according to steps, with the [sort_bytes](Self::sort_bytes) function.
+    pub bytes_size_medium: u64,
+    /// Number of transfers sorted by size according to steps, with the [sort_bytes](Self::sort_bytes) function.
+    pub bytes_size_large: u64,
+    /// Number of transfers sorted by size according to steps, with the [sort_bytes](Self::sort_bytes) function.
+    pub bytes_size_huge: u64,
 
     /// Static Prediction
     pub prediction_static: Option<f32>,
+    /// Excluded from kills by a [crate::exclusions::ExclusionScope::NeverKill] rule
+    pub never_kill: bool,
+    /// Found malicious in the *AUDIT* or *LEARNING* [crate::config::Mode], so not killed
+    pub would_kill: bool,
+    /// User and session of the first process of the gid
+    pub owner: Option<ProcessOwner>,
+    /// *THRESHOLD_PREDICTION* (of the active [crate::profiles] profile), or the one of the
+    /// [crate::exclusions::UserPolicy] of the owner
+    pub threshold_prediction: f32,
+    /// Threshold of the [crate::exclusions::UserPolicy] of the owner, which wins over the profiles
+    pub policy_threshold: Option<f32>,
+    /// Name of the [crate::backup] agent the gid belongs to
+    pub backup_agent: Option<&'static str>,
+    /// That agent is running a job: the write volumes are relaxed
+    pub backup_job: bool,
+    /// Prior of the [crate::reputation] of the executable
+    pub reputation: Option<f32>,
+    /// What the root of the gid runs, if it is a [crate::scripthost]
+    pub script: Option<ScriptInvocation>,
+    /// What the root of the gid runs, if it is a [crate::lolbin]
+    pub lolbin: Option<LolbinContext>,
+    /// Job object, AppContainer or container of the root of the gid, see [crate::container]
+    pub containment: Containment,
+    /// Hollowing of the root of the gid, or spoofed parent of one of its processes, see [crate::integrity]
+    pub integrity: Integrity,
+    /// Privileges of the root of the gid, and those it enabled during the run, see [crate::privileges]
+    pub privileges: PrivilegeUse,
+    /// Some for the WSL hosts, with the Linux processes writing on the Windows drives, see [crate::wsl]
+    pub wsl: Option<Vec<LinuxProcess>>,
+    /// Process creations, connections and file creations seen by Sysmon, see [crate::sysmon]
+    pub sysmon: SysmonActivity,
+    /// Keyboard and clipboard APIs called, see [crate::inputcapture]
+    pub input_capture: InputCaptureActivity,
+    /// Selects the model scoring the gid, see [crate::routing]
+    pub category: ProcessCategory,
+    /// Last driver messages, for the incident reports
+    pub history: MsgHistory,
+    /// Shared allocations of the paths stored in the sets above
+    paths: PathInterner,
 }
 
 /// A tuple-struct to communicate with the thread in charge of calculating the clusters.
@@ -190,11 +308,13 @@ impl ProcessRecord<'_> {
         prediction_static: Option<f32>
     ) -> ProcessRecord<'a> {
         let (tx, rx) = mpsc::channel::<MultiThreadClustering>();
+        let max_entries = config.get_usize(Param::HistoryMaxEntries);
 
         ProcessRecord {
             appname: appname,
             gid: iomsg.gid,
             pids: HashSet::new(),
+            merged_gids: HashSet::new(),
             ops_read: 0,
             ops_setinfo: 0,
             ops_written: 0,
@@ -203,18 +323,38 @@ impl ProcessRecord<'_> {
             bytes_written: 0,
             entropy_read: 0.0,
             entropy_written: 0.0,
-            files_read: HashSet::new(),
-            files_renamed: HashSet::new(),
-            files_opened: HashSet::new(),
-            files_written: HashSet::new(),
-            files_deleted: HashSet::new(),
-            fpaths_created: HashSet::new(),
-            fpaths_updated: HashSet::new(),
-            dirs_with_files_created: HashSet::new(),
-            dirs_with_files_updated: HashSet::new(),
-            dirs_with_files_opened: HashSet::new(),
+            files_read: BoundedSet::new(max_entries),
+            files_renamed: BoundedSet::new(max_entries),
+            files_opened: BoundedSet::new(max_entries),
+            files_written: BoundedSet::new(max_entries),
+            files_deleted: BoundedSet::new(max_entries),
+            fpaths_created: BoundedSet::new(max_entries),
+            fpaths_updated: BoundedSet::new(max_entries),
+            dirs_with_files_created: BoundedSet::new(max_entries),
+            dirs_with_files_updated: BoundedSet::new(max_entries),
+            dirs_with_files_opened: BoundedSet::new(max_entries),
+            dir_tree: DirTree::new(max_entries),
+            files_magic_checked: 0,
+            files_magic_mismatch: 0,
+            magic_pending: HashSet::new(),
+            file_table: FileTable::new(max_entries),
+            heatmap: WriteHeatmap::new(),
+            ransom_note: RansomNoteDetector::new(),
+            fingerprint: FamilyFingerprinter::new(),
+            autostart: AutostartMonitor::new(),
+            fast_path: FastPath::from(config),
+            wiper: WipeMonitor::from(config),
+            exfil: ExfilMonitor::from(config),
+            escalation: Escalation::from(config),
+            payloads: DroppedPayloads::from(config),
+            files_written_sync: BoundedSet::new(max_entries),
+            sync_clients: Vec::new(),
+            ops_written_remote: 0,
+            files_written_remote: BoundedSet::new(max_entries),
+            decayed: DecayedActivity::new(),
             extensions_read: ExtensionsCount::new(&config.extensions_list),
             extensions_written: ExtensionsCount::new(&config.extensions_list),
+            extension_usage: ExtensionUsage::default(),
             exepath: exepath,
             exe_exists: true,
             process_state: ProcessState::Running,
@@ -222,7 +362,7 @@ impl ProcessRecord<'_> {
             time_started: SystemTime::now(),
             time_killed: None,
             config: &config,
-            prediction_matrix: VecvecCapped::new(PREDMTRXCOLS, PREDMTRXROWS),
+            prediction_matrix: RollingFeatures::new(PREDMTRXROWS),
             predictions: Predictions::new(),
             debug_csv_writer: CsvWriter::from(&config),
             driver_msg_count: 0,
@@ -233,26 +373,60 @@ impl ProcessRecord<'_> {
             is_thread_clustering_running: false,
             last_thread_clustering_time: SystemTime::now(),
             last_thread_clustering_duration: Duration::ZERO,
-            file_size_empty: HashSet::new(),
-            file_size_tiny: HashSet::new(),
-            file_size_small: HashSet::new(),
-            file_size_medium: HashSet::new(),
-            file_size_large: HashSet::new(),
-            file_size_huge: HashSet::new(),
-            bytes_size_empty: Vec::new(),
-            bytes_size_tiny: Vec::new(),
-            bytes_size_small: Vec::new(),
-            bytes_size_medium: Vec::new(),
-            bytes_size_large: Vec::new(),
-            bytes_size_huge: Vec::new(),
+            file_size_empty: BoundedSet::new(max_entries),
+            file_size_tiny: BoundedSet::new(max_entries),
+            file_size_small: BoundedSet::new(max_entries),
+            file_size_medium: BoundedSet::new(max_entries),
+            file_size_large: BoundedSet::new(max_entries),
+            file_size_huge: BoundedSet::new(max_entries),
+            bytes_size_empty: 0,
+            bytes_size_tiny: 0,
+            bytes_size_small: 0,
+            bytes_size_medium: 0,
+            bytes_size_large: 0,
+            bytes_size_huge: 0,
             prediction_static: prediction_static,
-            time_suspended: None
+            time_suspended: None,
+            time_exited: None,
+            never_kill: false,
+            would_kill: false,
+            owner: None,
+            threshold_prediction: config.get_threshold_prediction(),
+            policy_threshold: None,
+            backup_agent: None,
+            backup_job: false,
+            reputation: None,
+            script: None,
+            lolbin: None,
+            containment: Containment::default(),
+            integrity: Integrity::default(),
+            privileges: PrivilegeUse::default(),
+            wsl: None,
+            sysmon: SysmonActivity::default(),
+            input_capture: InputCaptureActivity::default(),
+            category: ProcessCategory::default(),
+            history: MsgHistory::from(config, iomsg.gid),
+            paths: PathInterner::new(max_entries),
+        }
+    }
+
+    /// The owner of the gid, for the logs and reports.
+    pub fn user(&self) -> String {
+        self.owner.as_ref().map_or(String::from("unknown"), |o| o.to_string())
+    }
+
+    /// *BACKUP_WRITE_RELAX*, while the [crate::backup] agent of the gid is running a job.
+    pub fn backup_write_relax(&self) -> Option<f32> {
+        if self.backup_job {
+            Some(self.config.get_f32(Param::BackupWriteRelax))
+        } else {
+            None
         }
     }
 
     pub fn launch_thread_clustering(&self) {
         let tx = self.tx.to_owned();
-        let dir_with_files_u = self.dirs_with_files_updated.clone();
+        let dir_with_files_u: HashSet<String> = self.dirs_with_files_updated.iter().map(|d| d.to_string()).collect();
         thread::spawn(move || {
             let cs = clustering(dir_with_files_u.clone());
             let res = MultiThreadClustering {
@@ -266,29 +440,142 @@ impl ProcessRecord<'_> {
     /// Entry point to call on new drivermsg.
     pub fn add_irp_record(&mut self, iomsg: &IOMessage) {
         self.driver_msg_count += 1;
-        self.pids.insert(iomsg.pid.clone());
+        if self.pids.insert(iomsg.pid) {
+            self.autostart.on_process(iomsg.pid, received(iomsg));
+        }
+        if let Some(gid) = iomsg.runtime_features.driver_gid {
+            self.merged_gids.insert(gid);
+        }
         self.exe_exists = iomsg.runtime_features.exe_still_exists;
+        if let Some(privileges) = iomsg.runtime_features.privileges {
+            self.update_privileges(privileges);
+        }
+        self.history.push(iomsg);
+        if iomsg.file_change == FileChangeInfo::FileChangeRawDiskWrite as u8 {
+            // not a file: no feature, escalated at once
+            self.fast_path.on_raw_disk_write(&iomsg.filepathstr);
+            return;
+        }
+        if let Some(client) = iomsg.runtime_features.sync_client {
+            self.update_sync(client, iomsg);
+        }
+        if iomsg.runtime_features.remote {
+            self.update_remote(iomsg);
+        }
         match IrpMajorOp::from_byte(iomsg.irp_op) {
             IrpMajorOp::IrpNone => {}
             IrpMajorOp::IrpRead => self.update_read(&iomsg),
             IrpMajorOp::IrpWrite => self.update_write(&iomsg),
             IrpMajorOp::IrpSetInfo => self.update_set(&iomsg),
             IrpMajorOp::IrpCreate => self.update_create(&iomsg),
-            IrpMajorOp::IrpCleanUp => {}
+            IrpMajorOp::IrpCleanUp => self.update_cleanup(&iomsg),
+        }
+    }
+
+    /// Adds the irps summarized by the minifilter while the pipeline was falling behind (see
+    /// [crate::backpressure]): only the counters, without the files.
+    pub fn add_aggregate(&mut self, aggregate: &IrpAggregate) {
+        let ops = |op: IrpMajorOp| aggregate.ops[op as usize];
+        let changes = |change: FileChangeInfo| aggregate.file_changes[change as usize];
+        self.driver_msg_count += aggregate.ops.iter().sum::<u32>() as usize;
+        self.pids.insert(aggregate.pid);
+        self.ops_read += ops(IrpMajorOp::IrpRead) as u64;
+        self.ops_written += ops(IrpMajorOp::IrpWrite) as u64;
+        self.ops_setinfo += ops(IrpMajorOp::IrpSetInfo) as u64;
+        self.ops_open += ops(IrpMajorOp::IrpCreate) as u64;
+        self.bytes_read += aggregate.bytes_read;
+        self.bytes_written += aggregate.bytes_written;
+        self.decayed.on_aggregate(
+            ops(IrpMajorOp::IrpWrite),
+            changes(FileChangeInfo::FileChangeDeleteFile),
+            changes(FileChangeInfo::FileChangeRenameFile) + changes(FileChangeInfo::FileChangeExtensionChanged),
+            SystemTime::now(),
+        );
+    }
+
+    fn update_sync(&mut self, client: SyncClient, iomsg: &IOMessage) {
+        if matches!(IrpMajorOp::from_byte(iomsg.irp_op), IrpMajorOp::IrpWrite | IrpMajorOp::IrpSetInfo) {
+            self.files_written_sync.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id));
+            if !self.sync_clients.contains(&client) {
+                self.sync_clients.push(client);
+            }
+        }
+    }
+
+    fn update_privileges(&mut self, privileges: Privileges) {
+        let enabled = self.privileges.update(privileges);
+        if !enabled.is_empty() {
+            warn!(gid = self.gid, appname = %self.appname, privileges = %enabled, "Privileges enabled during the run");
+        }
+    }
+
+    fn update_remote(&mut self, iomsg: &IOMessage) {
+        match IrpMajorOp::from_byte(iomsg.irp_op) {
+            IrpMajorOp::IrpWrite => {
+                self.ops_written_remote += 1;
+                self.files_written_remote.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id));
+            }
+            IrpMajorOp::IrpSetInfo => {
+                self.files_written_remote.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id));
+            }
+            _ => {}
+        }
+    }
+
+    fn update_cleanup(&mut self, iomsg: &IOMessage) {
+        if self.exfil.is_pending(&iomsg.filepathstr) {
+            self.exfil.on_closed(&iomsg.filepathstr, received(iomsg));
+        }
+        if self.magic_pending.remove(&FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id)) {
+            self.check_magic(&iomsg.filepathstr);
+        }
+        if self.payloads.is_pending(&iomsg.filepathstr) {
+            self.payloads.on_closed(&iomsg.filepathstr, received(iomsg));
+        }
+        if self.ransom_note.is_pending(&iomsg.filepathstr) {
+            let dir = self.paths.dir(&iomsg.filepathstr);
+            // sent by the worker, see RansomNoteDetector::take_escalation
+            self.ransom_note.on_closed(&iomsg.filepathstr, dir);
+        }
+    }
+
+    fn check_fast_path(&mut self, fpath: &str, renamed: bool, time: SystemTime) {
+        if let Some(extension) = Path::new(fpath).extension().and_then(|e| e.to_str()) {
+            self.fast_path
+                .on_file(&self.config.extensions_list, extension, renamed, time);
+        }
+    }
+
+    fn check_magic(&mut self, fpath: &str) {
+        if let Some(is_match) = magic::check_file(Path::new(fpath)) {
+            self.files_magic_checked += 1;
+            if !is_match {
+                self.files_magic_mismatch += 1;
+            }
+        }
+    }
+
+    /// Ratio of the files checked by [crate::magic] whose header does not match the extension.
+    pub fn magic_mismatch_ratio(&self) -> f32 {
+        if self.files_magic_checked == 0 {
+            0.0
+        } else {
+            self.files_magic_mismatch as f32 / self.files_magic_checked as f32
         }
     }
 
     fn update_read(&mut self, iomsg: &IOMessage) {
         self.ops_read += 1;
         self.bytes_read += iomsg.mem_sized_used;
-        self.files_read.insert(FileId::from(&FILE_ID_INFO {
-            FileId: FILE_ID_128 {
-                Identifier: iomsg.file_id_id,
-            },
-            VolumeSerialNumber: iomsg.file_id_vsn,
-        })); //FileId::from(&drivermsg.file_id));
-        self.extensions_read
-            .add_cat_extension(&*String::from_utf16_lossy(&iomsg.extension));
+        self.files_read.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id)); //FileId::from(&drivermsg.file_id));
+        let fpath = self.paths.file(iomsg);
+        self.record_file(iomsg, &fpath, FileOp::Read);
+        let extension = String::from_utf16_lossy(&iomsg.extension);
+        self.extensions_read.add_cat_extension(&extension);
+        let extension = extension.trim_matches(char::from(0));
+        if self.config.extensions_list.get_extension_category(extension) == ExtensionCategory::Docs {
+            self.exfil.on_doc_read(&iomsg.filepathstr, received(iomsg));
+        }
         self.entropy_read =
             (iomsg.entropy * (iomsg.mem_sized_used as f64)) + self.entropy_read;
     }
@@ -296,31 +583,28 @@ impl ProcessRecord<'_> {
     fn update_write(&mut self, iomsg: &IOMessage) {
         self.ops_written += 1;
         self.bytes_written += iomsg.mem_sized_used;
-        let fpath = iomsg.filepathstr.clone(); //.to_string();
+        let fpath = self.paths.file(iomsg);
         self.fpaths_updated.insert(fpath.clone());
-        self.files_written.insert(FileId::from(&FILE_ID_INFO {
-            FileId: FILE_ID_128 {
-                Identifier: iomsg.file_id_id,
-            },
-            VolumeSerialNumber: iomsg.file_id_vsn,
-        })); //FileId::from(&drivermsg.file_id));
+        self.files_written.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id)); //FileId::from(&drivermsg.file_id));
+        self.record_file(iomsg, &fpath, FileOp::Written);
              //if let Some(dir) = &drivermsg.filepath.dirname() {
-        if let Some(dir) = Some(
-            Path::new(&iomsg.filepathstr)
-                .parent()
-                .unwrap_or(Path::new(r".\"))
-                .to_string_lossy()
-                .parse()
-                .unwrap(),
-        ) {
-            self.dirs_with_files_updated.insert(dir);
-        }
+        let dir = self.paths.dir(&fpath);
+        self.heatmap.on_write(&dir, received(iomsg));
+        self.add_dir_updated(dir);
         self.extensions_written
             .add_cat_extension(&*String::from_utf16_lossy(&iomsg.extension));
+        self.extension_usage.on_write(&String::from_utf16_lossy(&iomsg.extension));
         self.entropy_written =
             (iomsg.entropy * (iomsg.mem_sized_used as f64)) + self.entropy_written;
         self.sort_bytes(iomsg.mem_sized_used);
-        self.sort_file_size(iomsg.file_size, &iomsg.filepathstr);
+        self.sort_file_size(iomsg.file_size, &fpath);
+        self.decayed.on_write(iomsg.entropy, iomsg.mem_sized_used, received(iomsg));
+        self.wiper.on_write(iomsg.entropy, received(iomsg));
+        // a document is not staged for exfiltration, whatever its entropy
+        let extension = String::from_utf16_lossy(&iomsg.extension);
+        if self.config.extensions_list.get_extension_category(extension.trim_matches(char::from(0))) != ExtensionCategory::Docs {
+            self.exfil.on_write(&fpath, iomsg.entropy, received(iomsg));
+        }
     }
 
     /// When
@@ -328,71 +612,43 @@ impl ProcessRecord<'_> {
         self.ops_setinfo += 1;
         let file_location_enum: Option<FileLocationInfo> = num::FromPrimitive::from_u8(iomsg.file_location_info);
         let file_change_enum = num::FromPrimitive::from_u8(iomsg.file_change);
-        let fpath = iomsg.filepathstr.clone(); //.to_string();
+        let fpath = self.paths.file(iomsg);
         match file_change_enum {
             Some(FileChangeInfo::FileChangeDeleteFile) => {
-                self.files_deleted.insert(FileId::from(&FILE_ID_INFO {
-                    FileId: FILE_ID_128 {
-                        Identifier: iomsg.file_id_id,
-                    },
-                    VolumeSerialNumber: iomsg.file_id_vsn,
-                })); //FileId::from(&drivermsg.file_id));
-
+                self.files_deleted.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id)); //FileId::from(&drivermsg.file_id));
+                self.record_file(iomsg, &fpath, FileOp::Deleted);
                 self.fpaths_updated.insert(fpath.clone());
-                if let Some(dir) = Some(
-                    Path::new(&iomsg.filepathstr)
-                        .parent()
-                        .unwrap_or(Path::new(r".\"))
-                        .to_string_lossy()
-                        .parse()
-                        .unwrap(),
-                ) {
-                    //if let Some(dir) = drivermsg.filepath.dirname() {
-                    self.dirs_with_files_updated.insert(dir);
-                }
+                let dir = self.paths.dir(&fpath);
+                self.add_dir_updated(dir);
+                self.decayed.on_delete(received(iomsg));
+                self.wiper.on_delete(&iomsg.filepathstr, received(iomsg));
             }
             Some(FileChangeInfo::FileChangeExtensionChanged) => {
                 self.extensions_written
                     .add_cat_extension(&*String::from_utf16_lossy(&iomsg.extension));
+                self.extension_usage.on_write(&String::from_utf16_lossy(&iomsg.extension));
 
                 self.fpaths_updated.insert(fpath.clone());
                 //if let Some(dir) = drivermsg.filepath.dirname() {
-                if let Some(dir) = Some(
-                    Path::new(&iomsg.filepathstr)
-                        .parent()
-                        .unwrap_or(Path::new(r".\"))
-                        .to_string_lossy()
-                        .parse()
-                        .unwrap(),
-                ) {
-                    self.dirs_with_files_updated.insert(dir);
-                }
-                self.files_renamed.insert(FileId::from(&FILE_ID_INFO {
-                    FileId: FILE_ID_128 {
-                        Identifier: iomsg.file_id_id,
-                    },
-                    VolumeSerialNumber: iomsg.file_id_vsn,
-                })); //FileId::from(&drivermsg.file_id));
+                let dir = self.paths.dir(&fpath);
+                self.add_dir_updated(dir);
+                self.files_renamed.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id)); //FileId::from(&drivermsg.file_id));
+                self.record_file(iomsg, &fpath, FileOp::Renamed);
+                self.decayed.on_rename(received(iomsg));
+                self.check_magic(&fpath);
+                self.check_fast_path(&fpath, true, received(iomsg));
+                self.fingerprint.on_file(&fpath);
             }
             Some(FileChangeInfo::FileChangeRenameFile) => {
                 self.fpaths_updated.insert(fpath.clone());
-                if let Some(dir) = Some(
-                    Path::new(&iomsg.filepathstr)
-                        .parent()
-                        .unwrap_or(Path::new(r".\"))
-                        .to_string_lossy()
-                        .parse()
-                        .unwrap(),
-                ) {
-                    //if let Some(dir) = drivermsg.filepath.dirname() {
-                    self.dirs_with_files_updated.insert(dir);
-                }
-                self.files_renamed.insert(FileId::from(&FILE_ID_INFO {
-                    FileId: FILE_ID_128 {
-                        Identifier: iomsg.file_id_id,
-                    },
-                    VolumeSerialNumber: iomsg.file_id_vsn,
-                })); //FileId::from(&drivermsg.file_id));
+                let dir = self.paths.dir(&fpath);
+                self.add_dir_updated(dir);
+                self.files_renamed.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id)); //FileId::from(&drivermsg.file_id));
+                self.record_file(iomsg, &fpath, FileOp::Renamed);
+                self.decayed.on_rename(received(iomsg));
+                self.check_magic(&fpath);
+                self.check_fast_path(&fpath, false, received(iomsg));
+                self.fingerprint.on_file(&fpath);
             }
             _ => {}
         }
@@ -430,76 +686,60 @@ impl ProcessRecord<'_> {
         self.extensions_written
             .add_cat_extension(&*String::from_utf16_lossy(&iomsg.extension));
         let file_change_enum = num::FromPrimitive::from_u8(iomsg.file_change);
-        let fpath = iomsg.filepathstr.clone(); //.to_string();
+        let fpath = self.paths.file(iomsg);
         match file_change_enum {
             Some(FileChangeInfo::FileChangeNewFile) => {
-                self.files_opened.insert(FileId::from(&FILE_ID_INFO {
-                    FileId: FILE_ID_128 {
-                        Identifier: iomsg.file_id_id,
-                    },
-                    VolumeSerialNumber: iomsg.file_id_vsn,
-                })); //FileId::from(&drivermsg.file_id));
-                self.fpaths_created.insert(fpath); //todo
-                if let Some(dir) = Some(
-                    Path::new(&iomsg.filepathstr)
-                        .parent()
-                        .unwrap_or(Path::new(r".\"))
-                        .to_string_lossy()
-                        .parse()
-                        .unwrap(),
-                ) {
-                    //if let Some(dir) = drivermsg.filepath.dirname() {
-                    self.dirs_with_files_created.insert(dir);
-                }
+                self.files_opened.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id)); //FileId::from(&drivermsg.file_id));
+                self.fpaths_created.insert(fpath.clone()); //todo
+                self.record_file(iomsg, &fpath, FileOp::Created);
+                self.ransom_note.on_created(&fpath);
+                self.payloads.on_created(&fpath);
+                self.check_fast_path(&fpath, false, received(iomsg));
+                self.fingerprint.on_file(&fpath);
+                self.autostart.on_file(&fpath, iomsg.pid, received(iomsg));
+                let dir = self.paths.dir(&fpath);
+                self.dirs_with_files_created.insert(dir);
             }
             Some(FileChangeInfo::FileChangeOverwriteFile) => {
                 //file is overwritten
-                self.files_opened.insert(FileId::from(&FILE_ID_INFO {
-                    FileId: FILE_ID_128 {
-                        Identifier: iomsg.file_id_id,
-                    },
-                    VolumeSerialNumber: iomsg.file_id_vsn,
-                })); //FileId::from(&drivermsg.file_id));
+                let id = FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id);
+                self.files_opened.insert(id.clone()); //FileId::from(&drivermsg.file_id));
+                self.record_file(iomsg, &fpath, FileOp::Written);
+                self.autostart.on_file(&fpath, iomsg.pid, received(iomsg));
+                // the new content is written after this create: checked on cleanup
+                if self.magic_pending.len() < MAGIC_MAX_PENDING {
+                    self.magic_pending.insert(id);
+                }
             }
             Some(FileChangeInfo::FileChangeDeleteFile) => {
                 //opened and deleted on close
-                self.files_deleted.insert(FileId::from(&FILE_ID_INFO {
-                    FileId: FILE_ID_128 {
-                        Identifier: iomsg.file_id_id,
-                    },
-                    VolumeSerialNumber: iomsg.file_id_vsn,
-                })); //FileId::from(&drivermsg.file_id));
-                self.fpaths_updated.insert(fpath);
-                if let Some(dir) = Some(
-                    Path::new(&iomsg.filepathstr)
-                        .parent()
-                        .unwrap_or(Path::new(r".\"))
-                        .to_string_lossy()
-                        .parse()
-                        .unwrap(),
-                ) {
-                    //if let Some(dir) = drivermsg.filepath.dirname() {
-                    self.dirs_with_files_updated.insert(dir);
-                }
+                self.files_deleted.insert(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id)); //FileId::from(&drivermsg.file_id));
+                self.record_file(iomsg, &fpath, FileOp::Deleted);
+                self.fpaths_updated.insert(fpath.clone());
+                let dir = self.paths.dir(&fpath);
+                self.add_dir_updated(dir);
+                self.decayed.on_delete(received(iomsg));
+                self.wiper.on_delete(&iomsg.filepathstr, received(iomsg));
             }
             Some(FileChangeInfo::FileOpenDirectory) => {
-                if let Some(dir) = Some(
-                    Path::new(&iomsg.filepathstr)
-                        .parent()
-                        .unwrap_or(Path::new(r".\"))
-                        .to_string_lossy()
-                        .parse()
-                        .unwrap(),
-                ) {
-                    //if let Some(dir) = drivermsg.filepath.dirname() {
-                    self.dirs_with_files_opened.insert(dir);
-                }
+                let dir = self.paths.dir(&fpath);
+                self.dirs_with_files_opened.insert(dir);
             }
             _ => {}
         }
     }
 
-    /// Sorts the number of bytes transferred according to the defined levels:
+    fn record_file(&mut self, iomsg: &IOMessage, fpath: &Arc<str>, op: FileOp) {
+        self.file_table
+            .record(FileId::new(iomsg.file_id_vsn, &iomsg.file_id_id), fpath, op, received(iomsg));
+    }
+
+    fn add_dir_updated(&mut self, dir: Arc<str>) {
+        self.dir_tree.add(&dir);
+        self.dirs_with_files_updated.insert(dir);
+    }
+
+    /// Counts the transfers by number of bytes according to the defined levels:
     /// * Empty	    (0 KB)
     /// * Tiny	    (0 – 16 KB)
     /// * Small	    (16 KB – 1 MB)
@@ -508,17 +748,17 @@ impl ProcessRecord<'_> {
     /// * Huge	    (> 1 GB)
     fn sort_bytes(&mut self, bytes: c_ulonglong) {
         if bytes == 0 {
-            self.bytes_size_empty.push(0);
+            self.bytes_size_empty += 1;
         } else if bytes > 0 && bytes <= 16_000 {
-            self.bytes_size_tiny.push(bytes);
+            self.bytes_size_tiny += 1;
         } else if bytes > 16_000 && bytes <= 1_000_000 {
-            self.bytes_size_small.push(bytes);
+            self.bytes_size_small += 1;
         } else if bytes > 1_000_000 && bytes <= 128_000_000 {
-            self.bytes_size_medium.push(bytes);
+            self.bytes_size_medium += 1;
         } else if bytes > 128_000_000 && bytes <= 1_000_000_000 {
-            self.bytes_size_large.push(bytes);
+            self.bytes_size_large += 1;
         } else if bytes > 1_000_000_000 {
-            self.bytes_size_huge.push(bytes);
+            self.bytes_size_huge += 1;
         }
     }
 
@@ -529,7 +769,7 @@ impl ProcessRecord<'_> {
     /// * Medium    (1 – 128 MB)
     /// * Large	    (128 MB – 1 GB)
     /// * Huge	    (> 1 GB)
-    fn sort_file_size(&mut self, fsize: i64, fpath: &String) {
+    fn sort_file_size(&mut self, fsize: i64, fpath: &Arc<str>) {
         if fsize == 0 {
             self.file_size_empty.insert(fpath.clone());
         } else if fsize > 0 && fsize <= 16_000 {
@@ -546,7 +786,7 @@ impl ProcessRecord<'_> {
     }
 
     pub fn write_learn_csv(&mut self) {
-        let predict_row = PredictionRow::from(&self);
+        let predict_row = REGISTRY.extract(self);
         //println!("Prediction Row - {:?}", predict_row);
         if self.driver_msg_count % self.config.threshold_drivermsgs == 0 {
             self.debug_csv_writer
@@ -556,6 +796,19 @@ impl ProcessRecord<'_> {
     }
 
     fn ponderate_predictions(&self, rows_len: usize, prediction: f32) -> f32 {
+        let prediction = self.ponderate_static(rows_len, prediction);
+        let prediction = match self.reputation {
+            Some(prior) => reputation::mix(Reputation::weight(self.config), prediction, prior),
+            None => prediction,
+        };
+        if self.integrity.is_suspicious() {
+            reputation::mix(self.config.get_f32(Param::IntegrityWeight), prediction, 1.0)
+        } else {
+            prediction
+        }
+    }
+
+    fn ponderate_static(&self, rows_len: usize, prediction: f32) -> f32 {
         if let Some(prediction_static) = self.prediction_static {
             // eprintln!("exepath.display() = {:?}", self.exepath.display());
             // eprintln!("prediction = {:?}", prediction);
@@ -573,12 +826,12 @@ impl ProcessRecord<'_> {
     }
 
     /// Manages computed features (calculated on a separate thread) and make a prediction if needed
-    /// by [Self::is_to_predict].
-    pub fn eval(&mut self, tflite: &TfLite) -> Option<(VecvecCappedF32, f32)> {
-        let predict_row = PredictionRow::from(&self);
+    /// by [Self::is_to_predict], with the *anomaly* model if any.
+    pub fn eval(&mut self, tflite: &TfLite, anomaly: Option<&AnomalyModel>) -> Option<(RollingFeatures, f32)> {
+        let predict_row = REGISTRY.extract(self);
 
         if self.driver_msg_count % self.config.threshold_drivermsgs == 0 {
-            self.prediction_matrix.push_row(predict_row.to_vec_f32()).unwrap();
+            self.prediction_matrix.push_row(predict_row).unwrap();
 
             if self.is_to_cluster() {
                 let start = Instant::now();
@@ -601,7 +854,12 @@ impl ProcessRecord<'_> {
 
             if self.prediction_matrix.rows_len() > 0 {
                 if self.is_to_predict() {
-                    let prediction = self.ponderate_predictions(self.prediction_matrix.rows_len(), tflite.make_prediction(&self.prediction_matrix));
+                    let prediction = match anomaly {
+                        Some(anomaly) => anomaly.predict(tflite, &self.prediction_matrix),
+                        None => tflite.make_prediction(&self.prediction_matrix),
+                    };
+                    // None if the model failed: the prediction is skipped
+                    let prediction = self.ponderate_predictions(self.prediction_matrix.rows_len(), prediction?);
                     //println!("PROC: {:?}", self);
                     //println!("MTRX: {:?}", self.predmtrx);
                     //println!("{}", prediction);
@@ -667,8 +925,13 @@ impl ProcessRecord<'_> {
     }
 }
 
-/// A simple tuple-struct about Windows fileids
-#[derive(Debug, Hash, PartialEq, Eq)]
+/// Time of *iomsg* (see [crate::clock]), now for the records made before it was added.
+fn received(iomsg: &IOMessage) -> SystemTime {
+    iomsg.runtime_features.time.unwrap_or_else(SystemTime::now)
+}
+
+/// A simple tuple-struct about fileids (Windows FILE_ID_INFO, or device and inode on Linux)
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct FileId {
     /// Volume identifier
     pub volume_serial: u64,
@@ -677,12 +940,17 @@ pub struct FileId {
 }
 
 impl FileId {
-    pub fn from(file_id_info: &FILE_ID_INFO) -> FileId {
+    pub fn new(volume_serial: u64, file_id: &[u8; 16]) -> FileId {
         FileId {
-            volume_serial: file_id_info.VolumeSerialNumber,
-            file_id: file_id_info.FileId.Identifier.to_vec(),
+            volume_serial,
+            file_id: file_id.to_vec(),
         }
     }
+
+    /// No file id (raw disk writes, aggregated records).
+    pub fn is_null(&self) -> bool {
+        self.file_id.iter().all(|b| *b == 0)
+    }
 }
 
 #[derive(std::cmp::PartialEq, Debug)]
@@ -702,19 +970,116 @@ impl fmt::Display for ProcessState {
     }
 }
 
+/// Summary of a process family whose state has been expired (see [procs::Procs::reap]), sent to
+/// the connectors.
+#[derive(Debug, Clone)]
+pub struct ProcessTerminated {
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub pids_count: usize,
+    pub owner: Option<ProcessOwner>,
+    pub time_started: SystemTime,
+    pub time_exited: SystemTime,
+    pub driver_msg_count: usize,
+    pub files_read: usize,
+    pub files_written: usize,
+    pub files_renamed: usize,
+    pub files_deleted: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub predictions_count: usize,
+    /// Highest score of the model over the life of the family
+    pub max_prediction: Option<f32>,
+    pub is_malicious: bool,
+    pub process_state: String,
+}
+
+impl ProcessTerminated {
+    pub fn from(proc: &ProcessRecord) -> ProcessTerminated {
+        ProcessTerminated {
+            gid: proc.gid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            pids_count: proc.pids.len(),
+            owner: proc.owner.clone(),
+            time_started: proc.time_started,
+            time_exited: proc.time_exited.unwrap_or_else(SystemTime::now),
+            driver_msg_count: proc.driver_msg_count,
+            files_read: proc.files_read.len(),
+            files_written: proc.files_written.len(),
+            files_renamed: proc.files_renamed.len(),
+            files_deleted: proc.files_deleted.len(),
+            bytes_read: proc.bytes_read,
+            bytes_written: proc.bytes_written,
+            predictions_count: proc.predictions.predictions_count(),
+            max_prediction: proc.predictions.get_max_prediction(),
+            is_malicious: proc.is_malicious,
+            process_state: proc.process_state.to_string(),
+        }
+    }
+
+    /// Life duration of the family, until its last process exited.
+    pub fn duration(&self) -> Duration {
+        self.time_exited.duration_since(self.time_started).unwrap_or(Duration::ZERO)
+    }
+}
+
 /// Structs and functions to manage a list of [ProcessRecord].
 /// As of now, it's not multithreaded.
 pub mod procs {
+    use std::collections::{HashMap, HashSet};
+    use std::time::{Duration, SystemTime};
+
     use sysinfo::System;
-    use crate::process::ProcessRecord;
+    use crate::exclusions::ExclusionScope;
+    use crate::process::{ProcessRecord, ProcessState};
 
     pub struct Procs<'a> {
         pub procs: Vec<ProcessRecord<'a>>,
+        /// Gids excluded from monitoring, to skip them without any further lookup.
+        ignored_gids: HashSet<u64>,
+        /// The exclusions of the gids without a record (whitelisted, system), evaluated once.
+        exclusion_scopes: HashMap<u64, Option<ExclusionScope>>,
+        /// Version of the [crate::exclusions::Exclusions] used to fill *ignored_gids* and
+        /// *exclusion_scopes*.
+        ignored_gids_version: u64,
     }
 
     impl<'a> Procs<'a> {
         pub fn new() -> Procs<'a> {
-            Procs { procs: vec![] }
+            Procs {
+                procs: vec![],
+                ignored_gids: HashSet::new(),
+                exclusion_scopes: HashMap::new(),
+                ignored_gids_version: 0,
+            }
+        }
+
+        pub fn is_gid_ignored(&self, gid: u64) -> bool {
+            self.ignored_gids.contains(&gid)
+        }
+
+        pub fn ignore_gid(&mut self, gid: u64) {
+            self.ignored_gids.insert(gid);
+        }
+
+        /// The exclusion scope of *gid*, None if not evaluated yet.
+        pub fn exclusion_scope(&self, gid: u64) -> Option<Option<ExclusionScope>> {
+            self.exclusion_scopes.get(&gid).copied()
+        }
+
+        pub fn set_exclusion_scope(&mut self, gid: u64, scope: Option<ExclusionScope>) {
+            self.exclusion_scopes.insert(gid, scope);
+        }
+
+        /// Forget the ignored gids if the exclusions rules have been reloaded since they were computed.
+        pub fn refresh_ignored_gids(&mut self, exclusions_version: u64) {
+            if self.ignored_gids_version != exclusions_version {
+                self.ignored_gids.clear();
+                self.exclusion_scopes.clear();
+                self.ignored_gids_version = exclusions_version;
+            }
         }
 
         pub fn get_by_gid_index(&self, gid: u64) -> Option<usize> {
@@ -730,12 +1095,48 @@ pub mod procs {
             self.procs.push(proc)
         }
 
-        pub fn purge(&mut self, system: &System) {
-            self.procs.retain(|p| p.is_process_still_running(system)); // || p.time_killed.is_some());
+        /// Removes the record of *gid*, to be processed outside of the lock of the procs (see
+        /// [crate::pipeline]) and added back.
+        pub fn take(&mut self, gid: u64) -> Option<ProcessRecord<'a>> {
+            self.get_by_gid_index(gid).map(|i| self.procs.swap_remove(i))
+        }
+
+        /// Removes the records whose processes have all exited for more than *grace*, and returns
+        /// them. The records of suspended processes are kept until the user decides.
+        pub fn reap(&mut self, system: &System, grace: Duration) -> Vec<ProcessRecord<'a>> {
+            let now = SystemTime::now();
+            let mut reaped = Vec::new();
+            let mut i = 0;
+            while i < self.procs.len() {
+                let proc = &mut self.procs[i];
+                if proc.process_state == ProcessState::Suspended || proc.is_process_still_running(system) {
+                    proc.time_exited = None;
+                } else if proc.time_exited.is_none() {
+                    proc.time_exited = Some(now);
+                }
+                let expired = proc
+                    .time_exited
+                    .map_or(false, |t| now.duration_since(t).unwrap_or(Duration::ZERO) >= grace);
+                if expired {
+                    self.exclusion_scopes.remove(&proc.gid);
+                    reaped.push(self.procs.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+            reaped
         }
 
         pub fn len(&self) -> usize {
             self.procs.len()
         }
+
+        /// The gid of each pid of the records. The records being processed are not included.
+        pub fn gids_by_pid(&self) -> HashMap<u32, u64> {
+            self.procs
+                .iter()
+                .flat_map(|proc| proc.pids.iter().map(move |pid| (*pid, proc.gid)))
+                .collect()
+        }
     }
 }
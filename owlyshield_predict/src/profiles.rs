@@ -0,0 +1,244 @@
+//! Scheduled protection profiles, overriding a few values of the [Config] on a weekly schedule.
+//!
+//! The profiles are the sub-tables *profiles.&lt;name&gt;* of *owlyshield.toml*:
+//! ```toml
+//! # More aggressive outside business hours (across midnight)
+//! [profiles.night]
+//! start = "19:00"
+//! end = "07:00"
+//! THRESHOLD_PREDICTION = 0.55
+//!
+//! # No kill during the backup window
+//! [profiles.backup]
+//! days = ["Sat", "Sun"]
+//! start = "01:00"
+//! end = "05:00"
+//! MODE = "AUDIT"
+//! ```
+//! *days* (all by default) are the days of the *start*. Only the values of [OVERRIDABLE] can be
+//! overridden, unless they are set by a policy (GPO). When several profiles are active, the first one in the alphabetical order of the
+//! names wins (*backup* above).
+//!
+//! The active profile is checked every [CHECK_INTERVAL] by the pipeline, and its changes are sent
+//! to the connectors.
+
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use tracing::{error, info};
+
+use crate::config::{Config, ConfigError, Param, CONFIG_FILE_NAME};
+use crate::connectors::connector::Connectors;
+
+/// Values a profile may override.
+pub const OVERRIDABLE: [Param; 2] = [Param::Mode, Param::ThresholdPrediction];
+/// Period of the check of the active profile.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct Profile {
+    name: String,
+    /// Empty for every day
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    values: HashMap<Param, String>,
+}
+
+impl Profile {
+    fn from_toml(name: &str, table: &toml::value::Table) -> Result<Profile, ConfigError> {
+        let invalid = |key: &str, value: &toml::Value, expected: &str| ConfigError::Invalid {
+            key: format!("profiles.{}.{}", name, key),
+            value: value.to_string(),
+            expected: String::from(expected),
+        };
+        let time = |key: &str| {
+            let value = table.get(key).ok_or_else(|| ConfigError::Missing(format!("profiles.{}.{}", name, key)))?;
+            value
+                .as_str()
+                .and_then(|t| NaiveTime::parse_from_str(t, "%H:%M").ok())
+                .ok_or_else(|| invalid(key, value, "a time as HH:MM"))
+        };
+        let mut profile = Profile {
+            name: String::from(name),
+            days: Vec::new(),
+            start: time("start")?,
+            end: time("end")?,
+            values: HashMap::new(),
+        };
+        for (key, value) in table {
+            match key.as_str() {
+                "start" | "end" => {}
+                "days" => {
+                    for day in value.as_array().into_iter().flatten() {
+                        let day = day.as_str().and_then(|d| d.parse::<Weekday>().ok());
+                        profile.days.push(day.ok_or_else(|| invalid(key, value, "days as Mon, Tue..."))?);
+                    }
+                }
+                _ => {
+                    let param = Param::from_key(key)
+                        .filter(|p| OVERRIDABLE.contains(p))
+                        .ok_or_else(|| invalid(key, value, "MODE or THRESHOLD_PREDICTION"))?;
+                    let value = match value {
+                        toml::Value::String(s) => s.clone(),
+                        v => v.to_string(),
+                    };
+                    Config::validate_value(param, &value)?;
+                    profile.values.insert(param, value);
+                }
+            }
+        }
+        Ok(profile)
+    }
+
+    fn is_active(&self, now: NaiveDateTime) -> bool {
+        let runs_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let (today, time) = (now.weekday(), now.time());
+        if self.start <= self.end {
+            runs_on(today) && self.start <= time && time < self.end
+        } else {
+            // across midnight: started today, or yesterday
+            (runs_on(today) && time >= self.start) || (runs_on(today.pred()) && time < self.end)
+        }
+    }
+}
+
+/// A change of the active profile, sent to the connectors.
+#[derive(Debug, Clone)]
+pub struct ProfileChange {
+    pub time: SystemTime,
+    pub previous: Option<String>,
+    pub current: Option<String>,
+    /// Values overridden by the current profile
+    pub values: HashMap<Param, String>,
+}
+
+/// The profiles of *owlyshield.toml*.
+#[derive(Debug, Default)]
+pub struct ProfileSchedule {
+    profiles: Vec<Profile>,
+}
+
+impl ProfileSchedule {
+    /// Loads the profiles. Invalid ones are logged and ignored.
+    pub fn from(config: &Config) -> ProfileSchedule {
+        let path = config.get_path(Param::ConfigPath).join(CONFIG_FILE_NAME);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return ProfileSchedule::default(),
+        };
+        match Self::parse(&content) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                error!("Scheduled profiles ignored: {}", e);
+                ProfileSchedule::default()
+            }
+        }
+    }
+
+    fn parse(content: &str) -> Result<ProfileSchedule, String> {
+        let file: toml::Value = content.parse().map_err(|e: toml::de::Error| e.to_string())?;
+        let mut schedule = ProfileSchedule::default();
+        if let Some(profiles) = file.get("profiles").and_then(|p| p.as_table()) {
+            for (name, table) in profiles {
+                let table = table.as_table().ok_or(format!("profiles.{} is not a table", name))?;
+                match Profile::from_toml(name, table) {
+                    Ok(profile) => schedule.profiles.push(profile),
+                    Err(e) => error!("Profile {} ignored: {}", name, e),
+                }
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// The errors of the profiles of *content*, which [Self::from] logs and ignores.
+    pub fn check(content: &str) -> Vec<String> {
+        let file: toml::Value = match content.parse() {
+            Ok(file) => file,
+            Err(e) => return vec![e.to_string()],
+        };
+        let profiles = match file.get("profiles") {
+            Some(profiles) => profiles,
+            None => return Vec::new(),
+        };
+        let profiles = match profiles.as_table() {
+            Some(profiles) => profiles,
+            None => return vec![String::from("profiles is not a table")],
+        };
+        profiles
+            .iter()
+            .filter_map(|(name, table)| match table.as_table() {
+                Some(table) => Profile::from_toml(name, table).err().map(|e| e.to_string()),
+                None => Some(format!("profiles.{} is not a table", name)),
+            })
+            .collect()
+    }
+
+    fn active(&self, now: NaiveDateTime) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.is_active(now))
+    }
+
+    /// Activates the profile of the current time in *config*, and notifies the connectors if it
+    /// changed.
+    pub fn refresh(&self, config: &Config, connectors: &Connectors) {
+        if self.profiles.is_empty() && config.active_profile().is_none() {
+            return;
+        }
+        let active = self.active(Local::now().naive_local());
+        let previous = config.active_profile();
+        if active.map(|p| &p.name) == previous.as_ref() {
+            return;
+        }
+        let change = ProfileChange {
+            time: SystemTime::now(),
+            previous,
+            current: active.map(|p| p.name.clone()),
+            values: active.map(|p| p.values.clone()).unwrap_or_default(),
+        };
+        info!(
+            previous = change.previous.as_deref().unwrap_or("none"),
+            current = change.current.as_deref().unwrap_or("none"),
+            "Scheduled profile changed"
+        );
+        config.set_profile(active.map(|p| (p.name.clone(), p.values.clone())));
+        connectors.send_profile_change(&change);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::config::Param;
+    use crate::profiles::ProfileSchedule;
+
+    #[test]
+    fn profiles_should_follow_the_schedule() {
+        let schedule = ProfileSchedule::parse(
+            r#"
+            KILL_POLICY = "SUSPEND"
+            [profiles.night]
+            start = "19:00"
+            end = "07:00"
+            THRESHOLD_PREDICTION = 0.55
+            [profiles.backup]
+            days = ["Sat"]
+            start = "01:00"
+            end = "05:00"
+            MODE = "AUDIT"
+            "#,
+        )
+        .unwrap();
+        // 2022-07-02 is a Saturday
+        let at = |day: u32, h: u32| NaiveDate::from_ymd_opt(2022, 7, day).unwrap().and_hms_opt(h, 0, 0).unwrap();
+        let active = |day: u32, h: u32| schedule.active(at(day, h)).map(|p| p.name.as_str());
+        assert_eq!(active(1, 12), None);
+        assert_eq!(active(1, 20), Some("night"));
+        assert_eq!(active(2, 2), Some("backup"));
+        assert_eq!(active(3, 2), Some("night"));
+        assert_eq!(active(3, 7), None);
+        assert_eq!(schedule.active(at(2, 2)).unwrap().values[&Param::Mode], "AUDIT");
+    }
+}
@@ -0,0 +1,303 @@
+//! Quarantine of the executables of the killed gids, so that they cannot be run again.
+//!
+//! With *QUARANTINE*, once a kill is verified ([crate::killcheck]), the executable of the gid and
+//! the copies it dropped (the files it created with the same sha256) are moved into
+//! *ConfigPath\quarantine*: each item is a *<id>.bin* content, encrypted with DPAPI in the machine
+//! scope on Windows, and a *<id>.json* [QuarantineItem]. As any process of the machine can decrypt
+//! the content, the store is only accessible to SYSTEM and the administrators. They are listed and
+//! restored with ```owlyshield_ransom quarantine list``` and
+//! ```owlyshield_ransom quarantine restore <id>```.
+//!
+//! The system executables (*%SystemRoot%*, *Program Files*) and the ones with a trusted signature
+//! are never quarantined.
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{Config, Param};
+use crate::killcheck::KillRequest;
+use crate::process::ProcessRecord;
+use crate::utils::{is_system_path, sha256_file, FILE_TIME_FORMAT, LONG_TIME_FORMAT};
+
+/// Directory of the store, in *ConfigPath*.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug)]
+pub enum QuarantineError {
+    Io(io::Error),
+    /// DPAPI failed, with the details.
+    Encryption(String),
+    /// No item with this id.
+    NotFound(String),
+    /// The original path of a restored item is already used.
+    Exists(PathBuf),
+    /// A system executable, or one with a trusted signature.
+    Protected(PathBuf),
+}
+
+impl Display for QuarantineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            QuarantineError::Io(e) => write!(f, "{}", e),
+            QuarantineError::Encryption(details) => write!(f, "Cannot encrypt or decrypt: {}", details),
+            QuarantineError::NotFound(id) => write!(f, "No quarantined item {}", id),
+            QuarantineError::Exists(path) => write!(f, "{} already exists", path.display()),
+            QuarantineError::Protected(path) => write!(f, "{} is a system or trusted executable", path.display()),
+        }
+    }
+}
+
+impl Error for QuarantineError {}
+
+impl From<io::Error> for QuarantineError {
+    fn from(e: io::Error) -> Self {
+        QuarantineError::Io(e)
+    }
+}
+
+/// Metadata of a quarantined file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuarantineItem {
+    pub id: String,
+    pub original_path: PathBuf,
+    pub sha256: String,
+    pub size: u64,
+    pub time: String,
+    pub gid: u64,
+    pub appname: String,
+    pub prediction: f32,
+    /// False on Linux, where the store is only protected by its permissions
+    pub encrypted: bool,
+}
+
+pub struct Quarantine {
+    dir: PathBuf,
+}
+
+impl Quarantine {
+    pub fn from(config: &Config) -> Quarantine {
+        Quarantine::new(config.get_path(Param::ConfigPath).join(QUARANTINE_DIR))
+    }
+
+    pub fn new(dir: PathBuf) -> Quarantine {
+        Quarantine { dir }
+    }
+
+    /// Moves *path*, a file of the gid killed by *request*, into the store.
+    pub fn store(&self, path: &Path, request: &KillRequest) -> Result<QuarantineItem, QuarantineError> {
+        if is_protected(path) {
+            return Err(QuarantineError::Protected(path.to_path_buf()));
+        }
+        create_dir(&self.dir)?;
+        let content = fs::read(path)?;
+        let prefix = format!("{}_{}", Local::now().format(FILE_TIME_FORMAT), request.gid);
+        let index = (0..).find(|i| !self.dir.join(format!("{}_{}.json", prefix, i)).exists()).unwrap_or(0);
+        let item = QuarantineItem {
+            id: format!("{}_{}", prefix, index),
+            original_path: path.to_path_buf(),
+            sha256: Sha256::digest(&content).iter().map(|b| format!("{:02x}", b)).collect(),
+            size: content.len() as u64,
+            time: Local::now().format(LONG_TIME_FORMAT).to_string(),
+            gid: request.gid,
+            appname: request.appname.clone(),
+            prediction: request.prediction,
+            encrypted: cfg!(windows),
+        };
+        let (bin, json) = self.paths(&item.id);
+        fs::write(&bin, encrypt(&content)?)?;
+        let res = serde_json::to_vec_pretty(&item)
+            .map_err(io::Error::from)
+            .and_then(|metadata| fs::write(&json, metadata))
+            .and_then(|()| fs::remove_file(path));
+        if let Err(e) = res {
+            let _ = fs::remove_file(&bin);
+            let _ = fs::remove_file(&json);
+            return Err(QuarantineError::Io(e));
+        }
+        Ok(item)
+    }
+
+    /// The quarantined items, oldest first.
+    pub fn list(&self) -> Result<Vec<QuarantineItem>, QuarantineError> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut items = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let item: QuarantineItem = serde_json::from_slice(&fs::read(&path)?).map_err(io::Error::from)?;
+                items.push(item);
+            }
+        }
+        items.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(items)
+    }
+
+    /// Writes the item *id* back to its original path, or to *to*, and removes it from the store.
+    pub fn restore(&self, id: &str, to: Option<&Path>) -> Result<QuarantineItem, QuarantineError> {
+        let (bin, json) = self.paths(id);
+        if !is_id(id) || !json.exists() {
+            return Err(QuarantineError::NotFound(id.to_string()));
+        }
+        let item: QuarantineItem = serde_json::from_slice(&fs::read(&json)?).map_err(io::Error::from)?;
+        let target = to.unwrap_or(&item.original_path);
+        if target.exists() {
+            return Err(QuarantineError::Exists(target.to_path_buf()));
+        }
+        let content = fs::read(&bin)?;
+        let content = if item.encrypted { decrypt(&content)? } else { content };
+        fs::write(target, content)?;
+        fs::remove_file(&bin)?;
+        fs::remove_file(&json)?;
+        Ok(item)
+    }
+
+    fn paths(&self, id: &str) -> (PathBuf, PathBuf) {
+        (self.dir.join(format!("{}.bin", id)), self.dir.join(format!("{}.json", id)))
+    }
+}
+
+/// Whether *id* has the format of the generated ids, *<time>_<gid>_<index>*: anything else could
+/// name a file outside of the store.
+fn is_id(id: &str) -> bool {
+    let parts: Vec<&str> = id.split('_').collect();
+    parts.len() == 4
+        && parts[0].len() == 8
+        && parts[1].len() == 6
+        && parts.iter().all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Whether *path* must never be quarantined.
+fn is_protected(path: &Path) -> bool {
+    is_system_path(path) || is_trusted(path)
+}
+
+#[cfg(windows)]
+fn is_trusted(path: &Path) -> bool {
+    crate::signer::is_trusted(path)
+}
+
+#[cfg(not(windows))]
+fn is_trusted(_path: &Path) -> bool {
+    false
+}
+
+/// The executable of *proc* and its copies among the files it created, except the protected ones
+/// (see [Quarantine::store]).
+pub fn executables(proc: &ProcessRecord) -> Vec<PathBuf> {
+    if is_protected(&proc.exepath) {
+        return Vec::new();
+    }
+    let mut executables = vec![proc.exepath.clone()];
+    let size = match proc.exepath.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return executables,
+    };
+    let sha256 = sha256_file(&proc.exepath).ok();
+    for fpath in &proc.fpaths_created {
+        let path = Path::new(fpath.as_ref());
+        // only the files of the same size are hashed
+        if path != proc.exepath
+            && path.metadata().is_ok_and(|m| m.len() == size)
+            && sha256.is_some()
+            && sha256_file(path).ok() == sha256
+        {
+            executables.push(path.to_path_buf());
+        }
+    }
+    executables
+}
+
+#[cfg(unix)]
+fn create_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::create_dir_all(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
+
+#[cfg(windows)]
+fn create_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    crate::selfprotect::restrict_to_admins(dir).map_err(|code| io::Error::from_raw_os_error(code as i32))
+}
+
+#[cfg(windows)]
+fn encrypt(content: &[u8]) -> Result<Vec<u8>, QuarantineError> {
+    crate::secrets::protect(content).map_err(|e| QuarantineError::Encryption(e.to_string()))
+}
+
+#[cfg(windows)]
+fn decrypt(content: &[u8]) -> Result<Vec<u8>, QuarantineError> {
+    crate::secrets::unprotect(content).map_err(|e| QuarantineError::Encryption(e.to_string()))
+}
+
+#[cfg(not(windows))]
+fn encrypt(content: &[u8]) -> Result<Vec<u8>, QuarantineError> {
+    Ok(content.to_vec())
+}
+
+#[cfg(not(windows))]
+fn decrypt(_content: &[u8]) -> Result<Vec<u8>, QuarantineError> {
+    Err(QuarantineError::Encryption(String::from("DPAPI is not available")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    use crate::killcheck::KillRequest;
+    use crate::quarantine::{Quarantine, QuarantineError};
+
+    #[test]
+    fn stored_files_should_be_listed_and_restored() {
+        let dir = std::env::temp_dir().join("owlyshield_quarantine_test");
+        let _ = fs::remove_dir_all(&dir);
+        let quarantine = Quarantine::new(dir.join("store"));
+        let exe = dir.join("evil.exe");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&exe, b"MZ evil").unwrap();
+        let request = KillRequest {
+            time: SystemTime::now(),
+            gid: 42,
+            appname: String::from("evil.exe"),
+            exepath: exe.clone(),
+            pids: vec![4242],
+            prediction: 0.97,
+            error: None,
+            executables: vec![exe.clone()],
+            tags: Vec::new(),
+            incident: None,
+            suspected_family: None,
+        };
+
+        let item = quarantine.store(&exe, &request).unwrap();
+        assert!(!exe.exists());
+        assert_eq!(quarantine.list().unwrap(), vec![item.clone()]);
+        assert_eq!(item.size, 7);
+        assert!(matches!(quarantine.restore("nope", None), Err(QuarantineError::NotFound(_))));
+        let traversal = format!(r"..\..\{}", item.id);
+        assert!(matches!(quarantine.restore(&traversal, None), Err(QuarantineError::NotFound(_))));
+        assert!(matches!(
+            quarantine.store(Path::new("/usr/bin/ls"), &request),
+            Err(QuarantineError::Protected(_))
+        ));
+
+        let restored = quarantine.restore(&item.id, None).unwrap();
+        assert_eq!(restored.original_path, PathBuf::from(&exe));
+        assert_eq!(fs::read(&exe).unwrap(), b"MZ evil");
+        assert!(quarantine.list().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
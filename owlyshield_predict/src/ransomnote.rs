@@ -0,0 +1,215 @@
+//! Detection of ransom notes: a ransomware drops the same small text or HTML file in each
+//! directory it encrypts.
+//!
+//! The text-like files created by a gid are read once closed. A note is detected when files with
+//! identical content are found in at least [NOTE_MIN_DIRS] directories. Its text is kept for the
+//! incident report, with its language and family hints ([crate::notelang]), and sent to the
+//! connectors as a Critical [RansomNoteDropped].
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::correlation::IncidentRef;
+use crate::notelang::NoteAnalysis;
+use crate::process::ProcessRecord;
+use crate::utils::extended_path;
+use crate::volumes::PathForms;
+
+/// Directories with the same content before it is considered a ransom note.
+pub const NOTE_MIN_DIRS: usize = 5;
+/// Bigger files are not notes.
+const NOTE_MAX_SIZE: u64 = 64 * 1024;
+/// Smaller files are not notes (empty placeholders, one-line logs...).
+const NOTE_MIN_SIZE: usize = 64;
+/// Text of the note kept for the report.
+const NOTE_MAX_TEXT: usize = 4096;
+/// Files waiting to be closed, and distinct contents tracked. Beyond, the new ones are ignored.
+const MAX_TRACKED: usize = 1000;
+static NOTE_EXTENSIONS: [&str; 7] = ["txt", "html", "htm", "hta", "rtf", "url", "md"];
+
+/// A ransom note found by [RansomNoteDetector].
+#[derive(Debug, Clone)]
+pub struct RansomNote {
+    /// Text of the note, truncated to 4 KB
+    pub text: String,
+    /// One of the copies
+    pub path: Arc<str>,
+    /// Number of directories with a copy, when detected
+    pub dirs: usize,
+    pub analysis: NoteAnalysis,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    dirs: HashSet<Arc<str>>,
+    path: Arc<str>,
+}
+
+#[derive(Debug)]
+pub struct RansomNoteDetector {
+    pending: HashSet<Arc<str>>,
+    /// By hash of the content
+    candidates: HashMap<u64, Candidate>,
+    max_dirs: usize,
+    note: Option<RansomNote>,
+    escalated: bool,
+}
+
+impl RansomNoteDetector {
+    pub fn new() -> RansomNoteDetector {
+        RansomNoteDetector {
+            pending: HashSet::new(),
+            candidates: HashMap::new(),
+            max_dirs: 0,
+            note: None,
+            escalated: false,
+        }
+    }
+
+    /// A file has been created: its content is read when closed.
+    pub fn on_created(&mut self, fpath: &Arc<str>) {
+        let is_text = Path::new(&**fpath)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map_or(false, |e| NOTE_EXTENSIONS.contains(&e.to_lowercase().as_str()));
+        if is_text && self.pending.len() < MAX_TRACKED {
+            self.pending.insert(Arc::clone(fpath));
+        }
+    }
+
+    pub fn is_pending(&self, fpath: &str) -> bool {
+        self.pending.contains(fpath)
+    }
+
+    /// A pending file has been closed. Returns true if the note has just been detected.
+    pub fn on_closed(&mut self, fpath: &str, dir: Arc<str>) -> bool {
+        if let Some(fpath) = self.pending.take(fpath) {
+            if let Some(content) = read_small_file(Path::new(&*fpath)) {
+                return self.add(fpath, dir, &content);
+            }
+        }
+        false
+    }
+
+    fn add(&mut self, fpath: Arc<str>, dir: Arc<str>, content: &[u8]) -> bool {
+        if content.len() < NOTE_MIN_SIZE {
+            return false;
+        }
+        let mut hasher = DefaultHasher::new();
+        hasher.write(content);
+        let hash = hasher.finish();
+        if !self.candidates.contains_key(&hash) && self.candidates.len() >= MAX_TRACKED {
+            return false;
+        }
+        let candidate = self.candidates.entry(hash).or_insert_with(|| Candidate {
+            dirs: HashSet::new(),
+            path: fpath,
+        });
+        if candidate.dirs.len() < MAX_TRACKED {
+            candidate.dirs.insert(dir);
+        }
+        self.max_dirs = self.max_dirs.max(candidate.dirs.len());
+        if self.note.is_none() && candidate.dirs.len() >= NOTE_MIN_DIRS {
+            let text = String::from_utf8_lossy(&content[..content.len().min(NOTE_MAX_TEXT)]);
+            self.note = Some(RansomNote {
+                analysis: NoteAnalysis::of(&text),
+                text: text.to_string(),
+                path: Arc::clone(&candidate.path),
+                dirs: candidate.dirs.len(),
+            });
+            return true;
+        }
+        false
+    }
+
+    /// From 0 (no identical files) to 1 (a note has been detected).
+    pub fn score(&self) -> f32 {
+        (self.max_dirs as f32 / NOTE_MIN_DIRS as f32).min(1.0)
+    }
+
+    pub fn note(&self) -> Option<&RansomNote> {
+        self.note.as_ref()
+    }
+
+    /// The note, returned only once for the escalation.
+    pub fn take_escalation(&mut self) -> Option<RansomNote> {
+        if self.escalated {
+            return None;
+        }
+        self.escalated = self.note.is_some();
+        self.note.clone()
+    }
+}
+
+/// A gid dropping the same note in many directories.
+#[derive(Debug, Clone)]
+pub struct RansomNoteDropped {
+    pub time: SystemTime,
+    pub gid: u64,
+    pub pid: u32,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub path: PathForms,
+    pub note: RansomNote,
+    /// Of the [crate::enrichment] providers, added when sent to the connectors
+    pub tags: Vec<String>,
+    /// Of the [crate::correlation], added when sent to the connectors
+    pub incident: Option<IncidentRef>,
+}
+
+impl RansomNoteDropped {
+    pub fn from(proc: &ProcessRecord, pid: u32, note: RansomNote) -> RansomNoteDropped {
+        RansomNoteDropped {
+            time: SystemTime::now(),
+            gid: proc.gid,
+            pid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            path: PathForms::of(&note.path),
+            note,
+            tags: Vec::new(),
+            incident: None,
+        }
+    }
+}
+
+fn read_small_file(path: &Path) -> Option<Vec<u8>> {
+    let mut file = File::open(extended_path(path)).ok()?;
+    if file.metadata().ok()?.len() > NOTE_MAX_SIZE {
+        return None;
+    }
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).ok()?;
+    Some(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::ransomnote::{RansomNoteDetector, NOTE_MIN_DIRS};
+
+    #[test]
+    fn same_content_in_many_dirs_should_be_a_note() {
+        let note = "All your files have been encrypted! To decrypt them, send 0.1 BTC to the address...";
+        let mut detector = RansomNoteDetector::new();
+        for i in 0..NOTE_MIN_DIRS {
+            let dir: Arc<str> = Arc::from(format!(r"C:\Users\bob\dir{}", i));
+            let path: Arc<str> = Arc::from(format!(r"{}\README.txt", dir));
+            assert_eq!(detector.add(path, dir, note.as_bytes()), i == NOTE_MIN_DIRS - 1);
+            // a different file in each directory
+            let other = format!("{} {}", note, i);
+            detector.add(Arc::from(r"C:\other.txt"), Arc::from(format!("dir{}", i)), other.as_bytes());
+        }
+        assert_eq!(detector.score(), 1.0);
+        assert_eq!(detector.note().unwrap().text, note);
+        assert_eq!(detector.take_escalation().unwrap().text, note);
+        assert!(detector.take_escalation().is_none());
+    }
+}
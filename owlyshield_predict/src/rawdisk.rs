@@ -0,0 +1,357 @@
+//! Detection of writes to the raw disk or to a volume, bypassing the files: a ransomware like
+//! Petya overwrites the MBR through *\\.\PhysicalDrive0*, a wiper the boot sector through
+//! *\\.\C:*.
+//!
+//! They are found in two ways:
+//! * the minifilter reports the writes on volume handles as
+//!   [FileChangeInfo::FileChangeRawDiskWrite];
+//! * the physical disks are below the file systems, out of reach of a minifilter. The handles of
+//!   the monitored processes are audited periodically, and those opened to write a disk or a volume
+//!   are reported the same way. This audit is only implemented on Windows.
+//!
+//! Each is a Critical [RawDiskWrite] event, and the gid is escalated without the model (see
+//! [crate::fastpath]).
+
+use std::collections::{HashMap, HashSet};
+#[cfg(windows)]
+use std::ffi::c_void;
+use std::fmt;
+#[cfg(windows)]
+use std::fs::File;
+#[cfg(windows)]
+use std::mem;
+#[cfg(windows)]
+use std::os::windows::io::AsRawHandle;
+
+#[cfg(windows)]
+use bindings::Windows::Win32::Foundation::{CloseHandle, DuplicateHandle, DUPLICATE_SAME_ACCESS, HANDLE};
+#[cfg(windows)]
+use bindings::Windows::Win32::Storage::FileSystem::{GetFileType, FILE_TYPE_DISK};
+#[cfg(windows)]
+use bindings::Windows::Win32::System::Threading::{GetCurrentProcess, GetCurrentProcessId, OpenProcess, PROCESS_DUP_HANDLE};
+
+use crate::driver_com::shared_def::{FileChangeInfo, IOMessage, RuntimeFeatures};
+use crate::driver_com::IrpMajorOp;
+use crate::volumes;
+
+/// Undocumented class of *NtQuerySystemInformation*, with 64 bits pids and handles.
+#[cfg(windows)]
+const SYSTEM_EXTENDED_HANDLE_INFORMATION: u32 = 64;
+#[cfg(windows)]
+const OBJECT_NAME_INFORMATION: u32 = 1;
+#[cfg(windows)]
+const STATUS_INFO_LENGTH_MISMATCH: i32 = 0xC000_0004_u32 as i32;
+/// Also granted by *GENERIC_WRITE*
+#[cfg(windows)]
+const FILE_WRITE_DATA: u32 = 0x2;
+/// Events already reported. Beyond, the set starts over.
+const MAX_REPORTED: usize = 1000;
+
+#[cfg(windows)]
+#[link(name = "ntdll")]
+extern "system" {
+    fn NtQuerySystemInformation(class: u32, info: *mut c_void, len: u32, ret_len: *mut u32) -> i32;
+    fn NtQueryObject(handle: HANDLE, class: u32, info: *mut c_void, len: u32, ret_len: *mut u32) -> i32;
+}
+
+#[cfg(windows)]
+#[repr(C)]
+pub(crate) struct HandleEntry {
+    object: *mut c_void,
+    pub(crate) pid: usize,
+    pub(crate) handle: usize,
+    granted_access: u32,
+    creator_back_trace_index: u16,
+    pub(crate) object_type_index: u16,
+    attributes: u32,
+    reserved: u32,
+}
+
+#[cfg(windows)]
+#[repr(C)]
+pub(crate) struct HandlesInformation {
+    pub(crate) count: usize,
+    reserved: usize,
+    pub(crate) handles: [HandleEntry; 1],
+}
+
+#[cfg(windows)]
+#[repr(C)]
+struct UnicodeStr {
+    length: u16,
+    maximum_length: u16,
+    buffer: *const u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RawDiskSource {
+    /// A write seen by the minifilter
+    Driver,
+    /// A handle opened for writing
+    HandleAudit,
+}
+
+impl fmt::Display for RawDiskSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RawDiskSource::Driver => write!(f, "driver"),
+            RawDiskSource::HandleAudit => write!(f, "handle audit"),
+        }
+    }
+}
+
+/// A monitored gid writes to a disk or a volume.
+#[derive(Debug, Clone)]
+pub struct RawDiskWrite {
+    pub gid: u64,
+    pub pid: u32,
+    /// *\Device\Harddisk0\DR0*, *\Device\HarddiskVolume3*...
+    pub device: String,
+    /// Mount point of the volume written, see [crate::volumes]
+    pub mount_point: Option<String>,
+    pub source: RawDiskSource,
+}
+
+/// Reports each (gid, device) once.
+pub struct RawDiskMonitor {
+    reported: HashSet<(u64, String)>,
+}
+
+impl RawDiskMonitor {
+    pub fn new() -> RawDiskMonitor {
+        RawDiskMonitor {
+            reported: HashSet::new(),
+        }
+    }
+
+    /// The event of a raw write reported by the minifilter, if it is the first one of the gid on
+    /// this device.
+    pub fn on_driver_msg(&mut self, iomsg: &IOMessage) -> Option<RawDiskWrite> {
+        if iomsg.file_change != FileChangeInfo::FileChangeRawDiskWrite as u8 {
+            return None;
+        }
+        self.report(RawDiskWrite {
+            gid: iomsg.gid,
+            pid: iomsg.pid,
+            device: iomsg.filepathstr.clone(),
+            mount_point: None,
+            source: RawDiskSource::Driver,
+        })
+    }
+
+    /// Looks for disks and volumes opened for writing by *pids* (with their gid). Returns the new
+    /// events, with the message to escalate their gid.
+    pub fn audit(&mut self, pids: &HashMap<u32, u64>) -> Vec<(RawDiskWrite, IOMessage)> {
+        if pids.is_empty() {
+            return Vec::new();
+        }
+        let handles = unsafe { write_handles(pids) };
+        handles
+            .into_iter()
+            .filter_map(|(pid, device)| {
+                let event = self.report(RawDiskWrite {
+                    gid: pids[&pid],
+                    pid,
+                    device,
+                    mount_point: None,
+                    source: RawDiskSource::HandleAudit,
+                })?;
+                let iomsg = raw_disk_iomsg(&event);
+                Some((event, iomsg))
+            })
+            .collect()
+    }
+
+    fn report(&mut self, mut event: RawDiskWrite) -> Option<RawDiskWrite> {
+        if self.reported.len() >= MAX_REPORTED {
+            self.reported.clear();
+        }
+        if self.reported.insert((event.gid, event.device.to_lowercase())) {
+            event.mount_point = volumes::mount_point(&event.device);
+            Some(event)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the kernel object *name* is a disk, a partition or a volume, and not a file on them.
+pub fn is_raw_device(name: &str) -> bool {
+    let name = name.to_lowercase();
+    let rest = match name.strip_prefix(r"\device\") {
+        Some(rest) => rest,
+        None => return false,
+    };
+    if let Some(volume) = rest.strip_prefix("harddiskvolume") {
+        return is_number(volume);
+    }
+    if let Some(disk) = rest.strip_prefix("harddisk") {
+        // \Device\Harddisk0\DR0 (\\.\PhysicalDrive0) or \Device\Harddisk0\Partition0
+        if let Some((number, device)) = disk.split_once('\\') {
+            return is_number(number)
+                && (device.strip_prefix("dr").map_or(false, is_number)
+                    || device.strip_prefix("partition").map_or(false, is_number));
+        }
+    }
+    false
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+/// The message pushed to the pipeline for a handle found by the audit.
+fn raw_disk_iomsg(event: &RawDiskWrite) -> IOMessage {
+    IOMessage {
+        extension: [0; 12],
+        file_id_vsn: 0,
+        file_id_id: [0; 16],
+        mem_sized_used: 0,
+        entropy: 0.0,
+        pid: event.pid,
+        irp_op: IrpMajorOp::IrpNone as u8,
+        is_entropy_calc: 0,
+        file_change: FileChangeInfo::FileChangeRawDiskWrite as u8,
+        file_location_info: 0,
+        filepathstr: event.device.clone(),
+        gid: event.gid,
+        runtime_features: RuntimeFeatures::new(),
+        file_size: -1,
+    }
+}
+
+/// All the handles of the system, as [HandlesInformation]. The buffer is made of usize for the
+/// alignment.
+#[cfg(windows)]
+pub(crate) unsafe fn system_handles() -> Option<Vec<usize>> {
+    let mut len: u32 = 1 << 20;
+    loop {
+        let mut buffer: Vec<usize> = vec![0; len as usize / mem::size_of::<usize>()];
+        let mut ret_len: u32 = 0;
+        let status = NtQuerySystemInformation(
+            SYSTEM_EXTENDED_HANDLE_INFORMATION,
+            buffer.as_mut_ptr() as *mut c_void,
+            len,
+            &mut ret_len,
+        );
+        match status {
+            0 => return Some(buffer),
+            STATUS_INFO_LENGTH_MISMATCH if len < 1 << 28 => len = ret_len.max(len * 2),
+            _ => return None,
+        }
+    }
+}
+
+/// Disks and volumes opened with write access by *pids*.
+#[cfg(windows)]
+unsafe fn write_handles(pids: &HashMap<u32, u64>) -> Vec<(u32, String)> {
+    // A file handle of ours, to know the type index of the files
+    let marker = match std::env::current_exe().and_then(File::open) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    let buffer = match system_handles() {
+        Some(buffer) => buffer,
+        None => return Vec::new(),
+    };
+    let info = &*(buffer.as_ptr() as *const HandlesInformation);
+    let entries = std::slice::from_raw_parts(info.handles.as_ptr(), info.count);
+    let current_pid = GetCurrentProcessId() as usize;
+    let file_type = entries
+        .iter()
+        .find(|e| e.pid == current_pid && e.handle == marker.as_raw_handle() as usize)
+        .map(|e| e.object_type_index);
+    let file_type = match file_type {
+        Some(file_type) => file_type,
+        None => return Vec::new(),
+    };
+
+    let mut res = Vec::new();
+    let mut processes: HashMap<u32, HANDLE> = HashMap::new();
+    for entry in entries {
+        let pid = entry.pid as u32;
+        if entry.object_type_index != file_type
+            || entry.granted_access & FILE_WRITE_DATA == 0
+            || !pids.contains_key(&pid)
+        {
+            continue;
+        }
+        let process = *processes
+            .entry(pid)
+            .or_insert_with(|| OpenProcess(PROCESS_DUP_HANDLE, false, pid));
+        if process.is_invalid() || process.0 == 0 {
+            continue;
+        }
+        let mut handle = HANDLE(0);
+        if !DuplicateHandle(
+            process,
+            HANDLE(entry.handle as isize),
+            GetCurrentProcess(),
+            &mut handle,
+            0,
+            false,
+            DUPLICATE_SAME_ACCESS,
+        )
+        .as_bool()
+        {
+            continue;
+        }
+        // NtQueryObject can hang on synchronous pipes: only the disks are queried
+        if GetFileType(handle) == FILE_TYPE_DISK {
+            if let Some(name) = object_name(handle) {
+                if is_raw_device(&name) {
+                    res.push((pid, name));
+                }
+            }
+        }
+        CloseHandle(handle);
+    }
+    for process in processes.values() {
+        if !process.is_invalid() && process.0 != 0 {
+            CloseHandle(*process);
+        }
+    }
+    res
+}
+
+#[cfg(windows)]
+pub(crate) unsafe fn object_name(handle: HANDLE) -> Option<String> {
+    let mut buffer: Vec<usize> = vec![0; 1024];
+    let mut ret_len: u32 = 0;
+    let status = NtQueryObject(
+        handle,
+        OBJECT_NAME_INFORMATION,
+        buffer.as_mut_ptr() as *mut c_void,
+        (buffer.len() * mem::size_of::<usize>()) as u32,
+        &mut ret_len,
+    );
+    if status != 0 {
+        return None;
+    }
+    let name = &*(buffer.as_ptr() as *const UnicodeStr);
+    if name.buffer.is_null() {
+        return None;
+    }
+    let chars = std::slice::from_raw_parts(name.buffer, name.length as usize / 2);
+    Some(String::from_utf16_lossy(chars))
+}
+
+#[cfg(not(windows))]
+unsafe fn write_handles(_pids: &HashMap<u32, u64>) -> Vec<(u32, String)> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rawdisk::is_raw_device;
+
+    #[test]
+    fn disks_and_volumes_should_be_raw_devices() {
+        assert!(is_raw_device(r"\Device\Harddisk0\DR0"));
+        assert!(is_raw_device(r"\Device\Harddisk1\Partition0"));
+        assert!(is_raw_device(r"\Device\HarddiskVolume3"));
+        assert!(!is_raw_device(r"\Device\HarddiskVolume3\Users\bob\report.docx"));
+        assert!(!is_raw_device(r"\Device\Harddisk0\DR0\file"));
+        assert!(!is_raw_device(r"\Device\NamedPipe\foo"));
+    }
+}
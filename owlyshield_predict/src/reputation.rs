@@ -0,0 +1,260 @@
+//! Reputation of the executables, a prior probability of being malicious mixed into the score of
+//! the models (see [crate::process::ProcessRecord::ponderate_predictions]), so that a brand-new
+//! unsigned binary in *%APPDATA%* is treated more suspiciously than a long-installed software.
+//!
+//! The prior combines, as log-odds:
+//! * the signer of the executable;
+//! * the prevalence of its hash: the number of distinct executables (paths) with this hash on this
+//!   machine, persisted in *DebugPath\reputation.json*. A binary relaunching itself does not
+//!   raise it;
+//! * the age of the file on disk;
+//! * its install location.
+//!
+//! It is weighted by *REPUTATION_WEIGHT*, 0 (disabled) by default.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+use crate::config::{Config, Param};
+use crate::exclusions::ExclusionSubject;
+use crate::utils::sha256_file;
+
+pub static REPUTATION_FILE_NAME: &str = "reputation.json";
+/// Period of the saves of the prevalences.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+const DAY: f32 = 86400.0;
+/// Paths kept per hash, beyond the highest prevalence bucket of [Factors::prior].
+const MAX_PATHS: usize = 16;
+
+/// Where an executable is installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// *Program Files*, */usr*, */opt*: only writable by the administrators
+    Installed,
+    /// *AppData*, *Temp*, *Downloads*, *Desktop*, */tmp*, */home*: writable by the user
+    UserWritable,
+    Other,
+}
+
+impl Location {
+    pub fn of(exepath: &Path) -> Location {
+        let path = exepath.to_string_lossy().to_lowercase().replace('\\', "/");
+        let user_writable = ["/appdata/", "/temp/", "/downloads/", "/desktop/", "/users/public/", "/tmp/", "/home/"];
+        let installed = ["/program files/", "/program files (x86)/", "/usr/", "/opt/"];
+        if user_writable.iter().any(|d| path.contains(d)) {
+            Location::UserWritable
+        } else if installed.iter().any(|d| path.contains(d)) {
+            Location::Installed
+        } else {
+            Location::Other
+        }
+    }
+}
+
+/// What the prior is computed from.
+#[derive(Debug, Clone)]
+pub struct Factors {
+    pub signed: bool,
+    /// Distinct executables with the same hash, this one included
+    pub prevalence: u64,
+    /// Days since the file was created or last modified
+    pub age_days: Option<f32>,
+    pub location: Location,
+}
+
+impl Factors {
+    /// Prior probability of being malicious, 0.5 when nothing is known.
+    pub fn prior(&self) -> f32 {
+        let mut logit = 0.0f32;
+        if self.signed {
+            logit -= 2.0;
+        }
+        logit += match self.prevalence {
+            0..=1 => 1.0,
+            2..=9 => 0.0,
+            _ => -1.5,
+        };
+        logit += match self.age_days {
+            Some(age) if age < 1.0 => 1.0,
+            Some(age) if age > 90.0 => -1.0,
+            _ => 0.0,
+        };
+        logit += match self.location {
+            Location::UserWritable => 1.0,
+            Location::Installed => -1.0,
+            Location::Other => 0.0,
+        };
+        1.0 / (1.0 + (-logit).exp())
+    }
+}
+
+/// Mixes the *prior* of a gid into the score of the models, with *REPUTATION_WEIGHT*.
+pub fn mix(weight: f32, prediction: f32, prior: f32) -> f32 {
+    (1.0 - weight) * prediction + weight * prior
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Prevalence {
+    /// Executables seen with the hash, at most [MAX_PATHS]
+    #[serde(default)]
+    paths: BTreeSet<PathBuf>,
+    /// Unix time
+    first_seen: u64,
+}
+
+#[derive(Default)]
+struct State {
+    /// By sha256
+    prevalences: HashMap<String, Prevalence>,
+    /// Hashes by path, with the modification time of the file
+    hashes: HashMap<PathBuf, (SystemTime, String)>,
+    dirty: bool,
+}
+
+impl State {
+    /// Counts *exepath* in the prevalence of *sha256*. Returns the prevalence.
+    fn count(&mut self, sha256: String, exepath: &Path) -> u64 {
+        let prevalence = self.prevalences.entry(sha256).or_insert_with(|| Prevalence {
+            paths: BTreeSet::new(),
+            first_seen: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        });
+        if prevalence.paths.len() < MAX_PATHS && prevalence.paths.insert(exepath.to_path_buf()) {
+            self.dirty = true;
+        }
+        prevalence.paths.len() as u64
+    }
+}
+
+/// Shared by the workers of the pipeline.
+pub struct Reputation {
+    path: PathBuf,
+    weight: f32,
+    state: Mutex<State>,
+}
+
+impl Reputation {
+    pub fn from(config: &Config) -> Reputation {
+        let path = config.get_path(Param::DebugPath).join(REPUTATION_FILE_NAME);
+        let prevalences = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                error!("Invalid {}, the prevalences are reset: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Reputation {
+            path,
+            weight: Reputation::weight(config),
+            state: Mutex::new(State {
+                prevalences,
+                ..State::default()
+            }),
+        }
+    }
+
+    /// *REPUTATION_WEIGHT*, between 0 and 1.
+    pub fn weight(config: &Config) -> f32 {
+        config.get_f32(Param::ReputationWeight).clamp(0.0, 1.0)
+    }
+
+    /// The prior of the executable of a new gid, which is counted in the prevalence of its hash.
+    /// None if disabled.
+    pub fn assess(&self, subject: &mut ExclusionSubject) -> Option<f32> {
+        if self.weight == 0.0 {
+            return None;
+        }
+        let modified = fs::metadata(subject.exepath).and_then(|m| m.modified()).ok();
+        let sha256 = self.sha256(subject.exepath, modified);
+        let prevalence = sha256.map_or(0, |sha256| self.state.lock().unwrap().count(sha256, subject.exepath));
+        let factors = Factors {
+            signed: subject.signer().is_some(),
+            prevalence,
+            age_days: modified
+                .and_then(|m| SystemTime::now().duration_since(m).ok())
+                .map(|age| age.as_secs_f32() / DAY),
+            location: Location::of(subject.exepath),
+        };
+        let prior = factors.prior();
+        debug!(exepath = %subject.exepath.display(), ?factors, prior, "Reputation");
+        Some(prior)
+    }
+
+    fn sha256(&self, exepath: &Path, modified: Option<SystemTime>) -> Option<String> {
+        let modified = modified?;
+        if let Some((time, sha256)) = self.state.lock().unwrap().hashes.get(exepath) {
+            if *time == modified {
+                return Some(sha256.clone());
+            }
+        }
+        // hashed without the lock
+        let sha256 = sha256_file(exepath).ok()?;
+        let mut state = self.state.lock().unwrap();
+        state.hashes.insert(exepath.to_path_buf(), (modified, sha256.clone()));
+        Some(sha256)
+    }
+
+    /// Writes the prevalences, if they changed.
+    pub fn save(&self) {
+        let json = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return;
+            }
+            state.dirty = false;
+            serde_json::to_string(&state.prevalences)
+        };
+        match json {
+            Ok(json) => {
+                if let Err(e) = fs::write(&self.path, json) {
+                    error!("Cannot write {}: {}", self.path.display(), e);
+                }
+            }
+            Err(e) => error!("Cannot serialize the prevalences: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::reputation::{Factors, Location, State};
+
+    #[test]
+    fn new_unsigned_binaries_in_appdata_should_be_suspicious() {
+        let dropped = Factors {
+            signed: false,
+            prevalence: 1,
+            age_days: Some(0.1),
+            location: Location::of(Path::new(r"C:\Users\bob\AppData\Roaming\x\svc.exe")),
+        };
+        let installed = Factors {
+            signed: true,
+            prevalence: 250,
+            age_days: Some(400.0),
+            location: Location::of(Path::new(r"C:\Program Files\Acme\acme.exe")),
+        };
+        assert_eq!(dropped.location, Location::UserWritable);
+        assert!(dropped.prior() > 0.9);
+        assert!(installed.prior() < 0.01);
+        let unknown = Factors { signed: false, prevalence: 5, age_days: None, location: Location::Other };
+        assert_eq!(unknown.prior(), 0.5);
+    }
+
+    #[test]
+    fn prevalence_should_count_the_distinct_executables() {
+        let mut state = State::default();
+        let dropped = Path::new(r"C:\Users\bob\AppData\Local\Temp\x.exe");
+        for _ in 0..20 {
+            assert_eq!(state.count(String::from("aa"), dropped), 1);
+        }
+        assert_eq!(state.count(String::from("aa"), Path::new(r"C:\Users\Public\x.exe")), 2);
+        assert_eq!(state.count(String::from("bb"), dropped), 1);
+    }
+}
@@ -0,0 +1,152 @@
+//! Behavioural models per process category: the writes of a browser, of a service or of a script
+//! host do not look like those of an interactive application, and a model trained on one category
+//! is more accurate on it than the embedded model trained on all of them.
+//!
+//! The [ProcessCategory] of a gid is set when its record is created, from the executable of its
+//! root ([crate::driver_com::shared_def::RuntimeFeatures::exepath]) and its owner. With
+//! *CATEGORY_MODELS*, the [ModelRouter] loads the models of *ConfigPath\models\<category>* (see
+//! [TfLite::from_dir]); the categories without a model, or all of them without *CATEGORY_MODELS*,
+//! are scored by the embedded model.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::config::{Config, Param};
+use crate::driver_com::shared_def::RuntimeFeatures;
+use crate::error::ModelError;
+use crate::prediction::TfLite;
+use crate::scripthost::SCRIPT_HOSTS;
+use crate::token::ProcessOwner;
+
+/// Lowercase file names of the browsers.
+const BROWSERS: [&str; 10] = [
+    "chrome.exe",
+    "msedge.exe",
+    "firefox.exe",
+    "brave.exe",
+    "opera.exe",
+    "vivaldi.exe",
+    "iexplore.exe",
+    "chrome",
+    "chromium",
+    "firefox",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessCategory {
+    #[default]
+    Interactive,
+    /// Run by a service account, see [ProcessOwner::is_service_account]
+    Service,
+    /// See [crate::scripthost]
+    ScriptHost,
+    Browser,
+}
+
+impl ProcessCategory {
+    pub const ALL: [ProcessCategory; 4] =
+        [ProcessCategory::Interactive, ProcessCategory::Service, ProcessCategory::ScriptHost, ProcessCategory::Browser];
+
+    /// Of the root of a gid, by its executable then its *owner*.
+    pub fn of(runtime_features: &RuntimeFeatures, owner: Option<&ProcessOwner>) -> ProcessCategory {
+        let file_name = runtime_features
+            .exepath
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if SCRIPT_HOSTS.contains(&file_name.as_str()) {
+            ProcessCategory::ScriptHost
+        } else if BROWSERS.contains(&file_name.as_str()) {
+            ProcessCategory::Browser
+        } else if owner.is_some_and(|owner| owner.is_service_account()) {
+            ProcessCategory::Service
+        } else {
+            ProcessCategory::Interactive
+        }
+    }
+
+    /// Name of the directory of its model, in *ConfigPath\models*.
+    pub fn name(self) -> &'static str {
+        match self {
+            ProcessCategory::Interactive => "interactive",
+            ProcessCategory::Service => "service",
+            ProcessCategory::ScriptHost => "script_host",
+            ProcessCategory::Browser => "browser",
+        }
+    }
+}
+
+impl Display for ProcessCategory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The model scoring each [ProcessCategory].
+pub struct ModelRouter {
+    embedded: TfLite,
+    /// Indexed as [ProcessCategory::ALL]
+    models: [Option<TfLite>; 4],
+}
+
+impl ModelRouter {
+    /// The embedded model, and with *CATEGORY_MODELS* the models of the categories found in
+    /// *ConfigPath\models*. A model which cannot be loaded, or does not fit the features, is an
+    /// error: its category would be scored by another model without notice.
+    pub fn from(config: &Config) -> Result<ModelRouter, ModelError> {
+        let mut models = [None, None, None, None];
+        if config.get_bool(Param::CategoryModels) {
+            let dir = config.get_path(Param::ConfigPath).join("models");
+            for (model, category) in models.iter_mut().zip(ProcessCategory::ALL) {
+                let category_dir = dir.join(category.name());
+                if category_dir.join("model.tflite").exists() {
+                    let tflite = TfLite::from_dir(category.name(), &category_dir)?;
+                    info!(%category, version = tflite.version(), quantized = tflite.is_quantized(), "Model of the category loaded");
+                    *model = Some(tflite);
+                }
+            }
+        }
+        Ok(ModelRouter { embedded: TfLite::new()?, models })
+    }
+
+    /// The model of *category*.
+    pub fn route(&self, category: ProcessCategory) -> &TfLite {
+        let index = ProcessCategory::ALL.iter().position(|c| *c == category).unwrap_or(0);
+        self.models[index].as_ref().unwrap_or(&self.embedded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::driver_com::shared_def::RuntimeFeatures;
+    use crate::routing::ProcessCategory;
+    use crate::token::ProcessOwner;
+
+    #[test]
+    fn category_should_follow_the_root() {
+        let of = |exepath: &str, session_id: Option<u32>| {
+            let runtime_features = RuntimeFeatures {
+                exepath: PathBuf::from(exepath),
+                ..RuntimeFeatures::new()
+            };
+            let owner = ProcessOwner {
+                sid: String::from("S-1-5-21-1004336348-1177238915-682003330-1001"),
+                username: None,
+                session_id,
+            };
+            ProcessCategory::of(&runtime_features, Some(&owner))
+        };
+        assert_eq!(of("Office/WINWORD.EXE", Some(1)), ProcessCategory::Interactive);
+        assert_eq!(of("Backup/agent.exe", Some(0)), ProcessCategory::Service);
+        assert_eq!(of("WindowsPowerShell/v1.0/PowerShell.exe", Some(0)), ProcessCategory::ScriptHost);
+        assert_eq!(of("Chrome/Application/chrome.exe", Some(1)), ProcessCategory::Browser);
+        assert_eq!(of("/usr/lib/firefox/firefox", None), ProcessCategory::Browser);
+        assert_eq!(ProcessCategory::of(&RuntimeFeatures::new(), None), ProcessCategory::Interactive);
+    }
+}
@@ -0,0 +1,745 @@
+//! Versioned JSON contract of the events and reports sent out of the agent.
+//!
+//! Everything the agent sends or writes for the integrators is an [Envelope]: the
+//! [SCHEMA_VERSION], the [Agent] which sent it, and an [Event] tagged by its *type*. The types of
+//! this module are the contract, decoupled from the internal structs they are built from: a
+//! refactoring of the agent does not change the payloads.
+//!
+//! The JSON schema is published in *schema/events.v1.schema.json* and printed by
+//! ```owlyshield_ransom schema dump```. Versioning:
+//! * the minor version is raised for additions (a new optional field, a new event type), which
+//!   the consumers must ignore when unknown;
+//! * the major version, and the name of the schema file, for a removal, a rename or a change of
+//!   type.
+//!
+//! The tests check that the schema matches the published one, and that the payloads of the
+//! examples (*schema/examples.v1.json*) are still read and written identically.
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Param;
+use crate::identity::AgentIdentity;
+use crate::process::ProcessRecord;
+
+pub const SCHEMA_VERSION: &str = "1.6";
+/// Files updated listed in a [Detection], at most.
+pub const MAX_FILES: usize = 100;
+
+/// An event or a report, as sent to the connectors or written to the disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Envelope {
+    /// Version of this contract, as major.minor
+    pub schema_version: String,
+    pub agent: Agent,
+    pub event: Event,
+}
+
+impl Envelope {
+    pub fn new(identity: &AgentIdentity, event: Event) -> Envelope {
+        Envelope {
+            schema_version: SCHEMA_VERSION.to_string(),
+            agent: Agent::from(identity),
+            event,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| String::from("{}"))
+    }
+}
+
+/// The machine and the agent which sent the event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Agent {
+    /// Random id of the machine, persisted by the agent
+    pub machine_id: String,
+    pub hostname: String,
+    /// Ex: Windows 10 Pro 19044, Linux 22.04 Ubuntu
+    pub os_version: String,
+    /// None if the machine is not joined to a domain
+    pub domain: Option<String>,
+    pub agent_version: String,
+}
+
+impl From<&AgentIdentity> for Agent {
+    fn from(identity: &AgentIdentity) -> Agent {
+        Agent {
+            machine_id: identity.machine_id.clone(),
+            hostname: identity.hostname.clone(),
+            os_version: identity.os_version.clone(),
+            domain: identity.domain.clone(),
+            agent_version: identity.agent_version.clone(),
+        }
+    }
+}
+
+/// The event, tagged by its type. The times are RFC 3339, in UTC.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A process family above its threshold, killed or suspended (or that would have been)
+    Detection(Detection),
+    /// A process family entering the PreAlert level, before the kill if ever
+    Escalation(Escalation),
+    /// An archive of documents staged for exfiltration
+    ExfilPreAlert(ExfilPreAlert),
+    /// Files deleted en masse, without encryption
+    MassDeletion(MassDeletion),
+    /// The same note dropped by a process family in many directories
+    RansomNote(RansomNote),
+    /// A scheduled task or a service created by a process family
+    Persistence(Persistence),
+    /// A write to a disk or a volume itself
+    RawDiskWrite(RawDiskWrite),
+    /// A kill, once verified
+    Kill(Kill),
+    /// Summary of a process family whose processes have all exited
+    ProcessTerminated(ProcessTerminated),
+    /// A change of the active scheduled profile
+    ProfileChange(ProfileChange),
+    /// A connector not called anymore after consecutive failures
+    ConnectorDegraded(ConnectorDegraded),
+    /// An incident of the agent itself
+    AgentIncident(AgentIncident),
+}
+
+/// A process family, identified by its gid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Family {
+    pub gid: u64,
+    pub appname: String,
+    /// Executable of the root process
+    pub exepath: String,
+}
+
+/// The user running a process family.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Owner {
+    /// String SID, or the uid on Linux
+    pub sid: String,
+    /// DOMAIN\user, if the SID can be resolved
+    pub username: Option<String>,
+    /// Terminal services session, None on Linux
+    pub session_id: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Detection {
+    pub time: String,
+    pub family: Family,
+    pub pids: Vec<u32>,
+    pub owner: Option<Owner>,
+    pub time_started: String,
+    /// Score of the model, in [0, 1]
+    pub prediction: f32,
+    pub threshold: f32,
+    /// One of killed, suspended, or audit if the family was left running (AUDIT mode)
+    pub action: String,
+    pub files_read: usize,
+    pub files_written: usize,
+    pub files_renamed: usize,
+    pub files_deleted: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// At most 100, sorted
+    pub files_updated: Vec<String>,
+    /// Shared by the alerts of the family, if correlated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incident: Option<IncidentRef>,
+    /// Known ransomware family matched by the fingerprints of the agent, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspected_family: Option<String>,
+}
+
+/// The incident of an alert, shared by its notifications (the toast, the reports and the
+/// connectors). Its id is the dedup key of the on-call services.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct IncidentRef {
+    /// owlyshield-<machine id>-<gid>-<start of the sha256 of the executable>
+    pub id: String,
+    /// 0 for the alert opening the incident, then incremented
+    pub update: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Escalation {
+    pub time: String,
+    pub family: Family,
+    pub score: f32,
+    pub threshold: f32,
+    /// Worst AMSI verdict on the memory of the processes: clean, not detected or detected
+    pub memory_verdict: Option<String>,
+    /// Executables dropped by the family, with their static prediction
+    pub dropped: Vec<DroppedExecutable>,
+    /// Of the enrichment providers, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Shared by the alerts of the family, if correlated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incident: Option<IncidentRef>,
+    /// Known ransomware family matched by the fingerprints of the agent, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspected_family: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DroppedExecutable {
+    pub path: String,
+    pub prediction: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ExfilPreAlert {
+    pub time: String,
+    pub family: Family,
+    pub pid: u32,
+    pub archive_path: String,
+    /// One of zip, rar or 7z
+    pub archive_format: String,
+    pub archive_size: u64,
+    /// Documents read by the family before writing the archive
+    pub docs_read: usize,
+    /// Of the enrichment providers, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Shared by the alerts of the family, if correlated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incident: Option<IncidentRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MassDeletion {
+    pub time: String,
+    pub family: Family,
+    pub pid: u32,
+    pub deleted: usize,
+    /// Window of the deletions counted
+    pub window_secs: u64,
+    /// Last file deleted, with its mount point
+    pub last_path: String,
+    /// Last file deleted, as reported by the minifilter
+    pub last_path_raw: String,
+    /// Of the enrichment providers, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Shared by the alerts of the family, if correlated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incident: Option<IncidentRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RansomNote {
+    pub time: String,
+    pub family: Family,
+    pub pid: u32,
+    /// One of the copies, with its mount point
+    pub path: String,
+    /// One of the copies, as reported by the minifilter
+    pub path_raw: String,
+    /// Directories with a copy, when detected
+    pub dirs: usize,
+    /// Text of the note, truncated to 4 KB
+    pub text: String,
+    /// ISO 639-1 code, if recognized
+    pub language: Option<String>,
+    /// Ransomware families hinted by the text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suspected_families: Vec<String>,
+    /// Of the enrichment providers, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Shared by the alerts of the family, if correlated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incident: Option<IncidentRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Persistence {
+    pub time: String,
+    pub family: Family,
+    pub pid: u32,
+    pub mechanism: PersistenceMechanism,
+    pub source: PersistenceSource,
+    /// Path of the task file, command line or registry key
+    pub target: String,
+    /// Of the enrichment providers, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Shared by the alerts of the family, if correlated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incident: Option<IncidentRef>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PersistenceMechanism {
+    ScheduledTask,
+    Service,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PersistenceSource {
+    /// A task definition written
+    TaskFile,
+    /// A process run with a command line creating it
+    CommandLine,
+    /// A registry value written, seen by Sysmon
+    Registry,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RawDiskWrite {
+    pub time: String,
+    pub gid: u64,
+    pub pid: u32,
+    /// Ex: \Device\Harddisk0\DR0, \Device\HarddiskVolume3
+    pub device: String,
+    pub mount_point: Option<String>,
+    pub source: RawDiskSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RawDiskSource {
+    /// A write seen by the minifilter
+    Driver,
+    /// A handle opened for writing
+    HandleAudit,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Kill {
+    /// Of the first kill
+    pub time: String,
+    pub family: Family,
+    pub pids: Vec<u32>,
+    pub prediction: f32,
+    /// Error of the first kill, if any
+    pub error: Option<String>,
+    pub outcome: KillOutcome,
+    /// Kills issued, the first one included
+    pub attempts: u32,
+    pub respawns: Vec<u32>,
+    /// Processes still running at the end of the verification
+    pub survivors: Vec<u32>,
+    pub verification_ms: u64,
+    pub quarantined: Vec<Quarantined>,
+    /// Of the enrichment providers, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Shared by the alerts of the family, if correlated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incident: Option<IncidentRef>,
+    /// Known ransomware family matched by the fingerprints of the agent, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspected_family: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum KillOutcome {
+    /// All the processes exited after the first kill
+    Exited,
+    /// All the processes exited, but some had to be killed again
+    Rekilled,
+    /// Some processes were still running after the last retry
+    Failed,
+}
+
+/// An executable of the family moved to the quarantine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Quarantined {
+    /// To restore it, with: owlyshield_ransom quarantine restore <id>
+    pub id: String,
+    pub original_path: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ProcessTerminated {
+    pub family: Family,
+    pub pids_count: usize,
+    pub owner: Option<Owner>,
+    pub time_started: String,
+    pub time_exited: String,
+    pub driver_msg_count: usize,
+    pub files_read: usize,
+    pub files_written: usize,
+    pub files_renamed: usize,
+    pub files_deleted: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub predictions_count: usize,
+    /// Highest score of the model over the life of the family
+    pub max_prediction: Option<f32>,
+    pub is_malicious: bool,
+    /// One of RUNNING, SUSPENDED or KILLED
+    pub process_state: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ProfileChange {
+    pub time: String,
+    pub previous: Option<String>,
+    pub current: Option<String>,
+    /// Parameters overridden by the current profile, by name
+    pub values: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ConnectorDegraded {
+    pub time: String,
+    pub connector: String,
+    pub failures: u32,
+    pub last_error: String,
+    /// Time before the connector is tried again
+    pub cool_down_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AgentIncident {
+    pub time: String,
+    pub kind: IncidentKind,
+    pub message: String,
+    /// Context written by the agent
+    pub report_path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentKind {
+    /// A panic of the protection loop, which has been restarted
+    Panic,
+    /// The protection loop did not beat for too long
+    Hang,
+    /// The self-test did not see the driver messages of its helper
+    SelfTest,
+    /// A process tried to terminate, suspend or inject into the agent
+    TamperAttempt,
+}
+
+/// The JSON schema of [Envelope].
+pub fn dump() -> RootSchema {
+    schema_for!(Envelope)
+}
+
+pub fn rfc3339(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn family(gid: u64, appname: &str, exepath: &std::path::Path) -> Family {
+    Family {
+        gid,
+        appname: appname.to_string(),
+        exepath: exepath.to_string_lossy().to_string(),
+    }
+}
+
+fn owner(owner: &crate::token::ProcessOwner) -> Owner {
+    Owner {
+        sid: owner.sid.clone(),
+        username: owner.username.clone(),
+        session_id: owner.session_id,
+    }
+}
+
+impl Detection {
+    pub fn from(proc: &ProcessRecord, prediction: f32) -> Detection {
+        let mut files_updated: Vec<String> = proc.fpaths_updated.iter().map(|p| p.to_string()).collect();
+        files_updated.sort_unstable();
+        files_updated.truncate(MAX_FILES);
+        let mut pids: Vec<u32> = proc.pids.iter().copied().collect();
+        pids.sort_unstable();
+        let action = if proc.would_kill {
+            "audit"
+        } else if proc.process_state == crate::process::ProcessState::Suspended {
+            "suspended"
+        } else {
+            "killed"
+        };
+        Detection {
+            time: rfc3339(proc.time_killed.unwrap_or_else(SystemTime::now)),
+            family: family(proc.gid, &proc.appname, &proc.exepath),
+            pids,
+            owner: proc.owner.as_ref().map(owner),
+            time_started: rfc3339(proc.time_started),
+            prediction,
+            threshold: proc.threshold_prediction,
+            action: action.to_string(),
+            files_read: proc.files_read.len(),
+            files_written: proc.files_written.len(),
+            files_renamed: proc.files_renamed.len(),
+            files_deleted: proc.files_deleted.len(),
+            bytes_read: proc.bytes_read,
+            bytes_written: proc.bytes_written,
+            files_updated,
+            incident: None,
+            suspected_family: proc.fingerprint.suspected_family().map(|suspected| suspected.family.to_string()),
+        }
+    }
+}
+
+impl From<&crate::correlation::IncidentRef> for IncidentRef {
+    fn from(incident: &crate::correlation::IncidentRef) -> IncidentRef {
+        IncidentRef {
+            id: incident.id.clone(),
+            update: incident.update,
+        }
+    }
+}
+
+impl From<&crate::escalation::Escalated> for Event {
+    fn from(event: &crate::escalation::Escalated) -> Event {
+        Event::Escalation(Escalation {
+            time: rfc3339(event.time),
+            family: family(event.gid, &event.appname, &event.exepath),
+            score: event.score,
+            threshold: event.threshold,
+            memory_verdict: event.evidence.memory.map(|verdict| verdict.to_string()),
+            dropped: event
+                .evidence
+                .dropped
+                .iter()
+                .map(|(path, prediction)| DroppedExecutable {
+                    path: path.to_string_lossy().to_string(),
+                    prediction: *prediction,
+                })
+                .collect(),
+            tags: event.tags.clone(),
+            incident: event.incident.as_ref().map(IncidentRef::from),
+            suspected_family: event.suspected_family.clone(),
+        })
+    }
+}
+
+impl From<&crate::exfil::PreAlert> for Event {
+    fn from(event: &crate::exfil::PreAlert) -> Event {
+        Event::ExfilPreAlert(ExfilPreAlert {
+            time: rfc3339(event.time),
+            family: family(event.gid, &event.appname, &event.exepath),
+            pid: event.pid,
+            archive_path: event.archive.path.to_string_lossy().to_string(),
+            archive_format: event.archive.format.to_string(),
+            archive_size: event.archive.size,
+            docs_read: event.archive.docs_read,
+            tags: event.tags.clone(),
+            incident: event.incident.as_ref().map(IncidentRef::from),
+        })
+    }
+}
+
+impl From<&crate::wiper::MassDeletion> for Event {
+    fn from(event: &crate::wiper::MassDeletion) -> Event {
+        Event::MassDeletion(MassDeletion {
+            time: rfc3339(event.time),
+            family: family(event.gid, &event.appname, &event.exepath),
+            pid: event.pid,
+            deleted: event.deleted,
+            window_secs: event.window.as_secs(),
+            last_path: event.last_path.normalized.clone(),
+            last_path_raw: event.last_path.raw.clone(),
+            tags: event.tags.clone(),
+            incident: event.incident.as_ref().map(IncidentRef::from),
+        })
+    }
+}
+
+impl From<&crate::ransomnote::RansomNoteDropped> for Event {
+    fn from(event: &crate::ransomnote::RansomNoteDropped) -> Event {
+        Event::RansomNote(RansomNote {
+            time: rfc3339(event.time),
+            family: family(event.gid, &event.appname, &event.exepath),
+            pid: event.pid,
+            path: event.path.normalized.clone(),
+            path_raw: event.path.raw.clone(),
+            dirs: event.note.dirs,
+            text: event.note.text.clone(),
+            language: event.note.analysis.language.as_ref().map(|language| language.code.to_string()),
+            suspected_families: event.note.analysis.families().iter().map(|family| family.to_string()).collect(),
+            tags: event.tags.clone(),
+            incident: event.incident.as_ref().map(IncidentRef::from),
+        })
+    }
+}
+
+impl From<&crate::autostart::Persistence> for Event {
+    fn from(event: &crate::autostart::Persistence) -> Event {
+        use crate::autostart::{AutostartKind, AutostartSource};
+
+        Event::Persistence(Persistence {
+            time: rfc3339(event.entry.time),
+            family: family(event.gid, &event.appname, &event.exepath),
+            pid: event.entry.pid,
+            mechanism: match event.entry.kind {
+                AutostartKind::ScheduledTask => PersistenceMechanism::ScheduledTask,
+                AutostartKind::Service => PersistenceMechanism::Service,
+            },
+            source: match event.entry.source {
+                AutostartSource::TaskFile => PersistenceSource::TaskFile,
+                AutostartSource::CommandLine => PersistenceSource::CommandLine,
+                AutostartSource::Registry => PersistenceSource::Registry,
+            },
+            target: event.entry.target.clone(),
+            tags: event.tags.clone(),
+            incident: event.incident.as_ref().map(IncidentRef::from),
+        })
+    }
+}
+
+impl From<&crate::rawdisk::RawDiskWrite> for Event {
+    fn from(event: &crate::rawdisk::RawDiskWrite) -> Event {
+        Event::RawDiskWrite(RawDiskWrite {
+            time: rfc3339(SystemTime::now()),
+            gid: event.gid,
+            pid: event.pid,
+            device: event.device.clone(),
+            mount_point: event.mount_point.clone(),
+            source: match event.source {
+                crate::rawdisk::RawDiskSource::Driver => RawDiskSource::Driver,
+                crate::rawdisk::RawDiskSource::HandleAudit => RawDiskSource::HandleAudit,
+            },
+        })
+    }
+}
+
+impl From<&crate::killcheck::Kill> for Event {
+    fn from(kill: &crate::killcheck::Kill) -> Event {
+        use crate::killcheck::KillOutcome as Outcome;
+
+        let request = &kill.request;
+        let verification = &kill.verification;
+        Event::Kill(Kill {
+            time: rfc3339(request.time),
+            family: family(request.gid, &request.appname, &request.exepath),
+            pids: request.pids.clone(),
+            prediction: request.prediction,
+            error: request.error.clone(),
+            outcome: match verification.outcome {
+                Outcome::Exited => KillOutcome::Exited,
+                Outcome::Rekilled => KillOutcome::Rekilled,
+                Outcome::Failed => KillOutcome::Failed,
+            },
+            attempts: verification.attempts,
+            respawns: verification.respawns.clone(),
+            survivors: verification.survivors.clone(),
+            verification_ms: verification.duration.as_millis() as u64,
+            quarantined: kill
+                .quarantined
+                .iter()
+                .map(|item| Quarantined {
+                    id: item.id.clone(),
+                    original_path: item.original_path.to_string_lossy().to_string(),
+                    sha256: item.sha256.clone(),
+                    size: item.size,
+                })
+                .collect(),
+            tags: request.tags.clone(),
+            incident: request.incident.as_ref().map(IncidentRef::from),
+            suspected_family: request.suspected_family.clone(),
+        })
+    }
+}
+
+impl From<&crate::process::ProcessTerminated> for Event {
+    fn from(summary: &crate::process::ProcessTerminated) -> Event {
+        Event::ProcessTerminated(ProcessTerminated {
+            family: family(summary.gid, &summary.appname, &summary.exepath),
+            pids_count: summary.pids_count,
+            owner: summary.owner.as_ref().map(owner),
+            time_started: rfc3339(summary.time_started),
+            time_exited: rfc3339(summary.time_exited),
+            driver_msg_count: summary.driver_msg_count,
+            files_read: summary.files_read,
+            files_written: summary.files_written,
+            files_renamed: summary.files_renamed,
+            files_deleted: summary.files_deleted,
+            bytes_read: summary.bytes_read,
+            bytes_written: summary.bytes_written,
+            predictions_count: summary.predictions_count,
+            max_prediction: summary.max_prediction,
+            is_malicious: summary.is_malicious,
+            process_state: summary.process_state.clone(),
+        })
+    }
+}
+
+impl From<&crate::profiles::ProfileChange> for Event {
+    fn from(change: &crate::profiles::ProfileChange) -> Event {
+        Event::ProfileChange(ProfileChange {
+            time: rfc3339(change.time),
+            previous: change.previous.clone(),
+            current: change.current.clone(),
+            values: change
+                .values
+                .iter()
+                .map(|(param, value)| (Param::convert_to_str(param).to_string(), value.clone()))
+                .collect(),
+        })
+    }
+}
+
+impl From<&crate::connectors::connector::ConnectorDegraded> for Event {
+    fn from(event: &crate::connectors::connector::ConnectorDegraded) -> Event {
+        Event::ConnectorDegraded(ConnectorDegraded {
+            time: rfc3339(event.time),
+            connector: event.connector.clone(),
+            failures: event.failures,
+            last_error: event.last_error.clone(),
+            cool_down_secs: event.cool_down.as_secs(),
+        })
+    }
+}
+
+impl From<&crate::watchdog::Incident> for Event {
+    fn from(incident: &crate::watchdog::Incident) -> Event {
+        use crate::watchdog::IncidentKind as Kind;
+
+        Event::AgentIncident(AgentIncident {
+            time: incident.time.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Micros, true),
+            kind: match incident.kind {
+                Kind::Panic => IncidentKind::Panic,
+                Kind::Hang => IncidentKind::Hang,
+                Kind::SelfTest => IncidentKind::SelfTest,
+                Kind::TamperAttempt => IncidentKind::TamperAttempt,
+            },
+            message: incident.message.clone(),
+            report_path: incident.report_path.to_string_lossy().to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::schema::{dump, Envelope, SCHEMA_VERSION};
+
+    const PUBLISHED: &str = include_str!("../schema/events.v1.schema.json");
+    const EXAMPLES: &str = include_str!("../schema/examples.v1.json");
+
+    #[test]
+    fn schema_should_match_the_published_one() {
+        let published: Value = serde_json::from_str(PUBLISHED).unwrap();
+        // on purpose: update the file, and the version if the change is not an addition
+        assert_eq!(serde_json::to_value(dump()).unwrap(), published, "run: owlyshield_ransom schema dump");
+        assert!(SCHEMA_VERSION.starts_with("1."));
+    }
+
+    #[test]
+    fn published_examples_should_be_read_and_written_identically() {
+        let examples: Vec<Value> = serde_json::from_str(EXAMPLES).unwrap();
+        assert_eq!(examples.len(), 12, "one example per event type");
+        for example in examples {
+            let envelope: Envelope = serde_json::from_value(example.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&envelope).unwrap(), example);
+        }
+    }
+}
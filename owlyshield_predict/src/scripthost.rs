@@ -0,0 +1,314 @@
+//! Content of the scripts run by the script hosts (PowerShell, Windows Script Host, mshta), which
+//! would otherwise only be seen as *powershell.exe* in the reports.
+//!
+//! When the root of a gid is a script host, its command line is parsed for the script path or the
+//! inline command, decoded if given with *-EncodedCommand*. A relative script path is resolved
+//! against the working directory of the process, and not scanned if it is unknown. With
+//! *SCRIPT_AMSI* (Windows), the content is scanned by the antimalware provider (Defender or another
+//! AV) through AMSI. The verdict is logged and written to the incident reports with the decoded
+//! command. A detection is also the *script_amsi_detected* column of the [crate::features], which
+//! the shipped models do not use: only a model trained on it takes it into account.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+use tracing::{info, warn};
+
+use crate::config::{Config, Param};
+
+/// Lowercase file names of the script hosts.
+pub const SCRIPT_HOSTS: [&str; 5] = ["powershell.exe", "pwsh.exe", "wscript.exe", "cscript.exe", "mshta.exe"];
+/// Larger scripts are only partially scanned.
+const MAX_SCANNED_LEN: u64 = 1024 * 1024;
+
+/// PowerShell parameters followed by a value, which is not the command, with the length of their
+/// shortest prefix: PowerShell accepts any of them (*-exec*, *-win*).
+const POWERSHELL_VALUED: [(&str, usize); 9] = [
+    ("executionpolicy", 2),
+    ("windowstyle", 1),
+    ("workingdirectory", 2),
+    ("version", 1),
+    ("inputformat", 3),
+    ("outputformat", 1),
+    ("configurationname", 4),
+    ("settingsfile", 2),
+    ("custompipename", 2),
+];
+/// Aliases of the [POWERSHELL_VALUED] parameters, which are not prefixes.
+const POWERSHELL_VALUED_ALIASES: [&str; 4] = ["ep", "if", "of", "wd"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub enum AmsiVerdict {
+    Clean,
+    NotDetected,
+    /// Detected or blocked by the administrator
+    Detected,
+}
+
+impl Display for AmsiVerdict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AmsiVerdict::Clean => write!(f, "clean"),
+            AmsiVerdict::NotDetected => write!(f, "not detected"),
+            AmsiVerdict::Detected => write!(f, "detected"),
+        }
+    }
+}
+
+/// What a script host was asked to run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScriptInvocation {
+    pub host: String,
+    pub script_path: Option<PathBuf>,
+    /// Inline command, decoded if it was given with *-EncodedCommand*
+    pub command: Option<String>,
+    pub encoded: bool,
+    pub amsi: Option<AmsiVerdict>,
+}
+
+impl ScriptInvocation {
+    /// Value of the *script_amsi_detected* feature.
+    pub fn amsi_detected(&self) -> f32 {
+        (self.amsi == Some(AmsiVerdict::Detected)) as u8 as f32
+    }
+
+    /// Parses the arguments (the executable first) of the script host *host*.
+    pub fn parse(host: &str, args: &[String]) -> ScriptInvocation {
+        let mut invocation = ScriptInvocation {
+            host: String::from(host),
+            ..ScriptInvocation::default()
+        };
+        let args = args.get(1..).unwrap_or_default();
+        match host {
+            "powershell.exe" | "pwsh.exe" => invocation.parse_powershell(args),
+            // host options start with //
+            "wscript.exe" | "cscript.exe" => {
+                invocation.script_path = args.iter().find(|a| !a.starts_with("//")).map(PathBuf::from);
+            }
+            _ => invocation.script_path = args.first().map(PathBuf::from),
+        }
+        invocation
+    }
+
+    fn parse_powershell(&mut self, args: &[String]) {
+        let mut i = 0;
+        while i < args.len() {
+            let arg = &args[i];
+            let name = arg.strip_prefix('-').or_else(|| arg.strip_prefix('/')).map(str::to_lowercase);
+            match name.as_deref() {
+                Some(n) if n == "ec" || (n.starts_with('e') && "encodedcommand".starts_with(n)) => {
+                    let decoded = args.get(i + 1).and_then(|b64| decode_utf16_base64(b64));
+                    self.encoded = true;
+                    self.command = decoded.or_else(|| args.get(i + 1).cloned());
+                    return;
+                }
+                Some(n) if n.starts_with('f') && "file".starts_with(n) => {
+                    self.script_path = args.get(i + 1).map(PathBuf::from);
+                    return;
+                }
+                Some(n) if n.starts_with('c') && n != "configurationname" && "command".starts_with(n) => {
+                    self.command = Some(args[i + 1..].join(" "));
+                    return;
+                }
+                Some(n) if is_powershell_valued(n) => i += 2,
+                Some(_) => i += 1,
+                None => {
+                    // powershell runs the remaining arguments as a command, pwsh as a file
+                    if self.host == "pwsh.exe" {
+                        self.script_path = Some(PathBuf::from(arg));
+                    } else {
+                        self.command = Some(args[i..].join(" "));
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Resolves a relative script path against *cwd*, the working directory of the host.
+    pub fn resolve(&mut self, cwd: &Path) {
+        if let Some(path) = self.script_path.as_mut() {
+            if path.is_relative() && cwd.is_absolute() {
+                *path = cwd.join(&*path);
+            }
+        }
+    }
+
+    /// The content to scan: the script file, or the command. None if the script path is still
+    /// relative: it would be read from the working directory of the agent.
+    fn content(&self) -> Option<Vec<u8>> {
+        if let Some(command) = &self.command {
+            return Some(command.as_bytes().to_vec());
+        }
+        let path = self.script_path.as_ref().filter(|path| path.is_absolute())?;
+        let mut content = Vec::new();
+        File::open(path).ok()?.take(MAX_SCANNED_LEN).read_to_end(&mut content).ok()?;
+        Some(content)
+    }
+}
+
+/// *name* (lowercase, without the dash) is a PowerShell parameter followed by a value.
+fn is_powershell_valued(name: &str) -> bool {
+    POWERSHELL_VALUED_ALIASES.contains(&name)
+        || POWERSHELL_VALUED.iter().any(|(param, min)| name.len() >= *min && param.starts_with(name))
+}
+
+/// The invocation of *exepath*, if it is a script host.
+pub fn capture(config: &Config, exepath: &Path, pid: u32) -> Option<ScriptInvocation> {
+    let host = exepath.file_name()?.to_string_lossy().to_lowercase();
+    if !SCRIPT_HOSTS.contains(&host.as_str()) {
+        return None;
+    }
+    let mut system = System::new();
+    system.refresh_process(pid as Pid);
+    let process = system.process(pid as Pid);
+    let args = process.map(|p| p.cmd().to_vec()).unwrap_or_default();
+    let mut invocation = ScriptInvocation::parse(&host, &args);
+    if let Some(process) = process {
+        invocation.resolve(process.cwd());
+    }
+    if config.get_bool(Param::ScriptAmsi) {
+        if let Some(content) = invocation.content() {
+            match amsi_scan(&content, &invocation.host) {
+                Ok(verdict) => invocation.amsi = verdict,
+                Err(e) => warn!("AMSI scan failed: {}", e),
+            }
+        }
+    }
+    info!(
+        pid,
+        host = %invocation.host,
+        script = %invocation.script_path.as_ref().map_or(String::new(), |p| p.display().to_string()),
+        command = invocation.command.as_deref().unwrap_or(""),
+        encoded = invocation.encoded,
+        amsi = %invocation.amsi.map_or(String::from("-"), |v| v.to_string()),
+        "Script host started"
+    );
+    Some(invocation)
+}
+
+/// Decodes a PowerShell *-EncodedCommand*: base64 of UTF-16LE.
+pub fn decode_utf16_base64(encoded: &str) -> Option<String> {
+    let bytes = decode_base64(encoded)?;
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+    let utf16: Vec<u16> = bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    String::from_utf16(&utf16).ok()
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let encoded = encoded.trim().trim_end_matches('=');
+    let mut res = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in encoded.bytes() {
+        acc = (acc << 6) | value(c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            res.push((acc >> bits) as u8);
+        }
+    }
+    Some(res)
+}
+
+/// Verdict of the antimalware provider on *content*, None if there is none.
+#[cfg(windows)]
+pub(crate) fn amsi_scan(content: &[u8], name: &str) -> Result<Option<AmsiVerdict>, windows::Error> {
+    use bindings::Windows::Win32::System::Antimalware::{
+        AmsiInitialize, AmsiScanBuffer, AmsiUninitialize, AMSI_RESULT, HAMSISESSION,
+    };
+
+    /// AMSI_RESULT_BLOCKED_BY_ADMIN_START, below AMSI_RESULT_DETECTED (32768)
+    const BLOCKED_BY_ADMIN: i32 = 0x4000;
+    unsafe {
+        let context = AmsiInitialize("Owlyshield")?;
+        let mut result = AMSI_RESULT::default();
+        let scanned = AmsiScanBuffer(
+            context,
+            content.as_ptr() as *mut core::ffi::c_void,
+            content.len() as u32,
+            name,
+            HAMSISESSION::default(),
+            &mut result,
+        );
+        AmsiUninitialize(context);
+        scanned?;
+        Ok(Some(match result.0 {
+            0 => AmsiVerdict::Clean,
+            r if r >= BLOCKED_BY_ADMIN => AmsiVerdict::Detected,
+            _ => AmsiVerdict::NotDetected,
+        }))
+    }
+}
+
+/// AMSI is a Windows API.
+#[cfg(not(windows))]
+pub(crate) fn amsi_scan(_content: &[u8], _name: &str) -> Result<Option<AmsiVerdict>, String> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::scripthost::ScriptInvocation;
+
+    #[test]
+    fn script_host_invocations_should_be_parsed() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+        // "Write-Host pwned" in UTF-16LE
+        let encoded = ScriptInvocation::parse(
+            "powershell.exe",
+            &args("powershell.exe -NoP -ep Bypass -w hidden -enc VwByAGkAdABlAC0ASABvAHMAdAAgAHAAdwBuAGUAZAA="),
+        );
+        assert_eq!(encoded.command.as_deref(), Some("Write-Host pwned"));
+        assert!(encoded.encoded);
+        let file = ScriptInvocation::parse("powershell.exe", &args(r"powershell.exe -ExecutionPolicy Bypass -File C:\Temp\x.ps1"));
+        assert_eq!(file.script_path, Some(PathBuf::from(r"C:\Temp\x.ps1")));
+        let wsh = ScriptInvocation::parse("wscript.exe", &args(r"wscript.exe //B //Nologo C:\Users\Public\a.vbs"));
+        assert_eq!(wsh.script_path, Some(PathBuf::from(r"C:\Users\Public\a.vbs")));
+    }
+
+    #[test]
+    fn abbreviated_powershell_parameters_should_skip_their_value() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+        for line in [
+            "powershell -exec bypass -enc VwByAGkAdABlAC0ASABvAHMAdAAgAHAAdwBuAGUAZAA=",
+            "powershell -win hidden -executionp bypass -e VwByAGkAdABlAC0ASABvAHMAdAAgAHAAdwBuAGUAZAA=",
+            "powershell -nop -wd C:\\ -config x -EncodedCommand VwByAGkAdABlAC0ASABvAHMAdAAgAHAAdwBuAGUAZAA=",
+        ] {
+            let invocation = ScriptInvocation::parse("powershell.exe", &args(line));
+            assert_eq!(invocation.command.as_deref(), Some("Write-Host pwned"), "{}", line);
+            assert!(invocation.encoded);
+        }
+        let inline = ScriptInvocation::parse("powershell.exe", &args("powershell -windowstyle hidden -noni Get-Date"));
+        assert_eq!(inline.command.as_deref(), Some("Get-Date"));
+    }
+
+    #[test]
+    fn relative_script_paths_should_be_resolved_against_the_host_cwd() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+        let cwd = std::env::temp_dir();
+        let mut file = ScriptInvocation::parse("powershell.exe", &args(r"powershell.exe -File x.ps1"));
+        assert_eq!(file.content(), None, "not read from the cwd of the agent");
+        file.resolve(&cwd);
+        assert_eq!(file.script_path, Some(cwd.join("x.ps1")));
+        let mut unknown = ScriptInvocation::parse("powershell.exe", &args(r"powershell.exe -File x.ps1"));
+        unknown.resolve(Path::new(""));
+        assert_eq!(unknown.script_path, Some(PathBuf::from("x.ps1")));
+    }
+}
@@ -0,0 +1,124 @@
+//! Encrypted storage of connectors credentials.
+//!
+//! Secrets are encrypted with DPAPI in the machine scope (only processes of this machine can
+//! decrypt them) and stored as binary values under [SECRETS_REGISTRY_KEY]. They are set with
+//! ```owlyshield_ransom secret set <name>``` (the value is read on stdin), where *name* is
+//! ```<Connector>.<KEY>```, for example ```SitinCloud.API_KEY```.
+
+use std::error::Error;
+use std::ffi::c_void;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ptr;
+
+use bindings::Windows::Win32::Foundation::PWSTR;
+use bindings::Windows::Win32::Security::Cryptography::Core::{
+    CryptProtectData, CryptUnprotectData, CRYPTOAPI_BLOB,
+};
+use bindings::Windows::Win32::System::Diagnostics::Debug::GetLastError;
+use bindings::Windows::Win32::System::Memory::LocalFree;
+use registry::{Data, Hive, Security};
+
+/// Registry key where the encrypted secrets are stored.
+pub static SECRETS_REGISTRY_KEY: &str = r"SOFTWARE\Owlyshield\Secrets";
+
+/// Any user on this machine can decrypt (access is restricted by the registry ACL).
+const CRYPTPROTECT_LOCAL_MACHINE: u32 = 0x4;
+/// The service has no UI.
+const CRYPTPROTECT_UI_FORBIDDEN: u32 = 0x1;
+
+#[derive(Debug)]
+pub enum SecretError {
+    /// The secret has not been set.
+    NotFound(String),
+    /// DPAPI failed, with the last error code.
+    Dpapi(u32),
+    Registry(String),
+}
+
+impl Display for SecretError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::NotFound(name) => write!(f, "Secret {} not found", name),
+            SecretError::Dpapi(code) => write!(f, "DPAPI error {}", code),
+            SecretError::Registry(details) => write!(f, "Registry error: {}", details),
+        }
+    }
+}
+
+impl Error for SecretError {}
+
+/// Encrypts and stores the secret *name*.
+pub fn set_secret(name: &str, value: &str) -> Result<(), SecretError> {
+    let encrypted = protect(value.as_bytes())?;
+    let regkey = Hive::LocalMachine
+        .create(SECRETS_REGISTRY_KEY, Security::Write)
+        .map_err(|e| SecretError::Registry(e.to_string()))?;
+    regkey
+        .set_value(name, &Data::Binary(encrypted))
+        .map_err(|e| SecretError::Registry(e.to_string()))
+}
+
+/// Reads and decrypts the secret *name*.
+pub fn get_secret(name: &str) -> Result<String, SecretError> {
+    let regkey = Hive::LocalMachine
+        .open(SECRETS_REGISTRY_KEY, Security::Read)
+        .map_err(|_| SecretError::NotFound(String::from(name)))?;
+    match regkey.value(name) {
+        Ok(Data::Binary(encrypted)) => {
+            let decrypted = unprotect(&encrypted)?;
+            Ok(String::from_utf8_lossy(&decrypted).to_string())
+        }
+        _ => Err(SecretError::NotFound(String::from(name))),
+    }
+}
+
+/// Encrypts *data* with DPAPI in the machine scope.
+pub fn protect(data: &[u8]) -> Result<Vec<u8>, SecretError> {
+    dpapi(data, true)
+}
+
+/// Decrypts *data* encrypted by [protect].
+pub fn unprotect(data: &[u8]) -> Result<Vec<u8>, SecretError> {
+    dpapi(data, false)
+}
+
+fn dpapi(data: &[u8], is_protect: bool) -> Result<Vec<u8>, SecretError> {
+    let mut data_in = CRYPTOAPI_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut u8,
+    };
+    let mut data_out = CRYPTOAPI_BLOB {
+        cbData: 0,
+        pbData: ptr::null_mut(),
+    };
+    unsafe {
+        let res = if is_protect {
+            CryptProtectData(
+                &mut data_in,
+                PWSTR(ptr::null_mut()),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                CRYPTPROTECT_LOCAL_MACHINE | CRYPTPROTECT_UI_FORBIDDEN,
+                &mut data_out,
+            )
+        } else {
+            CryptUnprotectData(
+                &mut data_in,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut data_out,
+            )
+        };
+        if !res.as_bool() {
+            return Err(SecretError::Dpapi(GetLastError().0));
+        }
+        let out = std::slice::from_raw_parts(data_out.pbData, data_out.cbData as usize).to_vec();
+        LocalFree(data_out.pbData as *mut c_void as isize);
+        Ok(out)
+    }
+}
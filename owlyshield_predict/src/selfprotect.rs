@@ -0,0 +1,185 @@
+//! Self-defense of the agent against a ransomware trying to disable it first.
+//!
+//! * the minifilter strips dangerous rights (terminate, suspend, write memory) from the handles
+//!   opened by other processes to the protected pids;
+//! * restrictive DACLs are applied to this process, to the config directory and to the registry
+//!   keys: only SYSTEM and the administrators can modify them;
+//! * tamper attempts blocked by the minifilter are reported as Critical events.
+//!
+//! Disabled with the *SELF_PROTECTION* [Param].
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use chrono::Local;
+
+use bindings::Windows::Win32::Foundation::{BOOL, PSID, PWSTR};
+use bindings::Windows::Win32::Security::Authorization::{
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, SetNamedSecurityInfoW, SetSecurityInfo,
+    SE_FILE_OBJECT, SE_KERNEL_OBJECT, SE_OBJECT_TYPE, SE_REGISTRY_KEY,
+};
+use bindings::Windows::Win32::Security::{GetSecurityDescriptorDacl, ACL};
+use bindings::Windows::Win32::System::Diagnostics::Debug::GetLastError;
+use bindings::Windows::Win32::System::Memory::LocalFree;
+use bindings::Windows::Win32::System::Threading::GetCurrentProcess;
+use tracing::{error, info};
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+use widestring::U16CString;
+
+use crate::config::{Config, Param, REGISTRY_KEY};
+use crate::connectors::connector::Connectors;
+use crate::driver_com::Driver;
+use crate::notifications::toast;
+use crate::watchdog::{Incident, IncidentKind};
+
+const SDDL_REVISION_1: u32 = 1;
+const DACL_SECURITY_INFORMATION: u32 = 0x4;
+/// The DACL does not inherit from the parent object.
+const PROTECTED_DACL_SECURITY_INFORMATION: u32 = 0x8000_0000;
+
+/// SYSTEM has full access, administrators can only query the process (no terminate, suspend, inject).
+static PROCESS_SDDL: &str = "D:P(A;;GA;;;SY)(A;;0x121410;;;BA)";
+/// SYSTEM and administrators have full access, users can read.
+static DIRECTORY_SDDL: &str = "D:PAI(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)(A;OICI;FR;;;BU)";
+static REGISTRY_SDDL: &str = "D:PAI(A;CI;KA;;;SY)(A;CI;KA;;;BA)(A;CI;KR;;;BU)";
+/// SYSTEM and administrators only, for the stores of the agent.
+static ADMIN_ONLY_SDDL: &str = "D:PAI(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)";
+
+/// Registers *pids* (this process is already protected by [Driver::driver_set_app_pid]) and
+/// applies the DACLs. Failures are logged: the agent keeps running without self-protection.
+pub fn apply(driver: &Driver, config: &Config, pids: &[u32]) {
+    if !config.get_bool(Param::SelfProtection) {
+        info!("Self-protection is disabled");
+        return;
+    }
+    for pid in pids {
+        if let Err(e) = driver.add_protected_pid(*pid) {
+            error!("Cannot protect pid {}: {}", pid, e);
+        }
+    }
+    unsafe {
+        if let Err(code) = set_security(PROCESS_SDDL, |dacl| {
+            SetSecurityInfo(
+                GetCurrentProcess(),
+                SE_KERNEL_OBJECT,
+                DACL_SECURITY_INFORMATION,
+                PSID(ptr::null_mut()),
+                PSID(ptr::null_mut()),
+                dacl,
+                ptr::null(),
+            )
+        }) {
+            error!("Cannot set the process DACL: {}", code);
+        }
+    }
+    let config_path = config.get_path(Param::ConfigPath);
+    if let Err(code) = protect_named_object(&config_path, SE_FILE_OBJECT, DIRECTORY_SDDL) {
+        error!("Cannot set the DACL of {}: {}", config_path.display(), code);
+    }
+    if config.portable_dir().is_some() {
+        // the portable mode does not use the registry
+        return;
+    }
+    let registry_path = PathBuf::from(format!(r"MACHINE\{}", REGISTRY_KEY));
+    if let Err(code) = protect_named_object(&registry_path, SE_REGISTRY_KEY, REGISTRY_SDDL) {
+        error!("Cannot set the DACL of {}: {}", registry_path.display(), code);
+    }
+}
+
+/// Reports the tamper attempts blocked by the minifilter since the last call, one Critical event
+/// per offending process.
+pub fn report_tamper_attempts(driver: &Driver, config: &Config, connectors: &Connectors) {
+    let attempts = match driver.get_tamper_attempts() {
+        Ok(attempts) => attempts,
+        Err(e) => {
+            error!("Cannot get tamper attempts: {}", e);
+            return;
+        }
+    };
+    if attempts.is_empty() {
+        return;
+    }
+    let mut by_source: HashMap<u32, (u32, usize)> = HashMap::new();
+    for attempt in &attempts {
+        let entry = by_source.entry(attempt.source_pid).or_insert((attempt.desired_access, 0));
+        entry.0 |= attempt.desired_access;
+        entry.1 += 1;
+    }
+    let mut system = System::new();
+    for (source_pid, (access, count)) in by_source {
+        system.refresh_process(source_pid as Pid);
+        let exepath = system
+            .process(source_pid as Pid)
+            .map(|p| p.exe().display().to_string())
+            .unwrap_or_else(|| String::from("unknown"));
+        let message = format!(
+            "Critical: {} tamper attempt(s) on Owlyshield blocked, from pid {} ({}), access {:#x}",
+            count, source_pid, exepath, access
+        );
+        error!("{}", message);
+        toast(config, &format!("Tamper attempt blocked: {}", exepath), "");
+        connectors.send_incident(&Incident {
+            kind: IncidentKind::TamperAttempt,
+            time: Local::now(),
+            message,
+            report_path: PathBuf::new(),
+        });
+    }
+}
+
+/// Restricts the file or directory *path* to SYSTEM and the administrators, whatever the
+/// *SELF_PROTECTION* [Param]. Returns the win32 error code on failure.
+pub fn restrict_to_admins(path: &Path) -> Result<(), u32> {
+    protect_named_object(path, SE_FILE_OBJECT, ADMIN_ONLY_SDDL)
+}
+
+fn protect_named_object(path: &Path, object_type: SE_OBJECT_TYPE, sddl: &str) -> Result<(), u32> {
+    let wpath = U16CString::from_os_str(path.as_os_str()).map_err(|_| 0u32)?;
+    unsafe {
+        set_security(sddl, |dacl| {
+            SetNamedSecurityInfoW(
+                PWSTR(wpath.as_ptr() as *mut u16),
+                object_type,
+                DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+                PSID(ptr::null_mut()),
+                PSID(ptr::null_mut()),
+                dacl,
+                ptr::null(),
+            )
+        })
+    }
+}
+
+/// Builds the DACL described by *sddl* and gives it to *set*, which returns a win32 error code.
+unsafe fn set_security<F>(sddl: &str, set: F) -> Result<(), u32>
+where
+    F: FnOnce(*const ACL) -> u32,
+{
+    let wsddl = U16CString::from_str(sddl).map_err(|_| 0u32)?;
+    let mut descriptor: *mut c_void = ptr::null_mut();
+    if !ConvertStringSecurityDescriptorToSecurityDescriptorW(
+        PWSTR(wsddl.as_ptr() as *mut u16),
+        SDDL_REVISION_1,
+        &mut descriptor,
+        ptr::null_mut(),
+    )
+    .as_bool()
+    {
+        return Err(GetLastError().0);
+    }
+    let mut present = BOOL(0);
+    let mut defaulted = BOOL(0);
+    let mut dacl: *mut ACL = ptr::null_mut();
+    let res = if GetSecurityDescriptorDacl(descriptor, &mut present, &mut dacl, &mut defaulted).as_bool() {
+        match set(dacl) {
+            0 => Ok(()),
+            code => Err(code),
+        }
+    } else {
+        Err(GetLastError().0)
+    };
+    LocalFree(descriptor as isize);
+    res
+}
@@ -0,0 +1,248 @@
+//! End-to-end self-test of the detection path: a driver unloaded or detached, or an altitude
+//! conflict with another minifilter, silently stops the events, and the agent protects nothing.
+//!
+//! Every *SELF_TEST_MINUTES*, the agent spawns itself as a helper child process (the hidden
+//! ```self-test-helper``` command), which writes then renames a temporary file. The fetch stage
+//! looks for the write and the rename of the helper among the driver messages: if they have not
+//! all arrived within [DEADLINE], an [Incident] is reported to the connectors. The messages of the
+//! helper are not processed further.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+#[cfg(windows)]
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::config::{Config, Param};
+use crate::connectors::connector::Connectors;
+use crate::driver_com::shared_def::{FileChangeInfo, IOMessage};
+use crate::driver_com::IrpMajorOp;
+#[cfg(windows)]
+use crate::driver_com::Driver;
+#[cfg(windows)]
+use crate::driver_reply;
+use crate::watchdog::{Incident, IncidentKind};
+
+/// Name of the hidden command of the helper.
+pub const HELPER_COMMAND: &str = "self-test-helper";
+/// Delay for the driver messages of the helper to arrive.
+pub const DEADLINE: Duration = Duration::from_secs(30);
+/// Prefix of the temporary files of the helper.
+const FILE_PREFIX: &str = "owlyshield_selftest_";
+
+/// A self-test in progress.
+#[derive(Debug)]
+struct Run {
+    token: String,
+    pid: u32,
+    started: Instant,
+    written: bool,
+    renamed: bool,
+    child: Option<Child>,
+}
+
+impl Run {
+    /// True if the operation *irp_op* of *pid* on *path* comes from the helper: then it is
+    /// recorded.
+    fn observe(&mut self, pid: u32, path: &str, irp_op: u8, file_change: u8) -> bool {
+        if pid != self.pid && !path.contains(&self.token) {
+            return false;
+        }
+        match IrpMajorOp::from_byte(irp_op) {
+            IrpMajorOp::IrpWrite => self.written = true,
+            IrpMajorOp::IrpSetInfo => {
+                let file_change: Option<FileChangeInfo> = num::FromPrimitive::from_u8(file_change);
+                if matches!(
+                    file_change,
+                    Some(FileChangeInfo::FileChangeRenameFile) | Some(FileChangeInfo::FileChangeExtensionChanged)
+                ) {
+                    self.renamed = true;
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn is_complete(&self) -> bool {
+        self.written && self.renamed
+    }
+}
+
+pub struct SelfTest {
+    interval: Option<Duration>,
+    dir: PathBuf,
+    last_run: Instant,
+    run: Option<Run>,
+    /// Consecutive failures, reported once each
+    failures: u32,
+}
+
+impl SelfTest {
+    pub fn from(config: &Config) -> SelfTest {
+        let minutes = config.get_usize(Param::SelfTestMinutes) as u64;
+        SelfTest {
+            interval: (minutes > 0).then(|| Duration::from_secs(minutes * 60)),
+            dir: std::env::temp_dir(),
+            last_run: Instant::now(),
+            run: None,
+            failures: 0,
+        }
+    }
+
+    /// True if *iomsg* comes from the helper, and must not be processed further.
+    pub fn observe(&mut self, iomsg: &IOMessage) -> bool {
+        self.run
+            .as_mut()
+            .is_some_and(|run| run.observe(iomsg.pid, &iomsg.filepathstr, iomsg.irp_op, iomsg.file_change))
+    }
+
+    /// Starts a self-test when due, and checks the one in progress.
+    pub fn tick(&mut self, connectors: &Connectors) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        if let Some(run) = self.run.as_mut() {
+            if let Some(child) = run.child.as_mut() {
+                if child.try_wait().ok().flatten().is_some() {
+                    run.child = None;
+                }
+            }
+            if run.is_complete() {
+                debug!(elapsed_ms = run.started.elapsed().as_millis() as u64, "Self-test passed");
+                if self.failures > 0 {
+                    info!(failures = self.failures, "Self-test passed again");
+                }
+                self.failures = 0;
+                self.run = None;
+            } else if run.started.elapsed() >= DEADLINE {
+                self.failures += 1;
+                let message = format!(
+                    "Self-test failed: the driver messages of the helper (pid {}) did not arrive within {} seconds (write: {}, rename: {}). The driver may be unloaded or detached",
+                    run.pid,
+                    DEADLINE.as_secs(),
+                    run.written,
+                    run.renamed
+                );
+                error!("Critical: {}", message);
+                connectors.send_incident(&Incident {
+                    kind: IncidentKind::SelfTest,
+                    time: Local::now(),
+                    message,
+                    report_path: self.dir.clone(),
+                });
+                self.run = None;
+            }
+            return;
+        }
+        if self.last_run.elapsed() >= interval {
+            self.last_run = Instant::now();
+            match self.spawn() {
+                Ok(run) => self.run = Some(run),
+                Err(e) => error!("Cannot start the self-test helper: {}", e),
+            }
+        }
+    }
+
+    fn spawn(&self) -> io::Result<Run> {
+        let token = Uuid::new_v4().simple().to_string();
+        let child = Command::new(std::env::current_exe()?).arg(HELPER_COMMAND).arg(&self.dir).arg(&token).spawn()?;
+        debug!(pid = child.id(), "Self-test started");
+        Ok(Run {
+            token,
+            pid: child.id(),
+            started: Instant::now(),
+            written: false,
+            renamed: false,
+            child: Some(child),
+        })
+    }
+}
+
+/// A self-test on *driver*, outside of the protection loop (which must not be connected): see
+/// [crate::setup]. Returns the delay of the driver messages of the helper.
+#[cfg(windows)]
+pub fn run_once(driver: &Driver) -> Result<Duration, String> {
+    let test = SelfTest {
+        interval: None,
+        dir: std::env::temp_dir(),
+        last_run: Instant::now(),
+        run: None,
+        failures: 0,
+    };
+    let mut run = test.spawn().map_err(|e| format!("Cannot start the self-test helper: {}", e))?;
+    let mut buffer = vec![0u8; driver_reply::BUFFER_SIZE];
+    while !run.is_complete() {
+        if run.started.elapsed() >= DEADLINE {
+            return Err(format!(
+                "the driver messages of the helper did not arrive within {} seconds (write: {}, rename: {})",
+                DEADLINE.as_secs(),
+                run.written,
+                run.renamed
+            ));
+        }
+        match driver.get_irp(&mut buffer) {
+            Ok(Some(drivermsgs)) if !drivermsgs.is_empty() => {
+                for drivermsg in &drivermsgs {
+                    let iomsg = IOMessage::from(drivermsg);
+                    run.observe(iomsg.pid, &iomsg.filepathstr, iomsg.irp_op, iomsg.file_change);
+                }
+            }
+            Ok(_) => thread::sleep(Duration::from_millis(100)),
+            Err(e) => return Err(format!("Cannot receive the driver messages: {}", e)),
+        }
+    }
+    if let Some(mut child) = run.child.take() {
+        let _ = child.wait();
+    }
+    Ok(run.started.elapsed())
+}
+
+/// The helper: writes, renames and deletes *dir\owlyshield_selftest_<token>.tmp*.
+pub fn run_helper(dir: &Path, token: &str) -> io::Result<()> {
+    let tmp = dir.join(format!("{}{}.tmp", FILE_PREFIX, token));
+    let done = tmp.with_extension("done");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(b"Owlyshield self-test")?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, &done)?;
+    fs::remove_file(&done)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::driver_com::shared_def::FileChangeInfo;
+    use crate::driver_com::IrpMajorOp;
+    use crate::selftest::Run;
+
+    #[test]
+    fn write_and_rename_of_the_helper_should_complete_the_run() {
+        let mut run = Run {
+            token: String::from("0123abcd"),
+            pid: 4242,
+            started: Instant::now(),
+            written: false,
+            renamed: false,
+            child: None,
+        };
+        let (write, set_info) = (IrpMajorOp::IrpWrite as u8, IrpMajorOp::IrpSetInfo as u8);
+        let renamed = FileChangeInfo::FileChangeExtensionChanged as u8;
+        assert!(!run.observe(17, r"C:\Users\bob\a.docx", write, 0));
+        assert!(run.observe(4242, r"C:\Windows\Temp\owlyshield_selftest_0123abcd.tmp", write, 0));
+        assert!(!run.is_complete());
+        // the rename may be seen from another pid, by the path
+        assert!(run.observe(4, r"C:\Windows\Temp\owlyshield_selftest_0123abcd.done", set_info, renamed));
+        assert!(run.is_complete());
+    }
+}
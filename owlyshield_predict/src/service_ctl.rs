@@ -0,0 +1,185 @@
+//! Installation and control of the agent Windows service, as done by the installer with sc.exe,
+//! and [Lifecycle] shared with the protection loop.
+
+#[cfg(windows)]
+use std::ffi::{OsStr, OsString};
+#[cfg(windows)]
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(windows)]
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(windows)]
+use windows_service::service::{
+    ServiceAccess, ServiceDependency, ServiceErrorControl, ServiceInfo, ServiceStartType,
+    ServiceState, ServiceType,
+};
+#[cfg(windows)]
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+/// Name of the agent service.
+pub const SERVICE_NAME: &str = "Owlyshield Service";
+#[cfg(windows)]
+pub const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+/// Name of the minifilter service, the agent depends on it.
+pub const FILTER_SERVICE_NAME: &str = "OwlyshieldRansomFilter";
+
+/// State shared between the Service Control Manager handler and the protection loop.
+#[derive(Debug, Default)]
+pub struct Lifecycle {
+    stop_requested: AtomicBool,
+    restart_requested: AtomicBool,
+    paused: AtomicBool,
+    /// Last activity of the protection loop, in ms since UNIX_EPOCH (see [crate::watchdog]).
+    heartbeat: AtomicU64,
+}
+
+impl Lifecycle {
+    /// The loop drains the IRPs queued by the driver, closes the driver port and returns.
+    pub fn request_stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stop_requested(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
+    }
+
+    /// Stops the loop, then the process relaunches itself (see [crate::updater]).
+    pub fn request_restart(&self) {
+        self.restart_requested.store(true, Ordering::SeqCst);
+        self.request_stop();
+    }
+
+    pub fn is_restart_requested(&self) -> bool {
+        self.restart_requested.load(Ordering::SeqCst)
+    }
+
+    /// While paused, driver messages are still processed and predictions made, but no process
+    /// is killed nor suspended.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Called by the protection loop at each iteration.
+    pub fn beat(&self) {
+        self.heartbeat.store(now_millis(), Ordering::Relaxed);
+    }
+
+    pub fn since_last_beat(&self) -> Duration {
+        Duration::from_millis(now_millis().saturating_sub(self.heartbeat.load(Ordering::Relaxed)))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Registers the current executable as the agent service. It is started manually, after the
+/// minifilter.
+#[cfg(windows)]
+pub fn install() -> Result<(), windows_service::Error> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let executable_path = std::env::current_exe().map_err(windows_service::Error::Winapi)?;
+    let service_info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_NAME),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::OnDemand,
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments: vec![],
+        dependencies: vec![ServiceDependency::Service(OsString::from(FILTER_SERVICE_NAME))],
+        account_name: None,
+        account_password: None,
+    };
+    manager.create_service(&service_info, ServiceAccess::QUERY_STATUS)?;
+    set_recovery_options();
+    Ok(())
+}
+
+/// Restarts the service when it exits with an error, as done by [crate::watchdog] on a hang or
+/// after too many restarts of the protection loop.
+#[cfg(windows)]
+fn set_recovery_options() {
+    let res = Command::new("sc.exe")
+        .args(&["failure", SERVICE_NAME, "reset=", "86400", "actions=", "restart/5000/restart/5000/restart/60000"])
+        .output();
+    if let Err(e) = res {
+        eprintln!("Cannot set the recovery options of {}: {}", SERVICE_NAME, e);
+    }
+}
+
+/// Stops the service if needed, then deletes it.
+#[cfg(windows)]
+pub fn uninstall() -> Result<(), windows_service::Error> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+        wait_for_state(SERVICE_NAME, ServiceState::Stopped)?;
+    }
+    service.delete()
+}
+
+/// Starts the minifilter if needed, then the agent.
+#[cfg(windows)]
+pub fn start() -> Result<(), windows_service::Error> {
+    for name in &[FILTER_SERVICE_NAME, SERVICE_NAME] {
+        start_service(name)?;
+    }
+    Ok(())
+}
+
+/// Starts the service *name* if it is not running.
+#[cfg(windows)]
+pub fn start_service(name: &str) -> Result<(), windows_service::Error> {
+    if query_state(name)? != ServiceState::Running {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(name, ServiceAccess::START)?;
+        service.start(&[] as &[&OsStr])?;
+        wait_for_state(name, ServiceState::Running)?;
+    }
+    Ok(())
+}
+
+/// Stops the agent. The minifilter is left running.
+#[cfg(windows)]
+pub fn stop() -> Result<(), windows_service::Error> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::STOP)?;
+    service.stop()?;
+    wait_for_state(SERVICE_NAME, ServiceState::Stopped)
+}
+
+#[cfg(windows)]
+pub fn query_state(name: &str) -> Result<ServiceState, windows_service::Error> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(name, ServiceAccess::QUERY_STATUS)?;
+    Ok(service.query_status()?.current_state)
+}
+
+/// Polls the service state, for at most 30 seconds.
+#[cfg(windows)]
+fn wait_for_state(name: &str, state: ServiceState) -> Result<(), windows_service::Error> {
+    for _ in 0..60 {
+        if query_state(name)? == state {
+            break;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    Ok(())
+}
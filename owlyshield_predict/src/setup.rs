@@ -0,0 +1,255 @@
+//! First-run setup of a machine, instead of the manual steps of the installation:
+//! 1. the configuration: the values of [Answers] in the registry key ```HKLM\SOFTWARE\Owlyshield```,
+//!    and the directories;
+//! 2. the minifilter: installed from its *.inf* ([driver_setup::install]), then started;
+//! 3. the agent service, registered (see [service_ctl::install]);
+//! 4. the handshake with the minifilter: the agent connects to its port and registers, then
+//!    disconnects;
+//! 5. optionally, a self-test on this connection ([selftest::run_once]);
+//! 6. optionally, the start of the agent service.
+//!
+//! ```owlyshield_ransom setup``` asks the answers on the console, the defaults between brackets.
+//! With ```--answers <file.toml>``` they are read from the file, for the unattended installs. The
+//! steps already done (minifilter or service installed) are skipped: the setup can be run again.
+
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::config::{Config, Param};
+
+#[cfg(windows)]
+use crate::config::REGISTRY_KEY;
+#[cfg(windows)]
+use crate::driver_com::Driver;
+#[cfg(windows)]
+use crate::{driver_setup, selftest, service_ctl};
+
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, Error)]
+pub enum SetupError {
+    #[error("answers: {0}")]
+    Answers(String),
+    #[error("registry: {0}")]
+    Registry(String),
+    #[error("cannot create {0}: {1}")]
+    Directory(PathBuf, std::io::Error),
+    #[error("minifilter: {0}")]
+    Driver(String),
+    #[cfg(windows)]
+    #[error("service: {0}")]
+    Service(#[from] windows_service::Error),
+    #[error("self-test: {0}")]
+    SelfTest(String),
+}
+
+/// The choices of the setup, the keys of the answers file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Answers {
+    pub config_path: PathBuf,
+    pub debug_path: PathBuf,
+    pub utils_path: PathBuf,
+    /// PROTECT, AUDIT or LEARNING
+    pub mode: String,
+    /// KILL or SUSPEND
+    pub kill_policy: String,
+    /// *.inf* of the minifilter, None if it is already installed
+    pub driver_inf: Option<PathBuf>,
+    pub self_test: bool,
+    /// Start the agent service at the end
+    pub start: bool,
+}
+
+impl Default for Answers {
+    fn default() -> Answers {
+        let default = |param: Param| param.default_value().unwrap_or_default();
+        Answers {
+            config_path: PathBuf::from(default(Param::ConfigPath)),
+            debug_path: PathBuf::from(default(Param::DebugPath)),
+            utils_path: PathBuf::from(default(Param::UtilsPath)),
+            mode: default(Param::Mode),
+            kill_policy: default(Param::KillPolicy),
+            driver_inf: None,
+            self_test: true,
+            start: true,
+        }
+    }
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Answers {
+    pub fn from_file(path: &Path) -> Result<Answers, SetupError> {
+        let content = fs::read_to_string(path).map_err(|e| SetupError::Answers(format!("{}: {}", path.display(), e)))?;
+        let answers: Answers =
+            toml::from_str(&content).map_err(|e| SetupError::Answers(format!("{}: {}", path.display(), e)))?;
+        answers.validate()?;
+        Ok(answers)
+    }
+
+    /// Asks the answers on *output*, read from *input*. An invalid answer is asked again.
+    pub fn ask(input: &mut impl BufRead, output: &mut impl Write) -> Result<Answers, SetupError> {
+        let defaults = Answers::default();
+        let path = |input: &mut _, output: &mut _, prompt: &str, default: &Path| {
+            ask(input, output, prompt, &default.to_string_lossy()).map(PathBuf::from)
+        };
+        let mut answers = Answers {
+            config_path: path(input, output, "Configuration directory", &defaults.config_path)?,
+            debug_path: path(input, output, "Debug directory", &defaults.debug_path)?,
+            utils_path: path(input, output, "Utilities directory", &defaults.utils_path)?,
+            ..defaults
+        };
+        loop {
+            answers.mode = ask(input, output, "Mode (PROTECT, AUDIT or LEARNING)", &answers.mode)?.to_uppercase();
+            answers.kill_policy = ask(input, output, "Kill policy (KILL or SUSPEND)", &answers.kill_policy)?.to_uppercase();
+            match answers.validate() {
+                Ok(()) => break,
+                Err(e) => writeln!(output, "{}", e).map_err(|e| SetupError::Answers(e.to_string()))?,
+            }
+        }
+        let inf = ask(input, output, "Minifilter .inf (empty if already installed)", "")?;
+        answers.driver_inf = (!inf.is_empty()).then(|| PathBuf::from(inf));
+        answers.self_test = yes(&ask(input, output, "Run the self-test (y/n)", "y")?);
+        answers.start = yes(&ask(input, output, "Start the agent service (y/n)", "y")?);
+        Ok(answers)
+    }
+
+    fn validate(&self) -> Result<(), SetupError> {
+        Config::validate_value(Param::Mode, &self.mode).map_err(|e| SetupError::Answers(e.to_string()))?;
+        Config::validate_value(Param::KillPolicy, &self.kill_policy).map_err(|e| SetupError::Answers(e.to_string()))
+    }
+
+    /// The registry values of the answers.
+    fn values(&self) -> Vec<(Param, String)> {
+        vec![
+            (Param::ConfigPath, self.config_path.to_string_lossy().to_string()),
+            (Param::DebugPath, self.debug_path.to_string_lossy().to_string()),
+            (Param::UtilsPath, self.utils_path.to_string_lossy().to_string()),
+            (Param::Mode, self.mode.clone()),
+            (Param::KillPolicy, self.kill_policy.clone()),
+        ]
+    }
+}
+
+/// The line read after *prompt*, or *default* if empty.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn ask(input: &mut impl BufRead, output: &mut impl Write, prompt: &str, default: &str) -> Result<String, SetupError> {
+    let io = |e: std::io::Error| SetupError::Answers(e.to_string());
+    if default.is_empty() {
+        write!(output, "{}: ", prompt).map_err(io)?;
+    } else {
+        write!(output, "{} [{}]: ", prompt, default).map_err(io)?;
+    }
+    output.flush().map_err(io)?;
+    let mut line = String::new();
+    input.read_line(&mut line).map_err(io)?;
+    let line = line.trim();
+    Ok(if line.is_empty() { default.to_string() } else { line.to_string() })
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+fn yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes" | "o" | "oui" | "true" | "1")
+}
+
+/// Runs the steps of the setup, printing their progress.
+#[cfg(windows)]
+pub fn run(answers: &Answers) -> Result<(), SetupError> {
+    println!("[1/6] Configuration in HKLM\\{}", REGISTRY_KEY);
+    write_registry(answers)?;
+    for dir in [answers.config_path.join("threats"), answers.debug_path.clone(), answers.utils_path.clone()] {
+        fs::create_dir_all(&dir).map_err(|e| SetupError::Directory(dir.clone(), e))?;
+    }
+
+    println!("[2/6] Minifilter {}", service_ctl::FILTER_SERVICE_NAME);
+    if service_ctl::query_state(service_ctl::FILTER_SERVICE_NAME).is_err() {
+        let inf = answers
+            .driver_inf
+            .as_ref()
+            .ok_or_else(|| SetupError::Driver(String::from("not installed, and no .inf given")))?;
+        driver_setup::install(inf).map_err(|e| SetupError::Driver(e.to_string()))?;
+    }
+    service_ctl::start_service(service_ctl::FILTER_SERVICE_NAME)?;
+
+    println!("[3/6] Agent service {}", service_ctl::SERVICE_NAME);
+    if service_ctl::query_state(service_ctl::SERVICE_NAME).is_err() {
+        service_ctl::install()?;
+    }
+
+    println!("[4/6] Handshake with the minifilter");
+    if service_ctl::query_state(service_ctl::SERVICE_NAME)? != windows_service::service::ServiceState::Stopped {
+        // the minifilter accepts one connection only
+        service_ctl::stop()?;
+    }
+    let driver = Driver::open_kernel_driver_com()
+        .map_err(|e| SetupError::Driver(format!("cannot connect to the port: {}", e)))?;
+    driver
+        .driver_set_app_pid()
+        .map_err(|e| SetupError::Driver(format!("cannot register the agent: {}", e)))?;
+
+    if answers.self_test {
+        println!("[5/6] Self-test");
+        let elapsed = selftest::run_once(&driver).map_err(SetupError::SelfTest)?;
+        println!("\tpassed in {} ms", elapsed.as_millis());
+    } else {
+        println!("[5/6] Self-test skipped");
+    }
+    // disconnects, for the agent service
+    drop(driver);
+
+    if answers.start {
+        println!("[6/6] Start of the agent service");
+        service_ctl::start()?;
+    } else {
+        println!("[6/6] The agent service is not started");
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_registry(answers: &Answers) -> Result<(), SetupError> {
+    use registry::{Data, Hive, Security};
+
+    let regkey = Hive::LocalMachine
+        .create(REGISTRY_KEY, Security::Write)
+        .map_err(|e| SetupError::Registry(e.to_string()))?;
+    for (param, value) in answers.values() {
+        let data = Data::String(value.parse().map_err(|_| SetupError::Registry(format!("invalid value {}", value)))?);
+        regkey
+            .set_value(Param::convert_to_str(&param), &data)
+            .map_err(|e| SetupError::Registry(e.to_string()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use crate::config::Param;
+    use crate::setup::Answers;
+
+    #[test]
+    fn answers_should_default_and_be_validated() {
+        let mut input = Cursor::new("\nD:\\owly\\debug\n\nfast\nkill\naudit\n\nE:\\filter.inf\nn\n\n");
+        let mut output = Vec::new();
+        let answers = Answers::ask(&mut input, &mut output).unwrap();
+        let defaults = Answers::default();
+        assert_eq!(answers.config_path, defaults.config_path);
+        assert_eq!(answers.debug_path, PathBuf::from("D:\\owly\\debug"));
+        // asked again after the invalid mode
+        assert_eq!((answers.mode.as_str(), answers.kill_policy.as_str()), ("AUDIT", "KILL"));
+        assert_eq!(answers.driver_inf, Some(PathBuf::from("E:\\filter.inf")));
+        assert!(!answers.self_test && answers.start);
+        assert!(String::from_utf8(output).unwrap().contains("MODE"));
+        assert_eq!(answers.values()[4], (Param::KillPolicy, String::from("KILL")));
+
+        let file: Answers = toml::from_str("mode = \"LEARNING\"\nstart = false").unwrap();
+        assert_eq!((file.mode.as_str(), file.kill_policy.as_str(), file.start), ("LEARNING", "KILL", false));
+        assert!(toml::from_str::<Answers>("kill = true").is_err());
+    }
+}
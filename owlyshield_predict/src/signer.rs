@@ -0,0 +1,161 @@
+//! Authenticode signature of executables, used to identify the editor of a process.
+//!
+//! Only the presence of an embedded signature and the subject of the signer certificate are read.
+//! The trust chain itself is verified by [is_trusted] only. The updates of the agent
+//! ([crate::updater]) are also pinned to the [signer_thumbprint] of the running executable.
+
+use std::ffi::c_void;
+use std::path::Path;
+use std::ptr;
+
+use bindings::Windows::Win32::Security::Cryptography::Core::{
+    CertCloseStore, CertFindCertificateInStore, CertFreeCertificateContext, CertGetNameStringW,
+    CryptMsgClose, CryptMsgGetParam, CryptQueryObject, CERT_CONTEXT, CERT_FIND_SUBJECT_CERT, CERT_INFO,
+    CERT_NAME_SIMPLE_DISPLAY_TYPE, CERT_QUERY_CONTENT_FLAG_PKCS7_SIGNED_EMBED,
+    CERT_QUERY_FORMAT_FLAG_BINARY, CERT_QUERY_OBJECT_FILE, CMSG_SIGNER_INFO,
+    CMSG_SIGNER_INFO_PARAM, HCERTSTORE, PKCS_7_ASN_ENCODING, X509_ASN_ENCODING,
+};
+use bindings::Windows::Win32::Foundation::{HWND, PWSTR};
+use bindings::Windows::Win32::Security::WinTrust::{
+    WinVerifyTrust, WINTRUST_DATA, WINTRUST_DATA_0, WINTRUST_FILE_INFO, WTD_CHOICE_FILE,
+    WTD_REVOKE_WHOLECHAIN, WTD_STATEACTION_CLOSE, WTD_STATEACTION_VERIFY, WTD_UI_NONE,
+};
+use sha2::{Digest, Sha256};
+use widestring::U16CString;
+use windows::Guid;
+
+/// WINTRUST_ACTION_GENERIC_VERIFY_V2, the Authenticode policy.
+const GENERIC_VERIFY_V2: Guid = Guid::from_values(
+    0x00aa_c56b,
+    0xcd44,
+    0x11d0,
+    [0x8c, 0xc2, 0x00, 0xc0, 0x4f, 0xc2, 0x95, 0xee],
+);
+
+/// Returns the subject (ex: *Microsoft Corporation*) of the certificate used to sign *path*, if any.
+pub fn signer_subject(path: &Path) -> Option<String> {
+    with_signer_cert(path, |cert| unsafe {
+        let mut name: Vec<u16> = vec![0; 256];
+        let name_len = CertGetNameStringW(
+            cert,
+            CERT_NAME_SIMPLE_DISPLAY_TYPE,
+            0,
+            ptr::null_mut(),
+            name.as_mut_ptr(),
+            name.len() as u32,
+        );
+        if name_len <= 1 {
+            None
+        } else {
+            Some(String::from_utf16_lossy(&name[..(name_len - 1) as usize]))
+        }
+    })
+}
+
+/// Returns the sha256 of the certificate used to sign *path*, as a lowercase hex string, if any.
+/// Unlike the subject, it cannot be shared by a certificate of another issuer.
+pub fn signer_thumbprint(path: &Path) -> Option<String> {
+    with_signer_cert(path, |cert| unsafe {
+        let encoded = std::slice::from_raw_parts((*cert).pbCertEncoded, (*cert).cbCertEncoded as usize);
+        Some(Sha256::digest(encoded).iter().map(|b| format!("{:02x}", b)).collect())
+    })
+}
+
+/// Calls *f* with the certificate of the signer of *path*, if signed.
+fn with_signer_cert<T>(path: &Path, f: impl FnOnce(*const CERT_CONTEXT) -> Option<T>) -> Option<T> {
+    let wpath = U16CString::from_os_str(path.as_os_str()).ok()?;
+    unsafe {
+        let mut store = HCERTSTORE(0);
+        let mut msg: *mut c_void = ptr::null_mut();
+        if !CryptQueryObject(
+            CERT_QUERY_OBJECT_FILE,
+            wpath.as_ptr() as *const c_void,
+            CERT_QUERY_CONTENT_FLAG_PKCS7_SIGNED_EMBED,
+            CERT_QUERY_FORMAT_FLAG_BINARY,
+            0,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut store,
+            &mut msg,
+            ptr::null_mut(),
+        )
+        .as_bool()
+        {
+            return None;
+        }
+
+        let res = signer_cert_from_msg(store, msg).and_then(|cert| {
+            let res = f(cert);
+            CertFreeCertificateContext(cert);
+            res
+        });
+        CryptMsgClose(msg);
+        CertCloseStore(store, 0);
+        res
+    }
+}
+
+unsafe fn signer_cert_from_msg(store: HCERTSTORE, msg: *mut c_void) -> Option<*const CERT_CONTEXT> {
+    let mut len: u32 = 0;
+    if !CryptMsgGetParam(msg, CMSG_SIGNER_INFO_PARAM, 0, ptr::null_mut(), &mut len).as_bool() {
+        return None;
+    }
+    let mut buffer: Vec<u8> = vec![0; len as usize];
+    if !CryptMsgGetParam(
+        msg,
+        CMSG_SIGNER_INFO_PARAM,
+        0,
+        buffer.as_mut_ptr() as *mut c_void,
+        &mut len,
+    )
+    .as_bool()
+    {
+        return None;
+    }
+    let signer_info = &*(buffer.as_ptr() as *const CMSG_SIGNER_INFO);
+    let mut cert_info: CERT_INFO = std::mem::zeroed();
+    cert_info.Issuer = signer_info.Issuer;
+    cert_info.SerialNumber = signer_info.SerialNumber;
+
+    let cert = CertFindCertificateInStore(
+        store,
+        X509_ASN_ENCODING | PKCS_7_ASN_ENCODING,
+        0,
+        CERT_FIND_SUBJECT_CERT,
+        &cert_info as *const CERT_INFO as *const c_void,
+        ptr::null(),
+    );
+    if cert.is_null() {
+        None
+    } else {
+        Some(cert as *const CERT_CONTEXT)
+    }
+}
+
+/// True if the Authenticode signature of *path* is valid and chains to a trusted root, the
+/// certificates not being revoked.
+pub fn is_trusted(path: &Path) -> bool {
+    let mut wpath = match U16CString::from_os_str(path.as_os_str()) {
+        Ok(wpath) => wpath.into_vec_with_nul(),
+        Err(_) => return false,
+    };
+    unsafe {
+        let mut file_info: WINTRUST_FILE_INFO = std::mem::zeroed();
+        file_info.cbStruct = std::mem::size_of::<WINTRUST_FILE_INFO>() as u32;
+        file_info.pcwszFilePath = PWSTR(wpath.as_mut_ptr());
+        let mut data: WINTRUST_DATA = std::mem::zeroed();
+        data.cbStruct = std::mem::size_of::<WINTRUST_DATA>() as u32;
+        data.dwUIChoice = WTD_UI_NONE;
+        data.fdwRevocationChecks = WTD_REVOKE_WHOLECHAIN;
+        data.dwUnionChoice = WTD_CHOICE_FILE;
+        data.Anonymous = WINTRUST_DATA_0 { pFile: &mut file_info };
+        data.dwStateAction = WTD_STATEACTION_VERIFY;
+        let mut action = GENERIC_VERIFY_V2;
+        let res = WinVerifyTrust(HWND(0), &mut action, &mut data as *mut WINTRUST_DATA as *mut c_void);
+        // releases the state data
+        data.dwStateAction = WTD_STATEACTION_CLOSE;
+        WinVerifyTrust(HWND(0), &mut action, &mut data as *mut WINTRUST_DATA as *mut c_void);
+        res == 0
+    }
+}
@@ -0,0 +1,164 @@
+//! Bounded-memory sets for the per-gid state of long running processes (backup agents,
+//! compilers...), which can touch millions of files.
+//!
+//! A [BoundedSet] keeps its elements up to a cap (*HISTORY_MAX_ENTRIES*), then only counts the
+//! new distinct elements with a [HyperLogLog] sketch.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+/// Number of bits of the hash used to select a register: 2^12 registers, 4 KB, ~1.6% error.
+const HLL_PRECISION: u32 = 12;
+
+/// Estimates the number of distinct elements inserted, in constant memory.
+///
+/// The harmonic sum of the registers is maintained on insert, so that [Self::count] is O(1): it
+/// is called for each prediction.
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+    /// Sum of 2^-register, in fixed point (2^64 is 1) to stay exact
+    sum: u128,
+    zeros: usize,
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; 1 << HLL_PRECISION],
+            sum: (1u128 << 64) << HLL_PRECISION,
+            zeros: 1 << HLL_PRECISION,
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, value: &T) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() as u8 + 1;
+        let register = self.registers[index];
+        if rank > register {
+            if register == 0 {
+                self.zeros -= 1;
+            }
+            self.sum = self.sum - (1u128 << (64 - register)) + (1u128 << (64 - rank));
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self.sum as f64 / 2f64.powi(64);
+        let estimate = alpha * m * m / sum;
+        if estimate <= 2.5 * m && self.zeros > 0 {
+            // small range correction (linear counting)
+            (m * (m / self.zeros as f64).ln()).round() as usize
+        } else {
+            estimate.round() as usize
+        }
+    }
+}
+
+/// A set keeping at most *cap* elements. Beyond, [Self::len] is an estimate and the new elements
+/// are not retained.
+#[derive(Debug, Clone)]
+pub struct BoundedSet<T: Hash + Eq> {
+    retained: HashSet<T>,
+    cap: usize,
+    /// Created when the cap is reached, with all the retained elements.
+    sketch: Option<HyperLogLog>,
+}
+
+impl<T: Hash + Eq> BoundedSet<T> {
+    pub fn new(cap: usize) -> BoundedSet<T> {
+        BoundedSet {
+            retained: HashSet::new(),
+            cap,
+            sketch: None,
+        }
+    }
+
+    pub fn insert(&mut self, value: T) {
+        if let Some(sketch) = self.sketch.as_mut() {
+            sketch.insert(&value);
+        } else if self.retained.len() < self.cap || self.retained.contains(&value) {
+            self.retained.insert(value);
+        } else {
+            let mut sketch = HyperLogLog::new();
+            self.retained.iter().for_each(|v| sketch.insert(v));
+            sketch.insert(&value);
+            self.sketch = Some(sketch);
+        }
+    }
+
+    /// Number of distinct elements inserted, estimated once the cap is reached.
+    pub fn len(&self) -> usize {
+        match &self.sketch {
+            Some(sketch) => sketch.count().max(self.retained.len()),
+            None => self.retained.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.retained.is_empty()
+    }
+
+    /// True if some elements were not retained.
+    pub fn is_saturated(&self) -> bool {
+        self.sketch.is_some()
+    }
+
+    /// The first elements inserted, at most *cap*.
+    pub fn retained(&self) -> &HashSet<T> {
+        &self.retained
+    }
+
+    pub fn iter(&self) -> std::collections::hash_set::Iter<'_, T> {
+        self.retained.iter()
+    }
+}
+
+impl<'s, T: Hash + Eq> IntoIterator for &'s BoundedSet<T> {
+    type Item = &'s T;
+    type IntoIter = std::collections::hash_set::Iter<'s, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.retained.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sketch::{BoundedSet, HyperLogLog};
+
+    #[test]
+    fn hyperloglog_should_estimate_distinct_count() {
+        let mut hll = HyperLogLog::new();
+        for i in 0..100_000u32 {
+            hll.insert(&i);
+            hll.insert(&i);
+        }
+        let count = hll.count() as f64;
+        assert!((count - 100_000.0).abs() / 100_000.0 < 0.05);
+    }
+
+    #[test]
+    fn bounded_set_should_stop_retaining_at_cap() {
+        let mut set = BoundedSet::new(100);
+        for i in 0..50 {
+            set.insert(format!("C:\\file_{}.txt", i));
+            set.insert(format!("C:\\file_{}.txt", i));
+        }
+        assert_eq!(set.len(), 50);
+        assert!(!set.is_saturated());
+        for i in 0..1000 {
+            set.insert(format!("C:\\file_{}.txt", i));
+        }
+        assert!(set.is_saturated());
+        assert_eq!(set.retained().len(), 100);
+        assert!((set.len() as i64 - 1000).abs() < 50);
+    }
+}
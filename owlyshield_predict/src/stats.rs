@@ -0,0 +1,201 @@
+//! Statistics of the local event store ([crate::eventstore]), pre-aggregated for the Grafana JSON
+//! datasource (*simpod-json-datasource*). Its URL is *http://127.0.0.1:API_PORT/grafana*, with the
+//! header ```Authorization: Bearer <token>``` of the API. The metrics are:
+//! * *alerts_per_day* and *alerts_per_hour*: a time series per type of alert, in UTC buckets;
+//! * *top_writers*: table of the gids which wrote the most bytes;
+//! * *score_histogram*: table of the process families per tenth of their highest score.
+
+use std::time::{Duration, SystemTime};
+
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::eventstore::EventStore;
+
+pub const METRICS: [&str; 4] = ["alerts_per_day", "alerts_per_hour", "top_writers", "score_histogram"];
+/// Gids of *top_writers*.
+const TOP_WRITERS: usize = 10;
+/// Points of a time series, beyond which the range is rejected.
+const MAX_POINTS: u64 = 10_000;
+
+#[derive(Error, Debug)]
+pub enum StatsError {
+    #[error("{0}")]
+    Request(String),
+    #[error("event store: {0}")]
+    Store(#[from] rusqlite::Error),
+}
+
+/// The body of a query of Grafana, with the fields used.
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    pub range: Range,
+    pub targets: Vec<Target>,
+}
+
+/// RFC 3339 times.
+#[derive(Debug, Deserialize)]
+pub struct Range {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Target {
+    #[serde(default)]
+    pub target: String,
+    #[serde(default)]
+    pub hide: bool,
+}
+
+/// The metrics, for the query editor (the older versions of the datasource list [METRICS]).
+pub fn metrics() -> Value {
+    json!(METRICS.iter().map(|metric| json!({ "label": metric, "value": metric })).collect::<Vec<Value>>())
+}
+
+/// The series and tables of the *targets* of *request*, in their order.
+pub fn query(store: &EventStore, request: &QueryRequest) -> Result<Value, StatsError> {
+    let from = parse_time(&request.range.from)?;
+    let to = parse_time(&request.range.to)?;
+    let mut results = Vec::new();
+    for target in request.targets.iter().filter(|target| !target.hide && !target.target.is_empty()) {
+        match target.target.as_str() {
+            "alerts_per_day" => results.extend(alerts(store, from, to, Duration::from_secs(24 * 3600))?),
+            "alerts_per_hour" => results.extend(alerts(store, from, to, Duration::from_secs(3600))?),
+            "top_writers" => results.push(json!({
+                "type": "table",
+                "columns": [
+                    { "text": "Gid", "type": "number" },
+                    { "text": "Application", "type": "string" },
+                    { "text": "Bytes written", "type": "number" },
+                ],
+                "rows": store
+                    .top_writers(from, to, TOP_WRITERS)?
+                    .into_iter()
+                    .map(|(gid, appname, bytes)| json!([gid, appname, bytes]))
+                    .collect::<Vec<Value>>(),
+            })),
+            "score_histogram" => results.push(json!({
+                "type": "table",
+                "columns": [{ "text": "Score", "type": "string" }, { "text": "Families", "type": "number" }],
+                "rows": store
+                    .score_histogram(from, to)?
+                    .iter()
+                    .enumerate()
+                    .map(|(tenth, count)| json!([format!("{:.1}-{:.1}", tenth as f32 / 10.0, (tenth + 1) as f32 / 10.0), count]))
+                    .collect::<Vec<Value>>(),
+            })),
+            other => return Err(StatsError::Request(format!("Unknown metric {}, expected one of {:?}", other, METRICS))),
+        }
+    }
+    Ok(Value::Array(results))
+}
+
+/// A time series per type of alert, with a point per *bucket*, zero when there is no alert.
+fn alerts(store: &EventStore, from: SystemTime, to: SystemTime, bucket: Duration) -> Result<Vec<Value>, StatsError> {
+    let step = bucket.as_secs();
+    let first = millis(from) / 1000 / step * step;
+    let last = millis(to) / 1000;
+    if last.saturating_sub(first) / step > MAX_POINTS {
+        return Err(StatsError::Request(format!("Range too long for buckets of {}s", step)));
+    }
+    let counts = store.alerts(from, to, bucket)?;
+    let mut kinds: Vec<&str> = counts.iter().map(|(kind, _, _)| kind.as_str()).collect();
+    kinds.sort_unstable();
+    kinds.dedup();
+    Ok(kinds
+        .into_iter()
+        .map(|kind| {
+            let datapoints: Vec<Value> = (first..last)
+                .step_by(step as usize)
+                .map(|start| {
+                    let count = counts
+                        .iter()
+                        .find(|(k, time, _)| k == kind && millis(*time) / 1000 == start)
+                        .map_or(0, |(_, _, count)| *count);
+                    json!([count, start * 1000])
+                })
+                .collect();
+            json!({ "target": kind, "datapoints": datapoints })
+        })
+        .collect())
+}
+
+fn parse_time(time: &str) -> Result<SystemTime, StatsError> {
+    DateTime::parse_from_rfc3339(time)
+        .map(SystemTime::from)
+        .map_err(|e| StatsError::Request(format!("Invalid time {}: {}", time, e)))
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use serde_json::json;
+
+    use crate::eventstore::EventStore;
+    use crate::schema::Envelope;
+    use crate::stats::{query, QueryRequest};
+
+    #[test]
+    fn grafana_queries_should_be_aggregated() {
+        let agent = json!({ "machine_id": "6f1c", "hostname": "srv-files", "os_version": "Windows 10", "domain": null, "agent_version": "1.2.0" });
+        let family = |gid: u64| json!({ "gid": gid, "appname": format!("app{}.exe", gid), "exepath": format!(r"C:\app{}.exe", gid) });
+        let terminated = |gid: u64, score: f32, bytes: u64| -> Envelope {
+            serde_json::from_value(json!({ "schema_version": "1.1", "agent": agent, "event": {
+                "type": "process_terminated", "family": family(gid), "pids_count": 1, "owner": null,
+                "time_started": "2026-10-16T09:00:00Z", "time_exited": "2026-10-16T09:10:00Z", "driver_msg_count": 10,
+                "files_read": 1, "files_written": 1, "files_renamed": 0, "files_deleted": 0, "bytes_read": 10,
+                "bytes_written": bytes, "predictions_count": 2, "max_prediction": score, "is_malicious": false,
+                "process_state": "RUNNING" } }))
+            .unwrap()
+        };
+        let raw_disk: Envelope = serde_json::from_value(json!({ "schema_version": "1.1", "agent": agent, "event": {
+            "type": "raw_disk_write", "time": "2026-10-16T09:12:47Z", "gid": 3, "pid": 4242,
+            "device": r"\Device\Harddisk0\DR0", "mount_point": null, "source": "driver" } }))
+        .unwrap();
+
+        let store = EventStore::open(std::path::Path::new(":memory:")).unwrap();
+        let day = Duration::from_secs(24 * 3600);
+        let today = SystemTime::UNIX_EPOCH + day * 20_000;
+        store.record(&terminated(1, 0.05, 100), today - day / 2).unwrap();
+        store.record(&terminated(2, 0.95, 5_000), today).unwrap();
+        store.record(&terminated(3, 0.12, 700), today).unwrap();
+        store.record(&terminated(4, 0.99, 9_000), today - day).unwrap();
+        store.record(&raw_disk, today - day / 4).unwrap();
+        store.record(&raw_disk, today + Duration::from_secs(60)).unwrap();
+        store.record(&raw_disk, today + Duration::from_secs(120)).unwrap();
+
+        let request: QueryRequest = serde_json::from_value(json!({
+            "range": { "from": "2024-10-03T12:00:00Z", "to": "2024-10-05T00:00:00Z", "raw": { "from": "now-2d", "to": "now" } },
+            "intervalMs": 60000,
+            "targets": [
+                { "refId": "A", "target": "alerts_per_day", "type": "timeseries" },
+                { "refId": "B", "target": "top_writers" },
+                { "refId": "C", "target": "score_histogram" },
+                { "refId": "D", "target": "alerts_per_hour", "hide": true }
+            ]
+        }))
+        .unwrap();
+        let results = query(&store, &request).unwrap();
+        let alerts = &results[0];
+        assert_eq!(alerts["target"], "raw_disk_write");
+        assert_eq!(alerts["datapoints"], json!([[1, 1_727_913_600_000u64], [2, 1_728_000_000_000u64]]));
+        assert_eq!(results[1]["rows"], json!([[2, "app2.exe", 5000], [3, "app3.exe", 700], [1, "app1.exe", 100]]));
+        assert_eq!(results[2]["rows"][1], json!(["0.1-0.2", 1]));
+        assert_eq!(results[2]["rows"][9], json!(["0.9-1.0", 1]));
+        assert_eq!(results.as_array().unwrap().len(), 3);
+
+        let unknown: QueryRequest = serde_json::from_value(json!({
+            "range": { "from": "2024-10-04T12:00:00Z", "to": "2024-10-06T00:00:00Z" }, "targets": [{ "target": "cpu" }]
+        }))
+        .unwrap();
+        assert!(query(&store, &unknown).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Live state of the protection, published by the [crate::pipeline] for the local API
+//! ([crate::api]) and the [crate::heartbeat]: the monitored gids with their scores, the last
+//! alerts, the depth of the queue and the [BackpressureStats], the trace of the followed gid
+//! ([crate::follow]), the antivirus of the machine ([Coexistence]) and the incidents of the alerts
+//! ([Correlator]). The reviews of the alerts go the other way, from the API to the connectors.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::Serialize;
+
+use crate::backpressure::BackpressureStats;
+use crate::connectors::connector::AlertReview;
+use crate::correlation::Correlator;
+use crate::defender::Coexistence;
+use crate::follow::Follow;
+use crate::process::ProcessRecord;
+
+/// Alerts kept in memory. Older ones are only in the threats reports.
+const MAX_ALERTS: usize = 100;
+
+/// A monitored process family, as of the last snapshot.
+#[derive(Debug, Clone, Serialize)]
+pub struct GidStatus {
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: String,
+    pub user: String,
+    pub pids_count: usize,
+    pub state: String,
+    pub driver_msg_count: usize,
+    pub last_prediction: Option<f32>,
+    pub max_prediction: Option<f32>,
+    pub prediction_static: Option<f32>,
+    pub time_started: String,
+    /// For the WSL hosts, see [crate::wsl]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub linux_processes: Vec<String>,
+}
+
+impl GidStatus {
+    pub fn from(proc: &ProcessRecord) -> GidStatus {
+        GidStatus {
+            gid: proc.gid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.to_string_lossy().to_string(),
+            user: proc.user(),
+            pids_count: proc.pids.len(),
+            state: proc.process_state.to_string(),
+            driver_msg_count: proc.driver_msg_count,
+            last_prediction: proc.predictions.get_last_prediction(),
+            max_prediction: proc.predictions.get_max_prediction(),
+            prediction_static: proc.prediction_static,
+            time_started: rfc3339(proc.time_started),
+            linux_processes: proc.wsl.iter().flatten().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+/// A gid found malicious, killed or suspended (or not, if excluded or paused).
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub time: String,
+    pub gid: u64,
+    pub appname: String,
+    pub exepath: String,
+    pub user: String,
+    pub prediction: f32,
+    /// Reason of a detection without the model, see [crate::fastpath]
+    pub fast_path: Option<String>,
+    /// KILLED, SUSPENDED or RUNNING
+    pub state: String,
+    /// Would have been killed, in the *AUDIT* or *LEARNING* mode
+    pub simulated: bool,
+    /// Who acknowledged the alert, see [crate::connectors::slack]
+    pub acknowledged_by: Option<String>,
+    /// Who marked the alert as a false positive
+    pub false_positive_by: Option<String>,
+}
+
+/// Shared by the pipeline, which writes, and the API, which reads.
+#[derive(Debug)]
+pub struct AgentStatus {
+    pub time_started: SystemTime,
+    gids: Mutex<Vec<GidStatus>>,
+    alerts: Mutex<VecDeque<Alert>>,
+    reviews: Mutex<Vec<AlertReview>>,
+    queued_msgs: AtomicUsize,
+    backpressure: Mutex<BackpressureStats>,
+    pub follow: Follow,
+    pub av: Coexistence,
+    pub incidents: Correlator,
+}
+
+impl AgentStatus {
+    pub fn new() -> AgentStatus {
+        AgentStatus {
+            time_started: SystemTime::now(),
+            gids: Mutex::new(Vec::new()),
+            alerts: Mutex::new(VecDeque::new()),
+            reviews: Mutex::new(Vec::new()),
+            queued_msgs: AtomicUsize::new(0),
+            backpressure: Mutex::new(BackpressureStats::default()),
+            follow: Follow::new(),
+            av: Coexistence::new(),
+            incidents: Correlator::new(),
+        }
+    }
+
+    /// Replaces the monitored gids. The gids being processed by a worker at that time are missing
+    /// until the next snapshot.
+    pub fn set_gids(&self, gids: Vec<GidStatus>) {
+        *self.gids.lock().unwrap() = gids;
+    }
+
+    pub fn gids(&self) -> Vec<GidStatus> {
+        self.gids.lock().unwrap().clone()
+    }
+
+    /// Driver messages fetched and not processed yet.
+    pub fn set_queued_msgs(&self, count: usize) {
+        self.queued_msgs.store(count, Ordering::Relaxed);
+    }
+
+    pub fn queued_msgs(&self) -> usize {
+        self.queued_msgs.load(Ordering::Relaxed)
+    }
+
+    pub fn set_backpressure(&self, stats: BackpressureStats) {
+        *self.backpressure.lock().unwrap() = stats;
+    }
+
+    pub fn backpressure(&self) -> BackpressureStats {
+        *self.backpressure.lock().unwrap()
+    }
+
+    pub fn push_alert(&self, proc: &ProcessRecord, prediction: f32) {
+        self.follow.on_alert(proc.gid, prediction, &proc.process_state.to_string(), proc.would_kill);
+        let mut alerts = self.alerts.lock().unwrap();
+        if alerts.len() >= MAX_ALERTS {
+            alerts.pop_front();
+        }
+        alerts.push_back(Alert {
+            time: rfc3339(SystemTime::now()),
+            gid: proc.gid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.to_string_lossy().to_string(),
+            user: proc.user(),
+            prediction,
+            fast_path: proc.fast_path.verdict().map(|v| v.to_string()),
+            state: proc.process_state.to_string(),
+            simulated: proc.would_kill,
+            acknowledged_by: None,
+            false_positive_by: None,
+        });
+    }
+
+    /// Marks the alerts of *gid* as acknowledged, or as false positives, by *user*, and queues the
+    /// [AlertReview] for the connectors. Returns the most recent one, None if *gid* has no alert.
+    pub fn review(&self, gid: u64, user: &str, false_positive: bool) -> Option<Alert> {
+        let mut alerts = self.alerts.lock().unwrap();
+        for alert in alerts.iter_mut().filter(|a| a.gid == gid) {
+            if false_positive {
+                alert.false_positive_by = Some(user.to_string());
+            } else {
+                alert.acknowledged_by = Some(user.to_string());
+            }
+        }
+        let alert = alerts.iter().rev().find(|a| a.gid == gid).cloned()?;
+        self.reviews.lock().unwrap().push(AlertReview {
+            time: SystemTime::now(),
+            gid,
+            appname: alert.appname.clone(),
+            exepath: alert.exepath.clone(),
+            user: user.to_string(),
+            false_positive,
+            incident: self.incidents.id_of(gid),
+        });
+        Some(alert)
+    }
+
+    /// The reviews not sent to the connectors yet, by the pipeline.
+    pub fn take_reviews(&self) -> Vec<AlertReview> {
+        std::mem::take(&mut *self.reviews.lock().unwrap())
+    }
+
+    /// The last alerts, most recent first.
+    pub fn alerts(&self) -> Vec<Alert> {
+        self.alerts.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+pub fn rfc3339(time: SystemTime) -> String {
+    DateTime::<Local>::from(time).to_rfc3339_opts(SecondsFormat::Secs, false)
+}
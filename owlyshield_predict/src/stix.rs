@@ -0,0 +1,296 @@
+//! Export of the incidents as STIX 2.1 bundles, for the threat intelligence platforms.
+//!
+//! With *STIX_EXPORT*, each incident is written as a bundle next to its reports, in
+//! *ConfigPath\threats*:
+//! * an *identity* of the machine, author of the other objects;
+//! * a *malware* instance (not a family), with the *file* of its executable as sample;
+//! * an *indicator* on the sha256 of the executable (on its name if it cannot be read), which
+//!   *indicates* the malware, with the prediction as confidence;
+//! * an *observed-data* of the files created and updated by the process family, at most
+//!   [MAX_FILES];
+//! * if a ransom note was dropped, its language, keywords and family hints as the
+//!   *x_owlyshield_ransom_note* of the malware, and a *malware* family per hint, of which the
+//!   instance is a *variant-of* (hints, hence a low confidence).
+//!
+//! If *MISP_URL* is set (not *NONE*), the bundle is also pushed to that MISP instance
+//! (*/events/upload_stix/2*), authenticated by the key in *ConfigPath\misp_key*, in a background
+//! thread.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use curl::easy::{Easy, List};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::config::{Config, Param};
+use crate::identity::AgentIdentity;
+use crate::notelang::NoteAnalysis;
+use crate::process::ProcessRecord;
+use crate::utils::sha256_file;
+
+/// Files of the observed-data, at most.
+pub const MAX_FILES: usize = 100;
+/// Name of the file of the MISP key, in *ConfigPath*.
+pub const KEY_FILE: &str = "misp_key";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Of the *variant-of* relationships given by the family hints of the note.
+const FAMILY_HINT_CONFIDENCE: u8 = 30;
+
+#[derive(Debug, Error)]
+pub enum StixError {
+    #[error("Cannot read the MISP key: {0}")]
+    Key(std::io::Error),
+    #[error("Cannot reach MISP: {0}")]
+    Http(#[from] curl::Error),
+    #[error("MISP answered {0}: {1}")]
+    Status(u32, String),
+}
+
+/// What a bundle tells of an incident.
+#[derive(Debug, Clone)]
+pub struct StixIncident {
+    pub hostname: String,
+    pub appname: String,
+    pub exepath: String,
+    pub sha256: Option<String>,
+    pub time_started: SystemTime,
+    pub time_detected: SystemTime,
+    pub prediction: f32,
+    pub ops_written: u64,
+    /// Created and updated
+    pub files: Vec<String>,
+    /// Of the ransom note, if any
+    pub note: Option<NoteAnalysis>,
+}
+
+impl StixIncident {
+    pub fn from(identity: &AgentIdentity, proc: &ProcessRecord, prediction: f32) -> StixIncident {
+        let mut files: Vec<String> = proc.fpaths_created.iter().chain(proc.fpaths_updated.iter()).map(|p| p.to_string()).collect();
+        files.sort();
+        files.dedup();
+        files.truncate(MAX_FILES);
+        StixIncident {
+            hostname: identity.hostname.clone(),
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.to_string_lossy().to_string(),
+            sha256: sha256_file(&proc.exepath).ok(),
+            time_started: proc.time_started,
+            time_detected: proc.time_killed.unwrap_or_else(SystemTime::now),
+            prediction,
+            ops_written: proc.ops_written,
+            files,
+            note: proc.ransom_note.note().map(|note| note.analysis.clone()),
+        }
+    }
+}
+
+/// The STIX 2.1 bundle of *incident*.
+pub fn bundle(incident: &StixIncident) -> Value {
+    let id = |kind: &str| format!("{}--{}", kind, Uuid::new_v4());
+    let created = timestamp(incident.time_detected);
+    let identity_id = id("identity");
+    let exe_id = id("file");
+    let malware_id = id("malware");
+    let indicator_id = id("indicator");
+
+    let mut exe = json!({"type": "file", "spec_version": "2.1", "id": exe_id, "name": incident.appname});
+    let pattern = match &incident.sha256 {
+        Some(sha256) => {
+            exe["hashes"] = json!({ "SHA-256": sha256 });
+            format!("[file:hashes.'SHA-256' = '{}']", sha256)
+        }
+        None => format!("[file:name = '{}']", escape(&incident.appname)),
+    };
+    let files: Vec<Value> = incident
+        .files
+        .iter()
+        .map(|path| {
+            let name = path.rsplit(['\\', '/']).next().unwrap_or(path);
+            json!({"type": "file", "spec_version": "2.1", "id": id("file"), "name": name, "x_owlyshield_path": path})
+        })
+        .collect();
+    let mut observed_refs = vec![Value::from(exe_id.clone())];
+    observed_refs.extend(files.iter().map(|file| file["id"].clone()));
+
+    let mut malware = json!({
+        "type": "malware", "spec_version": "2.1", "id": malware_id,
+        "created": created, "modified": created, "created_by_ref": identity_id,
+        "name": incident.appname, "is_family": false, "malware_types": ["ransomware"],
+        "first_seen": timestamp(incident.time_started), "sample_refs": [exe_id],
+        "description": format!("Ransomware behaviour of {} on {}", incident.exepath, incident.hostname),
+    });
+    let mut families = Vec::new();
+    if let Some(note) = &incident.note {
+        malware["x_owlyshield_ransom_note"] = json!(note);
+        for hint in &note.family_hints {
+            let family_id = id("malware");
+            families.push(json!({
+                "type": "malware", "spec_version": "2.1", "id": family_id,
+                "created": created, "modified": created, "created_by_ref": identity_id,
+                "name": hint.family, "is_family": true, "malware_types": ["ransomware"],
+            }));
+            families.push(json!({
+                "type": "relationship", "spec_version": "2.1", "id": id("relationship"),
+                "created": created, "modified": created, "created_by_ref": identity_id,
+                "relationship_type": "variant-of", "source_ref": malware_id, "target_ref": family_id,
+                "confidence": FAMILY_HINT_CONFIDENCE,
+                "description": format!("The ransom note contains \"{}\"", hint.phrase),
+            }));
+        }
+    }
+
+    let mut objects = vec![
+        json!({
+            "type": "identity", "spec_version": "2.1", "id": identity_id,
+            "created": created, "modified": created,
+            "name": format!("Owlyshield on {}", incident.hostname), "identity_class": "system",
+        }),
+        exe,
+        malware,
+        json!({
+            "type": "indicator", "spec_version": "2.1", "id": indicator_id,
+            "created": created, "modified": created, "created_by_ref": identity_id,
+            "name": format!("Executable of {}", incident.appname), "indicator_types": ["malicious-activity"],
+            "pattern": pattern, "pattern_type": "stix", "valid_from": created,
+            "confidence": (incident.prediction.clamp(0.0, 1.0) * 100.0).round() as u8,
+        }),
+        json!({
+            "type": "relationship", "spec_version": "2.1", "id": id("relationship"),
+            "created": created, "modified": created, "created_by_ref": identity_id,
+            "relationship_type": "indicates", "source_ref": indicator_id, "target_ref": malware_id,
+        }),
+        json!({
+            "type": "observed-data", "spec_version": "2.1", "id": id("observed-data"),
+            "created": created, "modified": created, "created_by_ref": identity_id,
+            "first_observed": timestamp(incident.time_started), "last_observed": created,
+            "number_observed": incident.ops_written.max(1), "object_refs": observed_refs,
+        }),
+    ];
+    objects.extend(files);
+    objects.extend(families);
+    json!({"type": "bundle", "id": id("bundle"), "objects": objects})
+}
+
+/// Writes the bundle of *proc* into *ConfigPath\threats*, and pushes it to MISP if configured.
+pub fn export(config: &Config, proc: &ProcessRecord, prediction: f32, now: &str) -> Result<(), std::io::Error> {
+    let incident = StixIncident::from(&AgentIdentity::load(config), proc, prediction);
+    let bundle = bundle(&incident);
+    let path = config.get_path(Param::ConfigPath).join("threats").join(format!(
+        "{}_{}_stix_{}.json",
+        &proc.appname.replace('.', "_"),
+        now,
+        &proc.gid,
+    ));
+    fs::write(&path, serde_json::to_string_pretty(&bundle)?)?;
+    info!("STIX bundle written to {}", path.display());
+
+    let url = config.get_str(Param::MispUrl).trim().trim_end_matches('/').to_string();
+    if !url.is_empty() && !url.eq_ignore_ascii_case("NONE") {
+        let key_path = config.get_path(Param::ConfigPath).join(KEY_FILE);
+        thread::spawn(move || match push_to_misp(&url, &key_path, &bundle) {
+            Ok(()) => info!("STIX bundle pushed to MISP"),
+            Err(e) => error!("{}", e),
+        });
+    }
+    Ok(())
+}
+
+/// Uploads *bundle* to the MISP instance *url*, which creates an event of it.
+fn push_to_misp(url: &str, key_path: &Path, bundle: &Value) -> Result<(), StixError> {
+    let key = fs::read_to_string(key_path).map_err(StixError::Key)?;
+    let body = bundle.to_string();
+    let mut data = body.as_bytes();
+    let mut response = Vec::new();
+    let mut headers = List::new();
+    headers.append(&format!("Authorization: {}", key.trim()))?;
+    headers.append("Accept: application/json")?;
+    headers.append("Content-Type: application/json")?;
+    let mut easy = Easy::new();
+    easy.url(&format!("{}/events/upload_stix/2", url))?;
+    easy.post(true)?;
+    easy.post_field_size(body.len() as u64)?;
+    easy.http_headers(headers)?;
+    easy.timeout(REQUEST_TIMEOUT)?;
+    {
+        let mut transfer = easy.transfer();
+        transfer.read_function(|buf| Ok(data.read(buf).unwrap_or(0)))?;
+        transfer.write_function(|chunk| {
+            response.extend_from_slice(chunk);
+            Ok(chunk.len())
+        })?;
+        transfer.perform()?;
+    }
+    match easy.response_code()? {
+        200..=299 => Ok(()),
+        code => Err(StixError::Status(code, String::from_utf8_lossy(&response).chars().take(200).collect())),
+    }
+}
+
+/// UTC, as required by STIX.
+fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// A string literal of a STIX pattern.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::notelang::NoteAnalysis;
+    use crate::stix::{bundle, StixIncident};
+
+    #[test]
+    fn incident_should_be_a_stix_bundle() {
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+        let mut incident = StixIncident {
+            hostname: String::from("srv-files"),
+            appname: String::from("locker.exe"),
+            exepath: String::from(r"C:\Users\bob\locker.exe"),
+            sha256: Some("ab".repeat(32)),
+            time_started: started,
+            time_detected: started + Duration::from_secs(30),
+            prediction: 0.974,
+            ops_written: 1200,
+            files: vec![String::from(r"C:\Users\bob\Documents\a.docx.locked"), String::from("/data/b.xlsx")],
+            note: None,
+        };
+        let bundle = bundle(&incident);
+        assert_eq!(bundle["type"], "bundle");
+        let objects = bundle["objects"].as_array().unwrap();
+        assert_eq!(objects.len(), 8);
+        let of = |kind: &str| objects.iter().find(|o| o["type"] == kind).unwrap();
+        let indicator = of("indicator");
+        assert_eq!(indicator["pattern"], format!("[file:hashes.'SHA-256' = '{}']", "ab".repeat(32)));
+        assert_eq!(indicator["confidence"], 97);
+        assert_eq!(indicator["valid_from"], "2022-04-15T05:20:30.000Z");
+        let relationship = of("relationship");
+        assert_eq!((&relationship["source_ref"], &relationship["target_ref"]), (&indicator["id"], &of("malware")["id"]));
+        let observed = of("observed-data");
+        assert_eq!(observed["object_refs"].as_array().unwrap().len(), 3);
+        assert_eq!(objects[6]["name"], "a.docx.locked");
+        assert_eq!(objects[7]["name"], "b.xlsx");
+
+        incident.sha256 = None;
+        incident.appname = String::from("it's.exe");
+        assert_eq!(super::bundle(&incident)["objects"][3]["pattern"], r"[file:name = 'it\'s.exe']");
+
+        incident.note = Some(NoteAnalysis::of("Your data are stolen and encrypted. Pay in bitcoin to get the decryptor."));
+        let bundle = super::bundle(&incident);
+        let objects = bundle["objects"].as_array().unwrap();
+        assert_eq!(objects.len(), 10);
+        assert_eq!(objects[2]["x_owlyshield_ransom_note"]["language"]["code"], "en");
+        assert_eq!((&objects[8]["name"], &objects[8]["is_family"]), (&"Black Basta".into(), &true.into()));
+        assert_eq!((&objects[9]["source_ref"], &objects[9]["target_ref"]), (&objects[2]["id"], &objects[8]["id"]));
+    }
+}
@@ -0,0 +1,321 @@
+//! Sysmon events as auxiliary features, for the sites which already deploy Sysmon (Windows only).
+//!
+//! With *SYSMON*, the agent subscribes to the *Microsoft-Windows-Sysmon/Operational* event log
+//! (*EvtSubscribe*). The process creations (event 1), network connections (3), file creations
+//! (11) and registry values set (13) are queued by the subscription callback ([SysmonFeed]), then
+//! attributed by the fetch stage of the [crate::pipeline] to the gids of their pids: the creator
+//! for a process creation. Each gid counts them in its [SysmonActivity], which gives the features
+//! *sysmon_processes_created* and *sysmon_remote_hosts*, and the command lines and hosts of the
+//! incident reports. They are also checked for the tasks and services created by the gid, see
+//! [crate::autostart].
+//!
+//! The events of a gid being processed by a worker when they are attributed are lost.
+
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+#[cfg(windows)]
+use tracing::{info, warn};
+
+use crate::config::Config;
+#[cfg(windows)]
+use crate::config::Param;
+use crate::process::procs::Procs;
+
+pub static CHANNEL: &str = "Microsoft-Windows-Sysmon/Operational";
+/// Events waiting to be attributed, beyond which they are dropped.
+const MAX_PENDING: usize = 10_000;
+/// Command lines kept per gid, the first ones.
+const MAX_COMMAND_LINES: usize = 16;
+/// Remote hosts kept per gid.
+const MAX_REMOTE_HOSTS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SysmonEventKind {
+    ProcessCreate { image: String, command_line: String },
+    NetworkConnect { destination: String },
+    FileCreate { target: String },
+    RegistrySet { target: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysmonEvent {
+    /// Of the creator, for a process creation
+    pub pid: u32,
+    pub kind: SysmonEventKind,
+}
+
+/// What Sysmon saw of a gid.
+#[derive(Debug, Clone, Default)]
+pub struct SysmonActivity {
+    pub processes_created: usize,
+    pub command_lines: Vec<String>,
+    pub connections: usize,
+    /// Destination ips
+    pub remote_hosts: BTreeSet<String>,
+    pub files_created: usize,
+}
+
+impl SysmonActivity {
+    pub fn on_event(&mut self, event: &SysmonEvent) {
+        match &event.kind {
+            SysmonEventKind::ProcessCreate { command_line, .. } => {
+                self.processes_created += 1;
+                if self.command_lines.len() < MAX_COMMAND_LINES {
+                    self.command_lines.push(command_line.clone());
+                }
+            }
+            SysmonEventKind::NetworkConnect { destination } => {
+                self.connections += 1;
+                if self.remote_hosts.len() < MAX_REMOTE_HOSTS {
+                    self.remote_hosts.insert(destination.clone());
+                }
+            }
+            SysmonEventKind::FileCreate { .. } => self.files_created += 1,
+            SysmonEventKind::RegistrySet { .. } => {}
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processes_created == 0 && self.connections == 0 && self.files_created == 0
+    }
+}
+
+/// Events queued by the subscription.
+#[derive(Debug, Default)]
+pub struct SysmonFeed {
+    pending: Mutex<Vec<SysmonEvent>>,
+}
+
+impl SysmonFeed {
+    pub fn push(&self, event: SysmonEvent) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() < MAX_PENDING {
+            pending.push(event);
+        }
+    }
+
+    pub fn take(&self) -> Vec<SysmonEvent> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+/// The subscription to [CHANNEL], closed on drop.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct Sysmon {
+    /// Boxed, for the callback
+    feed: Box<SysmonFeed>,
+    subscription: isize,
+}
+
+impl Sysmon {
+    /// None without *SYSMON*, or if Sysmon is not installed.
+    #[cfg(windows)]
+    pub fn from(config: &Config) -> Option<Sysmon> {
+        if !config.get_bool(Param::Sysmon) {
+            return None;
+        }
+        let feed = Box::new(SysmonFeed::default());
+        match subscription::subscribe(&feed) {
+            Ok(subscription) => {
+                info!("Subscribed to the Sysmon events");
+                Some(Sysmon { feed, subscription })
+            }
+            Err(e) => {
+                warn!("Cannot subscribe to {}, is Sysmon installed? {}", CHANNEL, e);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(windows))]
+    pub fn from(_config: &Config) -> Option<Sysmon> {
+        None
+    }
+
+    /// Attributes the events queued to the gids of *procs*.
+    pub fn attribute(&self, procs: &mut Procs) {
+        let events = self.feed.take();
+        if events.is_empty() {
+            return;
+        }
+        let gids = procs.gids_by_pid();
+        for event in &events {
+            let index = gids.get(&event.pid).and_then(|gid| procs.get_by_gid_index(*gid));
+            if let Some(index) = index {
+                procs.procs[index].sysmon.on_event(event);
+                procs.procs[index].autostart.on_sysmon(event);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Sysmon {
+    fn drop(&mut self) {
+        // waits for the callbacks in progress
+        subscription::close(self.subscription);
+    }
+}
+
+#[cfg(windows)]
+mod subscription {
+    use std::ffi::c_void;
+    use std::ptr;
+
+    use bindings::Windows::Win32::System::EventLog::{
+        EvtClose, EvtRender, EvtSubscribe, EVT_SUBSCRIBE_NOTIFY_ACTION, EvtRenderEventXml, EvtSubscribeActionDeliver,
+        EvtSubscribeToFutureEvents,
+    };
+
+    use crate::sysmon::{parse_event, SysmonFeed, CHANNEL};
+
+    /// Events 1, 3, 11 and 13.
+    const QUERY: &str = "*[System[(EventID=1 or EventID=3 or EventID=11 or EventID=13)]]";
+
+    pub fn subscribe(feed: &SysmonFeed) -> Result<isize, windows::Error> {
+        let handle = unsafe {
+            EvtSubscribe(
+                0,
+                None,
+                CHANNEL,
+                QUERY,
+                0,
+                feed as *const SysmonFeed as *mut c_void,
+                Some(on_event),
+                EvtSubscribeToFutureEvents.0 as u32,
+            )
+        };
+        if handle == 0 {
+            Err(windows::Error::from_win32())
+        } else {
+            Ok(handle)
+        }
+    }
+
+    pub fn close(subscription: isize) {
+        unsafe {
+            EvtClose(subscription);
+        }
+    }
+
+    extern "system" fn on_event(action: EVT_SUBSCRIBE_NOTIFY_ACTION, context: *mut c_void, event: isize) -> u32 {
+        if action == EvtSubscribeActionDeliver {
+            let feed = unsafe { &*(context as *const SysmonFeed) };
+            if let Some(event) = render(event).as_deref().and_then(parse_event) {
+                feed.push(event);
+            }
+        }
+        0
+    }
+
+    /// The XML of *event*.
+    fn render(event: isize) -> Option<String> {
+        let (mut used, mut count) = (0u32, 0u32);
+        unsafe {
+            // the first call gives the size
+            EvtRender(0, event, EvtRenderEventXml.0 as u32, 0, ptr::null_mut(), &mut used, &mut count);
+            let mut buffer = vec![0u16; (used as usize + 1) / 2];
+            EvtRender(
+                0,
+                event,
+                EvtRenderEventXml.0 as u32,
+                (buffer.len() * 2) as u32,
+                buffer.as_mut_ptr() as *mut c_void,
+                &mut used,
+                &mut count,
+            )
+            .ok()
+            .ok()?;
+            Some(String::from_utf16_lossy(&buffer).trim_end_matches('\0').to_string())
+        }
+    }
+}
+
+/// The event of the XML rendering of a Sysmon event, None for the other events.
+pub fn parse_event(xml: &str) -> Option<SysmonEvent> {
+    let event_id: u32 = between(xml, "<EventID>", "</EventID>")?.trim().parse().ok()?;
+    let pid = |name: &str| data(xml, name).and_then(|pid| pid.trim().parse().ok());
+    match event_id {
+        1 => Some(SysmonEvent {
+            pid: pid("ParentProcessId")?,
+            kind: SysmonEventKind::ProcessCreate {
+                image: data(xml, "Image")?,
+                command_line: data(xml, "CommandLine").unwrap_or_default(),
+            },
+        }),
+        3 => Some(SysmonEvent {
+            pid: pid("ProcessId")?,
+            kind: SysmonEventKind::NetworkConnect {
+                destination: data(xml, "DestinationIp")?,
+            },
+        }),
+        11 => Some(SysmonEvent {
+            pid: pid("ProcessId")?,
+            kind: SysmonEventKind::FileCreate {
+                target: data(xml, "TargetFilename")?,
+            },
+        }),
+        13 => Some(SysmonEvent {
+            pid: pid("ProcessId")?,
+            kind: SysmonEventKind::RegistrySet {
+                target: data(xml, "TargetObject")?,
+            },
+        }),
+        _ => None,
+    }
+}
+
+/// The value of the *Data* element *name*, unescaped.
+fn data(xml: &str, name: &str) -> Option<String> {
+    let value = between(xml, &format!("<Data Name='{}'>", name), "</Data>")
+        .or_else(|| between(xml, &format!("<Data Name=\"{}\">", name), "</Data>"))?;
+    Some(
+        value
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+fn between<'a>(xml: &'a str, start: &str, end: &str) -> Option<&'a str> {
+    let from = xml.find(start)? + start.len();
+    let len = xml[from..].find(end)?;
+    Some(&xml[from..from + len])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sysmon::{parse_event, SysmonActivity, SysmonEventKind};
+
+    #[test]
+    fn sysmon_events_should_be_parsed_and_counted() {
+        let process_create = "<Event><System><Provider Name='Microsoft-Windows-Sysmon'/><EventID>1</EventID></System><EventData>\
+            <Data Name='ProcessId'>5120</Data><Data Name='Image'>C:\\Windows\\System32\\vssadmin.exe</Data>\
+            <Data Name='CommandLine'>vssadmin delete shadows /all /quiet &amp;&amp; exit</Data>\
+            <Data Name='ParentProcessId'>4242</Data></EventData></Event>";
+        let event = parse_event(process_create).unwrap();
+        assert_eq!(event.pid, 4242);
+        assert_eq!(
+            event.kind,
+            SysmonEventKind::ProcessCreate {
+                image: String::from("C:\\Windows\\System32\\vssadmin.exe"),
+                command_line: String::from("vssadmin delete shadows /all /quiet && exit"),
+            }
+        );
+        let connect = "<Event><System><EventID>3</EventID></System><EventData><Data Name=\"ProcessId\">4242</Data>\
+            <Data Name=\"DestinationIp\">203.0.113.7</Data></EventData></Event>";
+        let registry = "<Event><System><EventID>13</EventID></System><EventData><Data Name='ProcessId'>4242</Data></EventData></Event>";
+        assert!(parse_event(registry).is_none());
+
+        let mut activity = SysmonActivity::default();
+        assert!(activity.is_empty());
+        activity.on_event(&event);
+        for _ in 0..2 {
+            activity.on_event(&parse_event(connect).unwrap());
+        }
+        assert_eq!((activity.processes_created, activity.connections, activity.remote_hosts.len()), (1, 2, 1));
+    }
+}
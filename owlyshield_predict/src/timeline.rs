@@ -0,0 +1,206 @@
+//! Forensic timeline export of the driver messages recorded with ```--features record```.
+//!
+//! The history of a gid is converted into a super-timeline that can be merged with other artifacts
+//! by DFIR teams:
+//! * [TimelineFormat::L2tCsv] is the *log2timeline* csv format, read by Plaso and Timesketch,
+//! * [TimelineFormat::JsonL] is one json object per line, with the fields required by the Timesketch
+//! importer (*message*, *datetime*, *timestamp_desc*).
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::csvwriter::IrpRecordsReader;
+use crate::driver_com::shared_def::{FileChangeInfo, IOMessage};
+use crate::driver_com::IrpMajorOp;
+
+/// Header of the l2tcsv format (17 columns).
+static L2TCSV_HEADER: &str =
+    "date,time,timezone,MACB,source,sourcetype,type,user,host,short,desc,version,filename,inode,notes,format,extra";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TimelineFormat {
+    L2tCsv,
+    JsonL,
+}
+
+impl FromStr for TimelineFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "l2tcsv" | "csv" => Ok(TimelineFormat::L2tCsv),
+            "jsonl" | "json" => Ok(TimelineFormat::JsonL),
+            _ => Err(format!("Unknown timeline format: {}", s)),
+        }
+    }
+}
+
+/// One event of the timeline, built from an [IOMessage].
+#[derive(Debug, Serialize)]
+pub struct TimelineEntry {
+    /// Human readable description of the event (Timesketch *message*)
+    pub message: String,
+    /// ISO 8601 date (Timesketch *datetime*)
+    pub datetime: String,
+    /// Microseconds since epoch (Timesketch *timestamp*)
+    pub timestamp: i64,
+    /// Meaning of the timestamp (Timesketch *timestamp_desc*)
+    pub timestamp_desc: String,
+    /// Modified, Accessed, Changed, Born flags
+    pub macb: String,
+    pub filename: String,
+    /// Volume serial and file id, as hex
+    pub inode: String,
+    pub gid: u64,
+    pub pid: u32,
+    pub exepath: String,
+    pub bytes: u64,
+    pub entropy: f64,
+    pub hostname: String,
+    #[serde(skip)]
+    time: DateTime<Utc>,
+}
+
+impl TimelineEntry {
+    /// *fallback_time* is used for records made before [crate::driver_com::shared_def::RuntimeFeatures::time]
+    /// was added.
+    pub fn from(iomsg: &IOMessage, hostname: &str, fallback_time: SystemTime) -> TimelineEntry {
+        let time: DateTime<Utc> = iomsg.runtime_features.time.unwrap_or(fallback_time).into();
+        let (macb, action) = Self::describe(iomsg);
+        let exepath = iomsg.runtime_features.exepath.to_string_lossy().to_string();
+        let appname = iomsg
+            .runtime_features
+            .exepath
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or(String::from("DEFAULT"));
+        TimelineEntry {
+            message: format!("{} (pid {}, gid {}) {} {}", appname, iomsg.pid, iomsg.gid, action, iomsg.filepathstr),
+            datetime: time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            timestamp: time.timestamp() * 1_000_000 + time.timestamp_subsec_micros() as i64,
+            timestamp_desc: String::from("Owlyshield File Operation"),
+            macb: String::from(macb),
+            filename: iomsg.filepathstr.clone(),
+            inode: format!("{:X}-{}", iomsg.file_id_vsn, crate::to_hex_string(iomsg.file_id_id.to_vec()).replace(" ", "")),
+            gid: iomsg.gid,
+            pid: iomsg.pid,
+            exepath,
+            bytes: iomsg.mem_sized_used,
+            entropy: iomsg.entropy,
+            hostname: String::from(hostname),
+            time,
+        }
+    }
+
+    /// Returns the MACB flags and a short description of the i/o operation.
+    fn describe(iomsg: &IOMessage) -> (&'static str, &'static str) {
+        let file_change: Option<FileChangeInfo> = num::FromPrimitive::from_u8(iomsg.file_change);
+        match IrpMajorOp::from_byte(iomsg.irp_op) {
+            IrpMajorOp::IrpRead => (".A..", "read"),
+            IrpMajorOp::IrpWrite => match file_change {
+                Some(FileChangeInfo::FileChangeRawDiskWrite) => ("M...", "wrote to raw disk"),
+                _ => ("M...", "wrote"),
+            },
+            IrpMajorOp::IrpSetInfo => match file_change {
+                Some(FileChangeInfo::FileChangeDeleteFile) => ("..C.", "deleted"),
+                Some(FileChangeInfo::FileChangeRenameFile) => ("..C.", "renamed"),
+                Some(FileChangeInfo::FileChangeExtensionChanged) => ("..C.", "changed extension of"),
+                _ => ("..C.", "set info of"),
+            },
+            IrpMajorOp::IrpCreate => match file_change {
+                Some(FileChangeInfo::FileChangeNewFile) => ("...B", "created"),
+                Some(FileChangeInfo::FileChangeOverwriteFile) => ("M..B", "overwrote"),
+                Some(FileChangeInfo::FileChangeDeleteFile) => ("..C.", "deleted"),
+                Some(FileChangeInfo::FileOpenDirectory) => (".A..", "opened directory"),
+                _ => (".A..", "opened"),
+            },
+            _ => match file_change {
+                Some(FileChangeInfo::FileChangeRawDiskWrite) => ("....", "opened for writing raw disk"),
+                _ => ("....", "accessed"),
+            },
+        }
+    }
+
+    pub fn to_l2tcsv(&self) -> String {
+        let extra = format!(
+            "gid: {} pid: {} bytes: {} entropy: {:.4} exepath: {}",
+            self.gid, self.pid, self.bytes, self.entropy, self.exepath
+        );
+        let cols = vec![
+            self.time.format("%m/%d/%Y").to_string(),
+            self.time.format("%H:%M:%S").to_string(),
+            String::from("UTC"),
+            self.macb.clone(),
+            String::from("OWLYSHIELD"),
+            String::from("Owlyshield minifilter"),
+            self.timestamp_desc.clone(),
+            String::from("-"),
+            self.hostname.clone(),
+            self.message.clone(),
+            self.message.clone(),
+            String::from("2"),
+            self.filename.clone(),
+            self.inode.clone(),
+            String::from("-"),
+            String::from("owlyshield"),
+            extra,
+        ];
+        cols.iter()
+            .map(|c| Self::escape_csv(c))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    fn escape_csv(col: &str) -> String {
+        if col.contains(',') || col.contains('"') || col.contains('\n') {
+            format!("\"{}\"", col.replace("\"", "\"\""))
+        } else {
+            String::from(col)
+        }
+    }
+}
+
+/// Export the recorded history of *gid* found in *records_path* to *timeline_path*.
+/// Returns the number of events written.
+pub fn export_timeline(
+    records_path: &Path,
+    gid: u64,
+    timeline_path: &Path,
+    format: TimelineFormat,
+) -> Result<usize, std::io::Error> {
+    let fallback_time = records_path.metadata()?.modified()?;
+    let hostname = hostname::get()
+        .map(|h| h.to_string_lossy().to_string())
+        .unwrap_or(String::from("Unknown host"));
+    let mut writer = BufWriter::new(File::create(timeline_path)?);
+    if format == TimelineFormat::L2tCsv {
+        writeln!(writer, "{}", L2TCSV_HEADER)?;
+    }
+    let mut count = 0;
+    for res_iomsg in IrpRecordsReader::from_path(records_path)? {
+        match res_iomsg {
+            Ok(iomsg) if iomsg.gid == gid => {
+                let entry = TimelineEntry::from(&iomsg, &hostname, fallback_time);
+                match format {
+                    TimelineFormat::L2tCsv => writeln!(writer, "{}", entry.to_l2tcsv())?,
+                    TimelineFormat::JsonL => writeln!(
+                        writer,
+                        "{}",
+                        serde_json::to_string(&entry).unwrap_or("{}".to_string())
+                    )?,
+                }
+                count += 1;
+            }
+            Ok(_) => {}
+            Err(offset) => println!("Error deserializing record at {}", offset),
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
@@ -0,0 +1,383 @@
+//! Access to the security token of monitored processes (the credentials of */proc* on Linux).
+
+#[cfg(windows)]
+use std::ffi::c_void;
+#[cfg(target_os = "linux")]
+use std::ffi::CStr;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::{fmt, mem, ptr};
+
+#[cfg(windows)]
+use bindings::Windows::Win32::Foundation::{CloseHandle, HANDLE, PSID, PWSTR};
+#[cfg(windows)]
+use bindings::Windows::Win32::Security::Authorization::ConvertSidToStringSidW;
+#[cfg(windows)]
+use bindings::Windows::Win32::Security::{
+    GetTokenInformation, LookupAccountSidW, TokenGroups, TokenIsAppContainer, TokenPrivileges, TokenSessionId,
+    TokenUser, SID_AND_ATTRIBUTES, SID_NAME_USE, TOKEN_GROUPS, TOKEN_QUERY, TOKEN_USER,
+};
+#[cfg(windows)]
+use bindings::Windows::Win32::System::Memory::LocalFree;
+#[cfg(windows)]
+use bindings::Windows::Win32::System::Threading::{
+    OpenProcess, OpenProcessToken, PROCESS_QUERY_LIMITED_INFORMATION,
+};
+#[cfg(windows)]
+use widestring::U16CStr;
+
+use crate::privileges::Privileges;
+
+/// Well-known SIDs of the accounts running services: *LocalSystem*, *LocalService* and
+/// *NetworkService*.
+const SERVICE_ACCOUNTS_SIDS: [&str; 3] = ["S-1-5-18", "S-1-5-19", "S-1-5-20"];
+/// On Linux, the lower uids are root and the system accounts of the daemons.
+const FIRST_USER_UID: u32 = 1000;
+/// The group is enabled in the token (not a deny-only group of a filtered UAC token).
+#[cfg(windows)]
+const SE_GROUP_ENABLED: u32 = 0x4;
+
+/// The user and terminal session of a process, resolved from its token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessOwner {
+    /// String SID (ex: *S-1-5-21-...-1001*), or the uid on Linux
+    pub sid: String,
+    /// *DOMAIN\user*, if the SID can be resolved
+    pub username: Option<String>,
+    /// Terminal services session (0 for the services), None on Linux
+    pub session_id: Option<u32>,
+}
+
+impl ProcessOwner {
+    /// Runs as *LocalSystem*, *LocalService*, *NetworkService*, or in the session of the services.
+    /// On Linux, runs as root or as a system account.
+    pub fn is_service_account(&self) -> bool {
+        SERVICE_ACCOUNTS_SIDS.contains(&self.sid.as_str())
+            || self.session_id == Some(0)
+            || self.sid.parse::<u32>().map_or(false, |uid| uid < FIRST_USER_UID)
+    }
+}
+
+impl fmt::Display for ProcessOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.username.as_ref().unwrap_or(&self.sid))?;
+        if let Some(session_id) = self.session_id {
+            write!(f, " (session {})", session_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the user and session owning *pid*, if the process can be opened.
+#[cfg(windows)]
+pub fn owner_from_pid(pid: u32) -> Option<ProcessOwner> {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid);
+        if handle.is_invalid() || handle.0 == 0 {
+            return None;
+        }
+        let mut token = HANDLE(0);
+        let res = if OpenProcessToken(handle, TOKEN_QUERY, &mut token).as_bool() {
+            let owner = token_owner(token);
+            CloseHandle(token);
+            owner
+        } else {
+            None
+        };
+        CloseHandle(handle);
+        res
+    }
+}
+
+/// The enabled groups of the token of *pid*, by SID and by *DOMAIN\\name* when it can be resolved.
+/// The Administrators group is only enabled in an elevated token.
+#[cfg(windows)]
+pub fn groups_from_pid(pid: u32) -> Option<Vec<String>> {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid);
+        if handle.is_invalid() || handle.0 == 0 {
+            return None;
+        }
+        let mut token = HANDLE(0);
+        let res = if OpenProcessToken(handle, TOKEN_QUERY, &mut token).as_bool() {
+            let groups = token_groups(token);
+            CloseHandle(token);
+            groups
+        } else {
+            None
+        };
+        CloseHandle(handle);
+        res
+    }
+}
+
+/// Is *pid* sandboxed in an AppContainer (see [crate::container])?
+#[cfg(windows)]
+pub fn is_app_container(pid: u32) -> bool {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid);
+        if handle.is_invalid() || handle.0 == 0 {
+            return false;
+        }
+        let mut token = HANDLE(0);
+        let mut app_container: u32 = 0;
+        let mut len: u32 = 0;
+        let res = OpenProcessToken(handle, TOKEN_QUERY, &mut token).as_bool()
+            && GetTokenInformation(
+                token,
+                TokenIsAppContainer,
+                &mut app_container as *mut u32 as *mut c_void,
+                mem::size_of::<u32>() as u32,
+                &mut len,
+            )
+            .as_bool()
+            && app_container != 0;
+        if token.0 != 0 {
+            CloseHandle(token);
+        }
+        CloseHandle(handle);
+        res
+    }
+}
+
+/// The privileges enabled in the token of *pid*, see [crate::privileges].
+#[cfg(windows)]
+pub fn privileges_from_pid(pid: u32) -> Option<Privileges> {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid);
+        if handle.is_invalid() || handle.0 == 0 {
+            return None;
+        }
+        let mut token = HANDLE(0);
+        let res = if OpenProcessToken(handle, TOKEN_QUERY, &mut token).as_bool() {
+            let privileges = token_privileges(token);
+            CloseHandle(token);
+            privileges
+        } else {
+            None
+        };
+        CloseHandle(handle);
+        res
+    }
+}
+
+#[cfg(windows)]
+unsafe fn token_privileges(token: HANDLE) -> Option<Privileges> {
+    let mut len: u32 = 0;
+    GetTokenInformation(token, TokenPrivileges, ptr::null_mut(), 0, &mut len);
+    if len == 0 {
+        return None;
+    }
+    let mut buffer: Vec<u8> = vec![0; len as usize];
+    if !GetTokenInformation(token, TokenPrivileges, buffer.as_mut_ptr() as *mut c_void, len, &mut len).as_bool() {
+        return None;
+    }
+    Some(Privileges::from_token_privileges(&buffer[..len as usize]))
+}
+
+#[cfg(windows)]
+unsafe fn token_groups(token: HANDLE) -> Option<Vec<String>> {
+    let mut len: u32 = 0;
+    GetTokenInformation(token, TokenGroups, ptr::null_mut(), 0, &mut len);
+    if len == 0 {
+        return None;
+    }
+    // u64 for the alignment of the SID pointers
+    let mut buffer: Vec<u64> = vec![0; (len as usize + 7) / 8];
+    if !GetTokenInformation(token, TokenGroups, buffer.as_mut_ptr() as *mut c_void, len, &mut len).as_bool() {
+        return None;
+    }
+    let token_groups = &*(buffer.as_ptr() as *const TOKEN_GROUPS);
+    let groups: &[SID_AND_ATTRIBUTES] =
+        std::slice::from_raw_parts(token_groups.Groups.as_ptr(), token_groups.GroupCount as usize);
+    let mut res = Vec::new();
+    for group in groups.iter().filter(|g| g.Attributes & SE_GROUP_ENABLED != 0) {
+        if let Some(sid) = sid_to_string(group.Sid) {
+            res.push(sid);
+        }
+        if let Some(name) = sid_to_account_name(group.Sid) {
+            res.push(name);
+        }
+    }
+    Some(res)
+}
+
+#[cfg(windows)]
+unsafe fn token_owner(token: HANDLE) -> Option<ProcessOwner> {
+    let (sid, username) = token_user(token)?;
+    let mut session_id: u32 = 0;
+    let mut len: u32 = 0;
+    let session_id = if GetTokenInformation(
+        token,
+        TokenSessionId,
+        &mut session_id as *mut u32 as *mut c_void,
+        mem::size_of::<u32>() as u32,
+        &mut len,
+    )
+    .as_bool()
+    {
+        Some(session_id)
+    } else {
+        None
+    };
+    Some(ProcessOwner { sid, username, session_id })
+}
+
+#[cfg(windows)]
+unsafe fn token_user(token: HANDLE) -> Option<(String, Option<String>)> {
+    let mut len: u32 = 0;
+    GetTokenInformation(token, TokenUser, ptr::null_mut(), 0, &mut len);
+    if len == 0 {
+        return None;
+    }
+    let mut buffer: Vec<u8> = vec![0; len as usize];
+    if !GetTokenInformation(
+        token,
+        TokenUser,
+        buffer.as_mut_ptr() as *mut c_void,
+        len,
+        &mut len,
+    )
+    .as_bool()
+    {
+        return None;
+    }
+    let token_user = &*(buffer.as_ptr() as *const TOKEN_USER);
+    let sid = sid_to_string(token_user.User.Sid)?;
+    Some((sid, sid_to_account_name(token_user.User.Sid)))
+}
+
+/// Returns *DOMAIN\user* for *sid*. Fails for the accounts of a domain which cannot be reached.
+#[cfg(windows)]
+pub unsafe fn sid_to_account_name(sid: PSID) -> Option<String> {
+    let mut name_len: u32 = 0;
+    let mut domain_len: u32 = 0;
+    let mut sid_name_use = SID_NAME_USE(0);
+    LookupAccountSidW(
+        PWSTR(ptr::null_mut()),
+        sid,
+        PWSTR(ptr::null_mut()),
+        &mut name_len,
+        PWSTR(ptr::null_mut()),
+        &mut domain_len,
+        &mut sid_name_use,
+    );
+    if name_len == 0 {
+        return None;
+    }
+    let mut name: Vec<u16> = vec![0; name_len as usize];
+    let mut domain: Vec<u16> = vec![0; domain_len.max(1) as usize];
+    if !LookupAccountSidW(
+        PWSTR(ptr::null_mut()),
+        sid,
+        PWSTR(name.as_mut_ptr()),
+        &mut name_len,
+        PWSTR(domain.as_mut_ptr()),
+        &mut domain_len,
+        &mut sid_name_use,
+    )
+    .as_bool()
+    {
+        return None;
+    }
+    // on success, the lengths exclude the terminating null
+    let name = String::from_utf16_lossy(&name[..name_len as usize]);
+    let domain = String::from_utf16_lossy(&domain[..domain_len as usize]);
+    if domain.is_empty() {
+        Some(name)
+    } else {
+        Some(format!("{}\\{}", domain, name))
+    }
+}
+
+#[cfg(windows)]
+pub unsafe fn sid_to_string(sid: PSID) -> Option<String> {
+    let mut pwstr = PWSTR(ptr::null_mut());
+    if !ConvertSidToStringSidW(sid, &mut pwstr).as_bool() || pwstr.0.is_null() {
+        return None;
+    }
+    let res = U16CStr::from_ptr_str(pwstr.0).to_string_lossy();
+    LocalFree(pwstr.0 as isize);
+    Some(res)
+}
+
+/// Returns the real user owning *pid*, if the process still exists.
+#[cfg(target_os = "linux")]
+pub fn owner_from_pid(pid: u32) -> Option<ProcessOwner> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    owner_from_uid(parse_uid(&status)?)
+}
+
+#[cfg(target_os = "linux")]
+pub fn owner_from_uid(uid: u32) -> Option<ProcessOwner> {
+    Some(ProcessOwner {
+        sid: uid.to_string(),
+        username: user_name(uid),
+        session_id: None,
+    })
+}
+
+/// The effective capabilities of *pid*, see [crate::privileges].
+#[cfg(target_os = "linux")]
+pub fn privileges_from_pid(pid: u32) -> Option<Privileges> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let cap_eff = status.lines().find_map(|line| line.strip_prefix("CapEff:"))?.trim();
+    u64::from_str_radix(cap_eff, 16).ok().map(Privileges::from_capabilities)
+}
+
+/// The first value of the *Uid:* line (real, effective, saved and filesystem uids).
+#[cfg(target_os = "linux")]
+fn parse_uid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Resolved through NSS, so that the directory users are found too.
+#[cfg(target_os = "linux")]
+fn user_name(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buffer: Vec<libc::c_char> = vec![0; 4096];
+    let mut result: *mut libc::passwd = ptr::null_mut();
+    let res = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if res != 0 || result.is_null() || passwd.pw_name.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(passwd.pw_name) }.to_string_lossy().to_string())
+}
+
+/// The names of the groups of *uid* (primary and supplementary), resolved through NSS.
+#[cfg(target_os = "linux")]
+pub fn groups_from_uid(uid: u32) -> Vec<String> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buffer: Vec<libc::c_char> = vec![0; 4096];
+    let mut result: *mut libc::passwd = ptr::null_mut();
+    let res = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if res != 0 || result.is_null() || passwd.pw_name.is_null() {
+        return Vec::new();
+    }
+    let mut gids: Vec<libc::gid_t> = vec![0; 256];
+    let mut count = gids.len() as libc::c_int;
+    if unsafe { libc::getgrouplist(passwd.pw_name, passwd.pw_gid, gids.as_mut_ptr(), &mut count) } < 0 {
+        // more than 256 groups: only the first ones are checked
+        count = gids.len() as libc::c_int;
+    }
+    gids.truncate(count.max(0) as usize);
+    gids.into_iter().filter_map(group_name).collect()
+}
+
+#[cfg(target_os = "linux")]
+fn group_name(gid: libc::gid_t) -> Option<String> {
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut buffer: Vec<libc::c_char> = vec![0; 4096];
+    let mut result: *mut libc::group = ptr::null_mut();
+    let res = unsafe { libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if res != 0 || result.is_null() || group.gr_name.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(group.gr_name) }.to_string_lossy().to_string())
+}
@@ -0,0 +1,410 @@
+//! Updates of the agent by the service itself, rolled out by stages over the fleet.
+//!
+//! Every *UPDATE_INTERVAL* seconds, the manifest at *UPDATE_URL* (*NONE* disables it) is fetched:
+//! ```json
+//! {"version": "1.4.0", "url": "https://updates.example.com/owlyshield_ransom.exe", "sha256": "9f86d0...", "rollout": 25}
+//! ```
+//! A newer version is installed on the machines of its *rollout*, a percentage: each machine
+//! falls in a bucket of the hash of its machine id and of the version ([rollout_bucket]), so that
+//! raising the percentage only adds machines. The executable downloaded must have the sha256 of
+//! the manifest and a trusted Authenticode signature by the same certificate as the running agent.
+//!
+//! The swap:
+//! 1. the running executable is renamed to *.old.exe* (Windows allows it) and the new one takes
+//!    its name. A [Pending] update is written to *DebugPath\update_pending.json*;
+//! 2. the protection loop is stopped: it drains the driver queue and saves the state of the gids
+//!    (*PERSIST_STATE*, see [crate::persistence]);
+//! 3. the process exits with an error, and the Service Control Manager starts the new executable
+//!    (see the recovery options set by [crate::service_ctl::install]). Out of the service, the
+//!    process relaunches itself ([relaunch]).
+//!
+//! The new version is healthy if its protection loop still beats after [HEALTH_PERIOD]: the
+//! pending update is then committed and *.old.exe* deleted. Otherwise, or after [MAX_ATTEMPTS]
+//! starts of the protection loop (crashes), the previous executable is restored the same way and
+//! the new one kept as *.failed.exe*.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use curl::easy::Easy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::config::{Config, Param};
+use crate::identity::AgentIdentity;
+use crate::service_ctl::Lifecycle;
+use crate::utils::sha256_file;
+
+/// Name of the file of the pending update, in *DebugPath*.
+pub static PENDING_FILE_NAME: &str = "update_pending.json";
+/// The new version must run that long with a beating protection loop.
+pub const HEALTH_PERIOD: Duration = Duration::from_secs(180);
+/// Starts of the protection loop of a pending update before it is rolled back.
+pub const MAX_ATTEMPTS: u32 = 3;
+/// Exit code of the process relaunched by the Service Control Manager.
+#[cfg_attr(not(all(windows, feature = "service")), allow(dead_code))]
+pub const RELAUNCH_EXIT_CODE: i32 = 3;
+/// Without a beat for longer, the protection loop is not healthy.
+const UNHEALTHY_AFTER: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error("cannot reach {0}: {1}")]
+    Http(String, curl::Error),
+    #[error("{0} answered {1}")]
+    Status(String, u32),
+    #[error("invalid manifest: {0}")]
+    Manifest(String),
+    #[error("sha256 {0} of the download, {1} expected")]
+    Hash(String, String),
+    #[error("signature: {0}")]
+    Signature(String),
+    #[error("{0}: {1}")]
+    Io(PathBuf, std::io::Error),
+}
+
+/// The latest version, published at *UPDATE_URL*.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    /// Of the executable
+    pub url: String,
+    pub sha256: String,
+    /// Percentage of the machines to update
+    #[serde(default = "full_rollout")]
+    pub rollout: u8,
+}
+
+fn full_rollout() -> u8 {
+    100
+}
+
+impl Manifest {
+    pub fn from_json(json: &str) -> Result<Manifest, UpdateError> {
+        let manifest: Manifest = serde_json::from_str(json).map_err(|e| UpdateError::Manifest(e.to_string()))?;
+        if parse_version(&manifest.version).is_none() {
+            return Err(UpdateError::Manifest(format!("invalid version {}", manifest.version)));
+        }
+        if manifest.sha256.len() != 64 || !manifest.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(UpdateError::Manifest(format!("invalid sha256 {}", manifest.sha256)));
+        }
+        Ok(manifest)
+    }
+
+    /// True if the manifest is newer than *current*, and *machine_id* in its rollout.
+    pub fn applies_to(&self, current: &str, machine_id: &str) -> bool {
+        is_newer(&self.version, current) && rollout_bucket(machine_id, &self.version) < self.rollout.min(100)
+    }
+}
+
+/// An update installed, not yet committed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pending {
+    pub from_version: String,
+    pub to_version: String,
+    pub staged: SystemTime,
+    /// Starts of the protection loop of the new version
+    pub attempts: u32,
+}
+
+impl Pending {
+    fn load(config: &Config) -> Option<Pending> {
+        let content = fs::read_to_string(config.get_path(Param::DebugPath).join(PENDING_FILE_NAME)).ok()?;
+        serde_json::from_str(&content)
+            .map_err(|e| warn!("Invalid {}, ignored: {}", PENDING_FILE_NAME, e))
+            .ok()
+    }
+
+    fn save(&self, config: &Config) -> Result<(), UpdateError> {
+        let path = config.get_path(Param::DebugPath).join(PENDING_FILE_NAME);
+        let content = serde_json::to_string_pretty(self).map_err(|e| UpdateError::Manifest(e.to_string()))?;
+        fs::write(&path, content).map_err(|e| UpdateError::Io(path, e))
+    }
+
+    fn remove(config: &Config) {
+        fs::remove_file(config.get_path(Param::DebugPath).join(PENDING_FILE_NAME)).unwrap_or(());
+    }
+}
+
+/// The bucket of *machine_id* for *version*, between 0 and 99.
+pub fn rollout_bucket(machine_id: &str, version: &str) -> u8 {
+    let digest = Sha256::new().chain(machine_id).chain(":").chain(version).finalize();
+    (u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 100) as u8
+}
+
+/// *1.4.0* to [1, 4, 0], None if not made of numbers.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.trim().trim_start_matches('v').split('.').map(|n| n.parse().ok()).collect()
+}
+
+fn is_newer(candidate: &str, current: &str) -> bool {
+    match (parse_version(candidate), parse_version(current)) {
+        (Some(candidate), Some(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+/// Counts a start of the protection loop with a pending update, and rolls it back after
+/// [MAX_ATTEMPTS]. Returns false if the process must relaunch the previous version.
+pub fn on_start(config: &Config) -> bool {
+    let mut pending = match Pending::load(config) {
+        Some(pending) => pending,
+        None => return true,
+    };
+    pending.attempts += 1;
+    if pending.attempts > MAX_ATTEMPTS {
+        error!(
+            "Update to {} failed, the protection loop crashed {} times",
+            pending.to_version, MAX_ATTEMPTS
+        );
+        return !rollback(config, &pending);
+    }
+    info!(
+        "Update from {} to {}, start {}/{}",
+        pending.from_version, pending.to_version, pending.attempts, MAX_ATTEMPTS
+    );
+    if let Err(e) = pending.save(config) {
+        error!("Cannot count the start of the update: {}", e);
+    }
+    true
+}
+
+/// Checks the updates and confirms the health of a pending one until *done* is set. Requests the
+/// restart of the agent when an update is installed or rolled back.
+pub fn run(config: &Config, lifecycle: &Lifecycle, done: &AtomicBool) {
+    let url = config.get_str(Param::UpdateUrl).trim().to_string();
+    let enabled = !url.is_empty() && !url.eq_ignore_ascii_case("NONE");
+    let mut pending = Pending::load(config);
+    if !enabled && pending.is_none() {
+        return;
+    }
+    let interval = Duration::from_secs(config.get_usize(Param::UpdateInterval).max(60) as u64);
+    let started = Instant::now();
+    let mut last_check: Option<Instant> = None;
+    while !done.load(Ordering::SeqCst) {
+        if let Some(update) = &pending {
+            if started.elapsed() >= HEALTH_PERIOD {
+                if lifecycle.since_last_beat() < UNHEALTHY_AFTER {
+                    commit(config, update);
+                    pending = None;
+                } else {
+                    error!(
+                        "Update to {} failed the health check, no activity for {} seconds",
+                        update.to_version,
+                        lifecycle.since_last_beat().as_secs()
+                    );
+                    if rollback(config, update) {
+                        lifecycle.request_restart();
+                        return;
+                    }
+                    pending = None;
+                }
+            }
+        } else if enabled && last_check.is_none_or(|t| t.elapsed() >= interval) {
+            last_check = Some(Instant::now());
+            match check(config, &url) {
+                Ok(true) => {
+                    lifecycle.request_restart();
+                    return;
+                }
+                Ok(false) => {}
+                Err(e) => warn!(%url, "Update failed: {}", e),
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Installs the update of the manifest at *url* if it applies. Returns true if installed.
+fn check(config: &Config, url: &str) -> Result<bool, UpdateError> {
+    let identity = AgentIdentity::load(config);
+    let manifest = Manifest::from_json(&String::from_utf8_lossy(&get(url, REQUEST_TIMEOUT)?))?;
+    if !manifest.applies_to(&identity.agent_version, &identity.machine_id) {
+        return Ok(false);
+    }
+    info!("Update from {} to {}", identity.agent_version, manifest.version);
+
+    let exe = current_exe()?;
+    let new = sibling(&exe, "new");
+    let content = get(&manifest.url, DOWNLOAD_TIMEOUT)?;
+    fs::File::create(&new)
+        .and_then(|mut file| file.write_all(&content))
+        .map_err(|e| UpdateError::Io(new.clone(), e))?;
+    if let Err(e) = verify(&new, &exe, &manifest.sha256) {
+        fs::remove_file(&new).unwrap_or(());
+        return Err(e);
+    }
+
+    let pending = Pending {
+        from_version: identity.agent_version,
+        to_version: manifest.version,
+        staged: SystemTime::now(),
+        attempts: 0,
+    };
+    pending.save(config)?;
+    if let Err(e) = swap(&exe, &new, &sibling(&exe, "old")) {
+        Pending::remove(config);
+        return Err(e);
+    }
+    info!("Update to {} installed, the agent restarts", pending.to_version);
+    Ok(true)
+}
+
+/// The download must have the sha256 of the manifest, and a trusted signature by the certificate
+/// of the running executable: a subject can be reused by a certificate of another issuer.
+fn verify(download: &Path, exe: &Path, sha256: &str) -> Result<(), UpdateError> {
+    let actual = sha256_file(download).map_err(|e| UpdateError::Io(download.to_path_buf(), e))?;
+    if !actual.eq_ignore_ascii_case(sha256) {
+        return Err(UpdateError::Hash(actual, sha256.to_lowercase()));
+    }
+    #[cfg(windows)]
+    {
+        use crate::signer::{is_trusted, signer_subject, signer_thumbprint};
+
+        let expected = signer_thumbprint(exe)
+            .ok_or_else(|| UpdateError::Signature(String::from("the running agent is not signed")))?;
+        if !is_trusted(download) {
+            return Err(UpdateError::Signature(String::from("not signed, or not trusted")));
+        }
+        match signer_thumbprint(download) {
+            Some(thumbprint) if thumbprint == expected => Ok(()),
+            thumbprint => Err(UpdateError::Signature(format!(
+                "signed by {} with the certificate {}, {} expected",
+                signer_subject(download).unwrap_or_default(),
+                thumbprint.unwrap_or_default(),
+                expected
+            ))),
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = exe;
+        Err(UpdateError::Signature(String::from("only verified on Windows")))
+    }
+}
+
+/// Moves *exe* to *backup* and *replacement* to *exe*, *exe* is restored on error.
+fn swap(exe: &Path, replacement: &Path, backup: &Path) -> Result<(), UpdateError> {
+    fs::remove_file(backup).unwrap_or(());
+    fs::rename(exe, backup).map_err(|e| UpdateError::Io(exe.to_path_buf(), e))?;
+    if let Err(e) = fs::rename(replacement, exe) {
+        fs::rename(backup, exe).unwrap_or(());
+        return Err(UpdateError::Io(replacement.to_path_buf(), e));
+    }
+    Ok(())
+}
+
+fn commit(config: &Config, pending: &Pending) {
+    Pending::remove(config);
+    if let Ok(exe) = current_exe() {
+        // may still be locked by the previous process
+        fs::remove_file(sibling(&exe, "old")).unwrap_or(());
+    }
+    info!("Update to {} committed", pending.to_version);
+}
+
+/// Restores the previous executable. Returns true if restored, the agent must then relaunch.
+fn rollback(config: &Config, pending: &Pending) -> bool {
+    Pending::remove(config);
+    let res = current_exe().and_then(|exe| swap(&exe, &sibling(&exe, "old"), &sibling(&exe, "failed")));
+    match res {
+        Ok(()) => {
+            warn!("Update to {} rolled back to {}", pending.to_version, pending.from_version);
+            true
+        }
+        Err(e) => {
+            error!("Cannot roll back the update to {}: {}", pending.to_version, e);
+            false
+        }
+    }
+}
+
+/// Starts the executable again with the same arguments, out of the service.
+#[cfg_attr(all(windows, feature = "service"), allow(dead_code))]
+pub fn relaunch() {
+    let res = std::env::current_exe()
+        .and_then(|exe| std::process::Command::new(exe).args(std::env::args_os().skip(1)).spawn());
+    if let Err(e) = res {
+        error!("Cannot relaunch the agent: {}", e);
+    }
+}
+
+fn current_exe() -> Result<PathBuf, UpdateError> {
+    std::env::current_exe().map_err(|e| UpdateError::Io(PathBuf::from("current executable"), e))
+}
+
+/// *owlyshield_ransom.exe* to *owlyshield_ransom.<suffix>.exe*.
+fn sibling(exe: &Path, suffix: &str) -> PathBuf {
+    match exe.extension() {
+        Some(extension) => exe.with_extension(format!("{}.{}", suffix, extension.to_string_lossy())),
+        None => exe.with_extension(suffix),
+    }
+}
+
+fn get(url: &str, timeout: Duration) -> Result<Vec<u8>, UpdateError> {
+    let http = |e| UpdateError::Http(String::from(url), e);
+    let mut easy = Easy::new();
+    let mut body = Vec::new();
+    easy.url(url).map_err(http)?;
+    easy.follow_location(true).map_err(http)?;
+    easy.timeout(timeout).map_err(http)?;
+    {
+        let mut transfer = easy.transfer();
+        transfer
+            .write_function(|chunk| {
+                body.extend_from_slice(chunk);
+                Ok(chunk.len())
+            })
+            .map_err(http)?;
+        transfer.perform().map_err(http)?;
+    }
+    match easy.response_code().map_err(http)? {
+        200..=299 => Ok(body),
+        code => Err(UpdateError::Status(String::from(url), code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::updater::{rollout_bucket, sibling, Manifest};
+
+    #[test]
+    fn manifest_should_apply_to_its_rollout() {
+        let json = format!(
+            r#"{{"version": "1.4.0", "url": "https://updates/owly.exe", "sha256": "{}", "rollout": 30}}"#,
+            "ab".repeat(32)
+        );
+        let mut manifest = Manifest::from_json(&json).unwrap();
+        let machines: Vec<String> = (0..1000).map(|i| format!("machine-{}", i)).collect();
+        let updated = machines.iter().filter(|m| manifest.applies_to("1.3.9", m)).count();
+        assert!((200..400).contains(&updated), "{}", updated);
+        // stable, and growing with the rollout
+        let first: Vec<&String> = machines.iter().filter(|m| manifest.applies_to("1.3.9", m)).collect();
+        manifest.rollout = 60;
+        assert!(first.iter().all(|m| manifest.applies_to("1.3.9", m)));
+        assert_eq!(rollout_bucket("machine-1", "1.4.0"), rollout_bucket("machine-1", "1.4.0"));
+        manifest.rollout = 100;
+        assert!(!manifest.applies_to("1.4.0", "machine-1"));
+        assert!(!manifest.applies_to("1.10.0", "machine-1"));
+        assert!(manifest.applies_to("1.3", "machine-1"));
+
+        assert!(Manifest::from_json(&json.replace("1.4.0", "latest")).is_err());
+        assert!(Manifest::from_json(&json.replace(&"ab".repeat(32), "abc")).is_err());
+        let full = Manifest::from_json(&json.replace(r#", "rollout": 30"#, "")).unwrap();
+        assert_eq!(full.rollout, 100);
+        assert_eq!(
+            sibling(Path::new(r"C:\Owlyshield\owlyshield_ransom.exe"), "old"),
+            Path::new(r"C:\Owlyshield\owlyshield_ransom.old.exe")
+        );
+    }
+}
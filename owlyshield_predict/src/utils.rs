@@ -1,2 +1,97 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
 pub static LONG_TIME_FORMAT: &str = "%d/%m/%Y %H:%M:%S";
 pub static FILE_TIME_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// From this length, the Win32 file functions need the *\\\\?\\* prefix (*MAX_PATH* less an
+/// 8.3 file name, the limit of the directories).
+const MAX_SHORT_PATH: usize = 248;
+
+/// Sha256 of a file, as a lowercase hex string.
+pub fn sha256_file(path: &Path) -> Result<String, io::Error> {
+    let mut file = File::open(extended_path(path))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// *path* with the *\\\\?\\* prefix (*\\\\?\\UNC\\* for a share) if it is too long for the Win32
+/// file functions, so that the paths of up to 32k characters reported by the minifilter can be
+/// opened. Relative and already prefixed paths are unchanged.
+pub fn extended_path(path: &Path) -> Cow<'_, Path> {
+    let s = match path.to_str() {
+        Some(s) if s.encode_utf16().count() >= MAX_SHORT_PATH => s,
+        _ => return Cow::Borrowed(path),
+    };
+    let bytes = s.as_bytes();
+    let extended = if s.starts_with(r"\\?\") || s.starts_with(r"\??\") || s.starts_with(r"\\.\") {
+        return Cow::Borrowed(path);
+    } else if let Some(share) = s.strip_prefix(r"\\") {
+        format!(r"\\?\UNC\{}", share)
+    } else if bytes.len() > 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && (bytes[2] == b'\\' || bytes[2] == b'/') {
+        format!(r"\\?\{}", s)
+    } else {
+        return Cow::Borrowed(path);
+    };
+    // no separator translation with the prefix
+    Cow::Owned(PathBuf::from(extended.replace('/', "\\")))
+}
+
+/// Whether *path* is under *%SystemRoot%*, *Program Files* or a system directory of Linux: the
+/// executables there are shared by many applications and only writable by the administrators.
+pub fn is_system_path(path: &Path) -> bool {
+    let path = path.to_string_lossy().to_lowercase().replace('\\', "/");
+    let system_root = std::env::var("SystemRoot").unwrap_or_else(|_| String::from(r"C:\Windows"));
+    let system_root = format!("{}/", system_root.to_lowercase().replace('\\', "/").trim_end_matches('/'));
+    path.starts_with(&system_root)
+        || ["/program files/", "/program files (x86)/"].iter().any(|dir| path.contains(dir))
+        || ["/usr/", "/bin/", "/sbin/", "/lib/", "/opt/"].iter().any(|dir| path.starts_with(dir))
+}
+
+/// Comparison whose duration does not depend on the position of the first difference.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::utils::extended_path;
+
+    #[test]
+    fn long_paths_should_be_prefixed() {
+        let dirs = "r\u{e9}pertoire-\u{1f600}\\".repeat(20);
+        let long = format!(r"C:\Users\bob\{}report.docx", dirs);
+        assert_eq!(extended_path(Path::new(&long)), Path::new(&format!(r"\\?\{}", long)));
+        assert_eq!(
+            extended_path(Path::new(&format!(r"\\nas\share\{}a.txt", dirs))),
+            Path::new(&format!(r"\\?\UNC\nas\share\{}a.txt", dirs))
+        );
+        assert_eq!(
+            extended_path(Path::new(&format!("c:/data/{}", dirs))),
+            Path::new(&format!(r"\\?\c:\data\{}", dirs))
+        );
+        let prefixed = format!(r"\\?\{}", long);
+        assert_eq!(extended_path(Path::new(&prefixed)), Path::new(&prefixed));
+        assert_eq!(extended_path(Path::new(&dirs)), Path::new(&dirs));
+        assert_eq!(extended_path(Path::new(r"C:\Users\bob\a.txt")), Path::new(r"C:\Users\bob\a.txt"));
+    }
+}
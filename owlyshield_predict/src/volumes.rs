@@ -0,0 +1,269 @@
+//! Mapping of the NT device paths to the drive letters and mount points.
+//!
+//! The minifilter converts the paths with *IoVolumeDeviceToDosName*, but leaves the device form
+//! (*\Device\HarddiskVolume5\data\report.docx*) for the volumes mounted in a folder or without a
+//! letter, and for the writes on the volumes themselves ([crate::rawdisk]). The events and the
+//! reports show both forms, see [PathForms].
+//!
+//! The paths are normalized lazily, only for the events and the reports, and not in the hot path:
+//! the device is replaced by its mount point, the drive letter is uppercased and the 8.3 short
+//! names (*PROGRA~1*) are expanded. The mounts are listed on the first use (Windows), then again
+//! when the drive letters change, on an unknown device, and every [MAX_AGE].
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// The mounts are listed again after this delay.
+const MAX_AGE: Duration = Duration::from_secs(300);
+/// The drive letters are checked at most this often.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// An unknown device lists the mounts again at most this often.
+const MISS_INTERVAL: Duration = Duration::from_secs(10);
+
+static MOUNTS: Mutex<Option<Mounts>> = Mutex::new(None);
+
+/// A volume mounted on a drive letter (*C:\\*) or in a folder (*D:\\mnt\\data\\*).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// *\Device\HarddiskVolume3*
+    pub device: String,
+    /// With a trailing separator
+    pub mount_point: String,
+}
+
+struct Mounts {
+    mounts: Vec<Mount>,
+    /// Bitmask of *GetLogicalDrives* when listed
+    drives: u32,
+    listed: Instant,
+    checked: Instant,
+}
+
+/// A path as reported by the minifilter, and normalized.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathForms {
+    pub raw: String,
+    pub normalized: String,
+}
+
+impl PathForms {
+    pub fn of(raw: &str) -> PathForms {
+        PathForms {
+            raw: raw.to_string(),
+            normalized: normalize(raw),
+        }
+    }
+}
+
+impl Display for PathForms {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.raw == self.normalized {
+            write!(f, "{}", self.raw)
+        } else {
+            write!(f, "{} ({})", self.normalized, self.raw)
+        }
+    }
+}
+
+/// The mount point of the volume of a device path, None for another path or an unknown device.
+pub fn mount_point(device: &str) -> Option<String> {
+    with_mounts(device, |mounts| {
+        let (mount, _) = resolve(mounts, device)?;
+        Some(mount.mount_point.clone())
+    })
+}
+
+/// *path* with its mount point instead of its device, its drive letter uppercased and its short
+/// names expanded. Unchanged if it cannot be resolved.
+pub fn normalize(path: &str) -> String {
+    let path = strip_prefix(path);
+    let path = with_mounts(path, |mounts| to_dos(mounts, path)).unwrap_or_else(|| path.to_string());
+    let path = if path.contains('~') { long_path(&path).unwrap_or(path) } else { path };
+    uppercase_drive(path)
+}
+
+/// Calls *f* with the mounts, listed again if needed. None if *path* is not a device path.
+fn with_mounts<T>(path: &str, f: impl Fn(&[Mount]) -> Option<T>) -> Option<T> {
+    if !is_device_path(path) {
+        return None;
+    }
+    let mut guard = MOUNTS.lock().unwrap();
+    let now = Instant::now();
+    let stale = match guard.as_mut() {
+        None => true,
+        Some(mounts) if now.duration_since(mounts.listed) >= MAX_AGE => true,
+        Some(mounts) if now.duration_since(mounts.checked) >= CHECK_INTERVAL => {
+            mounts.checked = now;
+            logical_drives() != mounts.drives
+        }
+        Some(_) => false,
+    };
+    if stale {
+        *guard = Some(list(now));
+    }
+    let mounts = guard.as_ref().unwrap();
+    let res = f(&mounts.mounts);
+    if res.is_none() && !stale && now.duration_since(mounts.listed) >= MISS_INTERVAL {
+        *guard = Some(list(now));
+        return f(&guard.as_ref().unwrap().mounts);
+    }
+    res
+}
+
+fn list(now: Instant) -> Mounts {
+    Mounts {
+        mounts: query_mounts(),
+        drives: logical_drives(),
+        listed: now,
+        checked: now,
+    }
+}
+
+fn is_device_path(path: &str) -> bool {
+    path.get(..8).is_some_and(|prefix| prefix.eq_ignore_ascii_case(r"\Device\"))
+}
+
+/// The mount of the device of *path* (the longest match), and the rest of the path.
+fn resolve<'a, 'm>(mounts: &'m [Mount], path: &'a str) -> Option<(&'m Mount, &'a str)> {
+    mounts
+        .iter()
+        .filter_map(|mount| {
+            let prefix = path.get(..mount.device.len())?;
+            let rest = &path[mount.device.len()..];
+            if prefix.eq_ignore_ascii_case(&mount.device) && (rest.is_empty() || rest.starts_with('\\')) {
+                Some((mount, rest))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(mount, _)| mount.device.len())
+}
+
+fn to_dos(mounts: &[Mount], path: &str) -> Option<String> {
+    let (mount, rest) = resolve(mounts, path)?;
+    Some(format!("{}{}", mount.mount_point, rest.trim_start_matches('\\')))
+}
+
+/// Removes the *\\\\?\\* and *\\??\\* prefixes of the Win32 and NT namespaces.
+fn strip_prefix(path: &str) -> &str {
+    path.strip_prefix(r"\\?\").or_else(|| path.strip_prefix(r"\??\")).unwrap_or(path)
+}
+
+fn uppercase_drive(mut path: String) -> String {
+    let bytes = path.as_bytes();
+    if bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_lowercase() {
+        path[..1].make_ascii_uppercase();
+    }
+    path
+}
+
+#[cfg(windows)]
+fn query_mounts() -> Vec<Mount> {
+    use bindings::Windows::Win32::Foundation::PWSTR;
+    use bindings::Windows::Win32::Storage::FileSystem::{
+        FindFirstVolumeW, FindNextVolumeW, FindVolumeClose, GetVolumePathNamesForVolumeNameW, QueryDosDeviceW,
+    };
+
+    let mut mounts = Vec::new();
+    let mut name = [0u16; 64];
+    unsafe {
+        let find = FindFirstVolumeW(PWSTR(name.as_mut_ptr()), name.len() as u32);
+        if find.is_invalid() {
+            return mounts;
+        }
+        loop {
+            // \\?\Volume{guid}\, and Volume{guid} for QueryDosDevice
+            let volume = from_wide(&name);
+            let mut device = [0u16; 260];
+            let inner = volume.trim_start_matches(r"\\?\").trim_end_matches('\\');
+            let mut paths = vec![0u16; 1024];
+            let mut len = 0u32;
+            if QueryDosDeviceW(inner, PWSTR(device.as_mut_ptr()), device.len() as u32) != 0
+                && GetVolumePathNamesForVolumeNameW(volume.as_str(), PWSTR(paths.as_mut_ptr()), paths.len() as u32, &mut len)
+                    .as_bool()
+            {
+                let device = from_wide(&device);
+                for mount_point in paths[..len as usize].split(|c| *c == 0).filter(|p| !p.is_empty()) {
+                    mounts.push(Mount {
+                        device: device.clone(),
+                        mount_point: String::from_utf16_lossy(mount_point),
+                    });
+                }
+            }
+            if !FindNextVolumeW(find, PWSTR(name.as_mut_ptr()), name.len() as u32).as_bool() {
+                break;
+            }
+        }
+        FindVolumeClose(find);
+    }
+    mounts
+}
+
+#[cfg(not(windows))]
+fn query_mounts() -> Vec<Mount> {
+    Vec::new()
+}
+
+#[cfg(windows)]
+fn logical_drives() -> u32 {
+    unsafe { bindings::Windows::Win32::Storage::FileSystem::GetLogicalDrives() }
+}
+
+#[cfg(not(windows))]
+fn logical_drives() -> u32 {
+    0
+}
+
+/// Expands the 8.3 short names of *path*, if it exists.
+#[cfg(windows)]
+fn long_path(path: &str) -> Option<String> {
+    use bindings::Windows::Win32::Foundation::PWSTR;
+    use bindings::Windows::Win32::Storage::FileSystem::GetLongPathNameW;
+
+    let mut buffer = vec![0u16; 1024];
+    let len = unsafe { GetLongPathNameW(path, PWSTR(buffer.as_mut_ptr()), buffer.len() as u32) } as usize;
+    if len == 0 || len >= buffer.len() {
+        return None;
+    }
+    Some(String::from_utf16_lossy(&buffer[..len]))
+}
+
+#[cfg(not(windows))]
+fn long_path(_path: &str) -> Option<String> {
+    None
+}
+
+#[cfg(windows)]
+fn from_wide(buffer: &[u16]) -> String {
+    let len = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+    String::from_utf16_lossy(&buffer[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::volumes::{strip_prefix, to_dos, uppercase_drive, Mount};
+
+    #[test]
+    fn device_paths_should_be_mapped_to_their_mount_points() {
+        let mounts = vec![
+            Mount { device: String::from(r"\Device\HarddiskVolume3"), mount_point: String::from(r"C:\") },
+            Mount { device: String::from(r"\Device\HarddiskVolume5"), mount_point: String::from(r"D:\") },
+            Mount { device: String::from(r"\Device\HarddiskVolume5"), mount_point: String::from(r"C:\mnt\data\") },
+        ];
+        assert_eq!(
+            to_dos(&mounts, r"\Device\harddiskvolume3\Users\bob\report.docx").as_deref(),
+            Some(r"C:\Users\bob\report.docx")
+        );
+        assert_eq!(to_dos(&mounts, r"\Device\HarddiskVolume3").as_deref(), Some(r"C:\"));
+        assert_eq!(to_dos(&mounts, r"\Device\HarddiskVolume30\x.docx"), None);
+        assert_eq!(to_dos(&mounts, r"\Device\HarddiskVolumeShadowCopy1\x.docx"), None);
+        assert!(to_dos(&mounts, r"\Device\HarddiskVolume5\photos\a.jpg").unwrap().ends_with(r"photos\a.jpg"));
+        assert_eq!(strip_prefix(r"\\?\C:\Users\bob"), r"C:\Users\bob");
+        assert_eq!(strip_prefix(r"\??\C:\Users\bob"), r"C:\Users\bob");
+        assert_eq!(uppercase_drive(String::from(r"c:\users\bob")), r"C:\users\bob");
+    }
+}
@@ -0,0 +1,208 @@
+//! Supervision of the protection loop.
+//!
+//! The loop runs in its own thread and beats [Lifecycle::beat] at each iteration. The watchdog:
+//! * restarts it after a panic or an [ErrorPolicy::Retry] error it returns (at most
+//!   [MAX_RESTARTS] times in a row, with a growing delay). The other errors end the process;
+//! * detects a hang, when no beat was seen for *WATCHDOG_TIMEOUT* seconds. A thread cannot be
+//!   killed.
+//!
+//! After a hang or too many restarts, the process exits with an error, without reporting the
+//! service as stopped: the Service Control Manager sees a failure and restarts it (see the
+//! recovery options set by [crate::service_ctl::install]).
+//!
+//! Each incident writes a context file and a minidump (on Windows) to *DebugPath\crashes*, and
+//! is reported to the connectors.
+
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+#[cfg(windows)]
+use std::os::windows::io::AsRawHandle;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(windows)]
+use bindings::Windows::Win32::Foundation::HANDLE;
+#[cfg(windows)]
+use bindings::Windows::Win32::System::Diagnostics::Debug::{
+    MiniDumpWithThreadInfo, MiniDumpWriteDump,
+};
+#[cfg(windows)]
+use bindings::Windows::Win32::System::Threading::{GetCurrentProcess, GetCurrentProcessId};
+use chrono::{DateTime, Local};
+use tracing::error;
+
+use crate::config::{Config, Param};
+use crate::connectors::connector::Connectors;
+use crate::error::{ErrorPolicy, OwlyError};
+use crate::logging;
+use crate::service_ctl::Lifecycle;
+use crate::utils::FILE_TIME_FORMAT;
+
+/// Consecutive restarts after a panic before giving up.
+pub const MAX_RESTARTS: u32 = 5;
+/// After running this long, the loop is considered stable and the restarts counter is reset.
+const STABLE_RUN: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncidentKind {
+    /// A panic in the protection loop, which has been restarted.
+    Panic,
+    /// The protection loop did not beat for too long.
+    Hang,
+    /// The self-test did not see the driver messages of its helper.
+    SelfTest,
+    /// A process tried to terminate, suspend or inject into the agent, blocked by the
+    /// minifilter (see [crate::selfprotect]).
+    TamperAttempt,
+}
+
+/// An incident of the agent itself, reported to the connectors.
+#[derive(Debug, Clone)]
+pub struct Incident {
+    pub kind: IncidentKind,
+    pub time: DateTime<Local>,
+    pub message: String,
+    /// Context file written in *DebugPath\crashes*, or the directory itself for a panic (the
+    /// context has been written by the panic hook).
+    pub report_path: PathBuf,
+}
+
+/// Runs *pipeline* in a supervised thread until it returns normally (after a stop request).
+pub fn supervise<F>(lifecycle: Arc<Lifecycle>, connectors: &Connectors, pipeline: F)
+where
+    F: Fn(&Lifecycle) -> Result<(), OwlyError> + Send + Sync + 'static,
+{
+    let config = Config::new().unwrap_or_else(|e| OwlyError::from(e).exit());
+    let crashes_path = config.get_path(Param::DebugPath).join("crashes");
+    let timeout = Duration::from_secs(config.get_usize(Param::WatchdogTimeout) as u64);
+    logging::init(&config);
+    set_panic_hook(crashes_path.clone());
+
+    let pipeline = Arc::new(pipeline);
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let (done_tx, done_rx) = mpsc::channel();
+        let pipeline_bis = Arc::clone(&pipeline);
+        let lifecycle_bis = Arc::clone(&lifecycle);
+        lifecycle.beat();
+        thread::spawn(move || {
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| pipeline_bis(&lifecycle_bis)));
+            let stopped = match res {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) if e.policy() == ErrorPolicy::Exit => e.exit(),
+                Ok(Err(e)) => {
+                    error!("Protection loop stopped: {}", e);
+                    false
+                }
+                Err(_) => false,
+            };
+            done_tx.send(stopped).unwrap_or(());
+        });
+
+        loop {
+            match done_rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(true) => return,
+                Ok(false) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if lifecycle.since_last_beat() > timeout {
+                        let message = format!(
+                            "Protection loop hung: no activity for {} seconds",
+                            lifecycle.since_last_beat().as_secs()
+                        );
+                        let report_path = write_crash_report(&crashes_path, "hang", &message)
+                            .unwrap_or_else(|| crashes_path.clone());
+                        report(connectors, IncidentKind::Hang, &message, report_path);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+
+        if lifecycle.is_stop_requested() {
+            return;
+        }
+        if started.elapsed() > STABLE_RUN {
+            restarts = 0;
+        }
+        restarts += 1;
+        if restarts > MAX_RESTARTS {
+            let message = format!("Protection loop crashed, giving up after {} restarts", MAX_RESTARTS);
+            report(connectors, IncidentKind::Panic, &message, crashes_path.clone());
+            std::process::exit(1);
+        }
+        let message = format!("Protection loop crashed, restart {}/{}", restarts, MAX_RESTARTS);
+        report(connectors, IncidentKind::Panic, &message, crashes_path.clone());
+        thread::sleep(Duration::from_secs(2u64.pow(restarts)));
+    }
+}
+
+/// Logs the panic and writes its context (location, message) with a minidump, before unwinding.
+fn set_panic_hook(crashes_path: PathBuf) {
+    panic::set_hook(Box::new(move |pi| {
+        error!("Critical error: {}", pi);
+        let thread = thread::current();
+        let context = format!(
+            "thread: {}\npanic: {}\n",
+            thread.name().unwrap_or("unnamed"),
+            pi
+        );
+        write_crash_report(&crashes_path, "panic", &context);
+    }));
+}
+
+fn report(connectors: &Connectors, kind: IncidentKind, message: &str, report_path: PathBuf) {
+    error!("Critical: {}", message);
+    connectors.send_incident(&Incident {
+        kind,
+        time: Local::now(),
+        message: String::from(message),
+        report_path,
+    });
+}
+
+/// Writes *crash_<time>_<kind>.txt* and the matching *.dmp* in *crashes_path*.
+fn write_crash_report(crashes_path: &Path, kind: &str, context: &str) -> Option<PathBuf> {
+    fs::create_dir_all(crashes_path).ok()?;
+    let basename = format!("crash_{}_{}", Local::now().format(FILE_TIME_FORMAT), kind);
+    let report_path = crashes_path.join(format!("{}.txt", basename));
+    let mut report = File::create(&report_path).ok()?;
+    writeln!(report, "time: {}", Local::now().to_rfc3339()).ok()?;
+    writeln!(report, "version: {}", env!("CARGO_PKG_VERSION")).ok()?;
+    write!(report, "{}", context).ok()?;
+
+    #[cfg(windows)]
+    {
+        let dump_path = crashes_path.join(format!("{}.dmp", basename));
+        if let Err(e) = write_minidump(&dump_path) {
+            error!("Cannot write minidump {}: {}", dump_path.display(), e);
+        }
+    }
+    Some(report_path)
+}
+
+#[cfg(windows)]
+fn write_minidump(path: &Path) -> Result<(), std::io::Error> {
+    let file = File::create(path)?;
+    let res = unsafe {
+        MiniDumpWriteDump(
+            GetCurrentProcess(),
+            GetCurrentProcessId(),
+            HANDLE(file.as_raw_handle() as isize),
+            MiniDumpWithThreadInfo,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if res.as_bool() {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
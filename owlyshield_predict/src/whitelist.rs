@@ -1,7 +1,7 @@
 use std::collections::HashSet;
 use std::fs::File;
 use std::{io, thread, time};
-use std::io::BufRead;
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
@@ -50,6 +50,45 @@ impl WhiteList {
         });
     }
 
+    /// Adds *appname* to the whitelist file. Returns false if it was already whitelisted.
+    /// The running agent picks the change at its next refresh.
+    pub fn add_to_file(path: &Path, appname: &str) -> Result<bool, std::io::Error> {
+        let mut appnames = Self::read_all(path)?;
+        if appnames.iter().any(|a| a == appname) {
+            return Ok(false);
+        }
+        appnames.push(String::from(appname));
+        Self::write_all(path, &appnames)?;
+        Ok(true)
+    }
+
+    /// Removes *appname* from the whitelist file. Returns false if it was not whitelisted.
+    pub fn remove_from_file(path: &Path, appname: &str) -> Result<bool, std::io::Error> {
+        let mut appnames = Self::read_all(path)?;
+        let len = appnames.len();
+        appnames.retain(|a| a != appname);
+        if appnames.len() == len {
+            return Ok(false);
+        }
+        Self::write_all(path, &appnames)?;
+        Ok(true)
+    }
+
+    fn read_all(path: &Path) -> Result<Vec<String>, std::io::Error> {
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        Self::load(path)?.collect()
+    }
+
+    fn write_all(path: &Path, appnames: &[String]) -> Result<(), std::io::Error> {
+        let mut file = File::create(path)?;
+        for appname in appnames.iter().filter(|a| !a.is_empty()) {
+            writeln!(file, "{}", appname)?;
+        }
+        Ok(())
+    }
+
     fn load(path: &Path) -> Result<io::Lines<io::BufReader<File>>, std::io::Error> {
         let file = File::open(path)?;
         let lines = io::BufReader::new(file).lines();
@@ -0,0 +1,196 @@
+//! Detection of the wipers, which delete the files instead of encrypting them: the model, trained
+//! on the entropy of the writes, does not see them.
+//!
+//! A gid deleting more than *WIPER_DELETED_FILES* files (*FILE_CHANGE_DELETE_FILE*: the files it
+//! created itself are not counted) within *WIPER_WINDOW_SECS* seconds, while writing almost no
+//! encrypted content, is reported without the model: a Critical [MassDeletion] event is sent to
+//! the connectors. It is killed at once with *WIPER_KILL* only. The deletions in the temporary
+//! and build directories are ignored.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Config, Param};
+use crate::correlation::IncidentRef;
+use crate::process::ProcessRecord;
+use crate::volumes::PathForms;
+
+/// Entropy (bits per byte) above which a write is considered encrypted.
+const ENCRYPTED_ENTROPY: f64 = 7.5;
+/// Encrypted writes tolerated per deleted file: beyond, it is a ransomware, left to the model.
+const MAX_ENCRYPTED_RATIO: f32 = 0.1;
+/// Directories of temporary files and build outputs, cleaned en masse by benign tools.
+const SCRATCH_DIRS: [&str; 10] = [
+    "/temp/",
+    "/tmp/",
+    "/inetcache/",
+    "/$recycle.bin/",
+    "/node_modules/",
+    "/target/",
+    "/obj/",
+    "/build/",
+    "/.git/",
+    "/__pycache__/",
+];
+
+/// Deletion rate of a gid.
+#[derive(Debug)]
+pub struct WipeMonitor {
+    max_deleted: usize,
+    window: Duration,
+    /// Times of the last deletions, at most *max_deleted* + 1
+    deletions: VecDeque<SystemTime>,
+    /// Times of the encrypted writes in the window
+    encrypted: VecDeque<SystemTime>,
+    detected: Option<usize>,
+    escalated: bool,
+}
+
+impl WipeMonitor {
+    pub fn from(config: &Config) -> WipeMonitor {
+        WipeMonitor::new(
+            config.get_usize(Param::WiperDeletedFiles),
+            Duration::from_secs(config.get_usize(Param::WiperWindowSecs) as u64),
+        )
+    }
+
+    pub fn new(max_deleted: usize, window: Duration) -> WipeMonitor {
+        WipeMonitor {
+            max_deleted,
+            window,
+            deletions: VecDeque::new(),
+            encrypted: VecDeque::new(),
+            detected: None,
+            escalated: false,
+        }
+    }
+
+    /// The file *path* has been deleted.
+    pub fn on_delete(&mut self, path: &str, now: SystemTime) {
+        if self.max_deleted == 0 || self.detected.is_some() || is_scratch(path) {
+            return;
+        }
+        self.deletions.push_back(now);
+        if self.deletions.len() <= self.max_deleted {
+            return;
+        }
+        let window = self.window;
+        let in_window = |t: &SystemTime| now.duration_since(*t).unwrap_or(Duration::ZERO) <= window;
+        self.encrypted.retain(in_window);
+        if self.deletions.front().is_some_and(in_window)
+            && (self.encrypted.len() as f32) < self.deletions.len() as f32 * MAX_ENCRYPTED_RATIO
+        {
+            self.detected = Some(self.deletions.len());
+        }
+        self.deletions.pop_front();
+    }
+
+    /// A write of *entropy* bits per byte.
+    pub fn on_write(&mut self, entropy: f64, now: SystemTime) {
+        if self.max_deleted == 0 || entropy < ENCRYPTED_ENTROPY {
+            return;
+        }
+        if self.encrypted.len() > self.max_deleted {
+            self.encrypted.pop_front();
+        }
+        self.encrypted.push_back(now);
+    }
+
+    /// The number of files deleted in the window, returned only once for the escalation.
+    pub fn take_escalation(&mut self) -> Option<usize> {
+        if self.escalated {
+            return None;
+        }
+        self.escalated = self.detected.is_some();
+        self.detected
+    }
+
+    /// Files deleted within the window, if detected.
+    pub fn detected(&self) -> Option<usize> {
+        self.detected
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+}
+
+fn is_scratch(path: &str) -> bool {
+    let path = path.to_lowercase().replace('\\', "/");
+    SCRATCH_DIRS.iter().any(|dir| path.contains(dir))
+}
+
+/// A gid deleting files en masse, without encrypting them.
+#[derive(Debug, Clone)]
+pub struct MassDeletion {
+    pub time: SystemTime,
+    pub gid: u64,
+    pub pid: u32,
+    pub appname: String,
+    pub exepath: PathBuf,
+    pub deleted: usize,
+    pub window: Duration,
+    /// Last file deleted
+    pub last_path: PathForms,
+    /// Of the [crate::enrichment] providers, added when sent to the connectors
+    pub tags: Vec<String>,
+    /// Of the [crate::correlation], added when sent to the connectors
+    pub incident: Option<IncidentRef>,
+}
+
+impl MassDeletion {
+    pub fn from(proc: &ProcessRecord, pid: u32, deleted: usize, last_path: &str) -> MassDeletion {
+        MassDeletion {
+            time: SystemTime::now(),
+            gid: proc.gid,
+            pid,
+            appname: proc.appname.clone(),
+            exepath: proc.exepath.clone(),
+            deleted,
+            window: proc.wiper.window(),
+            last_path: PathForms::of(last_path),
+            tags: Vec::new(),
+            incident: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::wiper::WipeMonitor;
+
+    #[test]
+    fn mass_deletion_without_encryption_should_escalate_once() {
+        let start = SystemTime::now();
+        let mut slow = WipeMonitor::new(10, Duration::from_secs(5));
+        for i in 0..50 {
+            slow.on_delete(r"C:\Users\bob\Documents\a.docx", start + Duration::from_secs(i));
+        }
+        assert_eq!(slow.take_escalation(), None);
+
+        let mut ransomware = WipeMonitor::new(10, Duration::from_secs(5));
+        for _ in 0..20 {
+            ransomware.on_write(7.9, start);
+            ransomware.on_delete(r"C:\Users\bob\Documents\a.docx", start);
+        }
+        assert_eq!(ransomware.take_escalation(), None);
+
+        let mut build = WipeMonitor::new(10, Duration::from_secs(5));
+        for _ in 0..20 {
+            build.on_delete(r"C:\src\app\target\debug\deps\a.rlib", start);
+            build.on_delete(r"C:\Users\bob\AppData\Local\Temp\a.tmp", start);
+        }
+        assert_eq!(build.take_escalation(), None);
+
+        let mut wiper = WipeMonitor::new(10, Duration::from_secs(5));
+        for _ in 0..11 {
+            wiper.on_write(4.2, start);
+            wiper.on_delete(r"C:\Users\bob\Documents\a.docx", start + Duration::from_secs(1));
+        }
+        assert_eq!(wiper.take_escalation(), Some(11));
+        assert_eq!(wiper.take_escalation(), None);
+    }
+}
@@ -1,101 +1,404 @@
+#[cfg(windows)]
 use std::collections::HashMap;
+#[cfg(windows)]
 use std::os::raw::c_ulong;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{fs, thread, time};
 use std::time::{Duration, SystemTime};
 
-use bindings::Windows::Win32::Foundation::{CloseHandle, HINSTANCE, PSTR};
-use bindings::Windows::Win32::System::Diagnostics::Debug::GetLastError;
-use bindings::Windows::Win32::System::ProcessStatus::K32GetModuleFileNameExA;
-use bindings::Windows::Win32::System::Threading::{
-    OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ,
-};
-use bindings::Windows::Win32::System::Diagnostics::Debug::{DebugActiveProcess, DebugActiveProcessStop, DebugSetProcessKillOnExit};
-use log::error;
+use tracing::{debug, error, info, info_span, warn};
 
 use crate::actions_on_kill::ActionsOnKill;
-use crate::config::{Config, KillPolicy, Param};
+use crate::anomaly::AnomalyModel;
+use crate::audit::AuditLog;
+use crate::autostart::Persistence;
+use crate::backup::BackupAgents;
+use crate::config::{Config, KillPolicy, Mode, Param};
+use crate::container::Containment;
+use crate::correlation::{incident_id, IncidentRef};
+#[cfg(windows)]
 use crate::csvwriter::CsvWriter;
-use crate::driver_com::shared_def::{CDriverMsg, IOMessage, RuntimeFeatures};
-use crate::driver_com::Driver;
-use crate::prediction::input_tensors::VecvecCappedF32;
-use crate::prediction::TfLite;
+#[cfg(windows)]
+use crate::driver_com::shared_def::RuntimeFeatures;
+#[cfg(windows)]
+use crate::driver_reply::DriverMsg;
+use crate::driver_com::shared_def::IOMessage;
+use crate::exclusions::{ExclusionScope, ExclusionSubject, Exclusions};
+use crate::extprofiles::ExtensionProfiles;
+use crate::identity::AgentIdentity;
+use crate::integrity::Integrity;
+use crate::iosource::IoEventSource;
+use crate::os;
+use crate::payloads;
+use crate::prediction::input_tensors::RollingFeatures;
 use crate::prediction_static::TfLiteStatic;
 use crate::process::procs::Procs;
 use crate::process::{ProcessRecord, ProcessState};
+use crate::reputation::Reputation;
+use crate::routing::{ModelRouter, ProcessCategory};
+use crate::cloudsync;
+use crate::escalation::{Escalated, Evidence, Level, Transition};
+use crate::events::{WorkerEvent, WorkerEvents};
+use crate::exfil::PreAlert;
+use crate::killcheck::KillRequest;
+use crate::isolation;
+use crate::journal;
+use crate::journal::{Action, Decision, Trigger};
+use crate::lolbin;
+use crate::ransomnote::RansomNoteDropped;
+use crate::scripthost;
+use crate::wiper::MassDeletion;
+use crate::service_ctl::Lifecycle;
+use crate::status::AgentStatus;
+#[cfg(windows)]
+use crate::token;
 use crate::whitelist::WhiteList;
+use crate::wsl;
 
-pub fn process_drivermessage<'a>(
-    driver: &Driver,
+/// Creates the record of a gid seen for the first time. Returns None if the gid is not monitored:
+/// excluded (it is then ignored in *procs*), whitelisted, a system process or already exited.
+///
+/// With *DRIVER_MUTE*, the gids never monitored because of the verified signer of their executable
+/// are muted in the *source*, which unmutes them at their next child or image load.
+#[allow(clippy::too_many_arguments)]
+pub fn new_process_record<'a>(
+    source: &dyn IoEventSource,
     config: &'a Config,
-    whitelist: &'a WhiteList,
-    procs: &mut Procs<'a>,
-    predictions_static: &mut HashMap<String, f32>,
-    tflite: &TfLite,
+    whitelist: &WhiteList,
+    exclusions: &Exclusions,
+    backup: &BackupAgents,
+    reputation: &Reputation,
+    extension_profiles: &ExtensionProfiles,
+    procs: &Mutex<Procs<'a>>,
     tflite_static: &TfLiteStatic,
     iomsg: &mut IOMessage,
-) -> Result<(), ()> {
-    // continue ? Processes without path should be ignored
-    let mut opt_index = procs.get_by_gid_index(iomsg.gid);
-    if opt_index.is_none() {
-        //        if let Some((appname, exepath)) = appname_from_pid(iomsg) {
-        if let Some(exepath) = exepath_from_pid(iomsg) {
-            iomsg.runtime_features.exepath = exepath.clone();
-            iomsg.runtime_features.exe_still_exists = true;
-            let appname = appname_from_exepath(&exepath).unwrap_or(String::from("DEFAULT"));
-            if !whitelist.is_app_whitelisted(&appname) {
-                // println!("ADD RECORD {} - {}", iomsg.gid, appname);
-                if !exepath.parent().unwrap_or(Path::new("/")).starts_with(r"C:\Windows\System32") {
-                    let record = ProcessRecord::from(&config, iomsg, appname, exepath.clone(), tflite_static.make_prediction(&exepath));
-                    procs.add_record(record);
-                    opt_index = procs.get_by_gid_index(iomsg.gid);
+) -> Option<ProcessRecord<'a>> {
+    // Processes without path are ignored
+    if let Some(exepath) = os::exepath_from_pid(iomsg.pid) {
+        iomsg.runtime_features.exepath = exepath.clone();
+        iomsg.runtime_features.exe_still_exists = true;
+        let appname = appname_from_exepath(&exepath).unwrap_or(String::from("DEFAULT"));
+        let mut subject = ExclusionSubject::new(&exepath, iomsg.pid);
+        let cached_scope = procs.lock().unwrap().exclusion_scope(iomsg.gid);
+        let exclusion_scope = cached_scope.unwrap_or_else(|| {
+            let scope = exclusions.get_scope(&mut subject);
+            procs.lock().unwrap().set_exclusion_scope(iomsg.gid, scope);
+            scope
+        });
+        if exclusion_scope == Some(ExclusionScope::NeverMonitor) {
+            procs.lock().unwrap().ignore_gid(iomsg.gid);
+            mute_benign_gid(source, config, exclusions, iomsg.gid, &mut subject);
+            return None;
+        }
+        // the LOLBins are assessed by what they run, see crate::lolbin
+        let is_lolbin = lolbin::is_lolbin(&exepath);
+        if !whitelist.is_app_whitelisted(&appname) || is_lolbin {
+            // println!("ADD RECORD {} - {}", iomsg.gid, appname);
+            if is_lolbin || !exepath.parent().unwrap_or(Path::new("/")).starts_with(r"C:\Windows\System32") {
+                let mut record = ProcessRecord::from(&config, iomsg, appname, exepath.clone(), tflite_static.make_prediction(&exepath));
+                record.never_kill = exclusion_scope == Some(ExclusionScope::NeverKill);
+                if let Some(threshold) = exclusions.get_user_policy(&mut subject).and_then(|p| p.threshold_prediction) {
+                    record.threshold_prediction = threshold;
+                    record.policy_threshold = Some(threshold);
                 }
+                record.owner = subject.owner().cloned();
+                record.category = ProcessCategory::of(&iomsg.runtime_features, record.owner.as_ref());
+                record.backup_agent = backup.detect(&mut subject);
+                record.backup_job = record.backup_agent.is_some_and(|agent| backup.is_job_running(agent));
+                record.lolbin = lolbin::capture(&exepath, iomsg.pid);
+                record.reputation = match &record.lolbin {
+                    Some(lolbin) => Some(lolbin.prior()),
+                    None => reputation.assess(&mut subject),
+                };
+                record.extension_usage = extension_profiles.usage_of(&exepath);
+                record.script = scripthost::capture(config, &exepath, iomsg.pid);
+                record.containment = Containment::of(iomsg.pid);
+                record.integrity = Integrity::of(iomsg.pid, &exepath);
+                record.wsl = wsl::is_wsl_host(&exepath).then(Vec::new);
+                return Some(record);
             }
-        } else {
-            iomsg.runtime_features.exe_still_exists = false;
         }
+    } else {
+        iomsg.runtime_features.exe_still_exists = false;
     }
-    if opt_index.is_some() {
-        let proc = procs.procs.get_mut(opt_index.unwrap()).unwrap();
-        proc.add_irp_record(iomsg);
-        // println!("RECORD - {:?}", proc.appname);
-        // proc.write_learn_csv(); //debug
-        if let Some((predmtrx, prediction)) = proc.eval(tflite) {
-            println!("{} - {}", proc.appname, prediction);
-            if prediction > config.threshold_prediction || proc.appname.contains("TEST-OLRANSOM")
-                // || proc.appname.contains("msedge.exe") //For testing
-            {
-                println!("Ransomware Suspected!!!");
-                eprintln!("proc.gid = {:?}", proc.gid);
-                println!("{}", proc.appname);
-                println!("with {} certainty", prediction);
-                println!("\nSee {}\\threats for details.", config[Param::DebugPath]);
-                println!(
-                    "\nPlease update {}\\exclusions.txt if it's a false positive",
-                    config[Param::ConfigPath]
-                );
+    None
+}
 
-                match config.get_kill_policy() {
-                    KillPolicy::Suspend => {
-                        if proc.process_state != ProcessState::Suspended {
-                            try_suspend(proc);
-                        }
-                    }
-                    KillPolicy::Kill => { try_kill(driver, proc) }
-                }
-                ActionsOnKill::new().run_actions(&config, &proc, &predmtrx, prediction);
-            }
+/// Stops the events of *gid*, never monitored, if *DRIVER_MUTE* and if it is excluded by the
+/// verified signer of its executable.
+fn mute_benign_gid(source: &dyn IoEventSource, config: &Config, exclusions: &Exclusions, gid: u64, subject: &mut ExclusionSubject) {
+    if !config.get_bool(Param::DriverMute) || !exclusions.is_never_monitored_signer(subject) {
+        return;
+    }
+    match source.mute_gid(gid) {
+        Ok(()) => debug!(gid, exepath = %subject.exepath.display(), "Muted in the driver"),
+        Err(e) => warn!(gid, "{}", e),
+    }
+}
+
+/// Aggregates *iomsg* in the record of its gid, then makes a prediction and acts if needed.
+#[allow(clippy::too_many_arguments)]
+pub fn process_drivermessage(
+    source: &dyn IoEventSource,
+    config: &Config,
+    proc: &mut ProcessRecord,
+    models: &ModelRouter,
+    tflite_static: &TfLiteStatic,
+    anomaly: Option<&AnomalyModel>,
+    lifecycle: &Lifecycle,
+    audit: &AuditLog,
+    status: &AgentStatus,
+    events: &WorkerEvents,
+    iomsg: &IOMessage,
+) {
+    proc.add_irp_record(iomsg);
+    payloads::scan_queued(config, proc, tflite_static);
+    // println!("RECORD - {:?}", proc.appname);
+    // proc.write_learn_csv(); //debug
+    if let Some(verdict) = proc.fast_path.take_escalation() {
+        let span = info_span!("process", gid = proc.gid, pid = iomsg.pid, appname = %proc.appname, user = %proc.user());
+        let _enter = span.enter();
+        warn!(%verdict, "Ransomware detected without the model");
+        let predmtrx = proc.prediction_matrix.clone();
+        act_on_malicious(source, config, proc, lifecycle, audit, status, events, &predmtrx, 1.0, Trigger::FastPath);
+        return;
+    }
+    if let Some(deleted) = proc.wiper.take_escalation() {
+        let span = info_span!("process", gid = proc.gid, pid = iomsg.pid, appname = %proc.appname, user = %proc.user());
+        let _enter = span.enter();
+        let event = MassDeletion::from(proc, iomsg.pid, deleted, &iomsg.filepathstr);
+        error!(deleted, window_secs = event.window.as_secs(), last_path = %event.last_path, "Critical: mass file deletion without encryption");
+        events.push(WorkerEvent::MassDeletion(event));
+        if config.get_bool(Param::WiperKill) {
+            let predmtrx = proc.prediction_matrix.clone();
+            act_on_malicious(source, config, proc, lifecycle, audit, status, events, &predmtrx, 1.0, Trigger::MassDeletion);
+            return;
+        }
+    }
+    if let Some(note) = proc.ransom_note.take_escalation() {
+        let event = RansomNoteDropped::from(proc, iomsg.pid, note);
+        error!(
+            gid = proc.gid,
+            appname = %proc.appname,
+            path = %event.path,
+            dirs = event.note.dirs,
+            language = event.note.analysis.language.as_ref().map_or("-", |l| l.code),
+            families = %event.note.analysis.families().join(", "),
+            "Critical: ransom note dropped"
+        );
+        events.push(WorkerEvent::RansomNote(event));
+    }
+    for archive in proc.exfil.take_staged() {
+        let event = PreAlert::from(proc, iomsg.pid, archive);
+        warn!(
+            gid = proc.gid,
+            appname = %proc.appname,
+            archive = %event.archive.path.display(),
+            size = event.archive.size,
+            docs_read = event.archive.docs_read,
+            "PreAlert: documents archived for exfiltration"
+        );
+        events.push(WorkerEvent::PreAlert(event));
+    }
+    for entry in proc.autostart.take_new() {
+        warn!(gid = proc.gid, appname = %proc.appname, pid = entry.pid, source = %entry.source, target = %entry.target, "Persistence: {} created", entry.kind);
+        events.push(WorkerEvent::Persistence(Persistence::from(proc, entry)));
+    }
+    let tflite = models.route(proc.category);
+    if let Some((predmtrx, prediction)) = proc.eval(tflite, anomaly) {
+        let span = info_span!("process", gid = proc.gid, pid = iomsg.pid, appname = %proc.appname, user = %proc.user());
+        let _enter = span.enter();
+        debug!(prediction, "Prediction");
+        proc.threshold_prediction = threshold(config, proc);
+        let version = anomaly.map_or_else(|| tflite.version().to_string(), |anomaly| anomaly.versions(tflite));
+        audit.write(proc, &version, prediction, &predmtrx[predmtrx.rows_len() - 1]);
+        status.follow.on_prediction(proc.gid, &predmtrx[predmtrx.rows_len() - 1], prediction, proc.threshold_prediction);
+        if let Some(transition) = proc.escalation.on_prediction(prediction, proc.threshold_prediction, SystemTime::now()) {
+            escalate(source, config, proc, tflite_static, events, transition, prediction);
+        }
+        if (proc.escalation.level() == Level::Alert && prediction > proc.threshold_prediction)
+            || proc.appname.contains("TEST-OLRANSOM")
+            // || proc.appname.contains("msedge.exe") //For testing
+        {
+            act_on_malicious(source, config, proc, lifecycle, audit, status, events, &predmtrx, prediction, Trigger::Model);
+        }
+    }
+}
+
+/// Runs the scans of Watch, or sends the PreAlert, of a gid going up its [crate::escalation]
+/// ladder. Nothing is delayed for a gid going straight to Alert.
+///
+/// With *CAPTURE*, the files of the gid are captured from Watch until it is back to Normal.
+fn escalate(
+    source: &dyn IoEventSource,
+    config: &Config,
+    proc: &mut ProcessRecord,
+    tflite_static: &TfLiteStatic,
+    events: &WorkerEvents,
+    transition: Transition,
+    prediction: f32,
+) {
+    info!(prediction, from = %transition.from, to = %transition.to, "Escalation");
+    if config.get_bool(Param::Capture) {
+        set_capture(source, proc.gid, transition);
+    }
+    if transition.to == Level::Alert {
+        return;
+    }
+    if transition.entered(Level::Watch) {
+        proc.escalation.evidence = Evidence::collect(proc, tflite_static);
+        let evidence = &proc.escalation.evidence;
+        if evidence.is_suspicious() {
+            warn!(
+                memory = %evidence.memory.map_or(String::from("-"), |v| v.to_string()),
+                dropped = evidence.dropped.len(),
+                "Watched: suspicious memory or dropped executables"
+            );
         }
-        Ok(())
+        let pids: Vec<u32> = proc.pids.iter().copied().collect();
+        proc.fingerprint.scan(&pids, &proc.exepath);
+        if let Some(suspected) = proc.fingerprint.suspected_family() {
+            warn!(%suspected, "Watched: suspected ransomware family");
+        }
+    }
+    if transition.entered(Level::PreAlert) {
+        warn!(prediction, threshold = proc.threshold_prediction, "PreAlert: approaching the threshold");
+        events.push(WorkerEvent::Escalated(Escalated::from(proc, prediction)));
+    }
+}
+
+/// Starts the [crate::capture] of a gid entering Watch, stops it when it is back to Normal.
+fn set_capture(source: &dyn IoEventSource, gid: u64, transition: Transition) {
+    let capture = if transition.entered(Level::Watch) {
+        true
+    } else if transition.to == Level::Normal {
+        false
     } else {
-        Err(())
+        return;
+    };
+    match source.capture_gid(gid, capture) {
+        Ok(()) => debug!(gid, capture, "Capture set in the driver"),
+        Err(e) => warn!(gid, "{}", e),
     }
 }
 
+/// The threshold of *proc*: of the [crate::exclusions::UserPolicy] of its owner or of the active
+/// profile, lowered by *CLOUD_SYNC_THRESHOLD* and *NETWORK_SHARE_THRESHOLD* if it writes in the
+/// cloud sync folders or on the network shares.
+fn threshold(config: &Config, proc: &ProcessRecord) -> f32 {
+    let mut threshold = proc.policy_threshold.unwrap_or_else(|| config.get_threshold_prediction());
+    let cloud_sync = config.get_f32(Param::CloudSyncThreshold);
+    if cloud_sync > 0.0 && !proc.sync_clients.is_empty() {
+        threshold = threshold.min(cloud_sync);
+    }
+    let network_share = config.get_f32(Param::NetworkShareThreshold);
+    if network_share > 0.0 && proc.files_written_remote.len() >= config.get_usize(Param::NetworkShareMinFiles) {
+        threshold = threshold.min(network_share);
+    }
+    threshold
+}
+
+/// Suspends or kills *proc* according to the *KILL_POLICY*, then runs the [ActionsOnKill]. The
+/// alert is published in any case. The decision is journaled before the action ([journal]).
+///
+/// In the *AUDIT* and *LEARNING* [Mode]s, *proc* is only reported, the first time.
+#[allow(clippy::too_many_arguments)]
+fn act_on_malicious(
+    source: &dyn IoEventSource,
+    config: &Config,
+    proc: &mut ProcessRecord,
+    lifecycle: &Lifecycle,
+    audit: &AuditLog,
+    status: &AgentStatus,
+    events: &WorkerEvents,
+    predmtrx: &RollingFeatures,
+    prediction: f32,
+    trigger: Trigger,
+) {
+    if let Some(linux) = proc.wsl.as_ref().filter(|linux| !linux.is_empty()) {
+        let linux: Vec<String> = linux.iter().map(|p| p.to_string()).collect();
+        warn!(linux_processes = %linux.join(", "), "Activity of WSL, from the Linux processes");
+    }
+    if proc.never_kill {
+        info!(prediction, "Excluded from kills");
+        status.push_alert(proc, prediction);
+        return;
+    }
+    if lifecycle.is_paused() {
+        info!(prediction, "Not killed: the service is paused");
+        status.push_alert(proc, prediction);
+        return;
+    }
+    let mode = config.get_mode();
+    if mode != Mode::Protect {
+        if !proc.would_kill {
+            proc.would_kill = true;
+            warn!(prediction, ?mode, "Ransomware suspected, would have been killed");
+            status.push_alert(proc, prediction);
+            if mode == Mode::Learning {
+                audit.write_would_kill(proc, prediction);
+            }
+            run_actions_on_kill(config, proc, status, predmtrx, prediction);
+        }
+        return;
+    }
+    warn!(
+        prediction,
+        "Ransomware Suspected!!! See {}\\threats for details. Please update {}\\exclusions.txt if it's a false positive",
+        config.get_path(Param::DebugPath).display(),
+        config.get_path(Param::ConfigPath).display()
+    );
+
+    if config.get_bool(Param::CloudSyncPause) && !proc.sync_clients.is_empty() {
+        cloudsync::pause_clients(config, &proc.sync_clients);
+    }
+    match config.get_kill_policy() {
+        KillPolicy::Suspend => {
+            if proc.process_state != ProcessState::Suspended {
+                journal::record(config, &Decision::of(config, proc, Action::Suspend, trigger, prediction, predmtrx));
+                try_suspend(proc);
+            }
+        }
+        KillPolicy::Kill => {
+            journal::record(config, &Decision::of(config, proc, Action::Kill, trigger, prediction, predmtrx));
+            try_kill(source, proc, events, prediction)
+        }
+    }
+    isolation::isolate(config, proc);
+    status.push_alert(proc, prediction);
+    run_actions_on_kill(config, proc, status, predmtrx, prediction);
+}
+
+/// The incident reports and notifications of *proc*, unless its executable was already detected
+/// by Defender ([crate::defender]).
+fn run_actions_on_kill(config: &Config, proc: &ProcessRecord, status: &AgentStatus, predmtrx: &RollingFeatures, prediction: f32) {
+    match status.av.detection_of(&proc.exepath) {
+        Some(detection) => info!(
+            threat = %detection.threat,
+            action = %detection.action,
+            "Already detected by Windows Defender, the alert is not repeated"
+        ),
+        None => {
+            let incident = detection_incident(config, proc, status, prediction);
+            ActionsOnKill::new().run_actions(config, proc, predmtrx, prediction, &incident)
+        }
+    }
+}
+
+/// The incident of the detection of *proc*, shared with its events sent to the connectors (see
+/// [crate::correlation]).
+fn detection_incident(config: &Config, proc: &ProcessRecord, status: &AgentStatus, prediction: f32) -> IncidentRef {
+    status.incidents.correlate(proc.gid, "detection", Some(prediction), SystemTime::now(), || {
+        incident_id(&AgentIdentity::load(config).machine_id, proc.gid, &proc.exepath)
+    })
+}
+
 pub fn process_drivermessage_replay<'a>(
     config: &'a Config,
     procs: &mut Procs<'a>,
-    tflite: &TfLite,
+    models: &ModelRouter,
+    anomaly: Option<&AnomalyModel>,
     iomsg: &IOMessage,
 ) {
     let mut opt_index = procs.get_by_gid_index(iomsg.gid);
@@ -104,7 +407,8 @@ pub fn process_drivermessage_replay<'a>(
         let appname = appname_from_exepath(&exepath).unwrap_or(String::from("DEFAULT"));
         //if appname.contains("Virus") {
         //println!("ADD RECORD {} - {}", iomsg.gid, appname);
-        let record = ProcessRecord::from(&config, iomsg, appname, exepath, None);
+        let mut record = ProcessRecord::from(&config, iomsg, appname, exepath, None);
+        record.category = ProcessCategory::of(&iomsg.runtime_features, None);
         procs.add_record(record);
         opt_index = procs.get_by_gid_index(iomsg.gid);
         // }
@@ -113,45 +417,12 @@ pub fn process_drivermessage_replay<'a>(
         let proc = procs.procs.get_mut(opt_index.unwrap()).unwrap();
         proc.add_irp_record(iomsg);
         proc.write_learn_csv();
-        if let Some((_predmtrx, prediction)) = proc.eval(tflite) {
-            if prediction > config.threshold_prediction {
-                println!("Record {}: {}", proc.appname, prediction);
-                println!("########");
-            }
-        }
-    }
-}
-
-fn exepath_from_pid(iomsg: &IOMessage) -> Option<PathBuf> {
-    let pid = iomsg.pid.clone() as u32;
-    //println!("PID {}", pid);
-    unsafe {
-        let handle = OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid);
-        if handle.is_invalid() || handle.0 == 0 {
-            //println!("ERROR: Invalid Handle: {} - {}", drivermsg.pid, GetLastError().0);
-        } else {
-            let mut buffer: Vec<u8> = Vec::new();
-            buffer.resize(1024, 0);
-            //println!("HANDLE is {:?}", handle);
-            // let res = GetModuleFileNameA(HINSTANCE(handle.0), PSTR(buffer.as_mut_ptr()), 1024);
-            let res =
-                K32GetModuleFileNameExA(handle, HINSTANCE(0), PSTR(buffer.as_mut_ptr()), 1024);
-            if res == 0 {
-                let _errorcode = GetLastError().0;
-                /*if errorcode != 31 {
-                    println!("ERROR: {} - {:?}", errorcode, drivermsg);
-                }*/
-            } else {
-                let pathbuf =
-                    PathBuf::from(String::from_utf8_unchecked(buffer).trim_matches(char::from(0)));
-                //println!("PATHBUF: {:?}", pathbuf);
-                //println!("FILENAME: {}", appname_from_exepath(&pathbuf).unwrap_or("DEFAULT".parse().unwrap()));
-                return Some(pathbuf);
+        if let Some((_predmtrx, prediction)) = proc.eval(models.route(proc.category), anomaly) {
+            if prediction > proc.threshold_prediction {
+                info!(gid = proc.gid, appname = %proc.appname, prediction, "Record above threshold");
             }
-            CloseHandle(handle);
         }
     }
-    None
 }
 
 fn appname_from_exepath(exepath: &PathBuf) -> Option<String> {
@@ -168,51 +439,67 @@ fn try_suspend(
     // println!("suspend!");
     // eprintln!("proc.gid = {:?}", proc.gid);
     proc.process_state = ProcessState::Suspended;
+    proc.history.keep();
 
     for pid in &proc.pids {
-        unsafe {
-                DebugActiveProcess(*pid as u32);
-        }
+        os::suspend_pid(*pid);
     }
 }
 
 fn try_awake(proc: &mut ProcessRecord, kill_proc_on_exit: bool) {
     for pid in &proc.pids {
-        unsafe {
-            DebugSetProcessKillOnExit(kill_proc_on_exit);
-            DebugActiveProcessStop(*pid as u32);
-        }
+        os::resume_pid(*pid, kill_proc_on_exit);
     }
     proc.process_state = ProcessState::Running;
 }
 
+/// Kills the gid of *proc*, then queues the verification of the kill, see [crate::killcheck]. Its
+/// processes are first terminated at once in a job object ([os::terminate_group]), then by the
+/// driver.
 fn try_kill(
-    driver: &Driver,
+    source: &dyn IoEventSource,
     proc: &mut ProcessRecord,
+    events: &WorkerEvents,
+    prediction: f32,
 ) {
     // println!("Try kill !");
     // eprintln!("proc.gid = {:?}", proc.gid);
-    let hres = driver.try_kill(proc.gid).expect("Cannot kill process");
-    if hres.is_err() {
-        error!("Cannot kill process {} with gid {}", proc.appname, proc.gid);
+    proc.history.keep();
+    let pids: Vec<u32> = proc.pids.iter().copied().collect();
+    // while its mutexes are still held
+    proc.fingerprint.scan(&pids, &proc.exepath);
+    match os::terminate_group(&pids) {
+        Ok(terminated) => debug!(gid = proc.gid, terminated, in_job = proc.containment.in_job, "Process group terminated"),
+        Err(code) => debug!(gid = proc.gid, code, "Cannot terminate the process group"),
+    }
+    let res = source.kill_gid(proc.gid);
+    if let Err(e) = &res {
+        error!("Cannot kill process {} with gid {}: {}", proc.appname, proc.gid, e);
+    }
+    for gid in &proc.merged_gids {
+        if let Err(e) = source.kill_gid(*gid) {
+            error!("Cannot kill process {} with gid {}: {}", proc.appname, gid, e);
+        }
     }
     proc.process_state = ProcessState::Killed;
     proc.time_killed = Some(SystemTime::now());
+    events.push(WorkerEvent::KillIssued(KillRequest::from(proc, prediction, res.err().map(|e| e.to_string()))));
 }
 
+#[cfg(windows)]
 pub fn record_drivermessage<'a>(
     path: &Path,
     pids_exepaths: &mut HashMap<c_ulong, PathBuf>,
-    c_drivermsg: &CDriverMsg,
+    drivermsg: &DriverMsg,
 ) {
     let irp_csv = path;
     let mut irp_csv_writer;
     irp_csv_writer = CsvWriter::from_path(irp_csv);
-    let mut iomsg = IOMessage::from(&c_drivermsg);
+    let mut iomsg = IOMessage::from(drivermsg);
 
     let o_exepath: Option<PathBuf>;
 
-    if let Some(exepath) = exepath_from_pid(&iomsg) {
+    if let Some(exepath) = os::exepath_from_pid(iomsg.pid) {
         pids_exepaths.insert(iomsg.pid, exepath.clone()); //because pids can be reused
         o_exepath = Some(exepath)
     } else {
@@ -224,6 +511,11 @@ pub fn record_drivermessage<'a>(
         let runtime_features = RuntimeFeatures {
             exepath: exepath,
             exe_still_exists: exepath_exists,
+            time: iomsg.runtime_features.time,
+            sync_client: iomsg.runtime_features.sync_client,
+            remote: iomsg.runtime_features.remote,
+            driver_gid: iomsg.runtime_features.driver_gid,
+            privileges: token::privileges_from_pid(iomsg.pid),
         };
         iomsg.runtime_features = runtime_features;
         let buf = rmp_serde::to_vec(&iomsg).unwrap();
@@ -231,25 +523,24 @@ pub fn record_drivermessage<'a>(
             .write_irp_csv_files(&buf)
             .expect("Cannot write irps file");
     }
-    iomsg.file_size = match PathBuf::from(&c_drivermsg.filepath.to_string_ext(c_drivermsg.extension)).metadata() {
-        Ok(f) => f.len() as i64,
-        Err(e) => -1,
-    }
 }
 
-pub fn process_suspended_procs<'a>(driver: &Driver, config: &Config, procs: &mut Procs<'a>) {
+pub fn process_suspended_procs<'a>(source: &dyn IoEventSource, config: &Config, status: &AgentStatus, events: &WorkerEvents, procs: &mut Procs<'a>) {
     let now = SystemTime::now();
     for proc in &mut procs.procs {
         if proc.process_state == ProcessState::Suspended {
             if now.duration_since(proc.time_suspended.unwrap_or(now)).unwrap_or(Duration::from_secs(0)) > Duration::from_secs(120) {
+                let prediction = proc.predictions.get_last_prediction().unwrap_or(0.0);
+                journal::record(config, &Decision::of(config, proc, Action::Kill, Trigger::SuspendTimeout, prediction, &proc.prediction_matrix));
                 try_awake(proc, true);
-                try_kill(driver, proc);
-                ActionsOnKill::new().run_actions(&config, &proc, &proc.prediction_matrix.clone(), proc.predictions.get_last_prediction().unwrap_or(0.0));
+                try_kill(source, proc, events, prediction);
+                let incident = detection_incident(config, proc, status, prediction);
+                ActionsOnKill::new().run_actions(&config, &proc, &proc.prediction_matrix.clone(), prediction, &incident);
             }
         }
     }
 
-    let command_files_path = Path::new(&config[Param::ConfigPath]).join("tmp");
+    let command_files_path = config.get_path(Param::ConfigPath).join("tmp");
     if command_files_path.exists() {
         for command_file_dir_entry in fs::read_dir(command_files_path).unwrap() {
             let pbuf_command_file = command_file_dir_entry.unwrap().path();
@@ -262,19 +553,20 @@ pub fn process_suspended_procs<'a>(driver: &Driver, config: &Config, procs: &mut
                                     let proc = procs.procs.get_mut(proc_index).unwrap();
                                     match command {
                                         "A" => {
-                                            println!("awake !");
+                                            info!(gid, appname = %proc.appname, "Awake command");
                                             try_awake(proc, false);
                                         }
                                         "K" => {
-                                            println!("FILE K DETECTED");
+                                            info!(gid, appname = %proc.appname, "Kill command");
+                                            let prediction = proc.predictions.get_last_prediction().unwrap_or(0.0);
+                                            journal::record(config, &Decision::of(config, proc, Action::Kill, Trigger::Command, prediction, &proc.prediction_matrix));
                                             try_awake(proc, true);
-                                            try_kill(&driver, proc);
+                                            try_kill(source, proc, events, prediction);
                                         }
                                         &_ => {}
                                     }
                                     if ! fs::remove_file(pbuf_command_file.as_path()).is_ok() {
-                                        println!("cannot remove");
-                                        eprintln!("pbuf_command_file = {:?}", pbuf_command_file);
+                                        error!("Cannot remove command file {}", pbuf_command_file.display());
                                         // try_kill(driver, proc);
                                         // ActionsOnKill::new().run_actions(&config, &proc, &proc.prediction_matrix.clone(), proc.predictions.get_last_prediction().unwrap_or(0.0));
                                     }
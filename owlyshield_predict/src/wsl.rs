@@ -0,0 +1,131 @@
+//! Activity of the Windows Subsystem for Linux. With WSL2, the files of the Windows drives
+//! (*/mnt/c*) are served to the Linux processes by the Plan 9 server of the WSL host processes
+//! ([HOSTS]): the driver sees all of their writes under one gid, whatever the Linux process.
+//!
+//! These gids are tagged ([crate::process::ProcessRecord::wsl]), so that they get their own policy
+//! (*wsl = true* in the user policies of [crate::exclusions]). Every [REFRESH_INTERVAL], while
+//! such a gid is monitored, the Linux processes of the running distributions with files open on
+//! the Windows drives are listed ([inventory]), to attribute the activity in the alerts and the
+//! status.
+
+use std::fmt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use serde::Serialize;
+use tracing::debug;
+
+/// Period of the inventory of the Linux processes.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Executables of the WSL hosts, lowercase.
+const HOSTS: [&str; 5] = ["vmmem", "vmmemwsl", "wslhost.exe", "wslservice.exe", "wslrelay.exe"];
+/// Lists, for each file descriptor on a Windows drive: pid, command and path.
+const FDS_SCRIPT: &str = r#"for fd in /proc/[0-9]*/fd/*; do t=$(readlink "$fd" 2>/dev/null); case "$t" in /mnt/[a-z]/*) p=${fd#/proc/}; p=${p%%/*}; echo "$p $(cat /proc/$p/comm 2>/dev/null) $t";; esac; done"#;
+/// Linux processes kept per gid, the ones with the most files open first.
+const MAX_PROCESSES: usize = 8;
+
+/// A Linux process with files open on the Windows drives.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LinuxProcess {
+    pub distro: String,
+    pub pid: u32,
+    pub comm: String,
+    /// Files open on the Windows drives
+    pub files: usize,
+}
+
+impl fmt::Display for LinuxProcess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}, pid {}, {} files)", self.comm, self.distro, self.pid, self.files)
+    }
+}
+
+/// Is *exepath* a WSL host?
+pub fn is_wsl_host(exepath: &Path) -> bool {
+    exepath
+        .file_name()
+        .is_some_and(|name| HOSTS.contains(&name.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// The Linux processes of the running distributions with files open on the Windows drives. Empty
+/// without WSL.
+pub fn inventory() -> Vec<LinuxProcess> {
+    let distros = match wsl(&["--list", "--quiet", "--running"]) {
+        Some(output) => parse_distros(&output),
+        None => return Vec::new(),
+    };
+    let mut processes = Vec::new();
+    for distro in distros {
+        if let Some(output) = wsl(&["--distribution", &distro, "--user", "root", "--exec", "sh", "-c", FDS_SCRIPT]) {
+            processes.extend(parse_fds(&distro, &String::from_utf8_lossy(&output)));
+        }
+    }
+    processes.sort_by_key(|p| std::cmp::Reverse(p.files));
+    processes.truncate(MAX_PROCESSES);
+    processes
+}
+
+fn wsl(args: &[&str]) -> Option<Vec<u8>> {
+    let output = Command::new("wsl.exe")
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| debug!("Cannot run wsl.exe: {}", e))
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+/// *wsl.exe --list* writes UTF-16LE.
+fn parse_distros(output: &[u8]) -> Vec<String> {
+    let utf16: Vec<u16> = output.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    String::from_utf16_lossy(&utf16)
+        .lines()
+        .map(|line| line.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}' || c == '\0'))
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The processes of the output of [FDS_SCRIPT], with their number of files.
+fn parse_fds(distro: &str, output: &str) -> Vec<LinuxProcess> {
+    let mut processes: Vec<LinuxProcess> = Vec::new();
+    for line in output.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let (pid, comm) = match (fields.next().and_then(|p| p.parse().ok()), fields.next()) {
+            (Some(pid), Some(comm)) if !comm.is_empty() => (pid, comm),
+            _ => continue,
+        };
+        match processes.iter_mut().find(|p| p.pid == pid) {
+            Some(process) => process.files += 1,
+            None => processes.push(LinuxProcess {
+                distro: distro.to_string(),
+                pid,
+                comm: comm.to_string(),
+                files: 1,
+            }),
+        }
+    }
+    processes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wsl::{parse_distros, parse_fds};
+
+    #[test]
+    fn linux_processes_should_be_counted_by_files() {
+        let list: Vec<u8> = "Ubuntu\r\nDebian\r\n".encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        assert_eq!(parse_distros(&list), vec!["Ubuntu", "Debian"]);
+
+        let output = "812 openssl /mnt/c/Users/bob/Documents/a.docx\n\
+                      812 openssl /mnt/c/Users/bob/Documents/a.docx.enc\n\
+                      90 vim /mnt/d/notes.txt\n\
+                      garbage\n";
+        let processes = parse_fds("Ubuntu", output);
+        assert_eq!(processes.len(), 2);
+        assert_eq!((processes[0].comm.as_str(), processes[0].files), ("openssl", 2));
+        assert_eq!(processes[1].to_string(), "vim (Ubuntu, pid 90, 1 files)");
+    }
+}
@@ -0,0 +1,97 @@
+//! YARA scan of a file, with the command line scanner of *YARA_PATH* (*yara64.exe*) and the rules
+//! of *YARA_RULES*: a source file, or compiled by *yarac* if its extension is *.yarc*.
+//!
+//! The scanner is run in its own process, killed after [SCAN_TIMEOUT], so that a bad rule or file
+//! cannot hang a worker.
+
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::config::{Config, Param};
+
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum YaraError {
+    #[error("cannot run the scanner: {0}")]
+    Io(io::Error),
+    #[error("no result after {:?}", SCAN_TIMEOUT)]
+    Timeout,
+    /// Exit code and error output of the scanner
+    #[error("exit code {0:?}: {1}")]
+    Failed(Option<i32>, String),
+}
+
+/// Names of the rules matched by *path*. Empty without *YARA_PATH* or *YARA_RULES*.
+pub fn scan(config: &Config, path: &Path) -> Result<Vec<String>, YaraError> {
+    let (yara, rules) = match (config.get_str(Param::YaraPath), config.get_str(Param::YaraRules)) {
+        (yara, rules) if yara == "NONE" || rules == "NONE" => return Ok(Vec::new()),
+        (yara, rules) => (PathBuf::from(yara), PathBuf::from(rules)),
+    };
+    let mut command = Command::new(yara);
+    if rules.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("yarc")) {
+        command.arg("-C");
+    }
+    let mut child = command
+        .arg("--no-warnings")
+        .arg(&rules)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(YaraError::Io)?;
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(YaraError::Io)? {
+            break status;
+        }
+        if started.elapsed() >= SCAN_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(YaraError::Timeout);
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    if let Some(mut out) = child.stdout.take() {
+        out.read_to_string(&mut stdout).map_err(YaraError::Io)?;
+    }
+    if let Some(mut err) = child.stderr.take() {
+        err.read_to_string(&mut stderr).map_err(YaraError::Io)?;
+    }
+    if !status.success() {
+        return Err(YaraError::Failed(status.code(), stderr.trim().to_string()));
+    }
+    Ok(parse_matches(&stdout))
+}
+
+/// The rules of the output of the scanner, a line *rule path* per match.
+fn parse_matches(stdout: &str) -> Vec<String> {
+    let mut rules: Vec<String> = stdout
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect();
+    rules.dedup();
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::yara::parse_matches;
+
+    #[test]
+    fn yara_output_should_give_the_rules() {
+        let stdout = "CobaltStrike_Beacon C:\\Users\\bob\\AppData\\Local\\Temp\\x.dll\r\n\
+                      Packed_UPX C:\\Users\\bob\\AppData\\Local\\Temp\\x.dll\r\n\r\n";
+        assert_eq!(parse_matches(stdout), vec!["CobaltStrike_Beacon", "Packed_UPX"]);
+    }
+}
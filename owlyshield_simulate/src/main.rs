@@ -0,0 +1,144 @@
+//! Ransomware simulation, to check a deployment of Owlyshield end to end: detection,
+//! notification, and delivery of the alerts to the connectors.
+//!
+//! ```owlyshield_simulate run``` generates documents in a sandbox directory, then behaves like a
+//! ransomware on them only: it replaces each file by an encrypted (high entropy) copy with the
+//! *.owlysim* extension, and drops the same note in each directory. In the *PROTECT* mode, the
+//! agent should kill it before it completes.
+//!
+//! The encryption is a xor with a keystream derived from a seed kept in the sandbox:
+//! ```owlyshield_simulate restore``` decrypts the files, and ```owlyshield_simulate clean```
+//! removes the sandbox. A directory is only used if it is new, or was created by this tool.
+//!
+//! With *QUARANTINE*, the agent also quarantines this executable: restore it with
+//! ```owlyshield_ransom quarantine restore```.
+
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+
+use crate::sandbox::Sandbox;
+
+mod sandbox;
+
+#[derive(Parser, Debug)]
+#[clap(name = "owlyshield_simulate", version, about = "Benign ransomware simulation, to test an Owlyshield deployment")]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate documents in the sandbox and encrypt them
+    Run {
+        /// Sandbox directory (default: owlyshield_simulate in the temporary directory)
+        #[clap(long)]
+        dir: Option<PathBuf>,
+        /// Number of documents
+        #[clap(long, default_value = "300")]
+        files: usize,
+        /// Number of subdirectories
+        #[clap(long, default_value = "10")]
+        dirs: usize,
+        /// Size of a document in bytes
+        #[clap(long, default_value = "65536")]
+        size: usize,
+        /// Pause between two documents, in milliseconds
+        #[clap(long, default_value = "5")]
+        delay_ms: u64,
+    },
+    /// Decrypt the documents of the sandbox
+    Restore {
+        #[clap(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Remove the sandbox
+    Clean {
+        #[clap(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let code = match cli.command {
+        Command::Run {
+            dir,
+            files,
+            dirs,
+            size,
+            delay_ms,
+        } => run(&sandbox_dir(dir), files, dirs, size, Duration::from_millis(delay_ms)),
+        Command::Restore { dir } => match Sandbox::open(&sandbox_dir(dir)).and_then(|sandbox| sandbox.restore()) {
+            Ok(count) => {
+                println!("{} files restored", count);
+                0
+            }
+            Err(e) => {
+                println!("Cannot restore the files: {}", e);
+                1
+            }
+        },
+        Command::Clean { dir } => match Sandbox::open(&sandbox_dir(dir)).and_then(|sandbox| sandbox.clean()) {
+            Ok(()) => {
+                println!("Sandbox removed");
+                0
+            }
+            Err(e) => {
+                println!("Cannot remove the sandbox: {}", e);
+                1
+            }
+        },
+    };
+    process::exit(code);
+}
+
+fn sandbox_dir(dir: Option<PathBuf>) -> PathBuf {
+    dir.unwrap_or_else(|| std::env::temp_dir().join("owlyshield_simulate"))
+}
+
+fn run(dir: &std::path::Path, files: usize, dirs: usize, size: usize, delay: Duration) -> i32 {
+    let sandbox = match Sandbox::create(dir) {
+        Ok(sandbox) => sandbox,
+        Err(e) => {
+            println!("Cannot create the sandbox: {}", e);
+            return 1;
+        }
+    };
+    let files = match sandbox.generate(files, dirs, size) {
+        Ok(files) => files,
+        Err(e) => {
+            println!("Cannot generate the documents: {}", e);
+            return 1;
+        }
+    };
+    println!(
+        "{} documents generated in {} folders of {}, now encrypting them (pid {})",
+        files.len(),
+        sandbox::dirs_of(&files),
+        sandbox.dir.display(),
+        process::id()
+    );
+    let start = Instant::now();
+    for (i, path) in files.iter().enumerate() {
+        if let Err(e) = sandbox.encrypt(path) {
+            println!("Cannot encrypt {}: {}", path.display(), e);
+            return 1;
+        }
+        if (i + 1) % 50 == 0 {
+            println!("{} documents encrypted", i + 1);
+        }
+        thread::sleep(delay);
+    }
+    println!(
+        "Completed: {} documents encrypted in {} s, without being stopped. In the PROTECT mode, the detection failed; \
+         in the other modes, check the alert. Run 'owlyshield_simulate restore' then 'owlyshield_simulate clean'",
+        files.len(),
+        start.elapsed().as_secs()
+    );
+    0
+}
@@ -0,0 +1,177 @@
+//! The sandbox directory, the only one touched by the simulation.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File identifying a sandbox, with the seed of its keystreams.
+pub const MARKER: &str = ".owlyshield_simulate";
+/// Extension of the encrypted copies.
+pub const ENCRYPTED_EXTENSION: &str = "owlysim";
+/// Note dropped in each directory, like a ransom note.
+pub const NOTE: &str = "OWLYSHIELD_SIMULATION_README.txt";
+
+const EXTENSIONS: [&str; 6] = ["docx", "xlsx", "pdf", "txt", "csv", "jpg"];
+const NOTE_TEXT: &str = "This is an Owlyshield simulation, your files are safe.\r\n\
+Run 'owlyshield_simulate restore' to decrypt them.\r\n";
+
+pub struct Sandbox {
+    pub dir: PathBuf,
+    seed: u64,
+}
+
+impl Sandbox {
+    /// Creates the sandbox *dir*, which must not exist or be a sandbox already.
+    pub fn create(dir: &Path) -> io::Result<Sandbox> {
+        if dir.exists() {
+            return Sandbox::open(dir);
+        }
+        fs::create_dir_all(dir)?;
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.as_nanos() as u64) | 1;
+        fs::write(dir.join(MARKER), seed.to_string())?;
+        Ok(Sandbox { dir: dir.to_path_buf(), seed })
+    }
+
+    /// Opens the existing sandbox *dir*, refused if it was not created by this tool.
+    pub fn open(dir: &Path) -> io::Result<Sandbox> {
+        let marker = fs::read_to_string(dir.join(MARKER)).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a simulation sandbox (no {} file)", dir.display(), MARKER),
+            )
+        })?;
+        let seed = marker
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {} file", MARKER)))?;
+        Ok(Sandbox { dir: dir.to_path_buf(), seed })
+    }
+
+    /// Writes *count* documents of *size* bytes of text, spread over *dirs* subdirectories.
+    pub fn generate(&self, count: usize, dirs: usize, size: usize) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::with_capacity(count);
+        for i in 0..count {
+            let dir = self.dir.join(format!("folder_{}", i % dirs.max(1)));
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("document_{}.{}", i, EXTENSIONS[i % EXTENSIONS.len()]));
+            let line = format!("Quarterly report {}: revenue, costs and forecasts of the department.\r\n", i);
+            let content: Vec<u8> = line.bytes().cycle().take(size).collect();
+            fs::write(&path, content)?;
+            files.push(path);
+        }
+        Ok(files)
+    }
+
+    /// Replaces *path* by its encrypted copy, and drops the note in its directory. Returns the
+    /// copy.
+    pub fn encrypt(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut content = fs::read(path)?;
+        self.apply_keystream(path, &mut content);
+        let copy = encrypted_path(path);
+        fs::write(&copy, &content)?;
+        fs::remove_file(path)?;
+        if let Some(dir) = path.parent() {
+            let note = dir.join(NOTE);
+            if !note.exists() {
+                fs::write(note, NOTE_TEXT)?;
+            }
+        }
+        Ok(copy)
+    }
+
+    /// Decrypts the encrypted copies and removes the notes. A copy whose original still exists
+    /// (the process was killed in between) is removed. Returns the number of files restored.
+    pub fn restore(&self) -> io::Result<usize> {
+        let mut restored = 0;
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.file_name().is_some_and(|name| name == NOTE) {
+                    fs::remove_file(&path)?;
+                } else if path.extension().is_some_and(|ext| ext == ENCRYPTED_EXTENSION) {
+                    let original = path.with_extension("");
+                    if !original.exists() {
+                        let mut content = fs::read(&path)?;
+                        self.apply_keystream(&original, &mut content);
+                        fs::write(&original, content)?;
+                        restored += 1;
+                    }
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Removes the sandbox.
+    pub fn clean(self) -> io::Result<()> {
+        fs::remove_dir_all(&self.dir)
+    }
+
+    /// Xors *content* with the keystream of *path*: a xorshift64* generator, seeded with the seed
+    /// of the sandbox and the name of the file.
+    fn apply_keystream(&self, path: &Path, content: &mut [u8]) {
+        let name = path.file_name().map_or(String::new(), |n| n.to_string_lossy().to_string());
+        // FNV-1a
+        let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3));
+        let mut state = (self.seed ^ hash) | 1;
+        for chunk in content.chunks_mut(8) {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            let key = state.wrapping_mul(0x2545_f491_4f6c_dd1d).to_le_bytes();
+            for (b, k) in chunk.iter_mut().zip(key.iter()) {
+                *b ^= k;
+            }
+        }
+    }
+}
+
+/// Number of directories of *files*.
+pub fn dirs_of(files: &[PathBuf]) -> usize {
+    files.iter().filter_map(|f| f.parent()).collect::<HashSet<&Path>>().len()
+}
+
+fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(ENCRYPTED_EXTENSION);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::sandbox::{Sandbox, MARKER};
+
+    #[test]
+    fn encrypted_files_should_be_restored() {
+        let dir = std::env::temp_dir().join("owlyshield_simulate_test");
+        let _ = fs::remove_dir_all(&dir);
+        let sandbox = Sandbox::create(&dir).unwrap();
+        let files = sandbox.generate(4, 2, 1000).unwrap();
+        let original = fs::read(&files[0]).unwrap();
+
+        let copy = sandbox.encrypt(&files[0]).unwrap();
+        sandbox.encrypt(&files[1]).unwrap();
+        assert!(!files[0].exists());
+        let encrypted = fs::read(&copy).unwrap();
+        assert_eq!(encrypted.len(), original.len());
+        assert_ne!(encrypted, original);
+
+        assert_eq!(sandbox.restore().unwrap(), 2);
+        assert_eq!(fs::read(&files[0]).unwrap(), original);
+        assert!(!copy.exists());
+
+        // only the sandboxes are opened
+        fs::remove_file(dir.join(MARKER)).unwrap();
+        assert!(Sandbox::open(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}